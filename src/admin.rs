@@ -0,0 +1,376 @@
+//! An admin HTTP endpoint to look at and act on what a process watches and
+//! registers.
+use crate::{
+    balance::{self, InstanceSet},
+    lifecycle::RegistrationState,
+    runtime::{self, AbortOnDrop},
+    snapshot::instance_to_json,
+    Instance, Registry,
+};
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use percent_encoding::percent_decode_str;
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::Display,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+/// Serves, as JSON:
+/// - `GET /apps`: the watched appids and their instance count.
+/// - `GET /instances?appid=...`: the instances of a watched appid.
+/// - `DELETE /instances?appid=...&id=...`: evicts the instances `id` names
+///   from the registry, whoever registered them, see `Registry::evict`.
+/// - `GET /registrations`: the instances this process registers, with their
+///   id, whether the registry reports them and whether they are cordoned.
+/// - `POST /registrations/{id}/register`: registers the instance again, e.g.
+///   after its node was removed by hand.
+/// - `POST /registrations/{id}/cordon`: deregisters the instance so it gets
+///   no new traffic, until `POST /registrations/{id}/uncordon`.
+///
+/// Use `handle` to mount it in an existing server, or `serve`.
+///
+/// ```ignore
+/// let admin = Admin::new(zk.clone());
+/// admin.watch("/dubbo-rs/provider");
+/// admin.registration("/dubbo-rs/provider", ins);
+/// tokio::spawn(admin.serve("127.0.0.1:9090".parse()?));
+/// ```
+pub struct Admin<R> {
+    inner: Arc<Inner<R>>,
+}
+
+struct Inner<R> {
+    registry: R,
+    apps: Mutex<BTreeMap<String, App>>,
+    registrations: Mutex<Vec<Registration>>,
+}
+
+struct App {
+    instances: InstanceSet,
+    _task: AbortOnDrop,
+}
+
+struct Registration {
+    appid: String,
+    ins: Arc<Instance>,
+    state: RegistrationState,
+    cordoned: bool,
+}
+
+impl<R> Clone for Admin<R> {
+    fn clone(&self) -> Self {
+        Admin {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<R> Admin<R>
+where
+    R: Registry + Send + Sync + 'static,
+    R::RegFuture: Send,
+    R::DeRegFuture: Send,
+    R::Watcher: Send + 'static,
+    R::Error: Display,
+{
+    pub fn new(registry: R) -> Self {
+        Admin {
+            inner: Arc::new(Inner {
+                registry,
+                apps: Mutex::new(BTreeMap::new()),
+                registrations: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Lists the instances of `appid`.
+    pub fn watch(&self, appid: &str) {
+        let mut apps = self.inner.apps.lock().unwrap();
+        apps.entry(appid.to_owned()).or_insert_with(|| {
+            let instances = InstanceSet::new();
+            let task = balance::drive(self.inner.registry.watch(appid), instances.clone());
+            App {
+                instances,
+                _task: runtime::spawn_abortable(task),
+            }
+        });
+    }
+
+    /// Lists `ins`, registered by this process in `appid`.
+    pub fn registration(&self, appid: &str, ins: Arc<Instance>) {
+        let state = RegistrationState::new(&self.inner.registry, appid, &ins);
+        self.inner.registrations.lock().unwrap().push(Registration {
+            appid: appid.to_owned(),
+            ins,
+            state,
+            cordoned: false,
+        });
+    }
+
+    /// Serves the admin endpoint on `addr`.
+    pub async fn serve(self, addr: SocketAddr) -> hyper::Result<()> {
+        let make_svc = make_service_fn(move |_| {
+            let admin = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let admin = admin.clone();
+                    async move { Ok::<_, Infallible>(admin.handle(req).await) }
+                }))
+            }
+        });
+        Server::bind(&addr).serve(make_svc).await
+    }
+
+    pub async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let path = req.uri().path().trim_end_matches('/');
+        let segments = path.split('/').skip(1).collect::<Vec<_>>();
+        match (req.method(), segments.as_slice()) {
+            (&Method::GET, ["apps"]) => reply(StatusCode::OK, self.apps()),
+            (&Method::GET, ["instances"]) => {
+                let appid = req.uri().query().and_then(|query| param(query, "appid"));
+                match appid.and_then(|appid| self.instances(&appid)) {
+                    Some(instances) => reply(StatusCode::OK, instances),
+                    None => error(StatusCode::NOT_FOUND, "appid not watched"),
+                }
+            }
+            (&Method::DELETE, ["instances"]) => {
+                let query = req.uri().query().unwrap_or_default();
+                let (appid, id) = match (param(query, "appid"), param(query, "id")) {
+                    (Some(appid), Some(id)) => (appid, id),
+                    _ => return error(StatusCode::BAD_REQUEST, "appid and id required"),
+                };
+                match self.inner.registry.evict(&appid, &id).await {
+                    Ok(()) => reply(StatusCode::OK, json!({})),
+                    Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+                }
+            }
+            (&Method::GET, ["registrations"]) => reply(StatusCode::OK, self.registrations()),
+            (&Method::POST, ["registrations", id, action]) => {
+                let id = match id.parse() {
+                    Ok(id) => id,
+                    Err(_) => return error(StatusCode::NOT_FOUND, "no such registration"),
+                };
+                let result = match *action {
+                    "register" => self.register(id, None).await,
+                    "cordon" => self.cordon(id).await,
+                    "uncordon" => self.register(id, Some(false)).await,
+                    _ => return error(StatusCode::NOT_FOUND, "no such action"),
+                };
+                match result {
+                    Ok(Some(())) => reply(StatusCode::OK, json!({})),
+                    Ok(None) => error(StatusCode::NOT_FOUND, "no such registration"),
+                    Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+                }
+            }
+            (_, ["apps"]) | (_, ["instances"]) | (_, ["registrations"]) => {
+                error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+            }
+            _ => error(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+
+    fn apps(&self) -> Value {
+        let apps = self.inner.apps.lock().unwrap();
+        apps.iter()
+            .map(|(appid, app)| (appid.to_string(), json!(app.instances.len())))
+            .collect()
+    }
+
+    fn instances(&self, appid: &str) -> Option<Value> {
+        let apps = self.inner.apps.lock().unwrap();
+        let instances = apps.get(appid)?.instances.snapshot();
+        Some(instances.iter().map(|ins| instance_to_json(ins)).collect())
+    }
+
+    fn registrations(&self) -> Value {
+        let registrations = self.inner.registrations.lock().unwrap();
+        registrations
+            .iter()
+            .enumerate()
+            .map(|(id, registration)| {
+                json!({
+                    "id": id,
+                    "appid": registration.appid,
+                    "registered": registration.state.is_ready(),
+                    "cordoned": registration.cordoned,
+                    "instance": instance_to_json(&registration.ins),
+                })
+            })
+            .collect()
+    }
+
+    /// Registers the instance `id`, and marks it cordoned or not once done.
+    async fn register(&self, id: usize, cordoned: Option<bool>) -> Result<Option<()>, R::Error> {
+        let ins = match self.inner.registrations.lock().unwrap().get(id) {
+            Some(registration) => registration.ins.clone(),
+            None => return Ok(None),
+        };
+        self.inner.registry.register(ins).await?;
+        self.set_cordoned(id, cordoned);
+        Ok(Some(()))
+    }
+
+    async fn cordon(&self, id: usize) -> Result<Option<()>, R::Error> {
+        let ins = match self.inner.registrations.lock().unwrap().get(id) {
+            Some(registration) => registration.ins.clone(),
+            None => return Ok(None),
+        };
+        self.inner.registry.deregister(&ins).await?;
+        self.set_cordoned(id, Some(true));
+        Ok(Some(()))
+    }
+
+    fn set_cordoned(&self, id: usize, cordoned: Option<bool>) {
+        if let Some(cordoned) = cordoned {
+            self.inner.registrations.lock().unwrap()[id].cordoned = cordoned;
+        }
+    }
+}
+
+fn param(query: &str, name: &str) -> Option<String> {
+    query.split('&').find_map(|pair| {
+        let mut kv = pair.splitn(2, '=');
+        if kv.next()? != name {
+            return None;
+        }
+        let v = kv.next().unwrap_or_default().replace('+', " ");
+        percent_decode_str(&v)
+            .decode_utf8()
+            .ok()
+            .map(|v| v.into_owned())
+    })
+}
+
+fn reply(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    reply(status, json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Admin;
+    use crate::{testing::MockRegistry, Instance};
+    use hyper::{body, Body, Method, Request, StatusCode};
+    use serde_json::{json, Value};
+    use std::{sync::Arc, time::Duration};
+
+    fn instance(addr: &str) -> Instance {
+        Instance {
+            appid: "/dubbo-rs/provider".into(),
+            addrs: vec![addr.to_owned()].into(),
+            ..Default::default()
+        }
+    }
+
+    async fn call(admin: &Admin<MockRegistry>, method: Method, uri: &str) -> (StatusCode, Value) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        let rsp = admin.handle(req).await;
+        let status = rsp.status();
+        let body = body::to_bytes(rsp.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_admin() {
+        let registry = MockRegistry::new();
+        registry.insert(instance("grpc://172.1.1.1:9999"));
+        let admin = Admin::new(registry.clone());
+        admin.watch("/dubbo-rs/provider");
+        admin.registration(
+            "/dubbo-rs/provider",
+            Arc::new(instance("grpc://172.1.1.2:9999")),
+        );
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+
+        assert_eq!(
+            call(&admin, Method::GET, "/apps").await,
+            (StatusCode::OK, json!({"/dubbo-rs/provider": 1}))
+        );
+        let (status, instances) = call(
+            &admin,
+            Method::GET,
+            "/instances?appid=%2Fdubbo-rs%2Fprovider",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(instances[0]["addrs"], json!(["grpc://172.1.1.1:9999"]));
+        assert_eq!(
+            call(&admin, Method::GET, "/instances?appid=consumer")
+                .await
+                .0,
+            StatusCode::NOT_FOUND
+        );
+        let (_, registrations) = call(&admin, Method::GET, "/registrations").await;
+        assert_eq!(registrations[0]["registered"], json!(false));
+
+        let register = call(&admin, Method::POST, "/registrations/0/register").await;
+        assert_eq!(register.0, StatusCode::OK);
+        registry.assert_registered(&instance("grpc://172.1.1.2:9999"));
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        let (_, registrations) = call(&admin, Method::GET, "/registrations").await;
+        assert_eq!(registrations[0]["registered"], json!(true));
+        assert_eq!(
+            call(&admin, Method::GET, "/apps").await.1,
+            json!({"/dubbo-rs/provider": 2})
+        );
+
+        let cordon = call(&admin, Method::POST, "/registrations/0/cordon").await;
+        assert_eq!(cordon.0, StatusCode::OK);
+        registry.assert_not_registered(&instance("grpc://172.1.1.2:9999"));
+        let (_, registrations) = call(&admin, Method::GET, "/registrations").await;
+        assert_eq!(registrations[0]["cordoned"], json!(true));
+
+        let evict = call(
+            &admin,
+            Method::DELETE,
+            "/instances?appid=%2Fdubbo-rs%2Fprovider&id=grpc%3A%2F%2F172.1.1.1%3A9999",
+        )
+        .await;
+        assert_eq!(evict.0, StatusCode::OK);
+        registry.assert_not_registered(&instance("grpc://172.1.1.1:9999"));
+        assert_eq!(
+            call(
+                &admin,
+                Method::DELETE,
+                "/instances?appid=%2Fdubbo-rs%2Fprovider"
+            )
+            .await
+            .0,
+            StatusCode::BAD_REQUEST
+        );
+
+        registry.fail_register("zk is down");
+        let uncordon = call(&admin, Method::POST, "/registrations/0/uncordon").await;
+        assert_eq!(
+            uncordon,
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"error": "mock registry error: zk is down"})
+            )
+        );
+        assert_eq!(
+            call(&admin, Method::POST, "/registrations/1/cordon")
+                .await
+                .0,
+            StatusCode::NOT_FOUND
+        );
+    }
+}
@@ -0,0 +1,307 @@
+use crate::{
+    identity::{DefaultIdentity, Identity},
+    watcher::{Event, WatchEvent},
+    Instance,
+};
+use futures::{pin_mut, Stream, StreamExt};
+use rand::Rng;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, RwLock,
+};
+
+pub use outlier::OutlierEjection;
+pub use ring::RingHash;
+pub use sticky::{Rebalance, Sticky};
+
+mod outlier;
+mod ring;
+mod sticky;
+
+/// Picks an instance to send a request to.
+pub trait Selector {
+    fn pick(&self) -> Option<Arc<Instance>>;
+}
+
+/// State kept up to date from watch events.
+pub trait Apply {
+    fn apply(&self, event: &Event);
+}
+
+impl<A> Apply for Arc<A>
+where
+    A: Apply + ?Sized,
+{
+    fn apply(&self, event: &Event) {
+        (**self).apply(event)
+    }
+}
+
+/// Applies every event of `watcher` to `target` until the watcher ends.
+///
+/// ```ignore
+/// let instances = InstanceSet::new();
+/// tokio::spawn(balance::drive(zk.watch("/dubbo-rs/provider"), instances.clone()));
+/// let selector = RoundRobin::new(instances);
+/// ```
+pub async fn drive<W, A>(watcher: W, target: A)
+where
+    W: Stream<Item = WatchEvent>,
+    A: Apply,
+{
+    pin_mut!(watcher);
+    while let Some(watch_event) = watcher.next().await {
+        target.apply(&watch_event.event);
+    }
+}
+
+/// The instances currently registered for an app, shared between the task
+/// applying watch events and any number of selectors.
+///
+/// A Create for an instance already in the set replaces it.
+pub struct InstanceSet<I = DefaultIdentity> {
+    identity: Arc<I>,
+    instances: Arc<RwLock<Arc<Vec<Arc<Instance>>>>>,
+}
+
+impl InstanceSet {
+    pub fn new() -> Self {
+        Self::with_identity(DefaultIdentity)
+    }
+}
+
+impl Default for InstanceSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I> Clone for InstanceSet<I> {
+    fn clone(&self) -> Self {
+        InstanceSet {
+            identity: self.identity.clone(),
+            instances: self.instances.clone(),
+        }
+    }
+}
+
+impl<I> InstanceSet<I> {
+    pub fn with_identity(identity: I) -> Self {
+        InstanceSet {
+            identity: Arc::new(identity),
+            instances: Arc::new(RwLock::new(Arc::new(Vec::new()))),
+        }
+    }
+
+    /// The current instances. Later changes don't affect a snapshot already taken.
+    pub fn snapshot(&self) -> Arc<Vec<Arc<Instance>>> {
+        self.instances.read().unwrap().clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.instances.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<I> Apply for InstanceSet<I>
+where
+    I: Identity,
+{
+    fn apply(&self, event: &Event) {
+        let mut instances = self.instances.write().unwrap();
+        let mut next = instances.as_ref().clone();
+        match event {
+            Event::Create(ins) | Event::Update(ins) => {
+                let key = self.identity.identify(ins);
+                let ins = ins.clone();
+                match next
+                    .iter()
+                    .position(|exist| self.identity.identify(exist) == key)
+                {
+                    Some(pos) => next[pos] = ins,
+                    None => next.push(ins),
+                }
+            }
+            Event::Delete(ins) => {
+                let key = self.identity.identify(ins);
+                next.retain(|exist| self.identity.identify(exist) != key);
+            }
+        }
+        *instances = Arc::new(next);
+    }
+}
+
+pub struct RoundRobin<I = DefaultIdentity> {
+    instances: InstanceSet<I>,
+    next: AtomicUsize,
+}
+
+impl<I> RoundRobin<I> {
+    pub fn new(instances: InstanceSet<I>) -> Self {
+        RoundRobin {
+            instances,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<I> Selector for RoundRobin<I> {
+    fn pick(&self) -> Option<Arc<Instance>> {
+        let instances = self.instances.snapshot();
+        if instances.is_empty() {
+            return None;
+        }
+        let pos = self.next.fetch_add(1, Ordering::Relaxed) % instances.len();
+        Some(instances[pos].clone())
+    }
+}
+
+pub struct Random<I = DefaultIdentity> {
+    instances: InstanceSet<I>,
+}
+
+impl<I> Random<I> {
+    pub fn new(instances: InstanceSet<I>) -> Self {
+        Random { instances }
+    }
+}
+
+impl<I> Selector for Random<I> {
+    fn pick(&self) -> Option<Arc<Instance>> {
+        let instances = self.instances.snapshot();
+        if instances.is_empty() {
+            return None;
+        }
+        let pos = rand::thread_rng().gen_range(0, instances.len());
+        Some(instances[pos].clone())
+    }
+}
+
+/// Picks instances proportionally to their `weight` metadata. Instances
+/// without a valid weight count as `default_weight`, 1 unless configured.
+pub struct WeightedRandom<I = DefaultIdentity> {
+    instances: InstanceSet<I>,
+    default_weight: u32,
+}
+
+impl<I> WeightedRandom<I> {
+    pub fn new(instances: InstanceSet<I>) -> Self {
+        WeightedRandom {
+            instances,
+            default_weight: 1,
+        }
+    }
+
+    pub fn with_default_weight(mut self, default_weight: u32) -> Self {
+        self.default_weight = default_weight;
+        self
+    }
+
+    fn weight(&self, ins: &Instance) -> u64 {
+        ins.weight().unwrap_or(self.default_weight) as u64
+    }
+}
+
+impl<I> Selector for WeightedRandom<I> {
+    fn pick(&self) -> Option<Arc<Instance>> {
+        let instances = self.instances.snapshot();
+        let total = instances.iter().map(|ins| self.weight(ins)).sum::<u64>();
+        if total == 0 {
+            return None;
+        }
+        let mut point = rand::thread_rng().gen_range(0, total);
+        for ins in instances.iter() {
+            let weight = self.weight(ins);
+            if point < weight {
+                return Some(ins.clone());
+            }
+            point -= weight;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Apply, InstanceSet, Random, RoundRobin, Selector, WeightedRandom};
+    use crate::{watcher::Event, Instance};
+    use std::sync::Arc;
+
+    fn instance(addr: &str, weight: &str) -> Arc<Instance> {
+        Arc::new(Instance {
+            appid: "provider".into(),
+            addrs: vec![addr.to_owned()].into(),
+            metadata: [("weight".to_owned(), weight.to_owned())]
+                .iter()
+                .cloned()
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_instance_set_apply() {
+        let set = InstanceSet::new();
+        set.apply(&Event::Create(instance("grpc://172.1.1.1:9999", "10")));
+        set.apply(&Event::Create(instance("grpc://172.1.1.2:9999", "10")));
+        let before = set.snapshot();
+        // same identity, replaces the first instance.
+        set.apply(&Event::Create(instance("grpc://172.1.1.1:9999", "20")));
+        assert_eq!(set.len(), 2);
+        assert_eq!(set.snapshot()[0].weight(), Some(20));
+        assert_eq!(before[0].weight(), Some(10));
+
+        set.apply(&Event::Delete(instance("grpc://172.1.1.1:9999", "20")));
+        assert_eq!(set.len(), 1);
+        assert_eq!(set.snapshot()[0].addrs[0], "grpc://172.1.1.2:9999");
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let set = InstanceSet::new();
+        let selector = RoundRobin::new(set.clone());
+        assert!(selector.pick().is_none());
+
+        set.apply(&Event::Create(instance("grpc://172.1.1.1:9999", "10")));
+        set.apply(&Event::Create(instance("grpc://172.1.1.2:9999", "10")));
+        let picked = (0..4)
+            .map(|_| selector.pick().unwrap().addrs[0].clone())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            picked,
+            vec![
+                "grpc://172.1.1.1:9999",
+                "grpc://172.1.1.2:9999",
+                "grpc://172.1.1.1:9999",
+                "grpc://172.1.1.2:9999"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_random() {
+        let set = InstanceSet::new();
+        let selector = Random::new(set.clone());
+        assert!(selector.pick().is_none());
+
+        set.apply(&Event::Create(instance("grpc://172.1.1.1:9999", "10")));
+        assert_eq!(selector.pick().unwrap().addrs[0], "grpc://172.1.1.1:9999");
+    }
+
+    #[test]
+    fn test_weighted_random_skips_zero_weight() {
+        let set = InstanceSet::new();
+        let selector = WeightedRandom::new(set.clone());
+        set.apply(&Event::Create(instance("grpc://172.1.1.1:9999", "0")));
+        assert!(selector.pick().is_none());
+
+        set.apply(&Event::Create(instance("grpc://172.1.1.2:9999", "5")));
+        for _ in 0..100 {
+            assert_eq!(selector.pick().unwrap().addrs[0], "grpc://172.1.1.2:9999");
+        }
+    }
+}
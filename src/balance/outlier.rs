@@ -0,0 +1,246 @@
+use super::{InstanceSet, Selector};
+use crate::{
+    identity::{DefaultIdentity, Identity},
+    Instance,
+};
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+const DEFAULT_FAILURE_RATIO: f64 = 0.5;
+const DEFAULT_MIN_REQUESTS: u32 = 10;
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_EJECTED: f64 = 0.5;
+
+/// Weighted random selection that leaves out instances failing too many
+/// requests, as reported by the caller.
+///
+/// An instance failing at least `failure_ratio` of at least `min_requests`
+/// requests within an `interval` is ejected for `cooldown`, longer each time
+/// it gets ejected again. At most `max_ejected` of the instances are ejected
+/// at once, so a failing dependency doesn't empty the set.
+///
+/// ```ignore
+/// let selector = OutlierEjection::new(instances).cooldown(Duration::from_secs(10));
+/// let ins = selector.pick().unwrap();
+/// let rsp = call(&ins).await;
+/// selector.report(&ins, rsp.is_ok());
+/// ```
+pub struct OutlierEjection<I = DefaultIdentity>
+where
+    I: Identity,
+{
+    instances: InstanceSet<I>,
+    failure_ratio: f64,
+    min_requests: u32,
+    interval: Duration,
+    cooldown: Duration,
+    max_ejected: f64,
+    stats: Mutex<HashMap<I::Key, Stats>>,
+}
+
+struct Stats {
+    since: Instant,
+    requests: u32,
+    failures: u32,
+    ejections: u32,
+    ejected_until: Option<Instant>,
+}
+
+impl<I> OutlierEjection<I>
+where
+    I: Identity,
+{
+    pub fn new(instances: InstanceSet<I>) -> Self {
+        OutlierEjection {
+            instances,
+            failure_ratio: DEFAULT_FAILURE_RATIO,
+            min_requests: DEFAULT_MIN_REQUESTS,
+            interval: DEFAULT_INTERVAL,
+            cooldown: DEFAULT_COOLDOWN,
+            max_ejected: DEFAULT_MAX_EJECTED,
+            stats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn failure_ratio(mut self, failure_ratio: f64) -> Self {
+        self.failure_ratio = failure_ratio;
+        self
+    }
+
+    pub fn min_requests(mut self, min_requests: u32) -> Self {
+        self.min_requests = min_requests.max(1);
+        self
+    }
+
+    /// How long requests are counted before the counts start over.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How long an instance stays ejected the first time, the n-th ejection
+    /// lasts n times as long.
+    pub fn cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// The largest share of the instances ejected at once.
+    pub fn max_ejected(mut self, max_ejected: f64) -> Self {
+        self.max_ejected = max_ejected;
+        self
+    }
+
+    /// Records the outcome of a request sent to `ins`.
+    pub fn report(&self, ins: &Instance, ok: bool) {
+        let now = Instant::now();
+        let instances = self.instances.snapshot();
+        let mut stats = self.stats.lock().unwrap();
+        if stats.len() > instances.len() {
+            let present = instances
+                .iter()
+                .map(|ins| self.instances.identity.identify(ins))
+                .collect::<Vec<_>>();
+            stats.retain(|key, _| present.contains(key));
+        }
+        let ejected = stats.values().filter(|stats| stats.is_ejected(now)).count();
+
+        let stats = stats
+            .entry(self.instances.identity.identify(ins))
+            .or_insert_with(|| Stats {
+                since: now,
+                requests: 0,
+                failures: 0,
+                ejections: 0,
+                ejected_until: None,
+            });
+        if stats.is_ejected(now) {
+            return;
+        }
+        if now.duration_since(stats.since) >= self.interval {
+            stats.since = now;
+            stats.requests = 0;
+            stats.failures = 0;
+        }
+        stats.requests += 1;
+        if !ok {
+            stats.failures += 1;
+        }
+
+        let failing = stats.requests >= self.min_requests
+            && stats.failures as f64 >= self.failure_ratio * stats.requests as f64;
+        let room = ((ejected + 1) as f64) <= self.max_ejected * instances.len() as f64;
+        if failing && room {
+            stats.ejections += 1;
+            stats.ejected_until = Some(now + self.cooldown * stats.ejections);
+            stats.since = now;
+            stats.requests = 0;
+            stats.failures = 0;
+        }
+    }
+
+    pub fn is_ejected(&self, ins: &Instance) -> bool {
+        let key = self.instances.identity.identify(ins);
+        matches!(
+            self.stats.lock().unwrap().get(&key),
+            Some(stats) if stats.is_ejected(Instant::now())
+        )
+    }
+}
+
+impl Stats {
+    fn is_ejected(&self, now: Instant) -> bool {
+        matches!(self.ejected_until, Some(until) if until > now)
+    }
+}
+
+impl<I> Selector for OutlierEjection<I>
+where
+    I: Identity,
+{
+    fn pick(&self) -> Option<Arc<Instance>> {
+        let now = Instant::now();
+        let instances = self.instances.snapshot();
+        let selectable = {
+            let stats = self.stats.lock().unwrap();
+            instances
+                .iter()
+                .filter(|ins| {
+                    let key = self.instances.identity.identify(ins);
+                    !matches!(stats.get(&key), Some(stats) if stats.is_ejected(now))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let weight = |ins: &Instance| ins.weight().unwrap_or(1) as u64;
+        let total = selectable.iter().map(|ins| weight(ins)).sum::<u64>();
+        if total == 0 {
+            return None;
+        }
+        let mut point = rand::thread_rng().gen_range(0, total);
+        for ins in selectable {
+            if point < weight(ins) {
+                return Some(ins.clone());
+            }
+            point -= weight(ins);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OutlierEjection;
+    use crate::{
+        balance::{Apply, InstanceSet, Selector},
+        testing::instance,
+        watcher::Event,
+    };
+    use std::{thread, time::Duration};
+
+    fn instances(n: usize) -> InstanceSet {
+        let set = InstanceSet::new();
+        for i in 1..=n {
+            set.apply(&Event::Create(instance(&format!(
+                "grpc://172.1.1.{}:9999",
+                i
+            ))));
+        }
+        set
+    }
+
+    #[test]
+    fn test_outlier_ejection() {
+        let selector = OutlierEjection::new(instances(2))
+            .min_requests(4)
+            .cooldown(Duration::from_millis(100));
+        let bad = instance("grpc://172.1.1.1:9999");
+        selector.report(&bad, true);
+        for _ in 0..3 {
+            selector.report(&bad, false);
+        }
+        assert!(selector.is_ejected(&bad));
+        for _ in 0..100 {
+            assert_eq!(selector.pick().unwrap().addrs[0], "grpc://172.1.1.2:9999");
+        }
+
+        thread::sleep(Duration::from_millis(150));
+        assert!(!selector.is_ejected(&bad));
+        assert!((0..100).any(|_| selector.pick().unwrap().addrs[0] == "grpc://172.1.1.1:9999"));
+    }
+
+    #[test]
+    fn test_outlier_ejection_limit() {
+        let selector = OutlierEjection::new(instances(2)).min_requests(1);
+        selector.report(&instance("grpc://172.1.1.1:9999"), false);
+        selector.report(&instance("grpc://172.1.1.2:9999"), false);
+        assert!(selector.is_ejected(&instance("grpc://172.1.1.1:9999")));
+        assert!(!selector.is_ejected(&instance("grpc://172.1.1.2:9999")));
+        assert_eq!(selector.pick().unwrap().addrs[0], "grpc://172.1.1.2:9999");
+    }
+}
@@ -0,0 +1,216 @@
+use super::Apply;
+use crate::{
+    identity::{DefaultIdentity, Identity},
+    watcher::Event,
+    Instance,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    sync::{Arc, RwLock},
+};
+
+const DEFAULT_REPLICAS: usize = 160;
+
+/// Consistent hashing over the watched instances: a request hash always maps
+/// to the same instance while it stays registered, and only the requests of a
+/// joining or leaving instance move.
+///
+/// Every instance is placed on the ring `replicas` times. The ring is updated
+/// in place from watch events, not rebuilt.
+pub struct RingHash<I = DefaultIdentity>
+where
+    I: Identity,
+{
+    identity: I,
+    replicas: usize,
+    ring: RwLock<Ring<I::Key>>,
+}
+
+struct Ring<K> {
+    points: BTreeMap<u64, Arc<Instance>>,
+    members: HashMap<K, (Arc<Instance>, Vec<u64>)>,
+}
+
+impl RingHash {
+    pub fn new() -> Self {
+        Self::with_identity(DefaultIdentity)
+    }
+}
+
+impl Default for RingHash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I> RingHash<I>
+where
+    I: Identity,
+{
+    pub fn with_identity(identity: I) -> Self {
+        RingHash {
+            identity,
+            replicas: DEFAULT_REPLICAS,
+            ring: RwLock::new(Ring {
+                points: BTreeMap::new(),
+                members: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Points per instance, more spreads load more evenly. Only affects
+    /// instances added afterwards.
+    pub fn replicas(mut self, replicas: usize) -> Self {
+        self.replicas = replicas.max(1);
+        self
+    }
+
+    /// The instance owning `hash`, e.g. a hashed user or session id.
+    pub fn pick(&self, hash: u64) -> Option<Arc<Instance>> {
+        let ring = self.ring.read().unwrap();
+        ring.points
+            .range(mix(hash)..)
+            .next()
+            .or_else(|| ring.points.iter().next())
+            .map(|(_, ins)| ins.clone())
+    }
+
+    pub fn pick_key<K>(&self, key: &K) -> Option<Arc<Instance>>
+    where
+        K: Hash + ?Sized,
+    {
+        self.pick(fxhash::hash64(key))
+    }
+
+    pub fn len(&self) -> usize {
+        self.ring.read().unwrap().members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<I> Apply for RingHash<I>
+where
+    I: Identity,
+{
+    fn apply(&self, event: &Event) {
+        let mut ring = self.ring.write().unwrap();
+        let ring = &mut *ring;
+        match event {
+            Event::Create(ins) | Event::Update(ins) => {
+                let key = self.identity.identify(ins);
+                let ins = ins.clone();
+                if let Some((exist, points)) = ring.members.get_mut(&key) {
+                    for point in points.iter() {
+                        if let Some(owner) = ring.points.get_mut(point) {
+                            if Arc::ptr_eq(owner, exist) {
+                                *owner = ins.clone();
+                            }
+                        }
+                    }
+                    *exist = ins;
+                    return;
+                }
+                let points = (0..self.replicas)
+                    .map(|replica| mix(fxhash::hash64(&(&key, replica))))
+                    .collect::<Vec<u64>>();
+                for point in points.iter() {
+                    ring.points.insert(*point, ins.clone());
+                }
+                ring.members.insert(key, (ins, points));
+            }
+            Event::Delete(ins) => {
+                let key = self.identity.identify(ins);
+                if let Some((exist, points)) = ring.members.remove(&key) {
+                    for point in points {
+                        // a colliding point may have been taken over by another instance.
+                        let owned = matches!(
+                            ring.points.get(&point),
+                            Some(owner) if Arc::ptr_eq(owner, &exist)
+                        );
+                        if owned {
+                            ring.points.remove(&point);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// splitmix64 finalizer, spreads fxhash output evenly over the ring.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RingHash;
+    use crate::{balance::Apply, testing::instance, watcher::Event, Instance};
+    use std::{collections::HashMap, sync::Arc};
+
+    fn owners(ring: &RingHash) -> Vec<String> {
+        (0..1000u32)
+            .map(|user| ring.pick_key(&user).unwrap().addrs[0].clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_ring_hash_spreads_keys() {
+        let ring = RingHash::new();
+        assert!(ring.pick(42).is_none());
+        for i in 1..=4 {
+            ring.apply(&Event::Create(instance(&format!(
+                "grpc://172.1.1.{}:9999",
+                i
+            ))));
+        }
+        let mut counts = HashMap::new();
+        for owner in owners(&ring) {
+            *counts.entry(owner).or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), 4);
+        assert!(counts.values().all(|count| *count > 150));
+    }
+
+    #[test]
+    fn test_ring_hash_only_moves_keys_of_removed_instance() {
+        let ring = RingHash::new();
+        for i in 1..=4 {
+            ring.apply(&Event::Create(instance(&format!(
+                "grpc://172.1.1.{}:9999",
+                i
+            ))));
+        }
+        let before = owners(&ring);
+        ring.apply(&Event::Delete(instance("grpc://172.1.1.3:9999")));
+        assert_eq!(ring.len(), 3);
+        let after = owners(&ring);
+        for (before, after) in before.iter().zip(after.iter()) {
+            if before != "grpc://172.1.1.3:9999" {
+                assert_eq!(before, after);
+            } else {
+                assert_ne!(after, "grpc://172.1.1.3:9999");
+            }
+        }
+
+        ring.apply(&Event::Create(instance("grpc://172.1.1.3:9999")));
+        assert_eq!(owners(&ring), before);
+    }
+
+    #[test]
+    fn test_ring_hash_replaces_in_place() {
+        let ring = RingHash::new();
+        ring.apply(&Event::Create(instance("grpc://172.1.1.1:9999")));
+        let mut updated = Instance::clone(&instance("grpc://172.1.1.1:9999"));
+        updated.hostname = "updated".to_owned();
+        ring.apply(&Event::Create(Arc::new(updated)));
+        assert_eq!(ring.len(), 1);
+        assert_eq!(ring.pick(7).unwrap().hostname, "updated");
+    }
+}
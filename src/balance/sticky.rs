@@ -0,0 +1,256 @@
+use super::Apply;
+use crate::{
+    identity::{DefaultIdentity, Identity},
+    watcher::Event,
+    Instance,
+};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+
+/// What happens to pinned sessions when an instance joins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rebalance {
+    /// Sessions stay where they are, the new instance only gets new sessions.
+    Never,
+    /// Sessions move from the busiest instances to the new one until it has
+    /// its fair share.
+    Even,
+}
+
+/// Pins sessions to instances: a session keeps going to the same instance as
+/// long as that instance stays registered, whatever else joins or leaves.
+///
+/// New sessions, and sessions whose instance left, are pinned to the instance
+/// with the fewest sessions. Call `release` when a session ends so it stops
+/// counting.
+pub struct Sticky<I = DefaultIdentity>
+where
+    I: Identity,
+{
+    identity: I,
+    rebalance: Rebalance,
+    state: Mutex<State<I::Key>>,
+}
+
+struct State<K> {
+    members: Vec<(K, Arc<Instance>, usize)>,
+    sessions: HashMap<u64, K>,
+}
+
+impl Sticky {
+    pub fn new() -> Self {
+        Self::with_identity(DefaultIdentity)
+    }
+}
+
+impl Default for Sticky {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I> Sticky<I>
+where
+    I: Identity,
+{
+    pub fn with_identity(identity: I) -> Self {
+        Sticky {
+            identity,
+            rebalance: Rebalance::Never,
+            state: Mutex::new(State {
+                members: Vec::new(),
+                sessions: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn rebalance(mut self, rebalance: Rebalance) -> Self {
+        self.rebalance = rebalance;
+        self
+    }
+
+    /// The instance `session` is pinned to, pinning it first if needed.
+    pub fn pick(&self, session: u64) -> Option<Arc<Instance>> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if let Some(key) = state.sessions.get(&session) {
+            if let Some((_, ins, _)) = state.members.iter().find(|(k, _, _)| k == key) {
+                return Some(ins.clone());
+            }
+        }
+        let (key, ins, load) = state.members.iter_mut().min_by_key(|(_, _, load)| *load)?;
+        *load += 1;
+        state.sessions.insert(session, key.clone());
+        Some(ins.clone())
+    }
+
+    pub fn pick_key<K>(&self, session: &K) -> Option<Arc<Instance>>
+    where
+        K: Hash + ?Sized,
+    {
+        self.pick(fxhash::hash64(session))
+    }
+
+    /// Forgets `session`, its next pick may go anywhere.
+    pub fn release(&self, session: u64) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if let Some(key) = state.sessions.remove(&session) {
+            if let Some((_, _, load)) = state.members.iter_mut().find(|(k, _, _)| *k == key) {
+                *load -= 1;
+            }
+        }
+    }
+
+    pub fn release_key<K>(&self, session: &K)
+    where
+        K: Hash + ?Sized,
+    {
+        self.release(fxhash::hash64(session))
+    }
+
+    /// The number of pinned sessions.
+    pub fn sessions(&self) -> usize {
+        self.state.lock().unwrap().sessions.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K> State<K>
+where
+    K: Eq + Clone,
+{
+    // moves sessions from instances above the fair share to `to`.
+    fn rebalance_to(&mut self, to: &K) {
+        let fair = self.sessions.len() / self.members.len();
+        let mut moved = 0;
+        for owner in self.sessions.values_mut() {
+            if moved >= fair {
+                break;
+            }
+            let load = match self.members.iter_mut().find(|(k, _, _)| k == owner) {
+                Some((_, _, load)) => load,
+                None => continue,
+            };
+            if *load > fair {
+                *load -= 1;
+                *owner = to.clone();
+                moved += 1;
+            }
+        }
+        if let Some((_, _, load)) = self.members.iter_mut().find(|(k, _, _)| k == to) {
+            *load += moved;
+        }
+    }
+}
+
+impl<I> Apply for Sticky<I>
+where
+    I: Identity,
+{
+    fn apply(&self, event: &Event) {
+        let mut state = self.state.lock().unwrap();
+        match event {
+            Event::Create(ins) | Event::Update(ins) => {
+                let key = self.identity.identify(ins);
+                let ins = ins.clone();
+                if let Some((_, exist, _)) = state.members.iter_mut().find(|(k, _, _)| *k == key) {
+                    *exist = ins;
+                    return;
+                }
+                state.members.push((key.clone(), ins, 0));
+                if self.rebalance == Rebalance::Even {
+                    state.rebalance_to(&key);
+                }
+            }
+            Event::Delete(ins) => {
+                let key = self.identity.identify(ins);
+                state.members.retain(|(k, _, _)| *k != key);
+                state.sessions.retain(|_, owner| *owner != key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rebalance, Sticky};
+    use crate::{balance::Apply, testing::instance, watcher::Event};
+
+    fn owners(sticky: &Sticky) -> Vec<String> {
+        (0..100u32)
+            .map(|session| sticky.pick_key(&session).unwrap().addrs[0].clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_sticky_survives_churn() {
+        let sticky = Sticky::new();
+        assert!(sticky.pick(42).is_none());
+        sticky.apply(&Event::Create(instance("grpc://172.1.1.1:9999")));
+        sticky.apply(&Event::Create(instance("grpc://172.1.1.2:9999")));
+        let before = owners(&sticky);
+        assert_eq!(
+            before
+                .iter()
+                .filter(|owner| *owner == "grpc://172.1.1.1:9999")
+                .count(),
+            50
+        );
+
+        sticky.apply(&Event::Create(instance("grpc://172.1.1.3:9999")));
+        assert_eq!(owners(&sticky), before);
+
+        sticky.apply(&Event::Delete(instance("grpc://172.1.1.2:9999")));
+        for (before, after) in before.iter().zip(owners(&sticky).iter()) {
+            if before == "grpc://172.1.1.1:9999" {
+                assert_eq!(before, after);
+            } else {
+                assert_eq!(after, "grpc://172.1.1.3:9999");
+            }
+        }
+        assert_eq!(sticky.sessions(), 100);
+    }
+
+    #[test]
+    fn test_sticky_rebalance_even() {
+        let sticky = Sticky::new().rebalance(Rebalance::Even);
+        sticky.apply(&Event::Create(instance("grpc://172.1.1.1:9999")));
+        let before = owners(&sticky);
+        sticky.apply(&Event::Create(instance("grpc://172.1.1.2:9999")));
+        let after = owners(&sticky);
+        let moved = before
+            .iter()
+            .zip(after.iter())
+            .filter(|(before, after)| before != after)
+            .count();
+        assert_eq!(moved, 50);
+    }
+
+    #[test]
+    fn test_sticky_release() {
+        let sticky = Sticky::new();
+        sticky.apply(&Event::Create(instance("grpc://172.1.1.1:9999")));
+        sticky.pick_key("session-1");
+        sticky.pick_key("session-2");
+        sticky.release_key("session-1");
+        assert_eq!(sticky.sessions(), 1);
+        // the freed capacity goes to the new session.
+        sticky.apply(&Event::Create(instance("grpc://172.1.1.2:9999")));
+        sticky.pick_key("session-3");
+        assert_eq!(
+            sticky.pick_key("session-3").unwrap().addrs[0],
+            "grpc://172.1.1.2:9999"
+        );
+    }
+}
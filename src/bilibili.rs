@@ -0,0 +1,709 @@
+//! A registry on a bilibili discovery cluster, through its HTTP API.
+//!
+//! Instances are registered with their zone, env, hostname, addresses,
+//! version and metadata, as that model is the one of `Instance`, and renewed
+//! in the background. Watches long-poll the instances of their app in the
+//! env of the registry, reporting the ones up.
+//!
+//! ```ignore
+//! let discovery = Bilibili::builder("10.0.0.1:7171,10.0.0.2:7171")
+//!     .env("prod")
+//!     .build();
+//! discovery.register(instance).await?;
+//! let discover = AppDiscover::new(discovery.watch("main.account.service"), make_service);
+//! ```
+//!
+//! The server tells instances apart by appid, env and hostname, so
+//! registering an instance replaces the one with the same hostname. The group
+//! of instances is kept in their metadata.
+use crate::{
+    identity::{DefaultIdentity, Identity},
+    intern::intern,
+    runtime::{self, AbortOnDrop},
+    watcher::{Clock, Event, SystemClock, WatchEvent},
+    Instance, Registry,
+};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future::{self, BoxFuture},
+    FutureExt, Stream,
+};
+use hyper::{client::HttpConnector, header, Body, Method, Request, StatusCode};
+use log::{error, warn};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use pin_project::pin_project;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    error, fmt,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, UNIX_EPOCH},
+};
+
+// how long a failed watch waits before polling again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// the codes of the answers of the server.
+const OK: i64 = 0;
+const NOT_MODIFIED: i64 = -304;
+const NOTHING_FOUND: i64 = -404;
+
+// up, receiving traffic.
+const STATUS_UP: u64 = 1;
+
+// the registrations by appid, env and hostname.
+type Registrations = Mutex<HashMap<(String, String, String), Vec<(&'static str, String)>>>;
+
+pub struct Bilibili<I = DefaultIdentity> {
+    client: Client,
+    env: String,
+    zone: String,
+    // registered again when the server lost them.
+    registered: Arc<Registrations>,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+    _renewal: AbortOnDrop,
+}
+
+impl Bilibili {
+    /// Talks to the nodes at `nodes`, see `BilibiliBuilder` for the options.
+    pub fn builder(nodes: &str) -> BilibiliBuilder {
+        BilibiliBuilder::new(nodes)
+    }
+}
+
+impl<I> Bilibili<I> {
+    /// Sets how watchers tell instances apart.
+    pub fn with_identity<NI>(self, identity: NI) -> Bilibili<NI> {
+        Bilibili {
+            client: self.client,
+            env: self.env,
+            zone: self.zone,
+            registered: self.registered,
+            identity: Arc::new(identity),
+            clock: self.clock,
+            _renewal: self._renewal,
+        }
+    }
+
+    /// Sets the clock timestamping the events of watchers.
+    pub fn with_clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    // the zone and env of the registration of `ins`, the ones of the
+    // registry unless set.
+    fn zone_env<'a>(&'a self, ins: &'a Instance) -> (&'a str, &'a str) {
+        let or = |field: &'a str, default: &'a str| if field.is_empty() { default } else { field };
+        (or(&ins.zone, &self.zone), or(&ins.env, &self.env))
+    }
+}
+
+/// Builds a `Bilibili`.
+pub struct BilibiliBuilder {
+    nodes: Vec<String>,
+    env: String,
+    zone: String,
+    renewal_interval: Duration,
+}
+
+impl BilibiliBuilder {
+    /// `nodes` are the addresses of the nodes of the cluster, comma
+    /// separated, e.g. `10.0.0.1:7171,10.0.0.2:7171`. The next one is called
+    /// when one fails.
+    pub fn new(nodes: &str) -> Self {
+        let nodes = nodes
+            .split(',')
+            .map(str::trim)
+            .map(|node| {
+                if node.contains("://") {
+                    node.trim_end_matches('/').to_owned()
+                } else {
+                    format!("http://{}", node)
+                }
+            })
+            .collect();
+        BilibiliBuilder {
+            nodes,
+            env: String::new(),
+            zone: String::new(),
+            renewal_interval: Duration::from_secs(30),
+        }
+    }
+
+    /// The env of the instances watched, and of the ones registered without
+    /// one.
+    pub fn env(mut self, env: &str) -> Self {
+        self.env = env.to_owned();
+        self
+    }
+
+    /// The zone of the instances registered without one. Watches report the
+    /// instances of every zone.
+    pub fn zone(mut self, zone: &str) -> Self {
+        self.zone = zone.to_owned();
+        self
+    }
+
+    /// How often the instances registered are renewed, 30 seconds by default
+    /// as the server expects.
+    pub fn renewal_interval(mut self, interval: Duration) -> Self {
+        self.renewal_interval = interval;
+        self
+    }
+
+    /// Needs a tokio runtime, the instances registered are renewed in the
+    /// background.
+    pub fn build(self) -> Bilibili {
+        let client = Client {
+            http: hyper::Client::new(),
+            nodes: Arc::new(self.nodes),
+            node: Arc::new(AtomicUsize::new(0)),
+        };
+        let registered = Arc::new(Mutex::new(HashMap::new()));
+        let interval = self.renewal_interval;
+        let renewal = {
+            let (client, registered) = (client.clone(), registered.clone());
+            async move {
+                loop {
+                    runtime::delay_for(interval).await;
+                    renew(&client, &registered).await;
+                }
+            }
+        };
+        let renewal = runtime::spawn_abortable(renewal);
+        Bilibili {
+            client,
+            env: self.env,
+            zone: self.zone,
+            registered,
+            identity: Arc::new(DefaultIdentity),
+            clock: Arc::new(SystemClock),
+            _renewal: renewal,
+        }
+    }
+}
+
+async fn renew(client: &Client, registered: &Registrations) {
+    let registrations = registered.lock().unwrap().clone();
+    for (key, params) in registrations {
+        if let Err(e) = renew_one(client, &params).await {
+            error!("failed to renew {:?}. {}", key, e);
+        }
+    }
+}
+
+// renews the instance registered with `params`, registering it again when
+// lost.
+async fn renew_one(
+    client: &Client,
+    params: &[(&'static str, String)],
+) -> Result<(), BilibiliError> {
+    let renewal = params
+        .iter()
+        .filter(|(key, _)| matches!(*key, "zone" | "env" | "appid" | "hostname"))
+        .cloned()
+        .collect::<Vec<_>>();
+    match client
+        .call(Method::POST, "/discovery/renew", &renewal)
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(BilibiliError::Code(NOTHING_FOUND, _)) => {
+            warn!(
+                "discovery lost the instance {:?}, registering it again",
+                renewal
+            );
+            client
+                .call(Method::POST, "/discovery/register", params)
+                .await?;
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Clone)]
+struct Client {
+    http: hyper::Client<HttpConnector>,
+    nodes: Arc<Vec<String>>,
+    // the node called, the next one after a failure.
+    node: Arc<AtomicUsize>,
+}
+
+impl Client {
+    // the data of the answer, the params are sent as a form or in the query.
+    async fn call(
+        &self,
+        method: Method,
+        path: &str,
+        params: &[(&str, String)],
+    ) -> Result<Value, BilibiliError> {
+        let form = params
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, encode(value)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let node = self.node.load(Ordering::Relaxed);
+        let address = &self.nodes[node % self.nodes.len()];
+        let req = if method == Method::GET {
+            Request::get(format!("{}{}?{}", address, path, form)).body(Body::empty())
+        } else {
+            Request::builder()
+                .method(method)
+                .uri(format!("{}{}", address, path))
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(Body::from(form))
+        }
+        .map_err(|e| BilibiliError::InvalidAddress(e.to_string()))?;
+        let rsp = match self.http.request(req).await {
+            Ok(rsp) => rsp,
+            Err(e) => {
+                let _ = self.node.compare_exchange(
+                    node,
+                    node.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+                return Err(e.into());
+            }
+        };
+        let status = rsp.status();
+        let body = hyper::body::to_bytes(rsp.into_body()).await?;
+        if !status.is_success() {
+            let message = String::from_utf8_lossy(&body).into_owned();
+            return Err(BilibiliError::Status(status, message));
+        }
+        let mut rsp: Value = serde_json::from_slice(&body)?;
+        match rsp["code"].as_i64() {
+            Some(OK) => Ok(rsp["data"].take()),
+            code => {
+                let message = rsp["message"].as_str().unwrap_or("").to_owned();
+                Err(BilibiliError::Code(code.unwrap_or_default(), message))
+            }
+        }
+    }
+}
+
+fn encode(value: &str) -> String {
+    utf8_percent_encode(value, NON_ALPHANUMERIC).to_string()
+}
+
+// what the server tells `ins` apart by, named after its fields when it has
+// no hostname.
+fn hostname(ins: &Instance) -> String {
+    if ins.hostname.is_empty() {
+        format!("{:016x}", fxhash::hash64(ins))
+    } else {
+        ins.hostname.clone()
+    }
+}
+
+// the form registering `ins`.
+fn to_params(ins: &Instance, zone: &str, env: &str) -> Vec<(&'static str, String)> {
+    let mut metadata = ins.metadata.clone();
+    if !ins.group.is_empty() {
+        metadata.insert("group".to_owned(), ins.group.to_string());
+    }
+    let mut params = vec![
+        ("zone", zone.to_owned()),
+        ("env", env.to_owned()),
+        ("appid", ins.appid.to_string()),
+        ("hostname", hostname(ins)),
+        ("status", STATUS_UP.to_string()),
+        ("version", ins.version.clone()),
+        (
+            "metadata",
+            serde_json::to_string(&metadata).unwrap_or_default(),
+        ),
+    ];
+    params.extend(ins.addrs.iter().map(|addr| ("addrs", addr.clone())));
+    params
+}
+
+// the instance of an instance of an answer, with its timestamps in
+// nanoseconds.
+fn from_json(value: &Value) -> Option<Instance> {
+    let string = |name: &str| value[name].as_str().unwrap_or("").to_owned();
+    let time = |name: &str| {
+        let nanos = value[name].as_u64().filter(|nanos| *nanos > 0)?;
+        Some(UNIX_EPOCH + Duration::from_nanos(nanos))
+    };
+    let mut metadata: HashMap<String, String> = match &value["metadata"] {
+        Value::Null => HashMap::new(),
+        metadata => serde_json::from_value(metadata.clone()).ok()?,
+    };
+    let group = metadata.remove("group").unwrap_or_default();
+    Some(Instance {
+        zone: intern(&string("zone")),
+        env: intern(&string("env")),
+        appid: intern(value["appid"].as_str()?),
+        hostname: string("hostname"),
+        addrs: serde_json::from_value::<Vec<String>>(value["addrs"].clone())
+            .ok()?
+            .into(),
+        version: string("version"),
+        group: intern(&group),
+        metadata,
+        registered_at: time("reg_timestamp"),
+        last_renewed_at: time("renew_timestamp"),
+        ..Default::default()
+    })
+}
+
+// the instances up of the answer for an app, in every zone.
+fn instances_up(app: &Value) -> Vec<Instance> {
+    app["instances"]
+        .as_object()
+        .into_iter()
+        .flat_map(|zones| zones.values())
+        .filter_map(Value::as_array)
+        .flatten()
+        .filter(|ins| ins["status"].as_u64() == Some(STATUS_UP))
+        .filter_map(from_json)
+        .collect()
+}
+
+#[derive(Debug)]
+pub enum BilibiliError {
+    InvalidAddress(String),
+    Http(hyper::Error),
+    /// The server answered with an error status.
+    Status(StatusCode, String),
+    Json(serde_json::Error),
+    /// The server answered with an error code, e.g. -404 when the instance
+    /// isn't registered.
+    Code(i64, String),
+    /// Discovery can't update instances only at a given version, see
+    /// `Registry::update_if`.
+    Unsupported,
+}
+
+impl fmt::Display for BilibiliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BilibiliError::InvalidAddress(e) => write!(f, "invalid discovery address: {}", e),
+            BilibiliError::Http(e) => write!(f, "failed to call discovery: {}", e),
+            BilibiliError::Status(status, message) => {
+                write!(f, "discovery answered {}: {}", status, message)
+            }
+            BilibiliError::Json(e) => write!(f, "bad json from discovery: {}", e),
+            BilibiliError::Code(code, message) => {
+                write!(f, "discovery answered code {}: {}", code, message)
+            }
+            BilibiliError::Unsupported => write!(f, "unsupported by discovery"),
+        }
+    }
+}
+
+impl error::Error for BilibiliError {}
+
+impl From<hyper::Error> for BilibiliError {
+    fn from(e: hyper::Error) -> Self {
+        BilibiliError::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for BilibiliError {
+    fn from(e: serde_json::Error) -> Self {
+        BilibiliError::Json(e)
+    }
+}
+
+impl<I> Registry for Bilibili<I>
+where
+    I: Identity + Send + Sync + 'static,
+    I::Key: Send + Sync,
+{
+    type Error = BilibiliError;
+
+    type RegFuture = BoxFuture<'static, Result<(), BilibiliError>>;
+
+    type DeRegFuture = BoxFuture<'static, Result<(), BilibiliError>>;
+
+    type ListFuture = BoxFuture<'static, Result<Vec<Arc<Instance>>, BilibiliError>>;
+
+    type Watcher = BilibiliWatcher;
+
+    fn register(&self, ins: Arc<Instance>) -> Self::RegFuture {
+        let client = self.client.clone();
+        let registered = self.registered.clone();
+        let (zone, env) = self.zone_env(&ins);
+        let params = to_params(&ins, zone, env);
+        let key = (ins.appid.to_string(), env.to_owned(), hostname(&ins));
+        async move {
+            client
+                .call(Method::POST, "/discovery/register", &params)
+                .await?;
+            registered.lock().unwrap().insert(key, params);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn deregister(&self, ins: &Arc<Instance>) -> Self::DeRegFuture {
+        let client = self.client.clone();
+        let (zone, env) = self.zone_env(ins);
+        let params = vec![
+            ("zone", zone.to_owned()),
+            ("env", env.to_owned()),
+            ("appid", ins.appid.to_string()),
+            ("hostname", hostname(ins)),
+        ];
+        let key = (ins.appid.to_string(), env.to_owned(), hostname(ins));
+        self.registered.lock().unwrap().remove(&key);
+        async move {
+            client
+                .call(Method::POST, "/discovery/cancel", &params)
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    // discovery doesn't tell why an instance went away, so watchers report
+    // evictions as deletes without a reason.
+    fn evict(&self, appid: &str, instance_id: &str) -> Self::DeRegFuture {
+        let client = self.client.clone();
+        let query = vec![
+            ("appid", appid.to_owned()),
+            ("env", self.env.clone()),
+            ("status", STATUS_UP.to_string()),
+        ];
+        let instance_id = instance_id.to_owned();
+        async move {
+            let app = client.call(Method::GET, "/discovery/fetch", &query).await?;
+            for ins in instances_up(&app) {
+                if !ins.has_id(&instance_id) {
+                    continue;
+                }
+                let params = vec![
+                    ("zone", ins.zone.to_string()),
+                    ("env", ins.env.to_string()),
+                    ("appid", ins.appid.to_string()),
+                    ("hostname", ins.hostname.clone()),
+                ];
+                match client
+                    .call(Method::POST, "/discovery/cancel", &params)
+                    .await
+                {
+                    // cancelled meanwhile.
+                    Ok(_) | Err(BilibiliError::Code(NOTHING_FOUND, _)) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    // discovery has no check-and-set on instances.
+    fn update_if(&self, _ins: Arc<Instance>, _expected_version: u64) -> Self::RegFuture {
+        future::ready(Err(BilibiliError::Unsupported)).boxed()
+    }
+
+    fn renew(&self, ins: &Arc<Instance>) -> Self::RegFuture {
+        let client = self.client.clone();
+        let (zone, env) = self.zone_env(ins);
+        let params = to_params(ins, zone, env);
+        async move { renew_one(&client, &params).await }.boxed()
+    }
+
+    fn list(&self, appid: &str) -> Self::ListFuture {
+        let client = self.client.clone();
+        let query = vec![
+            ("appid", appid.to_owned()),
+            ("env", self.env.clone()),
+            ("status", STATUS_UP.to_string()),
+        ];
+        async move {
+            match client.call(Method::GET, "/discovery/fetch", &query).await {
+                Ok(app) => Ok(instances_up(&app).into_iter().map(Arc::new).collect()),
+                // no instance registered.
+                Err(BilibiliError::Code(NOTHING_FOUND, _)) => Ok(Vec::new()),
+                Err(e) => Err(e),
+            }
+        }
+        .boxed()
+    }
+
+    fn watch(&self, appid: &str) -> Self::Watcher {
+        self.watch_from(appid, &[])
+    }
+
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher {
+        let (tx, rx) = mpsc::unbounded();
+        let mut known = HashMap::new();
+        for ins in instances {
+            known.insert(self.identity.identify(ins), ins.clone());
+            let event = WatchEvent::with_clock(Event::Create(ins.clone()), &*self.clock);
+            let _ = tx.unbounded_send(event);
+        }
+        let hostname = hostname::get()
+            .map(|hostname| hostname.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let watch = AppWatch {
+            client: self.client.clone(),
+            appid: appid.to_owned(),
+            env: self.env.clone(),
+            hostname,
+            identity: self.identity.clone(),
+            clock: self.clock.clone(),
+            known,
+            tx,
+        };
+        BilibiliWatcher {
+            rx,
+            _task: runtime::spawn_abortable(watch.run()),
+        }
+    }
+}
+
+/// The instances of an app in discovery, see `Registry::watch`.
+#[pin_project]
+pub struct BilibiliWatcher {
+    #[pin]
+    rx: UnboundedReceiver<WatchEvent>,
+    _task: AbortOnDrop,
+}
+
+impl Stream for BilibiliWatcher {
+    type Item = WatchEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().rx.poll_next(cx)
+    }
+}
+
+struct AppWatch<I: Identity> {
+    client: Client,
+    appid: String,
+    env: String,
+    // of the process, the server wants to know who polls.
+    hostname: String,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+    // the instances reported.
+    known: HashMap<I::Key, Arc<Instance>>,
+    tx: UnboundedSender<WatchEvent>,
+}
+
+impl<I> AppWatch<I>
+where
+    I: Identity,
+{
+    async fn run(mut self) {
+        // the first poll answers at once.
+        let mut latest_timestamp = 0;
+        loop {
+            let query = vec![
+                ("appid", self.appid.to_owned()),
+                ("env", self.env.clone()),
+                ("hostname", self.hostname.clone()),
+                ("latest_timestamp", latest_timestamp.to_string()),
+            ];
+            // held by the server until the app changes, or for 30 seconds.
+            match self
+                .client
+                .call(Method::GET, "/discovery/polls", &query)
+                .await
+            {
+                Ok(apps) => {
+                    let app = &apps[self.appid.as_str()];
+                    latest_timestamp = app["latest_timestamp"].as_i64().unwrap_or(0);
+                    self.update(instances_up(app));
+                }
+                Err(BilibiliError::Code(NOT_MODIFIED, _)) => {}
+                Err(e) => {
+                    error!("failed to watch {}. {}", self.appid, e);
+                    runtime::delay_for(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    // reports the instances up now and not before as Creates, the ones
+    // changed too, and the ones gone as Deletes.
+    fn update(&mut self, instances: Vec<Instance>) {
+        let mut gone = std::mem::take(&mut self.known);
+        for ins in instances {
+            let ins = Arc::new(ins);
+            let key = self.identity.identify(&ins);
+            let unchanged = gone.remove(&key).filter(|old| *old == ins);
+            if unchanged.is_none() {
+                self.send(Event::Create(ins.clone()));
+            }
+            self.known.insert(key, ins);
+        }
+        for (_, ins) in gone {
+            self.send(Event::Delete(ins));
+        }
+    }
+
+    fn send(&self, event: Event) {
+        let _ = self
+            .tx
+            .unbounded_send(WatchEvent::with_clock(event, &*self.clock));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{instances_up, to_params};
+    use crate::{intern::intern, Instance};
+    use serde_json::json;
+
+    #[test]
+    fn test_instances() {
+        let params = to_params(
+            &Instance {
+                appid: intern("main.account.service"),
+                hostname: "account-1".to_owned(),
+                addrs: vec!["grpc://10.0.0.1:9000".to_owned()].into(),
+                group: intern("blue"),
+                ..Default::default()
+            },
+            "sh001",
+            "prod",
+        );
+        assert_eq!(params[3], ("hostname", "account-1".to_owned()));
+        assert_eq!(params[6], ("metadata", r#"{"group":"blue"}"#.to_owned()));
+        assert_eq!(params[7], ("addrs", "grpc://10.0.0.1:9000".to_owned()));
+
+        let app = json!({
+            "instances": {
+                "sh001": [
+                    {
+                        "zone": "sh001",
+                        "env": "prod",
+                        "appid": "main.account.service",
+                        "hostname": "account-1",
+                        "addrs": ["grpc://10.0.0.1:9000"],
+                        "version": "1.2.0",
+                        "metadata": {"group": "blue", "weight": "10"},
+                        "status": 1,
+                        "reg_timestamp": 1_590_000_000_123_000_000u64,
+                    },
+                    {"appid": "main.account.service", "addrs": [], "status": 2},
+                ],
+            },
+            "latest_timestamp": 1_590_000_000_123_000_000u64,
+        });
+        let instances = instances_up(&app);
+        assert_eq!(instances.len(), 1);
+        let ins = &instances[0];
+        assert_eq!((&*ins.zone, &*ins.group), ("sh001", "blue"));
+        assert_eq!(ins.metadata["weight"], "10");
+        assert!(ins.registered_at.is_some());
+        assert!(ins.last_renewed_at.is_none());
+    }
+}
@@ -0,0 +1,169 @@
+//! Registries behind one type, to pick the backend at runtime.
+use crate::{watcher::WatchEvent, Instance, Registry};
+use futures::{
+    future::BoxFuture,
+    stream::{BoxStream, StreamExt},
+    FutureExt, TryFutureExt,
+};
+use std::{error, sync::Arc};
+
+pub type BoxError = Box<dyn error::Error + Send + Sync>;
+
+/// Any registry, see `boxed`.
+pub type BoxRegistry = Box<
+    dyn Registry<
+            Error = BoxError,
+            RegFuture = BoxFuture<'static, Result<(), BoxError>>,
+            DeRegFuture = BoxFuture<'static, Result<(), BoxError>>,
+            ListFuture = BoxFuture<'static, Result<Vec<Arc<Instance>>, BoxError>>,
+            Watcher = BoxStream<'static, WatchEvent>,
+        > + Send
+        + Sync,
+>;
+
+/// Boxes `registry` and its futures, watchers and errors.
+pub fn boxed<R>(registry: R) -> BoxRegistry
+where
+    R: Registry + Send + Sync + 'static,
+    R::Error: Into<BoxError> + 'static,
+    R::RegFuture: Send + 'static,
+    R::DeRegFuture: Send + 'static,
+    R::ListFuture: Send + 'static,
+    R::Watcher: Send + 'static,
+{
+    Box::new(Boxed(registry))
+}
+
+struct Boxed<R>(R);
+
+impl<R> Registry for Boxed<R>
+where
+    R: Registry,
+    R::Error: Into<BoxError> + 'static,
+    R::RegFuture: Send + 'static,
+    R::DeRegFuture: Send + 'static,
+    R::ListFuture: Send + 'static,
+    R::Watcher: Send + 'static,
+{
+    type Error = BoxError;
+
+    type RegFuture = BoxFuture<'static, Result<(), BoxError>>;
+
+    type DeRegFuture = BoxFuture<'static, Result<(), BoxError>>;
+
+    type ListFuture = BoxFuture<'static, Result<Vec<Arc<Instance>>, BoxError>>;
+
+    type Watcher = BoxStream<'static, WatchEvent>;
+
+    fn register(&self, ins: Arc<Instance>) -> Self::RegFuture {
+        self.0.register(ins).map_err(Into::into).boxed()
+    }
+
+    fn deregister(&self, ins: &Arc<Instance>) -> Self::DeRegFuture {
+        self.0.deregister(ins).map_err(Into::into).boxed()
+    }
+
+    fn evict(&self, appid: &str, instance_id: &str) -> Self::DeRegFuture {
+        self.0.evict(appid, instance_id).map_err(Into::into).boxed()
+    }
+
+    fn update_if(&self, ins: Arc<Instance>, expected_version: u64) -> Self::RegFuture {
+        self.0
+            .update_if(ins, expected_version)
+            .map_err(Into::into)
+            .boxed()
+    }
+
+    fn renew(&self, ins: &Arc<Instance>) -> Self::RegFuture {
+        self.0.renew(ins).map_err(Into::into).boxed()
+    }
+
+    fn list(&self, appid: &str) -> Self::ListFuture {
+        self.0.list(appid).map_err(Into::into).boxed()
+    }
+
+    fn watch(&self, appid: &str) -> Self::Watcher {
+        self.0.watch(appid).boxed()
+    }
+
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher {
+        self.0.watch_from(appid, instances).boxed()
+    }
+}
+
+impl<R> Registry for Box<R>
+where
+    R: Registry + ?Sized,
+{
+    type Error = R::Error;
+
+    type RegFuture = R::RegFuture;
+
+    type DeRegFuture = R::DeRegFuture;
+
+    type ListFuture = R::ListFuture;
+
+    type Watcher = R::Watcher;
+
+    fn register(&self, ins: Arc<Instance>) -> Self::RegFuture {
+        (**self).register(ins)
+    }
+
+    fn deregister(&self, ins: &Arc<Instance>) -> Self::DeRegFuture {
+        (**self).deregister(ins)
+    }
+
+    fn evict(&self, appid: &str, instance_id: &str) -> Self::DeRegFuture {
+        (**self).evict(appid, instance_id)
+    }
+
+    fn update_if(&self, ins: Arc<Instance>, expected_version: u64) -> Self::RegFuture {
+        (**self).update_if(ins, expected_version)
+    }
+
+    fn renew(&self, ins: &Arc<Instance>) -> Self::RegFuture {
+        (**self).renew(ins)
+    }
+
+    fn list(&self, appid: &str) -> Self::ListFuture {
+        (**self).list(appid)
+    }
+
+    fn watch(&self, appid: &str) -> Self::Watcher {
+        (**self).watch(appid)
+    }
+
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher {
+        (**self).watch_from(appid, instances)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::boxed;
+    use crate::{
+        testing::{expect_create, MockRegistry},
+        Instance, Registry,
+    };
+    use std::{sync::Arc, time::Duration};
+
+    #[tokio::test]
+    async fn test_boxed() {
+        let mock = MockRegistry::new();
+        let registry = boxed(mock.clone());
+        let ins = Arc::new(Instance {
+            appid: "provider".into(),
+            addrs: vec!["grpc://172.1.1.1:9999".to_owned()].into(),
+            ..Default::default()
+        });
+        registry.register(ins.clone()).await.unwrap();
+        mock.assert_registered(&ins);
+        let mut watcher = registry.watch("provider");
+        let created = expect_create(&mut watcher, |_| true, Duration::from_millis(10)).await;
+        assert_eq!(created, ins);
+
+        mock.fail_register("zk is down");
+        let err = registry.register(ins).await.unwrap_err();
+        assert_eq!(err.to_string(), "mock registry error: zk is down");
+    }
+}
@@ -0,0 +1,259 @@
+//! One appid watched across several independent registries, e.g. a ZooKeeper
+//! ensemble per datacenter, as a single watcher.
+//!
+//! ```ignore
+//! let sh_hooks = Hooks::new();
+//! let sh = Zk::builder(sh_urls).hooks(sh_hooks.clone()).build().await?;
+//! let clusters = MultiCluster::new()
+//!     .cluster_with_hooks("sh", boxed(sh), &sh_hooks)
+//!     .cluster("bj", boxed(bj));
+//! let providers = clusters.watch("/dubbo-rs/provider");
+//! ```
+use crate::{
+    boxed::BoxRegistry,
+    hooks::{ConnectionState, Hooks},
+    identity::{DefaultIdentity, Identity},
+    watcher::{Event, WatchEvent},
+    Instance,
+};
+use futures::{
+    ready,
+    stream::{self, BoxStream, SelectAll},
+    Stream, StreamExt,
+};
+use pin_project::pin_project;
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+};
+
+/// The metadata key instances are labeled with the name of their cluster
+/// under.
+pub const CLUSTER_KEY: &str = "cluster";
+
+struct Cluster {
+    name: Arc<str>,
+    registry: BoxRegistry,
+    state: Arc<RwLock<ConnectionState>>,
+}
+
+/// Registries watched together, see the module docs.
+pub struct MultiCluster<I = DefaultIdentity> {
+    clusters: Vec<Cluster>,
+    identity: I,
+}
+
+impl MultiCluster {
+    pub fn new() -> Self {
+        MultiCluster {
+            clusters: Vec::new(),
+            identity: DefaultIdentity,
+        }
+    }
+}
+
+impl Default for MultiCluster {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I> MultiCluster<I> {
+    /// Tells instances of different clusters apart by `identity`, the same
+    /// instance in two clusters is reported once.
+    pub fn with_identity<NI>(self, identity: NI) -> MultiCluster<NI> {
+        MultiCluster {
+            clusters: self.clusters,
+            identity,
+        }
+    }
+
+    /// Adds the cluster `name`. Its connection is assumed healthy.
+    pub fn cluster(mut self, name: &str, registry: BoxRegistry) -> Self {
+        self.clusters.push(Cluster {
+            name: name.into(),
+            registry,
+            state: Arc::new(RwLock::new(ConnectionState::Connected)),
+        });
+        self
+    }
+
+    /// Adds the cluster `name`, its connection health tracked by `hooks`, the
+    /// hooks its registry was built with.
+    pub fn cluster_with_hooks(self, name: &str, registry: BoxRegistry, hooks: &Hooks) -> Self {
+        let this = self.cluster(name, registry);
+        let state = this.clusters.last().unwrap().state.clone();
+        hooks.on_connection_change(move |changed| *state.write().unwrap() = changed);
+        this
+    }
+
+    /// The connection state of each cluster, in the order they were added.
+    pub fn health(&self) -> Vec<(Arc<str>, ConnectionState)> {
+        self.clusters
+            .iter()
+            .map(|cluster| (cluster.name.clone(), *cluster.state.read().unwrap()))
+            .collect()
+    }
+
+    /// Watches `appid` in every cluster, its instances labeled with their
+    /// cluster under `CLUSTER_KEY`.
+    pub fn watch(&self, appid: &str) -> MultiClusterWatcher<I>
+    where
+        I: Identity + Clone,
+    {
+        let watchers = self.clusters.iter().enumerate().map(|(i, cluster)| {
+            cluster
+                .registry
+                .watch(appid)
+                .map(move |watch_event| (i, watch_event))
+                .boxed()
+        });
+        MultiClusterWatcher {
+            watchers: stream::select_all(watchers),
+            names: self.clusters.iter().map(|c| c.name.clone()).collect(),
+            identity: self.identity.clone(),
+            present: HashMap::new(),
+        }
+    }
+}
+
+/// See `MultiCluster::watch`.
+#[pin_project]
+pub struct MultiClusterWatcher<I = DefaultIdentity>
+where
+    I: Identity,
+{
+    watchers: SelectAll<BoxStream<'static, (usize, WatchEvent)>>,
+    names: Vec<Arc<str>>,
+    identity: I,
+    // the clusters each instance is in, the labeled instance of each.
+    present: HashMap<I::Key, Vec<(usize, Arc<Instance>)>>,
+}
+
+impl<I> Stream for MultiClusterWatcher<I>
+where
+    I: Identity,
+{
+    type Item = WatchEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        loop {
+            let (cluster, watch_event) = match ready!(this.watchers.poll_next_unpin(cx)) {
+                Some(next) => next,
+                None => return Poll::Ready(None),
+            };
+            let key = match &watch_event.event {
+                Event::Create(ins) | Event::Update(ins) | Event::Delete(ins) => {
+                    this.identity.identify(ins)
+                }
+            };
+            let event = match &watch_event.event {
+                Event::Create(ins) | Event::Update(ins) => {
+                    let labeled = label(ins, &this.names[cluster]);
+                    let present = this.present.entry(key).or_default();
+                    present.retain(|(c, _)| *c != cluster);
+                    present.push((cluster, labeled.clone()));
+                    if matches!(watch_event.event, Event::Update(_)) {
+                        Event::Update(labeled)
+                    } else {
+                        Event::Create(labeled)
+                    }
+                }
+                Event::Delete(_) => {
+                    let present = match this.present.get_mut(&key) {
+                        Some(present) => present,
+                        None => continue,
+                    };
+                    let deleted = match present.iter().position(|(c, _)| *c == cluster) {
+                        Some(pos) => present.remove(pos).1,
+                        None => continue,
+                    };
+                    // still in another cluster, which takes its place.
+                    match present.last() {
+                        Some((_, other)) => Event::Create(other.clone()),
+                        None => {
+                            this.present.remove(&key);
+                            Event::Delete(deleted)
+                        }
+                    }
+                }
+            };
+            return Poll::Ready(Some(WatchEvent {
+                event,
+                ..watch_event
+            }));
+        }
+    }
+}
+
+fn label(ins: &Instance, cluster: &str) -> Arc<Instance> {
+    let mut labeled = ins.clone();
+    labeled
+        .metadata
+        .insert(CLUSTER_KEY.to_owned(), cluster.to_owned());
+    Arc::new(labeled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MultiCluster, CLUSTER_KEY};
+    use crate::{
+        boxed::boxed,
+        hooks::{ConnectionState, Hooks},
+        testing::{instance, MockRegistry},
+        watcher::Event,
+    };
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn test_multi_cluster() {
+        let (sh, bj) = (MockRegistry::new(), MockRegistry::new());
+        let bj_hooks = Hooks::new();
+        let clusters = MultiCluster::new()
+            .cluster("sh", boxed(sh.clone()))
+            .cluster_with_hooks("bj", boxed(bj.clone()), &bj_hooks);
+        let mut watcher = clusters.watch("provider").map(|e| match e.event {
+            Event::Create(ins) => format!("create {} {}", ins.addrs[0], ins.metadata[CLUSTER_KEY]),
+            Event::Update(ins) => format!("update {} {}", ins.addrs[0], ins.metadata[CLUSTER_KEY]),
+            Event::Delete(ins) => format!("delete {} {}", ins.addrs[0], ins.metadata[CLUSTER_KEY]),
+        });
+
+        sh.insert(instance("grpc://172.1.1.1:9999"));
+        assert_eq!(
+            watcher.next().await.unwrap(),
+            "create grpc://172.1.1.1:9999 sh"
+        );
+        bj.insert(instance("grpc://172.1.1.1:9999"));
+        assert_eq!(
+            watcher.next().await.unwrap(),
+            "create grpc://172.1.1.1:9999 bj"
+        );
+        bj.remove(&instance("grpc://172.1.1.1:9999"));
+        assert_eq!(
+            watcher.next().await.unwrap(),
+            "create grpc://172.1.1.1:9999 sh"
+        );
+        sh.remove(&instance("grpc://172.1.1.1:9999"));
+        assert_eq!(
+            watcher.next().await.unwrap(),
+            "delete grpc://172.1.1.1:9999 sh"
+        );
+
+        bj_hooks.connection_changed(ConnectionState::Disconnected);
+        let health = clusters
+            .health()
+            .into_iter()
+            .map(|(name, state)| (name.to_string(), state))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            health,
+            vec![
+                ("sh".to_owned(), ConnectionState::Connected),
+                ("bj".to_owned(), ConnectionState::Disconnected),
+            ]
+        );
+    }
+}
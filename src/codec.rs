@@ -1,15 +1,82 @@
-use crate::Instance;
+use crate::{intern, Instance};
 use fmt::{Debug, Display};
 use lazy_static::lazy_static;
 use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet};
-use std::{fmt, str::Utf8Error};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    error::Error,
+    fmt,
+    num::ParseIntError,
+    str::Utf8Error,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub use compressed::{compressed, Compressed, CompressedCodec, CompressedError, Compression};
+#[cfg(feature = "gzip")]
+pub use compressed::Gzip;
+pub use dubbo::{DubboCodecError, DubboDecoder, DubboEncoder};
+#[cfg(feature = "json")]
+pub use json::{JsonCodecError, JsonDecoder, JsonEncoder};
+#[cfg(feature = "msgpack")]
+pub use msgpack::{MsgPackCodecError, MsgPackDecoder, MsgPackEncoder};
+pub use multi::{MultiCodec, MultiCodecError, MultiDecoder, MultiEncoder};
+#[cfg(feature = "protobuf")]
+pub use proto::{ProtoCodecError, ProtoDecoder, ProtoEncoder};
+pub use spring::{
+    service_instance_id, SpringCloudCodecError, SpringCloudDecoder, SpringCloudEncoder,
+};
+
+mod compressed;
+mod dubbo;
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "msgpack")]
+mod msgpack;
+mod multi;
+#[cfg(feature = "protobuf")]
+mod proto;
+mod spring;
+
+/// An instance couldn't be encoded, with the error of the encoder.
+#[derive(Debug)]
+pub struct EncodeError(Box<dyn Error + Send + Sync>);
+
+impl EncodeError {
+    pub fn new<E>(cause: E) -> Self
+    where
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        EncodeError(cause.into())
+    }
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to encode the instance: {}", self.0)
+    }
+}
 
-pub struct EncodeError {}
+impl Error for EncodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.0)
+    }
+}
 
+/// Implement `encode_to`, and `encode` when it has a quicker way to a new
+/// buffer.
 pub trait Encoder {
     type Error: Into<EncodeError> + Display + Debug;
 
-    fn encode(&self, ins: &Instance) -> Result<Vec<u8>, Self::Error>;
+    fn encode(&self, ins: &Instance) -> Result<Vec<u8>, Self::Error> {
+        let mut buf = Vec::new();
+        self.encode_to(ins, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Appends the encoded instance to `buf`, e.g. a buffer reused from one
+    /// instance to the next.
+    fn encode_to(&self, ins: &Instance, buf: &mut Vec<u8>) -> Result<(), Self::Error>;
 }
 
 impl<F, E> Encoder for F
@@ -21,20 +88,194 @@ where
     fn encode(&self, ins: &Instance) -> Result<Vec<u8>, Self::Error> {
         self(ins)
     }
+
+    fn encode_to(&self, ins: &Instance, buf: &mut Vec<u8>) -> Result<(), Self::Error> {
+        buf.extend_from_slice(&self(ins)?);
+        Ok(())
+    }
+}
+
+// the bytes of the payload kept by a `DecodeError`.
+const MAX_PAYLOAD_LEN: usize = 256;
+
+/// An instance couldn't be decoded, with the error of the decoder and what is
+/// known of where it failed, to find the registrant writing bad payloads.
+#[derive(Debug)]
+pub struct DecodeError {
+    cause: Box<dyn Error + Send + Sync>,
+    field: Option<&'static str>,
+    offset: Option<usize>,
+    // the first `MAX_PAYLOAD_LEN` bytes of the payload.
+    payload: Vec<u8>,
+    payload_len: usize,
+}
+
+impl DecodeError {
+    pub fn new<E>(cause: E) -> Self
+    where
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        DecodeError {
+            cause: cause.into(),
+            field: None,
+            offset: None,
+            payload: Vec::new(),
+            payload_len: 0,
+        }
+    }
+
+    /// Names the field of the instance that failed to decode.
+    pub fn with_field(mut self, field: &'static str) -> Self {
+        self.field = Some(field);
+        self
+    }
+
+    /// The offset in the payload where decoding failed.
+    pub fn with_offset(mut self, offset: usize) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Keeps the payload that failed to decode, truncated.
+    pub fn with_payload(mut self, data: &[u8]) -> Self {
+        self.payload = data[..data.len().min(MAX_PAYLOAD_LEN)].to_vec();
+        self.payload_len = data.len();
+        self
+    }
+
+    pub fn field(&self) -> Option<&'static str> {
+        self.field
+    }
+
+    pub fn offset(&self) -> Option<usize> {
+        self.offset
+    }
+
+    /// The start of the payload, empty unless given with `with_payload`.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to decode the instance: {}", self.cause)?;
+        if let Some(field) = self.field {
+            write!(f, ", field {}", field)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, ", at byte {}", offset)?;
+        }
+        if self.payload_len > 0 {
+            write!(f, ", payload \"{}\"", self.payload.escape_ascii())?;
+            if self.payload_len > self.payload.len() {
+                write!(f, "... ({} bytes)", self.payload_len)?;
+            }
+        }
+        Ok(())
+    }
 }
 
-pub struct DecodeErorr {}
+impl Error for DecodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.cause)
+    }
+}
+
+/// Renamed to `DecodeError`.
+#[deprecated(note = "renamed to DecodeError")]
+pub type DecodeErorr = DecodeError;
+
+/// Decodes `data`, keeping it in the error to tell which payload is bad, e.g.
+/// when a watcher logs the error.
+#[cfg(any(test, feature = "zk", feature = "etcd", feature = "redis"))]
+pub(crate) fn decode_with_payload<D>(decoder: &D, data: &[u8]) -> Result<Instance, DecodeError>
+where
+    D: Decoder,
+{
+    decoder.decode(data).map_err(|e| {
+        let e: DecodeError = e.into();
+        e.with_payload(data)
+    })
+}
 
 pub trait Decoder {
-    type Error: Into<DecodeErorr> + Display + Debug;
+    type Error: Into<DecodeError> + Display + Debug;
 
     fn decode(&self, data: &[u8]) -> Result<Instance, Self::Error>;
+
+    /// Decodes an instance borrowing from `data` where the encoding allows,
+    /// to look at instances without keeping them. Decodes an owned instance
+    /// unless the decoder knows better.
+    fn decode_ref<'a>(&self, data: &'a [u8]) -> Result<InstanceRef<'a>, Self::Error> {
+        self.decode(data).map(InstanceRef::from)
+    }
+}
+
+/// An `Instance` whose fields may borrow from the data it was decoded from.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InstanceRef<'a> {
+    pub zone: Cow<'a, str>,
+    pub env: Cow<'a, str>,
+    pub appid: Cow<'a, str>,
+    pub hostname: Cow<'a, str>,
+    pub addrs: Vec<Cow<'a, str>>,
+    pub version: Cow<'a, str>,
+    pub group: Cow<'a, str>,
+    pub metadata: HashMap<Cow<'a, str>, Cow<'a, str>>,
+    pub registered_at: Option<SystemTime>,
+    pub last_renewed_at: Option<SystemTime>,
+    pub revision: Option<u64>,
+}
+
+impl InstanceRef<'_> {
+    pub fn into_owned(self) -> Instance {
+        Instance {
+            zone: intern(&self.zone),
+            env: intern(&self.env),
+            appid: intern(&self.appid),
+            hostname: self.hostname.into_owned(),
+            addrs: self.addrs.into_iter().map(Cow::into_owned).collect(),
+            version: self.version.into_owned(),
+            group: intern(&self.group),
+            metadata: self
+                .metadata
+                .into_iter()
+                .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                .collect(),
+            registered_at: self.registered_at,
+            last_renewed_at: self.last_renewed_at,
+            revision: self.revision,
+        }
+    }
+}
+
+impl From<Instance> for InstanceRef<'_> {
+    fn from(ins: Instance) -> Self {
+        InstanceRef {
+            zone: ins.zone.to_string().into(),
+            env: ins.env.to_string().into(),
+            appid: ins.appid.to_string().into(),
+            hostname: ins.hostname.into(),
+            addrs: ins.addrs.into_iter().map(Cow::Owned).collect(),
+            version: ins.version.into(),
+            group: ins.group.to_string().into(),
+            metadata: ins
+                .metadata
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+            registered_at: ins.registered_at,
+            last_renewed_at: ins.last_renewed_at,
+            revision: ins.revision,
+        }
+    }
 }
 
 impl<F, E> Decoder for F
 where
     F: Fn(&[u8]) -> Result<Instance, E>,
-    E: Into<DecodeErorr> + Display + Debug,
+    E: Into<DecodeError> + Display + Debug,
 {
     type Error = E;
     fn decode(&self, data: &[u8]) -> Result<Instance, Self::Error> {
@@ -73,31 +314,59 @@ const URL_ENCODE_SET: &AsciiSet = &percent_encoding::NON_ALPHANUMERIC
 
 #[derive(Debug)]
 pub enum DefaultCodecError {
-    UTF8(Utf8Error),
-    MetadataSerde(serde_json::Error),
+    /// The payload, or a value in it once unescaped, isn't utf8, with the
+    /// offset in the payload of where.
+    UTF8(Utf8Error, usize),
+    /// With the offset in the payload of the metadata, when decoding.
+    MetadataSerde(serde_json::Error, Option<usize>),
+    /// A timestamp, by its field and offset in the payload, isn't a number.
+    Timestamp(&'static str, ParseIntError, usize),
 }
 
 impl fmt::Display for DefaultCodecError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "DefaultCodecError")
+        match self {
+            DefaultCodecError::UTF8(e, _) => write!(f, "instance is not utf8: {}", e),
+            DefaultCodecError::MetadataSerde(e, _) => write!(f, "bad instance metadata: {}", e),
+            DefaultCodecError::Timestamp(_, e, _) => write!(f, "bad instance timestamp: {}", e),
+        }
+    }
+}
+
+impl Error for DefaultCodecError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DefaultCodecError::UTF8(e, _) => Some(e),
+            DefaultCodecError::MetadataSerde(e, _) => Some(e),
+            DefaultCodecError::Timestamp(_, e, _) => Some(e),
+        }
     }
 }
 
 impl From<Utf8Error> for DefaultCodecError {
     fn from(e: Utf8Error) -> Self {
-        DefaultCodecError::UTF8(e)
+        DefaultCodecError::UTF8(e, e.valid_up_to())
     }
 }
 
 impl From<DefaultCodecError> for EncodeError {
-    fn from(_: DefaultCodecError) -> Self {
-        todo!()
+    fn from(e: DefaultCodecError) -> Self {
+        EncodeError::new(e)
     }
 }
 
-impl From<DefaultCodecError> for DecodeErorr {
-    fn from(_: DefaultCodecError) -> Self {
-        todo!()
+impl From<DefaultCodecError> for DecodeError {
+    fn from(e: DefaultCodecError) -> Self {
+        match e {
+            DefaultCodecError::MetadataSerde(_, Some(offset)) => DecodeError::new(e)
+                .with_field("metadata")
+                .with_offset(offset),
+            DefaultCodecError::MetadataSerde(_, None) => DecodeError::new(e).with_field("metadata"),
+            DefaultCodecError::Timestamp(field, _, offset) => {
+                DecodeError::new(e).with_field(field).with_offset(offset)
+            }
+            DefaultCodecError::UTF8(_, offset) => DecodeError::new(e).with_offset(offset),
+        }
     }
 }
 
@@ -106,29 +375,54 @@ pub struct DefaultEncoder;
 impl Encoder for DefaultEncoder {
     type Error = DefaultCodecError;
 
-    fn encode(&self, ins: &Instance) -> Result<Vec<u8>, Self::Error> {
-        let mut s = String::new();
-        s.push_str("zone=");
-        s.extend(utf8_percent_encode(&ins.zone, URL_ENCODE_SET));
-        s.push_str("&env=");
-        s.extend(utf8_percent_encode(&ins.env, URL_ENCODE_SET));
-        s.push_str("&appid=");
-        s.extend(utf8_percent_encode(&ins.appid, URL_ENCODE_SET));
-        s.push_str("&hostname=");
-        s.extend(utf8_percent_encode(&ins.hostname, URL_ENCODE_SET));
+    fn encode_to(&self, ins: &Instance, buf: &mut Vec<u8>) -> Result<(), Self::Error> {
+        let metadata = serde_json::to_string(&ins.metadata)
+            .map_err(|e| DefaultCodecError::MetadataSerde(e, None))?;
+        // most characters are left as is, the separators are percent-encoded.
+        let fields = [
+            &*ins.zone,
+            &ins.env,
+            &ins.appid,
+            &ins.hostname,
+            &ins.version,
+            &ins.group,
+        ];
+        buf.reserve(
+            128 + fields.iter().map(|field| field.len()).sum::<usize>()
+                + ins.addrs.iter().map(|addr| 16 + addr.len() * 3 / 2).sum::<usize>()
+                + metadata.len() * 2,
+        );
+        let mut first = true;
+        let mut push = |name: &str, value: &str| {
+            if !first {
+                buf.push(b'&');
+            }
+            first = false;
+            buf.extend_from_slice(name.as_bytes());
+            buf.push(b'=');
+            for chunk in utf8_percent_encode(value, URL_ENCODE_SET) {
+                buf.extend_from_slice(chunk.as_bytes());
+            }
+        };
+        push("zone", &ins.zone);
+        push("env", &ins.env);
+        push("appid", &ins.appid);
+        push("hostname", &ins.hostname);
         for addr in ins.addrs.iter() {
-            s.push_str("&addrs=");
-            s.extend(utf8_percent_encode(addr, URL_ENCODE_SET));
+            push("addrs", addr);
+        }
+        push("version", &ins.version);
+        if !ins.group.is_empty() {
+            push("group", &ins.group);
         }
-        s.push_str("&version=");
-        s.extend(utf8_percent_encode(&ins.version, URL_ENCODE_SET));
-        s.push_str("&metadata=");
-        s.extend(utf8_percent_encode(
-            &(serde_json::to_string(&ins.metadata)
-                .map_err(|e| DefaultCodecError::MetadataSerde(e))?),
-            URL_ENCODE_SET,
-        ));
-        Ok(s.into_bytes())
+        push("metadata", &metadata);
+        if let Some(registered_at) = ins.registered_at {
+            push("registered_at", &to_unix_millis(registered_at).to_string());
+        }
+        if let Some(last_renewed_at) = ins.last_renewed_at {
+            push("last_renewed_at", &to_unix_millis(last_renewed_at).to_string());
+        }
+        Ok(())
     }
 }
 
@@ -138,33 +432,53 @@ impl Decoder for DefaultDecoder {
     type Error = DefaultCodecError;
 
     fn decode(&self, data: &[u8]) -> Result<Instance, Self::Error> {
-        let mut ins = Instance::default();
+        self.decode_ref(data).map(InstanceRef::into_owned)
+    }
+
+    fn decode_ref<'a>(&self, data: &'a [u8]) -> Result<InstanceRef<'a>, Self::Error> {
+        let mut ins = InstanceRef::default();
         let value = std::str::from_utf8(data)?;
 
+        // the pairs with the offset of their value in the payload.
+        let mut start = 0;
         let pair_iter = value.split('&').map(|pair| {
-            let pair = pair.splitn(2, '=').collect::<Vec<&str>>();
-            if pair.len() < 2 {
-                (unsafe { *pair.get_unchecked(0) }, "")
-            } else {
-                unsafe { (*pair.get_unchecked(0), *pair.get_unchecked(1)) }
-            }
+            let (k, v) = match pair.find('=') {
+                Some(i) => (&pair[..i], &pair[i + 1..]),
+                None => (pair, ""),
+            };
+            let at = (start + k.len() + 1).min(data.len());
+            start += pair.len() + 1;
+            (k, v, at)
         });
 
-        for (k, v) in pair_iter {
+        for (k, v, at) in pair_iter {
             let v = percent_decode_str(v)
                 .decode_utf8()
-                .map_err(|err| DefaultCodecError::UTF8(err))?;
+                .map_err(|e| DefaultCodecError::UTF8(e, at))?;
 
             match k {
-                "zone" => ins.zone = v.into_owned(),
-                "env" => ins.env = v.into_owned(),
-                "appid" => ins.appid = v.into_owned(),
-                "hostname" => ins.hostname = v.into_owned(),
-                "addrs" => ins.addrs.push(v.into_owned()),
-                "version" => ins.version = v.into_owned(),
+                "zone" => ins.zone = v,
+                "env" => ins.env = v,
+                "appid" => ins.appid = v,
+                "hostname" => ins.hostname = v,
+                "addrs" => ins.addrs.push(v),
+                "version" => ins.version = v,
+                "group" => ins.group = v,
                 "metadata" => {
                     ins.metadata = serde_json::from_str(v.as_ref())
-                        .map_err(|e| DefaultCodecError::MetadataSerde(e))?
+                        .map_err(|e| DefaultCodecError::MetadataSerde(e, Some(at)))?
+                }
+                "registered_at" => {
+                    let millis = v
+                        .parse()
+                        .map_err(|e| DefaultCodecError::Timestamp("registered_at", e, at))?;
+                    ins.registered_at = Some(from_unix_millis(millis));
+                }
+                "last_renewed_at" => {
+                    let millis = v
+                        .parse()
+                        .map_err(|e| DefaultCodecError::Timestamp("last_renewed_at", e, at))?;
+                    ins.last_renewed_at = Some(from_unix_millis(millis));
                 }
                 _ => {}
             }
@@ -173,32 +487,85 @@ impl Decoder for DefaultDecoder {
     }
 }
 
+/// Timestamps are carried with millisecond precision, which is what
+/// registries such as ZooKeeper report. Times before the epoch encode as 0.
+pub fn to_unix_millis(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub fn from_unix_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
 pub fn new_default_codec() -> Codec<DefaultEncoder, DefaultDecoder> {
     Codec::new(DefaultEncoder, DefaultDecoder)
 }
 
+/// The urls Java Dubbo providers register under
+/// `/dubbo/{interface}/providers`, see `DubboEncoder` and `zk::DubboLayout`.
+pub type DubboCodec = Codec<DubboEncoder, DubboDecoder>;
+
+pub fn new_dubbo_codec() -> DubboCodec {
+    Codec::new(DubboEncoder, DubboDecoder)
+}
+
+pub fn new_spring_cloud_codec() -> Codec<SpringCloudEncoder, SpringCloudDecoder> {
+    Codec::new(SpringCloudEncoder, SpringCloudDecoder)
+}
+
+#[cfg(feature = "json")]
+pub fn new_json_codec() -> Codec<JsonEncoder, JsonDecoder> {
+    Codec::new(JsonEncoder, JsonDecoder)
+}
+
+#[cfg(feature = "msgpack")]
+pub fn new_msgpack_codec() -> Codec<MsgPackEncoder, MsgPackDecoder> {
+    Codec::new(MsgPackEncoder, MsgPackDecoder)
+}
+
+#[cfg(feature = "protobuf")]
+pub fn new_proto_codec() -> Codec<ProtoEncoder, ProtoDecoder> {
+    Codec::new(ProtoEncoder, ProtoDecoder)
+}
+
 lazy_static! {
     pub static ref DEFAULT_CODEC: Codec<DefaultEncoder, DefaultDecoder> = new_default_codec();
+    /// Instances as the urls Java Dubbo registers, see `zk::DubboLayout`.
+    pub static ref DUBBO_CODEC: DubboCodec = new_dubbo_codec();
+    /// Instances as the service instances Spring Cloud Zookeeper registers,
+    /// see `zk::SpringCloudLayout`.
+    pub static ref SPRING_CLOUD_CODEC: Codec<SpringCloudEncoder, SpringCloudDecoder> =
+        new_spring_cloud_codec();
 }
 
 #[cfg(test)]
 mod tests {
 
-    use super::{Decoder, Encoder, DEFAULT_CODEC};
+    use super::{decode_with_payload, from_unix_millis, Decoder, Encoder, DEFAULT_CODEC};
     use crate::Instance;
+    use std::{borrow::Cow, error::Error};
 
     #[test]
     fn test_default_encoder_encode() {
         let cases = [
             (Instance {
-                zone: "sh1".to_owned(),
-                env: "test".to_owned(),
-                appid: "provider".to_owned(),
+                zone: "sh1".into(),
+                env: "test".into(),
+                appid: "provider".into(),
                 hostname: "myhostname".to_owned(),
-                addrs: vec!["http://172.1.1.1:8000".to_owned(), "grpc://172.1.1.1:9999".to_owned()],
+                addrs: vec!["http://172.1.1.1:8000".to_owned(), "grpc://172.1.1.1:9999".to_owned()].into(),
                 version: "111".to_owned(),
-                metadata: [("weight".to_owned(), "10".to_owned())].iter().cloned().collect()
-            }, "zone=sh1&env=test&appid=provider&hostname=myhostname&addrs=http%3A%2F%2F172.1.1.1%3A8000&addrs=grpc%3A%2F%2F172.1.1.1%3A9999&version=111&metadata=%7B%22weight%22%3A%2210%22%7D")
+                metadata: [("weight".to_owned(), "10".to_owned())].iter().cloned().collect(),
+                ..Default::default()
+            }, "zone=sh1&env=test&appid=provider&hostname=myhostname&addrs=http%3A%2F%2F172.1.1.1%3A8000&addrs=grpc%3A%2F%2F172.1.1.1%3A9999&version=111&metadata=%7B%22weight%22%3A%2210%22%7D"),
+            (Instance {
+                appid: "provider".into(),
+                registered_at: Some(from_unix_millis(1_590_000_000_123)),
+                last_renewed_at: Some(from_unix_millis(1_590_000_100_456)),
+                ..Default::default()
+            }, "zone=&env=&appid=provider&hostname=&version=&metadata=%7B%7D&registered_at=1590000000123&last_renewed_at=1590000100456")
         ];
         let encoder = DEFAULT_CODEC.get_encoder_ref();
         for case in cases.iter() {
@@ -208,19 +575,44 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_default_encoder_encode_to() {
+        let ins = Instance {
+            appid: "provider".into(),
+            addrs: vec!["grpc://172.1.1.1:9999".to_owned()].into(),
+            ..Default::default()
+        };
+        let encoder = DEFAULT_CODEC.get_encoder_ref();
+        let mut buf = b"/dubbo-rs/".to_vec();
+        encoder.encode_to(&ins, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "/dubbo-rs/zone=&env=&appid=provider&hostname=&addrs=grpc%3A%2F%2F172.1.1.1%3A9999&version=&metadata=%7B%7D"
+        );
+    }
+
     #[test]
     fn test_default_decoder_decode() {
         let cases = [
             (
                 "zone=sh1&env=test&appid=provider&hostname=myhostname&addrs=http%3A%2F%2F172.1.1.1%3A8000&addrs=grpc%3A%2F%2F172.1.1.1%3A9999&version=111&metadata=%7B%22weight%22%3A%2210%22%7D",
                 Instance {
-                zone: "sh1".to_owned(),
-                env: "test".to_owned(),
-                appid: "provider".to_owned(),
+                zone: "sh1".into(),
+                env: "test".into(),
+                appid: "provider".into(),
                 hostname: "myhostname".to_owned(),
-                addrs: vec!["http://172.1.1.1:8000".to_owned(), "grpc://172.1.1.1:9999".to_owned()],
+                addrs: vec!["http://172.1.1.1:8000".to_owned(), "grpc://172.1.1.1:9999".to_owned()].into(),
                 version: "111".to_owned(),
-                metadata: [("weight".to_owned(), "10".to_owned())].iter().cloned().collect()
+                metadata: [("weight".to_owned(), "10".to_owned())].iter().cloned().collect(),
+                ..Default::default()
+            }),
+            (
+                "appid=provider&registered_at=1590000000123&last_renewed_at=1590000100456",
+                Instance {
+                appid: "provider".into(),
+                registered_at: Some(from_unix_millis(1_590_000_000_123)),
+                last_renewed_at: Some(from_unix_millis(1_590_000_100_456)),
+                ..Default::default()
             })
         ];
         let decoder = DEFAULT_CODEC.get_decoder_ref();
@@ -230,4 +622,46 @@ mod tests {
             assert_eq!(res.unwrap(), case.1);
         }
     }
+
+    #[test]
+    fn test_default_decoder_decode_ref() {
+        let data = "zone=sh1&appid=provider&addrs=http%3A%2F%2F172.1.1.1%3A8000&metadata=%7B%22weight%22%3A%2210%22%7D";
+        let decoder = DEFAULT_CODEC.get_decoder_ref();
+        let ins = decoder.decode_ref(data.as_bytes()).unwrap();
+        // only what was percent-encoded is copied.
+        assert!(matches!(ins.zone, Cow::Borrowed("sh1")));
+        assert!(matches!(ins.appid, Cow::Borrowed("provider")));
+        assert!(matches!(ins.addrs[0], Cow::Owned(_)));
+        assert_eq!(ins.addrs, vec!["http://172.1.1.1:8000"]);
+        assert_eq!(
+            ins.into_owned(),
+            decoder.decode(data.as_bytes()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_decode_error() {
+        let decoder = DEFAULT_CODEC.get_decoder_ref();
+        let err =
+            decode_with_payload(decoder, b"appid=provider&registered_at=yesterday").unwrap_err();
+        assert_eq!(err.field(), Some("registered_at"));
+        assert_eq!(err.offset(), Some(29));
+        assert_eq!(
+            err.to_string(),
+            "failed to decode the instance: bad instance timestamp: invalid digit found in string, \
+             field registered_at, at byte 29, payload \"appid=provider&registered_at=yesterday\""
+        );
+        assert!(err.source().unwrap().source().is_some());
+
+        // truncated.
+        let data = format!("hostname={}&registered_at=yesterday", "a".repeat(300));
+        let err = decode_with_payload(decoder, data.as_bytes()).unwrap_err();
+        assert_eq!(err.payload(), &data.as_bytes()[..256]);
+        assert!(err.to_string().ends_with("... (333 bytes)"));
+
+        let err = decode_with_payload(decoder, b"appid=provider&hostname=%FF").unwrap_err();
+        assert_eq!(err.offset(), Some(24));
+        let err = decode_with_payload(decoder, b"appid=\xFF").unwrap_err();
+        assert_eq!(err.offset(), Some(6));
+    }
 }
@@ -0,0 +1,265 @@
+use super::{Codec, DecodeError, Decoder, EncodeError, Encoder, InstanceRef};
+use crate::Instance;
+use std::{error::Error, fmt, io};
+
+// prefixes compressed payloads. 0xff never starts utf8, which the payloads of
+// the other codecs are, so the ones registered uncompressed decode as before.
+const MAGIC: &[u8] = b"\xffz";
+
+/// A compression algorithm, e.g. `Gzip`.
+pub trait Compression {
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>>;
+
+    fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// Gzip, behind the `gzip` feature.
+#[cfg(feature = "gzip")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Gzip {
+    level: flate2::Compression,
+}
+
+#[cfg(feature = "gzip")]
+impl Gzip {
+    /// Compresses at `level`, from 0 (none) to 9 (best).
+    pub fn new(level: u32) -> Self {
+        Gzip {
+            level: flate2::Compression::new(level),
+        }
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl Compression for Gzip {
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), self.level);
+        encoder.write_all(data)?;
+        encoder.finish()
+    }
+
+    fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        use std::io::Read;
+
+        let mut buf = Vec::new();
+        flate2::read::GzDecoder::new(data).read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Compresses the payloads of `C` with `Z`, see `compressed`.
+///
+/// Payloads registered uncompressed decode as is, so that instances may
+/// start compressing one at a time.
+#[derive(Debug, Clone)]
+pub struct Compressed<C, Z> {
+    inner: C,
+    compression: Z,
+    min_size: usize,
+}
+
+/// A codec compressing the payloads of another, see `compressed`.
+pub type CompressedCodec<E, D, Z> = Codec<Compressed<E, Z>, Compressed<D, Z>>;
+
+/// Compresses the payloads of `codec` with `compression`, e.g. for apps with
+/// large metadata. Their size and watch traffic shrink, but they are binary,
+/// so use a layout holding instances as data, e.g. `zk::DataLayout`.
+pub fn compressed<E, D, Z>(codec: Codec<E, D>, compression: Z) -> CompressedCodec<E, D, Z>
+where
+    E: Encoder,
+    D: Decoder,
+    Z: Compression + Clone,
+{
+    Codec::new(
+        Compressed::new(codec.encoder, compression.clone()),
+        Compressed::new(codec.decoder, compression),
+    )
+}
+
+impl<C, Z> Compressed<C, Z> {
+    pub fn new(inner: C, compression: Z) -> Self {
+        Compressed {
+            inner,
+            compression,
+            min_size: 0,
+        }
+    }
+
+    /// Leaves the payloads shorter than `min_size` bytes uncompressed, which
+    /// compressing wouldn't shrink much.
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+}
+
+impl<C, Z> Encoder for Compressed<C, Z>
+where
+    C: Encoder,
+    Z: Compression,
+{
+    type Error = CompressedError<C::Error>;
+
+    fn encode_to(&self, ins: &Instance, buf: &mut Vec<u8>) -> Result<(), Self::Error> {
+        let data = self.inner.encode(ins).map_err(CompressedError::Codec)?;
+        if data.len() < self.min_size {
+            buf.extend_from_slice(&data);
+            return Ok(());
+        }
+        let compressed = self
+            .compression
+            .compress(&data)
+            .map_err(CompressedError::Compression)?;
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&compressed);
+        Ok(())
+    }
+}
+
+impl<C, Z> Decoder for Compressed<C, Z>
+where
+    C: Decoder,
+    Z: Compression,
+{
+    type Error = CompressedError<C::Error>;
+
+    fn decode(&self, data: &[u8]) -> Result<Instance, Self::Error> {
+        match data.strip_prefix(MAGIC) {
+            Some(compressed) => {
+                let data = self
+                    .compression
+                    .decompress(compressed)
+                    .map_err(CompressedError::Compression)?;
+                self.inner.decode(&data).map_err(CompressedError::Codec)
+            }
+            None => self.inner.decode(data).map_err(CompressedError::Codec),
+        }
+    }
+
+    // borrows from uncompressed payloads only.
+    fn decode_ref<'a>(&self, data: &'a [u8]) -> Result<InstanceRef<'a>, Self::Error> {
+        if data.starts_with(MAGIC) {
+            return self.decode(data).map(InstanceRef::from);
+        }
+        self.inner.decode_ref(data).map_err(CompressedError::Codec)
+    }
+}
+
+#[derive(Debug)]
+pub enum CompressedError<E> {
+    Codec(E),
+    Compression(io::Error),
+}
+
+impl<E> fmt::Display for CompressedError<E>
+where
+    E: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompressedError::Codec(e) => e.fmt(f),
+            CompressedError::Compression(e) => write!(f, "bad compressed payload: {}", e),
+        }
+    }
+}
+
+impl<E> Error for CompressedError<E>
+where
+    E: Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CompressedError::Codec(e) => e.source(),
+            CompressedError::Compression(e) => Some(e),
+        }
+    }
+}
+
+impl<E> From<CompressedError<E>> for EncodeError
+where
+    E: Into<EncodeError>,
+{
+    fn from(e: CompressedError<E>) -> Self {
+        match e {
+            CompressedError::Codec(e) => e.into(),
+            CompressedError::Compression(e) => EncodeError::new(e),
+        }
+    }
+}
+
+impl<E> From<CompressedError<E>> for DecodeError
+where
+    E: Into<DecodeError>,
+{
+    fn from(e: CompressedError<E>) -> Self {
+        match e {
+            CompressedError::Codec(e) => e.into(),
+            CompressedError::Compression(e) => DecodeError::new(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compressed, Compression};
+    use crate::{
+        codec::{new_default_codec, Decoder, Encoder},
+        Instance,
+    };
+    use std::io;
+
+    // stands in for a real algorithm, reversing the payload.
+    #[derive(Clone)]
+    struct Reverse;
+
+    impl Compression for Reverse {
+        fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+            Ok(data.iter().rev().cloned().collect())
+        }
+
+        fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+            self.compress(data)
+        }
+    }
+
+    #[test]
+    fn test_compressed() {
+        let ins = Instance {
+            appid: "provider".into(),
+            addrs: vec!["grpc://172.1.1.1:9999".to_owned()].into(),
+            ..Default::default()
+        };
+        let codec = compressed(new_default_codec(), Reverse);
+        let data = codec.get_encoder_ref().encode(&ins).unwrap();
+        assert!(data.starts_with(b"\xffz"));
+        assert_eq!(codec.get_decoder_ref().decode(&data).unwrap(), ins);
+
+        // registered before compressing.
+        let plain = new_default_codec().get_encoder_ref().encode(&ins).unwrap();
+        assert_eq!(codec.get_decoder_ref().decode(&plain).unwrap(), ins);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_gzip() {
+        let ins = Instance {
+            appid: "provider".into(),
+            addrs: vec!["grpc://172.1.1.1:9999".to_owned()].into(),
+            metadata: (0..32)
+                .map(|i| (format!("key{}", i), "value".to_owned()))
+                .collect(),
+            ..Default::default()
+        };
+        let plain = new_default_codec().get_encoder_ref().encode(&ins).unwrap();
+        let codec = compressed(new_default_codec(), super::Gzip::default());
+        let data = codec.get_encoder_ref().encode(&ins).unwrap();
+        assert!(data.starts_with(b"\xffz"));
+        assert!(data.len() < plain.len());
+        assert_eq!(codec.get_decoder_ref().decode(&data).unwrap(), ins);
+
+        let corrupt = [&data[..data.len() / 2], b"garbage"].concat();
+        assert!(codec.get_decoder_ref().decode(&corrupt).is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use super::{from_unix_millis, to_unix_millis, DecodeError, Decoder, EncodeError, Encoder};
+use crate::{intern, Instance};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use smallvec::smallvec;
+use std::{collections::BTreeMap, error::Error, fmt, num::ParseIntError, str::Utf8Error};
+
+// what would break the url apart, Java Dubbo reads the other characters as is.
+const PARAM_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'=')
+    .add(b'?');
+
+// like java.net.URLEncoder, which Dubbo uses for node names.
+const NODE_ENCODE_SET: &AsciiSet = &percent_encoding::NON_ALPHANUMERIC
+    .remove(b'*')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_');
+
+/// Parameters mapped to `Instance` fields, the others are kept in metadata.
+const INTERFACE: &str = "interface";
+const VERSION: &str = "version";
+const ZONE: &str = "zone";
+const ENV: &str = "environment";
+const HOSTNAME: &str = "hostname";
+const TIMESTAMP: &str = "timestamp";
+// what Dubbo tag routing reads.
+const TAG: &str = "dubbo.tag";
+
+#[derive(Debug)]
+pub enum DubboCodecError {
+    /// A Dubbo url carries exactly one address.
+    Addrs(usize),
+    BadUrl(String),
+    UTF8(Utf8Error),
+    Timestamp(ParseIntError),
+}
+
+impl fmt::Display for DubboCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DubboCodecError::Addrs(n) => write!(f, "dubbo url needs exactly one addr, got {}", n),
+            DubboCodecError::BadUrl(url) => write!(f, "bad dubbo url {:?}", url),
+            DubboCodecError::UTF8(e) => write!(f, "dubbo url is not utf8: {}", e),
+            DubboCodecError::Timestamp(e) => write!(f, "bad dubbo timestamp: {}", e),
+        }
+    }
+}
+
+impl Error for DubboCodecError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DubboCodecError::UTF8(e) => Some(e),
+            DubboCodecError::Timestamp(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Utf8Error> for DubboCodecError {
+    fn from(e: Utf8Error) -> Self {
+        DubboCodecError::UTF8(e)
+    }
+}
+
+impl From<DubboCodecError> for EncodeError {
+    fn from(e: DubboCodecError) -> Self {
+        EncodeError::new(e)
+    }
+}
+
+impl From<DubboCodecError> for DecodeError {
+    fn from(e: DubboCodecError) -> Self {
+        match e {
+            DubboCodecError::Timestamp(_) => DecodeError::new(e).with_field(TIMESTAMP),
+            e => DecodeError::new(e),
+        }
+    }
+}
+
+/// Encodes an instance as the url Java Dubbo registers, e.g.
+/// `dubbo://172.1.1.1:20880/org.apache.dubbo.demo.DemoService?application=demo&interface=org.apache.dubbo.demo.DemoService&side=provider&version=1.0.0`,
+/// url encoded as a node name.
+///
+/// The only addr is the url address, the appid the service interface and
+/// metadata the url parameters.
+pub struct DubboEncoder;
+
+impl Encoder for DubboEncoder {
+    type Error = DubboCodecError;
+
+    fn encode_to(&self, ins: &Instance, buf: &mut Vec<u8>) -> Result<(), Self::Error> {
+        if ins.addrs.len() != 1 {
+            return Err(DubboCodecError::Addrs(ins.addrs.len()));
+        }
+        let mut params = ins
+            .metadata
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.clone()))
+            .collect::<BTreeMap<&str, String>>();
+        params.insert(INTERFACE, ins.appid.to_string());
+        for (k, v) in [
+            (VERSION, ins.version.as_str()),
+            (ZONE, &ins.zone),
+            (ENV, &ins.env),
+            (HOSTNAME, &ins.hostname),
+            (TAG, &ins.group),
+        ]
+        .iter()
+        {
+            if !v.is_empty() {
+                params.insert(k, v.to_string());
+            }
+        }
+        if let Some(registered_at) = ins.registered_at {
+            params.insert(TIMESTAMP, to_unix_millis(registered_at).to_string());
+        }
+
+        let mut url = format!("{}/{}?", ins.addrs[0].trim_end_matches('/'), ins.appid);
+        for (i, (k, v)) in params.iter().enumerate() {
+            if i > 0 {
+                url.push('&');
+            }
+            url.extend(utf8_percent_encode(k, PARAM_ENCODE_SET));
+            url.push('=');
+            url.extend(utf8_percent_encode(v, PARAM_ENCODE_SET));
+        }
+        for encoded in utf8_percent_encode(&url, NODE_ENCODE_SET) {
+            buf.extend_from_slice(encoded.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+/// Decodes the node names Java Dubbo registers, see `DubboEncoder`.
+pub struct DubboDecoder;
+
+impl Decoder for DubboDecoder {
+    type Error = DubboCodecError;
+
+    fn decode(&self, data: &[u8]) -> Result<Instance, Self::Error> {
+        // java.net.URLEncoder encodes spaces as '+'.
+        let node = std::str::from_utf8(data)?.replace('+', " ");
+        let url = percent_decode_str(&node).decode_utf8()?;
+        let bad_url = || DubboCodecError::BadUrl(url.to_string());
+
+        let (base, query) = match url.find('?') {
+            Some(pos) => (&url[..pos], &url[pos + 1..]),
+            None => (url.as_ref(), ""),
+        };
+        let host_start = base.find("://").ok_or_else(bad_url)? + 3;
+        let (addr, path) = match base[host_start..].find('/') {
+            Some(pos) => base.split_at(host_start + pos),
+            None => (base, ""),
+        };
+
+        let mut ins = Instance {
+            appid: intern(path.trim_start_matches('/')),
+            addrs: smallvec![addr.to_owned()],
+            ..Default::default()
+        };
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let mut kv = pair.splitn(2, '=');
+            let k = percent_decode_str(kv.next().unwrap_or_default()).decode_utf8()?;
+            let v = percent_decode_str(kv.next().unwrap_or_default())
+                .decode_utf8()?
+                .into_owned();
+            match k.as_ref() {
+                INTERFACE => ins.appid = intern(&v),
+                VERSION => ins.version = v,
+                ZONE => ins.zone = intern(&v),
+                ENV => ins.env = intern(&v),
+                HOSTNAME => ins.hostname = v,
+                TAG => ins.group = intern(&v),
+                TIMESTAMP => {
+                    ins.registered_at = Some(from_unix_millis(
+                        v.parse().map_err(DubboCodecError::Timestamp)?,
+                    ))
+                }
+                _ => {
+                    ins.metadata.insert(k.into_owned(), v);
+                }
+            }
+        }
+        Ok(ins)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DubboDecoder, DubboEncoder};
+    use crate::{
+        codec::{from_unix_millis, Decoder, Encoder},
+        Instance,
+    };
+
+    fn provider() -> Instance {
+        Instance {
+            appid: "org.apache.dubbo.demo.DemoService".into(),
+            addrs: vec!["dubbo://172.1.1.1:20880".to_owned()].into(),
+            version: "1.0.0".to_owned(),
+            metadata: [
+                ("application", "demo-provider"),
+                ("methods", "sayHello,sayHelloAsync"),
+                ("side", "provider"),
+            ]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+            registered_at: Some(from_unix_millis(1_590_000_000_123)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_dubbo_encode() {
+        let encoded = DubboEncoder.encode(&provider()).unwrap();
+        assert_eq!(
+            String::from_utf8(encoded).unwrap(),
+            "dubbo%3A%2F%2F172.1.1.1%3A20880%2Forg.apache.dubbo.demo.DemoService%3Fapplication%3Ddemo-provider%26interface%3Dorg.apache.dubbo.demo.DemoService%26methods%3DsayHello%2CsayHelloAsync%26side%3Dprovider%26timestamp%3D1590000000123%26version%3D1.0.0"
+        );
+        assert!(DubboEncoder.encode(&Instance::default()).is_err());
+    }
+
+    #[test]
+    fn test_dubbo_decode() {
+        // as registered by Java Dubbo.
+        let node = "dubbo%3A%2F%2F172.1.1.1%3A20880%2Forg.apache.dubbo.demo.DemoService%3Fanyhost%3Dtrue%26application%3Ddemo-provider%26interface%3Dorg.apache.dubbo.demo.DemoService%26methods%3DsayHello%2CsayHelloAsync%26side%3Dprovider%26timestamp%3D1590000000123%26version%3D1.0.0";
+        let mut expected = provider();
+        expected
+            .metadata
+            .insert("anyhost".to_owned(), "true".to_owned());
+        assert_eq!(DubboDecoder.decode(node.as_bytes()).unwrap(), expected);
+
+        let encoded = DubboEncoder.encode(&provider()).unwrap();
+        assert_eq!(DubboDecoder.decode(&encoded).unwrap(), provider());
+    }
+}
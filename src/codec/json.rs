@@ -0,0 +1,165 @@
+use super::{
+    from_unix_millis, to_unix_millis, DecodeError, Decoder, EncodeError, Encoder, InstanceRef,
+};
+use crate::Instance;
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, collections::HashMap, error::Error, fmt};
+
+// an instance as json, borrowing the strings of either side.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct JsonInstance<'a> {
+    #[serde(borrow)]
+    zone: Cow<'a, str>,
+    #[serde(borrow)]
+    env: Cow<'a, str>,
+    #[serde(borrow)]
+    appid: Cow<'a, str>,
+    #[serde(borrow)]
+    hostname: Cow<'a, str>,
+    #[serde(borrow)]
+    addrs: Vec<Cow<'a, str>>,
+    #[serde(borrow)]
+    version: Cow<'a, str>,
+    #[serde(borrow)]
+    group: Cow<'a, str>,
+    #[serde(borrow)]
+    metadata: HashMap<Cow<'a, str>, Cow<'a, str>>,
+    registered_at: Option<u64>,
+    last_renewed_at: Option<u64>,
+}
+
+impl<'a> From<JsonInstance<'a>> for InstanceRef<'a> {
+    fn from(ins: JsonInstance<'a>) -> Self {
+        InstanceRef {
+            zone: ins.zone,
+            env: ins.env,
+            appid: ins.appid,
+            hostname: ins.hostname,
+            addrs: ins.addrs,
+            version: ins.version,
+            group: ins.group,
+            metadata: ins.metadata,
+            registered_at: ins.registered_at.map(from_unix_millis),
+            last_renewed_at: ins.last_renewed_at.map(from_unix_millis),
+            revision: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct JsonCodecError(serde_json::Error);
+
+impl fmt::Display for JsonCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bad instance json: {}", self.0)
+    }
+}
+
+impl Error for JsonCodecError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+impl From<JsonCodecError> for EncodeError {
+    fn from(e: JsonCodecError) -> Self {
+        EncodeError::new(e)
+    }
+}
+
+impl From<JsonCodecError> for DecodeError {
+    fn from(e: JsonCodecError) -> Self {
+        DecodeError::new(e)
+    }
+}
+
+/// Encodes an instance as JSON, what clients in other languages read and
+/// write most easily: the fields of `Instance` by the same names, the
+/// timestamps in unix milliseconds, e.g.
+/// `{"appid":"provider","addrs":["grpc://172.1.1.1:9999"],"metadata":{"weight":"10"}}`.
+///
+/// Missing fields decode as empty, so clients only write the ones they know.
+/// The revision is the backend's to fill in. JSON isn't fit for node names,
+/// use a layout holding instances as data, e.g. `zk::DataLayout`.
+pub struct JsonEncoder;
+
+impl Encoder for JsonEncoder {
+    type Error = JsonCodecError;
+
+    fn encode_to(&self, ins: &Instance, buf: &mut Vec<u8>) -> Result<(), Self::Error> {
+        let json = JsonInstance {
+            zone: Cow::Borrowed(&ins.zone),
+            env: Cow::Borrowed(&ins.env),
+            appid: Cow::Borrowed(&ins.appid),
+            hostname: Cow::Borrowed(&ins.hostname),
+            addrs: ins
+                .addrs
+                .iter()
+                .map(|addr| Cow::Borrowed(&**addr))
+                .collect(),
+            version: Cow::Borrowed(&ins.version),
+            group: Cow::Borrowed(&ins.group),
+            metadata: ins
+                .metadata
+                .iter()
+                .map(|(k, v)| (Cow::Borrowed(&**k), Cow::Borrowed(&**v)))
+                .collect(),
+            registered_at: ins.registered_at.map(to_unix_millis),
+            last_renewed_at: ins.last_renewed_at.map(to_unix_millis),
+        };
+        serde_json::to_writer(buf, &json).map_err(JsonCodecError)
+    }
+}
+
+/// Decodes instances encoded by `JsonEncoder`, borrowing the strings without
+/// escapes with `decode_ref`.
+pub struct JsonDecoder;
+
+impl Decoder for JsonDecoder {
+    type Error = JsonCodecError;
+
+    fn decode(&self, data: &[u8]) -> Result<Instance, Self::Error> {
+        self.decode_ref(data).map(InstanceRef::into_owned)
+    }
+
+    fn decode_ref<'a>(&self, data: &'a [u8]) -> Result<InstanceRef<'a>, Self::Error> {
+        serde_json::from_slice::<JsonInstance<'a>>(data)
+            .map(InstanceRef::from)
+            .map_err(JsonCodecError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JsonDecoder, JsonEncoder};
+    use crate::{
+        codec::{from_unix_millis, Decoder, Encoder},
+        Instance,
+    };
+    use std::borrow::Cow;
+
+    #[test]
+    fn test_json_codec() {
+        let ins = Instance {
+            zone: "sh1".into(),
+            appid: "provider".into(),
+            addrs: vec!["grpc://172.1.1.1:9999".to_owned()].into(),
+            metadata: [("weight".to_owned(), "10".to_owned())]
+                .iter()
+                .cloned()
+                .collect(),
+            registered_at: Some(from_unix_millis(1_590_000_000_123)),
+            ..Default::default()
+        };
+        let encoded = JsonEncoder.encode(&ins).unwrap();
+        assert_eq!(JsonDecoder.decode(&encoded).unwrap(), ins);
+
+        // as written by a client knowing a few fields.
+        let data = br#"{"appid":"provider","addrs":["grpc://172.1.1.1:9999"],"extra":1}"#;
+        let decoded = JsonDecoder.decode_ref(data).unwrap();
+        assert!(matches!(decoded.appid, Cow::Borrowed("provider")));
+        assert_eq!(decoded.zone, "");
+        assert!(JsonDecoder.decode(b"provider").is_err());
+    }
+}
@@ -0,0 +1,356 @@
+use super::{
+    from_unix_millis, to_unix_millis, DecodeError, Decoder, EncodeError, Encoder, InstanceRef,
+};
+use crate::Instance;
+use std::{borrow::Cow, convert::TryInto, error::Error, fmt, str, str::Utf8Error};
+
+#[derive(Debug)]
+pub enum MsgPackCodecError {
+    /// The payload ends in the middle of the value read from the offset.
+    Eof(usize),
+    /// A value of another type than the field's, by its marker and offset.
+    Unexpected(u8, usize),
+    UTF8(Utf8Error),
+}
+
+impl fmt::Display for MsgPackCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MsgPackCodecError::Eof(_) => write!(f, "truncated msgpack instance"),
+            MsgPackCodecError::Unexpected(marker, _) => {
+                write!(f, "unexpected msgpack marker {:#04x}", marker)
+            }
+            MsgPackCodecError::UTF8(e) => write!(f, "msgpack string is not utf8: {}", e),
+        }
+    }
+}
+
+impl Error for MsgPackCodecError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MsgPackCodecError::UTF8(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<MsgPackCodecError> for EncodeError {
+    fn from(e: MsgPackCodecError) -> Self {
+        EncodeError::new(e)
+    }
+}
+
+impl From<MsgPackCodecError> for DecodeError {
+    fn from(e: MsgPackCodecError) -> Self {
+        match e {
+            MsgPackCodecError::Eof(offset) | MsgPackCodecError::Unexpected(_, offset) => {
+                DecodeError::new(e).with_offset(offset)
+            }
+            e => DecodeError::new(e),
+        }
+    }
+}
+
+/// Encodes an instance as a MessagePack map, compact and self-describing,
+/// for deployments whose clients are all built with this crate.
+///
+/// The keys are the names of the fields of `Instance`, the empty ones left
+/// out, and the timestamps unix milliseconds. Unknown keys are skipped when
+/// decoding, so fields may be added. The payloads are binary, use a layout
+/// holding instances as data, e.g. `zk::DataLayout`.
+pub struct MsgPackEncoder;
+
+impl Encoder for MsgPackEncoder {
+    type Error = MsgPackCodecError;
+
+    fn encode_to(&self, ins: &Instance, buf: &mut Vec<u8>) -> Result<(), Self::Error> {
+        let strings = [
+            ("zone", &*ins.zone),
+            ("env", &*ins.env),
+            ("appid", &*ins.appid),
+            ("hostname", &*ins.hostname),
+            ("version", &*ins.version),
+            ("group", &*ins.group),
+        ];
+        let strings = strings.iter().filter(|(_, v)| !v.is_empty());
+        let times = [
+            ("registered_at", ins.registered_at),
+            ("last_renewed_at", ins.last_renewed_at),
+        ];
+        let times = times
+            .iter()
+            .filter_map(|(k, t)| t.map(|t| (k, to_unix_millis(t))));
+        let len = strings.clone().count()
+            + times.clone().count()
+            + !ins.addrs.is_empty() as usize
+            + !ins.metadata.is_empty() as usize;
+        write_collection(buf, len, 0x80, 0xde);
+        for (k, v) in strings {
+            write_str(buf, k);
+            write_str(buf, v);
+        }
+        if !ins.addrs.is_empty() {
+            write_str(buf, "addrs");
+            write_collection(buf, ins.addrs.len(), 0x90, 0xdc);
+            for addr in &ins.addrs {
+                write_str(buf, addr);
+            }
+        }
+        if !ins.metadata.is_empty() {
+            write_str(buf, "metadata");
+            write_collection(buf, ins.metadata.len(), 0x80, 0xde);
+            for (k, v) in &ins.metadata {
+                write_str(buf, k);
+                write_str(buf, v);
+            }
+        }
+        for (k, millis) in times {
+            write_str(buf, k);
+            write_uint(buf, millis);
+        }
+        Ok(())
+    }
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    let len = s.len();
+    if len < 32 {
+        buf.push(0xa0 | len as u8);
+    } else if len < 0x100 {
+        buf.extend_from_slice(&[0xd9, len as u8]);
+    } else if len < 0x1_0000 {
+        buf.push(0xda);
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buf.push(0xdb);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+    buf.extend_from_slice(s.as_bytes());
+}
+
+// the header of an array or a map of `len` entries, `fix` being the marker of
+// the short ones and `marker16` the one of those with a 16 bits length.
+fn write_collection(buf: &mut Vec<u8>, len: usize, fix: u8, marker16: u8) {
+    if len < 16 {
+        buf.push(fix | len as u8);
+    } else if len < 0x1_0000 {
+        buf.push(marker16);
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buf.push(marker16 + 1);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+fn write_uint(buf: &mut Vec<u8>, n: u64) {
+    if n < 0x80 {
+        buf.push(n as u8);
+    } else if n < 0x100 {
+        buf.extend_from_slice(&[0xcc, n as u8]);
+    } else if n < 0x1_0000 {
+        buf.push(0xcd);
+        buf.extend_from_slice(&(n as u16).to_be_bytes());
+    } else if n < 0x1_0000_0000 {
+        buf.push(0xce);
+        buf.extend_from_slice(&(n as u32).to_be_bytes());
+    } else {
+        buf.push(0xcf);
+        buf.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+/// Decodes instances encoded by `MsgPackEncoder`, borrowing the strings with
+/// `decode_ref`.
+pub struct MsgPackDecoder;
+
+impl Decoder for MsgPackDecoder {
+    type Error = MsgPackCodecError;
+
+    fn decode(&self, data: &[u8]) -> Result<Instance, Self::Error> {
+        self.decode_ref(data).map(InstanceRef::into_owned)
+    }
+
+    fn decode_ref<'a>(&self, data: &'a [u8]) -> Result<InstanceRef<'a>, Self::Error> {
+        let mut reader = Reader { data, offset: 0 };
+        let mut ins = InstanceRef::default();
+        for _ in 0..reader.collection(0x80, 0xde)? {
+            match reader.str()? {
+                "zone" => ins.zone = Cow::Borrowed(reader.str()?),
+                "env" => ins.env = Cow::Borrowed(reader.str()?),
+                "appid" => ins.appid = Cow::Borrowed(reader.str()?),
+                "hostname" => ins.hostname = Cow::Borrowed(reader.str()?),
+                "version" => ins.version = Cow::Borrowed(reader.str()?),
+                "group" => ins.group = Cow::Borrowed(reader.str()?),
+                "addrs" => {
+                    for _ in 0..reader.collection(0x90, 0xdc)? {
+                        ins.addrs.push(Cow::Borrowed(reader.str()?));
+                    }
+                }
+                "metadata" => {
+                    for _ in 0..reader.collection(0x80, 0xde)? {
+                        let k = reader.str()?;
+                        ins.metadata.insert(k.into(), reader.str()?.into());
+                    }
+                }
+                "registered_at" => ins.registered_at = Some(from_unix_millis(reader.uint()?)),
+                "last_renewed_at" => ins.last_renewed_at = Some(from_unix_millis(reader.uint()?)),
+                _ => reader.skip()?,
+            }
+        }
+        Ok(ins)
+    }
+}
+
+// reads msgpack values off the front of `data`.
+struct Reader<'a> {
+    data: &'a [u8],
+    // of `data` in the payload.
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], MsgPackCodecError> {
+        if self.data.len() < n {
+            return Err(MsgPackCodecError::Eof(self.offset));
+        }
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        self.offset += n;
+        Ok(taken)
+    }
+
+    // the marker just read isn't of the expected type.
+    fn unexpected(&self, marker: u8) -> MsgPackCodecError {
+        MsgPackCodecError::Unexpected(marker, self.offset - 1)
+    }
+
+    fn marker(&mut self) -> Result<u8, MsgPackCodecError> {
+        self.take(1).map(|taken| taken[0])
+    }
+
+    // a big endian integer of `n` bytes.
+    fn be(&mut self, n: usize) -> Result<u64, MsgPackCodecError> {
+        let taken = self.take(n)?;
+        Ok(taken.iter().fold(0, |n, b| n << 8 | *b as u64))
+    }
+
+    fn str(&mut self) -> Result<&'a str, MsgPackCodecError> {
+        let len = match self.marker()? {
+            marker @ 0xa0..=0xbf => (marker & 0x1f) as u64,
+            0xd9 => self.be(1)?,
+            0xda => self.be(2)?,
+            0xdb => self.be(4)?,
+            marker => return Err(self.unexpected(marker)),
+        };
+        let len = len
+            .try_into()
+            .map_err(|_| MsgPackCodecError::Eof(self.offset))?;
+        let taken = self.take(len)?;
+        str::from_utf8(taken).map_err(MsgPackCodecError::UTF8)
+    }
+
+    // the length of an array or a map, see `write_collection`.
+    fn collection(&mut self, fix: u8, marker16: u8) -> Result<u64, MsgPackCodecError> {
+        match self.marker()? {
+            marker if marker & 0xf0 == fix => Ok((marker & 0x0f) as u64),
+            marker if marker == marker16 => self.be(2),
+            marker if marker == marker16 + 1 => self.be(4),
+            marker => Err(self.unexpected(marker)),
+        }
+    }
+
+    fn uint(&mut self) -> Result<u64, MsgPackCodecError> {
+        match self.marker()? {
+            marker @ 0x00..=0x7f => Ok(marker as u64),
+            0xcc => self.be(1),
+            0xcd => self.be(2),
+            0xce => self.be(4),
+            0xcf => self.be(8),
+            marker => Err(self.unexpected(marker)),
+        }
+    }
+
+    // skips a value of any type, e.g. of a field added since.
+    fn skip(&mut self) -> Result<(), MsgPackCodecError> {
+        let marker = self.marker()?;
+        let (len, values) = match marker {
+            0x00..=0x7f | 0xe0..=0xff | 0xc0 | 0xc2 | 0xc3 => (0, 0),
+            0x80..=0x8f => (0, (marker & 0x0f) as u64 * 2),
+            0x90..=0x9f => (0, (marker & 0x0f) as u64),
+            0xa0..=0xbf => ((marker & 0x1f) as u64, 0),
+            0xc4 | 0xd9 => (self.be(1)?, 0),
+            0xc5 | 0xda => (self.be(2)?, 0),
+            0xc6 | 0xdb => (self.be(4)?, 0),
+            // extensions, their type included.
+            0xc7 => (self.be(1)? + 1, 0),
+            0xc8 => (self.be(2)? + 1, 0),
+            0xc9 => (self.be(4)? + 1, 0),
+            0xcc | 0xd0 => (1, 0),
+            0xcd | 0xd1 | 0xd4 => (2, 0),
+            0xd5 => (3, 0),
+            0xca | 0xce | 0xd2 => (4, 0),
+            0xd6 => (5, 0),
+            0xcb | 0xcf | 0xd3 => (8, 0),
+            0xd7 => (9, 0),
+            0xd8 => (17, 0),
+            0xdc => (0, self.be(2)?),
+            0xdd => (0, self.be(4)?),
+            0xde => (0, self.be(2)? * 2),
+            0xdf => (0, self.be(4)? * 2),
+            marker => return Err(self.unexpected(marker)),
+        };
+        let len = len
+            .try_into()
+            .map_err(|_| MsgPackCodecError::Eof(self.offset))?;
+        self.take(len)?;
+        for _ in 0..values {
+            self.skip()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MsgPackDecoder, MsgPackEncoder};
+    use crate::{
+        codec::{from_unix_millis, DecodeError, Decoder, Encoder, DEFAULT_CODEC},
+        Instance,
+    };
+
+    #[test]
+    fn test_msgpack_codec() {
+        let ins = Instance {
+            zone: "sh1".into(),
+            appid: "provider".into(),
+            hostname: "myhostname".repeat(4),
+            addrs: vec!["grpc://172.1.1.1:9999".to_owned()].into(),
+            metadata: [("weight".to_owned(), "10".to_owned())]
+                .iter()
+                .cloned()
+                .collect(),
+            registered_at: Some(from_unix_millis(1_590_000_000_123)),
+            ..Default::default()
+        };
+        let encoded = MsgPackEncoder.encode(&ins).unwrap();
+        assert_eq!(MsgPackDecoder.decode(&encoded).unwrap(), ins);
+        let querystring = DEFAULT_CODEC.get_encoder_ref().encode(&ins).unwrap();
+        assert!(encoded.len() < querystring.len());
+
+        // {"appid": "provider", "weight": [1.5, nil]} from a later version.
+        let data = b"\x82\xa5appid\xa8provider\xa6weight\x92\xcb\x3f\xf8\0\0\0\0\0\0\xc0";
+        assert_eq!(
+            MsgPackDecoder.decode(data).unwrap(),
+            Instance {
+                appid: "provider".into(),
+                ..Default::default()
+            }
+        );
+        let err: DecodeError = MsgPackDecoder
+            .decode(&encoded[..encoded.len() - 1])
+            .unwrap_err()
+            .into();
+        // the 8 bytes of registered_at.
+        assert_eq!(err.offset(), Some(encoded.len() - 8));
+    }
+}
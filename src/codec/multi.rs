@@ -0,0 +1,204 @@
+use super::{Codec, DecodeError, Decoder, Encoder, InstanceRef};
+use crate::Instance;
+use std::{collections::HashMap, error::Error, fmt};
+
+// the payloads in an envelope start with `v`, the decimal version of their
+// encoding and `;`, which no querystring, url or json starts with. Ascii, to
+// stay a valid node name with layouts putting payloads in names.
+const ENVELOPE: u8 = b'v';
+const VERSION_END: u8 = b';';
+
+/// A codec encoding instances in an envelope naming their encoding, see
+/// `MultiEncoder`.
+pub type MultiCodec<E> = Codec<MultiEncoder<E>, MultiDecoder>;
+
+/// Encodes instances with `E` in an envelope carrying `version`, the one
+/// `MultiDecoder` picks the decoder of, to roll out another encoding.
+///
+/// First deploy everywhere a `MultiDecoder` decoding both encodings, while
+/// still encoding the legacy way, then switch the encoder:
+///
+/// ```ignore
+/// let codec = Codec::new(
+///     MultiEncoder::new(1, JsonEncoder),
+///     MultiDecoder::new(DefaultDecoder).version(1, JsonDecoder),
+/// );
+/// ```
+pub struct MultiEncoder<E> {
+    version: u8,
+    inner: E,
+}
+
+impl<E> MultiEncoder<E> {
+    pub fn new(version: u8, inner: E) -> Self {
+        MultiEncoder { version, inner }
+    }
+}
+
+impl<E> Encoder for MultiEncoder<E>
+where
+    E: Encoder,
+{
+    type Error = E::Error;
+
+    fn encode_to(&self, ins: &Instance, buf: &mut Vec<u8>) -> Result<(), Self::Error> {
+        buf.push(ENVELOPE);
+        buf.extend_from_slice(self.version.to_string().as_bytes());
+        buf.push(VERSION_END);
+        self.inner.encode_to(ins, buf)
+    }
+}
+
+// a decoder of any error, to keep decoders of different types.
+trait AnyDecoder: Send + Sync {
+    fn decode_any<'a>(&self, data: &'a [u8]) -> Result<InstanceRef<'a>, DecodeError>;
+}
+
+impl<D> AnyDecoder for D
+where
+    D: Decoder + Send + Sync,
+{
+    fn decode_any<'a>(&self, data: &'a [u8]) -> Result<InstanceRef<'a>, DecodeError> {
+        self.decode_ref(data).map_err(Into::into)
+    }
+}
+
+/// Decodes the payloads of `MultiEncoder` with the decoder of their version,
+/// and the ones without an envelope, of the encoding before, with the legacy
+/// decoder.
+pub struct MultiDecoder {
+    legacy: Box<dyn AnyDecoder>,
+    versions: HashMap<u8, Box<dyn AnyDecoder>>,
+}
+
+impl MultiDecoder {
+    pub fn new<D>(legacy: D) -> Self
+    where
+        D: Decoder + Send + Sync + 'static,
+    {
+        MultiDecoder {
+            legacy: Box::new(legacy),
+            versions: HashMap::new(),
+        }
+    }
+
+    /// Decodes the payloads of `version` with `decoder`.
+    pub fn version<D>(mut self, version: u8, decoder: D) -> Self
+    where
+        D: Decoder + Send + Sync + 'static,
+    {
+        self.versions.insert(version, Box::new(decoder));
+        self
+    }
+}
+
+impl Decoder for MultiDecoder {
+    type Error = MultiCodecError;
+
+    fn decode(&self, data: &[u8]) -> Result<Instance, Self::Error> {
+        self.decode_ref(data).map(InstanceRef::into_owned)
+    }
+
+    fn decode_ref<'a>(&self, data: &'a [u8]) -> Result<InstanceRef<'a>, Self::Error> {
+        let (decoder, data) = match envelope(data) {
+            Some((version, data)) => {
+                let version = std::str::from_utf8(version)
+                    .ok()
+                    .and_then(|version| version.parse::<u8>().ok())
+                    .ok_or(MultiCodecError::NoVersion)?;
+                let decoder = self
+                    .versions
+                    .get(&version)
+                    .ok_or(MultiCodecError::UnknownVersion(version))?;
+                (decoder, data)
+            }
+            None => (&self.legacy, data),
+        };
+        decoder.decode_any(data).map_err(MultiCodecError::Decode)
+    }
+}
+
+// the digits of the version of an enveloped payload, and the payload.
+fn envelope(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let data = data.strip_prefix(&[ENVELOPE])?;
+    let digits = data.iter().take_while(|b| b.is_ascii_digit()).count();
+    match data.get(digits) {
+        Some(&VERSION_END) => Some((&data[..digits], &data[digits + 1..])),
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+pub enum MultiCodecError {
+    /// The envelope holds no version, or one past 255.
+    NoVersion,
+    /// No decoder was given for the version, e.g. encoded by instances
+    /// rolled out before this one.
+    UnknownVersion(u8),
+    /// The decoder of the payload failed, with its error.
+    Decode(DecodeError),
+}
+
+impl fmt::Display for MultiCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MultiCodecError::NoVersion => write!(f, "payload envelope without a valid version"),
+            MultiCodecError::UnknownVersion(version) => {
+                write!(f, "no decoder for payload version {}", version)
+            }
+            MultiCodecError::Decode(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for MultiCodecError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MultiCodecError::Decode(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<MultiCodecError> for DecodeError {
+    fn from(e: MultiCodecError) -> Self {
+        match e {
+            MultiCodecError::Decode(e) => e,
+            e => DecodeError::new(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MultiCodecError, MultiDecoder, MultiEncoder};
+    use crate::{
+        codec::{Decoder, DefaultDecoder, DefaultEncoder, Encoder},
+        Instance,
+    };
+
+    #[test]
+    fn test_multi_codec() {
+        let ins = Instance {
+            appid: "provider".into(),
+            addrs: vec!["grpc://172.1.1.1:9999".to_owned()].into(),
+            ..Default::default()
+        };
+        let decoder = MultiDecoder::new(DefaultDecoder).version(2, DefaultDecoder);
+        let legacy = DefaultEncoder.encode(&ins).unwrap();
+        assert_eq!(decoder.decode(&legacy).unwrap(), ins);
+
+        let encoded = MultiEncoder::new(2, DefaultEncoder).encode(&ins).unwrap();
+        assert!(encoded.starts_with(b"v2;zone="));
+        assert_eq!(decoder.decode(&encoded).unwrap(), ins);
+        assert!(matches!(
+            decoder.decode(b"v;zone="),
+            Err(MultiCodecError::NoVersion)
+        ));
+        let unknown = MultiEncoder::new(3, DefaultEncoder).encode(&ins).unwrap();
+        assert!(matches!(
+            decoder.decode(&unknown),
+            Err(MultiCodecError::UnknownVersion(3))
+        ));
+    }
+}
@@ -0,0 +1,176 @@
+use super::{from_unix_millis, to_unix_millis, DecodeError, Decoder, EncodeError, Encoder};
+use crate::{intern, Instance};
+use prost::Message;
+use std::{collections::HashMap, error::Error, fmt};
+
+// the message of the schema of `ProtoEncoder`.
+#[derive(Clone, PartialEq, prost::Message)]
+struct ProtoInstance {
+    #[prost(string, tag = "1")]
+    zone: String,
+    #[prost(string, tag = "2")]
+    env: String,
+    #[prost(string, tag = "3")]
+    appid: String,
+    #[prost(string, tag = "4")]
+    hostname: String,
+    #[prost(string, repeated, tag = "5")]
+    addrs: Vec<String>,
+    #[prost(string, tag = "6")]
+    version: String,
+    #[prost(string, tag = "7")]
+    group: String,
+    #[prost(map = "string, string", tag = "8")]
+    metadata: HashMap<String, String>,
+    #[prost(uint64, tag = "9")]
+    registered_at: u64,
+    #[prost(uint64, tag = "10")]
+    last_renewed_at: u64,
+}
+
+#[derive(Debug)]
+pub enum ProtoCodecError {
+    Encode(prost::EncodeError),
+    Decode(prost::DecodeError),
+}
+
+impl fmt::Display for ProtoCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtoCodecError::Encode(e) => write!(f, "failed to encode instance proto: {}", e),
+            ProtoCodecError::Decode(e) => write!(f, "bad instance proto: {}", e),
+        }
+    }
+}
+
+impl Error for ProtoCodecError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProtoCodecError::Encode(e) => Some(e),
+            ProtoCodecError::Decode(e) => Some(e),
+        }
+    }
+}
+
+impl From<ProtoCodecError> for EncodeError {
+    fn from(e: ProtoCodecError) -> Self {
+        EncodeError::new(e)
+    }
+}
+
+impl From<ProtoCodecError> for DecodeError {
+    fn from(e: ProtoCodecError) -> Self {
+        DecodeError::new(e)
+    }
+}
+
+/// Encodes an instance as a protobuf message, compact and read by the
+/// clients of gRPC-centric ecosystems, of the schema:
+///
+/// ```proto
+/// syntax = "proto3";
+///
+/// package discover;
+///
+/// message Instance {
+///   string zone = 1;
+///   string env = 2;
+///   string appid = 3;
+///   string hostname = 4;
+///   repeated string addrs = 5;
+///   string version = 6;
+///   string group = 7;
+///   map<string, string> metadata = 8;
+///   // unix milliseconds, 0 when unknown.
+///   uint64 registered_at = 9;
+///   uint64 last_renewed_at = 10;
+/// }
+/// ```
+///
+/// The payloads are binary, use a layout holding instances as data, e.g.
+/// `zk::DataLayout`.
+pub struct ProtoEncoder;
+
+impl Encoder for ProtoEncoder {
+    type Error = ProtoCodecError;
+
+    fn encode_to(&self, ins: &Instance, buf: &mut Vec<u8>) -> Result<(), Self::Error> {
+        let message = ProtoInstance {
+            zone: ins.zone.to_string(),
+            env: ins.env.to_string(),
+            appid: ins.appid.to_string(),
+            hostname: ins.hostname.clone(),
+            addrs: ins.addrs.to_vec(),
+            version: ins.version.clone(),
+            group: ins.group.to_string(),
+            metadata: ins.metadata.clone(),
+            registered_at: ins.registered_at.map(to_unix_millis).unwrap_or(0),
+            last_renewed_at: ins.last_renewed_at.map(to_unix_millis).unwrap_or(0),
+        };
+        message.encode(buf).map_err(ProtoCodecError::Encode)
+    }
+}
+
+/// Decodes instances encoded by `ProtoEncoder`.
+pub struct ProtoDecoder;
+
+impl Decoder for ProtoDecoder {
+    type Error = ProtoCodecError;
+
+    fn decode(&self, data: &[u8]) -> Result<Instance, Self::Error> {
+        let message = ProtoInstance::decode(data).map_err(ProtoCodecError::Decode)?;
+        let time = |millis| {
+            Some(millis)
+                .filter(|millis| *millis > 0)
+                .map(from_unix_millis)
+        };
+        Ok(Instance {
+            zone: intern(&message.zone),
+            env: intern(&message.env),
+            appid: intern(&message.appid),
+            hostname: message.hostname,
+            addrs: message.addrs.into(),
+            version: message.version,
+            group: intern(&message.group),
+            metadata: message.metadata,
+            registered_at: time(message.registered_at),
+            last_renewed_at: time(message.last_renewed_at),
+            revision: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ProtoDecoder, ProtoEncoder};
+    use crate::{
+        codec::{from_unix_millis, Decoder, Encoder},
+        Instance,
+    };
+
+    #[test]
+    fn test_proto_codec() {
+        let ins = Instance {
+            zone: "sh1".into(),
+            appid: "provider".into(),
+            addrs: vec!["grpc://172.1.1.1:9999".to_owned()].into(),
+            metadata: [("weight".to_owned(), "10".to_owned())]
+                .iter()
+                .cloned()
+                .collect(),
+            registered_at: Some(from_unix_millis(1_590_000_000_123)),
+            ..Default::default()
+        };
+        let encoded = ProtoEncoder.encode(&ins).unwrap();
+        assert_eq!(ProtoDecoder.decode(&encoded).unwrap(), ins);
+        // appid = "provider" alone, as another client would write it.
+        assert_eq!(
+            ProtoDecoder.decode(b"\x1a\x08provider").unwrap(),
+            Instance {
+                appid: "provider".into(),
+                ..Default::default()
+            }
+        );
+        assert!(ProtoDecoder.decode(b"\x1a\x08prov").is_err());
+    }
+}
@@ -0,0 +1,309 @@
+use super::{from_unix_millis, to_unix_millis, DecodeError, Decoder, EncodeError, Encoder};
+use crate::{intern, Instance};
+use fxhash::FxHasher64;
+use serde_json::{json, Map, Value};
+use smallvec::smallvec;
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    hash::{Hash, Hasher},
+};
+
+const PAYLOAD_CLASS: &str = "org.springframework.cloud.zookeeper.discovery.ZookeeperInstance";
+
+/// Metadata entries mapped to `Instance` fields, the others are kept in
+/// metadata.
+const ZONE: &str = "zone";
+const ENV: &str = "env";
+const VERSION: &str = "version";
+const HOSTNAME: &str = "hostname";
+const GROUP: &str = "group";
+
+#[derive(Debug)]
+pub enum SpringCloudCodecError {
+    /// A Curator service instance has exactly one address.
+    Addrs(usize),
+    /// Only http and https addresses are supported.
+    BadAddr(String),
+    Json(serde_json::Error),
+    MissingField(&'static str),
+}
+
+impl fmt::Display for SpringCloudCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpringCloudCodecError::Addrs(n) => {
+                write!(f, "service instance needs exactly one addr, got {}", n)
+            }
+            SpringCloudCodecError::BadAddr(addr) => write!(f, "bad http addr {:?}", addr),
+            SpringCloudCodecError::Json(e) => write!(f, "bad service instance json: {}", e),
+            SpringCloudCodecError::MissingField(field) => {
+                write!(f, "service instance without {}", field)
+            }
+        }
+    }
+}
+
+impl Error for SpringCloudCodecError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SpringCloudCodecError::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for SpringCloudCodecError {
+    fn from(e: serde_json::Error) -> Self {
+        SpringCloudCodecError::Json(e)
+    }
+}
+
+impl From<SpringCloudCodecError> for EncodeError {
+    fn from(e: SpringCloudCodecError) -> Self {
+        EncodeError::new(e)
+    }
+}
+
+impl From<SpringCloudCodecError> for DecodeError {
+    fn from(e: SpringCloudCodecError) -> Self {
+        match e {
+            SpringCloudCodecError::MissingField(field) => DecodeError::new(e).with_field(field),
+            e => DecodeError::new(e),
+        }
+    }
+}
+
+/// The id of the Curator service instance of `ins`, a UUID derived from
+/// everything but its timestamps, so it is the same whenever the instance is
+/// encoded and differs once it changes.
+pub fn service_instance_id(ins: &Instance) -> String {
+    let hash = |seed: u64| {
+        let mut hasher = FxHasher64::default();
+        seed.hash(&mut hasher);
+        ins.zone.hash(&mut hasher);
+        ins.env.hash(&mut hasher);
+        ins.appid.hash(&mut hasher);
+        ins.hostname.hash(&mut hasher);
+        ins.addrs.hash(&mut hasher);
+        ins.version.hash(&mut hasher);
+        // left out in the default group, which the ids predate.
+        if !ins.group.is_empty() {
+            ins.group.hash(&mut hasher);
+        }
+        ins.metadata
+            .iter()
+            .collect::<BTreeMap<_, _>>()
+            .hash(&mut hasher);
+        hasher.finish()
+    };
+    let (high, low) = (hash(1), hash(2));
+    // formatted as a random (version 4) UUID, like the ones Curator generates.
+    format!(
+        "{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0x0fff,
+        (low >> 48) & 0x3fff | 0x8000,
+        low & 0xffff_ffff_ffff
+    )
+}
+
+/// Encodes an instance as the JSON Curator service instance Spring Cloud
+/// Zookeeper registers as node data, see `zk::SpringCloudLayout`.
+///
+/// The appid is the service name and the only addr, `http://` or
+/// `https://`, the address and port. Zone, env, version, hostname and group
+/// are carried in the metadata of the payload along with the metadata.
+pub struct SpringCloudEncoder;
+
+impl Encoder for SpringCloudEncoder {
+    type Error = SpringCloudCodecError;
+
+    fn encode_to(&self, ins: &Instance, buf: &mut Vec<u8>) -> Result<(), Self::Error> {
+        if ins.addrs.len() != 1 {
+            return Err(SpringCloudCodecError::Addrs(ins.addrs.len()));
+        }
+        let bad_addr = || SpringCloudCodecError::BadAddr(ins.addrs[0].clone());
+        let (ssl, host_port) = if let Some(host_port) = ins.addrs[0].strip_prefix("https://") {
+            (true, host_port)
+        } else if let Some(host_port) = ins.addrs[0].strip_prefix("http://") {
+            (false, host_port)
+        } else {
+            return Err(bad_addr());
+        };
+        let pos = host_port.rfind(':').ok_or_else(bad_addr)?;
+        let address = host_port[..pos]
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let port = host_port[pos + 1..]
+            .parse::<u16>()
+            .map_err(|_| bad_addr())?;
+
+        let mut metadata = ins
+            .metadata
+            .iter()
+            .map(|(k, v)| (k.clone(), Value::from(v.as_str())))
+            .collect::<Map<String, Value>>();
+        for (k, v) in [
+            (ZONE, &*ins.zone),
+            (ENV, &ins.env),
+            (VERSION, &ins.version),
+            (HOSTNAME, &ins.hostname),
+            (GROUP, &ins.group),
+        ]
+        .iter()
+        {
+            if !v.is_empty() {
+                metadata.insert(k.to_string(), Value::from(*v));
+            }
+        }
+
+        let appid = &*ins.appid;
+        let value = json!({
+            "name": appid,
+            "id": service_instance_id(ins),
+            "address": address,
+            "port": if ssl { None } else { Some(port) },
+            "sslPort": if ssl { Some(port) } else { None },
+            "payload": {
+                "@class": PAYLOAD_CLASS,
+                "id": appid,
+                "name": appid,
+                "metadata": metadata,
+            },
+            "registrationTimeUTC": ins.registered_at.map(to_unix_millis).unwrap_or(0),
+            "serviceType": "DYNAMIC",
+            "uriSpec": {
+                "parts": [
+                    {"value": "scheme", "variable": true},
+                    {"value": "://", "variable": false},
+                    {"value": "address", "variable": true},
+                    {"value": ":", "variable": false},
+                    {"value": "port", "variable": true},
+                ]
+            },
+        });
+        Ok(serde_json::to_writer(buf, &value)?)
+    }
+}
+
+/// Decodes Curator service instances, see `SpringCloudEncoder`.
+pub struct SpringCloudDecoder;
+
+impl Decoder for SpringCloudDecoder {
+    type Error = SpringCloudCodecError;
+
+    fn decode(&self, data: &[u8]) -> Result<Instance, Self::Error> {
+        let value = serde_json::from_slice::<Value>(data)?;
+        let field = |name: &'static str| {
+            value[name]
+                .as_str()
+                .ok_or(SpringCloudCodecError::MissingField(name))
+        };
+        let address = field("address")?;
+        let address = if address.contains(':') {
+            format!("[{}]", address)
+        } else {
+            address.to_owned()
+        };
+        let addr = match (value["sslPort"].as_u64(), value["port"].as_u64()) {
+            (Some(port), _) => format!("https://{}:{}", address, port),
+            (None, Some(port)) => format!("http://{}:{}", address, port),
+            (None, None) => return Err(SpringCloudCodecError::MissingField("port")),
+        };
+
+        let mut ins = Instance {
+            appid: intern(field("name")?),
+            addrs: smallvec![addr],
+            registered_at: value["registrationTimeUTC"]
+                .as_u64()
+                .filter(|millis| *millis > 0)
+                .map(from_unix_millis),
+            ..Default::default()
+        };
+        if let Some(metadata) = value["payload"]["metadata"].as_object() {
+            for (k, v) in metadata {
+                let v = match v {
+                    Value::String(v) => v.clone(),
+                    v => v.to_string(),
+                };
+                match k.as_str() {
+                    ZONE => ins.zone = intern(&v),
+                    ENV => ins.env = intern(&v),
+                    VERSION => ins.version = v,
+                    HOSTNAME => ins.hostname = v,
+                    GROUP => ins.group = intern(&v),
+                    _ => {
+                        ins.metadata.insert(k.clone(), v);
+                    }
+                }
+            }
+        }
+        Ok(ins)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{service_instance_id, SpringCloudDecoder, SpringCloudEncoder};
+    use crate::{
+        codec::{from_unix_millis, Decoder, Encoder},
+        Instance,
+    };
+    use smallvec::smallvec;
+
+    fn instance() -> Instance {
+        Instance {
+            zone: "sh1".into(),
+            appid: "provider".into(),
+            addrs: smallvec!["http://172.1.1.1:8080".to_owned()],
+            metadata: [("instance_status".to_owned(), "UP".to_owned())]
+                .iter()
+                .cloned()
+                .collect(),
+            registered_at: Some(from_unix_millis(1_590_000_000_123)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_spring_cloud_roundtrip() {
+        let encoded = SpringCloudEncoder.encode(&instance()).unwrap();
+        assert_eq!(SpringCloudDecoder.decode(&encoded).unwrap(), instance());
+
+        let mut https = instance();
+        https.addrs = smallvec!["https://[::1]:8443".to_owned()];
+        let encoded = SpringCloudEncoder.encode(&https).unwrap();
+        assert_eq!(SpringCloudDecoder.decode(&encoded).unwrap(), https);
+
+        let mut grpc = instance();
+        grpc.addrs = smallvec!["grpc://172.1.1.1:9999".to_owned()];
+        assert!(SpringCloudEncoder.encode(&grpc).is_err());
+    }
+
+    #[test]
+    fn test_spring_cloud_decode() {
+        // as registered by Spring Cloud Zookeeper.
+        let data = r#"{"name":"provider","id":"0f6f5f3c-6c0b-4e0a-a3a4-9a3a2b1b0c0d","address":"172.1.1.1","port":8080,"sslPort":null,"payload":{"@class":"org.springframework.cloud.zookeeper.discovery.ZookeeperInstance","id":"application-1","name":"provider","metadata":{"instance_status":"UP","zone":"sh1"}},"registrationTimeUTC":1590000000123,"serviceType":"DYNAMIC","uriSpec":{"parts":[{"value":"scheme","variable":true},{"value":"://","variable":false},{"value":"address","variable":true},{"value":":","variable":false},{"value":"port","variable":true}]}}"#;
+        assert_eq!(
+            SpringCloudDecoder.decode(data.as_bytes()).unwrap(),
+            instance()
+        );
+    }
+
+    #[test]
+    fn test_service_instance_id() {
+        let id = service_instance_id(&instance());
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+        let mut renewed = instance();
+        renewed.last_renewed_at = Some(from_unix_millis(1_590_000_100_456));
+        assert_eq!(service_instance_id(&renewed), id);
+        renewed
+            .metadata
+            .insert("weight".to_owned(), "10".to_owned());
+        assert_ne!(service_instance_id(&renewed), id);
+    }
+}
@@ -0,0 +1,394 @@
+//! Registries used as one, e.g. to write to both the old and the new
+//! registry while migrating from one to the other.
+use crate::{
+    identity::{DefaultIdentity, Identity},
+    watcher::{Event, WatchEvent},
+    Instance, Registry,
+};
+use futures::{future, Stream};
+use std::{
+    collections::{HashMap, HashSet},
+    error, fmt,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// Registers to every registry, and watches all of them as one.
+///
+/// Watchers report an instance once whichever registries have it, update
+/// it when the registries disagree, and delete it once none has.
+/// `update_if` goes to the first registry, the only one with revisions that
+/// mean something to the caller, and the updated instance is registered to
+/// the others. Registries of different types are put together with `boxed`.
+///
+/// ```ignore
+/// let registry = CompositeRegistry::new(vec![boxed(zk), boxed(etcd)]);
+/// registry.register(instance).await?;
+/// let discover = AppDiscover::new(registry.watch("provider"), make_service);
+/// ```
+pub struct CompositeRegistry<R, I = DefaultIdentity> {
+    registries: Arc<Vec<R>>,
+    identity: Arc<I>,
+}
+
+impl<R> CompositeRegistry<R> {
+    pub fn new(registries: Vec<R>) -> Self {
+        CompositeRegistry {
+            registries: Arc::new(registries),
+            identity: Arc::new(DefaultIdentity),
+        }
+    }
+}
+
+impl<R, I> CompositeRegistry<R, I> {
+    /// Sets how watchers tell that the registries report the same instance.
+    pub fn with_identity<NI>(self, identity: NI) -> CompositeRegistry<R, NI> {
+        CompositeRegistry {
+            registries: self.registries,
+            identity: Arc::new(identity),
+        }
+    }
+
+    pub fn registries(&self) -> &[R] {
+        &self.registries
+    }
+}
+
+impl<R, I> Clone for CompositeRegistry<R, I> {
+    fn clone(&self) -> Self {
+        CompositeRegistry {
+            registries: self.registries.clone(),
+            identity: self.identity.clone(),
+        }
+    }
+}
+
+/// The calls that failed, by the index of their registry. The others
+/// succeeded.
+#[derive(Debug)]
+pub struct CompositeError<E>(pub Vec<(usize, E)>);
+
+impl<E> fmt::Display for CompositeError<E>
+where
+    E: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed on")?;
+        for (i, (index, e)) in self.0.iter().enumerate() {
+            let sep = if i == 0 { "" } else { ";" };
+            write!(f, "{} registry {}: {}", sep, index, e)?;
+        }
+        Ok(())
+    }
+}
+
+impl<E> error::Error for CompositeError<E> where E: fmt::Debug + fmt::Display {}
+
+type CompositeFuture<T, E> = Pin<Box<dyn Future<Output = Result<T, CompositeError<E>>> + Send>>;
+
+// runs the calls at once, failing with every failure.
+async fn join<F, T, E>(calls: Vec<F>) -> Result<Vec<T>, CompositeError<E>>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    let (mut outputs, mut failures) = (Vec::new(), Vec::new());
+    for (index, result) in future::join_all(calls).await.into_iter().enumerate() {
+        match result {
+            Ok(output) => outputs.push(output),
+            Err(e) => failures.push((index, e)),
+        }
+    }
+    if failures.is_empty() {
+        Ok(outputs)
+    } else {
+        Err(CompositeError(failures))
+    }
+}
+
+fn all<F, E>(calls: Vec<F>) -> CompositeFuture<(), E>
+where
+    F: Future<Output = Result<(), E>> + Send + 'static,
+    E: Send + 'static,
+{
+    Box::pin(async move { join(calls).await.map(|_| ()) })
+}
+
+impl<R, I> Registry for CompositeRegistry<R, I>
+where
+    R: Registry + Send + Sync + 'static,
+    R::Error: Send + 'static,
+    R::RegFuture: Send + 'static,
+    R::DeRegFuture: Send + 'static,
+    R::ListFuture: Send + 'static,
+    R::Watcher: Unpin,
+    I: Identity + Send + Sync + 'static,
+{
+    type Error = CompositeError<R::Error>;
+
+    type RegFuture = CompositeFuture<(), R::Error>;
+
+    type DeRegFuture = CompositeFuture<(), R::Error>;
+
+    type ListFuture = CompositeFuture<Vec<Arc<Instance>>, R::Error>;
+
+    type Watcher = CompositeWatcher<R::Watcher, I>;
+
+    fn register(&self, ins: Arc<Instance>) -> Self::RegFuture {
+        all(self
+            .registries
+            .iter()
+            .map(|registry| registry.register(ins.clone()))
+            .collect())
+    }
+
+    fn deregister(&self, ins: &Arc<Instance>) -> Self::DeRegFuture {
+        all(self
+            .registries
+            .iter()
+            .map(|registry| registry.deregister(ins))
+            .collect())
+    }
+
+    fn evict(&self, appid: &str, instance_id: &str) -> Self::DeRegFuture {
+        all(self
+            .registries
+            .iter()
+            .map(|registry| registry.evict(appid, instance_id))
+            .collect())
+    }
+
+    fn update_if(&self, ins: Arc<Instance>, expected_version: u64) -> Self::RegFuture {
+        let registries = self.registries.clone();
+        let updated = match registries.first() {
+            Some(first) => first.update_if(ins.clone(), expected_version),
+            None => return Box::pin(future::ok(())),
+        };
+        Box::pin(async move {
+            updated.await.map_err(|e| CompositeError(vec![(0, e)]))?;
+            let others = registries[1..]
+                .iter()
+                .map(|registry| registry.register(ins.clone()))
+                .collect();
+            // numbered after the first.
+            all(others).await.map_err(|CompositeError(failures)| {
+                let failures = failures.into_iter().map(|(i, e)| (i + 1, e));
+                CompositeError(failures.collect())
+            })
+        })
+    }
+
+    fn renew(&self, ins: &Arc<Instance>) -> Self::RegFuture {
+        all(self
+            .registries
+            .iter()
+            .map(|registry| registry.renew(ins))
+            .collect())
+    }
+
+    // an instance in several registries is listed as the first has it.
+    fn list(&self, appid: &str) -> Self::ListFuture {
+        let lists = self
+            .registries
+            .iter()
+            .map(|registry| registry.list(appid))
+            .collect();
+        let identity = self.identity.clone();
+        Box::pin(async move {
+            let listed = join(lists).await?;
+            let mut seen = HashSet::new();
+            let instances = listed.into_iter().flatten();
+            Ok(instances
+                .filter(|ins| seen.insert(identity.identify(ins)))
+                .collect())
+        })
+    }
+
+    fn watch(&self, appid: &str) -> Self::Watcher {
+        self.watch_from(appid, &[])
+    }
+
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher {
+        let watchers = self
+            .registries
+            .iter()
+            .map(|registry| Some(registry.watch_from(appid, instances)))
+            .collect();
+        CompositeWatcher {
+            watchers,
+            next: 0,
+            identity: self.identity.clone(),
+            instances: HashMap::new(),
+        }
+    }
+}
+
+/// The instances of an app in any of the registries, see
+/// `CompositeRegistry`.
+pub struct CompositeWatcher<W, I: Identity> {
+    // none once ended.
+    watchers: Vec<Option<W>>,
+    // the watcher polled first, in turn so that none starves the others.
+    next: usize,
+    identity: Arc<I>,
+    instances: HashMap<I::Key, Reported>,
+}
+
+// the watchers are never pinned.
+impl<W, I> Unpin for CompositeWatcher<W, I>
+where
+    W: Unpin,
+    I: Identity,
+{
+}
+
+// an instance as reported, and as each registry has it.
+struct Reported {
+    reported: Arc<Instance>,
+    by: Vec<Option<Arc<Instance>>>,
+}
+
+impl<W, I> CompositeWatcher<W, I>
+where
+    I: Identity,
+{
+    // the event to report for `event` of the watcher `from`, if any.
+    fn merge(&mut self, from: usize, mut watch_event: WatchEvent) -> Option<WatchEvent> {
+        let len = self.watchers.len();
+        match &watch_event.event {
+            Event::Create(ins) | Event::Update(ins) => {
+                let key = self.identity.identify(ins);
+                let ins = ins.clone();
+                let reported = self.instances.entry(key).or_insert_with(|| Reported {
+                    reported: ins.clone(),
+                    by: vec![None; len],
+                });
+                let first = reported.by.iter().all(Option::is_none);
+                reported.by[from] = Some(ins.clone());
+                if first {
+                    watch_event.event = Event::Create(ins);
+                    return Some(watch_event);
+                }
+                if reported.reported == ins {
+                    return None;
+                }
+                reported.reported = ins.clone();
+                watch_event.event = Event::Update(ins);
+                Some(watch_event)
+            }
+            Event::Delete(ins) => {
+                let key = self.identity.identify(ins);
+                let reported = self.instances.get_mut(&key)?;
+                reported.by[from] = None;
+                // still in another registry, as it has it.
+                if let Some(other) = reported.by.iter().flatten().next() {
+                    if reported.reported == *other {
+                        return None;
+                    }
+                    reported.reported = other.clone();
+                    watch_event.event = Event::Update(other.clone());
+                    watch_event.reason = None;
+                    return Some(watch_event);
+                }
+                let reported = self.instances.remove(&key)?;
+                watch_event.event = Event::Delete(reported.reported);
+                Some(watch_event)
+            }
+        }
+    }
+}
+
+impl<W, I> Stream for CompositeWatcher<W, I>
+where
+    W: Stream<Item = WatchEvent> + Unpin,
+    I: Identity,
+{
+    type Item = WatchEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let len = this.watchers.len();
+        loop {
+            let mut pending = false;
+            let mut polled = None;
+            let next = this.next;
+            for i in (0..len).map(|i| (next + i) % len) {
+                let watcher = match &mut this.watchers[i] {
+                    Some(watcher) => watcher,
+                    None => continue,
+                };
+                match Pin::new(watcher).poll_next(cx) {
+                    Poll::Ready(Some(event)) => {
+                        polled = Some((i, event));
+                        break;
+                    }
+                    Poll::Ready(None) => this.watchers[i] = None,
+                    Poll::Pending => pending = true,
+                }
+            }
+            match polled {
+                Some((i, event)) => {
+                    this.next = (i + 1) % len;
+                    if let Some(event) = this.merge(i, event) {
+                        return Poll::Ready(Some(event));
+                    }
+                }
+                None if pending => return Poll::Pending,
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompositeError, CompositeRegistry};
+    use crate::{
+        testing::{collect_until_quiescent, instance, MockRegistry},
+        watcher::Event,
+        Registry,
+    };
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_composite() {
+        let (old, new) = (MockRegistry::new(), MockRegistry::new());
+        let registry = CompositeRegistry::new(vec![old.clone(), new.clone()]);
+        let (a, b) = (
+            instance("grpc://10.0.0.1:9000"),
+            instance("grpc://10.0.0.2:9000"),
+        );
+        old.insert(a.clone());
+        registry.register(b.clone()).await.unwrap();
+        old.assert_registered(&b);
+        new.assert_registered(&b);
+        // listed once though in both.
+        let mut listed = registry.list("provider").await.unwrap();
+        listed.sort_by_key(|ins| ins.addrs.to_vec());
+        assert_eq!(listed, [a.clone(), b.clone()]);
+
+        let quiet = Duration::from_millis(10);
+        let mut watcher = registry.watch("provider");
+        let mut events = collect_until_quiescent(&mut watcher, quiet).await;
+        events.sort_by_key(|event| format!("{:?}", event));
+        assert_eq!(events, [Event::Create(a.clone()), Event::Create(b.clone())]);
+
+        // only gone once gone from both.
+        old.remove(&b);
+        assert!(collect_until_quiescent(&mut watcher, quiet)
+            .await
+            .is_empty());
+        new.remove(&b);
+        let events = collect_until_quiescent(&mut watcher, quiet).await;
+        assert_eq!(events, [Event::Delete(b.clone())]);
+
+        new.fail_register("etcd is down");
+        let err = registry.register(a).await.unwrap_err();
+        assert!(matches!(&err, CompositeError(failures) if failures[0].0 == 1));
+        assert_eq!(
+            err.to_string(),
+            "failed on registry 1: mock registry error: etcd is down"
+        );
+    }
+}
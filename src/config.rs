@@ -0,0 +1,157 @@
+//! Registries configured from a file or the environment, in any format serde
+//! reads.
+//!
+//! ```toml
+//! codec = "dubbo"
+//!
+//! [registry]
+//! backend = "zk"
+//! connect_string = "10.0.0.1:2181,10.0.0.2:2181"
+//! session_timeout_ms = 10000
+//! root_prefix = "/discovery/prod"
+//!
+//! [watcher]
+//! identity = "hostname"
+//! ```
+//!
+//! ```ignore
+//! let config: DiscoverConfig = toml::from_str(&fs::read_to_string("discover.toml")?)?;
+//! let registry = build_registry(&config).await?;
+//! ```
+use crate::boxed::{BoxError, BoxRegistry};
+use serde::Deserialize;
+use std::{error, fmt};
+
+#[cfg(feature = "consul")]
+pub use self::consul::ConsulSettings;
+#[cfg(feature = "etcd")]
+pub use self::etcd::EtcdSettings;
+#[cfg(feature = "redis")]
+pub use self::redis::RedisSettings;
+#[cfg(feature = "zk")]
+pub use self::zk::{ZkAcl, ZkAuth, ZkSettings};
+
+// boxes `$registry` with the identity `$identity` selects.
+#[cfg(any(
+    feature = "zk",
+    feature = "etcd",
+    feature = "redis",
+    feature = "consul"
+))]
+macro_rules! with_identity {
+    ($registry:expr, $identity:expr) => {{
+        use crate::identity::{AppIdentity, DefaultIdentity, HostnameIdentity, MetadataIdentity};
+        let registry = $registry;
+        match $identity {
+            IdentityConfig::Default => boxed(registry.with_identity(DefaultIdentity)),
+            IdentityConfig::App => boxed(registry.with_identity(AppIdentity)),
+            IdentityConfig::Hostname => boxed(registry.with_identity(HostnameIdentity)),
+            IdentityConfig::Metadata(key) => {
+                boxed(registry.with_identity(MetadataIdentity::new(key)))
+            }
+        }
+    }};
+}
+
+#[cfg(feature = "consul")]
+mod consul;
+#[cfg(feature = "etcd")]
+mod etcd;
+#[cfg(feature = "redis")]
+mod redis;
+#[cfg(feature = "zk")]
+mod zk;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DiscoverConfig {
+    pub registry: RegistryConfig,
+    #[serde(default)]
+    pub codec: CodecConfig,
+    #[serde(default)]
+    pub watcher: WatcherConfig,
+}
+
+/// The backend, by its `backend` field. Only the backends of the features
+/// enabled can be configured.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum RegistryConfig {
+    #[cfg(feature = "zk")]
+    Zk(ZkSettings),
+    #[cfg(feature = "etcd")]
+    Etcd(EtcdSettings),
+    #[cfg(feature = "redis")]
+    Redis(RedisSettings),
+    #[cfg(feature = "consul")]
+    Consul(ConsulSettings),
+}
+
+/// How instances are encoded. The ZooKeeper backend lays them out like the
+/// registries of the same name, Consul only takes the default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CodecConfig {
+    #[default]
+    Default,
+    Dubbo,
+    SpringCloud,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct WatcherConfig {
+    pub identity: IdentityConfig,
+    /// The Dubbo category watched, `providers` by default.
+    pub category: Option<String>,
+}
+
+/// See `identity`, e.g. `identity = "hostname"` or
+/// `identity = { metadata = "instance_id" }`.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentityConfig {
+    #[default]
+    Default,
+    App,
+    Hostname,
+    Metadata(String),
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Invalid(String),
+    /// The backend couldn't be reached.
+    Connect(BoxError),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Invalid(e) => write!(f, "bad discover config: {}", e),
+            ConfigError::Connect(e) => write!(f, "failed to connect to the registry: {}", e),
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ConfigError::Invalid(_) => None,
+            ConfigError::Connect(e) => Some(&**e),
+        }
+    }
+}
+
+/// Connects to the registry `config` selects.
+pub async fn build_registry(config: &DiscoverConfig) -> Result<BoxRegistry, ConfigError> {
+    match config.registry {
+        #[cfg(feature = "zk")]
+        RegistryConfig::Zk(ref settings) => zk::build_registry(settings, config).await,
+        #[cfg(feature = "etcd")]
+        RegistryConfig::Etcd(ref settings) => etcd::build_registry(settings, config).await,
+        #[cfg(feature = "redis")]
+        RegistryConfig::Redis(ref settings) => redis::build_registry(settings, config),
+        #[cfg(feature = "consul")]
+        RegistryConfig::Consul(ref settings) => consul::build_registry(settings, config),
+    }
+}
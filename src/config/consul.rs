@@ -0,0 +1,70 @@
+use super::{CodecConfig, ConfigError, DiscoverConfig, IdentityConfig};
+use crate::{
+    boxed::{boxed, BoxRegistry},
+    consul::ConsulBuilder,
+};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// `ConsulBuilder`, with durations in milliseconds. The options left out keep
+/// the defaults of the builder.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConsulSettings {
+    pub address: String,
+    pub token: Option<String>,
+    pub check_ttl_ms: Option<u64>,
+    pub passing_only: Option<bool>,
+}
+
+pub(super) fn build_registry(
+    settings: &ConsulSettings,
+    config: &DiscoverConfig,
+) -> Result<BoxRegistry, ConfigError> {
+    // consul keeps instances as services of its own, not encoded.
+    if config.codec != CodecConfig::Default {
+        return Err(ConfigError::Invalid(format!(
+            "consul can't take the {:?} codec",
+            config.codec
+        )));
+    }
+    let mut builder = ConsulBuilder::new(&settings.address);
+    if let Some(token) = &settings.token {
+        builder = builder.token(token);
+    }
+    if let Some(ttl) = settings.check_ttl_ms {
+        builder = builder.check_ttl(Duration::from_millis(ttl));
+    }
+    if let Some(passing_only) = settings.passing_only {
+        builder = builder.passing_only(passing_only);
+    }
+    Ok(with_identity!(builder.build(), &config.watcher.identity))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::build_registry;
+    use crate::config::{ConfigError, DiscoverConfig, RegistryConfig};
+
+    #[test]
+    fn test_consul_settings() {
+        let config: DiscoverConfig = serde_json::from_str(
+            r#"{
+                "codec": "dubbo",
+                "registry": {"backend": "consul", "address": "http://127.0.0.1:8500", "passing_only": true}
+            }"#,
+        )
+        .unwrap();
+        let settings = match &config.registry {
+            RegistryConfig::Consul(settings) => settings,
+            #[allow(unreachable_patterns)]
+            other => panic!("unexpected registry {:?}", other),
+        };
+        assert_eq!(settings.address, "http://127.0.0.1:8500");
+        assert_eq!(settings.passing_only, Some(true));
+        assert_eq!(settings.check_ttl_ms, None);
+        match build_registry(settings, &config) {
+            Err(ConfigError::Invalid(_)) => {}
+            _ => panic!("consul took the dubbo codec"),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use super::{CodecConfig, ConfigError, DiscoverConfig, IdentityConfig};
+use crate::{
+    boxed::{boxed, BoxRegistry},
+    codec::{new_dubbo_codec, new_spring_cloud_codec, Decoder, Encoder},
+    etcd::EtcdBuilder,
+};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// `EtcdBuilder`, with durations in milliseconds. The options left out keep
+/// the defaults of the builder.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EtcdSettings {
+    pub endpoints: String,
+    pub root_prefix: Option<String>,
+    pub lease_ttl_ms: Option<u64>,
+}
+
+pub(super) async fn build_registry(
+    settings: &EtcdSettings,
+    config: &DiscoverConfig,
+) -> Result<BoxRegistry, ConfigError> {
+    let mut builder = EtcdBuilder::new(&settings.endpoints);
+    if let Some(prefix) = &settings.root_prefix {
+        builder = builder.root_prefix(prefix);
+    }
+    if let Some(ttl) = settings.lease_ttl_ms {
+        builder = builder.lease_ttl(Duration::from_millis(ttl));
+    }
+    match config.codec {
+        CodecConfig::Default => build_etcd(builder, config).await,
+        CodecConfig::Dubbo => build_etcd(builder.codec(new_dubbo_codec()), config).await,
+        CodecConfig::SpringCloud => {
+            build_etcd(builder.codec(new_spring_cloud_codec()), config).await
+        }
+    }
+}
+
+async fn build_etcd<EC, DC>(
+    builder: EtcdBuilder<EC, DC>,
+    config: &DiscoverConfig,
+) -> Result<BoxRegistry, ConfigError>
+where
+    EC: Encoder + Send + Sync + 'static,
+    DC: Decoder + Send + Sync + 'static,
+{
+    let etcd = builder
+        .build()
+        .await
+        .map_err(|e| ConfigError::Connect(e.into()))?;
+    Ok(with_identity!(etcd, &config.watcher.identity))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{DiscoverConfig, RegistryConfig};
+
+    #[test]
+    fn test_etcd_settings() {
+        let config: DiscoverConfig = serde_json::from_str(
+            r#"{"registry": {"backend": "etcd", "endpoints": "10.0.0.1:2379", "lease_ttl_ms": 5000}}"#,
+        )
+        .unwrap();
+        match &config.registry {
+            RegistryConfig::Etcd(settings) => {
+                assert_eq!(settings.endpoints, "10.0.0.1:2379");
+                assert_eq!(settings.root_prefix, None);
+                assert_eq!(settings.lease_ttl_ms, Some(5000));
+            }
+            #[allow(unreachable_patterns)]
+            other => panic!("unexpected registry {:?}", other),
+        }
+        assert!(
+            serde_json::from_str::<DiscoverConfig>(r#"{"registry": {"backend": "etcd"}}"#).is_err()
+        );
+    }
+}
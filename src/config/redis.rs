@@ -0,0 +1,83 @@
+use super::{CodecConfig, ConfigError, DiscoverConfig, IdentityConfig};
+use crate::{
+    boxed::{boxed, BoxRegistry},
+    codec::{new_dubbo_codec, new_spring_cloud_codec, Decoder, Encoder},
+    redis::RedisBuilder,
+};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// `RedisBuilder`, with durations in milliseconds. The options left out keep
+/// the defaults of the builder.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedisSettings {
+    pub address: String,
+    pub password: Option<String>,
+    pub db: Option<u32>,
+    pub key_prefix: Option<String>,
+    pub ttl_ms: Option<u64>,
+    pub poll_interval_ms: Option<u64>,
+    pub notifications: Option<bool>,
+}
+
+pub(super) fn build_registry(
+    settings: &RedisSettings,
+    config: &DiscoverConfig,
+) -> Result<BoxRegistry, ConfigError> {
+    let mut builder = RedisBuilder::new(&settings.address);
+    if let Some(password) = &settings.password {
+        builder = builder.password(password);
+    }
+    if let Some(db) = settings.db {
+        builder = builder.db(db);
+    }
+    if let Some(prefix) = &settings.key_prefix {
+        builder = builder.key_prefix(prefix);
+    }
+    if let Some(ttl) = settings.ttl_ms {
+        builder = builder.ttl(Duration::from_millis(ttl));
+    }
+    if let Some(interval) = settings.poll_interval_ms {
+        builder = builder.poll_interval(Duration::from_millis(interval));
+    }
+    if let Some(enabled) = settings.notifications {
+        builder = builder.notifications(enabled);
+    }
+    Ok(match config.codec {
+        CodecConfig::Default => build_redis(builder, config),
+        CodecConfig::Dubbo => build_redis(builder.codec(new_dubbo_codec()), config),
+        CodecConfig::SpringCloud => build_redis(builder.codec(new_spring_cloud_codec()), config),
+    })
+}
+
+// connects lazily, so never fails.
+fn build_redis<EC, DC>(builder: RedisBuilder<EC, DC>, config: &DiscoverConfig) -> BoxRegistry
+where
+    EC: Encoder + Send + Sync + 'static,
+    DC: Decoder + Send + Sync + 'static,
+{
+    with_identity!(builder.build(), &config.watcher.identity)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{DiscoverConfig, RegistryConfig};
+
+    #[test]
+    fn test_redis_settings() {
+        let config: DiscoverConfig = serde_json::from_str(
+            r#"{"registry": {"backend": "redis", "address": "10.0.0.1:6379", "db": 2, "notifications": false}}"#,
+        )
+        .unwrap();
+        match &config.registry {
+            RegistryConfig::Redis(settings) => {
+                assert_eq!(settings.address, "10.0.0.1:6379");
+                assert_eq!(settings.db, Some(2));
+                assert_eq!(settings.notifications, Some(false));
+                assert_eq!(settings.ttl_ms, None);
+            }
+            #[allow(unreachable_patterns)]
+            other => panic!("unexpected registry {:?}", other),
+        }
+    }
+}
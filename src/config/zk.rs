@@ -0,0 +1,216 @@
+use super::{CodecConfig, ConfigError, DiscoverConfig, IdentityConfig, WatcherConfig};
+use crate::{
+    boxed::{boxed, BoxRegistry},
+    codec::{new_dubbo_codec, new_spring_cloud_codec, Decoder, Encoder},
+    zk::{
+        AppidLayout, Category, DubboLayout, Layout, RetryPolicy, SpringCloudLayout, ZkBuilder,
+        ZkConfig,
+    },
+};
+use serde::Deserialize;
+use std::time::Duration;
+use zookeeper::{Acl, Permission};
+
+/// `ZkConfig`, with durations in milliseconds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ZkSettings {
+    pub connect_string: String,
+    pub session_timeout_ms: u64,
+    pub connect_timeout_ms: u64,
+    pub auth: Vec<ZkAuth>,
+    /// Open to everyone when empty.
+    pub acl: Vec<ZkAcl>,
+    pub root_prefix: String,
+    pub max_retries: usize,
+    pub retry_backoff_ms: u64,
+    pub worker_threads: usize,
+    /// Never resyncs when none.
+    pub resync_interval_ms: Option<u64>,
+    pub sequential: bool,
+}
+
+impl Default for ZkSettings {
+    fn default() -> Self {
+        let config = ZkConfig::default();
+        ZkSettings {
+            connect_string: config.connect_string,
+            session_timeout_ms: config.session_timeout.as_millis() as u64,
+            connect_timeout_ms: config.connect_timeout.as_millis() as u64,
+            auth: Vec::new(),
+            acl: Vec::new(),
+            root_prefix: config.root_prefix,
+            max_retries: config.retry.max_retries,
+            retry_backoff_ms: config.retry.backoff.as_millis() as u64,
+            worker_threads: config.worker_threads,
+            resync_interval_ms: None,
+            sequential: config.sequential,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ZkAuth {
+    pub scheme: String,
+    pub auth: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ZkAcl {
+    /// Like the ZooKeeper CLI, any of `crwda`.
+    pub perms: String,
+    pub scheme: String,
+    pub id: String,
+}
+
+impl ZkSettings {
+    pub fn to_zk_config(&self) -> Result<ZkConfig, ConfigError> {
+        let mut config = ZkConfig {
+            connect_string: self.connect_string.clone(),
+            session_timeout: Duration::from_millis(self.session_timeout_ms),
+            connect_timeout: Duration::from_millis(self.connect_timeout_ms),
+            auth: self
+                .auth
+                .iter()
+                .map(|auth| (auth.scheme.clone(), auth.auth.clone().into_bytes()))
+                .collect(),
+            root_prefix: self.root_prefix.clone(),
+            retry: RetryPolicy {
+                max_retries: self.max_retries,
+                backoff: Duration::from_millis(self.retry_backoff_ms),
+            },
+            worker_threads: self.worker_threads,
+            resync_interval: self.resync_interval_ms.map(Duration::from_millis),
+            sequential: self.sequential,
+            ..Default::default()
+        };
+        if !self.acl.is_empty() {
+            config.acl = self
+                .acl
+                .iter()
+                .map(|acl| Ok(Acl::new(perms(&acl.perms)?, &acl.scheme, &acl.id)))
+                .collect::<Result<_, ConfigError>>()?;
+        }
+        Ok(config)
+    }
+}
+
+fn perms(perms: &str) -> Result<Permission, ConfigError> {
+    perms.chars().try_fold(Permission::NONE, |all, perm| {
+        let perm = match perm {
+            'c' => Permission::CREATE,
+            'r' => Permission::READ,
+            'w' => Permission::WRITE,
+            'd' => Permission::DELETE,
+            'a' => Permission::ADMIN,
+            _ => return Err(ConfigError::Invalid(format!("unknown permission {}", perm))),
+        };
+        Ok(all | perm)
+    })
+}
+
+fn category(name: &str) -> Result<Category, ConfigError> {
+    let all = [
+        Category::Providers,
+        Category::Consumers,
+        Category::Configurators,
+        Category::Routers,
+    ];
+    all.iter()
+        .find(|category| category.as_str() == name)
+        .copied()
+        .ok_or_else(|| ConfigError::Invalid(format!("unknown category {}", name)))
+}
+
+pub(super) async fn build_registry(
+    settings: &ZkSettings,
+    config: &DiscoverConfig,
+) -> Result<BoxRegistry, ConfigError> {
+    let builder = ZkBuilder::from_config(settings.to_zk_config()?);
+    let watcher = &config.watcher;
+    match config.codec {
+        CodecConfig::Default => build_zk(builder, AppidLayout, watcher).await,
+        CodecConfig::Dubbo => {
+            let mut layout = DubboLayout::new();
+            if let Some(name) = &watcher.category {
+                layout = layout.watch(category(name)?);
+            }
+            build_zk(builder.codec(new_dubbo_codec()), layout, watcher).await
+        }
+        CodecConfig::SpringCloud => {
+            let builder = builder.codec(new_spring_cloud_codec());
+            build_zk(builder, SpringCloudLayout::new(), watcher).await
+        }
+    }
+}
+
+async fn build_zk<EC, DC, L>(
+    builder: ZkBuilder<EC, DC>,
+    layout: L,
+    watcher: &WatcherConfig,
+) -> Result<BoxRegistry, ConfigError>
+where
+    EC: Encoder + Send + Sync + 'static,
+    DC: Decoder + Send + Sync + 'static,
+    L: Layout + 'static,
+{
+    let zk = builder
+        .build()
+        .await
+        .map_err(|e| ConfigError::Connect(e.into()))?
+        .with_layout(layout);
+    Ok(with_identity!(zk, &watcher.identity))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{CodecConfig, DiscoverConfig, IdentityConfig, RegistryConfig};
+    use std::time::Duration;
+
+    #[test]
+    fn test_discover_config() {
+        let config: DiscoverConfig = serde_json::from_str(
+            r#"{
+                "codec": "dubbo",
+                "registry": {
+                    "backend": "zk",
+                    "connect_string": "10.0.0.1:2181",
+                    "session_timeout_ms": 10000,
+                    "auth": [{"scheme": "digest", "auth": "user:password"}],
+                    "acl": [{"perms": "crwda", "scheme": "digest", "id": "user:hash"}]
+                },
+                "watcher": {"identity": {"metadata": "instance_id"}}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.codec, CodecConfig::Dubbo);
+        assert_eq!(
+            config.watcher.identity,
+            IdentityConfig::Metadata("instance_id".to_owned())
+        );
+        let settings = match &config.registry {
+            RegistryConfig::Zk(settings) => settings,
+            #[allow(unreachable_patterns)]
+            other => panic!("unexpected registry {:?}", other),
+        };
+        let zk = settings.to_zk_config().unwrap();
+        assert_eq!(zk.connect_string, "10.0.0.1:2181");
+        assert_eq!(zk.session_timeout, Duration::from_secs(10));
+        assert_eq!(zk.connect_timeout, Duration::from_secs(3));
+        assert_eq!(
+            zk.auth,
+            vec![("digest".to_owned(), b"user:password".to_vec())]
+        );
+        assert_eq!(zk.acl[0].id, "user:hash");
+
+        let mut settings = settings.clone();
+        settings.acl[0].perms = "rx".to_owned();
+        assert!(settings.to_zk_config().is_err());
+
+        let minimal: DiscoverConfig =
+            serde_json::from_str(r#"{"registry": {"backend": "zk"}}"#).unwrap();
+        assert_eq!(minimal.codec, CodecConfig::Default);
+        assert_eq!(minimal.watcher.identity, IdentityConfig::Default);
+        assert!(serde_json::from_str::<DiscoverConfig>(r#"{"registry": {}}"#).is_err());
+    }
+}
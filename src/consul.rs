@@ -0,0 +1,638 @@
+//! A registry on Consul.
+//!
+//! Instances are services registered with the local agent, named after their
+//! appid, with a TTL check the registry passes while the process runs.
+//! Watches are blocking queries on the health endpoint, instances are
+//! reported while their checks pass.
+//!
+//! ```ignore
+//! let consul = Consul::builder("http://127.0.0.1:8500")
+//!     .token("...")
+//!     .build();
+//! consul.register(instance).await?;
+//! let discover = AppDiscover::new(consul.watch("billing"), make_service);
+//! ```
+//!
+//! The fields of instances besides their appid and address are kept in the
+//! service metadata, so keys of instance metadata Consul doesn't allow, e.g.
+//! with dots, are left out. Services registered by other means are reported
+//! with their address as `http://<address>:<port>`.
+use crate::{
+    flat,
+    identity::{DefaultIdentity, Identity},
+    runtime::{self, AbortOnDrop},
+    watcher::{Clock, Event, SystemClock, WatchEvent},
+    Instance, Registry,
+};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future::BoxFuture,
+    FutureExt, Stream,
+};
+use hyper::{body::Bytes, client::HttpConnector, Body, Method, Request, StatusCode};
+use log::{error, warn};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use pin_project::pin_project;
+use serde_json::{json, Map, Value};
+use std::{
+    collections::HashMap,
+    error, fmt,
+    hash::Hash,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+// how long a failed watch waits before querying again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Consul<I = DefaultIdentity> {
+    client: Client,
+    check_ttl: Duration,
+    passing_only: bool,
+    // the registrations by service id, registered again when the agent lost
+    // them, e.g. after a restart.
+    registered: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+    _keep_alive: AbortOnDrop,
+}
+
+impl Consul {
+    /// Talks to the agent at `address`, see `ConsulBuilder` for the options.
+    pub fn builder(address: &str) -> ConsulBuilder {
+        ConsulBuilder::new(address)
+    }
+}
+
+impl<I> Consul<I> {
+    /// Sets how watchers tell instances apart.
+    pub fn with_identity<NI>(self, identity: NI) -> Consul<NI> {
+        Consul {
+            client: self.client,
+            check_ttl: self.check_ttl,
+            passing_only: self.passing_only,
+            registered: self.registered,
+            identity: Arc::new(identity),
+            clock: self.clock,
+            _keep_alive: self._keep_alive,
+        }
+    }
+
+    /// Sets the clock timestamping the events of watchers.
+    pub fn with_clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+}
+
+/// Builds a `Consul`.
+///
+/// ```ignore
+/// let consul = Consul::builder("http://127.0.0.1:8500")
+///     .check_ttl(Duration::from_secs(15))
+///     .passing_only(true)
+///     .build();
+/// ```
+pub struct ConsulBuilder {
+    address: String,
+    token: Option<String>,
+    check_ttl: Duration,
+    passing_only: bool,
+}
+
+impl ConsulBuilder {
+    pub fn new(address: &str) -> Self {
+        ConsulBuilder {
+            address: address.trim_end_matches('/').to_owned(),
+            token: None,
+            check_ttl: Duration::from_secs(15),
+            passing_only: false,
+        }
+    }
+
+    /// The ACL token of the calls.
+    pub fn token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_owned());
+        self
+    }
+
+    /// How long an instance stays healthy once the process stops passing its
+    /// check, 15 seconds by default. Passed every third of it. The agent
+    /// removes instances critical for a minute.
+    pub fn check_ttl(mut self, ttl: Duration) -> Self {
+        self.check_ttl = ttl;
+        self
+    }
+
+    /// Whether instances with checks in warning are left out of watches,
+    /// only critical ones are by default.
+    pub fn passing_only(mut self, passing_only: bool) -> Self {
+        self.passing_only = passing_only;
+        self
+    }
+
+    /// Needs a tokio runtime, passing the checks of the instances registered
+    /// runs in the background.
+    pub fn build(self) -> Consul {
+        let client = Client {
+            http: hyper::Client::new(),
+            address: self.address,
+            token: self.token,
+        };
+        let registered = Arc::new(Mutex::new(HashMap::new()));
+        let interval = self.check_ttl / 3;
+        let keep_alive = {
+            let (client, registered) = (client.clone(), registered.clone());
+            async move {
+                loop {
+                    runtime::delay_for(interval).await;
+                    pass_checks(&client, &registered).await;
+                }
+            }
+        };
+        let keep_alive = runtime::spawn_abortable(keep_alive);
+        Consul {
+            client,
+            check_ttl: self.check_ttl,
+            passing_only: self.passing_only,
+            registered,
+            identity: Arc::new(DefaultIdentity),
+            clock: Arc::new(SystemClock),
+            _keep_alive: keep_alive,
+        }
+    }
+}
+
+async fn pass_checks(client: &Client, registered: &Mutex<HashMap<String, Vec<u8>>>) {
+    let services = registered.lock().unwrap().clone();
+    for (id, registration) in services {
+        if let Err(e) = pass_check(client, &id, registration).await {
+            error!("failed to pass the check of the service {}. {}", id, e);
+        }
+    }
+}
+
+// passes the check of the service `id`, registering it again when lost.
+async fn pass_check(client: &Client, id: &str, registration: Vec<u8>) -> Result<(), ConsulError> {
+    let path = format!("/v1/agent/check/pass/{}", encode(&check_id(id)));
+    match client.call(Method::PUT, &path, Vec::new()).await {
+        Ok(_) => Ok(()),
+        Err(ConsulError::Status(StatusCode::NOT_FOUND, _)) => {
+            warn!("consul lost the service {}, registering it again", id);
+            client
+                .call(Method::PUT, "/v1/agent/service/register", registration)
+                .await?;
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Clone)]
+struct Client {
+    http: hyper::Client<HttpConnector>,
+    address: String,
+    token: Option<String>,
+}
+
+impl Client {
+    // the body and the `X-Consul-Index` of the response.
+    async fn call(
+        &self,
+        method: Method,
+        path: &str,
+        body: Vec<u8>,
+    ) -> Result<(Bytes, Option<u64>), ConsulError> {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(format!("{}{}", self.address, path));
+        if let Some(token) = &self.token {
+            req = req.header("X-Consul-Token", token.as_str());
+        }
+        let req = req
+            .body(Body::from(body))
+            .map_err(|e| ConsulError::InvalidAddress(e.to_string()))?;
+        let rsp = self.http.request(req).await?;
+        let status = rsp.status();
+        let index = rsp
+            .headers()
+            .get("X-Consul-Index")
+            .and_then(|index| index.to_str().ok())
+            .and_then(|index| index.parse().ok());
+        let body = hyper::body::to_bytes(rsp.into_body()).await?;
+        if !status.is_success() {
+            let message = String::from_utf8_lossy(&body).into_owned();
+            return Err(ConsulError::Status(status, message));
+        }
+        Ok((body, index))
+    }
+
+    // the entries of the service `appid` as of `index`, blocking until it
+    // changes.
+    async fn health(&self, appid: &str, index: u64) -> Result<(Vec<Value>, u64), ConsulError> {
+        let path = format!(
+            "/v1/health/service/{}?index={}&wait=5m",
+            encode(appid),
+            index
+        );
+        let (body, new_index) = self.call(Method::GET, &path, Vec::new()).await?;
+        let entries = serde_json::from_slice(&body)?;
+        Ok((entries, new_index.unwrap_or(0)))
+    }
+}
+
+fn encode(segment: &str) -> String {
+    utf8_percent_encode(segment, NON_ALPHANUMERIC).to_string()
+}
+
+// named after the fields identifying `ins`, so registering it again replaces
+// it.
+fn service_id(ins: &Instance) -> String {
+    format!("{}-{:016x}", ins.appid, fxhash::hash64(ins))
+}
+
+fn check_id(service_id: &str) -> String {
+    format!("service:{}", service_id)
+}
+
+// the service registering `ins`, with a TTL check.
+fn to_service(ins: &Instance, id: &str, check_ttl: Duration) -> Value {
+    let mut meta = Map::new();
+    for (key, value) in flat::to_metadata(ins) {
+        let allowed = !key.starts_with("consul-")
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if allowed {
+            meta.insert(key, json!(value));
+        } else {
+            warn!(
+                "left out the metadata {} of {}, consul doesn't allow it",
+                key, id
+            );
+        }
+    }
+    let (address, port) = flat::address(ins);
+    json!({
+        "ID": id,
+        "Name": &*ins.appid,
+        "Address": address,
+        "Port": port,
+        "Meta": meta,
+        "Check": {
+            "CheckID": check_id(id),
+            "TTL": format!("{}s", check_ttl.as_secs().max(1)),
+            "Status": "passing",
+            "DeregisterCriticalServiceAfter": "1m",
+        },
+    })
+}
+
+// the instance of an entry of the health endpoint.
+fn from_entry(entry: &Value) -> Option<Instance> {
+    let service = &entry["Service"];
+    let address = match service["Address"].as_str() {
+        Some(address) if !address.is_empty() => address,
+        _ => entry["Node"]["Address"].as_str()?,
+    };
+    let default_addr = format!("http://{}:{}", address, service["Port"].as_u64()?);
+    let metadata = service["Meta"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_owned())))
+        .collect();
+    let appid = service["Service"].as_str()?;
+    Some(Instance {
+        revision: service["ModifyIndex"].as_u64(),
+        ..flat::from_metadata(appid, default_addr, metadata)
+    })
+}
+
+// whether the checks of an entry pass, or only warn unless `passing_only`.
+fn serving(entry: &Value, passing_only: bool) -> bool {
+    let checks = match entry["Checks"].as_array() {
+        Some(checks) => checks,
+        None => return true,
+    };
+    checks.iter().all(|check| match check["Status"].as_str() {
+        Some("passing") => true,
+        Some("warning") => !passing_only,
+        _ => false,
+    })
+}
+
+#[derive(Debug)]
+pub enum ConsulError {
+    InvalidAddress(String),
+    Http(hyper::Error),
+    /// The agent answered with an error.
+    Status(StatusCode, String),
+    Json(serde_json::Error),
+    /// Consul can't update services only at a given version, see
+    /// `Registry::update_if`.
+    Unsupported,
+}
+
+impl fmt::Display for ConsulError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsulError::InvalidAddress(e) => write!(f, "invalid consul address: {}", e),
+            ConsulError::Http(e) => write!(f, "failed to call consul: {}", e),
+            ConsulError::Status(status, message) => {
+                write!(f, "consul answered {}: {}", status, message)
+            }
+            ConsulError::Json(e) => write!(f, "bad json from consul: {}", e),
+            ConsulError::Unsupported => write!(f, "unsupported by consul"),
+        }
+    }
+}
+
+impl error::Error for ConsulError {}
+
+impl From<hyper::Error> for ConsulError {
+    fn from(e: hyper::Error) -> Self {
+        ConsulError::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for ConsulError {
+    fn from(e: serde_json::Error) -> Self {
+        ConsulError::Json(e)
+    }
+}
+
+impl<I> Registry for Consul<I>
+where
+    I: Identity + Send + Sync + 'static,
+    I::Key: Send,
+{
+    type Error = ConsulError;
+
+    type RegFuture = BoxFuture<'static, Result<(), ConsulError>>;
+
+    type DeRegFuture = BoxFuture<'static, Result<(), ConsulError>>;
+
+    type ListFuture = BoxFuture<'static, Result<Vec<Arc<Instance>>, ConsulError>>;
+
+    type Watcher = ConsulWatcher;
+
+    fn register(&self, ins: Arc<Instance>) -> Self::RegFuture {
+        let client = self.client.clone();
+        let registered = self.registered.clone();
+        let id = service_id(&ins);
+        let registration = to_service(&ins, &id, self.check_ttl)
+            .to_string()
+            .into_bytes();
+        async move {
+            client
+                .call(
+                    Method::PUT,
+                    "/v1/agent/service/register",
+                    registration.clone(),
+                )
+                .await?;
+            registered.lock().unwrap().insert(id, registration);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn deregister(&self, ins: &Arc<Instance>) -> Self::DeRegFuture {
+        let client = self.client.clone();
+        let id = service_id(ins);
+        self.registered.lock().unwrap().remove(&id);
+        async move {
+            let path = format!("/v1/agent/service/deregister/{}", encode(&id));
+            client.call(Method::PUT, &path, Vec::new()).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    // deregistered from the catalog, as they may be registered with other
+    // agents. Consul doesn't tell why a service went away, so watchers report
+    // evictions as deletes without a reason.
+    fn evict(&self, appid: &str, instance_id: &str) -> Self::DeRegFuture {
+        let client = self.client.clone();
+        let appid = appid.to_owned();
+        let instance_id = instance_id.to_owned();
+        async move {
+            let (entries, _) = client.health(&appid, 0).await?;
+            for entry in entries {
+                match from_entry(&entry) {
+                    Some(ins) if ins.has_id(&instance_id) => {}
+                    _ => continue,
+                }
+                let deregistration = json!({
+                    "Node": entry["Node"]["Node"],
+                    "ServiceID": entry["Service"]["ID"],
+                });
+                let body = deregistration.to_string().into_bytes();
+                client
+                    .call(Method::PUT, "/v1/catalog/deregister", body)
+                    .await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    // Consul has no check-and-set on services.
+    fn update_if(&self, _ins: Arc<Instance>, _expected_version: u64) -> Self::RegFuture {
+        futures::future::ready(Err(ConsulError::Unsupported)).boxed()
+    }
+
+    fn renew(&self, ins: &Arc<Instance>) -> Self::RegFuture {
+        let client = self.client.clone();
+        let id = service_id(ins);
+        let registration = to_service(ins, &id, self.check_ttl)
+            .to_string()
+            .into_bytes();
+        async move { pass_check(&client, &id, registration).await }.boxed()
+    }
+
+    // the instances watchers would report, at index 0 not blocking.
+    fn list(&self, appid: &str) -> Self::ListFuture {
+        let client = self.client.clone();
+        let appid = appid.to_owned();
+        let passing_only = self.passing_only;
+        async move {
+            let (entries, _) = client.health(&appid, 0).await?;
+            Ok(entries
+                .iter()
+                .filter(|entry| serving(entry, passing_only))
+                .filter_map(from_entry)
+                .map(Arc::new)
+                .collect())
+        }
+        .boxed()
+    }
+
+    fn watch(&self, appid: &str) -> Self::Watcher {
+        self.watch_from(appid, &[])
+    }
+
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher {
+        let (tx, rx) = mpsc::unbounded();
+        let mut known = HashMap::new();
+        for ins in instances {
+            known.insert(self.identity.identify(ins), ins.clone());
+            let event = WatchEvent::with_clock(Event::Create(ins.clone()), &*self.clock);
+            let _ = tx.unbounded_send(event);
+        }
+        let watch = AppWatch {
+            client: self.client.clone(),
+            appid: appid.to_owned(),
+            passing_only: self.passing_only,
+            identity: self.identity.clone(),
+            clock: self.clock.clone(),
+            known,
+            tx,
+        };
+        ConsulWatcher {
+            rx,
+            _task: runtime::spawn_abortable(watch.run()),
+        }
+    }
+}
+
+/// The instances of an app in Consul, see `Registry::watch`.
+#[pin_project]
+pub struct ConsulWatcher {
+    #[pin]
+    rx: UnboundedReceiver<WatchEvent>,
+    _task: AbortOnDrop,
+}
+
+impl Stream for ConsulWatcher {
+    type Item = WatchEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().rx.poll_next(cx)
+    }
+}
+
+struct AppWatch<I: Identity> {
+    client: Client,
+    appid: String,
+    passing_only: bool,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+    // the instances reported.
+    known: HashMap<I::Key, Arc<Instance>>,
+    tx: UnboundedSender<WatchEvent>,
+}
+
+impl<I> AppWatch<I>
+where
+    I: Identity,
+    I::Key: Hash + Eq,
+{
+    async fn run(mut self) {
+        let mut index = 0;
+        loop {
+            match self.client.health(&self.appid, index).await {
+                Ok((entries, new_index)) => {
+                    // the index went back, e.g. with a new leader.
+                    index = if new_index < index { 0 } else { new_index };
+                    self.update(&entries);
+                }
+                Err(e) => {
+                    error!("failed to watch {}. {}", self.appid, e);
+                    runtime::delay_for(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    // reports the instances serving now and not before as Creates, the ones
+    // changed too, and the ones gone as Deletes.
+    fn update(&mut self, entries: &[Value]) {
+        let mut gone = std::mem::take(&mut self.known);
+        for entry in entries {
+            if !serving(entry, self.passing_only) {
+                continue;
+            }
+            let ins = match from_entry(entry) {
+                Some(ins) => Arc::new(ins),
+                None => continue,
+            };
+            let key = self.identity.identify(&ins);
+            let unchanged = gone.remove(&key).filter(|old| *old == ins);
+            if unchanged.is_none() {
+                self.send(Event::Create(ins.clone()));
+            }
+            self.known.insert(key, ins);
+        }
+        for (_, ins) in gone {
+            self.send(Event::Delete(ins));
+        }
+    }
+
+    fn send(&self, event: Event) {
+        let _ = self
+            .tx
+            .unbounded_send(WatchEvent::with_clock(event, &*self.clock));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_entry, service_id, serving, to_service};
+    use crate::{intern::intern, Instance};
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[test]
+    fn test_service() {
+        let ins = Instance {
+            env: intern("prod"),
+            appid: intern("billing"),
+            addrs: vec!["grpc://10.0.0.1:9000".to_owned()].into(),
+            version: "1.2.0".to_owned(),
+            metadata: vec![
+                ("weight".to_owned(), "10".to_owned()),
+                ("dubbo.tag".to_owned(), "blue".to_owned()),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        let id = service_id(&ins);
+        let service = to_service(&ins, &id, Duration::from_secs(15));
+        assert_eq!(service["Address"], "10.0.0.1");
+        assert_eq!(service["Port"], 9000);
+        assert_eq!(service["Check"]["TTL"], "15s");
+
+        let mut entry = json!({
+            "Node": {"Node": "node-1", "Address": "10.0.0.1"},
+            "Service": service,
+            "Checks": [{"Status": "passing"}, {"Status": "warning"}],
+        });
+        entry["Service"]["Service"] = json!("billing");
+        entry["Service"]["ModifyIndex"] = json!(7);
+        let decoded = from_entry(&entry).unwrap();
+        assert_eq!(decoded.addrs, ins.addrs);
+        assert_eq!(decoded.env, ins.env);
+        assert_eq!(decoded.weight(), Some(10));
+        // not a valid metadata key in consul.
+        assert!(!decoded.metadata.contains_key("dubbo.tag"));
+        assert_eq!(decoded.revision, Some(7));
+        assert!(serving(&entry, false));
+        assert!(!serving(&entry, true));
+
+        // registered by other means.
+        let native = json!({
+            "Node": {"Node": "node-2", "Address": "10.0.0.2"},
+            "Service": {"Service": "billing", "Address": "", "Port": 8080},
+        });
+        let decoded = from_entry(&native).unwrap();
+        assert_eq!(&decoded.addrs[..], ["http://10.0.0.2:8080"]);
+    }
+}
@@ -0,0 +1,250 @@
+//! Routing parameters of an app, such as weights, rate limits or feature
+//! flags, kept in the backend next to its instances and merged into their
+//! metadata, instead of registering the instances again to change them.
+//!
+//! ```ignore
+//! let params = ControlParams::from_json(
+//!     r#"{"metadata": {"weight": "5"}, "instances": {"grpc://172.1.1.1:9999": {"weight": "0"}}}"#,
+//! )?;
+//! zk.set_control("/dubbo-rs/provider", &params).await?;
+//! let providers = watch_controlled(&zk, "/dubbo-rs/provider");
+//! ```
+use crate::{
+    watcher::{Event, WatchEvent},
+    Instance, Registry,
+};
+use futures::{Future, Stream};
+use pin_project::pin_project;
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ControlParams {
+    /// Merged into the metadata of every instance.
+    pub metadata: HashMap<String, String>,
+    /// Merged into the metadata of the instances an id names, over
+    /// `metadata`. See `Instance::has_id`.
+    pub instances: HashMap<String, HashMap<String, String>>,
+}
+
+impl ControlParams {
+    pub fn to_json(&self) -> String {
+        json!({
+            "metadata": self.metadata,
+            "instances": self.instances,
+        })
+        .to_string()
+    }
+
+    /// Parses what `to_json` returns, both fields being optional.
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        let mut value = serde_json::from_str::<Value>(json)?;
+        let mut params = ControlParams::default();
+        match value.get_mut("metadata").map(Value::take) {
+            None | Some(Value::Null) => {}
+            Some(metadata) => params.metadata = serde_json::from_value(metadata)?,
+        }
+        match value.get_mut("instances").map(Value::take) {
+            None | Some(Value::Null) => {}
+            Some(instances) => params.instances = serde_json::from_value(instances)?,
+        }
+        Ok(params)
+    }
+
+    /// `ins` with the parameters merged into its metadata, `ins` itself when
+    /// they change nothing.
+    pub fn apply(&self, ins: &Arc<Instance>) -> Arc<Instance> {
+        let params = self
+            .instances
+            .iter()
+            .filter(|(id, _)| ins.has_id(id))
+            .flat_map(|(_, params)| params);
+        let mut metadata = None;
+        for (k, v) in self.metadata.iter().chain(params) {
+            if ins.metadata.get(k) != Some(v) {
+                let metadata = metadata.get_or_insert_with(|| ins.metadata.clone());
+                metadata.insert(k.clone(), v.clone());
+            }
+        }
+        match metadata {
+            Some(metadata) => Arc::new(Instance {
+                metadata,
+                ..Instance::clone(ins)
+            }),
+            None => ins.clone(),
+        }
+    }
+}
+
+/// A backend keeping the `ControlParams` of apps.
+pub trait Control {
+    type Error;
+
+    type SetFuture: Future<Output = Result<(), Self::Error>>;
+
+    type ControlWatcher: Stream<Item = ControlParams>;
+
+    /// Replaces the parameters of `appid`.
+    fn set_control(&self, appid: &str, params: &ControlParams) -> Self::SetFuture;
+
+    /// The parameters of `appid`, then every change of them. Empty when none
+    /// were set.
+    fn watch_control(&self, appid: &str) -> Self::ControlWatcher;
+}
+
+/// Watches `appid` with its control parameters merged into the metadata of
+/// its instances. An instance the parameters change is reported again, as a
+/// Create.
+pub fn watch_controlled<R>(registry: &R, appid: &str) -> Controlled<R::Watcher, R::ControlWatcher>
+where
+    R: Registry + Control,
+{
+    Controlled::new(registry.watch(appid), registry.watch_control(appid))
+}
+
+/// See `watch_controlled`.
+#[pin_project]
+pub struct Controlled<W, C> {
+    #[pin]
+    watcher: W,
+    #[pin]
+    control: C,
+    control_done: bool,
+    params: ControlParams,
+    // the instances reported, by the instance watched.
+    reported: HashMap<Arc<Instance>, Arc<Instance>>,
+    pending: VecDeque<WatchEvent>,
+}
+
+impl<W, C> Controlled<W, C> {
+    pub fn new(watcher: W, control: C) -> Self {
+        Controlled {
+            watcher,
+            control,
+            control_done: false,
+            params: ControlParams::default(),
+            reported: HashMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<W, C> Stream for Controlled<W, C>
+where
+    W: Stream<Item = WatchEvent>,
+    C: Stream<Item = ControlParams>,
+{
+    type Item = WatchEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        // parameters first, so instances ready at the same time get them.
+        while !*this.control_done {
+            match this.control.as_mut().poll_next(cx) {
+                Poll::Ready(Some(params)) => {
+                    for (watched, reported) in this.reported.iter_mut() {
+                        let applied = params.apply(watched);
+                        if applied != *reported {
+                            *reported = applied.clone();
+                            let event = WatchEvent::new(Event::Create(applied));
+                            this.pending.push_back(event);
+                        }
+                    }
+                    *this.params = params;
+                }
+                // keeps the last parameters.
+                Poll::Ready(None) => *this.control_done = true,
+                Poll::Pending => break,
+            }
+        }
+        if let Some(watch_event) = this.pending.pop_front() {
+            return Poll::Ready(Some(watch_event));
+        }
+        let watch_event = match this.watcher.poll_next(cx) {
+            Poll::Ready(Some(watch_event)) => watch_event,
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => return Poll::Pending,
+        };
+        let event = match watch_event.event {
+            Event::Create(ins) => {
+                let applied = this.params.apply(&ins);
+                this.reported.insert(ins, applied.clone());
+                Event::Create(applied)
+            }
+            Event::Update(ins) => {
+                let applied = this.params.apply(&ins);
+                this.reported.insert(ins, applied.clone());
+                Event::Update(applied)
+            }
+            // the instance as reported, for consumers comparing them.
+            Event::Delete(ins) => Event::Delete(this.reported.remove(&ins).unwrap_or(ins)),
+        };
+        Poll::Ready(Some(WatchEvent {
+            event,
+            ..watch_event
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{watch_controlled, Control, ControlParams};
+    use crate::{
+        testing::{instance, MockRegistry},
+        watcher::Event,
+    };
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn test_controlled() {
+        let registry = MockRegistry::new();
+        registry.insert(instance("grpc://172.1.1.1:9999"));
+        registry.insert(instance("grpc://172.1.1.2:9999"));
+        let params = ControlParams::from_json(
+            r#"{"metadata": {"weight": "5"}, "instances": {"grpc://172.1.1.2:9999": {"weight": "0"}}}"#,
+        )
+        .unwrap();
+        registry.set_control("provider", &params).await.unwrap();
+
+        let mut watcher = watch_controlled(&registry, "provider").map(|e| e.event);
+        let weights = |events: Vec<Event>| {
+            let mut weights = events
+                .into_iter()
+                .map(|event| match event {
+                    Event::Create(ins) | Event::Update(ins) => (ins.addrs[0].clone(), ins.weight()),
+                    Event::Delete(ins) => panic!("{:?} deleted", ins),
+                })
+                .collect::<Vec<_>>();
+            weights.sort();
+            weights
+        };
+        let created = (&mut watcher).take(2).collect::<Vec<_>>().await;
+        assert_eq!(
+            weights(created),
+            vec![
+                ("grpc://172.1.1.1:9999".to_owned(), Some(5)),
+                ("grpc://172.1.1.2:9999".to_owned(), Some(0)),
+            ]
+        );
+
+        let params = ControlParams::from_json(r#"{"metadata": {"weight": "5"}}"#).unwrap();
+        registry.set_control("provider", &params).await.unwrap();
+        let updated = watcher.next().await.unwrap();
+        assert_eq!(
+            weights(vec![updated]),
+            vec![("grpc://172.1.1.2:9999".to_owned(), Some(5))]
+        );
+
+        registry.remove(&instance("grpc://172.1.1.1:9999"));
+        match watcher.next().await.unwrap() {
+            Event::Delete(ins) => assert_eq!(ins.weight(), Some(5)),
+            event => panic!("unexpected {:?}", event),
+        }
+        assert_eq!(ControlParams::from_json(&params.to_json()).unwrap(), params);
+    }
+}
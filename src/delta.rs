@@ -0,0 +1,137 @@
+use crate::Instance;
+
+/// What changed between two versions of an instance, see `Instance::diff`.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct InstanceDelta {
+    pub zone_changed: bool,
+    pub env_changed: bool,
+    pub hostname_changed: bool,
+    pub version_changed: bool,
+    pub group_changed: bool,
+    pub addrs_added: Vec<String>,
+    pub addrs_removed: Vec<String>,
+    /// Metadata keys that were added, removed or got a new value, sorted.
+    pub metadata_changed: Vec<String>,
+    pub weight: Option<WeightChange>,
+}
+
+/// The `weight` metadata before and after, `None` when absent or not a number.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct WeightChange {
+    pub from: Option<u32>,
+    pub to: Option<u32>,
+}
+
+impl InstanceDelta {
+    pub(crate) fn between(old: &Instance, new: &Instance) -> Self {
+        let mut metadata_changed = old
+            .metadata
+            .iter()
+            .filter(|(k, v)| new.metadata.get(*k) != Some(*v))
+            .map(|(k, _)| k.clone())
+            .chain(
+                new.metadata
+                    .keys()
+                    .filter(|k| !old.metadata.contains_key(*k))
+                    .cloned(),
+            )
+            .collect::<Vec<String>>();
+        metadata_changed.sort();
+
+        let (from, to) = (old.weight(), new.weight());
+        InstanceDelta {
+            zone_changed: old.zone != new.zone,
+            env_changed: old.env != new.env,
+            hostname_changed: old.hostname != new.hostname,
+            version_changed: old.version != new.version,
+            group_changed: old.group != new.group,
+            addrs_added: difference(&new.addrs, &old.addrs),
+            addrs_removed: difference(&old.addrs, &new.addrs),
+            metadata_changed,
+            weight: if from != to {
+                Some(WeightChange { from, to })
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == InstanceDelta::default()
+    }
+}
+
+fn difference(a: &[String], b: &[String]) -> Vec<String> {
+    a.iter().filter(|x| !b.contains(x)).cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InstanceDelta, WeightChange};
+    use crate::Instance;
+
+    fn instance(addrs: &[&str], metadata: &[(&str, &str)]) -> Instance {
+        Instance {
+            appid: "provider".into(),
+            version: "111".to_owned(),
+            addrs: addrs.iter().map(|a| a.to_string()).collect(),
+            metadata: metadata
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_diff_identical() {
+        let ins = instance(&["grpc://172.1.1.1:9999"], &[("weight", "10")]);
+        assert!(ins.diff(&ins.clone()).is_empty());
+    }
+
+    #[test]
+    fn test_diff_addrs_and_metadata() {
+        let old = instance(
+            &["http://172.1.1.1:8000", "grpc://172.1.1.1:9999"],
+            &[("weight", "10"), ("color", "blue"), ("dc", "sh")],
+        );
+        let new = instance(
+            &["grpc://172.1.1.1:9999", "grpc://172.1.1.1:9998"],
+            &[("weight", "20"), ("color", "green"), ("lane", "x")],
+        );
+        assert_eq!(
+            old.diff(&new),
+            InstanceDelta {
+                addrs_added: vec!["grpc://172.1.1.1:9998".to_owned()],
+                addrs_removed: vec!["http://172.1.1.1:8000".to_owned()],
+                metadata_changed: vec![
+                    "color".to_owned(),
+                    "dc".to_owned(),
+                    "lane".to_owned(),
+                    "weight".to_owned()
+                ],
+                weight: Some(WeightChange {
+                    from: Some(10),
+                    to: Some(20)
+                }),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_diff_weight_removed() {
+        let old = instance(&[], &[("weight", "10")]);
+        let mut new = instance(&[], &[]);
+        new.version = "112".to_owned();
+        let delta = old.diff(&new);
+        assert!(delta.version_changed);
+        assert_eq!(
+            delta.weight,
+            Some(WeightChange {
+                from: Some(10),
+                to: None
+            })
+        );
+    }
+}
@@ -0,0 +1,299 @@
+//! Process-wide discovery handles, for code deep in the call stack that can't
+//! be handed a registry.
+//!
+//! ```ignore
+//! // at startup
+//! discovery::configure(discovery::DEFAULT_NAMESPACE, config);
+//! discovery::set("billing", Discovery::new(boxed(billing_zk)));
+//!
+//! // anywhere
+//! let providers = discovery::global().await?.instances("/dubbo-rs/provider");
+//! ```
+#[cfg(all(
+    feature = "config",
+    any(
+        feature = "zk",
+        feature = "etcd",
+        feature = "redis",
+        feature = "consul"
+    )
+))]
+use crate::config::{build_registry, DiscoverConfig};
+use crate::{
+    balance::{self, InstanceSet},
+    boxed::{BoxError, BoxRegistry},
+    runtime::{self, AbortOnDrop},
+    Registry,
+};
+use futures::future::{BoxFuture, Future, FutureExt, Shared};
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    error, fmt,
+    sync::{Arc, Mutex},
+};
+
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+lazy_static! {
+    static ref NAMESPACES: Mutex<HashMap<String, Slot>> = Mutex::new(HashMap::new());
+}
+
+type Building = Shared<BoxFuture<'static, Result<Discovery, Arc<BoxError>>>>;
+
+enum Slot {
+    Ready(Discovery),
+    Lazy {
+        build: Arc<dyn Fn() -> Building + Send + Sync>,
+        building: Building,
+    },
+}
+
+/// A registry, with the instances of the apps asked for watched once and
+/// shared.
+#[derive(Clone)]
+pub struct Discovery {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    registry: BoxRegistry,
+    apps: Mutex<HashMap<String, App>>,
+}
+
+struct App {
+    instances: InstanceSet,
+    _task: AbortOnDrop,
+}
+
+impl Discovery {
+    pub fn new(registry: BoxRegistry) -> Self {
+        Discovery {
+            inner: Arc::new(Inner {
+                registry,
+                apps: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    pub fn registry(&self) -> &BoxRegistry {
+        &self.inner.registry
+    }
+
+    /// The instances of `appid`, watched from the first call on.
+    pub fn instances(&self, appid: &str) -> InstanceSet {
+        let mut apps = self.inner.apps.lock().unwrap();
+        let app = apps.entry(appid.to_owned()).or_insert_with(|| {
+            let instances = InstanceSet::new();
+            let task = balance::drive(self.inner.registry.watch(appid), instances.clone());
+            App {
+                instances,
+                _task: runtime::spawn_abortable(task),
+            }
+        });
+        app.instances.clone()
+    }
+}
+
+#[derive(Debug)]
+pub enum DiscoveryError {
+    /// Nothing was set or configured for the namespace.
+    Unknown(String),
+    Build(Arc<BoxError>),
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoveryError::Unknown(namespace) => write!(f, "unknown namespace {}", namespace),
+            DiscoveryError::Build(e) => write!(f, "failed to build the discovery: {}", e),
+        }
+    }
+}
+
+impl error::Error for DiscoveryError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DiscoveryError::Unknown(_) => None,
+            DiscoveryError::Build(e) => Some(&***e),
+        }
+    }
+}
+
+/// Sets the discovery of `namespace`, replacing the previous one.
+pub fn set(namespace: &str, discovery: Discovery) {
+    let mut namespaces = NAMESPACES.lock().unwrap();
+    namespaces.insert(namespace.to_owned(), Slot::Ready(discovery));
+}
+
+/// Sets the discovery of `namespace` to be built by `build` when first asked
+/// for. A failed build is tried again on the next call.
+pub fn set_lazy<F, B>(namespace: &str, build: F)
+where
+    F: Fn() -> B + Send + Sync + 'static,
+    B: Future<Output = Result<Discovery, BoxError>> + Send + 'static,
+{
+    let build = Arc::new(move || {
+        build()
+            .map(|built| built.map_err(Arc::new))
+            .boxed()
+            .shared()
+    });
+    let building = build();
+    let mut namespaces = NAMESPACES.lock().unwrap();
+    namespaces.insert(namespace.to_owned(), Slot::Lazy { build, building });
+}
+
+/// Sets the discovery of `namespace` to be built from `config`, see
+/// `set_lazy`. Needs the feature of a backend, `config` can't select any
+/// without one.
+#[cfg(all(
+    feature = "config",
+    any(
+        feature = "zk",
+        feature = "etcd",
+        feature = "redis",
+        feature = "consul"
+    )
+))]
+pub fn configure(namespace: &str, config: DiscoverConfig) {
+    set_lazy(namespace, move || {
+        let config = config.clone();
+        async move { Ok(Discovery::new(build_registry(&config).await?)) }
+    });
+}
+
+/// The discovery of `namespace`.
+pub async fn get(namespace: &str) -> Result<Discovery, DiscoveryError> {
+    let building = match NAMESPACES.lock().unwrap().get(namespace) {
+        Some(Slot::Ready(discovery)) => return Ok(discovery.clone()),
+        Some(Slot::Lazy { building, .. }) => building.clone(),
+        None => return Err(DiscoveryError::Unknown(namespace.to_owned())),
+    };
+    let built = building.clone().await;
+    let mut namespaces = NAMESPACES.lock().unwrap();
+    // by the first caller done with this build, unless set again meanwhile,
+    // so that callers sharing a failed build only start one more.
+    let build = match namespaces.get(namespace) {
+        Some(Slot::Lazy {
+            build,
+            building: current,
+        }) if current.ptr_eq(&building) => build.clone(),
+        _ => return built.map_err(DiscoveryError::Build),
+    };
+    let slot = match &built {
+        Ok(discovery) => Slot::Ready(discovery.clone()),
+        Err(_) => Slot::Lazy {
+            building: build(),
+            build,
+        },
+    };
+    namespaces.insert(namespace.to_owned(), slot);
+    built.map_err(DiscoveryError::Build)
+}
+
+/// The discovery of `DEFAULT_NAMESPACE`.
+pub async fn global() -> Result<Discovery, DiscoveryError> {
+    get(DEFAULT_NAMESPACE).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get, set, set_lazy, Discovery, DiscoveryError};
+    use crate::{
+        boxed::boxed,
+        testing::{Call, MockRegistry},
+        Instance,
+    };
+    use futures::future;
+    use std::{
+        error::Error,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    #[tokio::test]
+    async fn test_discovery() {
+        tokio::time::pause();
+        let registry = MockRegistry::new();
+        registry.insert(Instance {
+            appid: "provider".into(),
+            addrs: vec!["grpc://172.1.1.1:9999".to_owned()].into(),
+            ..Default::default()
+        });
+        set("test_discovery", Discovery::new(boxed(registry.clone())));
+
+        let discovery = get("test_discovery").await.unwrap();
+        let instances = discovery.instances("provider");
+        let shared = get("test_discovery").await.unwrap().instances("provider");
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        assert_eq!(instances.len(), 1);
+        assert_eq!(shared.len(), 1);
+        assert_eq!(registry.calls(), vec![Call::Watch("provider".to_owned())]);
+
+        match get("test_discovery_unknown").await {
+            Err(DiscoveryError::Unknown(namespace)) => {
+                assert_eq!(namespace, "test_discovery_unknown")
+            }
+            _ => panic!("unknown namespace found"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_lazy() {
+        let builds = Arc::new(AtomicUsize::new(0));
+        let registry = MockRegistry::new();
+        set_lazy("test_set_lazy", {
+            let builds = builds.clone();
+            move || {
+                let registry = registry.clone();
+                let first = builds.fetch_add(1, Ordering::SeqCst) == 0;
+                async move {
+                    if first {
+                        return Err("zk is down".into());
+                    }
+                    Ok(Discovery::new(boxed(registry)))
+                }
+            }
+        });
+        assert_eq!(builds.load(Ordering::SeqCst), 1);
+
+        let failed = get("test_set_lazy").await.err().unwrap();
+        assert_eq!(
+            failed.to_string(),
+            "failed to build the discovery: zk is down"
+        );
+        let discovery = get("test_set_lazy").await.unwrap();
+        assert!(get("test_set_lazy").await.is_ok());
+        assert_eq!(builds.load(Ordering::SeqCst), 2);
+        assert!(discovery.instances("provider").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_set_lazy_shared() {
+        tokio::time::pause();
+        let builds = Arc::new(AtomicUsize::new(0));
+        set_lazy("test_set_lazy_shared", {
+            let builds = builds.clone();
+            move || {
+                builds.fetch_add(1, Ordering::SeqCst);
+                // both callers wait for the same build.
+                async {
+                    tokio::time::delay_for(Duration::from_millis(1)).await;
+                    Err("zk is down".into())
+                }
+            }
+        });
+
+        let (first, second) =
+            future::join(get("test_set_lazy_shared"), get("test_set_lazy_shared")).await;
+        let failed = first.err().unwrap();
+        assert_eq!(failed.source().unwrap().to_string(), "zk is down");
+        assert!(second.is_err());
+        // built once and started again once, not once per caller.
+        assert_eq!(builds.load(Ordering::SeqCst), 2);
+    }
+}
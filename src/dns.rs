@@ -0,0 +1,29 @@
+//! Instances over DNS, either answering queries for them with `DnsServer`,
+//! or finding them in DNS records with `Dns`.
+#[cfg(feature = "dns")]
+mod registry;
+#[cfg(feature = "dns-server")]
+mod server;
+
+#[cfg(feature = "dns")]
+pub use registry::{Dns, DnsBuilder, DnsError, DnsWatcher};
+#[cfg(feature = "dns-server")]
+pub use server::DnsServer;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+const NOERROR: u8 = 0;
+const NXDOMAIN: u8 = 3;
+
+fn encode_name(name: &str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(name.len() + 2);
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf
+}
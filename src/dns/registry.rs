@@ -0,0 +1,613 @@
+use super::{encode_name, CLASS_IN, NOERROR, NXDOMAIN, TYPE_A, TYPE_AAAA, TYPE_SRV};
+use crate::{
+    identity::{DefaultIdentity, Identity},
+    intern::intern,
+    runtime::{self, AbortOnDrop},
+    watcher::{Clock, Event, SystemClock, WatchEvent},
+    Instance, Registry,
+};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future::{self, BoxFuture},
+    FutureExt, Stream,
+};
+use log::error;
+use pin_project::pin_project;
+use std::{
+    collections::HashMap,
+    error, fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+
+const TYPE_OPT: u16 = 41;
+
+// the size of the responses taken, advertised with EDNS.
+const MAX_UDP_SIZE: usize = 4096;
+
+/// Finds the instances of apps in DNS, polling their records, for the places
+/// without a registry.
+///
+/// The domain of an app is either:
+/// - an SRV name, e.g. `_grpc._tcp.billing.example.com`, each record of it
+///   being an instance at the IPs of its target and its port, with the
+///   service label as scheme, `grpc://10.0.0.1:9000`, or `tcp` without one.
+/// - a host and port, e.g. `billing.example.com:9000`, each of its A and AAAA
+///   records being an instance, `tcp://10.0.0.1:9000`.
+///
+/// Apps without a domain are looked up under their appid.
+///
+/// ```ignore
+/// let dns = Dns::builder("10.0.0.2:53".parse()?)
+///     .app("billing", "_grpc._tcp.billing.example.com")
+///     .interval(Duration::from_secs(10))
+///     .build();
+/// let discover = AppDiscover::new(dns.watch("billing"), make_service);
+/// ```
+///
+/// Instances can't be registered through DNS, registering fails with
+/// `DnsError::Unsupported`.
+pub struct Dns<I = DefaultIdentity> {
+    resolver: Arc<Resolver>,
+    domains: HashMap<String, String>,
+    interval: Duration,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Dns {
+    /// Asks the name server at `nameserver`, see `DnsBuilder` for the
+    /// options.
+    pub fn builder(nameserver: SocketAddr) -> DnsBuilder {
+        DnsBuilder::new(nameserver)
+    }
+}
+
+impl<I> Dns<I> {
+    /// Sets how watchers tell instances apart.
+    pub fn with_identity<NI>(self, identity: NI) -> Dns<NI> {
+        Dns {
+            resolver: self.resolver,
+            domains: self.domains,
+            interval: self.interval,
+            identity: Arc::new(identity),
+            clock: self.clock,
+        }
+    }
+
+    /// Sets the clock timestamping the events of watchers.
+    pub fn with_clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+}
+
+/// Builds a `Dns`.
+pub struct DnsBuilder {
+    nameserver: SocketAddr,
+    domains: HashMap<String, String>,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl DnsBuilder {
+    pub fn new(nameserver: SocketAddr) -> Self {
+        DnsBuilder {
+            nameserver,
+            domains: HashMap::new(),
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+        }
+    }
+
+    /// Looks `appid` up under `domain`, see `Dns`.
+    pub fn app(mut self, appid: &str, domain: &str) -> Self {
+        self.domains.insert(appid.to_owned(), domain.to_owned());
+        self
+    }
+
+    /// How often watchers look their app up, 5s by default.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How long queries wait for their answer, 2s by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn build(self) -> Dns {
+        Dns {
+            resolver: Arc::new(Resolver {
+                nameserver: self.nameserver,
+                timeout: self.timeout,
+            }),
+            domains: self.domains,
+            interval: self.interval,
+            identity: Arc::new(DefaultIdentity),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DnsError {
+    Io(io::Error),
+    /// The name server didn't answer in time.
+    Timeout,
+    /// The name server answered with an error code.
+    Rcode(u8),
+    /// The answer didn't fit in a datagram.
+    Truncated,
+    Malformed,
+    /// Instances can't be registered through DNS.
+    Unsupported,
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsError::Io(e) => write!(f, "failed to query the name server: {}", e),
+            DnsError::Timeout => write!(f, "the name server didn't answer in time"),
+            DnsError::Rcode(rcode) => write!(f, "the name server answered rcode {}", rcode),
+            DnsError::Truncated => write!(f, "the answer was truncated"),
+            DnsError::Malformed => write!(f, "malformed answer"),
+            DnsError::Unsupported => write!(f, "unsupported by dns"),
+        }
+    }
+}
+
+impl error::Error for DnsError {}
+
+impl From<io::Error> for DnsError {
+    fn from(e: io::Error) -> Self {
+        DnsError::Io(e)
+    }
+}
+
+enum Rdata {
+    Ip(IpAddr),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+}
+
+// the answers and additional records of a response, of the types above.
+type Records = Vec<(String, Rdata)>;
+
+fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    // recursion desired, one question and the OPT record.
+    let mut query = vec![0, 0, 0x01, 0, 0, 1, 0, 0, 0, 0, 0, 1];
+    query[..2].copy_from_slice(&id.to_be_bytes());
+    query.extend(encode_name(name));
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    // the root name, its class the size taken.
+    query.extend_from_slice(&[0]);
+    query.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    query.extend_from_slice(&(MAX_UDP_SIZE as u16).to_be_bytes());
+    query.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+    query
+}
+
+// the name at `pos` of `msg`, and where it ends.
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // a bound on pointers, against loops.
+    for _ in 0..128 {
+        let len = *msg.get(pos)? as usize;
+        match len {
+            0 => {
+                let name = labels.join(".");
+                return Some((name, end.unwrap_or(pos + 1)));
+            }
+            len if len & 0xc0 == 0xc0 => {
+                let offset = (len & 0x3f) << 8 | *msg.get(pos + 1)? as usize;
+                end.get_or_insert(pos + 2);
+                pos = offset;
+            }
+            len => {
+                let label = msg.get(pos + 1..pos + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                pos += 1 + len;
+            }
+        }
+    }
+    None
+}
+
+fn parse(id: u16, rsp: &[u8]) -> Result<Records, DnsError> {
+    if rsp.len() < 12 || rsp[..2] != id.to_be_bytes() {
+        return Err(DnsError::Malformed);
+    }
+    if rsp[2] & 0x02 != 0 {
+        return Err(DnsError::Truncated);
+    }
+    match rsp[3] & 0x0f {
+        NOERROR => {}
+        // no such name, so no instances.
+        NXDOMAIN => return Ok(Vec::new()),
+        rcode => return Err(DnsError::Rcode(rcode)),
+    }
+    let count = |at: usize| u16::from_be_bytes([rsp[at], rsp[at + 1]]) as usize;
+    let mut pos = 12;
+    for _ in 0..count(4) {
+        pos = read_name(rsp, pos).ok_or(DnsError::Malformed)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..count(6) + count(8) + count(10) {
+        let (name, end) = read_name(rsp, pos).ok_or(DnsError::Malformed)?;
+        let fixed = rsp.get(end..end + 10).ok_or(DnsError::Malformed)?;
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let len = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        let start = end + 10;
+        let rdata = rsp.get(start..start + len).ok_or(DnsError::Malformed)?;
+        let rdata = match (rtype, len) {
+            (TYPE_A, 4) => Rdata::Ip(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]).into()),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                Rdata::Ip(Ipv6Addr::from(octets).into())
+            }
+            (TYPE_SRV, len) if len > 6 => Rdata::Srv {
+                priority: u16::from_be_bytes([rdata[0], rdata[1]]),
+                weight: u16::from_be_bytes([rdata[2], rdata[3]]),
+                port: u16::from_be_bytes([rdata[4], rdata[5]]),
+                target: read_name(rsp, start + 6).ok_or(DnsError::Malformed)?.0,
+            },
+            _ => {
+                pos = start + len;
+                continue;
+            }
+        };
+        records.push((name, rdata));
+        pos = start + len;
+    }
+    Ok(records)
+}
+
+// the IPs of `records`, or of `name` among them.
+fn ips<'a>(records: &'a Records, name: Option<&'a str>) -> impl Iterator<Item = IpAddr> + 'a {
+    records
+        .iter()
+        .filter_map(move |(owner, rdata)| match rdata {
+            Rdata::Ip(ip) if name.is_none_or(|name| name == owner) => Some(*ip),
+            _ => None,
+        })
+}
+
+fn addr(scheme: &str, ip: IpAddr, port: u16) -> String {
+    format!("{}://{}", scheme, SocketAddr::new(ip, port))
+}
+
+struct Resolver {
+    nameserver: SocketAddr,
+    timeout: Duration,
+}
+
+impl Resolver {
+    async fn lookup(&self, name: &str, qtype: u16) -> Result<Records, DnsError> {
+        let local: SocketAddr = match self.nameserver {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let mut socket = UdpSocket::bind(local).await?;
+        let id = rand::random();
+        socket
+            .send_to(&query(id, name, qtype), &self.nameserver)
+            .await?;
+        let nameserver = self.nameserver;
+        let answer = async move {
+            let mut buf = vec![0u8; MAX_UDP_SIZE];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await?;
+                // strays, e.g. answers to queries given up on.
+                if from == nameserver && buf[..len.min(2)] == id.to_be_bytes() {
+                    return parse(id, &buf[..len]);
+                }
+            }
+        };
+        runtime::timeout(self.timeout, answer)
+            .await
+            .unwrap_or(Err(DnsError::Timeout))
+    }
+
+    // the A and AAAA records of `host`.
+    async fn lookup_ips(&self, host: &str) -> Result<Vec<IpAddr>, DnsError> {
+        let (v4, v6) = future::join(self.lookup(host, TYPE_A), self.lookup(host, TYPE_AAAA)).await;
+        let (v4, v6) = (v4?, v6?);
+        Ok(ips(&v4, None).chain(ips(&v6, None)).collect())
+    }
+
+    // the instances of `appid` under `domain`, see `Dns`.
+    async fn instances(&self, appid: &str, domain: &str) -> Result<Vec<Instance>, DnsError> {
+        let instance = |hostname: &str, addrs| Instance {
+            appid: intern(appid),
+            hostname: hostname.to_owned(),
+            addrs,
+            ..Default::default()
+        };
+        if let Some((host, port)) = domain.rsplit_once(':') {
+            if let Ok(port) = port.parse() {
+                let ips = self.lookup_ips(host).await?;
+                let instances = ips
+                    .into_iter()
+                    .map(|ip| instance(host, std::iter::once(addr("tcp", ip, port)).collect()))
+                    .collect();
+                return Ok(instances);
+            }
+        }
+
+        let scheme = match domain.split('.').next() {
+            Some(label) if label.starts_with('_') => &label[1..],
+            _ => "tcp",
+        };
+        let records = self.lookup(domain, TYPE_SRV).await?;
+        let mut instances = Vec::new();
+        for (_, rdata) in &records {
+            let (priority, weight, port, target) = match rdata {
+                Rdata::Srv {
+                    priority,
+                    weight,
+                    port,
+                    target,
+                } => (priority, weight, *port, target),
+                Rdata::Ip(_) => continue,
+            };
+            // the IPs of the target, unless in the additional records.
+            let mut target_ips = ips(&records, Some(target)).collect::<Vec<_>>();
+            if target_ips.is_empty() {
+                target_ips = self.lookup_ips(target).await?;
+            }
+            let addrs = target_ips
+                .into_iter()
+                .map(|ip| addr(scheme, ip, port))
+                .collect();
+            let mut ins = instance(target, addrs);
+            ins.metadata
+                .insert("priority".to_owned(), priority.to_string());
+            ins.metadata.insert("weight".to_owned(), weight.to_string());
+            instances.push(ins);
+        }
+        Ok(instances)
+    }
+}
+
+impl<I> Registry for Dns<I>
+where
+    I: Identity + Send + Sync + 'static,
+    I::Key: Send + Sync,
+{
+    type Error = DnsError;
+
+    type RegFuture = BoxFuture<'static, Result<(), DnsError>>;
+
+    type DeRegFuture = BoxFuture<'static, Result<(), DnsError>>;
+
+    type ListFuture = BoxFuture<'static, Result<Vec<Arc<Instance>>, DnsError>>;
+
+    type Watcher = DnsWatcher;
+
+    fn register(&self, _ins: Arc<Instance>) -> Self::RegFuture {
+        future::ready(Err(DnsError::Unsupported)).boxed()
+    }
+
+    fn deregister(&self, _ins: &Arc<Instance>) -> Self::DeRegFuture {
+        future::ready(Err(DnsError::Unsupported)).boxed()
+    }
+
+    fn evict(&self, _appid: &str, _instance_id: &str) -> Self::DeRegFuture {
+        future::ready(Err(DnsError::Unsupported)).boxed()
+    }
+
+    fn update_if(&self, _ins: Arc<Instance>, _expected_version: u64) -> Self::RegFuture {
+        future::ready(Err(DnsError::Unsupported)).boxed()
+    }
+
+    fn renew(&self, _ins: &Arc<Instance>) -> Self::RegFuture {
+        future::ready(Err(DnsError::Unsupported)).boxed()
+    }
+
+    fn list(&self, appid: &str) -> Self::ListFuture {
+        let resolver = self.resolver.clone();
+        let appid = appid.to_owned();
+        let domain = self.domains.get(&appid).unwrap_or(&appid).clone();
+        async move {
+            let instances = resolver.instances(&appid, &domain).await?;
+            Ok(instances.into_iter().map(Arc::new).collect())
+        }
+        .boxed()
+    }
+
+    fn watch(&self, appid: &str) -> Self::Watcher {
+        self.watch_from(appid, &[])
+    }
+
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher {
+        let (tx, rx) = mpsc::unbounded();
+        let mut known = HashMap::new();
+        for ins in instances {
+            known.insert(self.identity.identify(ins), ins.clone());
+            let event = WatchEvent::with_clock(Event::Create(ins.clone()), &*self.clock);
+            let _ = tx.unbounded_send(event);
+        }
+        let domain = self.domains.get(appid).map_or(appid, String::as_str);
+        let watch = DomainWatch {
+            resolver: self.resolver.clone(),
+            appid: appid.to_owned(),
+            domain: domain.to_owned(),
+            interval: self.interval,
+            identity: self.identity.clone(),
+            clock: self.clock.clone(),
+            known,
+            tx,
+        };
+        DnsWatcher {
+            rx,
+            _task: runtime::spawn_abortable(watch.run()),
+        }
+    }
+}
+
+/// The instances of an app in DNS, see `Registry::watch`.
+#[pin_project]
+pub struct DnsWatcher {
+    #[pin]
+    rx: UnboundedReceiver<WatchEvent>,
+    _task: AbortOnDrop,
+}
+
+impl Stream for DnsWatcher {
+    type Item = WatchEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().rx.poll_next(cx)
+    }
+}
+
+struct DomainWatch<I: Identity> {
+    resolver: Arc<Resolver>,
+    appid: String,
+    domain: String,
+    interval: Duration,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+    // the instances reported.
+    known: HashMap<I::Key, Arc<Instance>>,
+    tx: UnboundedSender<WatchEvent>,
+}
+
+impl<I> DomainWatch<I>
+where
+    I: Identity,
+{
+    async fn run(mut self) {
+        loop {
+            // the instances stay as they were while lookups fail.
+            match self.resolver.instances(&self.appid, &self.domain).await {
+                Ok(instances) => self.update(instances),
+                Err(e) => error!("failed to look {} up. {}", self.domain, e),
+            }
+            runtime::delay_for(self.interval).await;
+        }
+    }
+
+    // reports the instances new or changed as Creates, and the ones gone as
+    // Deletes.
+    fn update(&mut self, instances: Vec<Instance>) {
+        let mut gone = std::mem::take(&mut self.known);
+        for ins in instances {
+            let ins = Arc::new(ins);
+            let key = self.identity.identify(&ins);
+            let unchanged = gone.remove(&key).filter(|old| *old == ins);
+            if unchanged.is_none() {
+                self.send(Event::Create(ins.clone()));
+            }
+            self.known.insert(key, ins);
+        }
+        for (_, ins) in gone {
+            self.send(Event::Delete(ins));
+        }
+    }
+
+    fn send(&self, event: Event) {
+        let _ = self
+            .tx
+            .unbounded_send(WatchEvent::with_clock(event, &*self.clock));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, query, Rdata, Resolver};
+    use std::{net::UdpSocket, thread, time::Duration};
+
+    // an answer to `query` with the records of `rdata`, under the name of the
+    // question.
+    fn answer(query: &[u8], records: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        // the question, without the OPT record.
+        let end = query.len() - 11;
+        let mut rsp = query[..end].to_vec();
+        rsp[2] |= 0x80;
+        rsp[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+        rsp[10..12].copy_from_slice(&[0, 0]);
+        for (rtype, rdata) in records {
+            rsp.extend_from_slice(&[0xc0, 12]);
+            rsp.extend_from_slice(&rtype.to_be_bytes());
+            rsp.extend_from_slice(&[0, 1, 0, 0, 0, 5]);
+            rsp.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            rsp.extend_from_slice(rdata);
+        }
+        rsp
+    }
+
+    #[tokio::test]
+    async fn test_instances() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let nameserver = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while let Ok((len, from)) = server.recv_from(&mut buf) {
+                let query = &buf[..len];
+                let qtype = u16::from_be_bytes([query[len - 15], query[len - 14]]);
+                let records = match qtype {
+                    // a target pointing back at the question.
+                    33 => vec![(33, vec![0, 1, 0, 2, 0x23, 0x28, 0xc0, 12])],
+                    1 => vec![(1, vec![10, 0, 0, 1]), (1, vec![10, 0, 0, 2])],
+                    _ => vec![],
+                };
+                server.send_to(&answer(query, &records), from).unwrap();
+            }
+        });
+        let resolver = Resolver {
+            nameserver,
+            timeout: Duration::from_secs(1),
+        };
+
+        let mut instances = resolver
+            .instances("billing", "billing.example.com:9000")
+            .await
+            .unwrap();
+        instances.sort_by(|a, b| a.addrs.cmp(&b.addrs));
+        assert_eq!(instances.len(), 2);
+        assert_eq!(&instances[0].addrs[..], ["tcp://10.0.0.1:9000"]);
+        assert_eq!(&*instances[1].appid, "billing");
+
+        let instances = resolver
+            .instances("billing", "_grpc._tcp.billing.example.com")
+            .await
+            .unwrap();
+        assert_eq!(instances.len(), 1);
+        assert_eq!(instances[0].hostname, "_grpc._tcp.billing.example.com");
+        assert_eq!(
+            &instances[0].addrs[..],
+            ["grpc://10.0.0.1:9000", "grpc://10.0.0.2:9000"]
+        );
+        assert_eq!(instances[0].metadata["weight"], "2");
+
+        let nxdomain = {
+            let mut rsp = answer(&query(7, "nope.example.com", 1), &[]);
+            rsp[3] = 3;
+            rsp
+        };
+        assert!(parse(7, &nxdomain).unwrap().is_empty());
+        let srv = answer(&query(7, "x", 33), &[(33, vec![0, 0, 0, 0, 0, 80, 0])]);
+        assert!(matches!(
+            &parse(7, &srv).unwrap()[0].1,
+            Rdata::Srv { port: 80, .. }
+        ));
+    }
+}
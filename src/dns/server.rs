@@ -0,0 +1,442 @@
+use super::{encode_name, CLASS_IN, NOERROR, NXDOMAIN, TYPE_A, TYPE_AAAA, TYPE_SRV};
+use crate::{resolver::Resolver, runtime, Registry};
+use log::warn;
+use std::{
+    collections::{BTreeSet, HashMap},
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{net::UdpSocket, sync::Mutex};
+
+const TYPE_ANY: u16 = 255;
+
+const FORMERR: u8 = 1;
+const NOTIMP: u8 = 4;
+const REFUSED: u8 = 5;
+
+// without EDNS, larger answers are truncated.
+const MAX_UDP_SIZE: usize = 512;
+
+/// A DNS server answering from a `Resolver`.
+///
+/// For an app named `provider` under the default `discover.local` domain:
+/// - `provider.discover.local` has the A and AAAA records of its instances'
+///   IPs and SRV records of their ports, the SRV targets being
+/// - `172-1-1-1.provider.discover.local`, an A record, or the 8 hex groups
+///   joined by `-` for IPv6.
+/// - `_grpc._tcp.provider.discover.local` has the same SRV records.
+///
+/// Pick the addresses served with `Resolver::scheme`.
+///
+/// ```ignore
+/// let resolver = Arc::new(Resolver::new(zk).scheme("grpc"));
+/// DnsServer::new(resolver)
+///     .app("provider", "/dubbo-rs/provider")
+///     .serve("0.0.0.0:5353".parse()?)
+///     .await?;
+/// ```
+pub struct DnsServer<R> {
+    resolver: Arc<Resolver<R>>,
+    domain: String,
+    ttl: u32,
+    apps: HashMap<String, String>,
+}
+
+impl<R> DnsServer<R>
+where
+    R: Registry + Send + Sync + 'static,
+    R::Watcher: Send + 'static,
+{
+    pub fn new(resolver: Arc<Resolver<R>>) -> Self {
+        DnsServer {
+            resolver,
+            domain: "discover.local".to_owned(),
+            ttl: 5,
+            apps: HashMap::new(),
+        }
+    }
+
+    /// The domain the apps are named under, queries for other names are
+    /// refused.
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = domain.into().trim_matches('.').to_ascii_lowercase();
+        self
+    }
+
+    /// How long answers may be cached, in whole seconds.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl.as_secs() as u32;
+        self
+    }
+
+    /// Serves the instances of `appid` as `name`, a DNS label.
+    pub fn app(mut self, name: impl Into<String>, appid: impl Into<String>) -> Self {
+        self.apps
+            .insert(name.into().to_ascii_lowercase(), appid.into());
+        self
+    }
+
+    /// Answers the queries sent to `addr` over UDP.
+    pub async fn serve(self, addr: SocketAddr) -> io::Result<()> {
+        let (mut recv, send) = UdpSocket::bind(addr).await?.split();
+        let send = Arc::new(Mutex::new(send));
+        let server = Arc::new(self);
+        let mut buf = [0u8; MAX_UDP_SIZE];
+        loop {
+            let (n, peer) = recv.recv_from(&mut buf).await?;
+            let (query, server, send) = (buf[..n].to_vec(), server.clone(), send.clone());
+            // the first query of an app waits for its instances.
+            runtime::spawn(async move {
+                if let Some(rsp) = server.answer(&query).await {
+                    if let Err(e) = send.lock().await.send_to(&rsp, &peer).await {
+                        warn!("failed to answer dns query from {}: {}", peer, e);
+                    }
+                }
+            });
+        }
+    }
+
+    /// The response to the DNS message `query`, `None` when it isn't one.
+    pub async fn answer(&self, query: &[u8]) -> Option<Vec<u8>> {
+        if query.len() < 12 || query[2] & 0x80 != 0 {
+            return None;
+        }
+        let mut rsp = Response::new(query);
+        let question = match parse_question(query) {
+            Some(question) if query[2] & 0x78 == 0 => question,
+            Some(_) => return Some(rsp.finish(NOTIMP)),
+            None => return Some(rsp.finish(FORMERR)),
+        };
+        rsp.question(&query[12..question.end]);
+        if question.class != CLASS_IN {
+            return Some(rsp.finish(REFUSED));
+        }
+
+        let suffix = format!(".{}", self.domain);
+        let labels = match question.name.strip_suffix(&suffix) {
+            Some(name) => name.split('.').collect::<Vec<_>>(),
+            None => return Some(rsp.finish(REFUSED)),
+        };
+        let (app, rest) = labels.split_last()?;
+        let appid = match self.apps.get(*app) {
+            Some(appid) => appid,
+            None => return Some(rsp.finish(NXDOMAIN)),
+        };
+        let addrs = self.resolver.resolve(appid).await;
+        let ips = addrs.iter().map(SocketAddr::ip).collect::<BTreeSet<_>>();
+        let app_name = format!("{}{}", app, suffix);
+
+        match rest {
+            // the app itself, or one of its services.
+            [] | [_, _] => {
+                if rest.iter().any(|label| !label.starts_with('_')) {
+                    return Some(rsp.finish(NXDOMAIN));
+                }
+                if rest.is_empty() && matches!(question.qtype, TYPE_A | TYPE_AAAA | TYPE_ANY) {
+                    for ip in &ips {
+                        rsp.ip(question.qtype, *ip, self.ttl);
+                    }
+                }
+                if matches!(question.qtype, TYPE_SRV | TYPE_ANY) {
+                    for addr in &addrs {
+                        let target = format!("{}.{}", ip_label(addr.ip()), app_name);
+                        rsp.srv(addr.port(), &target, self.ttl);
+                    }
+                    for ip in &ips {
+                        let target = format!("{}.{}", ip_label(*ip), app_name);
+                        rsp.additional_ip(&target, *ip, self.ttl);
+                    }
+                }
+            }
+            [label] => match parse_ip_label(label).filter(|ip| ips.contains(ip)) {
+                Some(ip) => rsp.ip(question.qtype, ip, self.ttl),
+                None => return Some(rsp.finish(NXDOMAIN)),
+            },
+            _ => return Some(rsp.finish(NXDOMAIN)),
+        }
+        Some(rsp.finish(NOERROR))
+    }
+}
+
+struct Question {
+    name: String,
+    qtype: u16,
+    class: u16,
+    // where the question ends in the query.
+    end: usize,
+}
+
+fn parse_question(query: &[u8]) -> Option<Question> {
+    if u16::from_be_bytes([query[4], query[5]]) != 1 {
+        return None;
+    }
+    let mut labels = Vec::new();
+    let mut pos = 12;
+    loop {
+        let len = *query.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // compression pointers aren't used in questions.
+        if len > 63 {
+            return None;
+        }
+        let label = query.get(pos..pos + len)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        pos += len;
+    }
+    let fixed = query.get(pos..pos + 4)?;
+    Some(Question {
+        name: labels.join("."),
+        qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+        class: u16::from_be_bytes([fixed[2], fixed[3]]),
+        end: pos + 4,
+    })
+}
+
+fn ip_label(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string().replace('.', "-"),
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            let segments = segments.iter().map(|s| format!("{:x}", s));
+            segments.collect::<Vec<_>>().join("-")
+        }
+    }
+}
+
+fn parse_ip_label(label: &str) -> Option<IpAddr> {
+    let parts = label.split('-').collect::<Vec<_>>();
+    match parts.len() {
+        4 => parts.join(".").parse().ok(),
+        8 => {
+            let mut segments = [0u16; 8];
+            for (segment, part) in segments.iter_mut().zip(parts) {
+                *segment = u16::from_str_radix(part, 16).ok()?;
+            }
+            Some(Ipv6Addr::from(segments).into())
+        }
+        _ => None,
+    }
+}
+
+/// A response being written, answers first then additional records.
+struct Response {
+    header: [u8; 12],
+    question: Vec<u8>,
+    answers: Vec<u8>,
+    answer_count: u16,
+    additionals: Vec<u8>,
+    additional_count: u16,
+}
+
+impl Response {
+    fn new(query: &[u8]) -> Self {
+        let mut header = [0u8; 12];
+        header[..2].copy_from_slice(&query[..2]);
+        // a response, authoritative, recursion desired as asked.
+        header[2] = 0x84 | (query[2] & 0x79);
+        Response {
+            header,
+            question: Vec::new(),
+            answers: Vec::new(),
+            answer_count: 0,
+            additionals: Vec::new(),
+            additional_count: 0,
+        }
+    }
+
+    fn question(&mut self, question: &[u8]) {
+        self.question = question.to_vec();
+    }
+
+    // the name of the question.
+    fn ip(&mut self, qtype: u16, ip: IpAddr, ttl: u32) {
+        if let Some(rdata) = ip_rdata(qtype, ip) {
+            write_record(&mut self.answers, &[0xc0, 12], rdata.0, ttl, &rdata.1);
+            self.answer_count += 1;
+        }
+    }
+
+    fn additional_ip(&mut self, name: &str, ip: IpAddr, ttl: u32) {
+        if let Some(rdata) = ip_rdata(TYPE_ANY, ip) {
+            write_record(
+                &mut self.additionals,
+                &encode_name(name),
+                rdata.0,
+                ttl,
+                &rdata.1,
+            );
+            self.additional_count += 1;
+        }
+    }
+
+    fn srv(&mut self, port: u16, target: &str, ttl: u32) {
+        let mut rdata = Vec::new();
+        // priority and weight.
+        rdata.extend_from_slice(&[0, 0, 0, 1]);
+        rdata.extend_from_slice(&port.to_be_bytes());
+        rdata.extend(encode_name(target));
+        write_record(&mut self.answers, &[0xc0, 12], TYPE_SRV, ttl, &rdata);
+        self.answer_count += 1;
+    }
+
+    fn finish(mut self, rcode: u8) -> Vec<u8> {
+        let question_count = if self.question.is_empty() { 0 } else { 1 };
+        let mut len = 12 + self.question.len() + self.answers.len();
+        if len + self.additionals.len() > MAX_UDP_SIZE {
+            self.additionals.clear();
+            self.additional_count = 0;
+        }
+        if len > MAX_UDP_SIZE {
+            self.answers.clear();
+            self.answer_count = 0;
+            // truncated, the client asks again over TCP.
+            self.header[2] |= 0x02;
+            len = 12 + self.question.len();
+        }
+        self.header[3] = rcode;
+        self.header[4..6].copy_from_slice(&(question_count as u16).to_be_bytes());
+        self.header[6..8].copy_from_slice(&self.answer_count.to_be_bytes());
+        self.header[8..10].copy_from_slice(&0u16.to_be_bytes());
+        self.header[10..12].copy_from_slice(&self.additional_count.to_be_bytes());
+
+        let mut rsp = Vec::with_capacity(len + self.additionals.len());
+        rsp.extend_from_slice(&self.header);
+        rsp.extend(self.question);
+        rsp.extend(self.answers);
+        rsp.extend(self.additionals);
+        rsp
+    }
+}
+
+// the A or AAAA record of `ip` answering `qtype`.
+fn ip_rdata(qtype: u16, ip: IpAddr) -> Option<(u16, Vec<u8>)> {
+    match (qtype, ip) {
+        (TYPE_A, IpAddr::V4(ip)) | (TYPE_ANY, IpAddr::V4(ip)) => {
+            Some((TYPE_A, ip.octets().to_vec()))
+        }
+        (TYPE_AAAA, IpAddr::V6(ip)) | (TYPE_ANY, IpAddr::V6(ip)) => {
+            Some((TYPE_AAAA, ip.octets().to_vec()))
+        }
+        _ => None,
+    }
+}
+
+fn write_record(buf: &mut Vec<u8>, name: &[u8], rtype: u16, ttl: u32, rdata: &[u8]) {
+    buf.extend_from_slice(name);
+    buf.extend_from_slice(&rtype.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    buf.extend_from_slice(&ttl.to_be_bytes());
+    buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    buf.extend_from_slice(rdata);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_name, ip_label, parse_ip_label, DnsServer, TYPE_A, TYPE_AAAA, TYPE_SRV};
+    use crate::{resolver::Resolver, testing::MockRegistry, Instance};
+    use std::{net::IpAddr, sync::Arc};
+
+    fn instance(addr: &str) -> Instance {
+        Instance {
+            appid: "/dubbo-rs/provider".into(),
+            addrs: vec![addr.to_owned()].into(),
+            ..Default::default()
+        }
+    }
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend(encode_name(name));
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&[0, 1]);
+        query
+    }
+
+    // the rcode, and the rdata of the answers and of the additional records.
+    fn parse(query: &[u8], rsp: &[u8]) -> (u8, Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        assert_eq!(rsp[..2], query[..2]);
+        let count = |at: usize| u16::from_be_bytes([rsp[at], rsp[at + 1]]) as usize;
+        let (answers, additionals) = (count(6), count(10));
+        let mut pos = query.len();
+        let mut records = Vec::new();
+        for _ in 0..answers + additionals {
+            // a pointer, or the name.
+            if rsp[pos] == 0xc0 {
+                pos += 2;
+            } else {
+                while rsp[pos] != 0 {
+                    pos += rsp[pos] as usize + 1;
+                }
+                pos += 1;
+            }
+            let len = count(pos + 8);
+            records.push(rsp[pos + 10..pos + 10 + len].to_vec());
+            pos += 10 + len;
+        }
+        assert_eq!(pos, rsp.len());
+        let additionals = records.split_off(answers);
+        (rsp[3] & 0x0f, records, additionals)
+    }
+
+    #[test]
+    fn test_ip_label() {
+        for ip in &["172.1.1.1", "::1", "fe80::1:2"] {
+            let ip = ip.parse::<IpAddr>().unwrap();
+            assert_eq!(parse_ip_label(&ip_label(ip)), Some(ip));
+        }
+        assert_eq!(parse_ip_label("provider"), None);
+    }
+
+    #[tokio::test]
+    async fn test_answer() {
+        let registry = MockRegistry::new();
+        registry.insert(instance("grpc://172.1.1.1:9999"));
+        registry.insert(instance("grpc://[::1]:9998"));
+        let resolver = Arc::new(Resolver::new(registry.clone()).scheme("grpc"));
+        let dns = DnsServer::new(resolver).app("provider", "/dubbo-rs/provider");
+        let answer = |name: &str, qtype: u16| {
+            let query = query(name, qtype);
+            let dns = &dns;
+            async move {
+                let rsp = dns.answer(&query).await.unwrap();
+                parse(&query, &rsp)
+            }
+        };
+
+        assert_eq!(
+            answer("Provider.discover.local", TYPE_A).await,
+            (0, vec![vec![172, 1, 1, 1]], vec![])
+        );
+        let (rcode, answers, _) = answer("provider.discover.local", TYPE_AAAA).await;
+        assert_eq!((rcode, answers.len()), (0, 1));
+        assert_eq!(answers[0][15], 1);
+
+        let (rcode, mut answers, additionals) =
+            answer("_grpc._tcp.provider.discover.local", TYPE_SRV).await;
+        answers.sort();
+        let mut expected = vec![0, 0, 0, 1, 0x27, 0x0e];
+        expected.extend(encode_name("0-0-0-0-0-0-0-1.provider.discover.local"));
+        assert_eq!(rcode, 0);
+        assert_eq!(answers[0], expected);
+        let mut expected = vec![0, 0, 0, 1, 0x27, 0x0f];
+        expected.extend(encode_name("172-1-1-1.provider.discover.local"));
+        assert_eq!(answers[1], expected);
+        assert_eq!(additionals.len(), 2);
+
+        assert_eq!(
+            answer("172-1-1-1.provider.discover.local", TYPE_A).await,
+            (0, vec![vec![172, 1, 1, 1]], vec![])
+        );
+        assert_eq!(
+            answer("172-1-1-2.provider.discover.local", TYPE_A).await.0,
+            3
+        );
+        assert_eq!(answer("consumer.discover.local", TYPE_A).await.0, 3);
+        assert_eq!(answer("provider.example.com", TYPE_A).await.0, 5);
+    }
+}
@@ -0,0 +1,69 @@
+//! Leader election on the backend of a registry, for when exactly one
+//! instance of a service may be doing some work.
+//!
+//! ```ignore
+//! let mut campaign = zk.campaign("billing-reconciler", &hostname);
+//! while let Some(leadership) = campaign.next().await {
+//!     match leadership? {
+//!         Leadership::Acquired { token } => reconciler.start(token),
+//!         Leadership::Lost => reconciler.stop(),
+//!     }
+//! }
+//! ```
+//!
+//! Dropping a campaign resigns from it.
+use futures::Stream;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Leadership {
+    /// `token` grows with every new leader, pass it along with writes so the
+    /// systems written to can reject those of a former leader.
+    Acquired { token: u64 },
+    /// Stop working as the leader, another may be elected already. Also
+    /// reported when the backend can't be reached and leadership can't be
+    /// confirmed, it may be acquired again afterwards.
+    Lost,
+}
+
+/// A backend electing leaders.
+pub trait Election {
+    type Error;
+
+    /// Changes of leadership, ending when it can't run anymore, e.g. once the
+    /// session expired.
+    type Campaign: Stream<Item = Result<Leadership, Self::Error>>;
+
+    /// Runs for the leadership of `name` as `candidate`, e.g. a hostname,
+    /// until dropped.
+    fn campaign(&self, name: &str, candidate: &str) -> Self::Campaign;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Election, Leadership};
+    use crate::testing::MockRegistry;
+    use futures::StreamExt;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_election() {
+        let registry = MockRegistry::new();
+        let mut first = registry.campaign("reconciler", "host-1");
+        let mut second = registry.campaign("reconciler", "host-2");
+        let token = match first.next().await.unwrap().unwrap() {
+            Leadership::Acquired { token } => token,
+            lost => panic!("unexpected {:?}", lost),
+        };
+        let waiting = tokio::time::timeout(Duration::from_millis(10), second.next());
+        assert!(waiting.await.is_err());
+
+        drop(first);
+        match second.next().await.unwrap().unwrap() {
+            Leadership::Acquired { token: next } => assert!(next > token),
+            lost => panic!("unexpected {:?}", lost),
+        }
+        registry.revoke_leader("reconciler");
+        assert_eq!(second.next().await.unwrap().unwrap(), Leadership::Lost);
+        assert!(second.next().await.is_none());
+    }
+}
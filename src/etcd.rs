@@ -0,0 +1,747 @@
+//! A registry on etcd v3.
+//!
+//! Instances are the values of keys under `<root prefix>/<appid>/`, attached
+//! to a lease the registry keeps alive, so they go away with the process like
+//! ephemeral nodes in ZooKeeper.
+//! Should the lease expire anyway, e.g. while etcd was unreachable, another
+//! is granted and the instances registered are put again, like in a new
+//! ZooKeeper session.
+//!
+//! ```ignore
+//! let etcd = Etcd::builder("10.0.0.1:2379,10.0.0.2:2379")
+//!     .root_prefix("/discovery/prod")
+//!     .lease_ttl(Duration::from_secs(10))
+//!     .build()
+//!     .await?;
+//! etcd.register(instance).await?;
+//! let discover = AppDiscover::new(etcd.watch("billing"), make_service);
+//! ```
+use crate::{
+    codec::{
+        decode_with_payload, new_default_codec, Codec, Decoder, DefaultDecoder, DefaultEncoder,
+        EncodeError, Encoder,
+    },
+    identity::{DefaultIdentity, Identity},
+    runtime::{self, AbortOnDrop},
+    watcher::{Clock, Event, SystemClock, WatchEvent},
+    Instance, Registry,
+};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future::{self, BoxFuture},
+    stream, FutureExt, Stream, StreamExt,
+};
+use log::error;
+use pin_project::pin_project;
+use proto::{
+    Compare, CompareResult, CompareTarget, DeleteRangeRequest, DeleteRangeResponse, EventType,
+    KeyValue, LeaseGrantRequest, LeaseGrantResponse, LeaseKeepAliveRequest, LeaseKeepAliveResponse,
+    PutRequest, PutResponse, RangeRequest, RangeResponse, RequestOp, RequestUnion, TargetUnion,
+    TxnRequest, TxnResponse, WatchCreateRequest, WatchRequest, WatchResponse,
+};
+use std::{
+    collections::HashMap,
+    error, fmt,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tonic::{
+    codec::ProstCodec,
+    codegen::http::uri::PathAndQuery,
+    transport::{Channel, Endpoint},
+    Status, Streaming,
+};
+
+mod proto;
+
+// how long a failed watch waits before listing the instances again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Etcd<EC, DC, I = DefaultIdentity> {
+    client: Client,
+    codec: Arc<Codec<EC, DC>>,
+    root_prefix: String,
+    lease: Arc<Lease>,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+    _keep_alive: AbortOnDrop,
+}
+
+impl Etcd<DefaultEncoder, DefaultDecoder> {
+    /// Connects to `endpoints`, see `EtcdBuilder` for the options.
+    pub fn builder(endpoints: &str) -> EtcdBuilder<DefaultEncoder, DefaultDecoder> {
+        EtcdBuilder::new(endpoints)
+    }
+}
+
+impl<EC, DC, I> Etcd<EC, DC, I> {
+    /// Sets how watchers decide that a changed key is a re-registration of an
+    /// instance they already reported rather than a new one.
+    pub fn with_identity<NI>(self, identity: NI) -> Etcd<EC, DC, NI> {
+        Etcd {
+            client: self.client,
+            codec: self.codec,
+            root_prefix: self.root_prefix,
+            lease: self.lease,
+            identity: Arc::new(identity),
+            clock: self.clock,
+            _keep_alive: self._keep_alive,
+        }
+    }
+
+    /// Sets the clock timestamping the events of watchers.
+    pub fn with_clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    // the keys of the instances of `appid` start with it.
+    fn dir(&self, appid: &str) -> String {
+        format!("{}/{}/", self.root_prefix, appid)
+    }
+
+    // named after the fields identifying `ins`, so registering it again
+    // replaces it.
+    fn key(&self, ins: &Instance) -> Vec<u8> {
+        format!("{}{:016x}", self.dir(&ins.appid), fxhash::hash64(ins)).into_bytes()
+    }
+}
+
+/// Builds an `Etcd`.
+///
+/// ```ignore
+/// let etcd = Etcd::builder("http://10.0.0.1:2379")
+///     .root_prefix("/discovery/prod")
+///     .codec(new_dubbo_codec())
+///     .build()
+///     .await?;
+/// ```
+pub struct EtcdBuilder<EC, DC> {
+    endpoints: String,
+    root_prefix: String,
+    lease_ttl: Duration,
+    codec: Arc<Codec<EC, DC>>,
+}
+
+impl EtcdBuilder<DefaultEncoder, DefaultDecoder> {
+    /// Connects to `endpoints`, e.g. `10.0.0.1:2379,10.0.0.2:2379`, with
+    /// the default codec. Endpoints without a scheme are plain http.
+    pub fn new(endpoints: &str) -> Self {
+        EtcdBuilder {
+            endpoints: endpoints.to_owned(),
+            root_prefix: String::new(),
+            lease_ttl: Duration::from_secs(10),
+            codec: Arc::new(new_default_codec()),
+        }
+    }
+}
+
+impl<EC, DC> EtcdBuilder<EC, DC> {
+    /// Prepended to every key registered or watched, e.g. `/discovery/prod`.
+    pub fn root_prefix(mut self, prefix: &str) -> Self {
+        self.root_prefix = prefix.to_owned();
+        self
+    }
+
+    /// How long instances stay registered once the process can't renew their
+    /// lease, 10 seconds by default. Renewed every third of it.
+    pub fn lease_ttl(mut self, ttl: Duration) -> Self {
+        self.lease_ttl = ttl;
+        self
+    }
+
+    /// Sets how instances are encoded, `new_default_codec()` by default.
+    pub fn codec<NEC, NDC>(self, codec: impl Into<Arc<Codec<NEC, NDC>>>) -> EtcdBuilder<NEC, NDC> {
+        EtcdBuilder {
+            endpoints: self.endpoints,
+            root_prefix: self.root_prefix,
+            lease_ttl: self.lease_ttl,
+            codec: codec.into(),
+        }
+    }
+
+    /// Connects and grants the lease of the registrations. Needs a tokio
+    /// runtime.
+    pub async fn build(self) -> Result<Etcd<EC, DC>, EtcdError> {
+        let mut endpoints = Vec::new();
+        for endpoint in self.endpoints.split(',').map(str::trim) {
+            let uri = if endpoint.contains("://") {
+                endpoint.to_owned()
+            } else {
+                format!("http://{}", endpoint)
+            };
+            let endpoint = Endpoint::from_shared(uri)
+                .map_err(|e| EtcdError::InvalidEndpoint(e.to_string()))?;
+            endpoints.push(endpoint);
+        }
+        let client = Client {
+            channel: Channel::balance_list(endpoints.into_iter()),
+        };
+        let ttl = self.lease_ttl.as_secs().max(1);
+        let lease = Arc::new(Lease {
+            id: AtomicI64::new(client.grant(ttl).await?),
+            registered: Mutex::new(HashMap::new()),
+        });
+        let keep_alive = lease.clone().keep_alive(client.clone(), ttl);
+        let keep_alive = runtime::spawn_abortable(keep_alive);
+        Ok(Etcd {
+            client,
+            codec: self.codec,
+            root_prefix: self.root_prefix.trim_end_matches('/').to_owned(),
+            lease,
+            identity: Arc::new(DefaultIdentity),
+            clock: Arc::new(SystemClock),
+            _keep_alive: keep_alive,
+        })
+    }
+}
+
+// The lease of the instances registered, granted again once it expires,
+// e.g. after the process couldn't reach etcd for its ttl.
+struct Lease {
+    id: AtomicI64,
+    // the values of the keys registered, put again with a new lease.
+    registered: Mutex<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl Lease {
+    fn id(&self) -> i64 {
+        self.id.load(Ordering::SeqCst)
+    }
+
+    async fn keep_alive(self: Arc<Self>, client: Client, ttl: u64) {
+        loop {
+            runtime::delay_for(Duration::from_secs(ttl) / 3).await;
+            let id = self.id();
+            match client.keep_alive(id).await {
+                Ok(left) if left > 0 => {}
+                Ok(_) => {
+                    error!("the etcd lease {:x} expired, registering again", id);
+                    if let Err(e) = self.grant_again(&client, ttl).await {
+                        error!("failed to grant a new etcd lease. {}", e);
+                    }
+                }
+                Err(e) => error!("failed to renew the etcd lease {:x}. {}", id, e),
+            }
+        }
+    }
+
+    // like a new session in ZooKeeper: the instances registered went away
+    // with the lease and are put again with the new one.
+    async fn grant_again(&self, client: &Client, ttl: u64) -> Result<(), EtcdError> {
+        let id = client.grant(ttl).await?;
+        self.id.store(id, Ordering::SeqCst);
+        let registered = self.registered.lock().unwrap().clone();
+        for (key, value) in registered {
+            let req = PutRequest {
+                key: key.clone(),
+                value,
+                lease: id,
+                ignore_lease: false,
+            };
+            if let Err(e) = client.unary::<_, PutResponse>(req, proto::PUT_PATH).await {
+                let key = String::from_utf8_lossy(&key);
+                error!("failed to register {} again. {}", key, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct Client {
+    channel: Channel,
+}
+
+impl Client {
+    async fn grpc(&self) -> Result<tonic::client::Grpc<Channel>, EtcdError> {
+        let mut grpc = tonic::client::Grpc::new(self.channel.clone());
+        grpc.ready().await.map_err(EtcdError::Transport)?;
+        Ok(grpc)
+    }
+
+    async fn unary<Req, Rsp>(&self, req: Req, path: &'static str) -> Result<Rsp, EtcdError>
+    where
+        Req: prost::Message + Send + Sync + 'static,
+        Rsp: prost::Message + Default + Send + Sync + 'static,
+    {
+        let rsp = self
+            .grpc()
+            .await?
+            .unary(
+                tonic::Request::new(req),
+                PathAndQuery::from_static(path),
+                ProstCodec::default(),
+            )
+            .await?;
+        Ok(rsp.into_inner())
+    }
+
+    // a new lease of `ttl` seconds.
+    async fn grant(&self, ttl: u64) -> Result<i64, EtcdError> {
+        let req = LeaseGrantRequest { ttl: ttl as i64 };
+        let granted: LeaseGrantResponse = self.unary(req, proto::LEASE_GRANT_PATH).await?;
+        if !granted.error.is_empty() {
+            return Err(EtcdError::Lease(granted.error));
+        }
+        Ok(granted.id)
+    }
+
+    // the ttl left, not positive once the lease expired.
+    async fn keep_alive(&self, lease: i64) -> Result<i64, EtcdError> {
+        // the server answers each request before reading the next, the call
+        // ends with the only one.
+        let req = stream::once(future::ready(LeaseKeepAliveRequest { id: lease }));
+        let mut rsps: Streaming<LeaseKeepAliveResponse> = self
+            .grpc()
+            .await?
+            .streaming(
+                tonic::Request::new(req),
+                PathAndQuery::from_static(proto::LEASE_KEEP_ALIVE_PATH),
+                ProstCodec::default(),
+            )
+            .await?
+            .into_inner();
+        Ok(rsps.message().await?.map_or(0, |rsp| rsp.ttl))
+    }
+
+    async fn watch(
+        &self,
+        create: WatchCreateRequest,
+    ) -> Result<Streaming<WatchResponse>, EtcdError> {
+        let req = WatchRequest {
+            request_union: Some(RequestUnion::CreateRequest(create)),
+        };
+        // the requests are kept open, closing them would end the watch.
+        let reqs = stream::once(future::ready(req)).chain(stream::pending());
+        let rsps = self
+            .grpc()
+            .await?
+            .streaming(
+                tonic::Request::new(reqs),
+                PathAndQuery::from_static(proto::WATCH_PATH),
+                ProstCodec::default(),
+            )
+            .await?;
+        Ok(rsps.into_inner())
+    }
+
+    async fn list(&self, prefix: &[u8]) -> Result<RangeResponse, EtcdError> {
+        let req = RangeRequest {
+            key: prefix.to_vec(),
+            range_end: prefix_end(prefix),
+        };
+        self.unary(req, proto::RANGE_PATH).await
+    }
+
+    async fn delete(&self, key: Vec<u8>) -> Result<(), EtcdError> {
+        let req = DeleteRangeRequest {
+            key,
+            range_end: Vec::new(),
+        };
+        let _: DeleteRangeResponse = self.unary(req, proto::DELETE_RANGE_PATH).await?;
+        Ok(())
+    }
+}
+
+// the end of the range of keys starting with `prefix`.
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // every key, `\0` in etcd.
+    vec![0]
+}
+
+#[derive(Debug)]
+pub enum EtcdError {
+    InvalidEndpoint(String),
+    Transport(tonic::transport::Error),
+    Status(Status),
+    /// The lease of the registrations couldn't be granted.
+    Lease(String),
+    /// The lease of the registrations expired before it was renewed, see
+    /// `Registry::renew`. The instances are registered again with a new one.
+    LeaseExpired,
+    Encode(EncodeError),
+    /// The key was updated or deleted since the expected revision, see
+    /// `Registry::update_if`.
+    Conflict,
+    /// The server canceled a watch, e.g. its revision was compacted.
+    WatchCanceled(String),
+}
+
+impl fmt::Display for EtcdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EtcdError::InvalidEndpoint(e) => write!(f, "invalid etcd endpoint: {}", e),
+            EtcdError::Transport(e) => write!(f, "failed to connect to etcd: {}", e),
+            EtcdError::Status(status) => write!(f, "etcd call failed: {}", status),
+            EtcdError::Lease(e) => write!(f, "failed to grant the etcd lease: {}", e),
+            EtcdError::LeaseExpired => write!(f, "the etcd lease expired"),
+            EtcdError::Encode(e) => e.fmt(f),
+            EtcdError::Conflict => write!(f, "the instance was updated since"),
+            EtcdError::WatchCanceled(reason) => write!(f, "etcd canceled the watch: {}", reason),
+        }
+    }
+}
+
+impl error::Error for EtcdError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            EtcdError::Transport(e) => Some(e),
+            EtcdError::Status(status) => Some(status),
+            EtcdError::Encode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Status> for EtcdError {
+    fn from(status: Status) -> Self {
+        EtcdError::Status(status)
+    }
+}
+
+impl From<EncodeError> for EtcdError {
+    fn from(e: EncodeError) -> Self {
+        EtcdError::Encode(e)
+    }
+}
+
+impl<EC, DC, I> Registry for Etcd<EC, DC, I>
+where
+    EC: Encoder + Send + Sync + 'static,
+    DC: Decoder + Send + Sync + 'static,
+    I: Identity + Send + Sync + 'static,
+{
+    type Error = EtcdError;
+
+    type RegFuture = BoxFuture<'static, Result<(), EtcdError>>;
+
+    type DeRegFuture = BoxFuture<'static, Result<(), EtcdError>>;
+
+    type ListFuture = BoxFuture<'static, Result<Vec<Arc<Instance>>, EtcdError>>;
+
+    type Watcher = EtcdWatcher;
+
+    fn register(&self, ins: Arc<Instance>) -> Self::RegFuture {
+        let client = self.client.clone();
+        let key = self.key(&ins);
+        let lease = self.lease.clone();
+        let value = self
+            .codec
+            .get_encoder_ref()
+            .encode(&ins)
+            .map_err(|e| -> EncodeError { e.into() });
+        async move {
+            let value = value?;
+            let req = PutRequest {
+                key: key.clone(),
+                value: value.clone(),
+                lease: lease.id(),
+                ignore_lease: false,
+            };
+            let _: PutResponse = client.unary(req, proto::PUT_PATH).await?;
+            lease.registered.lock().unwrap().insert(key, value);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn deregister(&self, ins: &Arc<Instance>) -> Self::DeRegFuture {
+        let client = self.client.clone();
+        let key = self.key(ins);
+        let lease = self.lease.clone();
+        async move {
+            client.delete(key.clone()).await?;
+            lease.registered.lock().unwrap().remove(&key);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    // etcd doesn't tell why a key went away, so watchers report evictions as
+    // deletes without a reason.
+    fn evict(&self, appid: &str, instance_id: &str) -> Self::DeRegFuture {
+        let client = self.client.clone();
+        let dir = self.dir(appid).into_bytes();
+        let codec = self.codec.clone();
+        let lease = self.lease.clone();
+        let instance_id = instance_id.to_owned();
+        async move {
+            let decoder = codec.get_decoder_ref();
+            let listed = client.list(&dir).await?;
+            for kv in listed.kvs {
+                let evicted = match decoder.decode(&kv.value) {
+                    Ok(ins) => ins.has_id(&instance_id),
+                    Err(_) => false,
+                };
+                if evicted {
+                    client.delete(kv.key.clone()).await?;
+                    lease.registered.lock().unwrap().remove(&kv.key);
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    // instances are attached to the lease of the registry, renewing one
+    // renews them all.
+    fn renew(&self, _ins: &Arc<Instance>) -> Self::RegFuture {
+        let (client, lease) = (self.client.clone(), self.lease.id());
+        async move {
+            if client.keep_alive(lease).await? <= 0 {
+                return Err(EtcdError::LeaseExpired);
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    // the revision of instances is the mod revision of their key.
+    fn update_if(&self, ins: Arc<Instance>, expected_version: u64) -> Self::RegFuture {
+        let client = self.client.clone();
+        let key = self.key(&ins);
+        let lease = self.lease.clone();
+        let value = self
+            .codec
+            .get_encoder_ref()
+            .encode(&ins)
+            .map_err(|e| -> EncodeError { e.into() });
+        async move {
+            let value = value?;
+            let put = PutRequest {
+                key: key.clone(),
+                value: value.clone(),
+                lease: 0,
+                ignore_lease: true,
+            };
+            let req = TxnRequest {
+                compare: vec![Compare {
+                    result: CompareResult::Equal as i32,
+                    target: CompareTarget::Mod as i32,
+                    key: key.clone(),
+                    target_union: Some(TargetUnion::ModRevision(expected_version as i64)),
+                }],
+                success: vec![RequestOp {
+                    request: Some(proto::Request::RequestPut(put)),
+                }],
+            };
+            let txn: TxnResponse = client.unary(req, proto::TXN_PATH).await?;
+            if !txn.succeeded {
+                return Err(EtcdError::Conflict);
+            }
+            // put again with the new value once the lease expires.
+            if let Some(registered) = lease.registered.lock().unwrap().get_mut(&key) {
+                *registered = value;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    // listed with their revision, as watchers report them.
+    fn list(&self, appid: &str) -> Self::ListFuture {
+        let client = self.client.clone();
+        let dir = self.dir(appid).into_bytes();
+        let codec = self.codec.clone();
+        async move {
+            let decoder = codec.get_decoder_ref();
+            let listed = client.list(&dir).await?;
+            let mut instances = Vec::new();
+            for kv in listed.kvs {
+                match decoder.decode(&kv.value) {
+                    Ok(mut ins) => {
+                        ins.revision = Some(kv.mod_revision as u64);
+                        instances.push(Arc::new(ins));
+                    }
+                    Err(e) => error!(
+                        "failed to decode {}. {}",
+                        String::from_utf8_lossy(&kv.key),
+                        e
+                    ),
+                }
+            }
+            Ok(instances)
+        }
+        .boxed()
+    }
+
+    fn watch(&self, appid: &str) -> Self::Watcher {
+        self.watch_from(appid, &[])
+    }
+
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher {
+        let (tx, rx) = mpsc::unbounded();
+        let mut known = HashMap::new();
+        for ins in instances {
+            known.insert(self.key(ins), ins.clone());
+            let event = WatchEvent::with_clock(Event::Create(ins.clone()), &*self.clock);
+            let _ = tx.unbounded_send(event);
+        }
+        let watch = AppWatch {
+            client: self.client.clone(),
+            prefix: self.dir(appid).into_bytes(),
+            codec: self.codec.clone(),
+            identity: self.identity.clone(),
+            clock: self.clock.clone(),
+            known,
+            tx,
+        };
+        EtcdWatcher {
+            rx,
+            _task: runtime::spawn_abortable(watch.run()),
+        }
+    }
+}
+
+/// The instances of an app in etcd, see `Registry::watch`.
+#[pin_project]
+pub struct EtcdWatcher {
+    #[pin]
+    rx: UnboundedReceiver<WatchEvent>,
+    _task: AbortOnDrop,
+}
+
+impl Stream for EtcdWatcher {
+    type Item = WatchEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().rx.poll_next(cx)
+    }
+}
+
+struct AppWatch<EC, DC, I> {
+    client: Client,
+    prefix: Vec<u8>,
+    codec: Arc<Codec<EC, DC>>,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+    // the instances reported by key.
+    known: HashMap<Vec<u8>, Arc<Instance>>,
+    tx: UnboundedSender<WatchEvent>,
+}
+
+impl<EC, DC, I> AppWatch<EC, DC, I>
+where
+    EC: Encoder,
+    DC: Decoder,
+    I: Identity,
+{
+    async fn run(mut self) {
+        loop {
+            if let Err(e) = self.watch().await {
+                let prefix = String::from_utf8_lossy(&self.prefix);
+                error!("failed to watch {}. {}", prefix, e);
+            }
+            runtime::delay_for(RETRY_INTERVAL).await;
+        }
+    }
+
+    // lists the instances, reporting what changed since the last listing,
+    // then watches the changes from there.
+    async fn watch(&mut self) -> Result<(), EtcdError> {
+        let listed = self.client.list(&self.prefix).await?;
+        let revision = listed.header.map_or(0, |header| header.revision);
+        let mut gone = self.known.clone();
+        for kv in listed.kvs {
+            gone.remove(&kv.key);
+            self.put(kv);
+        }
+        for key in gone.keys() {
+            self.delete(key);
+        }
+        let mut events = self
+            .client
+            .watch(WatchCreateRequest {
+                key: self.prefix.clone(),
+                range_end: prefix_end(&self.prefix),
+                start_revision: revision + 1,
+            })
+            .await?;
+        while let Some(rsp) = events.message().await? {
+            if rsp.canceled {
+                return Err(EtcdError::WatchCanceled(rsp.cancel_reason));
+            }
+            for event in rsp.events {
+                let kv = match event.kv {
+                    Some(kv) => kv,
+                    None => continue,
+                };
+                if event.r#type == EventType::Delete as i32 {
+                    self.delete(&kv.key);
+                } else {
+                    self.put(kv);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // reports the instance of `kv` as a Create, like a re-registration, and
+    // the one it replaces as a Delete when it isn't the same instance.
+    fn put(&mut self, kv: KeyValue) {
+        let mut ins = match decode_with_payload(self.codec.get_decoder_ref(), &kv.value) {
+            Ok(ins) => ins,
+            Err(e) => {
+                error!(
+                    "failed to decode {}. {}",
+                    String::from_utf8_lossy(&kv.key),
+                    e
+                );
+                return self.delete(&kv.key);
+            }
+        };
+        ins.revision = Some(kv.mod_revision as u64);
+        let ins = Arc::new(ins);
+        let old = self.known.insert(kv.key, ins.clone());
+        // listed again unchanged.
+        if old.as_ref() == Some(&ins) {
+            return;
+        }
+        self.send(Event::Create(ins.clone()));
+        if let Some(old) = old {
+            if self.identity.identify(&old) != self.identity.identify(&ins) {
+                self.send(Event::Delete(old));
+            }
+        }
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        if let Some(old) = self.known.remove(key) {
+            self.send(Event::Delete(old));
+        }
+    }
+
+    fn send(&self, event: Event) {
+        let _ = self
+            .tx
+            .unbounded_send(WatchEvent::with_clock(event, &*self.clock));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::prefix_end;
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end(b"/discovery/billing/"), b"/discovery/billing0");
+        assert_eq!(prefix_end(b"a\xff\xff"), b"b");
+        assert_eq!(prefix_end(b"\xff"), b"\0");
+    }
+}
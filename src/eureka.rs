@@ -0,0 +1,657 @@
+//! A registry on Eureka, through its REST API.
+//!
+//! Instances are registered under their appid, upper-cased as Eureka does,
+//! and their leases renewed in the background. Watches fetch the instances of
+//! their app, then only the recent changes of the registry, its delta. As the
+//! delta only covers a few minutes, the whole app is fetched again after a
+//! failure and every few fetches.
+//!
+//! ```ignore
+//! let eureka = Eureka::builder("http://eureka:8080/eureka/v2").build();
+//! eureka.register(instance).await?;
+//! let discover = AppDiscover::new(eureka.watch("billing"), make_service);
+//! ```
+//!
+//! The fields of instances besides their appid and address are kept in the
+//! instance metadata. Instances registered by other means are reported with
+//! their address as `http://<ipAddr>:<port>`, and only while `UP`.
+use crate::{
+    flat,
+    identity::{DefaultIdentity, Identity},
+    runtime::{self, AbortOnDrop},
+    watcher::{Clock, Event, SystemClock, WatchEvent},
+    Instance, Registry,
+};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future::BoxFuture,
+    FutureExt, Stream,
+};
+use hyper::{body::Bytes, client::HttpConnector, header, Body, Method, Request, StatusCode};
+use log::{error, warn};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use pin_project::pin_project;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    error, fmt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+// every how many fetches a watch fetches the whole app.
+const FULL_FETCH_EVERY: u64 = 10;
+
+// the instance infos registered by app and instance id.
+type Registrations = Mutex<HashMap<(String, String), Vec<u8>>>;
+
+pub struct Eureka<I = DefaultIdentity> {
+    client: Client,
+    lease: LeaseParams,
+    fetch_interval: Duration,
+    // registered again when the server lost them.
+    registered: Arc<Registrations>,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+    _renewal: AbortOnDrop,
+}
+
+impl Eureka {
+    /// Talks to the server at `service_url`, see `EurekaBuilder` for the
+    /// options.
+    pub fn builder(service_url: &str) -> EurekaBuilder {
+        EurekaBuilder::new(service_url)
+    }
+}
+
+impl<I> Eureka<I> {
+    /// Sets how watchers tell instances apart.
+    pub fn with_identity<NI>(self, identity: NI) -> Eureka<NI> {
+        Eureka {
+            client: self.client,
+            lease: self.lease,
+            fetch_interval: self.fetch_interval,
+            registered: self.registered,
+            identity: Arc::new(identity),
+            clock: self.clock,
+            _renewal: self._renewal,
+        }
+    }
+
+    /// Sets the clock timestamping the events of watchers.
+    pub fn with_clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+}
+
+/// Builds a `Eureka`.
+///
+/// ```ignore
+/// let eureka = Eureka::builder("http://eureka:8761/eureka")
+///     .renewal_interval(Duration::from_secs(10))
+///     .lease_duration(Duration::from_secs(30))
+///     .build();
+/// ```
+pub struct EurekaBuilder {
+    service_url: String,
+    lease: LeaseParams,
+    fetch_interval: Duration,
+}
+
+impl EurekaBuilder {
+    /// `service_url` is the root of the REST API, e.g.
+    /// `http://eureka:8080/eureka/v2`.
+    pub fn new(service_url: &str) -> Self {
+        EurekaBuilder {
+            service_url: service_url.trim_end_matches('/').to_owned(),
+            lease: LeaseParams {
+                renewal_interval: Duration::from_secs(30),
+                duration: Duration::from_secs(90),
+            },
+            fetch_interval: Duration::from_secs(30),
+        }
+    }
+
+    /// How often leases are renewed, 30 seconds by default.
+    pub fn renewal_interval(mut self, interval: Duration) -> Self {
+        self.lease.renewal_interval = interval;
+        self
+    }
+
+    /// How long the server keeps instances whose lease isn't renewed, 90
+    /// seconds by default.
+    pub fn lease_duration(mut self, duration: Duration) -> Self {
+        self.lease.duration = duration;
+        self
+    }
+
+    /// How often watches fetch changes, 30 seconds by default.
+    pub fn fetch_interval(mut self, interval: Duration) -> Self {
+        self.fetch_interval = interval;
+        self
+    }
+
+    /// Needs a tokio runtime, the leases of the instances registered are
+    /// renewed in the background.
+    pub fn build(self) -> Eureka {
+        let client = Client {
+            http: hyper::Client::new(),
+            service_url: self.service_url,
+        };
+        let registered = Arc::new(Mutex::new(HashMap::new()));
+        let interval = self.lease.renewal_interval;
+        let renewal = {
+            let (client, registered) = (client.clone(), registered.clone());
+            async move {
+                loop {
+                    runtime::delay_for(interval).await;
+                    renew(&client, &registered).await;
+                }
+            }
+        };
+        let renewal = runtime::spawn_abortable(renewal);
+        Eureka {
+            client,
+            lease: self.lease,
+            fetch_interval: self.fetch_interval,
+            registered,
+            identity: Arc::new(DefaultIdentity),
+            clock: Arc::new(SystemClock),
+            _renewal: renewal,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LeaseParams {
+    renewal_interval: Duration,
+    duration: Duration,
+}
+
+async fn renew(client: &Client, registered: &Registrations) {
+    let registrations = registered.lock().unwrap().clone();
+    for ((app, id), registration) in registrations {
+        if let Err(e) = renew_lease(client, &app, &id, registration).await {
+            error!("failed to renew the lease of {}. {}", id, e);
+        }
+    }
+}
+
+// renews the lease of the instance `id` of `app`, registering it again when
+// lost.
+async fn renew_lease(
+    client: &Client,
+    app: &str,
+    id: &str,
+    registration: Vec<u8>,
+) -> Result<(), EurekaError> {
+    let path = format!("/apps/{}/{}", encode(app), encode(id));
+    match client.call(Method::PUT, &path, None).await {
+        Ok(_) => Ok(()),
+        Err(EurekaError::Status(StatusCode::NOT_FOUND, _)) => {
+            warn!("eureka lost the instance {}, registering it again", id);
+            let path = format!("/apps/{}", encode(app));
+            client.call(Method::POST, &path, Some(registration)).await?;
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Clone)]
+struct Client {
+    http: hyper::Client<HttpConnector>,
+    service_url: String,
+}
+
+impl Client {
+    async fn call(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Vec<u8>>,
+    ) -> Result<Bytes, EurekaError> {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(format!("{}{}", self.service_url, path))
+            .header(header::ACCEPT, "application/json");
+        if body.is_some() {
+            req = req.header(header::CONTENT_TYPE, "application/json");
+        }
+        let req = req
+            .body(body.map_or_else(Body::empty, Body::from))
+            .map_err(|e| EurekaError::InvalidAddress(e.to_string()))?;
+        let rsp = self.http.request(req).await?;
+        let status = rsp.status();
+        let body = hyper::body::to_bytes(rsp.into_body()).await?;
+        if !status.is_success() {
+            let message = String::from_utf8_lossy(&body).into_owned();
+            return Err(EurekaError::Status(status, message));
+        }
+        Ok(body)
+    }
+
+    // the instances of `app`, none when it has none.
+    async fn app(&self, app: &str) -> Result<Vec<Value>, EurekaError> {
+        let path = format!("/apps/{}", encode(app));
+        let rsp = match self.call(Method::GET, &path, None).await {
+            Ok(rsp) => rsp,
+            Err(EurekaError::Status(StatusCode::NOT_FOUND, _)) => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let rsp: Value = serde_json::from_slice(&rsp)?;
+        Ok(list(&rsp["application"]["instance"]))
+    }
+
+    // the instances of `app` changed lately, with their `actionType`.
+    async fn delta(&self, app: &str) -> Result<Vec<Value>, EurekaError> {
+        let rsp = self.call(Method::GET, "/apps/delta", None).await?;
+        let rsp: Value = serde_json::from_slice(&rsp)?;
+        let changed = list(&rsp["applications"]["application"])
+            .into_iter()
+            .filter(|application| {
+                application["name"]
+                    .as_str()
+                    .is_some_and(|name| name.eq_ignore_ascii_case(app))
+            })
+            .flat_map(|application| list(&application["instance"]))
+            .collect();
+        Ok(changed)
+    }
+}
+
+// Eureka answers a single element rather than a list of one.
+fn list(value: &Value) -> Vec<Value> {
+    match value {
+        Value::Array(values) => values.clone(),
+        Value::Null => Vec::new(),
+        value => vec![value.clone()],
+    }
+}
+
+fn encode(segment: &str) -> String {
+    utf8_percent_encode(segment, NON_ALPHANUMERIC).to_string()
+}
+
+// named after the fields identifying `ins`, so registering it again replaces
+// it.
+fn instance_id(ins: &Instance) -> String {
+    format!("{}-{:016x}", ins.appid, fxhash::hash64(ins))
+}
+
+fn app_name(appid: &str) -> String {
+    appid.to_uppercase()
+}
+
+// the registration of `ins`, as `id`.
+fn to_instance_info(ins: &Instance, id: &str, lease: LeaseParams) -> Value {
+    let (ip, port) = flat::address(ins);
+    let host_name = if ins.hostname.is_empty() {
+        ip
+    } else {
+        &ins.hostname
+    };
+    json!({
+        "instance": {
+            "instanceId": id,
+            "hostName": host_name,
+            "app": app_name(&ins.appid),
+            "ipAddr": ip,
+            "vipAddress": &*ins.appid,
+            "status": "UP",
+            "port": {"$": port, "@enabled": "true"},
+            "securePort": {"$": 443, "@enabled": "false"},
+            "dataCenterInfo": {
+                "@class": "com.netflix.appinfo.InstanceInfo$DefaultDataCenterInfo",
+                "name": "MyOwn",
+            },
+            "leaseInfo": {
+                "renewalIntervalInSecs": lease.renewal_interval.as_secs(),
+                "durationInSecs": lease.duration.as_secs(),
+            },
+            "metadata": flat::to_metadata(ins),
+        }
+    })
+}
+
+// the instance of an instance info, `appid` as watched rather than
+// upper-cased.
+fn from_instance_info(appid: &str, info: &Value) -> Option<Instance> {
+    let port = &info["port"]["$"];
+    let port = match port.as_u64() {
+        Some(port) => port,
+        None => port.as_str()?.parse().ok()?,
+    };
+    let default_addr = format!("http://{}:{}", info["ipAddr"].as_str()?, port);
+    let metadata = info["metadata"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        // Eureka's own.
+        .filter(|(key, _)| key.as_str() != "@class")
+        .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_owned())))
+        .collect();
+    Some(flat::from_metadata(appid, default_addr, metadata))
+}
+
+fn up(info: &Value) -> bool {
+    info["status"].as_str() == Some("UP")
+}
+
+#[derive(Debug)]
+pub enum EurekaError {
+    InvalidAddress(String),
+    Http(hyper::Error),
+    /// The server answered with an error status.
+    Status(StatusCode, String),
+    Json(serde_json::Error),
+    /// Eureka can't update instances only at a given version, see
+    /// `Registry::update_if`.
+    Unsupported,
+}
+
+impl fmt::Display for EurekaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EurekaError::InvalidAddress(e) => write!(f, "invalid eureka address: {}", e),
+            EurekaError::Http(e) => write!(f, "failed to call eureka: {}", e),
+            EurekaError::Status(status, message) => {
+                write!(f, "eureka answered {}: {}", status, message)
+            }
+            EurekaError::Json(e) => write!(f, "bad json from eureka: {}", e),
+            EurekaError::Unsupported => write!(f, "unsupported by eureka"),
+        }
+    }
+}
+
+impl error::Error for EurekaError {}
+
+impl From<hyper::Error> for EurekaError {
+    fn from(e: hyper::Error) -> Self {
+        EurekaError::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for EurekaError {
+    fn from(e: serde_json::Error) -> Self {
+        EurekaError::Json(e)
+    }
+}
+
+impl<I> Registry for Eureka<I>
+where
+    I: Identity + Send + Sync + 'static,
+    I::Key: Send + Sync,
+{
+    type Error = EurekaError;
+
+    type RegFuture = BoxFuture<'static, Result<(), EurekaError>>;
+
+    type DeRegFuture = BoxFuture<'static, Result<(), EurekaError>>;
+
+    type ListFuture = BoxFuture<'static, Result<Vec<Arc<Instance>>, EurekaError>>;
+
+    type Watcher = EurekaWatcher;
+
+    fn register(&self, ins: Arc<Instance>) -> Self::RegFuture {
+        let client = self.client.clone();
+        let registered = self.registered.clone();
+        let (app, id) = (app_name(&ins.appid), instance_id(&ins));
+        let info = to_instance_info(&ins, &id, self.lease)
+            .to_string()
+            .into_bytes();
+        async move {
+            let path = format!("/apps/{}", encode(&app));
+            client.call(Method::POST, &path, Some(info.clone())).await?;
+            registered.lock().unwrap().insert((app, id), info);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn deregister(&self, ins: &Arc<Instance>) -> Self::DeRegFuture {
+        let client = self.client.clone();
+        let (app, id) = (app_name(&ins.appid), instance_id(ins));
+        self.registered
+            .lock()
+            .unwrap()
+            .remove(&(app.clone(), id.clone()));
+        async move {
+            let path = format!("/apps/{}/{}", encode(&app), encode(&id));
+            client.call(Method::DELETE, &path, None).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    // Eureka doesn't tell why an instance went away, so watchers report
+    // evictions as deletes without a reason.
+    fn evict(&self, appid: &str, instance_id: &str) -> Self::DeRegFuture {
+        let client = self.client.clone();
+        let appid = appid.to_owned();
+        let instance_id = instance_id.to_owned();
+        async move {
+            let app = app_name(&appid);
+            for info in client.app(&app).await? {
+                match from_instance_info(&appid, &info) {
+                    Some(ins) if ins.has_id(&instance_id) => {}
+                    _ => continue,
+                }
+                let id = match info["instanceId"].as_str() {
+                    Some(id) => id,
+                    None => continue,
+                };
+                let path = format!("/apps/{}/{}", encode(&app), encode(id));
+                match client.call(Method::DELETE, &path, None).await {
+                    // deregistered meanwhile.
+                    Ok(_) | Err(EurekaError::Status(StatusCode::NOT_FOUND, _)) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    // Eureka has no check-and-set on instances.
+    fn update_if(&self, _ins: Arc<Instance>, _expected_version: u64) -> Self::RegFuture {
+        futures::future::ready(Err(EurekaError::Unsupported)).boxed()
+    }
+
+    fn renew(&self, ins: &Arc<Instance>) -> Self::RegFuture {
+        let client = self.client.clone();
+        let (app, id) = (app_name(&ins.appid), instance_id(ins));
+        let info = to_instance_info(ins, &id, self.lease)
+            .to_string()
+            .into_bytes();
+        async move { renew_lease(&client, &app, &id, info).await }.boxed()
+    }
+
+    fn list(&self, appid: &str) -> Self::ListFuture {
+        let client = self.client.clone();
+        let appid = appid.to_owned();
+        async move {
+            let infos = client.app(&app_name(&appid)).await?;
+            Ok(infos
+                .iter()
+                .filter(|info| up(info))
+                .filter_map(|info| from_instance_info(&appid, info))
+                .map(Arc::new)
+                .collect())
+        }
+        .boxed()
+    }
+
+    fn watch(&self, appid: &str) -> Self::Watcher {
+        self.watch_from(appid, &[])
+    }
+
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher {
+        let (tx, rx) = mpsc::unbounded();
+        let mut known = HashMap::new();
+        for ins in instances {
+            known.insert(self.identity.identify(ins), ins.clone());
+            let event = WatchEvent::with_clock(Event::Create(ins.clone()), &*self.clock);
+            let _ = tx.unbounded_send(event);
+        }
+        let watch = AppWatch {
+            client: self.client.clone(),
+            appid: appid.to_owned(),
+            fetch_interval: self.fetch_interval,
+            identity: self.identity.clone(),
+            clock: self.clock.clone(),
+            known,
+            tx,
+        };
+        EurekaWatcher {
+            rx,
+            _task: runtime::spawn_abortable(watch.run()),
+        }
+    }
+}
+
+/// The instances of an app in Eureka, see `Registry::watch`.
+#[pin_project]
+pub struct EurekaWatcher {
+    #[pin]
+    rx: UnboundedReceiver<WatchEvent>,
+    _task: AbortOnDrop,
+}
+
+impl Stream for EurekaWatcher {
+    type Item = WatchEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().rx.poll_next(cx)
+    }
+}
+
+struct AppWatch<I: Identity> {
+    client: Client,
+    appid: String,
+    fetch_interval: Duration,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+    // the instances reported.
+    known: HashMap<I::Key, Arc<Instance>>,
+    tx: UnboundedSender<WatchEvent>,
+}
+
+impl<I> AppWatch<I>
+where
+    I: Identity,
+{
+    async fn run(mut self) {
+        let app = app_name(&self.appid);
+        let mut fetches = 0;
+        let mut failed = false;
+        loop {
+            let fetched = if failed || fetches % FULL_FETCH_EVERY == 0 {
+                self.client.app(&app).await.map(|infos| self.update(&infos))
+            } else {
+                self.client
+                    .delta(&app)
+                    .await
+                    .map(|infos| self.apply(&infos))
+            };
+            failed = match fetched {
+                Ok(()) => false,
+                Err(e) => {
+                    error!("failed to watch {}. {}", self.appid, e);
+                    true
+                }
+            };
+            fetches += 1;
+            runtime::delay_for(self.fetch_interval).await;
+        }
+    }
+
+    // reports the instances up now and not before as Creates, the ones
+    // changed too, and the ones gone as Deletes.
+    fn update(&mut self, infos: &[Value]) {
+        let mut gone = std::mem::take(&mut self.known);
+        for info in infos.iter().filter(|info| up(info)) {
+            let ins = match from_instance_info(&self.appid, info) {
+                Some(ins) => Arc::new(ins),
+                None => continue,
+            };
+            let key = self.identity.identify(&ins);
+            let unchanged = gone.remove(&key).filter(|old| *old == ins);
+            if unchanged.is_none() {
+                self.send(Event::Create(ins.clone()));
+            }
+            self.known.insert(key, ins);
+        }
+        for (_, ins) in gone {
+            self.send(Event::Delete(ins));
+        }
+    }
+
+    // applies the changes of a delta, instances no longer up are deleted.
+    fn apply(&mut self, infos: &[Value]) {
+        for info in infos {
+            let ins = match from_instance_info(&self.appid, info) {
+                Some(ins) => Arc::new(ins),
+                None => continue,
+            };
+            let key = self.identity.identify(&ins);
+            let deleted = info["actionType"].as_str() == Some("DELETED") || !up(info);
+            if deleted {
+                if let Some(old) = self.known.remove(&key) {
+                    self.send(Event::Delete(old));
+                }
+                continue;
+            }
+            match self.known.insert(key, ins.clone()) {
+                Some(old) if old == ins => {}
+                _ => self.send(Event::Create(ins)),
+            }
+        }
+    }
+
+    fn send(&self, event: Event) {
+        let _ = self
+            .tx
+            .unbounded_send(WatchEvent::with_clock(event, &*self.clock));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_instance_info, list, to_instance_info, up, EurekaBuilder};
+    use crate::{intern::intern, Instance};
+    use serde_json::json;
+
+    #[test]
+    fn test_instance_info() {
+        let ins = Instance {
+            appid: intern("billing"),
+            addrs: vec!["http://10.0.0.1:8080".to_owned()].into(),
+            version: "1.2.0".to_owned(),
+            ..Default::default()
+        };
+        let lease = EurekaBuilder::new("http://eureka:8080/eureka/v2").lease;
+        let mut info = to_instance_info(&ins, "billing-1", lease)["instance"].clone();
+        assert_eq!(info["app"], "BILLING");
+        assert_eq!(info["port"]["$"], 8080);
+        assert_eq!(info["leaseInfo"]["durationInSecs"], 90);
+        assert!(up(&info));
+        assert_eq!(from_instance_info("billing", &info).unwrap(), ins);
+
+        // as some servers answer.
+        info["port"]["$"] = json!("8080");
+        info["metadata"] = json!({"@class": "java.util.Collections$EmptyMap"});
+        let native = from_instance_info("billing", &info).unwrap();
+        assert_eq!(&native.addrs[..], ["http://10.0.0.1:8080"]);
+        assert!(native.metadata.is_empty());
+
+        assert_eq!(list(&json!({"name": "BILLING"})).len(), 1);
+        assert!(list(&json!(null)).is_empty());
+    }
+}
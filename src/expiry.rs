@@ -0,0 +1,253 @@
+//! Expires the instances of backends keeping them until deleted, like a file
+//! or an HTTP endpoint, once they are no longer renewed.
+//!
+//! ```ignore
+//! let watcher = Expiring::new(registry.watch("provider"));
+//! let discover = AppDiscover::new(watcher, make_service);
+//! ```
+use crate::{
+    identity::{DefaultIdentity, Identity},
+    runtime,
+    watcher::{Clock, DeleteReason, Event, SystemClock, WatchEvent},
+    HashSet, Instance,
+};
+use futures::{future::BoxFuture, Stream};
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+/// Reports the instances whose registration carries a TTL, see
+/// `Instance::ttl`, as deleted once it lapses, with `DeleteReason::Expired`.
+///
+/// A registration is renewed when its `last_renewed_at` moves, and counts as
+/// renewed when the watcher saw it if the backend doesn't track that. An
+/// instance renewed after it expired is reported created again. Instances
+/// without a TTL pass through.
+pub struct Expiring<W, I = DefaultIdentity>
+where
+    I: Identity,
+{
+    inner: W,
+    identity: I,
+    clock: Arc<dyn Clock>,
+    // the instances with a TTL reported, and when they expire.
+    live: HashMap<I::Key, (Arc<Instance>, SystemTime)>,
+    // the ones reported expired and not deleted since.
+    expired: HashSet<I::Key>,
+    // when the first live instance expires.
+    timer: Option<(SystemTime, BoxFuture<'static, ()>)>,
+}
+
+// the timer is boxed, nothing is pinned but the watcher.
+impl<W, I> Unpin for Expiring<W, I>
+where
+    W: Unpin,
+    I: Identity,
+{
+}
+
+impl<W> Expiring<W> {
+    pub fn new(inner: W) -> Self {
+        Expiring {
+            inner,
+            identity: DefaultIdentity,
+            clock: Arc::new(SystemClock),
+            live: HashMap::new(),
+            expired: HashSet::default(),
+            timer: None,
+        }
+    }
+}
+
+impl<W, I> Expiring<W, I>
+where
+    I: Identity,
+{
+    /// Sets how renewals tell an instance from a new one.
+    pub fn with_identity<NI: Identity>(self, identity: NI) -> Expiring<W, NI> {
+        Expiring {
+            inner: self.inner,
+            identity,
+            clock: self.clock,
+            live: HashMap::new(),
+            expired: HashSet::default(),
+            timer: None,
+        }
+    }
+
+    /// Timestamps the deletes of expired instances with `clock`.
+    pub fn with_clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    // the event to report for `watch_event` of the inner watcher, if any.
+    fn track(&mut self, mut watch_event: WatchEvent) -> Option<WatchEvent> {
+        let now = self.clock.now();
+        match &watch_event.event {
+            Event::Create(ins) | Event::Update(ins) => {
+                let key = self.identity.identify(ins);
+                let expires = ins.ttl().map(|ttl| {
+                    let renewed = ins.last_renewed_at.or(ins.registered_at);
+                    renewed.unwrap_or(now) + ttl
+                });
+                let was_expired = self.expired.contains(&key);
+                match expires {
+                    // lapsed already, e.g. left behind by a process gone.
+                    Some(expires) if expires <= now => {
+                        if !self.live.contains_key(&key) {
+                            self.expired.insert(key);
+                            return None;
+                        }
+                        return self.expire(key);
+                    }
+                    Some(expires) => {
+                        self.live.insert(key.clone(), (ins.clone(), expires));
+                    }
+                    None => {
+                        self.live.remove(&key);
+                    }
+                }
+                if was_expired {
+                    self.expired.remove(&key);
+                    watch_event.event = Event::Create(ins.clone());
+                }
+                Some(watch_event)
+            }
+            Event::Delete(ins) => {
+                let key = self.identity.identify(ins);
+                if self.expired.remove(&key) {
+                    return None;
+                }
+                self.live.remove(&key);
+                Some(watch_event)
+            }
+        }
+    }
+
+    fn expire(&mut self, key: I::Key) -> Option<WatchEvent> {
+        let (ins, _) = self.live.remove(&key)?;
+        self.expired.insert(key);
+        let watch_event = WatchEvent::with_clock(Event::Delete(ins), &*self.clock);
+        Some(watch_event.with_reason(DeleteReason::Expired))
+    }
+
+    // the live instance expiring first, and when.
+    fn first_expiry(&self) -> Option<(I::Key, SystemTime)> {
+        let first = self.live.iter().min_by_key(|(_, (_, expires))| *expires);
+        first.map(|(key, (_, expires))| (key.clone(), *expires))
+    }
+}
+
+impl<W, I> Stream for Expiring<W, I>
+where
+    W: Stream<Item = WatchEvent> + Unpin,
+    I: Identity,
+{
+    type Item = WatchEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            while let Poll::Ready(watch_event) = Pin::new(&mut this.inner).poll_next(cx) {
+                let watch_event = match watch_event {
+                    Some(watch_event) => watch_event,
+                    None => return Poll::Ready(None),
+                };
+                if let Some(watch_event) = this.track(watch_event) {
+                    return Poll::Ready(Some(watch_event));
+                }
+            }
+            let (key, expires) = match this.first_expiry() {
+                Some(first) => first,
+                None => {
+                    this.timer = None;
+                    return Poll::Pending;
+                }
+            };
+            let now = this.clock.now();
+            if expires <= now {
+                return Poll::Ready(this.expire(key));
+            }
+            if this.timer.as_ref().map(|(at, _)| *at) != Some(expires) {
+                let wait = expires.duration_since(now).unwrap_or_default();
+                this.timer = Some((expires, runtime::delay_for(wait)));
+            }
+            let (_, timer) = this.timer.as_mut().unwrap();
+            if timer.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            this.timer = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Expiring;
+    use crate::{
+        watcher::{DeleteReason, Event, WatchEvent},
+        Instance,
+    };
+    use futures::{channel::mpsc, StreamExt};
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    fn instance(addr: &str, ttl: Option<u64>, renewed_ago: Duration) -> Arc<Instance> {
+        let mut ins = Instance {
+            appid: "provider".into(),
+            addrs: vec![addr.to_owned()].into(),
+            last_renewed_at: Some(SystemTime::now() - renewed_ago),
+            ..Default::default()
+        };
+        if let Some(ttl) = ttl {
+            ins.metadata.insert("ttl".to_owned(), ttl.to_string());
+        }
+        Arc::new(ins)
+    }
+
+    #[tokio::test]
+    async fn test_expiring() {
+        let (tx, rx) = mpsc::unbounded();
+        let mut watcher = Expiring::new(rx);
+        let send = |event| tx.unbounded_send(WatchEvent::new(event)).unwrap();
+        let almost = Duration::from_millis(950);
+        let (a, b, stale) = (
+            instance("grpc://10.0.0.1:9000", Some(1), almost),
+            instance("grpc://10.0.0.2:9000", None, almost),
+            instance("grpc://10.0.0.3:9000", Some(1), Duration::from_secs(5)),
+        );
+        send(Event::Create(stale.clone()));
+        send(Event::Create(a.clone()));
+        send(Event::Create(b.clone()));
+        assert_eq!(
+            watcher.next().await.unwrap().event,
+            Event::Create(a.clone())
+        );
+        assert_eq!(watcher.next().await.unwrap().event, Event::Create(b));
+
+        let expired = watcher.next().await.unwrap();
+        assert_eq!(expired.event, Event::Delete(a));
+        assert_eq!(expired.reason, Some(DeleteReason::Expired));
+
+        // renewed, and deleted once expired.
+        let renewed = instance("grpc://10.0.0.1:9000", Some(1), Duration::default());
+        send(Event::Update(renewed.clone()));
+        send(Event::Delete(stale));
+        send(Event::Delete(renewed.clone()));
+        assert_eq!(
+            watcher.next().await.unwrap().event,
+            Event::Create(renewed.clone())
+        );
+        assert_eq!(watcher.next().await.unwrap().event, Event::Delete(renewed));
+    }
+}
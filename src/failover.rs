@@ -0,0 +1,366 @@
+//! A registry backed by another when it fails, e.g. ZooKeeper backed by a
+//! file of the instances last seen.
+use crate::{
+    identity::{DefaultIdentity, Identity},
+    watcher::{Event, WatchEvent},
+    Instance, Registry,
+};
+use futures::{future::BoxFuture, FutureExt, Stream};
+use log::warn;
+use std::{
+    collections::HashMap,
+    error, fmt,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// Calls the primary registry, and the secondary when the primary fails.
+///
+/// Each call that fails on the primary is made again on the secondary.
+/// Watchers switch to the secondary for good when the watcher of the
+/// primary ends, resuming from the instances reported so far. The first
+/// event after has `WatchEvent::failed_over` set.
+///
+/// ```ignore
+/// let registry = FailoverRegistry::new(zk, FileRegistry::new("instances.json"));
+/// let discover = AppDiscover::new(registry.watch("provider"), make_service);
+/// ```
+pub struct FailoverRegistry<P, S, I = DefaultIdentity> {
+    primary: Arc<P>,
+    secondary: Arc<S>,
+    identity: Arc<I>,
+}
+
+impl<P, S> FailoverRegistry<P, S> {
+    pub fn new(primary: P, secondary: S) -> Self {
+        FailoverRegistry {
+            primary: Arc::new(primary),
+            secondary: Arc::new(secondary),
+            identity: Arc::new(DefaultIdentity),
+        }
+    }
+}
+
+impl<P, S, I> FailoverRegistry<P, S, I> {
+    /// Sets how watchers tell instances apart when resuming on the
+    /// secondary.
+    pub fn with_identity<NI>(self, identity: NI) -> FailoverRegistry<P, S, NI> {
+        FailoverRegistry {
+            primary: self.primary,
+            secondary: self.secondary,
+            identity: Arc::new(identity),
+        }
+    }
+
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    pub fn secondary(&self) -> &S {
+        &self.secondary
+    }
+}
+
+impl<P, S, I> Clone for FailoverRegistry<P, S, I> {
+    fn clone(&self) -> Self {
+        FailoverRegistry {
+            primary: self.primary.clone(),
+            secondary: self.secondary.clone(),
+            identity: self.identity.clone(),
+        }
+    }
+}
+
+/// A call failed on both registries.
+#[derive(Debug)]
+pub struct FailoverError<P, S> {
+    pub primary: P,
+    pub secondary: S,
+}
+
+impl<P, S> fmt::Display for FailoverError<P, S>
+where
+    P: fmt::Display,
+    S: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed on the primary: {}, and on the secondary: {}",
+            self.primary, self.secondary
+        )
+    }
+}
+
+impl<P, S> error::Error for FailoverError<P, S>
+where
+    P: fmt::Debug + fmt::Display,
+    S: fmt::Debug + fmt::Display,
+{
+}
+
+type FailoverFuture<P, S, T = ()> =
+    BoxFuture<'static, Result<T, FailoverError<<P as Registry>::Error, <S as Registry>::Error>>>;
+
+// awaits `primary`, and `secondary` if it failed.
+fn or_else<P, S, T, PF, SF>(primary: PF, secondary: SF) -> FailoverFuture<P, S, T>
+where
+    P: Registry,
+    S: Registry,
+    P::Error: fmt::Display + Send + 'static,
+    S::Error: Send + 'static,
+    T: Send + 'static,
+    PF: std::future::Future<Output = Result<T, P::Error>> + Send + 'static,
+    SF: FnOnce() -> BoxFuture<'static, Result<T, S::Error>> + Send + 'static,
+{
+    async move {
+        let primary = match primary.await {
+            Ok(output) => return Ok(output),
+            Err(e) => e,
+        };
+        warn!(
+            "the primary registry failed, trying the secondary. {}",
+            primary
+        );
+        secondary()
+            .await
+            .map_err(|secondary| FailoverError { primary, secondary })
+    }
+    .boxed()
+}
+
+impl<P, S, I> Registry for FailoverRegistry<P, S, I>
+where
+    P: Registry + Send + Sync + 'static,
+    P::Error: fmt::Display + Send + 'static,
+    P::RegFuture: Send + 'static,
+    P::DeRegFuture: Send + 'static,
+    P::ListFuture: Send + 'static,
+    P::Watcher: Unpin,
+    S: Registry + Send + Sync + 'static,
+    S::Error: Send + 'static,
+    S::RegFuture: Send + 'static,
+    S::DeRegFuture: Send + 'static,
+    S::ListFuture: Send + 'static,
+    S::Watcher: Unpin,
+    I: Identity,
+{
+    type Error = FailoverError<P::Error, S::Error>;
+
+    type RegFuture = FailoverFuture<P, S>;
+
+    type DeRegFuture = FailoverFuture<P, S>;
+
+    type ListFuture = FailoverFuture<P, S, Vec<Arc<Instance>>>;
+
+    type Watcher = FailoverWatcher<P::Watcher, S, I>;
+
+    fn register(&self, ins: Arc<Instance>) -> Self::RegFuture {
+        let secondary = self.secondary.clone();
+        or_else::<P, S, _, _, _>(self.primary.register(ins.clone()), move || {
+            secondary.register(ins).boxed()
+        })
+    }
+
+    fn deregister(&self, ins: &Arc<Instance>) -> Self::DeRegFuture {
+        let (secondary, ins) = (self.secondary.clone(), ins.clone());
+        or_else::<P, S, _, _, _>(self.primary.deregister(&ins), move || {
+            secondary.deregister(&ins).boxed()
+        })
+    }
+
+    fn evict(&self, appid: &str, instance_id: &str) -> Self::DeRegFuture {
+        let secondary = self.secondary.clone();
+        let (appid, instance_id) = (appid.to_owned(), instance_id.to_owned());
+        or_else::<P, S, _, _, _>(self.primary.evict(&appid, &instance_id), move || {
+            secondary.evict(&appid, &instance_id).boxed()
+        })
+    }
+
+    fn update_if(&self, ins: Arc<Instance>, expected_version: u64) -> Self::RegFuture {
+        let secondary = self.secondary.clone();
+        let updated = self.primary.update_if(ins.clone(), expected_version);
+        or_else::<P, S, _, _, _>(updated, move || {
+            secondary.update_if(ins, expected_version).boxed()
+        })
+    }
+
+    fn renew(&self, ins: &Arc<Instance>) -> Self::RegFuture {
+        let (secondary, ins) = (self.secondary.clone(), ins.clone());
+        or_else::<P, S, _, _, _>(self.primary.renew(&ins), move || {
+            secondary.renew(&ins).boxed()
+        })
+    }
+
+    fn list(&self, appid: &str) -> Self::ListFuture {
+        let secondary = self.secondary.clone();
+        let appid = appid.to_owned();
+        or_else::<P, S, _, _, _>(self.primary.list(&appid), move || {
+            secondary.list(&appid).boxed()
+        })
+    }
+
+    fn watch(&self, appid: &str) -> Self::Watcher {
+        self.watch_from(appid, &[])
+    }
+
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher {
+        let known = instances
+            .iter()
+            .map(|ins| (self.identity.identify(ins), ins.clone()))
+            .collect();
+        FailoverWatcher {
+            primary: Some(self.primary.watch_from(appid, instances)),
+            secondary: None,
+            registry: self.secondary.clone(),
+            appid: appid.to_owned(),
+            identity: self.identity.clone(),
+            known,
+            failed_over: false,
+        }
+    }
+}
+
+/// The instances of an app in the primary registry, then in the secondary
+/// once the primary's watcher ended, see `FailoverRegistry`.
+pub struct FailoverWatcher<W, S, I>
+where
+    S: Registry,
+    I: Identity,
+{
+    // none once ended.
+    primary: Option<W>,
+    secondary: Option<S::Watcher>,
+    registry: Arc<S>,
+    appid: String,
+    identity: Arc<I>,
+    // the instances reported, to resume from on the secondary.
+    known: HashMap<I::Key, Arc<Instance>>,
+    // set until the first event of the secondary is reported.
+    failed_over: bool,
+}
+
+// the watchers are never pinned.
+impl<W, S, I> Unpin for FailoverWatcher<W, S, I>
+where
+    W: Unpin,
+    S: Registry,
+    S::Watcher: Unpin,
+    I: Identity,
+{
+}
+
+impl<W, S, I> FailoverWatcher<W, S, I>
+where
+    S: Registry,
+    I: Identity,
+{
+    /// Whether the watcher switched to the secondary.
+    pub fn is_failed_over(&self) -> bool {
+        self.primary.is_none()
+    }
+
+    fn track(&mut self, event: &Event) {
+        match event {
+            Event::Create(ins) | Event::Update(ins) => {
+                self.known.insert(self.identity.identify(ins), ins.clone());
+            }
+            Event::Delete(ins) => {
+                self.known.remove(&self.identity.identify(ins));
+            }
+        }
+    }
+}
+
+impl<W, S, I> Stream for FailoverWatcher<W, S, I>
+where
+    W: Stream<Item = WatchEvent> + Unpin,
+    S: Registry,
+    S::Watcher: Unpin,
+    I: Identity,
+{
+    type Item = WatchEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if let Some(primary) = &mut this.primary {
+            match Pin::new(primary).poll_next(cx) {
+                Poll::Ready(Some(watch_event)) => {
+                    this.track(&watch_event.event);
+                    return Poll::Ready(Some(watch_event));
+                }
+                Poll::Ready(None) => {
+                    warn!(
+                        "the primary registry stopped watching {}, failing over",
+                        this.appid
+                    );
+                    let known = this.known.values().cloned().collect::<Vec<_>>();
+                    this.primary = None;
+                    this.secondary = Some(this.registry.watch_from(&this.appid, &known));
+                    this.failed_over = true;
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let secondary = match &mut this.secondary {
+            Some(secondary) => secondary,
+            None => return Poll::Ready(None),
+        };
+        match Pin::new(secondary).poll_next(cx) {
+            Poll::Ready(Some(mut watch_event)) => {
+                this.track(&watch_event.event);
+                watch_event.failed_over = std::mem::take(&mut this.failed_over);
+                Poll::Ready(Some(watch_event))
+            }
+            Poll::Ready(None) => {
+                this.secondary = None;
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FailoverRegistry;
+    use crate::{
+        mem::MemRegistry,
+        testing::{instance, MockRegistry},
+        watcher::Event,
+        Registry,
+    };
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn test_failover() {
+        let (primary, secondary) = (MockRegistry::new(), MemRegistry::new());
+        let registry = FailoverRegistry::new(primary.clone(), secondary.clone());
+        let (a, b) = (
+            instance("grpc://10.0.0.1:9000"),
+            instance("grpc://10.0.0.2:9000"),
+        );
+        registry.register(a.clone()).await.unwrap();
+        primary.assert_registered(&a);
+        primary.fail_register("zk is down");
+        registry.register(b.clone()).await.unwrap();
+        assert_eq!(secondary.instances("provider"), vec![b.clone()]);
+
+        let mut watcher = registry.watch("provider");
+        let first = watcher.next().await.unwrap();
+        assert_eq!(first.event, Event::Create(a.clone()));
+        assert!(!first.failed_over);
+
+        // the watcher of the primary ends with it.
+        drop((registry, primary));
+        let resumed = watcher.next().await.unwrap();
+        assert_eq!(resumed.event, Event::Create(a.clone()));
+        assert!(resumed.failed_over && watcher.is_failed_over());
+        let events = vec![
+            watcher.next().await.unwrap().event,
+            watcher.next().await.unwrap().event,
+        ];
+        assert_eq!(events, [Event::Create(b), Event::Delete(a)]);
+    }
+}
@@ -0,0 +1,407 @@
+//! Instances kept in a JSON file, reloaded on edits, for local development
+//! and the places without a registry.
+//!
+//! The file maps appids to their instances, each either an address or an
+//! object with the fields of an `Instance`, all optional but `addrs`:
+//!
+//! ```json
+//! {
+//!     "billing": [
+//!         "grpc://10.0.0.1:9000",
+//!         {"addrs": ["grpc://10.0.0.2:9000"], "zone": "sh1", "metadata": {"weight": "10"}}
+//!     ]
+//! }
+//! ```
+//!
+//! Snapshots taken with `snapshot::export` are read as well.
+use crate::{
+    identity::{DefaultIdentity, Identity},
+    intern,
+    runtime::{self, AbortOnDrop},
+    snapshot::{DiscoverySnapshot, SnapshotError},
+    watcher::{Clock, Event, SystemClock, WatchEvent},
+    Instance, Registry,
+};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future::{self, BoxFuture},
+    FutureExt, Stream,
+};
+use log::error;
+use pin_project::pin_project;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    error, fmt, fs, io,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// Watches the instances of a file, checking it for edits every second by
+/// default.
+///
+/// ```ignore
+/// let file = FileRegistry::new("instances.json");
+/// let discover = AppDiscover::new(file.watch("billing"), make_service);
+/// ```
+///
+/// The file is edited by hand, registering fails with
+/// `FileError::Unsupported`.
+pub struct FileRegistry<I = DefaultIdentity> {
+    path: Arc<PathBuf>,
+    interval: Duration,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+}
+
+impl FileRegistry {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileRegistry {
+            path: Arc::new(path.into()),
+            interval: Duration::from_secs(1),
+            identity: Arc::new(DefaultIdentity),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl<I> FileRegistry<I> {
+    /// How often watchers check the file for edits.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets how watchers tell instances apart.
+    pub fn with_identity<NI>(self, identity: NI) -> FileRegistry<NI> {
+        FileRegistry {
+            path: self.path,
+            interval: self.interval,
+            identity: Arc::new(identity),
+            clock: self.clock,
+        }
+    }
+
+    /// Sets the clock timestamping the events of watchers.
+    pub fn with_clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+}
+
+#[derive(Debug)]
+pub enum FileError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    /// The file doesn't map appids to instances.
+    Format(String),
+    /// The file is edited by hand.
+    Unsupported,
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileError::Io(e) => write!(f, "failed to read the file: {}", e),
+            FileError::Json(e) => write!(f, "bad json in the file: {}", e),
+            FileError::Format(e) => write!(f, "bad instances file: {}", e),
+            FileError::Unsupported => write!(f, "unsupported by files"),
+        }
+    }
+}
+
+impl error::Error for FileError {}
+
+impl From<io::Error> for FileError {
+    fn from(e: io::Error) -> Self {
+        FileError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for FileError {
+    fn from(e: serde_json::Error) -> Self {
+        FileError::Json(e)
+    }
+}
+
+impl From<SnapshotError> for FileError {
+    fn from(e: SnapshotError) -> Self {
+        match e {
+            SnapshotError::Json(e) => FileError::Json(e),
+            SnapshotError::Format(e) => FileError::Format(e),
+        }
+    }
+}
+
+async fn read(path: Arc<PathBuf>) -> Result<String, FileError> {
+    let content = runtime::spawn_blocking(move || fs::read_to_string(&*path))
+        .await
+        .unwrap_or_else(|| Err(io::Error::other("panicked")))?;
+    Ok(content)
+}
+
+// the instances of `json` by appid.
+fn parse(json: &str) -> Result<HashMap<String, Vec<Instance>>, FileError> {
+    let value = serde_json::from_str::<Value>(json)?;
+    if value["version"].is_u64() && value["apps"].is_object() {
+        let snapshot = DiscoverySnapshot::from_json(json)?;
+        let apps = snapshot.apps.into_iter().map(|(appid, instances)| {
+            let instances = instances.iter().map(|ins| Instance::clone(ins)).collect();
+            (appid, instances)
+        });
+        return Ok(apps.collect());
+    }
+    let bad = |what: String| FileError::Format(what);
+    let apps = value
+        .as_object()
+        .ok_or_else(|| bad("not an object".to_owned()))?;
+    let mut instances = HashMap::new();
+    for (appid, values) in apps {
+        let values = values
+            .as_array()
+            .ok_or_else(|| bad(format!("the instances of {} are not an array", appid)))?;
+        let app = values
+            .iter()
+            .map(|value| {
+                instance(appid, value).ok_or_else(|| bad(format!("bad instance of {}", appid)))
+            })
+            .collect::<Result<_, _>>()?;
+        instances.insert(appid.clone(), app);
+    }
+    Ok(instances)
+}
+
+fn instance(appid: &str, value: &Value) -> Option<Instance> {
+    if let Some(addr) = value.as_str() {
+        return Some(Instance {
+            appid: intern(appid),
+            addrs: std::iter::once(addr.to_owned()).collect(),
+            ..Default::default()
+        });
+    }
+    let string = |name: &str| match &value[name] {
+        Value::Null => Some(String::new()),
+        value => value.as_str().map(str::to_owned),
+    };
+    Some(Instance {
+        zone: intern(&string("zone")?),
+        env: intern(&string("env")?),
+        appid: intern(appid),
+        hostname: string("hostname")?,
+        addrs: serde_json::from_value::<Vec<String>>(value["addrs"].clone())
+            .ok()?
+            .into(),
+        version: string("version")?,
+        group: intern(&string("group")?),
+        metadata: match &value["metadata"] {
+            Value::Null => HashMap::new(),
+            metadata => serde_json::from_value(metadata.clone()).ok()?,
+        },
+        ..Default::default()
+    })
+}
+
+impl<I> Registry for FileRegistry<I>
+where
+    I: Identity + Send + Sync + 'static,
+    I::Key: Send + Sync,
+{
+    type Error = FileError;
+
+    type RegFuture = BoxFuture<'static, Result<(), FileError>>;
+
+    type DeRegFuture = BoxFuture<'static, Result<(), FileError>>;
+
+    type ListFuture = BoxFuture<'static, Result<Vec<Arc<Instance>>, FileError>>;
+
+    type Watcher = FileWatcher;
+
+    fn register(&self, _ins: Arc<Instance>) -> Self::RegFuture {
+        future::ready(Err(FileError::Unsupported)).boxed()
+    }
+
+    fn deregister(&self, _ins: &Arc<Instance>) -> Self::DeRegFuture {
+        future::ready(Err(FileError::Unsupported)).boxed()
+    }
+
+    fn evict(&self, _appid: &str, _instance_id: &str) -> Self::DeRegFuture {
+        future::ready(Err(FileError::Unsupported)).boxed()
+    }
+
+    fn update_if(&self, _ins: Arc<Instance>, _expected_version: u64) -> Self::RegFuture {
+        future::ready(Err(FileError::Unsupported)).boxed()
+    }
+
+    fn renew(&self, _ins: &Arc<Instance>) -> Self::RegFuture {
+        future::ready(Err(FileError::Unsupported)).boxed()
+    }
+
+    fn list(&self, appid: &str) -> Self::ListFuture {
+        let (path, appid) = (self.path.clone(), appid.to_owned());
+        async move {
+            let mut apps = parse(&read(path).await?)?;
+            let instances = apps.remove(&appid).unwrap_or_default();
+            Ok(instances.into_iter().map(Arc::new).collect())
+        }
+        .boxed()
+    }
+
+    fn watch(&self, appid: &str) -> Self::Watcher {
+        self.watch_from(appid, &[])
+    }
+
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher {
+        let (tx, rx) = mpsc::unbounded();
+        let mut known = HashMap::new();
+        for ins in instances {
+            known.insert(self.identity.identify(ins), ins.clone());
+            let event = WatchEvent::with_clock(Event::Create(ins.clone()), &*self.clock);
+            let _ = tx.unbounded_send(event);
+        }
+        let watch = FileWatch {
+            path: self.path.clone(),
+            appid: appid.to_owned(),
+            interval: self.interval,
+            content: None,
+            identity: self.identity.clone(),
+            clock: self.clock.clone(),
+            known,
+            tx,
+        };
+        FileWatcher {
+            rx,
+            _task: runtime::spawn_abortable(watch.run()),
+        }
+    }
+}
+
+/// The instances of an app in a file, see `Registry::watch`.
+#[pin_project]
+pub struct FileWatcher {
+    #[pin]
+    rx: UnboundedReceiver<WatchEvent>,
+    _task: AbortOnDrop,
+}
+
+impl Stream for FileWatcher {
+    type Item = WatchEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().rx.poll_next(cx)
+    }
+}
+
+struct FileWatch<I: Identity> {
+    path: Arc<PathBuf>,
+    appid: String,
+    interval: Duration,
+    // the content last read.
+    content: Option<String>,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+    // the instances reported.
+    known: HashMap<I::Key, Arc<Instance>>,
+    tx: UnboundedSender<WatchEvent>,
+}
+
+impl<I> FileWatch<I>
+where
+    I: Identity,
+{
+    async fn run(mut self) {
+        loop {
+            // the instances stay as they were while the file is broken, e.g.
+            // half written.
+            if let Err(e) = self.reload().await {
+                error!("failed to reload {}. {}", self.path.display(), e);
+            }
+            runtime::delay_for(self.interval).await;
+        }
+    }
+
+    async fn reload(&mut self) -> Result<(), FileError> {
+        let content = read(self.path.clone()).await?;
+        if self.content.as_ref() == Some(&content) {
+            return Ok(());
+        }
+        let mut apps = parse(&content)?;
+        self.content = Some(content);
+        self.update(apps.remove(&self.appid).unwrap_or_default());
+        Ok(())
+    }
+
+    // reports the instances new or changed as Creates, and the ones gone as
+    // Deletes.
+    fn update(&mut self, instances: Vec<Instance>) {
+        let mut gone = std::mem::take(&mut self.known);
+        for ins in instances {
+            let ins = Arc::new(ins);
+            let key = self.identity.identify(&ins);
+            let unchanged = gone.remove(&key).filter(|old| *old == ins);
+            if unchanged.is_none() {
+                self.send(Event::Create(ins.clone()));
+            }
+            self.known.insert(key, ins);
+        }
+        for (_, ins) in gone {
+            self.send(Event::Delete(ins));
+        }
+    }
+
+    fn send(&self, event: Event) {
+        let _ = self
+            .tx
+            .unbounded_send(WatchEvent::with_clock(event, &*self.clock));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileRegistry;
+    use crate::{watcher::Event, Registry};
+    use futures::StreamExt;
+    use std::{fs, time::Duration};
+
+    #[tokio::test]
+    async fn test_reload() {
+        let path = std::env::temp_dir().join(format!("discover-{}.json", rand::random::<u64>()));
+        fs::write(&path, r#"{"billing": ["grpc://10.0.0.1:9000"]}"#).unwrap();
+        let file = FileRegistry::new(&path).interval(Duration::from_millis(10));
+        let mut watcher = file.watch("billing");
+        let addr = |event: Event| match event {
+            Event::Create(ins) => ("create", ins.addrs[0].clone()),
+            Event::Update(ins) => ("update", ins.addrs[0].clone()),
+            Event::Delete(ins) => ("delete", ins.addrs[0].clone()),
+        };
+        let event = watcher.next().await.unwrap().event;
+        assert_eq!(addr(event), ("create", "grpc://10.0.0.1:9000".to_owned()));
+
+        // broken files are skipped.
+        fs::write(&path, r#"{"billing": ["#).unwrap();
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        fs::write(
+            &path,
+            r#"{"billing": [{"addrs": ["grpc://10.0.0.2:9000"], "zone": "sh1"}]}"#,
+        )
+        .unwrap();
+        let mut events = vec![
+            addr(watcher.next().await.unwrap().event),
+            addr(watcher.next().await.unwrap().event),
+        ];
+        events.sort();
+        assert_eq!(
+            events,
+            [
+                ("create", "grpc://10.0.0.2:9000".to_owned()),
+                ("delete", "grpc://10.0.0.1:9000".to_owned()),
+            ]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
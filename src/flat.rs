@@ -0,0 +1,112 @@
+// Instances as the address and string metadata of the services of the
+// registries with a service model of their own, e.g. Consul, rather than
+// encoded with a `Codec`.
+use crate::{intern::intern, Instance};
+use std::collections::HashMap;
+
+// the metadata keeping the fields of instances, the address aside.
+pub(crate) const FIELDS: [&str; 6] = ["env", "zone", "version", "group", "hostname", "addrs"];
+
+// the host and port of an address such as `grpc://10.0.0.1:9000`.
+pub(crate) fn host_port(addr: &str) -> Option<(&str, u16)> {
+    let addr = addr.splitn(2, "://").last()?;
+    let addr = addr.split('/').next()?;
+    let (host, port) = addr.rsplit_once(':')?;
+    Some((
+        host.trim_start_matches('[').trim_end_matches(']'),
+        port.parse().ok()?,
+    ))
+}
+
+// the host and port of the first address of `ins`, to register it at.
+pub(crate) fn address(ins: &Instance) -> (&str, u16) {
+    ins.addrs
+        .first()
+        .and_then(|addr| host_port(addr))
+        .unwrap_or(("", 0))
+}
+
+// the metadata of `ins` along with its fields, which win over metadata of the
+// same keys.
+pub(crate) fn to_metadata(ins: &Instance) -> HashMap<String, String> {
+    let mut metadata = ins.metadata.clone();
+    let fields = [
+        &*ins.env,
+        &*ins.zone,
+        &*ins.version,
+        &*ins.group,
+        &*ins.hostname,
+        &*ins.addrs.join(","),
+    ];
+    for (key, value) in FIELDS.iter().zip(fields.iter()) {
+        metadata.insert((*key).to_owned(), (*value).to_owned());
+    }
+    metadata
+}
+
+// the instance `metadata` keeps, at `default_addr` unless it keeps the
+// addresses, e.g. when registered by other means.
+pub(crate) fn from_metadata(
+    appid: &str,
+    default_addr: String,
+    mut metadata: HashMap<String, String>,
+) -> Instance {
+    let mut field = |key: &str| metadata.remove(key).unwrap_or_default();
+    let (env, zone, version, group, hostname, addrs) = (
+        field("env"),
+        field("zone"),
+        field("version"),
+        field("group"),
+        field("hostname"),
+        field("addrs"),
+    );
+    let addrs = match addrs.as_str() {
+        "" => std::iter::once(default_addr).collect(),
+        addrs => addrs.split(',').map(str::to_owned).collect(),
+    };
+    Instance {
+        zone: intern(&zone),
+        env: intern(&env),
+        appid: intern(appid),
+        hostname,
+        addrs,
+        version,
+        group: intern(&group),
+        metadata,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_metadata, host_port, to_metadata};
+    use crate::{intern::intern, Instance};
+
+    #[test]
+    fn test_flat() {
+        assert_eq!(host_port("grpc://10.0.0.1:9000"), Some(("10.0.0.1", 9000)));
+        assert_eq!(host_port("http://[::1]:80/health"), Some(("::1", 80)));
+        assert_eq!(host_port("10.0.0.1"), None);
+
+        let ins = Instance {
+            env: intern("prod"),
+            appid: intern("billing"),
+            addrs: vec!["grpc://10.0.0.1:9000".to_owned()].into(),
+            version: "1.2.0".to_owned(),
+            metadata: vec![("weight".to_owned(), "10".to_owned())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let metadata = to_metadata(&ins);
+        assert_eq!(metadata["version"], "1.2.0");
+        assert_eq!(from_metadata("billing", String::new(), metadata), ins);
+
+        let native = from_metadata(
+            "billing",
+            "http://10.0.0.2:80".to_owned(),
+            Default::default(),
+        );
+        assert_eq!(&native.addrs[..], ["http://10.0.0.2:80"]);
+    }
+}
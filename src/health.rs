@@ -0,0 +1,399 @@
+//! Reports instances only while they pass the grpc health checking protocol.
+use crate::{
+    identity::{DefaultIdentity, Identity},
+    runtime::{self, AbortOnDrop},
+    watcher::{Event, WatchEvent},
+    Instance,
+};
+use futures::{
+    channel::mpsc,
+    future,
+    stream::{self, BoxStream},
+    Stream, StreamExt,
+};
+use pin_project::pin_project;
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tonic::{
+    codec::ProstCodec, codegen::http::uri::PathAndQuery, transport::Endpoint, Code, Request,
+    Status, Streaming,
+};
+
+const WATCH_PATH: &str = "/grpc.health.v1.Health/Watch";
+
+type Key = <DefaultIdentity as Identity>::Key;
+
+type ProbeFn = dyn Fn(&Instance) -> Option<BoxStream<'static, bool>> + Send + Sync;
+
+/// Filters watch events down to the instances actually serving, as told by
+/// `grpc.health.v1.Health/Watch`.
+///
+/// An instance is reported with a Create once its health service says
+/// SERVING, and with a Delete once it says otherwise, can't be reached or
+/// goes away from the registry. Instances without an address of the probed
+/// scheme, `grpc` by default, and servers not implementing the health service
+/// are taken as serving. Needs a tokio runtime.
+///
+/// ```ignore
+/// let watcher = HealthCheck::new().service("helloworld.Greeter").filter(zk.watch("/dubbo-rs/provider"));
+/// ```
+#[derive(Clone)]
+pub struct HealthCheck {
+    service: String,
+    scheme: String,
+    retry: Duration,
+    probe: Option<Arc<ProbeFn>>,
+}
+
+impl HealthCheck {
+    pub fn new() -> Self {
+        HealthCheck {
+            service: String::new(),
+            scheme: "grpc".to_owned(),
+            retry: Duration::from_secs(5),
+            probe: None,
+        }
+    }
+
+    /// The service checked, the whole server by default.
+    pub fn service(mut self, service: impl Into<String>) -> Self {
+        self.service = service.into();
+        self
+    }
+
+    /// The scheme of the address probed.
+    pub fn scheme(mut self, scheme: impl Into<String>) -> Self {
+        self.scheme = scheme.into();
+        self
+    }
+
+    /// How long to wait before probing again an instance that couldn't be
+    /// reached.
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Replaces the grpc probe: `probe` returns whether the instance is
+    /// serving each time it changes, or `None` to take it as serving.
+    pub fn probe<F>(mut self, probe: F) -> Self
+    where
+        F: Fn(&Instance) -> Option<BoxStream<'static, bool>> + Send + Sync + 'static,
+    {
+        self.probe = Some(Arc::new(probe));
+        self
+    }
+
+    pub fn filter<W>(self, watcher: W) -> HealthChecked<W>
+    where
+        W: Stream<Item = WatchEvent>,
+    {
+        let (updates_tx, updates) = mpsc::unbounded();
+        HealthChecked {
+            watcher,
+            check: self,
+            probes: HashMap::new(),
+            updates_tx,
+            updates,
+            next_probe: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn start(&self, ins: &Instance) -> Option<BoxStream<'static, bool>> {
+        if let Some(probe) = &self.probe {
+            return probe(ins);
+        }
+        let uri = format!("http://{}", ins.addr_for_scheme(&self.scheme)?);
+        Some(grpc_probe(uri, self.service.clone(), self.retry))
+    }
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The watcher of `HealthCheck::filter`.
+#[pin_project]
+pub struct HealthChecked<W> {
+    #[pin]
+    watcher: W,
+    check: HealthCheck,
+    probes: HashMap<Key, Probe>,
+    updates_tx: mpsc::UnboundedSender<(Key, u64, bool)>,
+    updates: mpsc::UnboundedReceiver<(Key, u64, bool)>,
+    next_probe: u64,
+    pending: VecDeque<WatchEvent>,
+}
+
+struct Probe {
+    ins: Arc<Instance>,
+    id: u64,
+    serving: bool,
+    _forward: Option<AbortOnDrop>,
+}
+
+impl<W> HealthChecked<W> {
+    fn on_event(self: Pin<&mut Self>, watch_event: WatchEvent) {
+        let this = self.project();
+        let update = matches!(watch_event.event, Event::Update(_));
+        match watch_event.event {
+            Event::Create(ins) | Event::Update(ins) => {
+                let key = DefaultIdentity.identify(&ins);
+                if let Some(probe) = this.probes.get_mut(&key) {
+                    // a new version of an instance being probed.
+                    probe.ins = ins.clone();
+                    if probe.serving {
+                        let event = if update {
+                            Event::Update(ins)
+                        } else {
+                            Event::Create(ins)
+                        };
+                        this.pending.push_back(WatchEvent {
+                            event,
+                            ..watch_event
+                        });
+                    }
+                    return;
+                }
+                *this.next_probe += 1;
+                let mut probe = Probe {
+                    ins: ins.clone(),
+                    id: *this.next_probe,
+                    serving: false,
+                    _forward: None,
+                };
+                match this.check.start(&ins) {
+                    Some(statuses) => {
+                        let (key, id) = (key.clone(), probe.id);
+                        let updates_tx = this.updates_tx.clone();
+                        let forward = statuses
+                            .map(move |serving| Ok((key.clone(), id, serving)))
+                            .forward(updates_tx);
+                        probe._forward = Some(runtime::spawn_abortable(forward));
+                    }
+                    None => {
+                        probe.serving = true;
+                        this.pending.push_back(WatchEvent {
+                            event: Event::Create(ins),
+                            ..watch_event
+                        });
+                    }
+                }
+                this.probes.insert(key, probe);
+            }
+            Event::Delete(ins) => {
+                let key = DefaultIdentity.identify(&ins);
+                if let Some(probe) = this.probes.remove(&key) {
+                    if probe.serving {
+                        this.pending.push_back(WatchEvent {
+                            event: Event::Delete(ins),
+                            ..watch_event
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    fn on_update(self: Pin<&mut Self>, key: Key, id: u64, serving: bool) {
+        let this = self.project();
+        let probe = match this.probes.get_mut(&key) {
+            Some(probe) if probe.id == id && probe.serving != serving => probe,
+            _ => return,
+        };
+        probe.serving = serving;
+        let event = if serving {
+            Event::Create(probe.ins.clone())
+        } else {
+            Event::Delete(probe.ins.clone())
+        };
+        this.pending.push_back(WatchEvent::new(event));
+    }
+}
+
+impl<W> Stream for HealthChecked<W>
+where
+    W: Stream<Item = WatchEvent>,
+{
+    type Item = WatchEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(watch_event) = self.as_mut().project().pending.pop_front() {
+                return Poll::Ready(Some(watch_event));
+            }
+            if let Poll::Ready(Some((key, id, serving))) =
+                self.as_mut().project().updates.poll_next_unpin(cx)
+            {
+                self.as_mut().on_update(key, id, serving);
+                continue;
+            }
+            match self.as_mut().project().watcher.poll_next(cx) {
+                Poll::Ready(Some(watch_event)) => self.as_mut().on_event(watch_event),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+enum ProbeState {
+    Connect { wait: bool },
+    Watch(Streaming<HealthCheckResponse>),
+    // the server has no health service.
+    Unimplemented,
+}
+
+fn grpc_probe(uri: String, service: String, retry: Duration) -> BoxStream<'static, bool> {
+    stream::unfold(ProbeState::Connect { wait: false }, move |mut state| {
+        let (uri, service) = (uri.clone(), service.clone());
+        async move {
+            loop {
+                state = match state {
+                    ProbeState::Connect { wait } => {
+                        if wait {
+                            runtime::delay_for(retry).await;
+                        }
+                        match watch(uri.clone(), service.clone()).await {
+                            Ok(statuses) => ProbeState::Watch(statuses),
+                            Err(status) if status.code() == Code::Unimplemented => {
+                                return Some((true, ProbeState::Unimplemented))
+                            }
+                            Err(_) => return Some((false, ProbeState::Connect { wait: true })),
+                        }
+                    }
+                    ProbeState::Watch(mut statuses) => match statuses.message().await {
+                        Ok(Some(rsp)) => {
+                            let serving = rsp.status == ServingStatus::Serving as i32;
+                            return Some((serving, ProbeState::Watch(statuses)));
+                        }
+                        _ => return Some((false, ProbeState::Connect { wait: true })),
+                    },
+                    ProbeState::Unimplemented => future::pending().await,
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+async fn watch(uri: String, service: String) -> Result<Streaming<HealthCheckResponse>, Status> {
+    let unavailable = |e: tonic::transport::Error| Status::unavailable(e.to_string());
+    let channel = Endpoint::from_shared(uri)
+        .map_err(|e| Status::invalid_argument(e.to_string()))?
+        .connect()
+        .await
+        .map_err(unavailable)?;
+    let mut grpc = tonic::client::Grpc::new(channel);
+    grpc.ready().await.map_err(unavailable)?;
+    let rsp = grpc
+        .server_streaming(
+            Request::new(HealthCheckRequest { service }),
+            PathAndQuery::from_static(WATCH_PATH),
+            ProstCodec::default(),
+        )
+        .await?;
+    Ok(rsp.into_inner())
+}
+
+// the messages of grpc/health/v1/health.proto.
+#[derive(Clone, PartialEq, prost::Message)]
+struct HealthCheckRequest {
+    #[prost(string, tag = "1")]
+    service: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct HealthCheckResponse {
+    #[prost(enumeration = "ServingStatus", tag = "1")]
+    status: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+enum ServingStatus {
+    Unknown = 0,
+    Serving = 1,
+    NotServing = 2,
+    ServiceUnknown = 3,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HealthCheck;
+    use crate::{
+        testing::{collect_until_quiescent, instance, MockRegistry},
+        watcher::Event,
+        Registry,
+    };
+    use futures::{channel::mpsc, StreamExt};
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[tokio::test]
+    async fn test_health_check() {
+        let registry = MockRegistry::new();
+        let probes = Arc::new(Mutex::new(HashMap::new()));
+        let check = {
+            let probes = probes.clone();
+            HealthCheck::new().probe(move |ins| {
+                if !ins.addrs[0].starts_with("grpc://") {
+                    return None;
+                }
+                let (tx, rx) = mpsc::unbounded();
+                probes.lock().unwrap().insert(ins.addrs[0].clone(), tx);
+                Some(rx.boxed())
+            })
+        };
+        registry.insert(instance("grpc://172.1.1.1:9999"));
+        registry.insert(instance("http://172.1.1.2:8000"));
+        let mut watcher = check.filter(registry.watch("provider"));
+        let quiet = Duration::from_millis(20);
+        let set = |addr: &str, serving: bool| {
+            let probes = probes.lock().unwrap();
+            probes[addr].unbounded_send(serving).unwrap();
+        };
+
+        // not probed, serving right away.
+        assert_eq!(
+            collect_until_quiescent(&mut watcher, quiet).await,
+            vec![Event::Create(instance("http://172.1.1.2:8000"))]
+        );
+
+        set("grpc://172.1.1.1:9999", false);
+        set("grpc://172.1.1.1:9999", true);
+        set("grpc://172.1.1.1:9999", true);
+        set("grpc://172.1.1.1:9999", false);
+        assert_eq!(
+            collect_until_quiescent(&mut watcher, quiet).await,
+            vec![
+                Event::Create(instance("grpc://172.1.1.1:9999")),
+                Event::Delete(instance("grpc://172.1.1.1:9999")),
+            ]
+        );
+
+        set("grpc://172.1.1.1:9999", true);
+        assert_eq!(
+            collect_until_quiescent(&mut watcher, quiet).await,
+            vec![Event::Create(instance("grpc://172.1.1.1:9999"))]
+        );
+        registry.remove(&instance("grpc://172.1.1.1:9999"));
+        assert_eq!(
+            collect_until_quiescent(&mut watcher, quiet).await,
+            vec![Event::Delete(instance("grpc://172.1.1.1:9999"))]
+        );
+        // the probe is gone with the instance.
+        assert!(probes.lock().unwrap()["grpc://172.1.1.1:9999"].is_closed());
+    }
+}
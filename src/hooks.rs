@@ -0,0 +1,399 @@
+//! Callbacks run on registrations, watch events and connection changes, to
+//! warm caches or notify someone without wrapping every call site.
+//!
+//! ```ignore
+//! let hooks = Hooks::new();
+//! hooks.on_watch_event(|appid, watch_event| firewall.apply(appid, &watch_event.event));
+//! hooks.install(SlackNotifier::new(webhook));
+//! let zk = Zk::builder(urls).hooks(hooks.clone()).build().await?;
+//! let registry = Hooked::new(zk, hooks);
+//! ```
+//!
+//! Hooks run on the task or thread that made the call or received the event,
+//! spawn anything slow. A panicking hook is logged and doesn't keep the
+//! others from running.
+use crate::{watcher::WatchEvent, Instance, Registry};
+use futures::{ready, Future, Stream};
+use log::error;
+use pin_project::pin_project;
+use std::{
+    any::Any,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+};
+
+/// A plugin, implementing the callbacks it cares about.
+pub trait Hook: Send + Sync {
+    /// `ins` was registered.
+    fn on_register(&self, _ins: &Instance) {}
+
+    /// `ins` was deregistered.
+    fn on_deregister(&self, _ins: &Instance) {}
+
+    fn on_watch_event(&self, _appid: &str, _watch_event: &WatchEvent) {}
+
+    fn on_connection_change(&self, _state: ConnectionState) {}
+}
+
+/// The state of the connection of a registry to its backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    /// Lost, the backend may still be reconnected to.
+    Disconnected,
+    /// The session expired, what it created is gone.
+    Expired,
+}
+
+/// The hooks installed. Clones share them, hooks installed on one run for
+/// all.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    hooks: Arc<RwLock<Vec<Arc<dyn Hook>>>>,
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let len = self.hooks.read().unwrap().len();
+        f.debug_struct("Hooks").field("len", &len).finish()
+    }
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn install<H>(&self, hook: H)
+    where
+        H: Hook + 'static,
+    {
+        self.hooks.write().unwrap().push(Arc::new(hook));
+    }
+
+    pub fn on_register<F>(&self, f: F)
+    where
+        F: Fn(&Instance) + Send + Sync + 'static,
+    {
+        self.install(OnRegister(f));
+    }
+
+    pub fn on_deregister<F>(&self, f: F)
+    where
+        F: Fn(&Instance) + Send + Sync + 'static,
+    {
+        self.install(OnDeregister(f));
+    }
+
+    pub fn on_watch_event<F>(&self, f: F)
+    where
+        F: Fn(&str, &WatchEvent) + Send + Sync + 'static,
+    {
+        self.install(OnWatchEvent(f));
+    }
+
+    pub fn on_connection_change<F>(&self, f: F)
+    where
+        F: Fn(ConnectionState) + Send + Sync + 'static,
+    {
+        self.install(OnConnectionChange(f));
+    }
+
+    pub(crate) fn registered(&self, ins: &Instance) {
+        self.run(|hook| hook.on_register(ins));
+    }
+
+    pub(crate) fn deregistered(&self, ins: &Instance) {
+        self.run(|hook| hook.on_deregister(ins));
+    }
+
+    pub(crate) fn watch_event(&self, appid: &str, watch_event: &WatchEvent) {
+        self.run(|hook| hook.on_watch_event(appid, watch_event));
+    }
+
+    // only zk reports its connection so far.
+    #[cfg(any(test, feature = "zk"))]
+    pub(crate) fn connection_changed(&self, state: ConnectionState) {
+        self.run(|hook| hook.on_connection_change(state));
+    }
+
+    fn run(&self, f: impl Fn(&dyn Hook)) {
+        // hooks may install hooks.
+        let hooks = self.hooks.read().unwrap().clone();
+        for hook in hooks {
+            if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| f(&*hook))) {
+                error!("hook panicked. {}", panic_message(&*panic));
+            }
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic
+            .downcast_ref::<String>()
+            .map(String::as_str)
+            .unwrap_or("unknown panic"),
+    }
+}
+
+struct OnRegister<F>(F);
+
+impl<F> Hook for OnRegister<F>
+where
+    F: Fn(&Instance) + Send + Sync,
+{
+    fn on_register(&self, ins: &Instance) {
+        (self.0)(ins)
+    }
+}
+
+struct OnDeregister<F>(F);
+
+impl<F> Hook for OnDeregister<F>
+where
+    F: Fn(&Instance) + Send + Sync,
+{
+    fn on_deregister(&self, ins: &Instance) {
+        (self.0)(ins)
+    }
+}
+
+struct OnWatchEvent<F>(F);
+
+impl<F> Hook for OnWatchEvent<F>
+where
+    F: Fn(&str, &WatchEvent) + Send + Sync,
+{
+    fn on_watch_event(&self, appid: &str, watch_event: &WatchEvent) {
+        (self.0)(appid, watch_event)
+    }
+}
+
+struct OnConnectionChange<F>(F);
+
+impl<F> Hook for OnConnectionChange<F>
+where
+    F: Fn(ConnectionState) + Send + Sync,
+{
+    fn on_connection_change(&self, state: ConnectionState) {
+        (self.0)(state)
+    }
+}
+
+/// Wraps a registry to run `hooks` on its successful registrations, updates
+/// included, and deregistrations, evictions aside, and on the events of its
+/// watchers.
+#[derive(Clone)]
+pub struct Hooked<R> {
+    inner: R,
+    hooks: Hooks,
+}
+
+impl<R> Hooked<R> {
+    pub fn new(inner: R, hooks: Hooks) -> Self {
+        Hooked { inner, hooks }
+    }
+
+    /// The wrapped registry.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn hooks(&self) -> &Hooks {
+        &self.hooks
+    }
+
+    fn on_ok<F>(
+        &self,
+        inner: F,
+        hook: fn(&Hooks, &Instance),
+        ins: Arc<Instance>,
+    ) -> HookedFuture<F> {
+        HookedFuture {
+            inner,
+            on_ok: Some((self.hooks.clone(), hook, ins)),
+        }
+    }
+}
+
+impl<R> Registry for Hooked<R>
+where
+    R: Registry,
+{
+    type Error = R::Error;
+
+    type RegFuture = HookedFuture<R::RegFuture>;
+
+    type DeRegFuture = HookedFuture<R::DeRegFuture>;
+
+    type ListFuture = R::ListFuture;
+
+    type Watcher = HookedWatcher<R::Watcher>;
+
+    fn register(&self, ins: Arc<Instance>) -> Self::RegFuture {
+        self.on_ok(self.inner.register(ins.clone()), Hooks::registered, ins)
+    }
+
+    fn deregister(&self, ins: &Arc<Instance>) -> Self::DeRegFuture {
+        let inner = self.inner.deregister(ins);
+        self.on_ok(inner, Hooks::deregistered, ins.clone())
+    }
+
+    fn evict(&self, appid: &str, instance_id: &str) -> Self::DeRegFuture {
+        HookedFuture {
+            inner: self.inner.evict(appid, instance_id),
+            on_ok: None,
+        }
+    }
+
+    fn update_if(&self, ins: Arc<Instance>, expected_version: u64) -> Self::RegFuture {
+        let inner = self.inner.update_if(ins.clone(), expected_version);
+        self.on_ok(inner, Hooks::registered, ins)
+    }
+
+    fn renew(&self, ins: &Arc<Instance>) -> Self::RegFuture {
+        HookedFuture {
+            inner: self.inner.renew(ins),
+            on_ok: None,
+        }
+    }
+
+    fn list(&self, appid: &str) -> Self::ListFuture {
+        self.inner.list(appid)
+    }
+
+    fn watch(&self, appid: &str) -> Self::Watcher {
+        HookedWatcher {
+            inner: self.inner.watch(appid),
+            appid: appid.to_owned(),
+            hooks: self.hooks.clone(),
+        }
+    }
+
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher {
+        HookedWatcher {
+            inner: self.inner.watch_from(appid, instances),
+            appid: appid.to_owned(),
+            hooks: self.hooks.clone(),
+        }
+    }
+}
+
+/// A call of a `Hooked` registry, running its hook once it succeeds.
+#[pin_project]
+pub struct HookedFuture<F> {
+    #[pin]
+    inner: F,
+    on_ok: Option<OnOk>,
+}
+
+// the hooks, which of them to run and on what.
+type OnOk = (Hooks, fn(&Hooks, &Instance), Arc<Instance>);
+
+impl<F, E> Future for HookedFuture<F>
+where
+    F: Future<Output = Result<(), E>>,
+{
+    type Output = Result<(), E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let out = ready!(this.inner.poll(cx));
+        if let (Ok(()), Some((hooks, hook, ins))) = (&out, this.on_ok.take()) {
+            hook(&hooks, &ins);
+        }
+        Poll::Ready(out)
+    }
+}
+
+/// The watcher of a `Hooked` registry.
+#[pin_project]
+pub struct HookedWatcher<W> {
+    #[pin]
+    inner: W,
+    appid: String,
+    hooks: Hooks,
+}
+
+impl<W> Stream for HookedWatcher<W>
+where
+    W: Stream<Item = WatchEvent>,
+{
+    type Item = WatchEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let watch_event = ready!(this.inner.poll_next(cx));
+        if let Some(watch_event) = &watch_event {
+            this.hooks.watch_event(this.appid, watch_event);
+        }
+        Poll::Ready(watch_event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Hooked, Hooks};
+    use crate::{
+        testing::{expect_create, MockRegistry},
+        Instance, Registry,
+    };
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[tokio::test]
+    async fn test_hooks() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hooks = Hooks::new();
+        hooks.on_register(|_| panic!("boom"));
+        hooks.on_register({
+            let seen = seen.clone();
+            move |ins| {
+                seen.lock()
+                    .unwrap()
+                    .push(format!("register {}", ins.addrs[0]))
+            }
+        });
+        hooks.on_deregister({
+            let seen = seen.clone();
+            move |ins| {
+                seen.lock()
+                    .unwrap()
+                    .push(format!("deregister {}", ins.addrs[0]))
+            }
+        });
+        hooks.on_watch_event({
+            let seen = seen.clone();
+            move |appid, _| seen.lock().unwrap().push(format!("event {}", appid))
+        });
+        let mock = MockRegistry::new();
+        let registry = Hooked::new(mock.clone(), hooks);
+        let ins = Arc::new(Instance {
+            appid: "provider".into(),
+            addrs: vec!["grpc://172.1.1.1:9999".to_owned()].into(),
+            ..Default::default()
+        });
+
+        registry.register(ins.clone()).await.unwrap();
+        let mut watcher = registry.watch("provider");
+        expect_create(&mut watcher, |_| true, Duration::from_millis(10)).await;
+        mock.fail_deregister("zk is down");
+        assert!(registry.deregister(&ins).await.is_err());
+        registry.deregister(&ins).await.unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                "register grpc://172.1.1.1:9999",
+                "event provider",
+                "deregister grpc://172.1.1.1:9999",
+            ]
+        );
+    }
+}
@@ -0,0 +1,158 @@
+use crate::{Addrs, Instance};
+use std::{hash::Hash, sync::Arc};
+
+/// Decides which instances are the same logical instance.
+///
+/// Watchers use it to tell a re-registration of an existing instance apart
+/// from a new one, and `AppDiscover` uses it to key the services it hands to
+/// tower. Any `Fn(&Instance) -> K` extracting the key is one, e.g.
+/// `|ins: &Instance| ins.addrs[0].clone()`.
+pub trait Identity {
+    type Key: Hash + Eq + Clone;
+
+    fn identify(&self, ins: &Instance) -> Self::Key;
+}
+
+impl<F, K> Identity for F
+where
+    F: Fn(&Instance) -> K,
+    K: Hash + Eq + Clone,
+{
+    type Key = K;
+
+    fn identify(&self, ins: &Instance) -> Self::Key {
+        self(ins)
+    }
+}
+
+/// appid, env, version and addrs, the fields `Instance`'s `Hash` impl covers.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultIdentity;
+
+impl Identity for DefaultIdentity {
+    type Key = (Arc<str>, Arc<str>, String, Addrs);
+
+    fn identify(&self, ins: &Instance) -> Self::Key {
+        (
+            ins.appid.clone(),
+            ins.env.clone(),
+            ins.version.clone(),
+            ins.addrs.clone(),
+        )
+    }
+}
+
+/// Every instance of an app is the same, only one is kept per appid.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AppIdentity;
+
+impl Identity for AppIdentity {
+    type Key = Arc<str>;
+
+    fn identify(&self, ins: &Instance) -> Self::Key {
+        ins.appid.clone()
+    }
+}
+
+/// One instance per host of an app.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HostnameIdentity;
+
+impl Identity for HostnameIdentity {
+    type Key = (Arc<str>, String);
+
+    fn identify(&self, ins: &Instance) -> Self::Key {
+        (ins.appid.clone(), ins.hostname.clone())
+    }
+}
+
+/// Instances carry their own id in a metadata field, e.g. `instance_id`.
+/// Instances missing the field share the empty id.
+#[derive(Debug, Clone)]
+pub struct MetadataIdentity {
+    key: String,
+}
+
+impl MetadataIdentity {
+    pub fn new(key: impl Into<String>) -> Self {
+        Self { key: key.into() }
+    }
+}
+
+impl Identity for MetadataIdentity {
+    type Key = (Arc<str>, String);
+
+    fn identify(&self, ins: &Instance) -> Self::Key {
+        (
+            ins.appid.clone(),
+            ins.metadata.get(&self.key).cloned().unwrap_or_default(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DefaultIdentity, HostnameIdentity, Identity, MetadataIdentity};
+    use crate::Instance;
+
+    fn instance(hostname: &str, id: &str, weight: &str) -> Instance {
+        Instance {
+            appid: "provider".into(),
+            hostname: hostname.to_owned(),
+            addrs: vec!["grpc://172.1.1.1:9999".to_owned()].into(),
+            metadata: [
+                ("instance_id".to_owned(), id.to_owned()),
+                ("weight".to_owned(), weight.to_owned()),
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_default_identity_ignores_metadata() {
+        let identity = DefaultIdentity;
+        assert_eq!(
+            identity.identify(&instance("host1", "a", "10")),
+            identity.identify(&instance("host2", "b", "20"))
+        );
+    }
+
+    #[test]
+    fn test_hostname_identity() {
+        let identity = HostnameIdentity;
+        assert_eq!(
+            identity.identify(&instance("host1", "a", "10")),
+            identity.identify(&instance("host1", "b", "20"))
+        );
+        assert_ne!(
+            identity.identify(&instance("host1", "a", "10")),
+            identity.identify(&instance("host2", "a", "10"))
+        );
+    }
+
+    #[test]
+    fn test_metadata_identity() {
+        let identity = MetadataIdentity::new("instance_id");
+        assert_eq!(
+            identity.identify(&instance("host1", "a", "10")),
+            identity.identify(&instance("host2", "a", "20"))
+        );
+        assert_ne!(
+            identity.identify(&instance("host1", "a", "10")),
+            identity.identify(&instance("host1", "b", "10"))
+        );
+    }
+
+    #[test]
+    fn test_fn_identity() {
+        let identity = |ins: &Instance| ins.metadata["instance_id"].clone();
+        assert_eq!(identity.identify(&instance("host1", "a", "10")), "a");
+        assert_ne!(
+            identity.identify(&instance("host1", "a", "10")),
+            identity.identify(&instance("host1", "b", "10"))
+        );
+    }
+}
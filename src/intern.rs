@@ -0,0 +1,38 @@
+use crate::HashSet;
+use lazy_static::lazy_static;
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    static ref INTERNED: Mutex<HashSet<Arc<str>>> = Mutex::new(HashSet::default());
+}
+
+/// The copy of `s` shared by every instance, e.g. the zone all the instances
+/// of a data center are in.
+///
+/// Interned strings live as long as the process, only intern ones taking a
+/// few distinct values, such as appids, zones and envs.
+pub fn intern(s: &str) -> Arc<str> {
+    let mut interned = INTERNED.lock().unwrap();
+    match interned.get(s) {
+        Some(shared) => shared.clone(),
+        None => {
+            let shared = Arc::<str>::from(s);
+            interned.insert(shared.clone());
+            shared
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::intern;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_intern() {
+        let zone = intern("sh001");
+        assert_eq!(&*zone, "sh001");
+        assert!(Arc::ptr_eq(&zone, &intern(&String::from("sh001"))));
+        assert!(!Arc::ptr_eq(&zone, &intern("sh002")));
+    }
+}
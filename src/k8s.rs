@@ -0,0 +1,624 @@
+//! Watches the ready pods of Kubernetes services, through their
+//! EndpointSlices.
+//!
+//! The appid is the service name, in the namespace of the `K8s` unless given
+//! as `<namespace>/<service>`. Each ready endpoint is an instance:
+//! - its addresses are `<scheme>://<ip>:<port>` for every port of the slice,
+//!   the scheme being the app protocol of the port, or else its name, or else
+//!   `tcp`;
+//! - its hostname is the name of its pod, its zone the zone of the endpoint;
+//! - its metadata are the labels of its pod, its version the
+//!   `app.kubernetes.io/version` or `version` label. Labels are read once per
+//!   pod, changes to them aren't watched.
+//!
+//! Kubernetes registers instances itself, registering through a `K8s` fails
+//! with `K8sError::Unsupported`.
+//!
+//! The API server is spoken to over plain HTTP, e.g. through `kubectl proxy`
+//! or an ambassador container, as TLS isn't supported.
+//!
+//! ```ignore
+//! let k8s = K8s::builder("http://127.0.0.1:8001").namespace("payments").build();
+//! let discover = AppDiscover::new(k8s.watch("billing"), make_service);
+//! ```
+use crate::{
+    identity::{DefaultIdentity, Identity},
+    intern::intern,
+    runtime::{self, AbortOnDrop},
+    watcher::{Clock, Event, SystemClock, WatchEvent},
+    Instance, Registry,
+};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future::{self, BoxFuture},
+    FutureExt, Stream,
+};
+use hyper::{body::HttpBody, client::HttpConnector, header, Body, Request, StatusCode};
+use log::error;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use pin_project::pin_project;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    error, fmt,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+const SERVICE_NAME_LABEL: &str = "kubernetes.io/service-name";
+
+// how long a failed watch waits before listing the slices again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct K8s<I = DefaultIdentity> {
+    client: Client,
+    namespace: String,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+}
+
+impl K8s {
+    /// Talks to the API server at `address`, see `K8sBuilder` for the
+    /// options.
+    pub fn builder(address: &str) -> K8sBuilder {
+        K8sBuilder::new(address)
+    }
+}
+
+impl<I> K8s<I> {
+    /// Sets how watchers tell instances apart.
+    pub fn with_identity<NI>(self, identity: NI) -> K8s<NI> {
+        K8s {
+            client: self.client,
+            namespace: self.namespace,
+            identity: Arc::new(identity),
+            clock: self.clock,
+        }
+    }
+
+    /// Sets the clock timestamping the events of watchers.
+    pub fn with_clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    // the namespace and name of the service of `appid`.
+    fn service<'a>(&'a self, appid: &'a str) -> (&'a str, &'a str) {
+        match appid.split_once('/') {
+            Some((namespace, name)) => (namespace, name),
+            None => (&self.namespace, appid),
+        }
+    }
+}
+
+/// Builds a `K8s`.
+///
+/// ```ignore
+/// let k8s = K8s::builder("http://127.0.0.1:8001")
+///     .namespace("payments")
+///     .token(&token)
+///     .build();
+/// ```
+pub struct K8sBuilder {
+    address: String,
+    namespace: String,
+    token: Option<String>,
+}
+
+impl K8sBuilder {
+    pub fn new(address: &str) -> Self {
+        K8sBuilder {
+            address: address.trim_end_matches('/').to_owned(),
+            namespace: "default".to_owned(),
+            token: None,
+        }
+    }
+
+    /// The namespace of the services watched by name only, `default` by
+    /// default.
+    pub fn namespace(mut self, namespace: &str) -> Self {
+        self.namespace = namespace.to_owned();
+        self
+    }
+
+    /// The bearer token of the calls, e.g. of a service account allowed to
+    /// list and watch endpointslices and get pods.
+    pub fn token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_owned());
+        self
+    }
+
+    pub fn build(self) -> K8s {
+        K8s {
+            client: Client {
+                http: hyper::Client::new(),
+                address: self.address,
+                token: self.token,
+            },
+            namespace: self.namespace,
+            identity: Arc::new(DefaultIdentity),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Client {
+    http: hyper::Client<HttpConnector>,
+    address: String,
+    token: Option<String>,
+}
+
+impl Client {
+    async fn get(&self, path: &str) -> Result<Body, K8sError> {
+        let mut req = Request::get(format!("{}{}", self.address, path))
+            .header(header::ACCEPT, "application/json");
+        if let Some(token) = &self.token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let req = req
+            .body(Body::empty())
+            .map_err(|e| K8sError::InvalidAddress(e.to_string()))?;
+        let rsp = self.http.request(req).await?;
+        let status = rsp.status();
+        if !status.is_success() {
+            let body = hyper::body::to_bytes(rsp.into_body()).await?;
+            let message = String::from_utf8_lossy(&body).into_owned();
+            return Err(K8sError::Status(status, message));
+        }
+        Ok(rsp.into_body())
+    }
+
+    async fn get_json(&self, path: &str) -> Result<Value, K8sError> {
+        let body = hyper::body::to_bytes(self.get(path).await?).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    async fn pod_labels(&self, namespace: &str, pod: &str) -> HashMap<String, String> {
+        let path = format!(
+            "/api/v1/namespaces/{}/pods/{}",
+            encode(namespace),
+            encode(pod)
+        );
+        match self.get_json(&path).await {
+            Ok(pod) => pod["metadata"]["labels"]
+                .as_object()
+                .into_iter()
+                .flatten()
+                .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_owned())))
+                .collect(),
+            Err(e) => {
+                error!("failed to get the labels of the pod {}. {}", pod, e);
+                HashMap::new()
+            }
+        }
+    }
+}
+
+// the slices of a service, `selector` being the encoded label selector.
+fn slices_path(namespace: &str, selector: &str) -> String {
+    format!(
+        "/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices?labelSelector={}",
+        encode(namespace),
+        selector
+    )
+}
+
+fn encode(value: &str) -> String {
+    utf8_percent_encode(value, NON_ALPHANUMERIC).to_string()
+}
+
+// the next line of a watch, none once it ended.
+async fn next_line(body: &mut Body, buf: &mut Vec<u8>) -> Result<Option<Vec<u8>>, K8sError> {
+    loop {
+        if let Some(pos) = buf.iter().position(|&b| b == b'\n') {
+            let line = buf.drain(..=pos).collect();
+            return Ok(Some(line));
+        }
+        match body.data().await {
+            Some(chunk) => buf.extend_from_slice(&chunk?),
+            None => return Ok(None),
+        }
+    }
+}
+
+// the scheme of the addresses on `port` of a slice.
+fn scheme(port: &Value) -> &str {
+    let named = |key: &str| port[key].as_str().filter(|value| !value.is_empty());
+    named("appProtocol")
+        .or_else(|| named("name"))
+        .unwrap_or("tcp")
+}
+
+// the ready endpoints of a slice, as instances without their pod labels, and
+// their pod.
+fn endpoints(appid: &str, slice: &Value) -> Vec<(Instance, Option<String>)> {
+    let ports = slice["ports"].as_array().map(Vec::as_slice).unwrap_or(&[]);
+    let mut instances = Vec::new();
+    for endpoint in slice["endpoints"].as_array().into_iter().flatten() {
+        // unknown readiness is taken as ready.
+        if endpoint["conditions"]["ready"].as_bool() == Some(false) {
+            continue;
+        }
+        let ip = match endpoint["addresses"][0].as_str() {
+            Some(ip) => ip,
+            None => continue,
+        };
+        let host = if ip.contains(':') {
+            format!("[{}]", ip)
+        } else {
+            ip.to_owned()
+        };
+        let addrs = ports
+            .iter()
+            .filter_map(|port| {
+                Some(format!(
+                    "{}://{}:{}",
+                    scheme(port),
+                    host,
+                    port["port"].as_u64()?
+                ))
+            })
+            .collect();
+        let pod = match &endpoint["targetRef"] {
+            target if target["kind"] == "Pod" => target["name"].as_str().map(str::to_owned),
+            _ => None,
+        };
+        let hostname = pod
+            .as_deref()
+            .or_else(|| endpoint["hostname"].as_str())
+            .unwrap_or(ip)
+            .to_owned();
+        let ins = Instance {
+            zone: intern(endpoint["zone"].as_str().unwrap_or("")),
+            appid: intern(appid),
+            hostname,
+            addrs,
+            ..Default::default()
+        };
+        instances.push((ins, pod));
+    }
+    instances
+}
+
+// fills in the labels of the pod of `ins`.
+fn with_labels(mut ins: Instance, labels: &HashMap<String, String>) -> Instance {
+    ins.version = labels
+        .get("app.kubernetes.io/version")
+        .or_else(|| labels.get("version"))
+        .cloned()
+        .unwrap_or_default();
+    ins.metadata = labels.clone();
+    ins
+}
+
+#[derive(Debug)]
+pub enum K8sError {
+    InvalidAddress(String),
+    Http(hyper::Error),
+    /// The API server answered with an error status.
+    Status(StatusCode, String),
+    Json(serde_json::Error),
+    /// The watch fell too far behind, e.g. with a `410 Gone`.
+    Expired(String),
+    /// Kubernetes registers instances itself.
+    Unsupported,
+}
+
+impl fmt::Display for K8sError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            K8sError::InvalidAddress(e) => write!(f, "invalid api server address: {}", e),
+            K8sError::Http(e) => write!(f, "failed to call the api server: {}", e),
+            K8sError::Status(status, message) => {
+                write!(f, "the api server answered {}: {}", status, message)
+            }
+            K8sError::Json(e) => write!(f, "bad json from the api server: {}", e),
+            K8sError::Expired(message) => write!(f, "the watch expired: {}", message),
+            K8sError::Unsupported => write!(f, "unsupported by kubernetes"),
+        }
+    }
+}
+
+impl error::Error for K8sError {}
+
+impl From<hyper::Error> for K8sError {
+    fn from(e: hyper::Error) -> Self {
+        K8sError::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for K8sError {
+    fn from(e: serde_json::Error) -> Self {
+        K8sError::Json(e)
+    }
+}
+
+impl<I> Registry for K8s<I>
+where
+    I: Identity + Send + Sync + 'static,
+    I::Key: Send + Sync,
+{
+    type Error = K8sError;
+
+    type RegFuture = BoxFuture<'static, Result<(), K8sError>>;
+
+    type DeRegFuture = BoxFuture<'static, Result<(), K8sError>>;
+
+    type ListFuture = BoxFuture<'static, Result<Vec<Arc<Instance>>, K8sError>>;
+
+    type Watcher = K8sWatcher;
+
+    fn register(&self, _ins: Arc<Instance>) -> Self::RegFuture {
+        future::ready(Err(K8sError::Unsupported)).boxed()
+    }
+
+    fn deregister(&self, _ins: &Arc<Instance>) -> Self::DeRegFuture {
+        future::ready(Err(K8sError::Unsupported)).boxed()
+    }
+
+    fn evict(&self, _appid: &str, _instance_id: &str) -> Self::DeRegFuture {
+        future::ready(Err(K8sError::Unsupported)).boxed()
+    }
+
+    fn update_if(&self, _ins: Arc<Instance>, _expected_version: u64) -> Self::RegFuture {
+        future::ready(Err(K8sError::Unsupported)).boxed()
+    }
+
+    fn renew(&self, _ins: &Arc<Instance>) -> Self::RegFuture {
+        future::ready(Err(K8sError::Unsupported)).boxed()
+    }
+
+    fn list(&self, appid: &str) -> Self::ListFuture {
+        let client = self.client.clone();
+        let appid = appid.to_owned();
+        let (namespace, name) = self.service(&appid);
+        let namespace = namespace.to_owned();
+        let selector = encode(&format!("{}={}", SERVICE_NAME_LABEL, name));
+        async move {
+            let list = client.get_json(&slices_path(&namespace, &selector)).await?;
+            let slices = list["items"].as_array().into_iter().flatten();
+            let mut instances = Vec::new();
+            for (ins, pod) in slices.flat_map(|slice| endpoints(&appid, slice)) {
+                let ins = match pod {
+                    Some(pod) => with_labels(ins, &client.pod_labels(&namespace, &pod).await),
+                    None => ins,
+                };
+                instances.push(Arc::new(ins));
+            }
+            Ok(instances)
+        }
+        .boxed()
+    }
+
+    fn watch(&self, appid: &str) -> Self::Watcher {
+        self.watch_from(appid, &[])
+    }
+
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher {
+        let (tx, rx) = mpsc::unbounded();
+        let mut known = HashMap::new();
+        for ins in instances {
+            known.insert(self.identity.identify(ins), ins.clone());
+            let event = WatchEvent::with_clock(Event::Create(ins.clone()), &*self.clock);
+            let _ = tx.unbounded_send(event);
+        }
+        let (namespace, name) = self.service(appid);
+        let watch = ServiceWatch {
+            client: self.client.clone(),
+            appid: appid.to_owned(),
+            namespace: namespace.to_owned(),
+            selector: encode(&format!("{}={}", SERVICE_NAME_LABEL, name)),
+            slices: HashMap::new(),
+            labels: HashMap::new(),
+            identity: self.identity.clone(),
+            clock: self.clock.clone(),
+            known,
+            tx,
+        };
+        K8sWatcher {
+            rx,
+            _task: runtime::spawn_abortable(watch.run()),
+        }
+    }
+}
+
+/// The ready pods of a service, see `Registry::watch`.
+#[pin_project]
+pub struct K8sWatcher {
+    #[pin]
+    rx: UnboundedReceiver<WatchEvent>,
+    _task: AbortOnDrop,
+}
+
+impl Stream for K8sWatcher {
+    type Item = WatchEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().rx.poll_next(cx)
+    }
+}
+
+struct ServiceWatch<I: Identity> {
+    client: Client,
+    appid: String,
+    namespace: String,
+    // the label selector of the slices of the service, encoded.
+    selector: String,
+    // the slices of the service by name.
+    slices: HashMap<String, Value>,
+    // the labels of the pods by name.
+    labels: HashMap<String, HashMap<String, String>>,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+    // the instances reported.
+    known: HashMap<I::Key, Arc<Instance>>,
+    tx: UnboundedSender<WatchEvent>,
+}
+
+impl<I> ServiceWatch<I>
+where
+    I: Identity,
+{
+    async fn run(mut self) {
+        loop {
+            if let Err(e) = self.watch().await {
+                error!("failed to watch {}. {}", self.appid, e);
+            }
+            runtime::delay_for(RETRY_INTERVAL).await;
+        }
+    }
+
+    // lists the slices, then watches them from there, until the watch
+    // expires.
+    async fn watch(&mut self) -> Result<(), K8sError> {
+        let list = self
+            .client
+            .get_json(&slices_path(&self.namespace, &self.selector))
+            .await?;
+        self.slices.clear();
+        for slice in list["items"].as_array().into_iter().flatten() {
+            if let Some(name) = slice["metadata"]["name"].as_str() {
+                self.slices.insert(name.to_owned(), slice.clone());
+            }
+        }
+        self.update().await;
+        let mut version = list["metadata"]["resourceVersion"]
+            .as_str()
+            .unwrap_or("")
+            .to_owned();
+        loop {
+            let path = format!(
+                "{}&watch=1&allowWatchBookmarks=true&resourceVersion={}",
+                slices_path(&self.namespace, &self.selector),
+                encode(&version)
+            );
+            let mut body = self.client.get(&path).await?;
+            let mut buf = Vec::new();
+            // the server ends watches after a while, they are resumed.
+            while let Some(line) = next_line(&mut body, &mut buf).await? {
+                let event: Value = serde_json::from_slice(&line)?;
+                let slice = &event["object"];
+                if let Some(new_version) = slice["metadata"]["resourceVersion"].as_str() {
+                    version = new_version.to_owned();
+                }
+                let name = slice["metadata"]["name"].as_str().unwrap_or("").to_owned();
+                match event["type"].as_str() {
+                    Some("ADDED") | Some("MODIFIED") => {
+                        self.slices.insert(name, slice.clone());
+                    }
+                    Some("DELETED") => {
+                        self.slices.remove(&name);
+                    }
+                    Some("ERROR") => {
+                        let message = slice["message"].as_str().unwrap_or("").to_owned();
+                        return Err(K8sError::Expired(message));
+                    }
+                    // bookmarks only move the version on.
+                    _ => continue,
+                }
+                self.update().await;
+            }
+        }
+    }
+
+    // reports the ready endpoints new or changed as Creates, and the ones
+    // gone as Deletes.
+    async fn update(&mut self) {
+        let endpoints: Vec<_> = self
+            .slices
+            .values()
+            .flat_map(|slice| endpoints(&self.appid, slice))
+            .collect();
+        let mut labels = HashMap::new();
+        let mut gone = std::mem::take(&mut self.known);
+        for (ins, pod) in endpoints {
+            let ins = match pod {
+                Some(pod) => {
+                    let pod_labels = match self.labels.remove(&pod) {
+                        Some(pod_labels) => pod_labels,
+                        None => self.client.pod_labels(&self.namespace, &pod).await,
+                    };
+                    let ins = with_labels(ins, &pod_labels);
+                    labels.insert(pod, pod_labels);
+                    ins
+                }
+                None => ins,
+            };
+            let ins = Arc::new(ins);
+            let key = self.identity.identify(&ins);
+            let unchanged = gone.remove(&key).filter(|old| *old == ins);
+            if unchanged.is_none() {
+                self.send(Event::Create(ins.clone()));
+            }
+            self.known.insert(key, ins);
+        }
+        // only the pods still there.
+        self.labels = labels;
+        for (_, ins) in gone {
+            self.send(Event::Delete(ins));
+        }
+    }
+
+    fn send(&self, event: Event) {
+        let _ = self
+            .tx
+            .unbounded_send(WatchEvent::with_clock(event, &*self.clock));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{endpoints, with_labels};
+    use serde_json::json;
+
+    #[test]
+    fn test_endpoints() {
+        let slice = json!({
+            "metadata": {"name": "billing-abc12"},
+            "addressType": "IPv4",
+            "endpoints": [
+                {
+                    "addresses": ["10.1.0.5"],
+                    "conditions": {"ready": true},
+                    "targetRef": {"kind": "Pod", "name": "billing-7d9f-x2x"},
+                    "zone": "us-east-1a",
+                },
+                {
+                    "addresses": ["10.1.0.6"],
+                    "conditions": {"ready": false},
+                    "targetRef": {"kind": "Pod", "name": "billing-7d9f-k8s"},
+                },
+            ],
+            "ports": [
+                {"name": "http", "port": 8080},
+                {"name": "metrics", "port": 9090, "appProtocol": "http"},
+                {"port": 7000},
+            ],
+        });
+        let endpoints = endpoints("billing", &slice);
+        assert_eq!(endpoints.len(), 1);
+        let (ins, pod) = endpoints.into_iter().next().unwrap();
+        assert_eq!(pod.as_deref(), Some("billing-7d9f-x2x"));
+        assert_eq!(
+            &ins.addrs[..],
+            [
+                "http://10.1.0.5:8080",
+                "http://10.1.0.5:9090",
+                "tcp://10.1.0.5:7000"
+            ]
+        );
+        assert_eq!(&*ins.zone, "us-east-1a");
+        assert_eq!(ins.hostname, "billing-7d9f-x2x");
+
+        let labels = vec![("app.kubernetes.io/version".to_owned(), "1.2.0".to_owned())];
+        let ins = with_labels(ins, &labels.into_iter().collect());
+        assert_eq!(ins.version, "1.2.0");
+        assert_eq!(ins.metadata["app.kubernetes.io/version"], "1.2.0");
+    }
+}
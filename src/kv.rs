@@ -0,0 +1,191 @@
+//! Dynamic configuration kept in the backend of a registry, watched over the
+//! same connection as the instances.
+//!
+//! ```ignore
+//! zk.set_key("/config/billing/limits", br#"{"qps": 100}"#.to_vec()).await?;
+//! let mut limits = watch_value(&zk, "/config/billing/limits", JsonValue);
+//! while let Some(event) = limits.next().await {
+//!     match event {
+//!         Ok(KeyEvent::Set(limits)) => limiter.update(&limits),
+//!         Ok(KeyEvent::Deleted) => limiter.reset(),
+//!         Err(e) => warn!("bad limits. {}", e),
+//!     }
+//! }
+//! ```
+use futures::{Future, Stream};
+use pin_project::pin_project;
+use std::{
+    convert::Infallible,
+    fmt::{Debug, Display},
+    pin::Pin,
+    str::Utf8Error,
+    task::{Context, Poll},
+};
+
+/// A backend keeping values by key, e.g. ZooKeeper nodes by path.
+pub trait KvStore {
+    type Error;
+
+    type SetFuture: Future<Output = Result<(), Self::Error>>;
+
+    type KeyWatcher: Stream<Item = Option<Vec<u8>>>;
+
+    /// Sets `key` to `value`, creating it as needed.
+    fn set_key(&self, key: &str, value: Vec<u8>) -> Self::SetFuture;
+
+    /// Deleting a missing key succeeds.
+    fn delete_key(&self, key: &str) -> Self::SetFuture;
+
+    /// The value of `key`, then every change of it. `None` while it doesn't
+    /// exist.
+    fn watch_key(&self, key: &str) -> Self::KeyWatcher;
+}
+
+/// Turns the values of a key into what the application reads.
+pub trait ValueDecoder {
+    type Value;
+    type Error: Display + Debug;
+
+    fn decode(&self, raw: &[u8]) -> Result<Self::Value, Self::Error>;
+}
+
+impl<F, T, E> ValueDecoder for F
+where
+    F: Fn(&[u8]) -> Result<T, E>,
+    E: Display + Debug,
+{
+    type Value = T;
+    type Error = E;
+
+    fn decode(&self, raw: &[u8]) -> Result<T, E> {
+        self(raw)
+    }
+}
+
+/// The raw bytes.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Raw;
+
+impl ValueDecoder for Raw {
+    type Value = Vec<u8>;
+    type Error = Infallible;
+
+    fn decode(&self, raw: &[u8]) -> Result<Vec<u8>, Infallible> {
+        Ok(raw.to_vec())
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Utf8;
+
+impl ValueDecoder for Utf8 {
+    type Value = String;
+    type Error = Utf8Error;
+
+    fn decode(&self, raw: &[u8]) -> Result<String, Utf8Error> {
+        std::str::from_utf8(raw).map(str::to_owned)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JsonValue;
+
+impl ValueDecoder for JsonValue {
+    type Value = serde_json::Value;
+    type Error = serde_json::Error;
+
+    fn decode(&self, raw: &[u8]) -> Result<serde_json::Value, serde_json::Error> {
+        serde_json::from_slice(raw)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyEvent<T> {
+    Set(T),
+    Deleted,
+}
+
+/// Watches `key` in `store`, its values decoded with `decoder`. A value
+/// failing to decode is reported as an error and the watch goes on.
+pub fn watch_value<S, D>(store: &S, key: &str, decoder: D) -> ValueWatcher<S::KeyWatcher, D>
+where
+    S: KvStore,
+    D: ValueDecoder,
+{
+    ValueWatcher {
+        watcher: store.watch_key(key),
+        decoder,
+        exists: false,
+    }
+}
+
+/// See `watch_value`.
+#[pin_project]
+pub struct ValueWatcher<W, D> {
+    #[pin]
+    watcher: W,
+    decoder: D,
+    // whether the key was last seen set, only its deletion is reported.
+    exists: bool,
+}
+
+impl<W, D> Stream for ValueWatcher<W, D>
+where
+    W: Stream<Item = Option<Vec<u8>>>,
+    D: ValueDecoder,
+{
+    type Item = Result<KeyEvent<D::Value>, D::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            let value = match this.watcher.as_mut().poll_next(cx) {
+                Poll::Ready(Some(value)) => value,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            let event = match value {
+                Some(raw) => {
+                    *this.exists = true;
+                    this.decoder.decode(&raw).map(KeyEvent::Set)
+                }
+                None if *this.exists => {
+                    *this.exists = false;
+                    Ok(KeyEvent::Deleted)
+                }
+                // never set.
+                None => continue,
+            };
+            return Poll::Ready(Some(event));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{watch_value, JsonValue, KeyEvent, KvStore};
+    use crate::testing::MockRegistry;
+    use futures::StreamExt;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_watch_value() {
+        let registry = MockRegistry::new();
+        let mut limits = watch_value(&registry, "/config/billing/limits", JsonValue);
+        registry
+            .set_key("/config/billing/limits", br#"{"qps": 100}"#.to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            limits.next().await.unwrap().unwrap(),
+            KeyEvent::Set(json!({"qps": 100}))
+        );
+        registry
+            .set_key("/config/billing/limits", b"{".to_vec())
+            .await
+            .unwrap();
+        assert!(limits.next().await.unwrap().is_err());
+        registry.delete_key("/config/billing/limits").await.unwrap();
+        assert_eq!(limits.next().await.unwrap().unwrap(), KeyEvent::Deleted);
+    }
+}
@@ -0,0 +1,80 @@
+//! Keeps instances alive on backends expiring the ones not renewed, see
+//! `Registry::renew`.
+use crate::{runtime, Instance, Registry};
+use futures::future::{AbortHandle, Abortable};
+use log::error;
+use std::{fmt, sync::Arc, time::Duration};
+
+/// Renews `ins` every `interval` until the returned `KeepAlive` is dropped,
+/// e.g. an instance registered by another process whose liveness this one
+/// vouches for. Failed renewals are logged and tried again the next time.
+///
+/// ```ignore
+/// let _alive = keep_alive(Arc::new(eureka), instance, Duration::from_secs(30));
+/// ```
+pub fn keep_alive<R>(registry: Arc<R>, ins: Arc<Instance>, interval: Duration) -> KeepAlive
+where
+    R: Registry + Send + Sync + 'static,
+    R::RegFuture: Send,
+    R::Error: fmt::Display,
+{
+    let renewal = async move {
+        loop {
+            runtime::delay_for(interval).await;
+            if let Err(e) = registry.renew(&ins).await {
+                error!("failed to renew {:?}. {}", ins.addrs, e);
+            }
+        }
+    };
+    let (abort, registration) = AbortHandle::new_pair();
+    runtime::spawn(Abortable::new(renewal, registration));
+    KeepAlive(abort)
+}
+
+/// Stops renewing once dropped, see `keep_alive`.
+pub struct KeepAlive(AbortHandle);
+
+impl Drop for KeepAlive {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::keep_alive;
+    use crate::{
+        testing::{Call, MockRegistry},
+        Instance,
+    };
+    use std::{sync::Arc, time::Duration};
+
+    #[tokio::test]
+    async fn test_keep_alive() {
+        tokio::time::pause();
+        let registry = MockRegistry::new();
+        let ins = Arc::new(Instance {
+            appid: "provider".into(),
+            addrs: vec!["grpc://10.0.0.1:9000".to_owned()].into(),
+            ..Default::default()
+        });
+        registry.fail_register("eureka is down");
+        let alive = keep_alive(
+            Arc::new(registry.clone()),
+            ins.clone(),
+            Duration::from_millis(10),
+        );
+        tokio::time::delay_for(Duration::from_millis(35)).await;
+        drop(alive);
+        let renewed = registry.calls().len();
+        assert!(renewed >= 2);
+        assert!(registry
+            .calls()
+            .iter()
+            .all(|call| *call == Call::Renew(ins.clone())));
+
+        // stopped once dropped.
+        tokio::time::delay_for(Duration::from_millis(30)).await;
+        assert_eq!(registry.calls().len(), renewed);
+    }
+}
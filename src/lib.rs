@@ -1,27 +1,160 @@
-use futures::{Future, Stream};
-use fxhash;
+use codec::DecodeError;
+use delta::InstanceDelta;
+use futures::{future::BoxFuture, ready, Future, Stream};
+use identity::{DefaultIdentity, Identity};
 use pin_project::pin_project;
-use std::{collections::HashMap, hash::Hash};
+use smallvec::SmallVec;
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt,
+    hash::Hash,
+    marker::PhantomData,
+    sync::Arc,
+    task::Poll,
+    time::{Duration, SystemTime},
+};
 use tower::discover::{Change, Discover};
-use watcher::{Event, WatchEvent};
+use watcher::{Event, WatchEvent, WatchItem};
 
+#[cfg(feature = "admin-http")]
+pub mod admin;
+pub mod balance;
+#[cfg(feature = "bilibili")]
+pub mod bilibili;
+pub mod boxed;
+pub mod cluster;
 pub mod codec;
+pub mod composite;
+#[cfg(feature = "consul")]
+pub mod consul;
+pub mod control;
+#[cfg(feature = "config")]
+pub mod config;
+pub mod delta;
+pub mod discovery;
+pub mod election;
+pub mod failover;
+#[cfg(feature = "etcd")]
+pub mod etcd;
+#[cfg(feature = "eureka")]
+pub mod eureka;
+pub mod expiry;
+pub mod file;
+#[cfg(feature = "k8s")]
+pub mod k8s;
+#[cfg(any(feature = "consul", feature = "nacos", feature = "eureka"))]
+mod flat;
+#[cfg(any(feature = "dns", feature = "dns-server"))]
+pub mod dns;
+#[cfg(feature = "grpc-health")]
+pub mod health;
+pub mod hooks;
+pub mod identity;
+pub mod kv;
+pub mod lease;
+mod intern;
+pub mod lifecycle;
+pub mod local;
+pub mod mem;
+pub mod mirror;
+#[cfg(feature = "nacos")]
+pub mod nacos;
+pub mod polling;
+pub mod prometheus;
+#[cfg(feature = "redis")]
+pub mod redis;
+pub mod registration;
+pub mod resolver;
+pub mod routing;
+pub mod runtime;
+pub mod service;
+#[cfg(feature = "signal")]
+pub mod shutdown;
+pub mod snapshot;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
 pub mod watcher;
+#[cfg(feature = "xds-server")]
+pub mod xds;
+#[cfg(feature = "zk")]
 pub mod zk;
 
-pub type HashSet<T> = std::collections::HashSet<T, std::hash::BuildHasherDefault<fxhash::FxHasher>>;
+pub use intern::intern;
+
+// internal only, the public api takes and returns std collections.
+pub(crate) type HashSet<T> =
+    std::collections::HashSet<T, std::hash::BuildHasherDefault<fxhash::FxHasher>>;
+
+/// The addresses of an instance, most have one or two.
+pub type Addrs = SmallVec<[String; 2]>;
 
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
 pub struct Instance {
-    pub zone: String,
-    pub env: String,
-    pub appid: String,
+    /// Interned, see `intern`, like `env` and `appid`.
+    pub zone: Arc<str>,
+    pub env: Arc<str>,
+    pub appid: Arc<str>,
     pub hostname: String,
-    pub addrs: Vec<String>,
+    pub addrs: Addrs,
     pub version: String,
+    /// The traffic group (lane) it serves, e.g. `blue`, empty for the default
+    /// group. Interned too. See `routing::GroupRouter`.
+    pub group: Arc<str>,
     pub metadata: HashMap<String, String>,
+    /// When the backend first saw this registration, if it tracks that.
+    pub registered_at: Option<SystemTime>,
+    /// When the registration was last renewed or modified, if the backend tracks that.
+    pub last_renewed_at: Option<SystemTime>,
+    /// The version of the registration in the backend, e.g. the znode
+    /// version, if it tracks one. See `Registry::update_if`.
+    pub revision: Option<u64>,
 }
 
+impl Instance {
+    /// Describes what changed going from `self` to `other`, see
+    /// `WatchEvent::delta` for the changes of updates.
+    pub fn diff(&self, other: &Instance) -> InstanceDelta {
+        InstanceDelta::between(self, other)
+    }
+
+    /// The `weight` metadata, `None` when absent or not a number.
+    pub fn weight(&self) -> Option<u32> {
+        self.metadata.get("weight").and_then(|w| w.parse().ok())
+    }
+
+    /// The `ttl` metadata in seconds, how long the registration lasts unless
+    /// renewed, see `expiry::Expiring`. `None` when absent or not a number.
+    pub fn ttl(&self) -> Option<Duration> {
+        let secs = self.metadata.get("ttl").and_then(|ttl| ttl.parse().ok());
+        secs.map(Duration::from_secs)
+    }
+
+    /// The first address of `scheme`, without it, e.g. `172.1.1.1:9999` of
+    /// `grpc://172.1.1.1:9999`, what a service connects to:
+    ///
+    /// ```ignore
+    /// let discover = AppDiscover::new(watcher, |ins: &Instance| {
+    ///     connect(ins.addr_for_scheme("grpc").unwrap())
+    /// })
+    /// .with_filter(|ins| ins.addr_for_scheme("grpc").is_some());
+    /// ```
+    pub fn addr_for_scheme(&self, scheme: &str) -> Option<&str> {
+        self.addrs
+            .iter()
+            .find_map(|addr| addr.strip_prefix(scheme)?.strip_prefix("://"))
+    }
+
+    /// Whether `id` names this instance, as its `instance_id` metadata or one
+    /// of its addresses. See `Registry::evict`.
+    pub fn has_id(&self, id: &str) -> bool {
+        self.metadata.get("instance_id").map(String::as_str) == Some(id)
+            || self.addrs.iter().any(|addr| addr == id)
+    }
+}
+
+/// Hashes the same fields `identity::DefaultIdentity` compares. Use an
+/// `Identity` when the notion of "same instance" matters.
 impl Hash for Instance {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.appid.hash(state);
@@ -38,66 +171,435 @@ pub trait Registry {
 
     type DeRegFuture: Future<Output = Result<(), Self::Error>>;
 
+    type ListFuture: Future<Output = Result<Vec<Arc<Instance>>, Self::Error>>;
+
     type Watcher: Stream<Item = WatchEvent>;
 
-    fn register(&self, ins: Instance) -> Self::RegFuture;
+    fn register(&self, ins: Arc<Instance>) -> Self::RegFuture;
+
+    fn deregister(&self, ins: &Arc<Instance>) -> Self::DeRegFuture;
+
+    /// Registers `instances`, e.g. the listeners of a service, one after the
+    /// other unless the backend batches them. Stops at the first failure.
+    fn register_all(&self, instances: Vec<Arc<Instance>>) -> BoxFuture<'_, Result<(), Self::Error>>
+    where
+        Self: Sync,
+        Self::RegFuture: Send,
+        Self::Error: Send,
+    {
+        Box::pin(async move {
+            for ins in instances {
+                self.register(ins).await?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Deregisters `instances`, all of them even when some fail, failing with
+    /// the first failure.
+    fn deregister_all(&self, instances: &[Arc<Instance>]) -> BoxFuture<'_, Result<(), Self::Error>>
+    where
+        Self: Sync,
+        Self::DeRegFuture: Send,
+        Self::Error: Send,
+    {
+        let deregistered = instances
+            .iter()
+            .map(|ins| self.deregister(ins))
+            .collect::<Vec<_>>();
+        Box::pin(async move {
+            let mut result = Ok(());
+            for deregistered in deregistered {
+                let deregistered = deregistered.await;
+                if result.is_ok() {
+                    result = deregistered;
+                }
+            }
+            result
+        })
+    }
+
+    /// Removes the instances of `appid` that `instance_id` names (see
+    /// `Instance::has_id`), whoever registered them, e.g. to yank a bad
+    /// instance that won't deregister itself. Meant for admin tooling.
+    /// Evicting nothing succeeds.
+    fn evict(&self, appid: &str, instance_id: &str) -> Self::DeRegFuture;
+
+    /// Updates the registration of `ins` in place, e.g. its metadata, unless
+    /// its revision is no longer `expected_version`. Concurrent updates fail
+    /// with the backend's conflict error instead of overwriting each other.
+    fn update_if(&self, ins: Arc<Instance>, expected_version: u64) -> Self::RegFuture;
 
-    fn deregister(&self, ins: &Instance) -> Self::DeRegFuture;
+    /// Renews the lease of `ins`, e.g. with a heartbeat, on backends expiring
+    /// the instances not renewed: Eureka, Nacos, Consul checks or redis TTLs.
+    /// Instances registered through the registry are renewed in the
+    /// background already, see `lease::keep_alive` for the others. Backends
+    /// keeping instances while their session lives have nothing to renew.
+    fn renew(&self, ins: &Arc<Instance>) -> Self::RegFuture;
 
-    fn watch(&self, appid: &'static str) -> Self::Watcher;
+    /// The instances of `appid` registered now, without watching it, e.g. for
+    /// a dashboard.
+    fn list(&self, appid: &str) -> Self::ListFuture;
+
+    /// The changes of the instances of `appid`, the ones registered already
+    /// reported as Creates first, so that what is built from the events
+    /// starts out complete.
+    fn watch(&self, appid: &str) -> Self::Watcher;
+
+    /// Like `watch`, picking up where a previous watch of `appid` left off,
+    /// e.g. before a restart: `instances` are the instances it had reported,
+    /// as kept in a `snapshot::DiscoverySnapshot`. They are reported first,
+    /// right away, then only what changed since.
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher;
 }
 
+/// Discovers the instances reported by a watcher of `R`, or by another
+/// watcher `W`, e.g. one failing with a `DiscoverError`, see `with_watcher`.
+///
+/// The services are keyed by the identity `I` of their instance, by default
+/// `DefaultIdentity`, so that tower balances over every instance of the app.
+/// Key them otherwise with `with_identity`, e.g. by address:
+///
+/// ```ignore
+/// let discover = AppDiscover::with_identity(watcher, make_service, |ins: &Instance| {
+///     ins.addrs[0].clone()
+/// });
+/// ```
 #[pin_project]
-pub struct AppDiscover<SB, R>
+pub struct AppDiscover<SB, R, I = DefaultIdentity, W = <R as Registry>::Watcher>
 where
     R: Registry,
 {
     #[pin]
-    watcher: R::Watcher,
+    watcher: W,
     #[pin]
     service_creater: SB,
+    identity: I,
+    // the changes derived from watch events and not delivered yet.
+    pending: VecDeque<PendingChange>,
+    filter: Option<Box<FilterFn>>,
+    _registry: PhantomData<fn() -> R>,
+}
+
+type FilterFn = dyn Fn(&Instance) -> bool + Send + Sync;
+
+enum PendingChange {
+    Insert(Arc<Instance>),
+    Remove(Arc<Instance>),
+}
+
+impl PendingChange {
+    // the changes `event` implies, in order.
+    fn push_all(pending: &mut VecDeque<PendingChange>, event: Event, filter: Option<&FilterFn>) {
+        let accepts = |ins: &Instance| filter.is_none_or(|filter| filter(ins));
+        match event {
+            Event::Create(ins) if !accepts(&ins) => {}
+            // updated to be rejected, e.g. moved to another zone.
+            Event::Update(ins) if !accepts(&ins) => pending.push_back(PendingChange::Remove(ins)),
+            // inserting again replaces the service of the instance.
+            Event::Create(ins) | Event::Update(ins) => {
+                pending.push_back(PendingChange::Insert(ins))
+            }
+            Event::Delete(ins) => pending.push_back(PendingChange::Remove(ins)),
+        }
+    }
 }
 
 impl<SB, R> AppDiscover<SB, R>
 where
     R: Registry,
 {
-    pub fn new<W>(watcher: R::Watcher, service_creater: SB) -> Self {
+    pub fn new(watcher: R::Watcher, service_creater: SB) -> Self {
+        Self::with_identity(watcher, service_creater, DefaultIdentity)
+    }
+}
+
+impl<SB, R, I> AppDiscover<SB, R, I>
+where
+    R: Registry,
+{
+    pub fn with_identity(watcher: R::Watcher, service_creater: SB, identity: I) -> Self {
+        Self::with_watcher(watcher, service_creater, identity)
+    }
+}
+
+impl<SB, R, I, W> AppDiscover<SB, R, I, W>
+where
+    R: Registry,
+{
+    /// Discovers from a watcher of results, whose errors `poll_discover`
+    /// returns, e.g. `zk::ZkWatcher::fallible` or a watcher of `R` mapped to
+    /// fail on what it reports.
+    pub fn with_watcher(watcher: W, service_creater: SB, identity: I) -> Self {
         Self {
             watcher,
             service_creater,
+            identity,
+            pending: VecDeque::new(),
+            filter: None,
+            _registry: PhantomData,
         }
     }
+
+    /// Skips the instances `filter` rejects before their services are
+    /// created, e.g. of other zones or of incompatible versions. Instances
+    /// updated to be rejected are removed.
+    pub fn with_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&Instance) -> bool + Send + Sync + 'static,
+    {
+        self.filter = Some(Box::new(filter));
+        self
+    }
 }
 
-impl<SB, R, S> Discover for AppDiscover<SB, R>
+impl<SB, R, I, W, S> Discover for AppDiscover<SB, R, I, W>
 where
     R: Registry,
     SB: Fn(&Instance) -> S,
+    I: Identity,
+    W: Stream,
+    W::Item: WatchItem,
 {
-    type Key = String;
+    type Key = I::Key;
     type Service = S;
-    type Error = Terminated;
+    type Error = DiscoverError;
 
     fn poll_discover(
-        mut self: std::pin::Pin<&mut Self>,
+        self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<Change<Self::Key, Self::Service>, Self::Error>> {
-        self.as_mut()
-            .project()
-            .watcher
-            .poll_next(cx)
-            .map(|watch_event_opt| match watch_event_opt {
-                Some(watch_event) => match watch_event.event {
-                    Event::Create(ins) => Ok(Change::Insert(
-                        ins.appid.clone(),
-                        (self.as_mut().project().service_creater)(&ins),
-                    )),
-                    Event::Delete(ins) => Ok(Change::Remove(ins.appid.clone())),
-                },
-                None => Err(Terminated),
-            })
+        let mut this = self.project();
+        loop {
+            // what is left of earlier events goes first, services are only
+            // created once their change is delivered.
+            match this.pending.pop_front() {
+                Some(PendingChange::Insert(ins)) => {
+                    let service = (this.service_creater)(&ins);
+                    return Poll::Ready(Ok(Change::Insert(this.identity.identify(&ins), service)));
+                }
+                Some(PendingChange::Remove(ins)) => {
+                    return Poll::Ready(Ok(Change::Remove(this.identity.identify(&ins))))
+                }
+                None => {}
+            }
+            match ready!(this.watcher.as_mut().poll_next(cx)).map(WatchItem::into_result) {
+                Some(Ok(watch_event)) => {
+                    PendingChange::push_all(this.pending, watch_event.event, this.filter.as_deref())
+                }
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                None => return Poll::Ready(Err(DiscoverError::Terminated)),
+            }
+        }
     }
 }
 
-pub struct Terminated;
+/// Why `AppDiscover` stopped discovering.
+#[derive(Debug)]
+pub enum DiscoverError {
+    /// The watcher ended, e.g. its registry was dropped.
+    Terminated,
+    /// The watcher failed on its registry.
+    Registry(Box<dyn Error + Send + Sync>),
+    /// The watcher failed to decode an instance.
+    Decode(DecodeError),
+    /// The watcher a `lifecycle::RegistrationState` follows failed, with
+    /// its error, shared by the clones of the state.
+    Registration(Arc<DiscoverError>),
+}
+
+impl DiscoverError {
+    pub fn registry<E>(e: E) -> Self
+    where
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        DiscoverError::Registry(e.into())
+    }
+}
+
+impl fmt::Display for DiscoverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoverError::Terminated => write!(f, "the watcher terminated"),
+            DiscoverError::Registry(e) => write!(f, "the watcher failed: {}", e),
+            DiscoverError::Decode(e) => e.fmt(f),
+            DiscoverError::Registration(e) => write!(f, "the registration state failed: {}", e),
+        }
+    }
+}
+
+impl Error for DiscoverError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DiscoverError::Terminated => None,
+            DiscoverError::Registry(e) => Some(&**e),
+            DiscoverError::Decode(e) => Some(e),
+            DiscoverError::Registration(e) => Some(&**e),
+        }
+    }
+}
+
+impl From<DecodeError> for DiscoverError {
+    fn from(e: DecodeError) -> Self {
+        DiscoverError::Decode(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AppDiscover, DiscoverError, Instance, Registry};
+    use crate::{
+        codec::DecodeError,
+        identity::DefaultIdentity,
+        testing::MockRegistry,
+        watcher::{Event, WatchEvent},
+    };
+    use futures::{future::poll_fn, stream};
+    use std::{pin::Pin, sync::Arc};
+    use tower::discover::{Change, Discover};
+
+    async fn next<D>(discover: &mut D) -> Result<Change<D::Key, D::Service>, D::Error>
+    where
+        D: Discover + Unpin,
+    {
+        poll_fn(|cx| Pin::new(&mut *discover).poll_discover(cx)).await
+    }
+
+    #[tokio::test]
+    async fn test_app_discover() {
+        let registry = MockRegistry::new();
+        for addr in &["grpc://172.1.1.1:9999", "grpc://172.1.1.2:9999"] {
+            registry.insert(Instance {
+                appid: "provider".into(),
+                addrs: vec![addr.to_string()].into(),
+                ..Default::default()
+            });
+        }
+        let make_service = |ins: &Instance| ins.addrs[0].clone();
+        let mut discover =
+            AppDiscover::<_, MockRegistry>::new(registry.watch("provider"), make_service);
+        let mut keys = Vec::new();
+        let mut inserted = Vec::new();
+        for _ in 0..2 {
+            match next(&mut discover).await {
+                Ok(Change::Insert(key, service)) => {
+                    keys.push(key);
+                    inserted.push(service);
+                }
+                _ => panic!("expected an insert"),
+            }
+        }
+        // both instances of the app are balanced over.
+        assert_ne!(keys[0], keys[1]);
+        inserted.sort();
+        assert_eq!(
+            inserted,
+            vec!["grpc://172.1.1.1:9999", "grpc://172.1.1.2:9999"]
+        );
+
+        registry.remove(&Arc::new(Instance {
+            appid: "provider".into(),
+            addrs: vec!["grpc://172.1.1.1:9999".to_owned()].into(),
+            ..Default::default()
+        }));
+        assert!(matches!(next(&mut discover).await, Ok(Change::Remove(_))));
+        drop(registry);
+        assert!(matches!(
+            next(&mut discover).await,
+            Err(DiscoverError::Terminated)
+        ));
+
+        let ins = Arc::new(Instance {
+            appid: "provider".into(),
+            addrs: vec!["grpc://172.1.1.1:9999".to_owned()].into(),
+            ..Default::default()
+        });
+        let watcher = stream::iter(vec![
+            Ok(WatchEvent::new(Event::Create(ins))),
+            Err(DecodeError::new("bad payload")),
+        ]);
+        let mut discover = AppDiscover::<_, MockRegistry, _, _>::with_watcher(
+            watcher,
+            |ins: &Instance| ins.addrs[0].clone(),
+            DefaultIdentity,
+        );
+        assert!(matches!(
+            next(&mut discover).await,
+            Ok(Change::Insert(_, _))
+        ));
+        assert!(matches!(
+            next(&mut discover).await,
+            Err(DiscoverError::Decode(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_app_discover_filter() {
+        let instance = |addr: &str, zone: &str| {
+            Arc::new(Instance {
+                zone: zone.into(),
+                appid: "provider".into(),
+                addrs: vec![addr.to_owned()].into(),
+                ..Default::default()
+            })
+        };
+        let watcher = stream::iter(vec![
+            WatchEvent::new(Event::Create(instance("grpc://172.1.1.2:9999", "sh2"))),
+            WatchEvent::new(Event::Create(instance("grpc://172.1.1.1:9999", "sh1"))),
+            WatchEvent::new(Event::Update(instance("grpc://172.1.1.1:9999", "sh2"))),
+        ]);
+        let mut discover = AppDiscover::<_, MockRegistry, _, _>::with_watcher(
+            watcher,
+            |ins: &Instance| ins.addrs[0].clone(),
+            DefaultIdentity,
+        )
+        .with_filter(|ins| &*ins.zone == "sh1");
+        match next(&mut discover).await {
+            Ok(Change::Insert(_, service)) => assert_eq!(service, "grpc://172.1.1.1:9999"),
+            _ => panic!("expected an insert"),
+        }
+        assert!(matches!(next(&mut discover).await, Ok(Change::Remove(_))));
+        assert!(matches!(
+            next(&mut discover).await,
+            Err(DiscoverError::Terminated)
+        ));
+    }
+
+    #[test]
+    fn test_addr_for_scheme() {
+        let ins = Instance {
+            addrs: vec![
+                "http://172.1.1.1:8000".to_owned(),
+                "grpc://172.1.1.1:9999".to_owned(),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        assert_eq!(ins.addr_for_scheme("grpc"), Some("172.1.1.1:9999"));
+        assert_eq!(ins.addr_for_scheme("http"), Some("172.1.1.1:8000"));
+        assert_eq!(ins.addr_for_scheme("h"), None);
+    }
+
+    #[tokio::test]
+    async fn test_register_all() {
+        let registry = MockRegistry::new();
+        let instances = ["grpc://172.1.1.1:9999", "grpc://172.1.1.2:9999"]
+            .iter()
+            .map(|addr| {
+                Arc::new(Instance {
+                    appid: "provider".into(),
+                    addrs: vec![addr.to_string()].into(),
+                    ..Default::default()
+                })
+            })
+            .collect::<Vec<_>>();
+        registry.register_all(instances.clone()).await.unwrap();
+        assert_eq!(registry.registered("provider").len(), 2);
+
+        // the others are deregistered still.
+        registry.fail_deregister("zk is down");
+        assert!(registry.deregister_all(&instances).await.is_err());
+        registry.assert_registered(&instances[0]);
+        registry.assert_not_registered(&instances[1]);
+    }
+}
@@ -0,0 +1,551 @@
+use crate::{
+    identity::{DefaultIdentity, Identity},
+    runtime::{self, delay_for, AbortOnDrop},
+    watcher::{Event, WatchItem},
+    DiscoverError, Instance, Registry,
+};
+use futures::{
+    future::{self, BoxFuture, Either},
+    pin_mut, Future, FutureExt, Stream, StreamExt,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::watch;
+
+const DEFAULT_READY_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_DRAIN: Duration = Duration::from_secs(10);
+const DEFAULT_WARM_UP_STEPS: u32 = 10;
+// what an instance without a weight ramps up to, the usual dubbo default.
+const DEFAULT_TARGET_WEIGHT: u32 = 100;
+
+/// Registers a service at the right moment and takes it out gracefully.
+///
+/// `serve` waits for the readiness check to pass before registering, keeps
+/// the instance registered until `shutdown` resolves, then deregisters it and
+/// waits `drain` so clients still holding the instance finish their requests
+/// before the service stops.
+///
+/// With `warm_up` the instance is first registered with a fraction of its
+/// `weight` metadata, which is raised step by step to the full weight over the
+/// warm-up duration. Every step updates the registration in place with
+/// `update_if`, or where the registry can't, registers the instance with the
+/// new weight and then deregisters the previous one. Once a step fails, the
+/// instance is deregistered and `serve` fails.
+///
+/// ```ignore
+/// Lifecycle::new()
+///     .readiness(|| async { db.ping().await.is_ok() })
+///     .drain(Duration::from_secs(15))
+///     .serve(&zk, instance, tokio::signal::ctrl_c().map(|_| ()))
+///     .await?;
+/// server.stop().await;
+/// ```
+pub struct Lifecycle {
+    readiness: Box<dyn FnMut() -> BoxFuture<'static, bool> + Send>,
+    ready_interval: Duration,
+    drain: Duration,
+    warm_up: Option<Duration>,
+    warm_up_steps: u32,
+}
+
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Lifecycle {
+    pub fn new() -> Self {
+        Lifecycle {
+            readiness: Box::new(|| future::ready(true).boxed()),
+            ready_interval: DEFAULT_READY_INTERVAL,
+            drain: DEFAULT_DRAIN,
+            warm_up: None,
+            warm_up_steps: DEFAULT_WARM_UP_STEPS,
+        }
+    }
+
+    /// The check to pass before registering, retried every `ready_interval`.
+    pub fn readiness<F, Fut>(mut self, mut readiness: F) -> Self
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        self.readiness = Box::new(move || readiness().boxed());
+        self
+    }
+
+    pub fn ready_interval(mut self, ready_interval: Duration) -> Self {
+        self.ready_interval = ready_interval;
+        self
+    }
+
+    /// How long to keep serving after deregistering.
+    pub fn drain(mut self, drain: Duration) -> Self {
+        self.drain = drain;
+        self
+    }
+
+    /// Ramps the weight of the new instance up over `warm_up`.
+    pub fn warm_up(mut self, warm_up: Duration) -> Self {
+        self.warm_up = Some(warm_up);
+        self
+    }
+
+    /// The number of weight updates of the warm-up, 10 unless configured.
+    pub fn warm_up_steps(mut self, warm_up_steps: u32) -> Self {
+        self.warm_up_steps = warm_up_steps.max(1);
+        self
+    }
+
+    /// Resolves once the instance is deregistered and drained, or right away
+    /// when `shutdown` resolves before the service got ready.
+    pub async fn serve<R, S>(
+        mut self,
+        registry: &R,
+        ins: Arc<Instance>,
+        shutdown: S,
+    ) -> Result<(), R::Error>
+    where
+        R: Registry,
+        S: Future<Output = ()>,
+    {
+        pin_mut!(shutdown);
+        loop {
+            match future::select((self.readiness)(), &mut shutdown).await {
+                Either::Left((true, _)) => break,
+                Either::Left((false, _)) => {}
+                Either::Right(_) => return Ok(()),
+            }
+            let retry = delay_for(self.ready_interval);
+            pin_mut!(retry);
+            if let Either::Right(_) = future::select(retry, &mut shutdown).await {
+                return Ok(());
+            }
+        }
+
+        let (warm_up, steps) = match self.warm_up {
+            Some(warm_up) => (warm_up, self.warm_up_steps),
+            None => (Duration::default(), 1),
+        };
+        let target = ins.weight().unwrap_or(DEFAULT_TARGET_WEIGHT);
+        let mut current = ins;
+        if steps > 1 {
+            current = with_weight(&current, ramp(target, 1, steps));
+        }
+        registry.register(current.clone()).await?;
+
+        let mut shut_down = false;
+        for step in 2..=steps {
+            let next_step = delay_for(warm_up / steps);
+            pin_mut!(next_step);
+            if let Either::Right(_) = future::select(next_step, &mut shutdown).await {
+                shut_down = true;
+                break;
+            }
+            let weight = ramp(target, step, steps);
+            if current.weight() == Some(weight) {
+                continue;
+            }
+            let next = with_weight(&current, weight);
+            if let Err(e) = reweigh(registry, &current, next.clone()).await {
+                // whichever of the two is registered.
+                let _ = registry.deregister(&next).await;
+                let _ = registry.deregister(&current).await;
+                return Err(e);
+            }
+            current = next;
+        }
+        if !shut_down {
+            shutdown.await;
+        }
+
+        registry.deregister(&current).await?;
+        delay_for(self.drain).await;
+        Ok(())
+    }
+}
+
+/// Whether an instance is currently registered, as seen by watching its app.
+///
+/// Starts not ready and becomes ready once the registry reports the instance,
+/// going back to not ready if it disappears, e.g. when a session expires. Meant
+/// for health endpoints, so traffic only comes once registration succeeded.
+///
+/// ```ignore
+/// let state = RegistrationState::new(&zk, "/dubbo-rs/provider", &instance);
+/// health.set_check(move || state.is_ready());
+/// ```
+#[derive(Clone)]
+pub struct RegistrationState {
+    rx: watch::Receiver<bool>,
+    // why the watcher stopped, when it failed.
+    failure: Arc<Mutex<Option<Arc<DiscoverError>>>>,
+    _task: Arc<AbortOnDrop>,
+}
+
+impl RegistrationState {
+    pub fn new<R>(registry: &R, appid: &str, ins: &Instance) -> Self
+    where
+        R: Registry,
+        R::Watcher: Send + 'static,
+    {
+        Self::with_identity(registry, appid, ins, DefaultIdentity)
+    }
+
+    /// Decides which reported instance is `ins` with `identity`.
+    pub fn with_identity<R, I>(registry: &R, appid: &str, ins: &Instance, identity: I) -> Self
+    where
+        R: Registry,
+        R::Watcher: Send + 'static,
+        I: Identity + Send + 'static,
+        I::Key: Send,
+    {
+        Self::with_watcher(registry.watch(appid), ins, identity)
+    }
+
+    /// Follows `watcher` of the app of `ins`, e.g. one failing with a
+    /// `DiscoverError`, whose failure `ready` and `changed` then return as
+    /// `DiscoverError::Registration`.
+    pub fn with_watcher<W, I>(watcher: W, ins: &Instance, identity: I) -> Self
+    where
+        W: Stream + Send + 'static,
+        W::Item: WatchItem,
+        I: Identity + Send + 'static,
+        I::Key: Send,
+    {
+        let (tx, rx) = watch::channel(false);
+        let key = identity.identify(ins);
+        let failure = Arc::new(Mutex::new(None));
+        let failed = failure.clone();
+        let task = async move {
+            pin_mut!(watcher);
+            let mut registered = false;
+            while let Some(item) = watcher.next().await {
+                let watch_event = match item.into_result() {
+                    Ok(watch_event) => watch_event,
+                    Err(e) => {
+                        *failed.lock().unwrap() = Some(Arc::new(e));
+                        return;
+                    }
+                };
+                let now = match &watch_event.event {
+                    Event::Create(ins) if identity.identify(ins) == key => true,
+                    Event::Delete(ins) if identity.identify(ins) == key => false,
+                    _ => continue,
+                };
+                if now != registered {
+                    registered = now;
+                    if tx.broadcast(registered).is_err() {
+                        return;
+                    }
+                }
+            }
+        };
+        RegistrationState {
+            rx,
+            failure,
+            _task: Arc::new(runtime::spawn_abortable(task)),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Waits until the instance is registered, failing with
+    /// `DiscoverError::Terminated` once the watcher ends.
+    pub async fn ready(&mut self) -> Result<(), DiscoverError> {
+        while !self.is_ready() {
+            self.changed().await?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the state to change and returns the new one.
+    pub async fn changed(&mut self) -> Result<bool, DiscoverError> {
+        let current = self.is_ready();
+        loop {
+            match self.rx.recv().await {
+                Some(state) if state != current => return Ok(state),
+                Some(_) => {}
+                None => {
+                    return Err(match &*self.failure.lock().unwrap() {
+                        Some(e) => DiscoverError::Registration(e.clone()),
+                        None => DiscoverError::Terminated,
+                    })
+                }
+            }
+        }
+    }
+}
+
+/// Publishes a changed registration: the new one is registered before the old
+/// one goes, so watchers never see the service without instances.
+pub(crate) async fn replace<R>(
+    registry: &R,
+    old: &Arc<Instance>,
+    new: Arc<Instance>,
+) -> Result<(), R::Error>
+where
+    R: Registry,
+{
+    registry.register(new).await?;
+    registry.deregister(old).await
+}
+
+// updates `current` to `next` in place at the revision listed, falling back
+// to `replace` for registries that can't, e.g. zk with names as payloads.
+async fn reweigh<R>(
+    registry: &R,
+    current: &Arc<Instance>,
+    next: Arc<Instance>,
+) -> Result<(), R::Error>
+where
+    R: Registry,
+{
+    let key = DefaultIdentity.identify(current);
+    let listed = registry.list(&current.appid).await?;
+    if let Some(registered) = listed
+        .iter()
+        .find(|ins| DefaultIdentity.identify(ins) == key)
+    {
+        let revision = registered.revision.unwrap_or(0);
+        if registry.update_if(next.clone(), revision).await.is_ok() {
+            return Ok(());
+        }
+    }
+    replace(registry, current, next).await
+}
+
+fn ramp(target: u32, step: u32, steps: u32) -> u32 {
+    ((target as u64 * step as u64 / steps as u64) as u32).max(1)
+}
+
+fn with_weight(ins: &Instance, weight: u32) -> Arc<Instance> {
+    let mut ins = ins.clone();
+    ins.metadata.insert("weight".to_owned(), weight.to_string());
+    Arc::new(ins)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Lifecycle, RegistrationState};
+    use crate::{
+        codec::DecodeError,
+        identity::DefaultIdentity,
+        testing::{instance, Call, MockRegistry},
+        watcher::{Event, WatchEvent},
+        DiscoverError, Instance, Registry,
+    };
+    use futures::{
+        channel::{mpsc, oneshot},
+        future, stream, FutureExt, StreamExt,
+    };
+    use std::{
+        error::Error,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    #[tokio::test]
+    async fn test_registration_state() {
+        let registry = MockRegistry::new();
+        let ins = instance("grpc://172.1.1.1:9999");
+        let other = instance("grpc://172.1.1.2:9999");
+        let mut state = RegistrationState::new(&registry, "provider", &ins);
+        assert!(!state.is_ready());
+
+        registry.insert(other.clone());
+        registry.insert(ins.clone());
+        state.ready().await.unwrap();
+        assert!(state.is_ready());
+
+        registry.remove(&other);
+        registry.remove(&ins);
+        assert!(matches!(state.changed().await, Ok(false)));
+
+        drop(registry);
+        assert!(matches!(
+            state.changed().await,
+            Err(DiscoverError::Terminated)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_registration_state_failed() {
+        let ins = instance("grpc://172.1.1.1:9999");
+        let watcher = stream::iter(vec![
+            Ok(WatchEvent::new(Event::Create(ins.clone()))),
+            Err(DecodeError::new("bad payload")),
+        ]);
+        let mut state = RegistrationState::with_watcher(watcher, &ins, DefaultIdentity);
+        state.ready().await.unwrap();
+        let failed = state.changed().await.err().unwrap();
+        match &failed {
+            DiscoverError::Registration(e) => assert!(matches!(**e, DiscoverError::Decode(_))),
+            e => panic!("unexpected {:?}", e),
+        }
+        assert!(failed.source().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_serve() {
+        tokio::time::pause();
+        let registry = MockRegistry::new();
+        let checks = Arc::new(AtomicUsize::new(0));
+        let counter = checks.clone();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let lifecycle = Lifecycle::new()
+            .readiness(move || future::ready(counter.fetch_add(1, Ordering::SeqCst) >= 2))
+            .ready_interval(Duration::from_millis(10))
+            .drain(Duration::from_millis(100));
+        let serving = {
+            let registry = registry.clone();
+            let ins = instance("grpc://172.1.1.1:9999");
+            tokio::spawn(async move {
+                lifecycle
+                    .serve(&registry, ins, shutdown_rx.map(|_| ()))
+                    .await
+            })
+        };
+
+        while registry.calls().is_empty() {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        assert_eq!(checks.load(Ordering::SeqCst), 3);
+        registry.assert_registered(&instance("grpc://172.1.1.1:9999"));
+        let shutdown_at = tokio::time::Instant::now();
+        shutdown_tx.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+        assert!(shutdown_at.elapsed() >= Duration::from_millis(100));
+        assert_eq!(
+            registry.calls(),
+            vec![
+                Call::Register(instance("grpc://172.1.1.1:9999")),
+                Call::Deregister(instance("grpc://172.1.1.1:9999")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_warm_up() {
+        tokio::time::pause();
+        let registry = MockRegistry::new();
+        let mut ins = Instance::clone(&instance("grpc://172.1.1.1:9999"));
+        ins.metadata.insert("weight".to_owned(), "40".to_owned());
+        let ins = Arc::new(ins);
+        let mut watcher = registry.watch("provider");
+        Lifecycle::new()
+            .warm_up(Duration::from_millis(40))
+            .warm_up_steps(4)
+            .drain(Duration::default())
+            .serve(
+                &registry,
+                ins,
+                tokio::time::delay_for(Duration::from_millis(100)),
+            )
+            .await
+            .unwrap();
+
+        let calls = registry
+            .calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::Register(ins) => Some(("register", ins.weight().unwrap())),
+                Call::Update(ins, _) => Some(("update", ins.weight().unwrap())),
+                Call::Deregister(ins) => Some(("deregister", ins.weight().unwrap())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            calls,
+            vec![
+                ("register", 10),
+                ("update", 20),
+                ("update", 30),
+                ("update", 40),
+                ("deregister", 40),
+            ]
+        );
+        // watchers only see the weight going up, and the final removal.
+        let mut events = Vec::new();
+        for _ in 0..5 {
+            events.push(watcher.next().await.unwrap().event);
+        }
+        assert!(matches!(events[0], Event::Create(_)));
+        assert!(events[1..4]
+            .iter()
+            .all(|event| matches!(event, Event::Update(_))));
+        assert!(matches!(events[4], Event::Delete(_)));
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_warm_up_failed() {
+        tokio::time::pause();
+        let registry = MockRegistry::new();
+        let mut ins = Instance::clone(&instance("grpc://172.1.1.1:9999"));
+        ins.metadata.insert("weight".to_owned(), "40".to_owned());
+        let serving = {
+            let (registry, ins) = (registry.clone(), Arc::new(ins));
+            tokio::spawn(async move {
+                Lifecycle::new()
+                    .warm_up(Duration::from_millis(40))
+                    .warm_up_steps(2)
+                    .serve(&registry, ins, future::pending())
+                    .await
+            })
+        };
+        while registry.calls().is_empty() {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        // neither in place nor by registering the new weight.
+        registry.fail_register("update");
+        registry.fail_register("register");
+        assert!(serving.await.unwrap().is_err());
+
+        let calls = registry
+            .calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::Register(ins) => Some(("register", ins.weight().unwrap())),
+                Call::Update(ins, _) => Some(("update", ins.weight().unwrap())),
+                Call::Deregister(ins) => Some(("deregister", ins.weight().unwrap())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            calls,
+            vec![
+                ("register", 20),
+                ("update", 40),
+                ("register", 40),
+                ("deregister", 40),
+                ("deregister", 20),
+            ]
+        );
+        assert!(registry.registered("provider").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_shutdown_before_ready() {
+        let registry = MockRegistry::new();
+        let (shutdown_tx, shutdown_rx) = mpsc::unbounded::<()>();
+        shutdown_tx.close_channel();
+        Lifecycle::new()
+            .readiness(|| future::ready(false))
+            .serve(
+                &registry,
+                Arc::new(Instance::default()),
+                shutdown_rx.into_future().map(|_| ()),
+            )
+            .await
+            .unwrap();
+        assert!(registry.calls().is_empty());
+    }
+}
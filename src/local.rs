@@ -0,0 +1,348 @@
+use crate::{intern, lifecycle, Instance, Registry};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+type IpFilter = dyn Fn(&IpAddr) -> bool + Send + Sync;
+
+/// Builds the `Instance` of the running service.
+///
+/// The hostname is detected, and so is the IP to advertise unless a listener
+/// is bound to a specific one: the first non-loopback address, IPv4 first,
+/// optionally restricted to an interface or to the addresses accepted by
+/// `prefer`. Each listener becomes an address `scheme://ip:port`.
+///
+/// ```ignore
+/// let grpc = TcpListener::bind("0.0.0.0:0").await?;
+/// let ins = LocalInstance::new("/dubbo-rs/provider")
+///     .env("prod")
+///     .interface("eth0")
+///     .listener("grpc", grpc.local_addr()?)
+///     .build()?;
+/// zk.register(Arc::new(ins)).await?;
+/// ```
+#[derive(Clone)]
+pub struct LocalInstance {
+    ins: Instance,
+    listeners: Vec<(String, SocketAddr)>,
+    interface: Option<String>,
+    prefer: Option<Arc<IpFilter>>,
+}
+
+impl LocalInstance {
+    pub fn new(appid: impl Into<String>) -> Self {
+        LocalInstance {
+            ins: Instance {
+                appid: intern(&appid.into()),
+                ..Default::default()
+            },
+            listeners: Vec::new(),
+            interface: None,
+            prefer: None,
+        }
+    }
+
+    pub fn zone(mut self, zone: impl Into<String>) -> Self {
+        self.ins.zone = intern(&zone.into());
+        self
+    }
+
+    pub fn env(mut self, env: impl Into<String>) -> Self {
+        self.ins.env = intern(&env.into());
+        self
+    }
+
+    pub fn version(mut self, version: impl Into<String>) -> Self {
+        self.ins.version = version.into();
+        self
+    }
+
+    /// The traffic group it serves, see `Instance::group`.
+    pub fn group(mut self, group: impl Into<String>) -> Self {
+        self.ins.group = intern(&group.into());
+        self
+    }
+
+    /// Overrides the detected hostname.
+    pub fn hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.ins.hostname = hostname.into();
+        self
+    }
+
+    pub fn metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.ins.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    /// Advertises a bound listener, e.g. `listener("grpc", listener.local_addr()?)`.
+    pub fn listener(mut self, scheme: impl Into<String>, addr: SocketAddr) -> Self {
+        self.listeners.push((scheme.into(), addr));
+        self
+    }
+
+    /// Only advertise IPs of this interface, e.g. `eth0`.
+    pub fn interface(mut self, interface: impl Into<String>) -> Self {
+        self.interface = Some(interface.into());
+        self
+    }
+
+    /// Only advertise IPs accepted by `prefer`, e.g. the ones in the service network.
+    pub fn prefer<F>(mut self, prefer: F) -> Self
+    where
+        F: Fn(&IpAddr) -> bool + Send + Sync + 'static,
+    {
+        self.prefer = Some(Arc::new(prefer));
+        self
+    }
+
+    pub fn build(self) -> io::Result<Instance> {
+        let mut ins = self.ins;
+        if ins.hostname.is_empty() {
+            ins.hostname = hostname::get()?.to_string_lossy().into_owned();
+        }
+
+        let mut detected = None;
+        for (scheme, addr) in self.listeners.iter() {
+            let ip = if addr.ip().is_unspecified() {
+                if detected.is_none() {
+                    let candidates = if_addrs::get_if_addrs()?
+                        .into_iter()
+                        .map(|iface| (iface.name.clone(), iface.ip()))
+                        .collect::<Vec<_>>();
+                    detected = Some(pick_ip(
+                        &candidates,
+                        self.interface.as_deref(),
+                        self.prefer.as_deref(),
+                    )?);
+                }
+                detected.unwrap()
+            } else {
+                addr.ip()
+            };
+            ins.addrs
+                .push(format!("{}://{}", scheme, SocketAddr::new(ip, addr.port())));
+        }
+        Ok(ins)
+    }
+}
+
+/// Keeps the registration of the running service in sync with its listeners.
+///
+/// Listeners can come and go at runtime, e.g. a metrics endpoint bound to
+/// port 0 later on. Every change publishes the instance with the new
+/// addresses, registering it before deregistering the previous one; with no
+/// listener left the instance is deregistered.
+///
+/// ```ignore
+/// let mut advertiser = Advertiser::new(zk, LocalInstance::new("/dubbo-rs/provider"));
+/// advertiser.add_listener("grpc", grpc.local_addr()?).await?;
+/// advertiser.add_listener("metrics", metrics.local_addr()?).await?;
+/// advertiser.remove_listener("metrics").await?;
+/// ```
+pub struct Advertiser<R> {
+    registry: R,
+    local: LocalInstance,
+    published: Option<Arc<Instance>>,
+}
+
+#[derive(Debug)]
+pub enum AdvertiseError<E> {
+    Detect(io::Error),
+    Registry(E),
+}
+
+impl<R> Advertiser<R>
+where
+    R: Registry,
+{
+    /// `local` describes the instance, with any listener known up front.
+    /// Nothing is registered until the first change, or `publish`.
+    pub fn new(registry: R, local: LocalInstance) -> Self {
+        Advertiser {
+            registry,
+            local,
+            published: None,
+        }
+    }
+
+    /// The currently registered instance.
+    pub fn instance(&self) -> Option<&Instance> {
+        self.published.as_deref()
+    }
+
+    pub async fn add_listener(
+        &mut self,
+        scheme: impl Into<String>,
+        addr: SocketAddr,
+    ) -> Result<(), AdvertiseError<R::Error>> {
+        self.local.listeners.push((scheme.into(), addr));
+        self.publish().await
+    }
+
+    /// Removes the listeners advertised with `scheme`.
+    pub async fn remove_listener(&mut self, scheme: &str) -> Result<(), AdvertiseError<R::Error>> {
+        self.local.listeners.retain(|(s, _)| s != scheme);
+        self.publish().await
+    }
+
+    /// Registers the instance as currently described, if it changed.
+    pub async fn publish(&mut self) -> Result<(), AdvertiseError<R::Error>> {
+        let ins = Arc::new(self.local.clone().build().map_err(AdvertiseError::Detect)?);
+        let result = match (&self.published, ins.addrs.is_empty()) {
+            (Some(published), _) if *published == ins => return Ok(()),
+            (None, true) => return Ok(()),
+            (None, false) => self.registry.register(ins.clone()).await,
+            (Some(published), true) => self.registry.deregister(published).await,
+            (Some(published), false) => {
+                lifecycle::replace(&self.registry, published, ins.clone()).await
+            }
+        };
+        result.map_err(AdvertiseError::Registry)?;
+        self.published = if ins.addrs.is_empty() {
+            None
+        } else {
+            Some(ins)
+        };
+        Ok(())
+    }
+
+    /// Deregisters the instance.
+    pub async fn withdraw(&mut self) -> Result<(), R::Error> {
+        if let Some(published) = self.published.take() {
+            self.registry.deregister(&published).await?;
+        }
+        Ok(())
+    }
+}
+
+fn pick_ip(
+    candidates: &[(String, IpAddr)],
+    interface: Option<&str>,
+    prefer: Option<&IpFilter>,
+) -> io::Result<IpAddr> {
+    let mut usable = candidates
+        .iter()
+        .filter(|(name, _)| !matches!(interface, Some(interface) if interface != name))
+        .map(|(_, ip)| *ip)
+        .filter(|ip| !ip.is_loopback() && !ip.is_unspecified() && !is_link_local(ip))
+        .filter(|ip| !matches!(prefer, Some(prefer) if !prefer(ip)))
+        .collect::<Vec<IpAddr>>();
+    usable.sort_by_key(|ip| ip.is_ipv6());
+    usable.into_iter().next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "no usable ip, interface {:?}, candidates {:?}",
+                interface, candidates
+            ),
+        )
+    })
+}
+
+fn is_link_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pick_ip, Advertiser, LocalInstance};
+    use crate::testing::{Call, MockRegistry};
+    use std::net::IpAddr;
+
+    fn candidates() -> Vec<(String, IpAddr)> {
+        vec![
+            ("lo".to_owned(), "127.0.0.1".parse().unwrap()),
+            ("eth0".to_owned(), "fe80::1".parse().unwrap()),
+            ("eth0".to_owned(), "2001:db8::1".parse().unwrap()),
+            ("eth0".to_owned(), "10.0.0.5".parse().unwrap()),
+            ("docker0".to_owned(), "172.17.0.1".parse().unwrap()),
+        ]
+    }
+
+    #[test]
+    fn test_pick_ip() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(pick_ip(&candidates(), None, None).unwrap(), ip("10.0.0.5"));
+        assert_eq!(
+            pick_ip(&candidates(), Some("docker0"), None).unwrap(),
+            ip("172.17.0.1")
+        );
+        let v6 = |ip: &IpAddr| ip.is_ipv6();
+        assert_eq!(
+            pick_ip(&candidates(), Some("eth0"), Some(&v6)).unwrap(),
+            ip("2001:db8::1")
+        );
+        assert!(pick_ip(&candidates(), Some("lo"), None).is_err());
+    }
+
+    #[test]
+    fn test_build() {
+        let ins = LocalInstance::new("provider")
+            .env("prod")
+            .hostname("provider-1")
+            .listener("grpc", "127.0.0.1:9999".parse().unwrap())
+            .listener("http", "[::1]:8000".parse().unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(ins.hostname, "provider-1");
+        assert_eq!(
+            ins.addrs.as_slice(),
+            ["grpc://127.0.0.1:9999", "http://[::1]:8000"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_advertiser() {
+        let local = LocalInstance::new("provider").hostname("provider-1");
+        let registry = MockRegistry::new();
+        let mut advertiser = Advertiser::new(registry.clone(), local);
+        advertiser
+            .add_listener("grpc", "127.0.0.1:9999".parse().unwrap())
+            .await
+            .unwrap();
+        advertiser
+            .add_listener("metrics", "127.0.0.1:9100".parse().unwrap())
+            .await
+            .unwrap();
+        advertiser.remove_listener("metrics").await.unwrap();
+        advertiser.remove_listener("grpc").await.unwrap();
+        assert!(advertiser.instance().is_none());
+
+        let grpc = || vec!["grpc://127.0.0.1:9999".to_owned()];
+        let both = || {
+            vec![
+                "grpc://127.0.0.1:9999".to_owned(),
+                "metrics://127.0.0.1:9100".to_owned(),
+            ]
+        };
+        let calls = registry
+            .calls()
+            .into_iter()
+            .map(|call| match call {
+                Call::Register(ins) => ("register", ins.addrs.to_vec()),
+                Call::Deregister(ins) => ("deregister", ins.addrs.to_vec()),
+                Call::Watch(appid) => ("watch", vec![appid]),
+                Call::Evict(appid, id) => ("evict", vec![appid, id]),
+                Call::Update(ins, _) => ("update", ins.addrs.to_vec()),
+                Call::List(appid) => ("list", vec![appid]),
+                Call::Renew(ins) => ("renew", ins.addrs.to_vec()),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            calls,
+            vec![
+                ("register", grpc()),
+                ("register", both()),
+                ("deregister", grpc()),
+                ("register", grpc()),
+                ("deregister", both()),
+                ("deregister", grpc()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,343 @@
+//! A registry kept in memory, for testing the code built on `AppDiscover`
+//! without a backend and for services discovering each other within a
+//! process.
+use crate::{
+    identity::{DefaultIdentity, Identity},
+    watcher::{Clock, DeleteReason, Event, SystemClock, WatchEvent},
+    Instance, Registry,
+};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future::{self, Ready},
+};
+use std::{
+    collections::HashMap,
+    error, fmt,
+    sync::{Arc, Mutex},
+};
+
+/// A `Registry` whose registrations live in process. Clones share them.
+///
+/// A registration replaces the one with the same identity, watchers get an
+/// Update for it, and `update_if` expects the revision of the registration,
+/// 0 unless set, then bumps it.
+///
+/// ```ignore
+/// let registry = MemRegistry::new();
+/// registry.register(Arc::new(provider)).await?;
+/// let discover = AppDiscover::new(registry.watch("provider"), make_service);
+/// ```
+#[derive(Clone)]
+pub struct MemRegistry<I = DefaultIdentity> {
+    inner: Arc<Mutex<Inner>>,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Default)]
+struct Inner {
+    apps: HashMap<String, Vec<Arc<Instance>>>,
+    watchers: HashMap<String, Vec<UnboundedSender<WatchEvent>>>,
+}
+
+impl MemRegistry {
+    pub fn new() -> Self {
+        MemRegistry {
+            inner: Default::default(),
+            identity: Arc::new(DefaultIdentity),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl Default for MemRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I> MemRegistry<I> {
+    /// Sets which registrations replace each other.
+    pub fn with_identity<NI>(self, identity: NI) -> MemRegistry<NI> {
+        MemRegistry {
+            inner: self.inner,
+            identity: Arc::new(identity),
+            clock: self.clock,
+        }
+    }
+
+    /// Sets the clock timestamping the events of watchers.
+    pub fn with_clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The instances registered for `appid`, in the order registered.
+    pub fn instances(&self, appid: &str) -> Vec<Arc<Instance>> {
+        let inner = self.inner.lock().unwrap();
+        inner.apps.get(appid).cloned().unwrap_or_default()
+    }
+}
+
+impl<I> MemRegistry<I>
+where
+    I: Identity,
+{
+    fn notify(&self, inner: &mut Inner, appid: &str, watch_event: WatchEvent) {
+        if let Some(watchers) = inner.watchers.get_mut(appid) {
+            watchers.retain(|tx| tx.unbounded_send(watch_event.clone()).is_ok());
+        }
+    }
+
+    // removes the registrations of `appid` matching `remove`.
+    fn remove<F>(&self, appid: &str, reason: DeleteReason, remove: F)
+    where
+        F: Fn(&Instance) -> bool,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        let instances = match inner.apps.get_mut(appid) {
+            Some(instances) => instances,
+            None => return,
+        };
+        let (removed, kept) = instances.drain(..).partition(|ins| remove(ins));
+        *instances = kept;
+        for ins in removed {
+            let event = WatchEvent::with_clock(Event::Delete(ins), &*self.clock);
+            self.notify(&mut inner, appid, event.with_reason(reason));
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemError {
+    /// `update_if` found no registration with the identity of the instance.
+    NotRegistered,
+    /// `update_if` found the registration at another revision.
+    Conflict { expected: u64, found: u64 },
+}
+
+impl fmt::Display for MemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemError::NotRegistered => write!(f, "the instance is not registered"),
+            MemError::Conflict { expected, found } => write!(
+                f,
+                "conflict, expected revision {} found {}",
+                expected, found
+            ),
+        }
+    }
+}
+
+impl error::Error for MemError {}
+
+impl<I> Registry for MemRegistry<I>
+where
+    I: Identity,
+{
+    type Error = MemError;
+
+    type RegFuture = Ready<Result<(), MemError>>;
+
+    type DeRegFuture = Ready<Result<(), MemError>>;
+
+    type ListFuture = Ready<Result<Vec<Arc<Instance>>, MemError>>;
+
+    type Watcher = UnboundedReceiver<WatchEvent>;
+
+    fn register(&self, ins: Arc<Instance>) -> Self::RegFuture {
+        let mut inner = self.inner.lock().unwrap();
+        let key = self.identity.identify(&ins);
+        let instances = inner.apps.entry(ins.appid.to_string()).or_default();
+        let event = match instances
+            .iter()
+            .position(|exist| self.identity.identify(exist) == key)
+        {
+            Some(pos) if instances[pos] == ins => return future::ok(()),
+            Some(pos) => {
+                let old = std::mem::replace(&mut instances[pos], ins.clone());
+                WatchEvent::with_clock(Event::Update(ins.clone()), &*self.clock).with_delta(&old)
+            }
+            None => {
+                instances.push(ins.clone());
+                WatchEvent::with_clock(Event::Create(ins.clone()), &*self.clock)
+            }
+        };
+        self.notify(&mut inner, &ins.appid, event);
+        future::ok(())
+    }
+
+    fn deregister(&self, ins: &Arc<Instance>) -> Self::DeRegFuture {
+        let key = self.identity.identify(ins);
+        self.remove(&ins.appid, DeleteReason::Deregistered, |exist| {
+            self.identity.identify(exist) == key
+        });
+        future::ok(())
+    }
+
+    fn evict(&self, appid: &str, instance_id: &str) -> Self::DeRegFuture {
+        self.remove(appid, DeleteReason::Evicted, |exist| {
+            exist.has_id(instance_id)
+        });
+        future::ok(())
+    }
+
+    fn update_if(&self, ins: Arc<Instance>, expected_version: u64) -> Self::RegFuture {
+        let mut inner = self.inner.lock().unwrap();
+        let key = self.identity.identify(&ins);
+        let instances = inner.apps.entry(ins.appid.to_string()).or_default();
+        let pos = match instances
+            .iter()
+            .position(|exist| self.identity.identify(exist) == key)
+        {
+            Some(pos) => pos,
+            None => return future::err(MemError::NotRegistered),
+        };
+        let found = instances[pos].revision.unwrap_or(0);
+        if found != expected_version {
+            return future::err(MemError::Conflict {
+                expected: expected_version,
+                found,
+            });
+        }
+        let updated = Arc::new(Instance {
+            revision: Some(found + 1),
+            ..Instance::clone(&ins)
+        });
+        let old = std::mem::replace(&mut instances[pos], updated.clone());
+        let event = WatchEvent::with_clock(Event::Update(updated), &*self.clock).with_delta(&old);
+        self.notify(&mut inner, &ins.appid, event);
+        future::ok(())
+    }
+
+    // registrations never expire, renewing only checks `ins` is registered.
+    fn renew(&self, ins: &Arc<Instance>) -> Self::RegFuture {
+        let key = self.identity.identify(ins);
+        let registered = self
+            .instances(&ins.appid)
+            .iter()
+            .any(|exist| self.identity.identify(exist) == key);
+        if registered {
+            future::ok(())
+        } else {
+            future::err(MemError::NotRegistered)
+        }
+    }
+
+    fn list(&self, appid: &str) -> Self::ListFuture {
+        future::ok(self.instances(appid))
+    }
+
+    fn watch(&self, appid: &str) -> Self::Watcher {
+        self.watch_from(appid, &[])
+    }
+
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher {
+        let mut inner = self.inner.lock().unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        let send = |event| {
+            let _ = tx.unbounded_send(WatchEvent::with_clock(event, &*self.clock));
+        };
+        let mut resumed = HashMap::new();
+        for ins in instances {
+            resumed.insert(self.identity.identify(ins), ins.clone());
+            send(Event::Create(ins.clone()));
+        }
+        let registered = inner.apps.get(appid).map(Vec::as_slice).unwrap_or_default();
+        for ins in registered {
+            match resumed.remove(&self.identity.identify(ins)) {
+                Some(old) if old == *ins => {}
+                _ => send(Event::Create(ins.clone())),
+            }
+        }
+        for (_, ins) in resumed {
+            send(Event::Delete(ins));
+        }
+        inner.watchers.entry(appid.to_owned()).or_default().push(tx);
+        rx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemError, MemRegistry};
+    use crate::{
+        testing::instance,
+        watcher::{DeleteReason, Event},
+        Instance, Registry,
+    };
+    use futures::StreamExt;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_mem_registry() {
+        let registry = MemRegistry::new();
+        let (a, b) = (
+            instance("grpc://10.0.0.1:9000"),
+            instance("grpc://10.0.0.2:9000"),
+        );
+        registry.register(a.clone()).await.unwrap();
+        let mut watcher = registry.watch("provider");
+        assert_eq!(
+            watcher.next().await.unwrap().event,
+            Event::Create(a.clone())
+        );
+
+        registry.register(b.clone()).await.unwrap();
+        assert_eq!(
+            watcher.next().await.unwrap().event,
+            Event::Create(b.clone())
+        );
+        registry.deregister(&a).await.unwrap();
+        let deleted = watcher.next().await.unwrap();
+        assert_eq!(deleted.event, Event::Delete(a.clone()));
+        assert_eq!(deleted.reason, Some(DeleteReason::Deregistered));
+        assert_eq!(registry.instances("provider"), vec![b.clone()]);
+
+        registry.update_if(b.clone(), 0).await.unwrap();
+        let updated = match watcher.next().await.unwrap().event {
+            Event::Update(ins) => ins,
+            event => panic!("unexpected {:?}", event),
+        };
+        assert_eq!(updated.revision, Some(1));
+        assert_eq!(
+            registry.update_if(b.clone(), 0).await,
+            Err(MemError::Conflict {
+                expected: 0,
+                found: 1
+            })
+        );
+        assert_eq!(
+            registry.update_if(a.clone(), 0).await,
+            Err(MemError::NotRegistered)
+        );
+
+        // resuming from what was seen only reports the changes.
+        let mut resumed = registry.watch_from("provider", &[a.clone(), updated.clone()]);
+        let events = vec![
+            resumed.next().await.unwrap().event,
+            resumed.next().await.unwrap().event,
+            resumed.next().await.unwrap().event,
+        ];
+        assert_eq!(
+            events,
+            [
+                Event::Create(a.clone()),
+                Event::Create(updated.clone()),
+                Event::Delete(a)
+            ]
+        );
+
+        // updates carry what changed.
+        let mut weighted = Instance::clone(&updated);
+        weighted
+            .metadata
+            .insert("weight".to_owned(), "10".to_owned());
+        registry.register(Arc::new(weighted)).await.unwrap();
+        let delta = watcher.next().await.unwrap().delta.unwrap();
+        assert_eq!(delta.metadata_changed, vec!["weight".to_owned()]);
+    }
+}
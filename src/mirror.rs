@@ -0,0 +1,283 @@
+//! Replays the registrations of one registry into another, to migrate
+//! between backends without downtime: mirror ZooKeeper into etcd, move the
+//! consumers to etcd, then the providers.
+//!
+//! ```ignore
+//! let mirror = Mirror::new(zk, etcd).app("billing").app("payment");
+//! runtime::spawn(async move { mirror.run().await });
+//! ```
+use crate::{
+    identity::{DefaultIdentity, Identity},
+    lifecycle, runtime,
+    watcher::{Event, WatchEvent},
+    Instance, Registry,
+};
+use futures::{
+    future,
+    stream::{self, StreamExt},
+};
+use log::error;
+use std::{collections::HashMap, fmt, sync::Arc, time::Duration};
+
+/// Mirrors the apps added from the source registry into the target, see the
+/// module docs.
+///
+/// Registries can't list their apps, the ones to mirror are added one by
+/// one. What the target registers lives as long as its session with the
+/// mirror, e.g. ephemeral znodes or leases.
+///
+/// Besides replaying the changes, every `interval`, 30s by default, the
+/// mirror registers again the instances missing or different in the target,
+/// after a failed call or someone else's deregistration. Instances only in
+/// the target are left alone unless pruned, they may be the ones registered
+/// to the target directly.
+pub struct Mirror<S, T, I = DefaultIdentity> {
+    source: S,
+    target: T,
+    apps: Vec<String>,
+    interval: Duration,
+    prune: bool,
+    identity: I,
+}
+
+impl<S, T> Mirror<S, T> {
+    pub fn new(source: S, target: T) -> Self {
+        Mirror {
+            source,
+            target,
+            apps: Vec::new(),
+            interval: Duration::from_secs(30),
+            prune: false,
+            identity: DefaultIdentity,
+        }
+    }
+}
+
+impl<S, T, I> Mirror<S, T, I> {
+    pub fn app(mut self, appid: &str) -> Self {
+        self.apps.push(appid.to_owned());
+        self
+    }
+
+    /// How often the target is reconciled with the source.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Whether reconciling deregisters the instances of the target missing
+    /// in the source.
+    pub fn prune(mut self, prune: bool) -> Self {
+        self.prune = prune;
+        self
+    }
+
+    /// Sets how instances of the source and the target are matched up.
+    pub fn with_identity<NI>(self, identity: NI) -> Mirror<S, T, NI> {
+        Mirror {
+            source: self.source,
+            target: self.target,
+            apps: self.apps,
+            interval: self.interval,
+            prune: self.prune,
+            identity,
+        }
+    }
+}
+
+enum Step {
+    // none once the source stopped watching.
+    Source(Option<WatchEvent>),
+    Target(WatchEvent),
+    Reconcile,
+}
+
+impl<S, T, I> Mirror<S, T, I>
+where
+    S: Registry,
+    T: Registry,
+    T::Error: fmt::Display,
+    I: Identity,
+{
+    /// Mirrors the apps until the watchers of the source end.
+    pub async fn run(&self) {
+        future::join_all(self.apps.iter().map(|appid| self.mirror(appid))).await;
+    }
+
+    async fn mirror(&self, appid: &str) {
+        let source = self
+            .source
+            .watch(appid)
+            .map(Some)
+            .chain(stream::once(future::ready(None)))
+            .map(Step::Source);
+        let target = self.target.watch(appid).map(Step::Target);
+        let interval = self.interval;
+        let ticks = stream::unfold((), move |()| async move {
+            runtime::delay_for(interval).await;
+            Some((Step::Reconcile, ()))
+        });
+        let steps = stream::select(source, stream::select(target, ticks));
+        futures::pin_mut!(steps);
+        let mut app = App {
+            source: HashMap::new(),
+            target: HashMap::new(),
+        };
+        while let Some(step) = steps.next().await {
+            match step {
+                Step::Source(None) => return,
+                Step::Source(Some(watch_event)) => match watch_event.event {
+                    Event::Create(ins) | Event::Update(ins) => {
+                        let key = self.identity.identify(&ins);
+                        self.sync(app.target.get(&key), &ins).await;
+                        app.source.insert(key, ins);
+                    }
+                    Event::Delete(ins) => {
+                        let key = self.identity.identify(&ins);
+                        app.source.remove(&key);
+                        // the target's own version, it may hold more.
+                        let registered = app.target.get(&key).cloned().unwrap_or(ins);
+                        self.deregister(&registered).await;
+                    }
+                },
+                Step::Target(watch_event) => match watch_event.event {
+                    Event::Create(ins) | Event::Update(ins) => {
+                        app.target.insert(self.identity.identify(&ins), ins);
+                    }
+                    Event::Delete(ins) => {
+                        // not when the version replacing it was reported first.
+                        let key = self.identity.identify(&ins);
+                        if same(app.target.get(&key), &ins) {
+                            app.target.remove(&key);
+                        }
+                    }
+                },
+                Step::Reconcile => self.reconcile(&app).await,
+            }
+        }
+    }
+
+    async fn reconcile(&self, app: &App<I::Key>) {
+        for (key, ins) in &app.source {
+            self.sync(app.target.get(key), ins).await;
+        }
+        if !self.prune {
+            return;
+        }
+        for (key, ins) in &app.target {
+            if !app.source.contains_key(key) {
+                self.deregister(ins).await;
+            }
+        }
+    }
+
+    // brings `registered`, the target's version of `ins`, up to date. An
+    // outdated version is deregistered once `ins` is registered.
+    async fn sync(&self, registered: Option<&Arc<Instance>>, ins: &Arc<Instance>) {
+        match registered {
+            Some(registered) if same(Some(registered), ins) => {}
+            Some(registered) => {
+                if let Err(e) = lifecycle::replace(&self.target, registered, ins.clone()).await {
+                    error!("failed to mirror {:?}. {}", ins.addrs, e);
+                }
+            }
+            None => self.register(ins).await,
+        }
+    }
+
+    // failures are retried when reconciling.
+    async fn register(&self, ins: &Arc<Instance>) {
+        if let Err(e) = self.target.register(ins.clone()).await {
+            error!("failed to mirror {:?}. {}", ins.addrs, e);
+        }
+    }
+
+    async fn deregister(&self, ins: &Arc<Instance>) {
+        if let Err(e) = self.target.deregister(ins).await {
+            error!("failed to deregister the mirror of {:?}. {}", ins.addrs, e);
+        }
+    }
+}
+
+// the instances of an app in the source and the target.
+struct App<K> {
+    source: HashMap<K, Arc<Instance>>,
+    target: HashMap<K, Arc<Instance>>,
+}
+
+// whether the target has `ins`, ignoring what backends keep track of like
+// revisions.
+fn same(target: Option<&Arc<Instance>>, ins: &Instance) -> bool {
+    target.is_some_and(|target| target.diff(ins).is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mirror;
+    use crate::{
+        mem::MemRegistry,
+        testing::{instance, Call, MockRegistry},
+        Instance, Registry,
+    };
+    use std::{sync::Arc, time::Duration};
+
+    #[tokio::test]
+    async fn test_mirror() {
+        tokio::time::pause();
+        let (source, target) = (MemRegistry::new(), MemRegistry::new());
+        let (a, b, c) = (
+            instance("grpc://10.0.0.1:9000"),
+            instance("grpc://10.0.0.2:9000"),
+            instance("grpc://10.0.0.3:9000"),
+        );
+        source.register(a.clone()).await.unwrap();
+        target.register(c.clone()).await.unwrap();
+        let mirror = Mirror::new(source.clone(), target.clone())
+            .app("provider")
+            .interval(Duration::from_millis(20))
+            .prune(true);
+        tokio::spawn(async move { mirror.run().await });
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        source.register(b.clone()).await.unwrap();
+        source.deregister(&a).await.unwrap();
+
+        // drift, fixed by reconciling.
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        target.deregister(&b).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        assert_eq!(target.instances("provider"), vec![b]);
+    }
+
+    #[tokio::test]
+    async fn test_mirror_update() {
+        tokio::time::pause();
+        let (source, target) = (MemRegistry::new(), MockRegistry::new());
+        let ins = instance("grpc://10.0.0.1:9000");
+        source.register(ins.clone()).await.unwrap();
+        let mirror = Mirror::new(source.clone(), target.clone()).app("provider");
+        tokio::spawn(async move { mirror.run().await });
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+
+        // the new version is registered before the old one goes.
+        let mut weighted = Instance::clone(&ins);
+        weighted
+            .metadata
+            .insert("weight".to_owned(), "10".to_owned());
+        let weighted = Arc::new(weighted);
+        source.register(weighted.clone()).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        let calls = target
+            .calls()
+            .into_iter()
+            .filter(|call| !matches!(call, Call::Watch(_)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            calls,
+            vec![
+                Call::Register(ins.clone()),
+                Call::Register(weighted),
+                Call::Deregister(ins),
+            ]
+        );
+    }
+}
@@ -0,0 +1,704 @@
+//! A registry on the Nacos naming service, through its v1 open API.
+//!
+//! Instances are ephemeral instances of the service named after their appid,
+//! kept alive by heartbeats. Watches poll the instance list as often as the
+//! server says and take its UDP pushes as a hint to poll right away.
+//!
+//! ```ignore
+//! let nacos = Nacos::builder("http://127.0.0.1:8848")
+//!     .namespace("prod")
+//!     .build();
+//! nacos.register(instance).await?;
+//! let discover = AppDiscover::new(nacos.watch("billing"), make_service);
+//! ```
+//!
+//! The fields of instances besides their appid and address are kept in the
+//! instance metadata. Instances registered by other means are reported with
+//! their address as `http://<ip>:<port>`.
+use crate::{
+    flat,
+    identity::{DefaultIdentity, Identity},
+    runtime::{self, AbortOnDrop},
+    watcher::{Clock, Event, SystemClock, WatchEvent},
+    Instance, Registry,
+};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future::BoxFuture,
+    FutureExt, Stream,
+};
+use hyper::{body::Bytes, client::HttpConnector, Body, Method, Request, Uri};
+use log::{error, warn};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use pin_project::pin_project;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    error, fmt, io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+
+const INSTANCE_PATH: &str = "/nacos/v1/ns/instance";
+
+// the code of heartbeats of instances the server doesn't know.
+const NOT_FOUND: i64 = 20404;
+
+// how long a failed watch waits before polling again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Nacos<I = DefaultIdentity> {
+    client: Client,
+    service: ServiceParams,
+    udp_push: bool,
+    // the registrations by instance, registered again when their heartbeat
+    // tells the server lost them.
+    registered: Arc<Mutex<HashMap<String, Registration>>>,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+    _heartbeat: AbortOnDrop,
+}
+
+impl Nacos {
+    /// Talks to the server at `address`, see `NacosBuilder` for the options.
+    pub fn builder(address: &str) -> NacosBuilder {
+        NacosBuilder::new(address)
+    }
+}
+
+impl<I> Nacos<I> {
+    /// Sets how watchers tell instances apart.
+    pub fn with_identity<NI>(self, identity: NI) -> Nacos<NI> {
+        Nacos {
+            client: self.client,
+            service: self.service,
+            udp_push: self.udp_push,
+            registered: self.registered,
+            identity: Arc::new(identity),
+            clock: self.clock,
+            _heartbeat: self._heartbeat,
+        }
+    }
+
+    /// Sets the clock timestamping the events of watchers.
+    pub fn with_clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+}
+
+/// Builds a `Nacos`.
+///
+/// ```ignore
+/// let nacos = Nacos::builder("http://127.0.0.1:8848")
+///     .namespace("prod")
+///     .group_name("PAYMENT")
+///     .heartbeat_interval(Duration::from_secs(5))
+///     .build();
+/// ```
+pub struct NacosBuilder {
+    address: String,
+    service: ServiceParams,
+    heartbeat_interval: Duration,
+    udp_push: bool,
+}
+
+impl NacosBuilder {
+    pub fn new(address: &str) -> Self {
+        NacosBuilder {
+            address: address.trim_end_matches('/').to_owned(),
+            service: ServiceParams {
+                namespace: String::new(),
+                group_name: "DEFAULT_GROUP".to_owned(),
+                cluster: "DEFAULT".to_owned(),
+            },
+            heartbeat_interval: Duration::from_secs(5),
+            udp_push: true,
+        }
+    }
+
+    /// The namespace id of the instances, the public namespace by default.
+    pub fn namespace(mut self, namespace: &str) -> Self {
+        self.service.namespace = namespace.to_owned();
+        self
+    }
+
+    /// The Nacos group of the services, `DEFAULT_GROUP` by default. Not the
+    /// traffic group of instances, see `Instance::group`.
+    pub fn group_name(mut self, group_name: &str) -> Self {
+        self.service.group_name = group_name.to_owned();
+        self
+    }
+
+    /// The cluster instances are registered in, `DEFAULT` by default.
+    pub fn cluster(mut self, cluster: &str) -> Self {
+        self.service.cluster = cluster.to_owned();
+        self
+    }
+
+    /// 5 seconds by default, the server takes instances as unhealthy after
+    /// 15 seconds without one and removes them after 30.
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    /// Whether watches listen for UDP pushes, on by default. Servers only
+    /// push to clients they know, watches poll anyway.
+    pub fn udp_push(mut self, udp_push: bool) -> Self {
+        self.udp_push = udp_push;
+        self
+    }
+
+    /// Needs a tokio runtime, the heartbeats of the instances registered run
+    /// in the background.
+    pub fn build(self) -> Nacos {
+        let client = Client {
+            http: hyper::Client::new(),
+            address: self.address,
+        };
+        let registered = Arc::new(Mutex::new(HashMap::new()));
+        let interval = self.heartbeat_interval;
+        let heartbeat = {
+            let (client, registered) = (client.clone(), registered.clone());
+            async move {
+                loop {
+                    runtime::delay_for(interval).await;
+                    beat(&client, &registered).await;
+                }
+            }
+        };
+        let heartbeat = runtime::spawn_abortable(heartbeat);
+        Nacos {
+            client,
+            service: self.service,
+            udp_push: self.udp_push,
+            registered,
+            identity: Arc::new(DefaultIdentity),
+            clock: Arc::new(SystemClock),
+            _heartbeat: heartbeat,
+        }
+    }
+}
+
+// where the instances of this process are registered.
+#[derive(Debug, Clone)]
+struct ServiceParams {
+    namespace: String,
+    group_name: String,
+    cluster: String,
+}
+
+impl ServiceParams {
+    // the parameters naming the service of `appid`.
+    fn of(&self, appid: &str) -> Vec<(&'static str, String)> {
+        let mut params = vec![
+            ("serviceName", appid.to_owned()),
+            ("groupName", self.group_name.clone()),
+        ];
+        if !self.namespace.is_empty() {
+            params.push(("namespaceId", self.namespace.clone()));
+        }
+        params
+    }
+}
+
+#[derive(Clone)]
+struct Registration {
+    register: String,
+    beat: String,
+}
+
+async fn beat(client: &Client, registered: &Mutex<HashMap<String, Registration>>) {
+    let registrations = registered.lock().unwrap().clone();
+    for (id, registration) in registrations {
+        if let Err(e) = beat_one(client, &id, &registration).await {
+            error!("failed to send the heartbeat of {}. {}", id, e);
+        }
+    }
+}
+
+// sends the heartbeat of the instance `id`, registering it again when lost.
+async fn beat_one(
+    client: &Client,
+    id: &str,
+    registration: &Registration,
+) -> Result<(), NacosError> {
+    let path = format!("{}/beat?{}", INSTANCE_PATH, registration.beat);
+    let rsp = client.call(Method::PUT, &path).await?;
+    let rsp = serde_json::from_slice::<Value>(&rsp)?;
+    if rsp["code"].as_i64() == Some(NOT_FOUND) {
+        warn!("nacos lost the instance {}, registering it again", id);
+        let path = format!("{}?{}", INSTANCE_PATH, registration.register);
+        client.call(Method::POST, &path).await?;
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
+struct Client {
+    http: hyper::Client<HttpConnector>,
+    address: String,
+}
+
+impl Client {
+    async fn call(&self, method: Method, path: &str) -> Result<Bytes, NacosError> {
+        let req = Request::builder()
+            .method(method)
+            .uri(format!("{}{}", self.address, path))
+            .body(Body::empty())
+            .map_err(|e| NacosError::InvalidAddress(e.to_string()))?;
+        let rsp = self.http.request(req).await?;
+        let status = rsp.status();
+        let body = hyper::body::to_bytes(rsp.into_body()).await?;
+        if !status.is_success() {
+            let message = String::from_utf8_lossy(&body).into_owned();
+            return Err(NacosError::Status(status.as_u16(), message));
+        }
+        Ok(body)
+    }
+
+    async fn list(&self, params: &[(&'static str, String)]) -> Result<Value, NacosError> {
+        let path = format!("{}/list?{}", INSTANCE_PATH, query(params));
+        let rsp = self.call(Method::GET, &path).await?;
+        Ok(serde_json::from_slice(&rsp)?)
+    }
+
+    // the host and port of the server, where pushes come from.
+    fn server(&self) -> Option<(String, u16)> {
+        let uri: Uri = self.address.parse().ok()?;
+        let port = uri.port_u16().unwrap_or(80);
+        Some((uri.host()?.to_owned(), port))
+    }
+}
+
+fn query(params: &[(&'static str, String)]) -> String {
+    let params = params
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, utf8_percent_encode(value, NON_ALPHANUMERIC)));
+    params.collect::<Vec<_>>().join("&")
+}
+
+// named after the fields identifying `ins`.
+fn instance_id(ins: &Instance) -> String {
+    format!("{}-{:016x}", ins.appid, fxhash::hash64(ins))
+}
+
+// the parameters and heartbeat registering `ins`.
+fn to_registration(ins: &Instance, service: &ServiceParams) -> Registration {
+    let (ip, port) = flat::address(ins);
+    let weight = ins.weight().unwrap_or(1);
+    let metadata = flat::to_metadata(ins);
+    let mut register = service.of(&ins.appid);
+    register.extend(vec![
+        ("ip", ip.to_owned()),
+        ("port", port.to_string()),
+        ("weight", weight.to_string()),
+        ("clusterName", service.cluster.clone()),
+        ("ephemeral", "true".to_owned()),
+        ("metadata", json!(metadata).to_string()),
+    ]);
+    let mut beat = service.of(&ins.appid);
+    let info = json!({
+        "serviceName": format!("{}@@{}", service.group_name, ins.appid),
+        "ip": ip,
+        "port": port,
+        "cluster": service.cluster,
+        "weight": weight,
+        "metadata": metadata,
+        "scheduled": false,
+    });
+    beat.push(("beat", info.to_string()));
+    Registration {
+        register: query(&register),
+        beat: query(&beat),
+    }
+}
+
+// the instance of a host of the instance list.
+fn from_host(appid: &str, host: &Value) -> Option<Instance> {
+    let default_addr = format!("http://{}:{}", host["ip"].as_str()?, host["port"].as_u64()?);
+    let mut metadata: HashMap<_, _> = host["metadata"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_owned())))
+        .collect();
+    if let Some(weight) = host["weight"].as_f64() {
+        metadata
+            .entry("weight".to_owned())
+            .or_insert_with(|| weight.to_string());
+    }
+    Some(flat::from_metadata(appid, default_addr, metadata))
+}
+
+fn serving(host: &Value) -> bool {
+    host["healthy"].as_bool().unwrap_or(true) && host["enabled"].as_bool().unwrap_or(true)
+}
+
+#[derive(Debug)]
+pub enum NacosError {
+    InvalidAddress(String),
+    Http(hyper::Error),
+    /// The server answered with an error status.
+    Status(u16, String),
+    Json(serde_json::Error),
+    /// Nacos can't update instances only at a given version, see
+    /// `Registry::update_if`.
+    Unsupported,
+}
+
+impl fmt::Display for NacosError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NacosError::InvalidAddress(e) => write!(f, "invalid nacos address: {}", e),
+            NacosError::Http(e) => write!(f, "failed to call nacos: {}", e),
+            NacosError::Status(status, message) => {
+                write!(f, "nacos answered {}: {}", status, message)
+            }
+            NacosError::Json(e) => write!(f, "bad json from nacos: {}", e),
+            NacosError::Unsupported => write!(f, "unsupported by nacos"),
+        }
+    }
+}
+
+impl error::Error for NacosError {}
+
+impl From<hyper::Error> for NacosError {
+    fn from(e: hyper::Error) -> Self {
+        NacosError::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for NacosError {
+    fn from(e: serde_json::Error) -> Self {
+        NacosError::Json(e)
+    }
+}
+
+impl<I> Registry for Nacos<I>
+where
+    I: Identity + Send + Sync + 'static,
+    I::Key: Send + Sync,
+{
+    type Error = NacosError;
+
+    type RegFuture = BoxFuture<'static, Result<(), NacosError>>;
+
+    type DeRegFuture = BoxFuture<'static, Result<(), NacosError>>;
+
+    type ListFuture = BoxFuture<'static, Result<Vec<Arc<Instance>>, NacosError>>;
+
+    type Watcher = NacosWatcher;
+
+    fn register(&self, ins: Arc<Instance>) -> Self::RegFuture {
+        let client = self.client.clone();
+        let registered = self.registered.clone();
+        let id = instance_id(&ins);
+        let registration = to_registration(&ins, &self.service);
+        async move {
+            let path = format!("{}?{}", INSTANCE_PATH, registration.register);
+            client.call(Method::POST, &path).await?;
+            registered.lock().unwrap().insert(id, registration);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn deregister(&self, ins: &Arc<Instance>) -> Self::DeRegFuture {
+        let client = self.client.clone();
+        self.registered.lock().unwrap().remove(&instance_id(ins));
+        let (ip, port) = flat::address(ins);
+        let mut params = self.service.of(&ins.appid);
+        params.extend(vec![
+            ("ip", ip.to_owned()),
+            ("port", port.to_string()),
+            ("clusterName", self.service.cluster.clone()),
+            ("ephemeral", "true".to_owned()),
+        ]);
+        let path = format!("{}?{}", INSTANCE_PATH, query(&params));
+        async move {
+            client.call(Method::DELETE, &path).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    // Nacos doesn't tell why an instance went away, so watchers report
+    // evictions as deletes without a reason.
+    fn evict(&self, appid: &str, instance_id: &str) -> Self::DeRegFuture {
+        let client = self.client.clone();
+        let appid = appid.to_owned();
+        let service = self.service.clone();
+        let instance_id = instance_id.to_owned();
+        async move {
+            let mut params = service.of(&appid);
+            params.push(("healthyOnly", "false".to_owned()));
+            let list = client.list(&params).await?;
+            for host in list["hosts"].as_array().into_iter().flatten() {
+                match from_host(&appid, host) {
+                    Some(ins) if ins.has_id(&instance_id) => {}
+                    _ => continue,
+                }
+                let field = |key: &str| match &host[key] {
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                let mut params = service.of(&appid);
+                params.extend(vec![
+                    ("ip", field("ip")),
+                    ("port", field("port")),
+                    ("clusterName", field("clusterName")),
+                    ("ephemeral", field("ephemeral")),
+                ]);
+                let path = format!("{}?{}", INSTANCE_PATH, query(&params));
+                client.call(Method::DELETE, &path).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    // Nacos has no check-and-set on instances.
+    fn update_if(&self, _ins: Arc<Instance>, _expected_version: u64) -> Self::RegFuture {
+        futures::future::ready(Err(NacosError::Unsupported)).boxed()
+    }
+
+    fn renew(&self, ins: &Arc<Instance>) -> Self::RegFuture {
+        let client = self.client.clone();
+        let id = instance_id(ins);
+        let registration = to_registration(ins, &self.service);
+        async move { beat_one(&client, &id, &registration).await }.boxed()
+    }
+
+    fn list(&self, appid: &str) -> Self::ListFuture {
+        let client = self.client.clone();
+        let appid = appid.to_owned();
+        let mut params = self.service.of(&appid);
+        params.push(("healthyOnly", "true".to_owned()));
+        async move {
+            let list = client.list(&params).await?;
+            let hosts = list["hosts"].as_array().into_iter().flatten();
+            Ok(hosts
+                .filter(|host| serving(host))
+                .filter_map(|host| from_host(&appid, host))
+                .map(Arc::new)
+                .collect())
+        }
+        .boxed()
+    }
+
+    fn watch(&self, appid: &str) -> Self::Watcher {
+        self.watch_from(appid, &[])
+    }
+
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher {
+        let (tx, rx) = mpsc::unbounded();
+        let mut known = HashMap::new();
+        for ins in instances {
+            known.insert(self.identity.identify(ins), ins.clone());
+            let event = WatchEvent::with_clock(Event::Create(ins.clone()), &*self.clock);
+            let _ = tx.unbounded_send(event);
+        }
+        let watch = AppWatch {
+            client: self.client.clone(),
+            appid: appid.to_owned(),
+            params: self.service.of(appid),
+            udp_push: self.udp_push,
+            identity: self.identity.clone(),
+            clock: self.clock.clone(),
+            known,
+            tx,
+        };
+        NacosWatcher {
+            rx,
+            _task: runtime::spawn_abortable(watch.run()),
+        }
+    }
+}
+
+/// The instances of an app in Nacos, see `Registry::watch`.
+#[pin_project]
+pub struct NacosWatcher {
+    #[pin]
+    rx: UnboundedReceiver<WatchEvent>,
+    _task: AbortOnDrop,
+}
+
+impl Stream for NacosWatcher {
+    type Item = WatchEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().rx.poll_next(cx)
+    }
+}
+
+struct AppWatch<I: Identity> {
+    client: Client,
+    appid: String,
+    params: Vec<(&'static str, String)>,
+    udp_push: bool,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+    // the instances reported.
+    known: HashMap<I::Key, Arc<Instance>>,
+    tx: UnboundedSender<WatchEvent>,
+}
+
+// where the server pushes changes to.
+struct Pushes {
+    socket: UdpSocket,
+    ip: String,
+    port: u16,
+}
+
+impl<I> AppWatch<I>
+where
+    I: Identity,
+{
+    async fn run(mut self) {
+        let mut pushes = None;
+        if self.udp_push {
+            match self.listen().await {
+                Ok(listening) => pushes = Some(listening),
+                Err(e) => warn!("no udp pushes for {}, polling only. {}", self.appid, e),
+            }
+        }
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let mut params = self.params.clone();
+            params.push(("healthyOnly", "true".to_owned()));
+            if let Some(pushes) = &pushes {
+                params.push(("udpPort", pushes.port.to_string()));
+                params.push(("clientIP", pushes.ip.clone()));
+            }
+            let wait = match self.client.list(&params).await {
+                Ok(list) => {
+                    self.update(&list);
+                    Duration::from_millis(list["cacheMillis"].as_u64().unwrap_or(10_000))
+                }
+                Err(e) => {
+                    error!("failed to watch {}. {}", self.appid, e);
+                    RETRY_INTERVAL
+                }
+            };
+            match &mut pushes {
+                Some(pushes) => {
+                    runtime::timeout(wait, recv_push(&mut pushes.socket, &mut buf)).await;
+                }
+                None => runtime::delay_for(wait).await,
+            }
+        }
+    }
+
+    async fn listen(&self) -> io::Result<Pushes> {
+        let server = self
+            .client
+            .server()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no server host"))?;
+        // the local address reaching the server.
+        let probe = UdpSocket::bind("0.0.0.0:0").await?;
+        probe.connect(server).await?;
+        let ip = probe.local_addr()?.ip();
+        let socket = UdpSocket::bind((ip, 0)).await?;
+        let port = socket.local_addr()?.port();
+        Ok(Pushes {
+            socket,
+            ip: ip.to_string(),
+            port,
+        })
+    }
+
+    // reports the instances serving now and not before as Creates, the ones
+    // changed too, and the ones gone as Deletes.
+    fn update(&mut self, list: &Value) {
+        let mut gone = std::mem::take(&mut self.known);
+        for host in list["hosts"].as_array().into_iter().flatten() {
+            if !serving(host) {
+                continue;
+            }
+            let ins = match from_host(&self.appid, host) {
+                Some(ins) => Arc::new(ins),
+                None => continue,
+            };
+            let key = self.identity.identify(&ins);
+            let unchanged = gone.remove(&key).filter(|old| *old == ins);
+            if unchanged.is_none() {
+                self.send(Event::Create(ins.clone()));
+            }
+            self.known.insert(key, ins);
+        }
+        for (_, ins) in gone {
+            self.send(Event::Delete(ins));
+        }
+    }
+
+    fn send(&self, event: Event) {
+        let _ = self
+            .tx
+            .unbounded_send(WatchEvent::with_clock(event, &*self.clock));
+    }
+}
+
+// waits for a push and acknowledges it, the instances are listed again
+// rather than read from it, as it may be compressed.
+async fn recv_push(socket: &mut UdpSocket, buf: &mut [u8]) {
+    let (len, from) = match socket.recv_from(buf).await {
+        Ok(received) => received,
+        Err(e) => {
+            error!("failed to receive a nacos push. {}", e);
+            return;
+        }
+    };
+    let push: Value = match serde_json::from_slice(&buf[..len]) {
+        Ok(push) => push,
+        Err(_) => return,
+    };
+    let ack = json!({
+        "type": "push-ack",
+        "lastRefTime": push["lastRefTime"],
+        "data": "",
+    });
+    let _ = socket.send_to(ack.to_string().as_bytes(), from).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_host, serving, to_registration, NacosBuilder};
+    use crate::{intern::intern, Instance};
+    use serde_json::json;
+
+    #[test]
+    fn test_registration() {
+        let ins = Instance {
+            appid: intern("billing"),
+            addrs: vec!["grpc://10.0.0.1:9000".to_owned()].into(),
+            version: "1.2.0".to_owned(),
+            ..Default::default()
+        };
+        let service = NacosBuilder::new("http://127.0.0.1:8848").service;
+        let registration = to_registration(&ins, &service);
+        assert!(registration.register.starts_with(
+            "serviceName=billing&groupName=DEFAULT%5FGROUP&ip=10%2E0%2E0%2E1&port=9000"
+        ));
+
+        let host = json!({
+            "ip": "10.0.0.1",
+            "port": 9000,
+            "weight": 1.0,
+            "healthy": true,
+            "metadata": {"version": "1.2.0", "addrs": "grpc://10.0.0.1:9000"},
+        });
+        let decoded = from_host("billing", &host).unwrap();
+        assert_eq!(decoded.addrs, ins.addrs);
+        assert_eq!(decoded.weight(), Some(1));
+        assert!(serving(&host));
+        assert!(!serving(&json!({"healthy": false})));
+    }
+}
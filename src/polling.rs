@@ -0,0 +1,205 @@
+//! Watches registries without a native watch by listing their instances
+//! again and again.
+use crate::{
+    identity::{DefaultIdentity, Identity},
+    runtime,
+    watcher::{Event, WatchEvent},
+    Instance, Registry,
+};
+use futures::{
+    future::{self, BoxFuture},
+    ready, FutureExt, Stream,
+};
+use log::error;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// The instances of an app, listed with `Registry::list` every `interval`,
+/// 30s by default.
+///
+/// Each listing is diffed against the one before: instances new to it are
+/// reported as Creates, changed as Updates and gone as Deletes. A failed
+/// listing is logged and the instances stay as they were until the next.
+///
+/// ```ignore
+/// let watcher = PollingWatcher::new(registry, "provider").interval(Duration::from_secs(5));
+/// let discover = AppDiscover::new(watcher, make_service);
+/// ```
+pub struct PollingWatcher<R, I = DefaultIdentity>
+where
+    R: Registry,
+    I: Identity,
+{
+    registry: R,
+    appid: String,
+    interval: Duration,
+    identity: I,
+    state: State<R::ListFuture>,
+    // the instances reported.
+    known: HashMap<I::Key, Arc<Instance>>,
+    events: VecDeque<WatchEvent>,
+}
+
+enum State<F> {
+    Listing(Pin<Box<F>>),
+    Waiting(BoxFuture<'static, ()>),
+}
+
+// the futures are boxed, nothing is pinned.
+impl<R, I> Unpin for PollingWatcher<R, I>
+where
+    R: Registry,
+    I: Identity,
+{
+}
+
+impl<R> PollingWatcher<R>
+where
+    R: Registry,
+{
+    /// Lists the instances of `appid` right away, then every interval.
+    pub fn new(registry: R, appid: &str) -> Self {
+        PollingWatcher {
+            registry,
+            appid: appid.to_owned(),
+            interval: Duration::from_secs(30),
+            identity: DefaultIdentity,
+            state: State::Waiting(future::ready(()).boxed()),
+            known: HashMap::new(),
+            events: VecDeque::new(),
+        }
+    }
+}
+
+impl<R, I> PollingWatcher<R, I>
+where
+    R: Registry,
+    I: Identity,
+{
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets how listings tell a changed instance from a new one.
+    pub fn with_identity<NI: Identity>(self, identity: NI) -> PollingWatcher<R, NI> {
+        let known = self
+            .known
+            .into_values()
+            .map(|ins| (identity.identify(&ins), ins))
+            .collect();
+        PollingWatcher {
+            registry: self.registry,
+            appid: self.appid,
+            interval: self.interval,
+            identity,
+            state: self.state,
+            known,
+            events: self.events,
+        }
+    }
+
+    /// Resumes from `instances`, reported right away, see
+    /// `Registry::watch_from`.
+    pub fn resume(mut self, instances: &[Arc<Instance>]) -> Self {
+        for ins in instances {
+            self.known.insert(self.identity.identify(ins), ins.clone());
+            self.events
+                .push_back(WatchEvent::new(Event::Create(ins.clone())));
+        }
+        self
+    }
+
+    // queues the differences between `instances` and the ones reported.
+    fn update(&mut self, instances: Vec<Arc<Instance>>) {
+        let mut gone = std::mem::take(&mut self.known);
+        for ins in instances {
+            let key = self.identity.identify(&ins);
+            let event = match gone.remove(&key) {
+                Some(old) if old == ins => None,
+                Some(old) => Some(WatchEvent::new(Event::Update(ins.clone())).with_delta(&old)),
+                None => Some(WatchEvent::new(Event::Create(ins.clone()))),
+            };
+            self.events.extend(event);
+            self.known.insert(key, ins);
+        }
+        for (_, ins) in gone {
+            self.events.push_back(WatchEvent::new(Event::Delete(ins)));
+        }
+    }
+}
+
+impl<R, I> Stream for PollingWatcher<R, I>
+where
+    R: Registry,
+    R::Error: fmt::Display,
+    I: Identity,
+{
+    type Item = WatchEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(event) = this.events.pop_front() {
+                return Poll::Ready(Some(event));
+            }
+            match &mut this.state {
+                State::Listing(list) => {
+                    match ready!(list.as_mut().poll(cx)) {
+                        Ok(instances) => this.update(instances),
+                        Err(e) => error!("failed to list {}. {}", this.appid, e),
+                    }
+                    this.state = State::Waiting(runtime::delay_for(this.interval));
+                }
+                State::Waiting(delay) => {
+                    ready!(delay.as_mut().poll(cx));
+                    this.state = State::Listing(Box::pin(this.registry.list(&this.appid)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PollingWatcher;
+    use crate::{mem::MemRegistry, testing::instance, watcher::Event, Registry};
+    use futures::StreamExt;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_polling_watcher() {
+        let registry = MemRegistry::new();
+        let (a, b) = (
+            instance("grpc://10.0.0.1:9000"),
+            instance("grpc://10.0.0.2:9000"),
+        );
+        registry.register(a.clone()).await.unwrap();
+        let mut watcher =
+            PollingWatcher::new(registry.clone(), "provider").interval(Duration::from_millis(10));
+        let first = watcher.next().await.unwrap();
+        assert_eq!(first.event, Event::Create(a.clone()));
+
+        registry.register(b.clone()).await.unwrap();
+        registry.deregister(&a).await.unwrap();
+        let mut events = vec![
+            watcher.next().await.unwrap().event,
+            watcher.next().await.unwrap().event,
+        ];
+        events.sort_by_key(|event| format!("{:?}", event));
+        assert_eq!(events, [Event::Create(b.clone()), Event::Delete(a)]);
+
+        registry.update_if(b.clone(), 0).await.unwrap();
+        match watcher.next().await.unwrap().event {
+            Event::Update(ins) => assert_eq!(ins.revision, Some(1)),
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! Exports the watched instances as Prometheus scrape targets, for `http_sd`
+//! and `file_sd` configs.
+use crate::{
+    identity::{DefaultIdentity, Identity},
+    runtime::{self, AbortOnDrop},
+    watcher::{Event, WatchEvent},
+    Instance,
+};
+use futures::{future, Stream, StreamExt};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+};
+use tokio::sync::watch;
+
+const LABEL_PREFIX: &str = "__meta_discover_";
+
+type Key = <DefaultIdentity as Identity>::Key;
+
+/// The scrape targets of the appids added with `add_app`.
+///
+/// Every instance with an address of the scheme, `http` by default, is a
+/// target group of its own, labeled `__meta_discover_appid`, `_zone`, `_env`,
+/// `_hostname`, `_version`, `_group` and `_metadata_<key>` for relabeling.
+/// Needs a tokio runtime.
+///
+/// ```ignore
+/// let sd = PrometheusSd::new();
+/// sd.add_app("provider", zk.watch("/dubbo-rs/provider"));
+/// runtime::spawn(sd.file_sd("/etc/prometheus/targets/discover.json"));
+/// ```
+#[derive(Clone)]
+pub struct PrometheusSd {
+    shared: Arc<Shared>,
+    tasks: Arc<Mutex<Vec<AbortOnDrop>>>,
+}
+
+struct Shared {
+    scheme: String,
+    apps: RwLock<BTreeMap<String, HashMap<Key, Arc<Instance>>>>,
+    changed_tx: watch::Sender<()>,
+    changed_rx: watch::Receiver<()>,
+}
+
+impl PrometheusSd {
+    pub fn new() -> Self {
+        Self::with_scheme("http")
+    }
+
+    /// Targets are the addresses of `scheme` of instances, the ones without
+    /// such an address are left out.
+    pub fn with_scheme(scheme: impl Into<String>) -> Self {
+        let (changed_tx, changed_rx) = watch::channel(());
+        PrometheusSd {
+            shared: Arc::new(Shared {
+                scheme: scheme.into(),
+                apps: RwLock::new(BTreeMap::new()),
+                changed_tx,
+                changed_rx,
+            }),
+            tasks: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Exports the instances of `watcher` as the targets of `appid`, until
+    /// the exporter is dropped.
+    pub fn add_app<W>(&self, appid: impl Into<String>, watcher: W)
+    where
+        W: Stream<Item = WatchEvent> + Send + 'static,
+    {
+        let appid = appid.into();
+        let shared = self.shared.clone();
+        let task = watcher.for_each(move |watch_event| {
+            {
+                let mut apps = shared.apps.write().unwrap();
+                let instances = apps.entry(appid.clone()).or_default();
+                match watch_event.event {
+                    Event::Create(ins) | Event::Update(ins) => {
+                        instances.insert(DefaultIdentity.identify(&ins), ins);
+                    }
+                    Event::Delete(ins) => {
+                        instances.remove(&DefaultIdentity.identify(&ins));
+                    }
+                }
+            }
+            let _ = shared.changed_tx.broadcast(());
+            future::ready(())
+        });
+        self.tasks
+            .lock()
+            .unwrap()
+            .push(runtime::spawn_abortable(task));
+    }
+
+    /// The target groups, as JSON.
+    pub fn target_groups(&self) -> Value {
+        let apps = self.shared.apps.read().unwrap();
+        let mut groups = Vec::new();
+        for instances in apps.values() {
+            let mut targets = instances
+                .values()
+                .filter_map(|ins| {
+                    let addr = ins.addr_for_scheme(&self.shared.scheme)?;
+                    Some((addr.trim_end_matches('/'), ins))
+                })
+                .collect::<Vec<_>>();
+            targets.sort_by_key(|(target, _)| *target);
+            groups.extend(targets.into_iter().map(|(target, ins)| {
+                json!({
+                    "targets": [target],
+                    "labels": labels(ins),
+                })
+            }));
+        }
+        Value::Array(groups)
+    }
+
+    /// The body of an `http_sd` response.
+    pub fn http_sd(&self) -> String {
+        self.target_groups().to_string()
+    }
+
+    /// Keeps the `file_sd` file at `path` up to date, replacing it
+    /// atomically on every change. Ends only when it fails to write.
+    pub async fn file_sd(self, path: impl Into<PathBuf>) -> io::Result<()> {
+        let path = path.into();
+        let mut changed = self.shared.changed_rx.clone();
+        // the first one is the current state.
+        while changed.recv().await.is_some() {
+            let (path, content) = (path.clone(), self.target_groups().to_string());
+            runtime::spawn_blocking(move || write_file(&path, content.as_bytes()))
+                .await
+                .ok_or_else(|| io::Error::other("writing the file panicked"))??;
+        }
+        Ok(())
+    }
+}
+
+impl Default for PrometheusSd {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn labels(ins: &Instance) -> Map<String, Value> {
+    let mut labels = Map::new();
+    for (name, value) in [
+        ("appid", &*ins.appid),
+        ("zone", &ins.zone),
+        ("env", &ins.env),
+        ("hostname", &ins.hostname),
+        ("version", &ins.version),
+        ("group", &ins.group),
+    ]
+    .iter()
+    {
+        labels.insert(format!("{}{}", LABEL_PREFIX, name), Value::from(*value));
+    }
+    for (k, v) in &ins.metadata {
+        labels.insert(
+            format!("{}metadata_{}", LABEL_PREFIX, label_name(k)),
+            Value::from(v.as_str()),
+        );
+    }
+    labels
+}
+
+// label names are [a-zA-Z_][a-zA-Z0-9_]*, the prefix starts them right.
+fn label_name(key: &str) -> String {
+    key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+// Prometheus may read the file anytime, so it's never seen half written.
+fn write_file(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PrometheusSd;
+    use crate::{testing::MockRegistry, Instance, Registry};
+    use serde_json::{json, Value};
+    use std::{fs, time::Duration};
+
+    fn instance(zone: &str, addr: &str) -> Instance {
+        Instance {
+            zone: zone.into(),
+            appid: "provider".into(),
+            addrs: vec!["grpc://172.1.1.1:9999".to_owned(), addr.to_owned()].into(),
+            metadata: [("instance.status".to_owned(), "UP".to_owned())]
+                .iter()
+                .cloned()
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn group(zone: &str, target: &str) -> Value {
+        json!({
+            "targets": [target],
+            "labels": {
+                "__meta_discover_appid": "provider",
+                "__meta_discover_zone": zone,
+                "__meta_discover_env": "",
+                "__meta_discover_hostname": "",
+                "__meta_discover_version": "",
+                "__meta_discover_group": "",
+                "__meta_discover_metadata_instance_status": "UP",
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn test_prometheus_sd() {
+        let registry = MockRegistry::new();
+        registry.insert(instance("sh2", "http://172.1.1.2:8000"));
+        registry.insert(instance("sh1", "http://172.1.1.1:8000"));
+        let sd = PrometheusSd::new();
+        sd.add_app("provider", registry.watch("provider"));
+        let path = std::env::temp_dir().join(format!("discover-sd-{}.json", std::process::id()));
+        tokio::spawn(sd.clone().file_sd(path.clone()));
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+
+        let expected = json!([
+            group("sh1", "172.1.1.1:8000"),
+            group("sh2", "172.1.1.2:8000"),
+        ]);
+        assert_eq!(sd.target_groups(), expected);
+        let written = serde_json::from_slice::<Value>(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(written, expected);
+
+        registry.remove(&instance("sh1", "http://172.1.1.1:8000"));
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        let expected = json!([group("sh2", "172.1.1.2:8000")]);
+        assert_eq!(
+            serde_json::from_str::<Value>(&sd.http_sd()).unwrap(),
+            expected
+        );
+        let written = serde_json::from_slice::<Value>(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(written, expected);
+        fs::remove_file(&path).unwrap();
+    }
+}
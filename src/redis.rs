@@ -0,0 +1,855 @@
+//! A registry on Redis.
+//!
+//! Instances are the values of keys `<key prefix><appid>:<hash>`, expiring
+//! unless the process keeps refreshing them. Watchers scan the keys of their
+//! app, then follow keyspace notifications, which the server only sends with
+//! `notify-keyspace-events` including `K$gx`. As notifications can be missed,
+//! the keys are scanned again every poll interval, the only updates when
+//! notifications are off.
+//!
+//! ```ignore
+//! let redis = Redis::builder("10.0.0.1:6379")
+//!     .key_prefix("discovery:prod:")
+//!     .ttl(Duration::from_secs(10))
+//!     .build();
+//! redis.register(instance).await?;
+//! let discover = AppDiscover::new(redis.watch("billing"), make_service);
+//! ```
+use crate::{
+    codec::{
+        decode_with_payload, new_default_codec, Codec, Decoder, DefaultDecoder, DefaultEncoder,
+        EncodeError, Encoder,
+    },
+    identity::{DefaultIdentity, Identity},
+    runtime::{self, AbortOnDrop},
+    watcher::{Clock, Event, SystemClock, WatchEvent},
+    Instance, Registry,
+};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future::{self, BoxFuture},
+    FutureExt, Stream,
+};
+use log::error;
+use pin_project::pin_project;
+use std::{
+    collections::HashMap,
+    error, fmt, io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::Mutex as AsyncMutex,
+};
+
+// how long a failed watch waits before scanning the keys again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// the keys of the registrations and their values, set again when they
+// expired.
+type Registrations = Mutex<HashMap<Vec<u8>, Vec<u8>>>;
+
+pub struct Redis<EC, DC, I = DefaultIdentity> {
+    client: Client,
+    codec: Arc<Codec<EC, DC>>,
+    key_prefix: String,
+    ttl: Duration,
+    poll_interval: Duration,
+    notifications: bool,
+    registered: Arc<Registrations>,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+    _refresh: AbortOnDrop,
+}
+
+impl Redis<DefaultEncoder, DefaultDecoder> {
+    /// Connects to `address`, see `RedisBuilder` for the options.
+    pub fn builder(address: &str) -> RedisBuilder<DefaultEncoder, DefaultDecoder> {
+        RedisBuilder::new(address)
+    }
+}
+
+impl<EC, DC, I> Redis<EC, DC, I> {
+    /// Sets how watchers decide that a changed key is a re-registration of an
+    /// instance they already reported rather than a new one.
+    pub fn with_identity<NI>(self, identity: NI) -> Redis<EC, DC, NI> {
+        Redis {
+            client: self.client,
+            codec: self.codec,
+            key_prefix: self.key_prefix,
+            ttl: self.ttl,
+            poll_interval: self.poll_interval,
+            notifications: self.notifications,
+            registered: self.registered,
+            identity: Arc::new(identity),
+            clock: self.clock,
+            _refresh: self._refresh,
+        }
+    }
+
+    /// Sets the clock timestamping the events of watchers.
+    pub fn with_clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    // the keys of the instances of `appid` start with it.
+    fn dir(&self, appid: &str) -> String {
+        format!("{}{}:", self.key_prefix, appid)
+    }
+
+    // named after the fields identifying `ins`, so registering it again
+    // replaces it.
+    fn key(&self, ins: &Instance) -> Vec<u8> {
+        format!("{}{:016x}", self.dir(&ins.appid), fxhash::hash64(ins)).into_bytes()
+    }
+}
+
+/// Builds a `Redis`.
+pub struct RedisBuilder<EC, DC> {
+    address: String,
+    password: Option<String>,
+    db: u32,
+    key_prefix: String,
+    ttl: Duration,
+    poll_interval: Duration,
+    notifications: bool,
+    codec: Arc<Codec<EC, DC>>,
+}
+
+impl RedisBuilder<DefaultEncoder, DefaultDecoder> {
+    /// Connects to `address`, e.g. `10.0.0.1:6379`, with the default codec.
+    pub fn new(address: &str) -> Self {
+        RedisBuilder {
+            address: address.to_owned(),
+            password: None,
+            db: 0,
+            key_prefix: "discovery:".to_owned(),
+            ttl: Duration::from_secs(10),
+            poll_interval: Duration::from_secs(30),
+            notifications: true,
+            codec: Arc::new(new_default_codec()),
+        }
+    }
+}
+
+impl<EC, DC> RedisBuilder<EC, DC> {
+    /// Authenticates connections with `password`.
+    pub fn password(mut self, password: &str) -> Self {
+        self.password = Some(password.to_owned());
+        self
+    }
+
+    /// The database of the keys, 0 by default.
+    pub fn db(mut self, db: u32) -> Self {
+        self.db = db;
+        self
+    }
+
+    /// Prepended to every key registered or watched, `discovery:` by default.
+    pub fn key_prefix(mut self, prefix: &str) -> Self {
+        self.key_prefix = prefix.to_owned();
+        self
+    }
+
+    /// How long instances stay registered once the process can't refresh
+    /// them, 10 seconds by default. Refreshed every third of it.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// How often watchers scan the keys of their app, 30 seconds by default.
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Whether watchers follow keyspace notifications, on by default. Off,
+    /// they only scan.
+    pub fn notifications(mut self, enabled: bool) -> Self {
+        self.notifications = enabled;
+        self
+    }
+
+    /// Sets how instances are encoded, `new_default_codec()` by default.
+    pub fn codec<NEC, NDC>(self, codec: impl Into<Arc<Codec<NEC, NDC>>>) -> RedisBuilder<NEC, NDC> {
+        RedisBuilder {
+            address: self.address,
+            password: self.password,
+            db: self.db,
+            key_prefix: self.key_prefix,
+            ttl: self.ttl,
+            poll_interval: self.poll_interval,
+            notifications: self.notifications,
+            codec: codec.into(),
+        }
+    }
+
+    /// Connects lazily, refreshing the instances registered in the
+    /// background. Needs a tokio runtime.
+    pub fn build(self) -> Redis<EC, DC> {
+        let client = Client {
+            options: Arc::new(ConnectOptions {
+                address: self.address,
+                password: self.password,
+                db: self.db,
+            }),
+            conn: Arc::new(AsyncMutex::new(None)),
+        };
+        let registered = Arc::new(Mutex::new(HashMap::new()));
+        let refresh = {
+            let (client, registered) = (client.clone(), registered.clone());
+            let ttl = self.ttl;
+            async move {
+                loop {
+                    runtime::delay_for(ttl / 3).await;
+                    refresh(&client, &registered, ttl).await;
+                }
+            }
+        };
+        let refresh = runtime::spawn_abortable(refresh);
+        Redis {
+            client,
+            codec: self.codec,
+            key_prefix: self.key_prefix,
+            ttl: self.ttl,
+            poll_interval: self.poll_interval,
+            notifications: self.notifications,
+            registered,
+            identity: Arc::new(DefaultIdentity),
+            clock: Arc::new(SystemClock),
+            _refresh: refresh,
+        }
+    }
+}
+
+// pushes the expiry of the registrations back, setting the ones that expired
+// again.
+async fn refresh(client: &Client, registered: &Registrations, ttl: Duration) {
+    let registrations = registered.lock().unwrap().clone();
+    for (key, value) in registrations {
+        if let Err(e) = refresh_key(client, &key, &value, ttl).await {
+            error!("failed to refresh {}. {}", String::from_utf8_lossy(&key), e);
+        }
+    }
+}
+
+// pushes the expiry of `key` back, setting it again when expired.
+async fn refresh_key(
+    client: &Client,
+    key: &[u8],
+    value: &[u8],
+    ttl: Duration,
+) -> Result<(), RedisError> {
+    let millis = ttl.as_millis().to_string();
+    match client.call(&[b"PEXPIRE", key, millis.as_bytes()]).await? {
+        Reply::Int(0) => set(client, key, value, ttl).await,
+        _ => Ok(()),
+    }
+}
+
+async fn set(client: &Client, key: &[u8], value: &[u8], ttl: Duration) -> Result<(), RedisError> {
+    let millis = ttl.as_millis().to_string();
+    client
+        .call(&[b"SET", key, value, b"PX", millis.as_bytes()])
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Error(String),
+    Int(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+impl Reply {
+    fn into_bulk(self) -> Option<Vec<u8>> {
+        match self {
+            Reply::Bulk(bulk) => bulk,
+            _ => None,
+        }
+    }
+
+    fn into_array(self) -> Vec<Reply> {
+        match self {
+            Reply::Array(Some(replies)) => replies,
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn encode_command(args: &[&[u8]]) -> Vec<u8> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf
+}
+
+// the reply at the start of `buf` and its length, none while incomplete.
+fn parse_reply(buf: &[u8]) -> Result<Option<(Reply, usize)>, RedisError> {
+    let line_end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+    let line = String::from_utf8_lossy(&buf[1..line_end]).into_owned();
+    let len = || {
+        line.parse::<i64>()
+            .map_err(|_| RedisError::Protocol(format!("bad length {}", line)))
+    };
+    let mut pos = line_end + 2;
+    let reply = match buf[0] {
+        b'+' => Reply::Status(line),
+        b'-' => Reply::Error(line),
+        b':' => Reply::Int(len()?),
+        b'$' => match len()? {
+            len if len < 0 => Reply::Bulk(None),
+            len => {
+                let end = pos + len as usize;
+                if buf.len() < end + 2 {
+                    return Ok(None);
+                }
+                let bulk = buf[pos..end].to_vec();
+                pos = end + 2;
+                Reply::Bulk(Some(bulk))
+            }
+        },
+        b'*' => match len()? {
+            len if len < 0 => Reply::Array(None),
+            len => {
+                let mut replies = Vec::with_capacity(len as usize);
+                for _ in 0..len {
+                    match parse_reply(&buf[pos..])? {
+                        Some((reply, n)) => {
+                            replies.push(reply);
+                            pos += n;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                Reply::Array(Some(replies))
+            }
+        },
+        b => return Err(RedisError::Protocol(format!("bad reply type {}", b))),
+    };
+    Ok(Some((reply, pos)))
+}
+
+// `pattern` matching `s` only, for SCAN and PSUBSCRIBE.
+fn escape_glob(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+struct ConnectOptions {
+    address: String,
+    password: Option<String>,
+    db: u32,
+}
+
+struct Conn {
+    stream: TcpStream,
+    // what was read past the last reply.
+    buf: Vec<u8>,
+}
+
+impl Conn {
+    async fn connect(options: &ConnectOptions) -> Result<Conn, RedisError> {
+        let stream = TcpStream::connect(&*options.address).await?;
+        let mut conn = Conn {
+            stream,
+            buf: Vec::new(),
+        };
+        if let Some(password) = &options.password {
+            conn.call(&[b"AUTH", password.as_bytes()]).await?;
+        }
+        if options.db != 0 {
+            conn.call(&[b"SELECT", options.db.to_string().as_bytes()])
+                .await?;
+        }
+        Ok(conn)
+    }
+
+    async fn call(&mut self, args: &[&[u8]]) -> Result<Reply, RedisError> {
+        self.stream.write_all(&encode_command(args)).await?;
+        match self.read_reply().await? {
+            Reply::Error(e) => Err(RedisError::Server(e)),
+            reply => Ok(reply),
+        }
+    }
+
+    // cancel safe, what was read stays in the buffer.
+    async fn read_reply(&mut self) -> Result<Reply, RedisError> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            if let Some((reply, n)) = parse_reply(&self.buf)? {
+                self.buf.drain(..n);
+                return Ok(reply);
+            }
+            match self.stream.read(&mut chunk).await? {
+                0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                n => self.buf.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Client {
+    options: Arc<ConnectOptions>,
+    // connected on the first call, and again after a failure.
+    conn: Arc<AsyncMutex<Option<Conn>>>,
+}
+
+impl Client {
+    async fn call(&self, args: &[&[u8]]) -> Result<Reply, RedisError> {
+        let mut conn = self.conn.lock().await;
+        if conn.is_none() {
+            *conn = Some(Conn::connect(&self.options).await?);
+        }
+        let result = conn.as_mut().unwrap().call(args).await;
+        // the connection is of no use after an io or protocol error.
+        if let Err(RedisError::Io(_)) | Err(RedisError::Protocol(_)) = result {
+            *conn = None;
+        }
+        result
+    }
+
+    // the keys matching `pattern` with their values.
+    async fn scan(&self, pattern: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>, RedisError> {
+        let mut cursor = b"0".to_vec();
+        let mut keys = Vec::new();
+        loop {
+            let args: [&[u8]; 6] = [
+                b"SCAN",
+                &cursor,
+                b"MATCH",
+                pattern.as_bytes(),
+                b"COUNT",
+                b"100",
+            ];
+            let mut reply = self.call(&args).await?.into_array().into_iter();
+            cursor = reply.next().and_then(Reply::into_bulk).unwrap_or_default();
+            keys.extend(
+                reply
+                    .next()
+                    .map(Reply::into_array)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(Reply::into_bulk),
+            );
+            if cursor == b"0" || cursor.is_empty() {
+                break;
+            }
+        }
+        // a key can be returned more than once.
+        keys.sort();
+        keys.dedup();
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut args: Vec<&[u8]> = vec![b"MGET"];
+        args.extend(keys.iter().map(Vec::as_slice));
+        let values = self.call(&args).await?.into_array();
+        // the keys expired since are nil.
+        let listed = keys
+            .into_iter()
+            .zip(values)
+            .filter_map(|(key, value)| Some((key, value.into_bulk()?)))
+            .collect();
+        Ok(listed)
+    }
+}
+
+#[derive(Debug)]
+pub enum RedisError {
+    Io(io::Error),
+    /// The server answered something else than a reply.
+    Protocol(String),
+    /// The server answered an error.
+    Server(String),
+    Encode(EncodeError),
+    Unsupported,
+}
+
+impl fmt::Display for RedisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RedisError::Io(e) => write!(f, "failed to talk to redis: {}", e),
+            RedisError::Protocol(e) => write!(f, "bad reply from redis: {}", e),
+            RedisError::Server(e) => write!(f, "redis answered: {}", e),
+            RedisError::Encode(e) => e.fmt(f),
+            RedisError::Unsupported => write!(f, "unsupported by redis"),
+        }
+    }
+}
+
+impl error::Error for RedisError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            RedisError::Io(e) => Some(e),
+            RedisError::Encode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RedisError {
+    fn from(e: io::Error) -> Self {
+        RedisError::Io(e)
+    }
+}
+
+impl From<EncodeError> for RedisError {
+    fn from(e: EncodeError) -> Self {
+        RedisError::Encode(e)
+    }
+}
+
+impl<EC, DC, I> Registry for Redis<EC, DC, I>
+where
+    EC: Encoder + Send + Sync + 'static,
+    DC: Decoder + Send + Sync + 'static,
+    I: Identity + Send + Sync + 'static,
+{
+    type Error = RedisError;
+
+    type RegFuture = BoxFuture<'static, Result<(), RedisError>>;
+
+    type DeRegFuture = BoxFuture<'static, Result<(), RedisError>>;
+
+    type ListFuture = BoxFuture<'static, Result<Vec<Arc<Instance>>, RedisError>>;
+
+    type Watcher = RedisWatcher;
+
+    fn register(&self, ins: Arc<Instance>) -> Self::RegFuture {
+        let client = self.client.clone();
+        let registered = self.registered.clone();
+        let key = self.key(&ins);
+        let ttl = self.ttl;
+        let value = self
+            .codec
+            .get_encoder_ref()
+            .encode(&ins)
+            .map_err(|e| -> EncodeError { e.into() });
+        async move {
+            let value = value?;
+            set(&client, &key, &value, ttl).await?;
+            registered.lock().unwrap().insert(key, value);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn deregister(&self, ins: &Arc<Instance>) -> Self::DeRegFuture {
+        let client = self.client.clone();
+        let key = self.key(ins);
+        self.registered.lock().unwrap().remove(&key);
+        async move {
+            client.call(&[b"DEL", &key]).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    // redis doesn't tell why a key went away, so watchers report evictions
+    // as deletes without a reason.
+    fn evict(&self, appid: &str, instance_id: &str) -> Self::DeRegFuture {
+        let client = self.client.clone();
+        let pattern = format!("{}*", escape_glob(&self.dir(appid)));
+        let codec = self.codec.clone();
+        let instance_id = instance_id.to_owned();
+        async move {
+            let decoder = codec.get_decoder_ref();
+            for (key, value) in client.scan(&pattern).await? {
+                let evicted = match decoder.decode(&value) {
+                    Ok(ins) => ins.has_id(&instance_id),
+                    Err(_) => false,
+                };
+                if evicted {
+                    client.call(&[b"DEL", &key]).await?;
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn update_if(&self, _ins: Arc<Instance>, _expected_version: u64) -> Self::RegFuture {
+        future::ready(Err(RedisError::Unsupported)).boxed()
+    }
+
+    fn renew(&self, ins: &Arc<Instance>) -> Self::RegFuture {
+        let client = self.client.clone();
+        let key = self.key(ins);
+        let ttl = self.ttl;
+        let value = self
+            .codec
+            .get_encoder_ref()
+            .encode(ins)
+            .map_err(|e| -> EncodeError { e.into() });
+        async move { refresh_key(&client, &key, &value?, ttl).await }.boxed()
+    }
+
+    fn list(&self, appid: &str) -> Self::ListFuture {
+        let client = self.client.clone();
+        let pattern = format!("{}*", escape_glob(&self.dir(appid)));
+        let codec = self.codec.clone();
+        async move {
+            let decoder = codec.get_decoder_ref();
+            let mut instances = Vec::new();
+            for (key, value) in client.scan(&pattern).await? {
+                match decoder.decode(&value) {
+                    Ok(ins) => instances.push(Arc::new(ins)),
+                    Err(e) => error!("failed to decode {}. {}", String::from_utf8_lossy(&key), e),
+                }
+            }
+            Ok(instances)
+        }
+        .boxed()
+    }
+
+    fn watch(&self, appid: &str) -> Self::Watcher {
+        self.watch_from(appid, &[])
+    }
+
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher {
+        let (tx, rx) = mpsc::unbounded();
+        let mut known = HashMap::new();
+        for ins in instances {
+            known.insert(self.key(ins), ins.clone());
+            let event = WatchEvent::with_clock(Event::Create(ins.clone()), &*self.clock);
+            let _ = tx.unbounded_send(event);
+        }
+        let pattern = format!("{}*", escape_glob(&self.dir(appid)));
+        let channel_prefix = format!("__keyspace@{}__:", self.client.options.db);
+        let watch = AppWatch {
+            client: self.client.clone(),
+            channels: format!("{}{}", channel_prefix, pattern),
+            channel_prefix,
+            pattern,
+            poll_interval: self.poll_interval,
+            notifications: self.notifications,
+            codec: self.codec.clone(),
+            identity: self.identity.clone(),
+            clock: self.clock.clone(),
+            known,
+            tx,
+        };
+        RedisWatcher {
+            rx,
+            _task: runtime::spawn_abortable(watch.run()),
+        }
+    }
+}
+
+/// The instances of an app in redis, see `Registry::watch`.
+#[pin_project]
+pub struct RedisWatcher {
+    #[pin]
+    rx: UnboundedReceiver<WatchEvent>,
+    _task: AbortOnDrop,
+}
+
+impl Stream for RedisWatcher {
+    type Item = WatchEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().rx.poll_next(cx)
+    }
+}
+
+struct AppWatch<EC, DC, I> {
+    client: Client,
+    // the keys of the app.
+    pattern: String,
+    // the notification channels of the keys of the app, and the prefix of a
+    // channel before its key.
+    channels: String,
+    channel_prefix: String,
+    poll_interval: Duration,
+    notifications: bool,
+    codec: Arc<Codec<EC, DC>>,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+    // the instances reported by key.
+    known: HashMap<Vec<u8>, Arc<Instance>>,
+    tx: UnboundedSender<WatchEvent>,
+}
+
+impl<EC, DC, I> AppWatch<EC, DC, I>
+where
+    EC: Encoder,
+    DC: Decoder,
+    I: Identity,
+{
+    async fn run(mut self) {
+        loop {
+            if let Err(e) = self.watch().await {
+                error!("failed to watch {}. {}", self.pattern, e);
+            }
+            runtime::delay_for(RETRY_INTERVAL).await;
+        }
+    }
+
+    // subscribes to the notifications of the keys, then scans them every
+    // poll interval, following the notifications in between.
+    async fn watch(&mut self) -> Result<(), RedisError> {
+        let mut sub = None;
+        if self.notifications {
+            let mut conn = Conn::connect(&self.client.options).await?;
+            conn.call(&[b"PSUBSCRIBE", self.channels.as_bytes()])
+                .await?;
+            sub = Some(conn);
+        }
+        loop {
+            self.scan().await?;
+            let interval = self.poll_interval;
+            if let Some(Err(e)) = runtime::timeout(interval, self.follow(&mut sub)).await {
+                return Err(e);
+            }
+        }
+    }
+
+    // reports the changes notified on `sub`, forever.
+    async fn follow(&mut self, sub: &mut Option<Conn>) -> Result<(), RedisError> {
+        let sub = match sub {
+            Some(sub) => sub,
+            None => return future::pending().await,
+        };
+        loop {
+            let message = sub.read_reply().await?;
+            self.notified(message).await?;
+        }
+    }
+
+    // reports what changed since the last scan.
+    async fn scan(&mut self) -> Result<(), RedisError> {
+        let listed = self.client.scan(&self.pattern).await?;
+        let mut gone = self.known.clone();
+        for (key, value) in listed {
+            gone.remove(&key);
+            self.put(key, &value);
+        }
+        for key in gone.keys() {
+            self.delete(key);
+        }
+        Ok(())
+    }
+
+    async fn notified(&mut self, message: Reply) -> Result<(), RedisError> {
+        let mut message = message
+            .into_array()
+            .into_iter()
+            .skip(2)
+            .filter_map(Reply::into_bulk);
+        let (channel, event) = match (message.next(), message.next()) {
+            (Some(channel), Some(event)) => (channel, event),
+            _ => return Ok(()),
+        };
+        let key = match channel.strip_prefix(self.channel_prefix.as_bytes()) {
+            Some(key) => key.to_vec(),
+            None => return Ok(()),
+        };
+        match &event[..] {
+            b"set" => match self.client.call(&[b"GET", &key]).await?.into_bulk() {
+                Some(value) => self.put(key, &value),
+                None => self.delete(&key),
+            },
+            b"del" | b"expired" | b"evicted" | b"rename_from" => self.delete(&key),
+            // refreshes among others.
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // reports the instance of `key` as a Create, like a re-registration, and
+    // the one it replaces as a Delete when it isn't the same instance.
+    fn put(&mut self, key: Vec<u8>, value: &[u8]) {
+        let ins = match decode_with_payload(self.codec.get_decoder_ref(), value) {
+            Ok(ins) => Arc::new(ins),
+            Err(e) => {
+                error!("failed to decode {}. {}", String::from_utf8_lossy(&key), e);
+                return self.delete(&key);
+            }
+        };
+        let old = self.known.insert(key, ins.clone());
+        // scanned again unchanged.
+        if old.as_ref() == Some(&ins) {
+            return;
+        }
+        self.send(Event::Create(ins.clone()));
+        if let Some(old) = old {
+            if self.identity.identify(&old) != self.identity.identify(&ins) {
+                self.send(Event::Delete(old));
+            }
+        }
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        if let Some(old) = self.known.remove(key) {
+            self.send(Event::Delete(old));
+        }
+    }
+
+    fn send(&self, event: Event) {
+        let _ = self
+            .tx
+            .unbounded_send(WatchEvent::with_clock(event, &*self.clock));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_command, escape_glob, parse_reply, Reply};
+
+    #[test]
+    fn test_resp() {
+        assert_eq!(
+            encode_command(&[b"GET", b"k"]),
+            b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n"
+        );
+        let buf = b"*3\r\n$4\r\nscan\r\n:7\r\n*2\r\n$-1\r\n+OK\r\n-ERR x\r\n";
+        let (reply, n) = parse_reply(buf).unwrap().unwrap();
+        assert_eq!(
+            reply,
+            Reply::Array(Some(vec![
+                Reply::Bulk(Some(b"scan".to_vec())),
+                Reply::Int(7),
+                Reply::Array(Some(vec![
+                    Reply::Bulk(None),
+                    Reply::Status("OK".to_owned())
+                ])),
+            ]))
+        );
+        assert_eq!(
+            parse_reply(&buf[n..]).unwrap().unwrap().0,
+            Reply::Error("ERR x".to_owned())
+        );
+        // incomplete.
+        assert!(parse_reply(b"$4\r\nsc").unwrap().is_none());
+        assert!(parse_reply(b"*2\r\n:1\r\n").unwrap().is_none());
+        assert!(parse_reply(b"?\r\n").is_err());
+
+        assert_eq!(escape_glob("discovery:a*b:"), "discovery:a\\*b:");
+    }
+}
@@ -0,0 +1,112 @@
+//! Registrations that can't outlive the service, e.g. so that a persistent
+//! node isn't left behind when the service shuts down.
+use crate::{runtime, Instance, Registry};
+use log::error;
+use std::{fmt, sync::Arc};
+
+/// Registers `ins`, deregistered once the returned `Registration` is dropped
+/// unless deregistered before.
+///
+/// ```ignore
+/// let registration = register_guarded(Arc::new(zk), instance).await?;
+/// server.await;
+/// registration.deregister().await?;
+/// ```
+pub async fn register_guarded<R>(
+    registry: Arc<R>,
+    ins: Arc<Instance>,
+) -> Result<Registration<R>, R::Error>
+where
+    R: Registry + Send + Sync + 'static,
+    R::DeRegFuture: Send + 'static,
+    R::Error: fmt::Display,
+{
+    registry.register(ins.clone()).await?;
+    Ok(Registration {
+        registry,
+        ins: Some(ins),
+    })
+}
+
+/// An instance registered with `register_guarded`.
+///
+/// Dropping it deregisters the instance in the background, failures being
+/// logged. Await `deregister` to know it's gone, e.g. before exiting.
+pub struct Registration<R>
+where
+    R: Registry + Send + Sync + 'static,
+    R::DeRegFuture: Send + 'static,
+    R::Error: fmt::Display,
+{
+    registry: Arc<R>,
+    // none once deregistered.
+    ins: Option<Arc<Instance>>,
+}
+
+impl<R> Registration<R>
+where
+    R: Registry + Send + Sync + 'static,
+    R::DeRegFuture: Send + 'static,
+    R::Error: fmt::Display,
+{
+    pub fn instance(&self) -> &Arc<Instance> {
+        self.ins.as_ref().expect("deregistered")
+    }
+
+    pub fn registry(&self) -> &Arc<R> {
+        &self.registry
+    }
+
+    pub async fn deregister(mut self) -> Result<(), R::Error> {
+        match self.ins.take() {
+            Some(ins) => self.registry.deregister(&ins).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl<R> Drop for Registration<R>
+where
+    R: Registry + Send + Sync + 'static,
+    R::DeRegFuture: Send + 'static,
+    R::Error: fmt::Display,
+{
+    fn drop(&mut self) {
+        let ins = match self.ins.take() {
+            Some(ins) => ins,
+            None => return,
+        };
+        let deregistered = self.registry.deregister(&ins);
+        runtime::spawn(async move {
+            if let Err(e) = deregistered.await {
+                error!("failed to deregister {:?}. {}", ins.addrs, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::register_guarded;
+    use crate::{mem::MemRegistry, testing::instance};
+    use std::{sync::Arc, time::Duration};
+
+    #[tokio::test]
+    async fn test_register_guarded() {
+        tokio::time::pause();
+        let registry = Arc::new(MemRegistry::new());
+        let (a, b) = (
+            instance("grpc://10.0.0.1:9000"),
+            instance("grpc://10.0.0.2:9000"),
+        );
+        let guarded = register_guarded(registry.clone(), a.clone()).await.unwrap();
+        let explicit = register_guarded(registry.clone(), b.clone()).await.unwrap();
+        assert_eq!(registry.instances("provider").len(), 2);
+
+        explicit.deregister().await.unwrap();
+        assert_eq!(registry.instances("provider"), vec![a]);
+        drop(guarded);
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        assert!(registry.instances("provider").is_empty());
+    }
+}
@@ -0,0 +1,227 @@
+use crate::{
+    balance::{Apply, InstanceSet},
+    runtime,
+    watcher::WatchEvent,
+    Instance, Registry,
+};
+use futures::{
+    channel::oneshot,
+    future::{AbortHandle, Abortable, FutureExt, Shared},
+    pin_mut, Stream, StreamExt,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+const DEFAULT_TTL: Duration = Duration::from_secs(300);
+const DEFAULT_INITIAL_WAIT: Duration = Duration::from_secs(3);
+
+/// Looks up the addresses of an app without dealing with watch streams.
+///
+/// The first lookup of an appid starts watching it and waits (up to
+/// `initial_wait`) for its instances; later lookups are answered from the
+/// kept up to date cache. Apps not looked up for `ttl` stop being watched.
+///
+/// ```ignore
+/// let resolver = Resolver::new(zk).scheme("grpc");
+/// let addrs = resolver.resolve("/dubbo-rs/provider").await;
+/// ```
+pub struct Resolver<R> {
+    registry: R,
+    ttl: Duration,
+    initial_wait: Duration,
+    scheme: Option<String>,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+struct Entry {
+    instances: InstanceSet,
+    ready: Shared<oneshot::Receiver<()>>,
+    abort: AbortHandle,
+    last_used: Instant,
+}
+
+impl<R> Resolver<R>
+where
+    R: Registry,
+    R::Watcher: Send + 'static,
+{
+    pub fn new(registry: R) -> Self {
+        Resolver {
+            registry,
+            ttl: DEFAULT_TTL,
+            initial_wait: DEFAULT_INITIAL_WAIT,
+            scheme: None,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How long an app stays watched after its last lookup.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// How long the first lookup of an app waits for its instances.
+    pub fn initial_wait(mut self, initial_wait: Duration) -> Self {
+        self.initial_wait = initial_wait;
+        self
+    }
+
+    /// Only resolve addresses with this scheme, e.g. `grpc` for `grpc://...`.
+    pub fn scheme(mut self, scheme: impl Into<String>) -> Self {
+        self.scheme = Some(scheme.into());
+        self
+    }
+
+    /// The socket addresses of all instances of `appid`. Addresses whose host
+    /// isn't an IP literal are skipped.
+    pub async fn resolve(&self, appid: &str) -> Vec<SocketAddr> {
+        self.instances(appid)
+            .await
+            .iter()
+            .flat_map(|ins| ins.addrs.iter())
+            .filter_map(|addr| socket_addr(addr, self.scheme.as_deref()))
+            .collect()
+    }
+
+    pub async fn instances(&self, appid: &str) -> Arc<Vec<Arc<Instance>>> {
+        let (instances, ready) = self.entry(appid);
+        let _ = runtime::timeout(self.initial_wait, ready).await;
+        instances.snapshot()
+    }
+
+    fn entry(&self, appid: &str) -> (InstanceSet, Shared<oneshot::Receiver<()>>) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let ttl = self.ttl;
+        entries.retain(|_, entry| {
+            let alive = now.duration_since(entry.last_used) < ttl;
+            if !alive {
+                entry.abort.abort();
+            }
+            alive
+        });
+
+        let registry = &self.registry;
+        let entry = entries.entry(appid.to_owned()).or_insert_with(|| {
+            let instances = InstanceSet::new();
+            let (ready_tx, ready_rx) = oneshot::channel();
+            let (abort, registration) = AbortHandle::new_pair();
+            runtime::spawn(Abortable::new(
+                keep_updated(registry.watch(appid), instances.clone(), ready_tx),
+                registration,
+            ));
+            Entry {
+                instances,
+                ready: ready_rx.shared(),
+                abort,
+                last_used: now,
+            }
+        });
+        entry.last_used = now;
+        (entry.instances.clone(), entry.ready.clone())
+    }
+}
+
+impl<R> Drop for Resolver<R> {
+    fn drop(&mut self) {
+        for entry in self.entries.lock().unwrap().values() {
+            entry.abort.abort();
+        }
+    }
+}
+
+async fn keep_updated<W>(watcher: W, instances: InstanceSet, ready: oneshot::Sender<()>)
+where
+    W: Stream<Item = WatchEvent>,
+{
+    pin_mut!(watcher);
+    let mut ready = Some(ready);
+    while let Some(watch_event) = watcher.next().await {
+        instances.apply(&watch_event.event);
+        if let Some(ready) = ready.take() {
+            // the instances present when the watch starts are sent back to back.
+            while let Some(Some(watch_event)) = watcher.next().now_or_never() {
+                instances.apply(&watch_event.event);
+            }
+            let _ = ready.send(());
+        }
+    }
+}
+
+fn socket_addr(addr: &str, scheme: Option<&str>) -> Option<SocketAddr> {
+    let (addr_scheme, rest) = match addr.find("://") {
+        Some(pos) => (&addr[..pos], &addr[pos + 3..]),
+        None => ("", addr),
+    };
+    if matches!(scheme, Some(scheme) if scheme != addr_scheme) {
+        return None;
+    }
+    rest.split('/').next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{socket_addr, Resolver};
+    use crate::{testing::MockRegistry, Instance};
+    use std::{net::SocketAddr, time::Duration};
+
+    fn instance(addrs: &[&str]) -> Instance {
+        Instance {
+            appid: "provider".into(),
+            addrs: addrs.iter().map(|a| a.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_socket_addr() {
+        let expected = "172.1.1.1:9999".parse::<SocketAddr>().ok();
+        assert_eq!(socket_addr("grpc://172.1.1.1:9999", None), expected);
+        assert_eq!(
+            socket_addr("grpc://172.1.1.1:9999/", Some("grpc")),
+            expected
+        );
+        assert_eq!(socket_addr("172.1.1.1:9999", None), expected);
+        assert_eq!(socket_addr("http://172.1.1.1:9999", Some("grpc")), None);
+        assert_eq!(socket_addr("grpc://provider.local:9999", None), None);
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        tokio::time::pause();
+        let registry = MockRegistry::new();
+        let resolver = Resolver::new(registry.clone())
+            .scheme("grpc")
+            .initial_wait(Duration::from_secs(5));
+
+        registry.insert(instance(&[
+            "http://172.1.1.1:8000",
+            "grpc://172.1.1.1:9999",
+        ]));
+        registry.insert(instance(&["grpc://172.1.1.2:9999"]));
+        let mut addrs = resolver.resolve("provider").await;
+        addrs.sort();
+        assert_eq!(
+            addrs,
+            vec![
+                "172.1.1.1:9999".parse::<SocketAddr>().unwrap(),
+                "172.1.1.2:9999".parse::<SocketAddr>().unwrap()
+            ]
+        );
+
+        // answered from the same watch.
+        registry.remove(&instance(&[
+            "http://172.1.1.1:8000",
+            "grpc://172.1.1.1:9999",
+        ]));
+        tokio::time::delay_for(Duration::from_millis(1)).await;
+        while resolver.resolve("provider").await.len() != 1 {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use crate::{
+    balance::{InstanceSet, Selector},
+    identity::DefaultIdentity,
+    Instance,
+};
+use rand::Rng;
+use std::{collections::HashMap, sync::Arc};
+
+pub use group::{GroupFilter, GroupRouter, DEFAULT_GROUP};
+pub use zone::ZoneFailover;
+
+mod group;
+mod zone;
+
+/// A group of instances traffic can be routed to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Version(String),
+    Metadata { key: String, value: String },
+}
+
+impl Target {
+    pub fn version(version: impl Into<String>) -> Self {
+        Target::Version(version.into())
+    }
+
+    pub fn metadata(key: impl Into<String>, value: impl Into<String>) -> Self {
+        Target::Metadata {
+            key: key.into(),
+            value: value.into(),
+        }
+    }
+
+    pub fn matches(&self, ins: &Instance) -> bool {
+        match self {
+            Target::Version(version) => ins.version == *version,
+            Target::Metadata { key, value } => ins.metadata.get(key) == Some(value),
+        }
+    }
+}
+
+enum Splits {
+    Static(Vec<(Target, u32)>),
+    Metadata(String),
+}
+
+struct LabelRule {
+    key: String,
+    value: String,
+    target: Target,
+}
+
+/// Splits traffic between versions of an app, for canary releases.
+///
+/// Requests carrying a label with a matching rule go to that rule's target.
+/// The rest is split by weight, either configured with `split` or read from
+/// the registry with `splits_from_metadata`. Targets without any registered
+/// instance are skipped, and without any usable split traffic goes to all
+/// instances.
+///
+/// ```ignore
+/// // 95% to version 111, 5% to version 112, testers always get 112.
+/// let router = VersionRouter::new(instances)
+///     .split(Target::version("111"), 95)
+///     .split(Target::version("112"), 5)
+///     .label("user-group", "tester", Target::version("112"));
+/// let ins = router.route(&request_labels);
+/// ```
+pub struct VersionRouter<I = DefaultIdentity> {
+    instances: InstanceSet<I>,
+    splits: Splits,
+    rules: Vec<LabelRule>,
+}
+
+impl<I> VersionRouter<I> {
+    pub fn new(instances: InstanceSet<I>) -> Self {
+        VersionRouter {
+            instances,
+            splits: Splits::Static(Vec::new()),
+            rules: Vec::new(),
+        }
+    }
+
+    /// Sends `weight` parts of the traffic to `target`.
+    pub fn split(mut self, target: Target, weight: u32) -> Self {
+        match &mut self.splits {
+            Splits::Static(splits) => splits.push((target, weight)),
+            Splits::Metadata(_) => self.splits = Splits::Static(vec![(target, weight)]),
+        }
+        self
+    }
+
+    /// Takes the percentage of each version from the `key` metadata of its
+    /// instances, e.g. version 112 registered with `traffic=5` gets 5%.
+    /// Versions not declaring one share what is left of 100% equally.
+    pub fn splits_from_metadata(mut self, key: impl Into<String>) -> Self {
+        self.splits = Splits::Metadata(key.into());
+        self
+    }
+
+    /// Requests with label `key` equal to `value` go to `target`.
+    pub fn label(
+        mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+        target: Target,
+    ) -> Self {
+        self.rules.push(LabelRule {
+            key: key.into(),
+            value: value.into(),
+            target,
+        });
+        self
+    }
+
+    pub fn route(&self, labels: &HashMap<String, String>) -> Option<Arc<Instance>> {
+        let instances = self.instances.snapshot();
+        for rule in self.rules.iter() {
+            if labels.get(&rule.key) == Some(&rule.value) {
+                let candidates = matching(&instances, &rule.target);
+                if !candidates.is_empty() {
+                    return pick_one(&candidates);
+                }
+            }
+        }
+
+        let groups = match &self.splits {
+            Splits::Static(splits) => splits
+                .iter()
+                .map(|(target, weight)| (matching(&instances, target), *weight))
+                .filter(|(candidates, _)| !candidates.is_empty())
+                .collect::<Vec<_>>(),
+            Splits::Metadata(key) => version_groups(&instances, key),
+        };
+        let total = groups.iter().map(|(_, weight)| *weight as u64).sum::<u64>();
+        if total == 0 {
+            return pick_one(&instances);
+        }
+        let mut point = rand::thread_rng().gen_range(0, total);
+        for (candidates, weight) in groups.iter() {
+            if point < *weight as u64 {
+                return pick_one(candidates);
+            }
+            point -= *weight as u64;
+        }
+        None
+    }
+}
+
+impl<I> Selector for VersionRouter<I> {
+    fn pick(&self) -> Option<Arc<Instance>> {
+        self.route(&HashMap::new())
+    }
+}
+
+fn matching(instances: &[Arc<Instance>], target: &Target) -> Vec<Arc<Instance>> {
+    instances
+        .iter()
+        .filter(|ins| target.matches(ins))
+        .cloned()
+        .collect()
+}
+
+fn version_groups(instances: &[Arc<Instance>], key: &str) -> Vec<(Vec<Arc<Instance>>, u32)> {
+    let mut groups: Vec<(Vec<Arc<Instance>>, Option<u32>)> = Vec::new();
+    for ins in instances.iter() {
+        let declared = ins.metadata.get(key).and_then(|w| w.parse::<u32>().ok());
+        match groups
+            .iter_mut()
+            .find(|(group, _)| group[0].version == ins.version)
+        {
+            Some((group, weight)) => {
+                group.push(ins.clone());
+                *weight = (*weight).max(declared);
+            }
+            None => groups.push((vec![ins.clone()], declared)),
+        }
+    }
+
+    let declared = groups.iter().filter_map(|(_, weight)| *weight).sum::<u32>();
+    let undeclared = groups.iter().filter(|(_, weight)| weight.is_none()).count() as u32;
+    let rest = 100u32
+        .saturating_sub(declared)
+        .checked_div(undeclared)
+        .unwrap_or(0);
+    groups
+        .into_iter()
+        .map(|(group, weight)| (group, weight.unwrap_or(rest)))
+        .collect()
+}
+
+fn pick_one(instances: &[Arc<Instance>]) -> Option<Arc<Instance>> {
+    if instances.is_empty() {
+        return None;
+    }
+    Some(instances[rand::thread_rng().gen_range(0, instances.len())].clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Target, VersionRouter};
+    use crate::{
+        balance::{Apply, InstanceSet, Selector},
+        watcher::Event,
+        Instance,
+    };
+    use std::{collections::HashMap, sync::Arc};
+
+    fn instance(addr: &str, version: &str, traffic: Option<&str>) -> Arc<Instance> {
+        Arc::new(Instance {
+            appid: "provider".into(),
+            version: version.to_owned(),
+            addrs: vec![addr.to_owned()].into(),
+            metadata: traffic
+                .iter()
+                .map(|t| ("traffic".to_owned(), t.to_string()))
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    fn instances(canary_traffic: Option<&str>) -> InstanceSet {
+        let set = InstanceSet::new();
+        set.apply(&Event::Create(instance(
+            "grpc://172.1.1.1:9999",
+            "111",
+            None,
+        )));
+        set.apply(&Event::Create(instance(
+            "grpc://172.1.1.2:9999",
+            "111",
+            None,
+        )));
+        set.apply(&Event::Create(instance(
+            "grpc://172.1.1.3:9999",
+            "112",
+            canary_traffic,
+        )));
+        set
+    }
+
+    fn canary_share(router: &VersionRouter) -> usize {
+        (0..2000)
+            .filter(|_| router.pick().unwrap().version == "112")
+            .count()
+    }
+
+    #[test]
+    fn test_static_split() {
+        let router = VersionRouter::new(instances(None))
+            .split(Target::version("111"), 90)
+            .split(Target::version("112"), 10);
+        let canary = canary_share(&router);
+        assert!(canary > 100 && canary < 300, "canary got {}", canary);
+    }
+
+    #[test]
+    fn test_split_skips_unregistered_target() {
+        let router = VersionRouter::new(instances(None))
+            .split(Target::version("113"), 99)
+            .split(Target::version("112"), 1);
+        assert_eq!(canary_share(&router), 2000);
+    }
+
+    #[test]
+    fn test_splits_from_metadata() {
+        let router = VersionRouter::new(instances(Some("20"))).splits_from_metadata("traffic");
+        let canary = canary_share(&router);
+        assert!(canary > 300 && canary < 500, "canary got {}", canary);
+    }
+
+    #[test]
+    fn test_label_rule() {
+        let router = VersionRouter::new(instances(None))
+            .split(Target::version("111"), 100)
+            .label("user-group", "tester", Target::version("112"));
+        let labels = [("user-group".to_owned(), "tester".to_owned())]
+            .iter()
+            .cloned()
+            .collect::<HashMap<String, String>>();
+        for _ in 0..100 {
+            assert_eq!(router.route(&labels).unwrap().version, "112");
+            assert_eq!(router.pick().unwrap().version, "111");
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use super::pick_one;
+use crate::{
+    balance::{InstanceSet, Selector},
+    identity::{DefaultIdentity, Identity},
+    watcher::{Event, WatchEvent},
+    Instance,
+};
+use futures::{ready, Stream};
+use pin_project::pin_project;
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// The group of the instances not in any lane.
+pub const DEFAULT_GROUP: &str = "";
+
+/// Sends requests tagged with a lane to the instances of that group, and the
+/// rest, or all of them when the lane has no instance, to the default group.
+///
+/// ```ignore
+/// let router = GroupRouter::new(instances);
+/// // a "feature-x" instance, any instance of the default group without one.
+/// let ins = router.route(Some("feature-x"));
+/// ```
+pub struct GroupRouter<I = DefaultIdentity> {
+    instances: InstanceSet<I>,
+    label: String,
+}
+
+impl<I> GroupRouter<I> {
+    pub fn new(instances: InstanceSet<I>) -> Self {
+        GroupRouter {
+            instances,
+            label: "lane".to_owned(),
+        }
+    }
+
+    /// The request label `route_labels` reads the lane from, `lane` unless
+    /// configured.
+    pub fn label(mut self, key: impl Into<String>) -> Self {
+        self.label = key.into();
+        self
+    }
+
+    pub fn route(&self, lane: Option<&str>) -> Option<Arc<Instance>> {
+        let instances = self.instances.snapshot();
+        if let Some(lane) = lane.filter(|lane| *lane != DEFAULT_GROUP) {
+            let candidates = in_group(&instances, lane);
+            if !candidates.is_empty() {
+                return pick_one(&candidates);
+            }
+        }
+        pick_one(&in_group(&instances, DEFAULT_GROUP))
+    }
+
+    pub fn route_labels(&self, labels: &HashMap<String, String>) -> Option<Arc<Instance>> {
+        self.route(labels.get(&self.label).map(String::as_str))
+    }
+}
+
+impl<I> Selector for GroupRouter<I> {
+    fn pick(&self) -> Option<Arc<Instance>> {
+        self.route(None)
+    }
+}
+
+fn in_group(instances: &[Arc<Instance>], group: &str) -> Vec<Arc<Instance>> {
+    instances
+        .iter()
+        .filter(|ins| *ins.group == *group)
+        .cloned()
+        .collect()
+}
+
+/// Passes on the events of the instances of `groups` only, e.g. to watch a
+/// lane along with the default group. An instance moving to another group is
+/// reported deleted.
+#[pin_project]
+pub struct GroupFilter<W, I = DefaultIdentity>
+where
+    I: Identity,
+{
+    #[pin]
+    watcher: W,
+    groups: Vec<Arc<str>>,
+    identity: I,
+    // the instances passed on, to delete those moving out.
+    reported: HashMap<I::Key, Arc<Instance>>,
+}
+
+impl<W> GroupFilter<W> {
+    pub fn new<G>(watcher: W, groups: G) -> Self
+    where
+        G: IntoIterator,
+        G::Item: Into<Arc<str>>,
+    {
+        Self::with_identity(watcher, groups, DefaultIdentity)
+    }
+}
+
+impl<W, I> GroupFilter<W, I>
+where
+    I: Identity,
+{
+    pub fn with_identity<G>(watcher: W, groups: G, identity: I) -> Self
+    where
+        G: IntoIterator,
+        G::Item: Into<Arc<str>>,
+    {
+        GroupFilter {
+            watcher,
+            groups: groups.into_iter().map(Into::into).collect(),
+            identity,
+            reported: HashMap::new(),
+        }
+    }
+}
+
+impl<W, I> Stream for GroupFilter<W, I>
+where
+    W: Stream<Item = WatchEvent>,
+    I: Identity,
+{
+    type Item = WatchEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            let watch_event = match ready!(this.watcher.as_mut().poll_next(cx)) {
+                Some(watch_event) => watch_event,
+                None => return Poll::Ready(None),
+            };
+            let event = match &watch_event.event {
+                Event::Create(ins) if this.groups.contains(&ins.group) => {
+                    this.reported
+                        .insert(this.identity.identify(ins), ins.clone());
+                    Event::Create(ins.clone())
+                }
+                Event::Update(ins) if this.groups.contains(&ins.group) => {
+                    let key = this.identity.identify(ins);
+                    match this.reported.insert(key, ins.clone()) {
+                        Some(_) => Event::Update(ins.clone()),
+                        // moved into the groups.
+                        None => Event::Create(ins.clone()),
+                    }
+                }
+                Event::Create(ins) | Event::Update(ins) | Event::Delete(ins) => {
+                    match this.reported.remove(&this.identity.identify(ins)) {
+                        Some(reported) => Event::Delete(reported),
+                        None => continue,
+                    }
+                }
+            };
+            return Poll::Ready(Some(WatchEvent {
+                event,
+                ..watch_event
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GroupFilter, GroupRouter, DEFAULT_GROUP};
+    use crate::{
+        balance::{Apply, InstanceSet, Selector},
+        watcher::{Event, WatchEvent},
+        Instance,
+    };
+    use futures::{stream, StreamExt};
+    use std::sync::Arc;
+
+    fn instance(addr: &str, group: &str) -> Arc<Instance> {
+        Arc::new(Instance {
+            appid: "provider".into(),
+            addrs: vec![addr.to_owned()].into(),
+            group: group.into(),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_groups() {
+        let set = InstanceSet::new();
+        set.apply(&Event::Create(instance("grpc://172.1.1.1:9999", "")));
+        set.apply(&Event::Create(instance("grpc://172.1.1.2:9999", "blue")));
+        let router = GroupRouter::new(set);
+        for _ in 0..100 {
+            assert_eq!(&*router.route(Some("blue")).unwrap().group, "blue");
+            assert_eq!(&*router.route(Some("green")).unwrap().group, "");
+            assert_eq!(&*router.pick().unwrap().group, "");
+        }
+
+        let events = vec![
+            Event::Create(instance("grpc://172.1.1.1:9999", "")),
+            Event::Create(instance("grpc://172.1.1.2:9999", "blue")),
+            Event::Create(instance("grpc://172.1.1.3:9999", "green")),
+            // moved out of the lane.
+            Event::Create(instance("grpc://172.1.1.2:9999", "green")),
+            Event::Delete(instance("grpc://172.1.1.3:9999", "green")),
+        ];
+        let filtered = GroupFilter::new(
+            stream::iter(events.into_iter().map(WatchEvent::new)),
+            vec![DEFAULT_GROUP, "blue"],
+        )
+        .map(|watch_event| match watch_event.event {
+            Event::Create(ins) => format!("create {} {}", ins.addrs[0], ins.group),
+            Event::Update(ins) => format!("update {} {}", ins.addrs[0], ins.group),
+            Event::Delete(ins) => format!("delete {} {}", ins.addrs[0], ins.group),
+        })
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(
+            filtered,
+            vec![
+                "create grpc://172.1.1.1:9999 ",
+                "create grpc://172.1.1.2:9999 blue",
+                "delete grpc://172.1.1.2:9999 blue",
+            ]
+        );
+    }
+}
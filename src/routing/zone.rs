@@ -0,0 +1,267 @@
+use crate::{balance::InstanceSet, balance::Selector, identity::DefaultIdentity, Instance};
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+const DEFAULT_MIN_RATIO: f64 = 0.7;
+
+/// Keeps traffic in the local zone while it has enough capacity, then spills
+/// it to the other zones of the region and finally to other regions.
+///
+/// The capacity of a zone is the sum of its instances' weights (1 when not
+/// set), and is compared to the most the zone has had so far. While the local
+/// zone is above `min_ratio` of that it gets all the traffic; below, it gets a
+/// share proportional to what it has left and the rest goes one level further
+/// out, which is in turn limited the same way. An instance's region is read
+/// from its `region` metadata.
+///
+/// ```ignore
+/// let router = ZoneFailover::new(instances, "sh001").region("sh").min_ratio(0.8);
+/// let ins = router.pick();
+/// ```
+pub struct ZoneFailover<I = DefaultIdentity> {
+    instances: InstanceSet<I>,
+    zone: String,
+    region: Option<String>,
+    min_ratio: f64,
+    // the most capacity each zone has had, with the level it is in.
+    peaks: Mutex<HashMap<Arc<str>, (usize, u64)>>,
+}
+
+impl<I> ZoneFailover<I> {
+    pub fn new(instances: InstanceSet<I>, zone: impl Into<String>) -> Self {
+        ZoneFailover {
+            instances,
+            zone: zone.into(),
+            region: None,
+            min_ratio: DEFAULT_MIN_RATIO,
+            peaks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The local region, without it every other zone counts as another region.
+    pub fn region(mut self, region: impl Into<String>) -> Self {
+        self.region = Some(region.into());
+        self
+    }
+
+    /// The share of its capacity below which a level starts spilling, 0.7
+    /// unless configured.
+    pub fn min_ratio(mut self, min_ratio: f64) -> Self {
+        self.min_ratio = min_ratio.clamp(f64::MIN_POSITIVE, 1.0);
+        self
+    }
+
+    /// The part of the traffic each level gets right now: the local zone, the
+    /// rest of the region and the other regions.
+    pub fn shares(&self) -> [f64; 3] {
+        self.levels().1
+    }
+
+    fn level(&self, ins: &Instance) -> usize {
+        if *ins.zone == *self.zone {
+            0
+        } else if self.region.is_some() && ins.metadata.get("region") == self.region.as_ref() {
+            1
+        } else {
+            2
+        }
+    }
+
+    fn levels(&self) -> ([Vec<Arc<Instance>>; 3], [f64; 3]) {
+        let mut levels: [Vec<Arc<Instance>>; 3] = Default::default();
+        for ins in self.instances.snapshot().iter() {
+            levels[self.level(ins)].push(ins.clone());
+        }
+
+        let mut peaks = self.peaks.lock().unwrap();
+        let mut capacities = HashMap::new();
+        for (level, instances) in levels.iter().enumerate() {
+            for ins in instances.iter() {
+                capacities.entry(&ins.zone).or_insert((level, 0)).1 += capacity(ins);
+            }
+        }
+        for (zone, (level, capacity)) in capacities.into_iter() {
+            let peak = peaks.entry(zone.clone()).or_insert((level, 0));
+            *peak = (level, peak.1.max(capacity));
+        }
+
+        let mut shares = [0f64; 3];
+        let mut remaining = 1f64;
+        for (level, instances) in levels.iter().enumerate() {
+            let peak = peaks
+                .values()
+                .filter(|(l, _)| *l == level)
+                .map(|(_, peak)| *peak)
+                .sum::<u64>();
+            if peak == 0 {
+                continue;
+            }
+            let health = capacities_of(instances) as f64 / peak as f64;
+            shares[level] = (health / self.min_ratio).min(remaining);
+            remaining -= shares[level];
+        }
+        // everything is degraded, share what there is.
+        let total = shares.iter().sum::<f64>();
+        if total > 0.0 {
+            for share in shares.iter_mut() {
+                *share /= total;
+            }
+        }
+        (levels, shares)
+    }
+}
+
+impl<I> Selector for ZoneFailover<I> {
+    fn pick(&self) -> Option<Arc<Instance>> {
+        let (levels, shares) = self.levels();
+        let mut rng = rand::thread_rng();
+        let mut point = rng.gen::<f64>();
+        let level = shares
+            .iter()
+            .position(|share| {
+                if point < *share {
+                    return true;
+                }
+                point -= share;
+                false
+            })
+            .or_else(|| levels.iter().rposition(|level| !level.is_empty()))?;
+
+        let instances = &levels[level];
+        let total = capacities_of(instances);
+        if total == 0 {
+            return None;
+        }
+        let mut point = rng.gen_range(0, total);
+        for ins in instances.iter() {
+            if point < capacity(ins) {
+                return Some(ins.clone());
+            }
+            point -= capacity(ins);
+        }
+        None
+    }
+}
+
+fn capacity(ins: &Instance) -> u64 {
+    ins.weight().unwrap_or(1) as u64
+}
+
+fn capacities_of(instances: &[Arc<Instance>]) -> u64 {
+    instances.iter().map(|ins| capacity(ins)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ZoneFailover;
+    use crate::{
+        balance::{Apply, InstanceSet, Selector},
+        watcher::Event,
+        Instance,
+    };
+    use std::sync::Arc;
+
+    fn instance(addr: &str, zone: &str, region: &str) -> Arc<Instance> {
+        Arc::new(Instance {
+            appid: "provider".into(),
+            zone: zone.into(),
+            addrs: vec![addr.to_owned()].into(),
+            metadata: [("region".to_owned(), region.to_owned())]
+                .iter()
+                .cloned()
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    fn instances() -> InstanceSet {
+        let set = InstanceSet::new();
+        for i in 1..=4 {
+            set.apply(&Event::Create(instance(
+                &format!("grpc://172.1.1.{}:9999", i),
+                "sh001",
+                "sh",
+            )));
+            set.apply(&Event::Create(instance(
+                &format!("grpc://172.1.2.{}:9999", i),
+                "sh002",
+                "sh",
+            )));
+            set.apply(&Event::Create(instance(
+                &format!("grpc://172.2.1.{}:9999", i),
+                "bj001",
+                "bj",
+            )));
+        }
+        set
+    }
+
+    #[test]
+    fn test_zone_failover_stays_local() {
+        let router = ZoneFailover::new(instances(), "sh001").region("sh");
+        assert_eq!(router.shares(), [1.0, 0.0, 0.0]);
+        for _ in 0..100 {
+            assert_eq!(&*router.pick().unwrap().zone, "sh001");
+        }
+    }
+
+    #[test]
+    fn test_zone_failover_spills() {
+        let set = instances();
+        let router = ZoneFailover::new(set.clone(), "sh001")
+            .region("sh")
+            .min_ratio(0.75);
+        router.shares();
+
+        // 3 of 4 left is still enough.
+        set.apply(&Event::Delete(instance(
+            "grpc://172.1.1.1:9999",
+            "sh001",
+            "sh",
+        )));
+        assert_eq!(router.shares(), [1.0, 0.0, 0.0]);
+
+        // 1 of 4 left keeps a third of the traffic, the region takes the rest.
+        set.apply(&Event::Delete(instance(
+            "grpc://172.1.1.2:9999",
+            "sh001",
+            "sh",
+        )));
+        set.apply(&Event::Delete(instance(
+            "grpc://172.1.1.3:9999",
+            "sh001",
+            "sh",
+        )));
+        let shares = router.shares();
+        assert!((shares[0] - 1.0 / 3.0).abs() < 1e-9);
+        assert!((shares[1] - 2.0 / 3.0).abs() < 1e-9);
+
+        // the region is degraded too, other regions take the rest.
+        for i in 1..=3 {
+            set.apply(&Event::Delete(instance(
+                &format!("grpc://172.1.2.{}:9999", i),
+                "sh002",
+                "sh",
+            )));
+        }
+        let shares = router.shares();
+        assert!((shares[2] - 1.0 / 3.0).abs() < 1e-9);
+
+        set.apply(&Event::Delete(instance(
+            "grpc://172.1.1.4:9999",
+            "sh001",
+            "sh",
+        )));
+        set.apply(&Event::Delete(instance(
+            "grpc://172.1.2.4:9999",
+            "sh002",
+            "sh",
+        )));
+        for _ in 0..100 {
+            assert_eq!(&*router.pick().unwrap().zone, "bj001");
+        }
+    }
+}
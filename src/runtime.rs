@@ -0,0 +1,166 @@
+//! The executor the background tasks of the crate run on, tokio by default.
+//!
+//! Without the `rt-tokio` feature, set one before using the crate:
+//!
+//! ```ignore
+//! struct AsyncStd;
+//!
+//! impl Runtime for AsyncStd {
+//!     fn spawn(&self, task: BoxFuture<'static, ()>) {
+//!         async_std::task::spawn(task);
+//!     }
+//!
+//!     fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>) {
+//!         async_std::task::spawn_blocking(f);
+//!     }
+//!
+//!     fn delay_for(&self, duration: Duration) -> BoxFuture<'static, ()> {
+//!         async_std::task::sleep(duration).boxed()
+//!     }
+//! }
+//!
+//! discover::runtime::set(AsyncStd);
+//! ```
+use futures::{
+    channel::oneshot,
+    future::{self, AbortHandle, Abortable, BoxFuture, Either},
+    pin_mut, Future, FutureExt,
+};
+use lazy_static::lazy_static;
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+pub trait Runtime: Send + Sync {
+    fn spawn(&self, task: BoxFuture<'static, ()>);
+
+    /// Runs `f` where blocking doesn't hold up other tasks.
+    fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>);
+
+    fn delay_for(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// Spawns on the tokio runtime the caller is in.
+#[cfg(any(test, feature = "rt-tokio"))]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioRuntime;
+
+#[cfg(any(test, feature = "rt-tokio"))]
+impl Runtime for TokioRuntime {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        tokio::spawn(task);
+    }
+
+    fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>) {
+        tokio::task::spawn_blocking(f);
+    }
+
+    fn delay_for(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        tokio::time::delay_for(duration).boxed()
+    }
+}
+
+lazy_static! {
+    static ref RUNTIME: RwLock<Option<Arc<dyn Runtime>>> = RwLock::new(default_runtime());
+}
+
+// the tests of the crate run on tokio whatever the features.
+#[cfg(any(test, feature = "rt-tokio"))]
+fn default_runtime() -> Option<Arc<dyn Runtime>> {
+    Some(Arc::new(TokioRuntime))
+}
+
+#[cfg(not(any(test, feature = "rt-tokio")))]
+fn default_runtime() -> Option<Arc<dyn Runtime>> {
+    None
+}
+
+/// Sets the runtime of the tasks spawned from now on.
+pub fn set<R>(runtime: R)
+where
+    R: Runtime + 'static,
+{
+    *RUNTIME.write().unwrap() = Some(Arc::new(runtime));
+}
+
+fn current() -> Arc<dyn Runtime> {
+    RUNTIME
+        .read()
+        .unwrap()
+        .clone()
+        .expect("no runtime, enable the rt-tokio feature or call discover::runtime::set")
+}
+
+pub(crate) fn spawn<F>(task: F)
+where
+    F: Future + Send + 'static,
+{
+    current().spawn(task.map(drop).boxed())
+}
+
+/// Runs `f` with `Runtime::spawn_blocking`, `None` when it panicked.
+pub(crate) fn spawn_blocking<F, T>(f: F) -> impl Future<Output = Option<T>>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    current().spawn_blocking(Box::new(move || {
+        let _ = tx.send(f());
+    }));
+    rx.map(Result::ok)
+}
+
+/// Aborts the task it was returned for once dropped, see `spawn_abortable`.
+pub(crate) struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Spawns `task` until the handle returned is dropped, e.g. the background
+/// task of a watcher or a registration.
+pub(crate) fn spawn_abortable<F>(task: F) -> AbortOnDrop
+where
+    F: Future + Send + 'static,
+{
+    let (abort, registration) = AbortHandle::new_pair();
+    spawn(Abortable::new(task, registration));
+    AbortOnDrop(abort)
+}
+
+pub(crate) fn delay_for(duration: Duration) -> BoxFuture<'static, ()> {
+    current().delay_for(duration)
+}
+
+/// The output of `fut`, `None` when it takes longer than `duration`.
+pub(crate) async fn timeout<F>(duration: Duration, fut: F) -> Option<F::Output>
+where
+    F: Future,
+{
+    pin_mut!(fut);
+    match future::select(fut, delay_for(duration)).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{spawn_blocking, timeout};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_runtime() {
+        assert_eq!(spawn_blocking(|| 1).await, Some(1));
+        assert_eq!(spawn_blocking(|| panic!("boom")).await, None::<()>);
+
+        let quick = timeout(Duration::from_millis(100), async { 1 }).await;
+        assert_eq!(quick, Some(1));
+        let never = futures::future::pending::<()>();
+        assert_eq!(timeout(Duration::from_millis(1), never).await, None);
+    }
+}
@@ -0,0 +1,248 @@
+use crate::{
+    balance::{Apply, InstanceSet},
+    identity::{DefaultIdentity, Identity},
+    runtime,
+    watcher::WatchEvent,
+    Instance, Registry,
+};
+use futures::{
+    future::{AbortHandle, Abortable, MapErr},
+    pin_mut,
+    task::AtomicWaker,
+    Stream, StreamExt, TryFutureExt,
+};
+use log::warn;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tower::Service;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+type Key = <DefaultIdentity as Identity>::Key;
+
+/// A `tower::Service` calling the instances of an app, so callers don't have
+/// to wire up a watcher, a balancer and per-instance clients themselves.
+///
+/// Requests are spread round-robin. Each instance gets its own service, made
+/// by the connector the first time the instance is picked; a service failing
+/// `poll_ready` is dropped and made again the next time its instance is
+/// picked. Until the app has instances, `poll_ready` stays pending.
+///
+/// ```ignore
+/// let mut svc = DiscoveredService::new(&zk, "/dubbo-rs/provider", |ins: &Instance| {
+///     HttpClient::new(ins.addrs[0].clone())
+/// });
+/// let rsp = svc.ready_and().await?.call(req).await?;
+/// ```
+pub struct DiscoveredService<C, S> {
+    instances: InstanceSet,
+    changed: Arc<AtomicWaker>,
+    abort: AbortHandle,
+    connector: C,
+    services: HashMap<Key, (Arc<Instance>, S)>,
+    next: usize,
+    ready: Option<Key>,
+}
+
+impl<C, S> DiscoveredService<C, S>
+where
+    C: Fn(&Instance) -> S,
+{
+    pub fn new<R>(registry: &R, appid: &str, connector: C) -> Self
+    where
+        R: Registry,
+        R::Watcher: Send + 'static,
+    {
+        let instances = InstanceSet::new();
+        let changed = Arc::new(AtomicWaker::new());
+        let (abort, registration) = AbortHandle::new_pair();
+        runtime::spawn(Abortable::new(
+            keep_updated(registry.watch(appid), instances.clone(), changed.clone()),
+            registration,
+        ));
+        DiscoveredService {
+            instances,
+            changed,
+            abort,
+            connector,
+            services: HashMap::new(),
+            next: 0,
+            ready: None,
+        }
+    }
+}
+
+impl<C, S> Drop for DiscoveredService<C, S> {
+    fn drop(&mut self) {
+        self.abort.abort();
+    }
+}
+
+impl<C, S, Req> Service<Req> for DiscoveredService<C, S>
+where
+    C: Fn(&Instance) -> S,
+    S: Service<Req>,
+    S::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = MapErr<S::Future, fn(S::Error) -> BoxError>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.ready.is_some() {
+            return Poll::Ready(Ok(()));
+        }
+        self.changed.register(cx.waker());
+        let instances = self.instances.snapshot();
+        let identity = DefaultIdentity;
+        // forget services of deregistered instances, and of changed ones so
+        // they get connected with the new data.
+        self.services.retain(|key, (ins, _)| {
+            instances
+                .iter()
+                .any(|exist| Arc::ptr_eq(exist, ins) && identity.identify(exist) == *key)
+        });
+
+        let mut last_err = None;
+        for i in 0..instances.len() {
+            let ins = &instances[(self.next + i) % instances.len()];
+            let key = identity.identify(ins);
+            let connector = &self.connector;
+            let (_, svc) = self
+                .services
+                .entry(key.clone())
+                .or_insert_with(|| (ins.clone(), connector(ins)));
+            match svc.poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    self.next = (self.next + i + 1) % instances.len();
+                    self.ready = Some(key);
+                    return Poll::Ready(Ok(()));
+                }
+                Poll::Ready(Err(e)) => {
+                    let e = e.into();
+                    warn!(
+                        "instance {:?} is not available, reconnecting. {}",
+                        ins.addrs, e
+                    );
+                    self.services.remove(&key);
+                    last_err = Some(e);
+                }
+                Poll::Pending => {}
+            }
+        }
+        match last_err {
+            Some(e) if self.services.is_empty() => Poll::Ready(Err(e)),
+            _ => Poll::Pending,
+        }
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        let key = self
+            .ready
+            .take()
+            .expect("DiscoveredService::call called before poll_ready");
+        let (_, svc) = self.services.get_mut(&key).unwrap();
+        svc.call(req)
+            .map_err(Into::into as fn(S::Error) -> BoxError)
+    }
+}
+
+async fn keep_updated<W>(watcher: W, instances: InstanceSet, changed: Arc<AtomicWaker>)
+where
+    W: Stream<Item = WatchEvent>,
+{
+    pin_mut!(watcher);
+    while let Some(watch_event) = watcher.next().await {
+        instances.apply(&watch_event.event);
+        changed.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DiscoveredService;
+    use crate::{testing::MockRegistry, Instance};
+    use futures::future::{self, poll_fn, Ready};
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+        time::Duration,
+    };
+    use tower::Service;
+
+    // answers with its address, fails `poll_ready` once when asked to.
+    struct AddrService {
+        addr: String,
+        fail: bool,
+    }
+
+    impl Service<()> for AddrService {
+        type Response = String;
+        type Error = String;
+        type Future = Ready<Result<String, String>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            if self.fail {
+                return Poll::Ready(Err("broken".to_owned()));
+            }
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: ()) -> Self::Future {
+            future::ok(self.addr.clone())
+        }
+    }
+
+    fn instance(addr: &str) -> Instance {
+        Instance {
+            appid: "provider".into(),
+            addrs: vec![addr.to_owned()].into(),
+            ..Default::default()
+        }
+    }
+
+    async fn call<S: Service<()>>(svc: &mut S) -> Result<S::Response, S::Error> {
+        poll_fn(|cx| svc.poll_ready(cx)).await?;
+        svc.call(()).await
+    }
+
+    #[tokio::test]
+    async fn test_discovered_service() {
+        tokio::time::pause();
+        let registry = MockRegistry::new();
+        let connects = Arc::new(AtomicUsize::new(0));
+        let counter = connects.clone();
+        let mut svc = DiscoveredService::new(&registry, "provider", move |ins: &Instance| {
+            // the first connection to the second instance is broken.
+            let n = counter.fetch_add(1, Ordering::SeqCst);
+            AddrService {
+                addr: ins.addrs[0].clone(),
+                fail: n == 1,
+            }
+        });
+
+        registry.insert(instance("grpc://172.1.1.1:9999"));
+        registry.insert(instance("grpc://172.1.1.2:9999"));
+        assert_eq!(call(&mut svc).await.unwrap(), "grpc://172.1.1.1:9999");
+        // the broken service is skipped and reconnected.
+        assert_eq!(call(&mut svc).await.unwrap(), "grpc://172.1.1.1:9999");
+        assert_eq!(call(&mut svc).await.unwrap(), "grpc://172.1.1.2:9999");
+        assert_eq!(connects.load(Ordering::SeqCst), 3);
+
+        registry.remove(&instance("grpc://172.1.1.1:9999"));
+        loop {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+            if call(&mut svc).await.unwrap() == "grpc://172.1.1.2:9999"
+                && call(&mut svc).await.unwrap() == "grpc://172.1.1.2:9999"
+            {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Deregisters the instances of the process when it's told to terminate, so
+//! that clients stop calling it before it exits.
+//!
+//! ```ignore
+//! zk.register(instance.clone()).await?;
+//! tokio::spawn(server);
+//! shutdown::deregister_on_signal(&zk, &[instance]).await?;
+//! ```
+use crate::{runtime, Instance, Registry};
+use futures::future;
+use log::info;
+use std::{error, fmt, io, sync::Arc, time::Duration};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Waits for ctrl-c, or SIGTERM on unix, then deregisters `instances`,
+/// giving up after 10 seconds, see `Shutdown` to change that.
+pub async fn deregister_on_signal<R>(
+    registry: &R,
+    instances: &[Arc<Instance>],
+) -> Result<(), ShutdownError<R::Error>>
+where
+    R: Registry,
+{
+    Shutdown::new()
+        .deregister_on_signal(registry, instances)
+        .await
+}
+
+/// How to deregister on termination signals, see `deregister_on_signal`.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    timeout: Duration,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown {
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// How long deregistering may take before the process goes on exiting,
+    /// 10 seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Waits for ctrl-c, or SIGTERM on unix, then deregisters `instances`.
+    pub async fn deregister_on_signal<R>(
+        self,
+        registry: &R,
+        instances: &[Arc<Instance>],
+    ) -> Result<(), ShutdownError<R::Error>>
+    where
+        R: Registry,
+    {
+        terminated().await.map_err(ShutdownError::Signal)?;
+        info!("terminating, deregistering {} instances", instances.len());
+        self.deregister(registry, instances).await
+    }
+
+    // deregisters `instances` at once, within the timeout.
+    async fn deregister<R>(
+        &self,
+        registry: &R,
+        instances: &[Arc<Instance>],
+    ) -> Result<(), ShutdownError<R::Error>>
+    where
+        R: Registry,
+    {
+        let deregistered = future::join_all(instances.iter().map(|ins| registry.deregister(ins)));
+        let results = runtime::timeout(self.timeout, deregistered)
+            .await
+            .ok_or(ShutdownError::Timeout)?;
+        let failures = instances
+            .iter()
+            .cloned()
+            .zip(results)
+            .filter_map(|(ins, result)| result.err().map(|e| (ins, e)))
+            .collect::<Vec<_>>();
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(ShutdownError::Deregister(failures))
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn terminated() -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut term = signal(SignalKind::terminate())?;
+    let (ctrl_c, sigterm) = (tokio::signal::ctrl_c(), term.recv());
+    futures::pin_mut!(ctrl_c, sigterm);
+    match future::select(ctrl_c, sigterm).await {
+        future::Either::Left((result, _)) => result,
+        future::Either::Right(_) => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminated() -> io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+#[derive(Debug)]
+pub enum ShutdownError<E> {
+    /// The termination signals couldn't be listened to.
+    Signal(io::Error),
+    /// The instances that failed to deregister, the others did.
+    Deregister(Vec<(Arc<Instance>, E)>),
+    /// Deregistering took longer than the timeout.
+    Timeout,
+}
+
+impl<E> fmt::Display for ShutdownError<E>
+where
+    E: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShutdownError::Signal(e) => write!(f, "failed to listen to signals: {}", e),
+            ShutdownError::Deregister(failures) => {
+                write!(f, "failed to deregister")?;
+                for (i, (ins, e)) in failures.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { ";" };
+                    write!(f, "{} {:?}: {}", sep, ins.addrs, e)?;
+                }
+                Ok(())
+            }
+            ShutdownError::Timeout => write!(f, "timed out deregistering"),
+        }
+    }
+}
+
+impl<E> error::Error for ShutdownError<E> where E: fmt::Debug + fmt::Display {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Shutdown, ShutdownError};
+    use crate::{
+        testing::{instance, MockRegistry},
+        Registry,
+    };
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_deregister() {
+        let registry = MockRegistry::new();
+        let (a, b) = (
+            instance("grpc://10.0.0.1:9000"),
+            instance("grpc://10.0.0.2:9000"),
+        );
+        registry.register(a.clone()).await.unwrap();
+        registry.register(b.clone()).await.unwrap();
+        let shutdown = Shutdown::new().timeout(Duration::from_secs(1));
+        registry.fail_deregister("zk is down");
+        let err = shutdown
+            .deregister(&registry, &[a.clone(), b.clone()])
+            .await
+            .unwrap_err();
+        assert!(matches!(&err, ShutdownError::Deregister(failures) if failures[0].0 == a));
+        registry.assert_registered(&a);
+        registry.assert_not_registered(&b);
+    }
+}
@@ -0,0 +1,245 @@
+//! Portable JSON snapshots of the instances of apps, for backups, moving
+//! between backends, seeding test environments and resuming watches after a
+//! restart, see `Registry::watch_from`.
+use crate::{
+    codec::{from_unix_millis, to_unix_millis},
+    identity::{DefaultIdentity, Identity},
+    intern, runtime,
+    watcher::Event,
+    Instance, Registry,
+};
+use futures::{future, pin_mut, FutureExt, StreamExt};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::BTreeMap,
+    error, fmt,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+const FORMAT_VERSION: u64 = 1;
+
+/// The instances of apps at a point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoverySnapshot {
+    pub taken_at: SystemTime,
+    /// The instances by the appid they were watched with.
+    pub apps: BTreeMap<String, Vec<Arc<Instance>>>,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Json(serde_json::Error),
+    /// The document isn't a snapshot, or one of a later format.
+    Format(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Json(e) => write!(f, "bad snapshot json: {}", e),
+            SnapshotError::Format(e) => write!(f, "bad snapshot: {}", e),
+        }
+    }
+}
+
+impl error::Error for SnapshotError {}
+
+impl From<serde_json::Error> for SnapshotError {
+    fn from(e: serde_json::Error) -> Self {
+        SnapshotError::Json(e)
+    }
+}
+
+impl DiscoverySnapshot {
+    pub fn to_json(&self) -> String {
+        let apps = self
+            .apps
+            .iter()
+            .map(|(appid, instances)| {
+                let instances = instances.iter().map(|ins| instance_to_json(ins)).collect();
+                (appid.clone(), Value::Array(instances))
+            })
+            .collect::<Map<_, _>>();
+        json!({
+            "version": FORMAT_VERSION,
+            "taken_at": to_unix_millis(self.taken_at),
+            "apps": apps,
+        })
+        .to_string()
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, SnapshotError> {
+        let value = serde_json::from_str::<Value>(json)?;
+        let bad = |what: &str| SnapshotError::Format(what.to_owned());
+        match value["version"].as_u64() {
+            Some(FORMAT_VERSION) => {}
+            Some(version) => return Err(bad(&format!("unknown version {}", version))),
+            None => return Err(bad("no version")),
+        }
+        let taken_at = value["taken_at"]
+            .as_u64()
+            .ok_or_else(|| bad("no taken_at"))?;
+        let mut apps = BTreeMap::new();
+        for (appid, instances) in value["apps"].as_object().ok_or_else(|| bad("no apps"))? {
+            let instances = instances
+                .as_array()
+                .ok_or_else(|| bad("apps are not arrays"))?
+                .iter()
+                .map(|ins| {
+                    instance_from_json(ins)
+                        .map(Arc::new)
+                        .ok_or_else(|| bad("bad instance"))
+                })
+                .collect::<Result<_, _>>()?;
+            apps.insert(appid.clone(), instances);
+        }
+        Ok(DiscoverySnapshot {
+            taken_at: from_unix_millis(taken_at),
+            apps,
+        })
+    }
+}
+
+/// Takes a snapshot of the instances of `appids`, as reported when starting
+/// to watch them. An app reporting nothing within `wait` has no instances.
+///
+/// ```ignore
+/// let snapshot = export(&zk, &["/dubbo-rs/provider"], Duration::from_secs(3)).await;
+/// fs::write("backup.json", snapshot.to_json())?;
+/// ```
+pub async fn export<R>(registry: &R, appids: &[&str], wait: Duration) -> DiscoverySnapshot
+where
+    R: Registry,
+{
+    let apps = appids.iter().map(|appid| async move {
+        let watcher = registry.watch(appid);
+        pin_mut!(watcher);
+        let mut instances = Vec::<Arc<Instance>>::new();
+        let mut apply = |event| match event {
+            Event::Create(ins) => {
+                instances.retain(|exist| *exist != ins);
+                instances.push(ins);
+            }
+            Event::Update(ins) => {
+                let key = DefaultIdentity.identify(&ins);
+                instances.retain(|exist| DefaultIdentity.identify(exist) != key);
+                instances.push(ins);
+            }
+            Event::Delete(ins) => instances.retain(|exist| *exist != ins),
+        };
+        if let Some(Some(watch_event)) = runtime::timeout(wait, watcher.next()).await {
+            apply(watch_event.event);
+            // the instances present when the watch starts are sent back to back.
+            while let Some(Some(watch_event)) = watcher.next().now_or_never() {
+                apply(watch_event.event);
+            }
+        }
+        (appid.to_string(), instances)
+    });
+    DiscoverySnapshot {
+        taken_at: SystemTime::now(),
+        apps: future::join_all(apps).await.into_iter().collect(),
+    }
+}
+
+/// Registers every instance of `snapshot`, stopping at the first failure.
+/// Returns how many were registered.
+pub async fn import<R>(registry: &R, snapshot: &DiscoverySnapshot) -> Result<usize, R::Error>
+where
+    R: Registry,
+{
+    let mut count = 0;
+    for ins in snapshot.apps.values().flatten() {
+        registry.register(ins.clone()).await?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+pub(crate) fn instance_to_json(ins: &Instance) -> Value {
+    json!({
+        "zone": &*ins.zone,
+        "env": &*ins.env,
+        "appid": &*ins.appid,
+        "hostname": ins.hostname,
+        "addrs": ins.addrs.as_slice(),
+        "version": ins.version,
+        "group": &*ins.group,
+        "metadata": ins.metadata,
+        "registered_at": ins.registered_at.map(to_unix_millis),
+        "last_renewed_at": ins.last_renewed_at.map(to_unix_millis),
+    })
+}
+
+fn instance_from_json(value: &Value) -> Option<Instance> {
+    let string = |name: &str| value[name].as_str().map(str::to_owned);
+    let time = |name: &str| match &value[name] {
+        Value::Null => Some(None),
+        millis => millis.as_u64().map(|millis| Some(from_unix_millis(millis))),
+    };
+    Some(Instance {
+        zone: intern(&string("zone")?),
+        env: intern(&string("env")?),
+        appid: intern(&string("appid")?),
+        hostname: string("hostname")?,
+        addrs: serde_json::from_value::<Vec<String>>(value["addrs"].clone())
+            .ok()?
+            .into(),
+        version: string("version")?,
+        // absent from snapshots taken before groups.
+        group: intern(&string("group").unwrap_or_default()),
+        metadata: serde_json::from_value(value["metadata"].clone()).ok()?,
+        registered_at: time("registered_at")?,
+        last_renewed_at: time("last_renewed_at")?,
+        // only meaningful to the backend it was read from.
+        revision: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export, import, DiscoverySnapshot};
+    use crate::{codec::from_unix_millis, testing::MockRegistry, Instance};
+    use std::time::Duration;
+
+    fn instance(appid: &str, addr: &str) -> Instance {
+        Instance {
+            zone: "sh1".into(),
+            appid: appid.into(),
+            addrs: vec![addr.to_owned()].into(),
+            metadata: [("weight".to_owned(), "10".to_owned())]
+                .iter()
+                .cloned()
+                .collect(),
+            registered_at: Some(from_unix_millis(1_590_000_000_123)),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_export_import() {
+        let registry = MockRegistry::new();
+        registry.insert(instance("provider", "grpc://172.1.1.1:9999"));
+        registry.insert(instance("provider", "grpc://172.1.1.2:9999"));
+        registry.insert(instance("consumer", "grpc://172.1.2.1:9999"));
+        let wait = Duration::from_millis(10);
+        let snapshot = export(&registry, &["provider", "consumer", "other"], wait).await;
+        assert_eq!(snapshot.apps["provider"].len(), 2);
+        assert_eq!(snapshot.apps["consumer"].len(), 1);
+        assert!(snapshot.apps["other"].is_empty());
+
+        let json = snapshot.to_json();
+        let restored = DiscoverySnapshot::from_json(&json).unwrap();
+        assert_eq!(restored.apps, snapshot.apps);
+
+        let seeded = MockRegistry::new();
+        assert_eq!(import(&seeded, &restored).await, Ok(3));
+        for ins in restored.apps.values().flatten() {
+            seeded.assert_registered(ins);
+        }
+
+        assert!(DiscoverySnapshot::from_json(r#"{"version":2,"apps":{}}"#).is_err());
+        assert!(DiscoverySnapshot::from_json("[]").is_err());
+    }
+}
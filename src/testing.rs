@@ -0,0 +1,773 @@
+//! Registries for testing code built on this crate without a real backend,
+//! assertions on watchers and property testing helpers for codecs.
+use crate::{
+    control::{Control, ControlParams},
+    election::{Election, Leadership},
+    kv::KvStore,
+    identity::{DefaultIdentity, Identity},
+    runtime,
+    watcher::{Clock, DeleteReason, Event, SystemClock, WatchEvent},
+    Instance, Registry,
+};
+use futures::{
+    channel::mpsc,
+    future::{self, Ready},
+    Stream,
+};
+use pin_project::{pin_project, pinned_drop};
+use std::{
+    collections::{HashMap, VecDeque},
+    error, fmt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub use arbitrary::assert_roundtrip;
+pub use chaos::{ChaosError, ChaosFuture, ChaosRegistry, ChaosWatcher};
+pub use expect::{
+    collect_until_quiescent, expect_create, expect_delete, expect_event, expect_quiescent,
+    expect_update,
+};
+pub use recording::{Record, RecordingRegistry};
+pub use zk_server::ZkServer;
+
+mod arbitrary;
+mod chaos;
+mod expect;
+mod recording;
+mod zk_server;
+
+/// An in-memory `Registry` scripted and inspected by tests.
+///
+/// Registered instances are kept per appid and reported to watchers like the
+/// zookeeper registry does: a new watcher first gets a Create for every
+/// instance already registered, and an instance replaced by one with the same
+/// identity only produces a Create. On top of that tests can push arbitrary events, play a timeline
+/// of events to every new watcher, make registrations fail and check which
+/// calls were made. Clones share the same state.
+///
+/// ```ignore
+/// let registry = MockRegistry::new();
+/// registry.insert(instance("grpc://172.1.1.1:9999"));
+/// registry.fail_register("zk is down");
+/// let resolver = Resolver::new(registry.clone());
+/// ...
+/// registry.assert_registered(&local);
+/// ```
+#[derive(Clone, Default)]
+pub struct MockRegistry {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    apps: HashMap<String, Vec<Arc<Instance>>>,
+    watchers: HashMap<String, Vec<mpsc::UnboundedSender<WatchEvent>>>,
+    timelines: HashMap<String, Vec<(Duration, Event)>>,
+    register_failures: VecDeque<MockError>,
+    deregister_failures: VecDeque<MockError>,
+    calls: Vec<Call>,
+    clock: Option<Arc<dyn Clock>>,
+    controls: HashMap<String, ControlParams>,
+    control_watchers: HashMap<String, Vec<mpsc::UnboundedSender<ControlParams>>>,
+    // the candidates of each election in line, the leader first.
+    elections: HashMap<String, Vec<(u64, CampaignSender)>>,
+    last_token: u64,
+    keys: HashMap<String, Vec<u8>>,
+    key_watchers: HashMap<String, Vec<mpsc::UnboundedSender<Option<Vec<u8>>>>>,
+}
+
+type CampaignSender = mpsc::UnboundedSender<Result<Leadership, MockError>>;
+
+/// A call received by a `MockRegistry`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Call {
+    Register(Arc<Instance>),
+    Deregister(Arc<Instance>),
+    Watch(String),
+    /// The appid and instance id evicted.
+    Evict(String, String),
+    /// The instance and expected version.
+    Update(Arc<Instance>, u64),
+    List(String),
+    Renew(Arc<Instance>),
+}
+
+/// The error of an injected failure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockError(pub String);
+
+impl fmt::Display for MockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "mock registry error: {}", self.0)
+    }
+}
+
+impl error::Error for MockError {}
+
+impl MockRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Timestamps events with `clock`, e.g. a `ManualClock`.
+    pub fn with_clock<C>(clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        let registry = Self::default();
+        registry.inner.lock().unwrap().clock = Some(Arc::new(clock));
+        registry
+    }
+
+    /// Adds an instance as if another process registered it.
+    pub fn insert(&self, ins: impl Into<Arc<Instance>>) {
+        self.inner.lock().unwrap().upsert(ins.into());
+    }
+
+    /// Removes an instance as if another process deregistered it.
+    pub fn remove(&self, ins: &Instance) {
+        self.inner
+            .lock()
+            .unwrap()
+            .remove(ins, DeleteReason::Deregistered);
+    }
+
+    /// Sends `event` to the current watchers of `appid` without touching the
+    /// registered instances, e.g. to report something that isn't there.
+    pub fn emit(&self, appid: &str, event: Event) {
+        self.inner.lock().unwrap().notify(appid, event, None);
+    }
+
+    /// Every watcher of `appid` created from now on gets these events after
+    /// the registered instances, each after waiting its delay. Needs a tokio
+    /// runtime.
+    pub fn timeline(&self, appid: impl Into<String>, steps: Vec<(Duration, Event)>) {
+        self.inner
+            .lock()
+            .unwrap()
+            .timelines
+            .insert(appid.into(), steps);
+    }
+
+    /// Makes the next register or update call fail with `error`. Failures
+    /// queue up.
+    pub fn fail_register(&self, error: impl Into<String>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.register_failures.push_back(MockError(error.into()));
+    }
+
+    /// Makes the next deregister or evict call fail with `error`. Failures
+    /// queue up.
+    pub fn fail_deregister(&self, error: impl Into<String>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.deregister_failures.push_back(MockError(error.into()));
+    }
+
+    /// The calls received so far, in order.
+    pub fn calls(&self) -> Vec<Call> {
+        self.inner.lock().unwrap().calls.clone()
+    }
+
+    /// The instances currently registered for `appid`.
+    pub fn registered(&self, appid: &str) -> Vec<Arc<Instance>> {
+        let inner = self.inner.lock().unwrap();
+        inner.apps.get(appid).cloned().unwrap_or_default()
+    }
+
+    /// Makes the leader of election `name` lose it, its campaign ends and the
+    /// next candidate is elected.
+    pub fn revoke_leader(&self, name: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let candidates = inner.elections.entry(name.to_owned()).or_default();
+        if candidates.is_empty() {
+            return;
+        }
+        let (_, leader) = candidates.remove(0);
+        let _ = leader.unbounded_send(Ok(Leadership::Lost));
+        elect(candidates);
+    }
+
+    pub fn assert_registered(&self, ins: &Instance) {
+        let registered = self.registered(&ins.appid);
+        assert!(
+            registered.iter().any(|exist| **exist == *ins),
+            "{:?} is not registered, registered: {:?}",
+            ins,
+            registered
+        );
+    }
+
+    pub fn assert_not_registered(&self, ins: &Instance) {
+        let registered = self.registered(&ins.appid);
+        assert!(
+            registered.iter().all(|exist| **exist != *ins),
+            "{:?} is registered",
+            ins
+        );
+    }
+}
+
+impl Inner {
+    // like a znode path, a registration is the whole instance.
+    fn upsert(&mut self, ins: Arc<Instance>) {
+        let instances = self.apps.entry(ins.appid.to_string()).or_default();
+        if instances.contains(&ins) {
+            return;
+        }
+        instances.push(ins.clone());
+        let appid = ins.appid.clone();
+        self.notify(&appid, Event::Create(ins), None);
+    }
+
+    // like the zk watcher, no Delete while an instance with the same identity
+    // is still registered.
+    fn remove(&mut self, ins: &Instance, reason: DeleteReason) {
+        let instances = match self.apps.get_mut(&*ins.appid) {
+            Some(instances) => instances,
+            None => return,
+        };
+        // an instance without a revision names the registration updated from it.
+        let same = |exist: &Instance| {
+            *exist == *ins
+                || ins.revision.is_none()
+                    && Instance {
+                        revision: None,
+                        ..exist.clone()
+                    } == *ins
+        };
+        if let Some(pos) = instances.iter().position(|exist| same(exist)) {
+            let removed = instances.remove(pos);
+            let key = DefaultIdentity.identify(ins);
+            let replaced = instances
+                .iter()
+                .any(|exist| DefaultIdentity.identify(exist) == key);
+            if !replaced {
+                self.notify(&ins.appid, Event::Delete(removed), Some(reason));
+            }
+        }
+    }
+
+    fn notify(&mut self, appid: &str, event: Event, reason: Option<DeleteReason>) {
+        let mut watch_event = WatchEvent::with_clock(event, &*self.clock());
+        watch_event.reason = reason;
+        if let Some(watchers) = self.watchers.get_mut(appid) {
+            watchers.retain(|tx| tx.unbounded_send(watch_event.clone()).is_ok());
+        }
+    }
+
+    fn notify_key(&mut self, key: &str, value: Option<Vec<u8>>) {
+        if let Some(watchers) = self.key_watchers.get_mut(key) {
+            watchers.retain(|tx| tx.unbounded_send(value.clone()).is_ok());
+        }
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone().unwrap_or_else(|| Arc::new(SystemClock))
+    }
+}
+
+/// A `Clock` standing still until moved by the test. Clones share the same
+/// time.
+///
+/// ```ignore
+/// let clock = ManualClock::default();
+/// let registry = MockRegistry::with_clock(clock.clone());
+/// clock.advance(Duration::from_secs(30));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl ManualClock {
+    pub fn new(now: SystemTime) -> Self {
+        ManualClock {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+/// Starts at the unix epoch.
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new(UNIX_EPOCH)
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+impl Registry for MockRegistry {
+    type Error = MockError;
+    type RegFuture = Ready<Result<(), MockError>>;
+    type DeRegFuture = Ready<Result<(), MockError>>;
+    type ListFuture = Ready<Result<Vec<Arc<Instance>>, MockError>>;
+    type Watcher = mpsc::UnboundedReceiver<WatchEvent>;
+
+    fn register(&self, ins: Arc<Instance>) -> Self::RegFuture {
+        let mut inner = self.inner.lock().unwrap();
+        inner.calls.push(Call::Register(ins.clone()));
+        if let Some(error) = inner.register_failures.pop_front() {
+            return future::err(error);
+        }
+        inner.upsert(ins);
+        future::ok(())
+    }
+
+    fn deregister(&self, ins: &Arc<Instance>) -> Self::DeRegFuture {
+        let mut inner = self.inner.lock().unwrap();
+        inner.calls.push(Call::Deregister(ins.clone()));
+        if let Some(error) = inner.deregister_failures.pop_front() {
+            return future::err(error);
+        }
+        inner.remove(ins, DeleteReason::Deregistered);
+        future::ok(())
+    }
+
+    fn evict(&self, appid: &str, instance_id: &str) -> Self::DeRegFuture {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .calls
+            .push(Call::Evict(appid.to_owned(), instance_id.to_owned()));
+        if let Some(error) = inner.deregister_failures.pop_front() {
+            return future::err(error);
+        }
+        let evicted = inner
+            .apps
+            .get(appid)
+            .into_iter()
+            .flatten()
+            .filter(|ins| ins.has_id(instance_id))
+            .cloned()
+            .collect::<Vec<_>>();
+        for ins in evicted {
+            inner.remove(&ins, DeleteReason::Evicted);
+        }
+        future::ok(())
+    }
+
+    // the registration updated is the one with the identity of `ins`, at
+    // revision 0 unless set.
+    fn update_if(&self, ins: Arc<Instance>, expected_version: u64) -> Self::RegFuture {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .calls
+            .push(Call::Update(ins.clone(), expected_version));
+        if let Some(error) = inner.register_failures.pop_front() {
+            return future::err(error);
+        }
+        let key = DefaultIdentity.identify(&ins);
+        let instances = inner.apps.entry(ins.appid.to_string()).or_default();
+        let pos = match instances
+            .iter()
+            .position(|exist| DefaultIdentity.identify(exist) == key)
+        {
+            Some(pos) => pos,
+            None => return future::err(MockError("conflict, not registered".to_owned())),
+        };
+        let version = instances[pos].revision.unwrap_or(0);
+        if version != expected_version {
+            return future::err(MockError(format!(
+                "conflict, expected version {} found {}",
+                expected_version, version
+            )));
+        }
+        let updated = Arc::new(Instance {
+            revision: Some(version + 1),
+            ..Instance::clone(&ins)
+        });
+        instances[pos] = updated.clone();
+        inner.notify(&ins.appid, Event::Update(updated), None);
+        future::ok(())
+    }
+
+    fn renew(&self, ins: &Arc<Instance>) -> Self::RegFuture {
+        let mut inner = self.inner.lock().unwrap();
+        inner.calls.push(Call::Renew(ins.clone()));
+        if let Some(error) = inner.register_failures.pop_front() {
+            return future::err(error);
+        }
+        future::ok(())
+    }
+
+    fn list(&self, appid: &str) -> Self::ListFuture {
+        let mut inner = self.inner.lock().unwrap();
+        inner.calls.push(Call::List(appid.to_owned()));
+        future::ok(inner.apps.get(appid).cloned().unwrap_or_default())
+    }
+
+    fn watch(&self, appid: &str) -> Self::Watcher {
+        self.watch_from(appid, &[])
+    }
+
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher {
+        let mut inner = self.inner.lock().unwrap();
+        inner.calls.push(Call::Watch(appid.to_owned()));
+        let (tx, rx) = mpsc::unbounded();
+        let clock = inner.clock();
+        let registered = inner.apps.get(appid).map(Vec::as_slice).unwrap_or_default();
+        let created = registered.iter().filter(|ins| !instances.contains(ins));
+        // like `remove`, no Delete for an instance replaced by a new version.
+        let deleted = instances.iter().filter(|ins| {
+            let key = DefaultIdentity.identify(ins);
+            registered
+                .iter()
+                .all(|exist| DefaultIdentity.identify(exist) != key)
+        });
+        for ins in instances.iter().chain(created) {
+            let event = Event::Create(ins.clone());
+            let _ = tx.unbounded_send(WatchEvent::with_clock(event, &*clock));
+        }
+        for ins in deleted {
+            let event = Event::Delete(ins.clone());
+            let _ = tx.unbounded_send(WatchEvent::with_clock(event, &*clock));
+        }
+        if let Some(steps) = inner.timelines.get(appid).cloned() {
+            let tx = tx.clone();
+            runtime::spawn(async move {
+                for (delay, event) in steps {
+                    runtime::delay_for(delay).await;
+                    if tx
+                        .unbounded_send(WatchEvent::with_clock(event, &*clock))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            });
+        }
+        inner.watchers.entry(appid.to_owned()).or_default().push(tx);
+        rx
+    }
+}
+
+impl Control for MockRegistry {
+    type Error = MockError;
+    type SetFuture = Ready<Result<(), MockError>>;
+    type ControlWatcher = mpsc::UnboundedReceiver<ControlParams>;
+
+    fn set_control(&self, appid: &str, params: &ControlParams) -> Self::SetFuture {
+        let mut inner = self.inner.lock().unwrap();
+        inner.controls.insert(appid.to_owned(), params.clone());
+        if let Some(watchers) = inner.control_watchers.get_mut(appid) {
+            watchers.retain(|tx| tx.unbounded_send(params.clone()).is_ok());
+        }
+        future::ok(())
+    }
+
+    fn watch_control(&self, appid: &str) -> Self::ControlWatcher {
+        let mut inner = self.inner.lock().unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        let params = inner.controls.get(appid).cloned().unwrap_or_default();
+        let _ = tx.unbounded_send(params);
+        inner
+            .control_watchers
+            .entry(appid.to_owned())
+            .or_default()
+            .push(tx);
+        rx
+    }
+}
+
+impl KvStore for MockRegistry {
+    type Error = MockError;
+    type SetFuture = Ready<Result<(), MockError>>;
+    type KeyWatcher = mpsc::UnboundedReceiver<Option<Vec<u8>>>;
+
+    fn set_key(&self, key: &str, value: Vec<u8>) -> Self::SetFuture {
+        let mut inner = self.inner.lock().unwrap();
+        inner.keys.insert(key.to_owned(), value.clone());
+        inner.notify_key(key, Some(value));
+        future::ok(())
+    }
+
+    fn delete_key(&self, key: &str) -> Self::SetFuture {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.keys.remove(key).is_some() {
+            inner.notify_key(key, None);
+        }
+        future::ok(())
+    }
+
+    fn watch_key(&self, key: &str) -> Self::KeyWatcher {
+        let mut inner = self.inner.lock().unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        let _ = tx.unbounded_send(inner.keys.get(key).cloned());
+        inner.key_watchers.entry(key.to_owned()).or_default().push(tx);
+        rx
+    }
+}
+
+impl Election for MockRegistry {
+    type Error = MockError;
+    type Campaign = MockCampaign;
+
+    fn campaign(&self, name: &str, _candidate: &str) -> Self::Campaign {
+        let mut inner = self.inner.lock().unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        inner.last_token += 1;
+        let token = inner.last_token;
+        let candidates = inner.elections.entry(name.to_owned()).or_default();
+        candidates.push((token, tx));
+        if candidates.len() == 1 {
+            elect(candidates);
+        }
+        MockCampaign {
+            registry: self.clone(),
+            name: name.to_owned(),
+            token,
+            rx,
+        }
+    }
+}
+
+// tells the first candidate it leads.
+fn elect(candidates: &[(u64, CampaignSender)]) {
+    if let Some((token, tx)) = candidates.first() {
+        let _ = tx.unbounded_send(Ok(Leadership::Acquired { token: *token }));
+    }
+}
+
+/// A campaign of a `MockRegistry`, resigning when dropped.
+#[pin_project(PinnedDrop)]
+pub struct MockCampaign {
+    registry: MockRegistry,
+    name: String,
+    token: u64,
+    #[pin]
+    rx: mpsc::UnboundedReceiver<Result<Leadership, MockError>>,
+}
+
+impl Stream for MockCampaign {
+    type Item = Result<Leadership, MockError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().rx.poll_next(cx)
+    }
+}
+
+#[pinned_drop]
+impl PinnedDrop for MockCampaign {
+    fn drop(self: Pin<&mut Self>) {
+        let mut inner = self.registry.inner.lock().unwrap();
+        if let Some(candidates) = inner.elections.get_mut(&self.name) {
+            let leader = candidates.first().map(|(token, _)| *token) == Some(self.token);
+            candidates.retain(|(token, _)| *token != self.token);
+            if leader {
+                elect(candidates);
+            }
+        }
+    }
+}
+
+/// An instance of `provider` at `addr`, the one most tests need.
+#[cfg(test)]
+pub(crate) fn instance(addr: &str) -> Arc<Instance> {
+    Arc::new(Instance {
+        appid: "provider".into(),
+        addrs: vec![addr.to_owned()].into(),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{instance, Call, ManualClock, MockError, MockRegistry};
+    use crate::{
+        watcher::{DeleteReason, Event},
+        Instance, Registry,
+    };
+    use futures::StreamExt;
+    use std::{
+        sync::Arc,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    #[tokio::test]
+    async fn test_mock_registry() {
+        let registry = MockRegistry::new();
+        registry.insert(instance("grpc://172.1.1.1:9999"));
+        let mut watcher = registry.watch("provider");
+        assert_eq!(
+            watcher.next().await.unwrap().event,
+            Event::Create(instance("grpc://172.1.1.1:9999"))
+        );
+
+        registry.fail_register("boom");
+        assert_eq!(
+            registry.register(instance("grpc://172.1.1.2:9999")).await,
+            Err(MockError("boom".to_owned()))
+        );
+        registry.assert_not_registered(&instance("grpc://172.1.1.2:9999"));
+        registry
+            .register(instance("grpc://172.1.1.2:9999"))
+            .await
+            .unwrap();
+        registry.assert_registered(&instance("grpc://172.1.1.2:9999"));
+        assert_eq!(
+            watcher.next().await.unwrap().event,
+            Event::Create(instance("grpc://172.1.1.2:9999"))
+        );
+
+        registry
+            .deregister(&instance("grpc://172.1.1.1:9999"))
+            .await
+            .unwrap();
+        assert_eq!(
+            watcher.next().await.unwrap().event,
+            Event::Delete(instance("grpc://172.1.1.1:9999"))
+        );
+        assert_eq!(
+            registry.calls(),
+            vec![
+                Call::Watch("provider".to_owned()),
+                Call::Register(instance("grpc://172.1.1.2:9999")),
+                Call::Register(instance("grpc://172.1.1.2:9999")),
+                Call::Deregister(instance("grpc://172.1.1.1:9999")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_registry_evict() {
+        let registry = MockRegistry::new();
+        registry.insert(instance("grpc://172.1.1.1:9999"));
+        registry.insert(instance("grpc://172.1.1.2:9999"));
+        let mut watcher = registry.watch("provider").skip(2);
+
+        registry
+            .evict("provider", "grpc://172.1.1.1:9999")
+            .await
+            .unwrap();
+        registry.evict("provider", "unknown").await.unwrap();
+        let evicted = watcher.next().await.unwrap();
+        assert_eq!(
+            evicted.event,
+            Event::Delete(instance("grpc://172.1.1.1:9999"))
+        );
+        assert_eq!(evicted.reason, Some(DeleteReason::Evicted));
+        registry.assert_registered(&instance("grpc://172.1.1.2:9999"));
+
+        registry
+            .deregister(&instance("grpc://172.1.1.2:9999"))
+            .await
+            .unwrap();
+        let deregistered = watcher.next().await.unwrap();
+        assert_eq!(deregistered.reason, Some(DeleteReason::Deregistered));
+    }
+
+    #[tokio::test]
+    async fn test_mock_registry_update_if() {
+        let registry = MockRegistry::new();
+        registry.insert(instance("grpc://172.1.1.1:9999"));
+        let mut watcher = registry.watch("provider").skip(1);
+        let weighted = |weight: &str| {
+            let mut ins = Instance::clone(&instance("grpc://172.1.1.1:9999"));
+            ins.metadata.insert("weight".to_owned(), weight.to_owned());
+            Arc::new(ins)
+        };
+
+        registry.update_if(weighted("10"), 0).await.unwrap();
+        let updated = watcher.next().await.unwrap().event;
+        match updated {
+            Event::Update(ins) => {
+                assert_eq!(ins.weight(), Some(10));
+                assert_eq!(ins.revision, Some(1));
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+        assert_eq!(
+            registry.update_if(weighted("20"), 0).await,
+            Err(MockError("conflict, expected version 0 found 1".to_owned()))
+        );
+        registry.update_if(weighted("20"), 1).await.unwrap();
+        assert_eq!(registry.registered("provider")[0].weight(), Some(20));
+    }
+
+    #[tokio::test]
+    async fn test_mock_registry_watch_from() {
+        let registry = MockRegistry::new();
+        registry.insert(instance("grpc://172.1.1.1:9999"));
+        registry.insert(instance("grpc://172.1.1.3:9999"));
+        let resume = [
+            instance("grpc://172.1.1.1:9999"),
+            instance("grpc://172.1.1.2:9999"),
+        ];
+        let events = registry
+            .watch_from("provider", &resume)
+            .take(4)
+            .map(|watch_event| watch_event.event)
+            .collect::<Vec<Event>>()
+            .await;
+        assert_eq!(
+            events,
+            vec![
+                Event::Create(instance("grpc://172.1.1.1:9999")),
+                Event::Create(instance("grpc://172.1.1.2:9999")),
+                Event::Create(instance("grpc://172.1.1.3:9999")),
+                Event::Delete(instance("grpc://172.1.1.2:9999")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_registry_timeline() {
+        let registry = MockRegistry::new();
+        registry.timeline(
+            "provider",
+            vec![
+                (
+                    Duration::from_millis(10),
+                    Event::Create(instance("grpc://172.1.1.1:9999")),
+                ),
+                (
+                    Duration::from_millis(10),
+                    Event::Delete(instance("grpc://172.1.1.1:9999")),
+                ),
+            ],
+        );
+        let events = registry
+            .watch("provider")
+            .take(2)
+            .map(|watch_event| watch_event.event)
+            .collect::<Vec<Event>>()
+            .await;
+        assert_eq!(
+            events,
+            vec![
+                Event::Create(instance("grpc://172.1.1.1:9999")),
+                Event::Delete(instance("grpc://172.1.1.1:9999")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_registry_clock() {
+        let clock = ManualClock::default();
+        let registry = MockRegistry::with_clock(clock.clone());
+        registry.insert(instance("grpc://172.1.1.1:9999"));
+        let mut watcher = registry.watch("provider");
+        assert_eq!(watcher.next().await.unwrap().timestamp, UNIX_EPOCH);
+
+        clock.advance(Duration::from_secs(30));
+        registry.remove(&instance("grpc://172.1.1.1:9999"));
+        assert_eq!(
+            watcher.next().await.unwrap().timestamp,
+            UNIX_EPOCH + Duration::from_secs(30)
+        );
+    }
+}
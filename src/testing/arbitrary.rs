@@ -0,0 +1,121 @@
+use crate::{
+    codec::{from_unix_millis, Codec, Decoder, Encoder},
+    intern, Instance,
+};
+use proptest::{
+    arbitrary::{any, Arbitrary},
+    collection::{hash_map, vec},
+    option, prop_oneof,
+    strategy::{BoxedStrategy, Just, Strategy},
+};
+use std::{collections::HashMap, time::SystemTime};
+
+// 2100-01-01, well within what every codec should carry.
+const MAX_UNIX_MILLIS: u64 = 4_102_444_800_000;
+
+/// Any instance: unicode and empty strings, up to a few addresses, up to a
+/// thousand metadata entries, and timestamps with millisecond precision as
+/// registries carry them.
+///
+/// ```ignore
+/// proptest! {
+///     #[test]
+///     fn my_codec_roundtrip(ins in any::<Instance>()) {
+///         assert_roundtrip(&MY_CODEC, &ins);
+///     }
+/// }
+/// ```
+impl Arbitrary for Instance {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Instance>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        let fields = (
+            any::<String>(),
+            any::<String>(),
+            any::<String>(),
+            any::<String>(),
+            vec(any::<String>(), 0..4),
+            any::<String>(),
+        );
+        let extra = (
+            any::<String>(),
+            metadata(),
+            option::of(timestamp()),
+            option::of(timestamp()),
+        );
+        (fields, extra)
+            .prop_map(
+                |(
+                    (zone, env, appid, hostname, addrs, version),
+                    (group, metadata, registered_at, last_renewed_at),
+                )| Instance {
+                    zone: intern(&zone),
+                    env: intern(&env),
+                    appid: intern(&appid),
+                    hostname,
+                    addrs: addrs.into(),
+                    version,
+                    group: intern(&group),
+                    metadata,
+                    registered_at,
+                    last_renewed_at,
+                    // not encoded, backends fill it in.
+                    revision: None,
+                },
+            )
+            .boxed()
+    }
+}
+
+fn metadata() -> impl Strategy<Value = HashMap<String, String>> {
+    prop_oneof![
+        1 => Just(HashMap::new()),
+        8 => hash_map(any::<String>(), any::<String>(), 1..8),
+        1 => hash_map("[a-z]{1,16}", ".{0,256}", 256..1024),
+    ]
+}
+
+fn timestamp() -> impl Strategy<Value = SystemTime> {
+    (0..MAX_UNIX_MILLIS).prop_map(from_unix_millis)
+}
+
+/// Asserts `codec` decodes what it encodes from `ins` back to `ins`.
+pub fn assert_roundtrip<E, D>(codec: &Codec<E, D>, ins: &Instance)
+where
+    E: Encoder,
+    D: Decoder,
+{
+    let data = match codec.get_encoder_ref().encode(ins) {
+        Ok(data) => data,
+        Err(e) => panic!("failed to encode {:?}: {}", ins, e),
+    };
+    match codec.get_decoder_ref().decode(&data) {
+        Ok(decoded) => assert_eq!(
+            decoded,
+            *ins,
+            "decoded instance differs, encoded as {:?}",
+            String::from_utf8_lossy(&data)
+        ),
+        Err(e) => panic!(
+            "failed to decode {:?} encoded from {:?}: {}",
+            String::from_utf8_lossy(&data),
+            ins,
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::assert_roundtrip;
+    use crate::{codec::DEFAULT_CODEC, Instance};
+    use proptest::{arbitrary::any, proptest};
+
+    proptest! {
+        #[test]
+        fn test_default_codec_roundtrip(ins in any::<Instance>()) {
+            assert_roundtrip(&DEFAULT_CODEC, &ins);
+        }
+    }
+}
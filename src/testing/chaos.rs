@@ -0,0 +1,318 @@
+use crate::{runtime::delay_for, watcher::WatchEvent, Instance, Registry};
+use futures::{future::BoxFuture, ready, Future, Stream};
+use pin_project::pin_project;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    error, fmt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// Wraps a registry to make it misbehave: slow or failing register and
+/// deregister calls, lost and repeated watch events.
+///
+/// Every call and event rolls the dice, so a test should check properties that
+/// hold whatever happens, or `seed` the randomness to replay a run. Clones and
+/// the watchers they create share the same randomness. Latency needs a tokio
+/// runtime with the time driver.
+///
+/// ```ignore
+/// let registry = ChaosRegistry::new(MockRegistry::new())
+///     .latency(Duration::from_millis(10), Duration::from_millis(200))
+///     .error_rate(0.2)
+///     .drop_rate(0.1)
+///     .duplicate_rate(0.1);
+/// ```
+#[derive(Clone)]
+pub struct ChaosRegistry<R> {
+    inner: R,
+    latency: (Duration, Duration),
+    error_rate: f64,
+    drop_rate: f64,
+    duplicate_rate: f64,
+    rng: Arc<Mutex<StdRng>>,
+}
+
+/// The error of a `ChaosRegistry`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChaosError<E> {
+    /// The call failed on purpose and never reached the wrapped registry.
+    Injected,
+    Registry(E),
+}
+
+impl<E> fmt::Display for ChaosError<E>
+where
+    E: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChaosError::Injected => write!(f, "injected registry failure"),
+            ChaosError::Registry(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E> error::Error for ChaosError<E> where E: error::Error {}
+
+impl<R> ChaosRegistry<R> {
+    /// Wraps `inner`, well behaved until configured otherwise.
+    pub fn new(inner: R) -> Self {
+        ChaosRegistry {
+            inner,
+            latency: (Duration::default(), Duration::default()),
+            error_rate: 0.0,
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+            rng: Arc::new(Mutex::new(StdRng::from_entropy())),
+        }
+    }
+
+    /// Delays each register and deregister call by a random duration
+    /// between `min` and `max`.
+    pub fn latency(mut self, min: Duration, max: Duration) -> Self {
+        self.latency = (min, max.max(min));
+        self
+    }
+
+    /// The share of register and deregister calls failing with
+    /// `ChaosError::Injected`.
+    pub fn error_rate(mut self, error_rate: f64) -> Self {
+        self.error_rate = error_rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// The share of watch events never delivered.
+    pub fn drop_rate(mut self, drop_rate: f64) -> Self {
+        self.drop_rate = drop_rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// The share of watch events delivered twice in a row.
+    pub fn duplicate_rate(mut self, duplicate_rate: f64) -> Self {
+        self.duplicate_rate = duplicate_rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Makes the injected faults the same from run to run.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = Arc::new(Mutex::new(StdRng::seed_from_u64(seed)));
+        self
+    }
+
+    /// The wrapped registry.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    fn roll(&self) -> Roll {
+        let mut rng = self.rng.lock().unwrap();
+        let (min, max) = self.latency;
+        let delay = if max > min {
+            min + (max - min).mul_f64(rng.gen::<f64>())
+        } else {
+            min
+        };
+        Roll {
+            delay,
+            fail: rng.gen_bool(self.error_rate),
+        }
+    }
+
+    fn call<F>(&self, call: impl FnOnce() -> F) -> ChaosFuture<F> {
+        let roll = self.roll();
+        ChaosFuture {
+            delay: if roll.delay > Duration::default() {
+                Some(delay_for(roll.delay))
+            } else {
+                None
+            },
+            inner: if roll.fail { None } else { Some(call()) },
+        }
+    }
+
+    fn watcher<W>(&self, inner: W) -> ChaosWatcher<W> {
+        ChaosWatcher {
+            inner,
+            drop_rate: self.drop_rate,
+            duplicate_rate: self.duplicate_rate,
+            rng: self.rng.clone(),
+            duplicate: None,
+        }
+    }
+}
+
+struct Roll {
+    delay: Duration,
+    fail: bool,
+}
+
+impl<R> Registry for ChaosRegistry<R>
+where
+    R: Registry,
+{
+    type Error = ChaosError<R::Error>;
+    type RegFuture = ChaosFuture<R::RegFuture>;
+    type DeRegFuture = ChaosFuture<R::DeRegFuture>;
+    type ListFuture = ChaosFuture<R::ListFuture>;
+    type Watcher = ChaosWatcher<R::Watcher>;
+
+    fn register(&self, ins: Arc<Instance>) -> Self::RegFuture {
+        self.call(|| self.inner.register(ins))
+    }
+
+    fn deregister(&self, ins: &Arc<Instance>) -> Self::DeRegFuture {
+        self.call(|| self.inner.deregister(ins))
+    }
+
+    fn evict(&self, appid: &str, instance_id: &str) -> Self::DeRegFuture {
+        self.call(|| self.inner.evict(appid, instance_id))
+    }
+
+    fn update_if(&self, ins: Arc<Instance>, expected_version: u64) -> Self::RegFuture {
+        self.call(|| self.inner.update_if(ins, expected_version))
+    }
+
+    fn renew(&self, ins: &Arc<Instance>) -> Self::RegFuture {
+        self.call(|| self.inner.renew(ins))
+    }
+
+    fn list(&self, appid: &str) -> Self::ListFuture {
+        self.call(|| self.inner.list(appid))
+    }
+
+    fn watch(&self, appid: &str) -> Self::Watcher {
+        self.watcher(self.inner.watch(appid))
+    }
+
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher {
+        self.watcher(self.inner.watch_from(appid, instances))
+    }
+}
+
+/// A call of a `ChaosRegistry` other than `watch`.
+#[pin_project]
+pub struct ChaosFuture<F> {
+    #[pin]
+    delay: Option<BoxFuture<'static, ()>>,
+    #[pin]
+    inner: Option<F>,
+}
+
+impl<F, T, E> Future for ChaosFuture<F>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    type Output = Result<T, ChaosError<E>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = self.project();
+        if let Some(delay) = this.delay.as_mut().as_pin_mut() {
+            ready!(delay.poll(cx));
+            this.delay.set(None);
+        }
+        match this.inner.as_pin_mut() {
+            Some(inner) => inner.poll(cx).map_err(ChaosError::Registry),
+            None => Poll::Ready(Err(ChaosError::Injected)),
+        }
+    }
+}
+
+/// The watcher of a `ChaosRegistry`.
+#[pin_project]
+pub struct ChaosWatcher<W> {
+    #[pin]
+    inner: W,
+    drop_rate: f64,
+    duplicate_rate: f64,
+    rng: Arc<Mutex<StdRng>>,
+    duplicate: Option<WatchEvent>,
+}
+
+impl<W> Stream for ChaosWatcher<W>
+where
+    W: Stream<Item = WatchEvent>,
+{
+    type Item = WatchEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        if let Some(watch_event) = this.duplicate.take() {
+            return Poll::Ready(Some(watch_event));
+        }
+        loop {
+            let watch_event = match ready!(this.inner.as_mut().poll_next(cx)) {
+                Some(watch_event) => watch_event,
+                None => return Poll::Ready(None),
+            };
+            let mut rng = this.rng.lock().unwrap();
+            if rng.gen_bool(*this.drop_rate) {
+                continue;
+            }
+            if rng.gen_bool(*this.duplicate_rate) {
+                *this.duplicate = Some(watch_event.clone());
+            }
+            return Poll::Ready(Some(watch_event));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChaosError, ChaosRegistry};
+    use crate::{
+        testing::{instance, MockRegistry},
+        watcher::Event,
+        Registry,
+    };
+    use futures::StreamExt;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_chaos_calls() {
+        let mock = MockRegistry::new();
+        let registry = ChaosRegistry::new(mock.clone()).error_rate(1.0);
+        assert_eq!(
+            registry.register(instance("grpc://172.1.1.1:9999")).await,
+            Err(ChaosError::Injected)
+        );
+        assert!(mock.calls().is_empty());
+
+        let registry = ChaosRegistry::new(mock.clone())
+            .latency(Duration::from_millis(20), Duration::from_millis(30));
+        let start = Instant::now();
+        registry
+            .register(instance("grpc://172.1.1.1:9999"))
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        mock.assert_registered(&instance("grpc://172.1.1.1:9999"));
+    }
+
+    #[tokio::test]
+    async fn test_chaos_watch() {
+        let mock = MockRegistry::new();
+        for i in 1..=100 {
+            mock.insert(instance(&format!("grpc://172.1.1.{}:9999", i)));
+        }
+        let events = |registry: ChaosRegistry<MockRegistry>| {
+            registry
+                .watch("provider")
+                .take_until(tokio::time::delay_for(Duration::from_millis(10)))
+                .map(|watch_event| watch_event.event)
+                .collect::<Vec<Event>>()
+        };
+
+        let dropped = events(ChaosRegistry::new(mock.clone()).drop_rate(0.5).seed(1)).await;
+        assert!(dropped.len() > 10 && dropped.len() < 90);
+
+        let duplicated = events(ChaosRegistry::new(mock.clone()).duplicate_rate(1.0).seed(1)).await;
+        assert_eq!(duplicated.len(), 200);
+        assert_eq!(duplicated[0], duplicated[1]);
+
+        let seeded = events(ChaosRegistry::new(mock).drop_rate(0.5).seed(1)).await;
+        assert_eq!(seeded, dropped);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::{
+    runtime::timeout,
+    watcher::{Event, WatchEvent},
+    Instance,
+};
+use futures::{Stream, StreamExt};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Waits up to `within` for an event matching `pred`, skipping the other
+/// ones, and panics if none comes.
+///
+/// ```ignore
+/// let mut watcher = zk.watch("/dubbo-rs/provider");
+/// expect_event(&mut watcher, |event| matches!(event, Event::Create(_)), Duration::from_secs(5)).await;
+/// ```
+pub async fn expect_event<W, F>(watcher: &mut W, mut pred: F, within: Duration) -> Event
+where
+    W: Stream<Item = WatchEvent> + Unpin,
+    F: FnMut(&Event) -> bool,
+{
+    let deadline = Instant::now() + within;
+    let mut skipped = Vec::new();
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match timeout(left, watcher.next()).await {
+            Some(Some(watch_event)) if pred(&watch_event.event) => return watch_event.event,
+            Some(Some(watch_event)) => skipped.push(watch_event.event),
+            Some(None) => panic!("watcher ended, got only {:?}", skipped),
+            None => panic!("no matching event within {:?}, got {:?}", within, skipped),
+        }
+    }
+}
+
+/// Waits up to `within` for the Create of an instance matching `pred`,
+/// skipping the other events, and panics if none comes.
+pub async fn expect_create<W, F>(watcher: &mut W, mut pred: F, within: Duration) -> Arc<Instance>
+where
+    W: Stream<Item = WatchEvent> + Unpin,
+    F: FnMut(&Instance) -> bool,
+{
+    let event = expect_event(
+        watcher,
+        |event| matches!(event, Event::Create(ins) if pred(ins)),
+        within,
+    );
+    match event.await {
+        Event::Create(ins) => ins,
+        Event::Update(_) | Event::Delete(_) => unreachable!(),
+    }
+}
+
+/// Waits up to `within` for the Update of an instance matching `pred`,
+/// skipping the other events, and panics if none comes.
+pub async fn expect_update<W, F>(watcher: &mut W, mut pred: F, within: Duration) -> Arc<Instance>
+where
+    W: Stream<Item = WatchEvent> + Unpin,
+    F: FnMut(&Instance) -> bool,
+{
+    let event = expect_event(
+        watcher,
+        |event| matches!(event, Event::Update(ins) if pred(ins)),
+        within,
+    );
+    match event.await {
+        Event::Update(ins) => ins,
+        Event::Create(_) | Event::Delete(_) => unreachable!(),
+    }
+}
+
+/// Waits up to `within` for the Delete of an instance matching `pred`,
+/// skipping the other events, and panics if none comes.
+pub async fn expect_delete<W, F>(watcher: &mut W, mut pred: F, within: Duration) -> Arc<Instance>
+where
+    W: Stream<Item = WatchEvent> + Unpin,
+    F: FnMut(&Instance) -> bool,
+{
+    let event = expect_event(
+        watcher,
+        |event| matches!(event, Event::Delete(ins) if pred(ins)),
+        within,
+    );
+    match event.await {
+        Event::Delete(ins) => ins,
+        Event::Create(_) | Event::Update(_) => unreachable!(),
+    }
+}
+
+/// Collects events until none came for `quiet`, or the watcher ended.
+pub async fn collect_until_quiescent<W>(watcher: &mut W, quiet: Duration) -> Vec<Event>
+where
+    W: Stream<Item = WatchEvent> + Unpin,
+{
+    let mut events = Vec::new();
+    while let Some(Some(watch_event)) = timeout(quiet, watcher.next()).await {
+        events.push(watch_event.event);
+    }
+    events
+}
+
+/// Panics if an event comes within `quiet`.
+pub async fn expect_quiescent<W>(watcher: &mut W, quiet: Duration)
+where
+    W: Stream<Item = WatchEvent> + Unpin,
+{
+    if let Some(Some(watch_event)) = timeout(quiet, watcher.next()).await {
+        panic!("unexpected event {:?}", watch_event.event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{collect_until_quiescent, expect_create, expect_delete, expect_quiescent};
+    use crate::{
+        testing::{instance, MockRegistry},
+        watcher::Event,
+        Registry,
+    };
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_expect() {
+        let registry = MockRegistry::new();
+        registry.insert(instance("grpc://172.1.1.1:9999"));
+        registry.insert(instance("grpc://172.1.1.2:9999"));
+        let mut watcher = registry.watch("provider");
+        let within = Duration::from_millis(100);
+
+        let ins = expect_create(
+            &mut watcher,
+            |ins| ins.addrs[0] == "grpc://172.1.1.2:9999",
+            within,
+        )
+        .await;
+        assert_eq!(ins, instance("grpc://172.1.1.2:9999"));
+        expect_quiescent(&mut watcher, Duration::from_millis(10)).await;
+
+        registry.remove(&instance("grpc://172.1.1.1:9999"));
+        registry.insert(instance("grpc://172.1.1.3:9999"));
+        assert_eq!(
+            collect_until_quiescent(&mut watcher, Duration::from_millis(10)).await,
+            vec![
+                Event::Delete(instance("grpc://172.1.1.1:9999")),
+                Event::Create(instance("grpc://172.1.1.3:9999")),
+            ]
+        );
+
+        registry.remove(&instance("grpc://172.1.1.3:9999"));
+        expect_delete(&mut watcher, |_| true, within).await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "no matching event")]
+    async fn test_expect_timeout() {
+        let registry = MockRegistry::new();
+        registry.insert(instance("grpc://172.1.1.1:9999"));
+        let mut watcher = registry.watch("provider");
+        expect_delete(&mut watcher, |_| true, Duration::from_millis(10)).await;
+    }
+}
@@ -0,0 +1,203 @@
+use super::Call;
+use crate::{
+    watcher::{Clock, SystemClock},
+    Instance, Registry,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// Passes calls through to a registry and records them, to check afterwards
+/// what code under test did with its registry.
+///
+/// Calls are recorded when made, whether or not they succeed. Clones share
+/// the same records.
+///
+/// ```ignore
+/// let registry = RecordingRegistry::new(zk);
+/// serve(registry.clone()).await;
+/// assert_eq!(registry.count(&Call::Deregister(ins)), 1);
+/// ```
+#[derive(Clone)]
+pub struct RecordingRegistry<R> {
+    inner: R,
+    records: Arc<Mutex<Vec<Record>>>,
+    clock: Arc<dyn Clock>,
+}
+
+/// A call received by a `RecordingRegistry`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub call: Call,
+    pub at: SystemTime,
+}
+
+impl<R> RecordingRegistry<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_clock(inner, SystemClock)
+    }
+
+    /// Timestamps the records with `clock`.
+    pub fn with_clock<C>(inner: R, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        RecordingRegistry {
+            inner,
+            records: Arc::new(Mutex::new(Vec::new())),
+            clock: Arc::new(clock),
+        }
+    }
+
+    /// The wrapped registry.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// The calls received so far, in order.
+    pub fn records(&self) -> Vec<Record> {
+        self.records.lock().unwrap().clone()
+    }
+
+    /// The calls received so far without their timestamps.
+    pub fn calls(&self) -> Vec<Call> {
+        let records = self.records.lock().unwrap();
+        records.iter().map(|record| record.call.clone()).collect()
+    }
+
+    /// How many times `call` was received.
+    pub fn count(&self, call: &Call) -> usize {
+        let records = self.records.lock().unwrap();
+        records.iter().filter(|record| record.call == *call).count()
+    }
+
+    /// The instances registered, in order, including the ones later
+    /// deregistered.
+    pub fn registered(&self) -> Vec<Arc<Instance>> {
+        self.filter(|call| match call {
+            Call::Register(ins) => Some(ins.clone()),
+            _ => None,
+        })
+    }
+
+    /// The instances deregistered, in order.
+    pub fn deregistered(&self) -> Vec<Arc<Instance>> {
+        self.filter(|call| match call {
+            Call::Deregister(ins) => Some(ins.clone()),
+            _ => None,
+        })
+    }
+
+    /// Forgets the calls received so far.
+    pub fn clear(&self) {
+        self.records.lock().unwrap().clear();
+    }
+
+    fn filter<T>(&self, f: impl Fn(&Call) -> Option<T>) -> Vec<T> {
+        let records = self.records.lock().unwrap();
+        records
+            .iter()
+            .filter_map(|record| f(&record.call))
+            .collect()
+    }
+
+    fn record(&self, call: Call) {
+        let at = self.clock.now();
+        self.records.lock().unwrap().push(Record { call, at });
+    }
+}
+
+impl<R> Registry for RecordingRegistry<R>
+where
+    R: Registry,
+{
+    type Error = R::Error;
+    type RegFuture = R::RegFuture;
+    type DeRegFuture = R::DeRegFuture;
+    type ListFuture = R::ListFuture;
+    type Watcher = R::Watcher;
+
+    fn register(&self, ins: Arc<Instance>) -> Self::RegFuture {
+        self.record(Call::Register(ins.clone()));
+        self.inner.register(ins)
+    }
+
+    fn deregister(&self, ins: &Arc<Instance>) -> Self::DeRegFuture {
+        self.record(Call::Deregister(ins.clone()));
+        self.inner.deregister(ins)
+    }
+
+    fn evict(&self, appid: &str, instance_id: &str) -> Self::DeRegFuture {
+        self.record(Call::Evict(appid.to_owned(), instance_id.to_owned()));
+        self.inner.evict(appid, instance_id)
+    }
+
+    fn update_if(&self, ins: Arc<Instance>, expected_version: u64) -> Self::RegFuture {
+        self.record(Call::Update(ins.clone(), expected_version));
+        self.inner.update_if(ins, expected_version)
+    }
+
+    fn renew(&self, ins: &Arc<Instance>) -> Self::RegFuture {
+        self.record(Call::Renew(ins.clone()));
+        self.inner.renew(ins)
+    }
+
+    fn list(&self, appid: &str) -> Self::ListFuture {
+        self.record(Call::List(appid.to_owned()));
+        self.inner.list(appid)
+    }
+
+    fn watch(&self, appid: &str) -> Self::Watcher {
+        self.record(Call::Watch(appid.to_owned()));
+        self.inner.watch(appid)
+    }
+
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher {
+        self.record(Call::Watch(appid.to_owned()));
+        self.inner.watch_from(appid, instances)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Record, RecordingRegistry};
+    use crate::{
+        testing::{instance, Call, ManualClock, MockRegistry},
+        Registry,
+    };
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[tokio::test]
+    async fn test_recording_registry() {
+        let clock = ManualClock::default();
+        let mock = MockRegistry::new();
+        let registry = RecordingRegistry::with_clock(mock.clone(), clock.clone());
+        let ins = instance("grpc://172.1.1.1:9999");
+        registry.register(ins.clone()).await.unwrap();
+        clock.advance(Duration::from_secs(1));
+        mock.fail_deregister("boom");
+        assert!(registry.deregister(&ins).await.is_err());
+        registry.deregister(&ins).await.unwrap();
+
+        mock.assert_not_registered(&ins);
+        assert_eq!(registry.registered(), vec![ins.clone()]);
+        assert_eq!(registry.count(&Call::Deregister(ins.clone())), 2);
+        assert_eq!(
+            registry.records()[..2],
+            [
+                Record {
+                    call: Call::Register(ins.clone()),
+                    at: UNIX_EPOCH,
+                },
+                Record {
+                    call: Call::Deregister(ins),
+                    at: UNIX_EPOCH + Duration::from_secs(1),
+                },
+            ]
+        );
+
+        registry.clear();
+        assert!(registry.calls().is_empty());
+    }
+}
@@ -0,0 +1,912 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const NOTIFICATION_XID: i32 = -1;
+
+const OP_CREATE: i32 = 1;
+const OP_DELETE: i32 = 2;
+const OP_EXISTS: i32 = 3;
+const OP_GET_DATA: i32 = 4;
+const OP_SET_DATA: i32 = 5;
+const OP_GET_ACL: i32 = 6;
+const OP_SET_ACL: i32 = 7;
+const OP_GET_CHILDREN: i32 = 8;
+const OP_SYNC: i32 = 9;
+const OP_PING: i32 = 11;
+const OP_GET_CHILDREN2: i32 = 12;
+const OP_AUTH: i32 = 100;
+const OP_SET_WATCHES: i32 = 101;
+const OP_CLOSE_SESSION: i32 = -11;
+
+const ERR_MARSHALLING: i32 = -5;
+const ERR_UNIMPLEMENTED: i32 = -6;
+const ERR_BAD_ARGUMENTS: i32 = -8;
+const ERR_NO_NODE: i32 = -101;
+const ERR_BAD_VERSION: i32 = -103;
+const ERR_NO_CHILDREN_FOR_EPHEMERALS: i32 = -108;
+const ERR_NODE_EXISTS: i32 = -110;
+const ERR_NOT_EMPTY: i32 = -111;
+
+const EVENT_CREATED: i32 = 1;
+const EVENT_DELETED: i32 = 2;
+const EVENT_DATA_CHANGED: i32 = 3;
+const EVENT_CHILDREN_CHANGED: i32 = 4;
+const STATE_SYNC_CONNECTED: i32 = 3;
+
+const FLAG_EPHEMERAL: i32 = 1;
+const FLAG_SEQUENTIAL: i32 = 2;
+
+/// An in-process ZooKeeper server for tests, speaking enough of the wire
+/// protocol for the `zookeeper` client: sessions, persistent, ephemeral and
+/// sequential nodes, data, acls (stored, not enforced) and one-shot watches.
+///
+/// There is no replication and nothing is persisted. Besides serving clients,
+/// tests can cut all connections to make clients reconnect, or expire all
+/// sessions as if the clients had been away too long.
+///
+/// ```ignore
+/// let server = ZkServer::start()?;
+/// let zk = Zk::builder(&server.connect_string()).build().await?;
+/// ```
+pub struct ZkServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    acceptor: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    stopped: AtomicBool,
+    next_conn: AtomicU64,
+}
+
+impl ZkServer {
+    /// Starts serving on a free port of 127.0.0.1.
+    pub fn start() -> io::Result<ZkServer> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::new()),
+            stopped: AtomicBool::new(false),
+            next_conn: AtomicU64::new(1),
+        });
+        let acceptor = {
+            let shared = shared.clone();
+            thread::spawn(move || accept(listener, shared))
+        };
+        Ok(ZkServer {
+            addr,
+            shared,
+            acceptor: Some(acceptor),
+        })
+    }
+
+    pub fn connect_string(&self) -> String {
+        self.addr.to_string()
+    }
+
+    /// Cuts every client connection. Sessions survive, so clients
+    /// reconnecting within their session timeout keep their ephemeral nodes.
+    pub fn disconnect(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        let now = Instant::now();
+        for session in state.sessions.values_mut() {
+            if let Some(conn) = session.conn.take() {
+                let _ = conn.stream.lock().unwrap().shutdown(Shutdown::Both);
+                session.disconnected_at = Some(now);
+            }
+        }
+    }
+
+    /// Expires every session, deleting their ephemeral nodes.
+    pub fn expire_sessions(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        let ids = state.sessions.keys().cloned().collect::<Vec<i64>>();
+        for id in ids {
+            state.expire(id);
+        }
+    }
+
+    /// The data of `path`, `None` if it doesn't exist.
+    pub fn get(&self, path: &str) -> Option<Vec<u8>> {
+        let state = self.shared.state.lock().unwrap();
+        state.nodes.get(path).map(|node| node.data.clone())
+    }
+
+    /// The names of the children of `path`, empty if it doesn't exist.
+    pub fn children(&self, path: &str) -> Vec<String> {
+        let state = self.shared.state.lock().unwrap();
+        state
+            .nodes
+            .get(path)
+            .map(|node| node.children.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for ZkServer {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        if let Some(acceptor) = self.acceptor.take() {
+            let _ = acceptor.join();
+        }
+        self.expire_sessions();
+    }
+}
+
+fn accept(listener: TcpListener, shared: Arc<Shared>) {
+    while !shared.stopped.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    let _ = serve(stream, shared);
+                });
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                shared.state.lock().unwrap().reap(Instant::now());
+                thread::sleep(Duration::from_millis(5));
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+fn serve(stream: TcpStream, shared: Arc<Shared>) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+    let mut reader = stream.try_clone()?;
+    let conn = Conn {
+        id: shared.next_conn.fetch_add(1, Ordering::SeqCst),
+        stream: Arc::new(Mutex::new(stream)),
+    };
+
+    let frame = read_frame(&mut reader)?;
+    let mut r = Reader(&frame);
+    let (_protocol_version, _last_zxid) =
+        (r.i32().map_err(bad_frame)?, r.i64().map_err(bad_frame)?);
+    let (timeout, session_id) = (r.i32().map_err(bad_frame)?, r.i64().map_err(bad_frame)?);
+    let connected = shared
+        .state
+        .lock()
+        .unwrap()
+        .connect(session_id, timeout, conn.clone());
+    let mut w = Writer::default();
+    match connected {
+        Some((session_id, timeout)) => {
+            w.i32(0)
+                .i32(timeout)
+                .i64(session_id)
+                .buffer(&[0; 16])
+                .bool(false);
+            conn.send(&w.0)?;
+        }
+        None => {
+            // an expired session is told so with a zero timeout.
+            w.i32(0).i32(0).i64(0).buffer(&[0; 16]).bool(false);
+            conn.send(&w.0)?;
+            return Ok(());
+        }
+    }
+    let session_id = connected.unwrap().0;
+
+    let result = (|| loop {
+        let frame = read_frame(&mut reader)?;
+        let mut r = Reader(&frame);
+        let (xid, op) = (r.i32().map_err(bad_frame)?, r.i32().map_err(bad_frame)?);
+        let mut state = shared.state.lock().unwrap();
+        let (err, body) = match state.handle(session_id, op, &mut r) {
+            Ok(body) => (0, body),
+            Err(err) => (err, Vec::new()),
+        };
+        let mut w = Writer::default();
+        w.i32(xid).i64(state.zxid).i32(err);
+        w.0.extend(body);
+        drop(state);
+        conn.send(&w.0)?;
+        if op == OP_CLOSE_SESSION {
+            shared.state.lock().unwrap().expire(session_id);
+            return Ok(());
+        }
+    })();
+    shared
+        .state
+        .lock()
+        .unwrap()
+        .disconnected(session_id, conn.id);
+    result
+}
+
+#[derive(Clone)]
+struct Conn {
+    id: u64,
+    stream: Arc<Mutex<TcpStream>>,
+}
+
+impl Conn {
+    fn send(&self, frame: &[u8]) -> io::Result<()> {
+        let mut stream = self.stream.lock().unwrap();
+        stream.write_all(&(frame.len() as i32).to_be_bytes())?;
+        stream.write_all(frame)
+    }
+}
+
+struct Session {
+    timeout: i32,
+    conn: Option<Conn>,
+    disconnected_at: Option<Instant>,
+}
+
+#[derive(Clone)]
+struct Acl {
+    perms: i32,
+    scheme: String,
+    id: String,
+}
+
+#[derive(Clone, Default)]
+struct Stat {
+    czxid: i64,
+    mzxid: i64,
+    ctime: i64,
+    mtime: i64,
+    version: i32,
+    cversion: i32,
+    aversion: i32,
+    ephemeral_owner: i64,
+    pzxid: i64,
+}
+
+struct Node {
+    data: Vec<u8>,
+    acl: Vec<Acl>,
+    stat: Stat,
+    children: BTreeSet<String>,
+}
+
+struct State {
+    zxid: i64,
+    next_session: i64,
+    nodes: BTreeMap<String, Node>,
+    sessions: HashMap<i64, Session>,
+    data_watches: HashMap<String, HashSet<i64>>,
+    exist_watches: HashMap<String, HashSet<i64>>,
+    child_watches: HashMap<String, HashSet<i64>>,
+}
+
+impl State {
+    fn new() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(
+            "/".to_owned(),
+            Node {
+                data: Vec::new(),
+                acl: Vec::new(),
+                stat: Stat::default(),
+                children: BTreeSet::new(),
+            },
+        );
+        State {
+            zxid: 0,
+            next_session: 1,
+            nodes,
+            sessions: HashMap::new(),
+            data_watches: HashMap::new(),
+            exist_watches: HashMap::new(),
+            child_watches: HashMap::new(),
+        }
+    }
+
+    fn connect(&mut self, session_id: i64, timeout: i32, conn: Conn) -> Option<(i64, i32)> {
+        if session_id != 0 {
+            let session = self.sessions.get_mut(&session_id)?;
+            if let Some(old) = session.conn.replace(conn) {
+                let _ = old.stream.lock().unwrap().shutdown(Shutdown::Both);
+            }
+            session.disconnected_at = None;
+            return Some((session_id, session.timeout));
+        }
+        let session_id = self.next_session;
+        self.next_session += 1;
+        self.sessions.insert(
+            session_id,
+            Session {
+                timeout,
+                conn: Some(conn),
+                disconnected_at: None,
+            },
+        );
+        Some((session_id, timeout))
+    }
+
+    fn disconnected(&mut self, session_id: i64, conn_id: u64) {
+        if let Some(session) = self.sessions.get_mut(&session_id) {
+            if matches!(&session.conn, Some(conn) if conn.id == conn_id) {
+                session.conn = None;
+                session.disconnected_at = Some(Instant::now());
+            }
+        }
+    }
+
+    fn reap(&mut self, now: Instant) {
+        let expired = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| {
+                matches!(session.disconnected_at, Some(at)
+                    if now.duration_since(at) >= Duration::from_millis(session.timeout.max(0) as u64))
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<i64>>();
+        for id in expired {
+            self.expire(id);
+        }
+    }
+
+    fn expire(&mut self, session_id: i64) {
+        let session = match self.sessions.remove(&session_id) {
+            Some(session) => session,
+            None => return,
+        };
+        if let Some(conn) = session.conn {
+            let _ = conn.stream.lock().unwrap().shutdown(Shutdown::Both);
+        }
+        let ephemerals = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.stat.ephemeral_owner == session_id)
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<String>>();
+        for path in ephemerals {
+            let _ = self.delete(&path, -1);
+        }
+        for watches in [
+            &mut self.data_watches,
+            &mut self.exist_watches,
+            &mut self.child_watches,
+        ]
+        .iter_mut()
+        {
+            for sessions in watches.values_mut() {
+                sessions.remove(&session_id);
+            }
+        }
+    }
+
+    fn handle(&mut self, session_id: i64, op: i32, r: &mut Reader) -> Result<Vec<u8>, i32> {
+        let mut w = Writer::default();
+        match op {
+            OP_PING | OP_AUTH | OP_CLOSE_SESSION => {}
+            OP_CREATE => {
+                let (path, data, acl, flags) = (r.string()?, r.buffer()?, r.acls()?, r.i32()?);
+                let owner = if flags & FLAG_EPHEMERAL != 0 {
+                    session_id
+                } else {
+                    0
+                };
+                let path = self.create(&path, data, acl, owner, flags & FLAG_SEQUENTIAL != 0)?;
+                w.string(&path);
+            }
+            OP_DELETE => {
+                let (path, version) = (r.string()?, r.i32()?);
+                self.delete(&path, version)?;
+            }
+            OP_EXISTS => {
+                let (path, watch) = (r.string()?, r.bool()?);
+                let node = self.nodes.get(&path);
+                if watch {
+                    let watches = if node.is_some() {
+                        &mut self.data_watches
+                    } else {
+                        &mut self.exist_watches
+                    };
+                    watches.entry(path.clone()).or_default().insert(session_id);
+                }
+                let node = self.nodes.get(&path).ok_or(ERR_NO_NODE)?;
+                w.stat(node);
+            }
+            OP_GET_DATA => {
+                let (path, watch) = (r.string()?, r.bool()?);
+                let node = self.nodes.get(&path).ok_or(ERR_NO_NODE)?;
+                w.buffer(&node.data).stat(node);
+                if watch {
+                    self.data_watches
+                        .entry(path)
+                        .or_default()
+                        .insert(session_id);
+                }
+            }
+            OP_SET_DATA => {
+                let (path, data, version) = (r.string()?, r.buffer()?, r.i32()?);
+                self.zxid += 1;
+                let (zxid, now) = (self.zxid, now_millis());
+                let node = self.nodes.get_mut(&path).ok_or(ERR_NO_NODE)?;
+                if version != -1 && version != node.stat.version {
+                    return Err(ERR_BAD_VERSION);
+                }
+                node.data = data;
+                node.stat.version += 1;
+                node.stat.mzxid = zxid;
+                node.stat.mtime = now;
+                w.stat(node);
+                self.notify(&path, EVENT_DATA_CHANGED);
+            }
+            OP_GET_ACL => {
+                let path = r.string()?;
+                let node = self.nodes.get(&path).ok_or(ERR_NO_NODE)?;
+                w.acls(&node.acl).stat(node);
+            }
+            OP_SET_ACL => {
+                let (path, acl, version) = (r.string()?, r.acls()?, r.i32()?);
+                let node = self.nodes.get_mut(&path).ok_or(ERR_NO_NODE)?;
+                if version != -1 && version != node.stat.aversion {
+                    return Err(ERR_BAD_VERSION);
+                }
+                node.acl = acl;
+                node.stat.aversion += 1;
+                w.stat(node);
+            }
+            OP_GET_CHILDREN | OP_GET_CHILDREN2 => {
+                let (path, watch) = (r.string()?, r.bool()?);
+                let node = self.nodes.get(&path).ok_or(ERR_NO_NODE)?;
+                w.i32(node.children.len() as i32);
+                for child in node.children.iter() {
+                    w.string(child);
+                }
+                if op == OP_GET_CHILDREN2 {
+                    w.stat(node);
+                }
+                if watch {
+                    self.child_watches
+                        .entry(path)
+                        .or_default()
+                        .insert(session_id);
+                }
+            }
+            OP_SYNC => {
+                let path = r.string()?;
+                w.string(&path);
+            }
+            OP_SET_WATCHES => {
+                let _relative_zxid = r.i64()?;
+                let (data, exist, child) = (r.strings()?, r.strings()?, r.strings()?);
+                for (paths, watches) in [
+                    (data, &mut self.data_watches),
+                    (exist, &mut self.exist_watches),
+                    (child, &mut self.child_watches),
+                ]
+                .iter_mut()
+                {
+                    for path in paths.iter() {
+                        watches.entry(path.clone()).or_default().insert(session_id);
+                    }
+                }
+            }
+            _ => return Err(ERR_UNIMPLEMENTED),
+        }
+        Ok(w.0)
+    }
+
+    fn create(
+        &mut self,
+        path: &str,
+        data: Vec<u8>,
+        acl: Vec<Acl>,
+        owner: i64,
+        sequential: bool,
+    ) -> Result<String, i32> {
+        let (parent_path, _) = split(path).ok_or(ERR_BAD_ARGUMENTS)?;
+        self.zxid += 1;
+        let (zxid, now) = (self.zxid, now_millis());
+        let parent = self.nodes.get_mut(parent_path).ok_or(ERR_NO_NODE)?;
+        if parent.stat.ephemeral_owner != 0 {
+            return Err(ERR_NO_CHILDREN_FOR_EPHEMERALS);
+        }
+        let path = if sequential {
+            format!("{}{:010}", path, parent.stat.cversion)
+        } else {
+            path.to_owned()
+        };
+        let name = split(&path).unwrap().1.to_owned();
+        if parent.children.contains(&name) {
+            return Err(ERR_NODE_EXISTS);
+        }
+        parent.children.insert(name);
+        parent.stat.cversion += 1;
+        parent.stat.pzxid = zxid;
+        self.nodes.insert(
+            path.clone(),
+            Node {
+                data,
+                acl,
+                stat: Stat {
+                    czxid: zxid,
+                    mzxid: zxid,
+                    ctime: now,
+                    mtime: now,
+                    ephemeral_owner: owner,
+                    pzxid: zxid,
+                    ..Default::default()
+                },
+                children: BTreeSet::new(),
+            },
+        );
+        self.notify(&path, EVENT_CREATED);
+        self.notify(parent_path, EVENT_CHILDREN_CHANGED);
+        Ok(path)
+    }
+
+    fn delete(&mut self, path: &str, version: i32) -> Result<(), i32> {
+        let (parent_path, name) = split(path).ok_or(ERR_BAD_ARGUMENTS)?;
+        let node = self.nodes.get(path).ok_or(ERR_NO_NODE)?;
+        if version != -1 && version != node.stat.version {
+            return Err(ERR_BAD_VERSION);
+        }
+        if !node.children.is_empty() {
+            return Err(ERR_NOT_EMPTY);
+        }
+        self.nodes.remove(path);
+        self.zxid += 1;
+        let zxid = self.zxid;
+        if let Some(parent) = self.nodes.get_mut(parent_path) {
+            parent.children.remove(name);
+            parent.stat.cversion += 1;
+            parent.stat.pzxid = zxid;
+        }
+        self.notify(path, EVENT_DELETED);
+        self.notify(parent_path, EVENT_CHILDREN_CHANGED);
+        Ok(())
+    }
+
+    // fires and clears the watches `event` triggers on `path`.
+    fn notify(&mut self, path: &str, event: i32) {
+        let mut sessions = HashSet::new();
+        let mut take = |watches: &mut HashMap<String, HashSet<i64>>| {
+            if let Some(watched) = watches.remove(path) {
+                sessions.extend(watched);
+            }
+        };
+        match event {
+            EVENT_CREATED => take(&mut self.exist_watches),
+            EVENT_DATA_CHANGED => take(&mut self.data_watches),
+            EVENT_CHILDREN_CHANGED => take(&mut self.child_watches),
+            _ => {
+                take(&mut self.data_watches);
+                take(&mut self.child_watches);
+            }
+        }
+
+        let mut w = Writer::default();
+        w.i32(NOTIFICATION_XID)
+            .i64(-1)
+            .i32(0)
+            .i32(event)
+            .i32(STATE_SYNC_CONNECTED)
+            .string(path);
+        for id in sessions {
+            if let Some(Session {
+                conn: Some(conn), ..
+            }) = self.sessions.get(&id)
+            {
+                let _ = conn.send(&w.0);
+            }
+        }
+    }
+}
+
+fn split(path: &str) -> Option<(&str, &str)> {
+    if !path.starts_with('/') || path.len() < 2 || path.ends_with('/') {
+        return None;
+    }
+    let pos = path.rfind('/')?;
+    Some((if pos == 0 { "/" } else { &path[..pos] }, &path[pos + 1..]))
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+fn read_frame(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = i32::from_be_bytes(len);
+    if len < 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "negative frame length",
+        ));
+    }
+    let mut frame = vec![0; len as usize];
+    stream.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+fn bad_frame(err: i32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("bad frame, zk error {}", err),
+    )
+}
+
+// jute, the zookeeper serialization: big endian numbers, length prefixed
+// buffers, strings and vectors.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], i32> {
+        if self.0.len() < n {
+            return Err(ERR_MARSHALLING);
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn i32(&mut self) -> Result<i32, i32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(i32::from_be_bytes(b))
+    }
+
+    fn i64(&mut self) -> Result<i64, i32> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(i64::from_be_bytes(b))
+    }
+
+    fn bool(&mut self) -> Result<bool, i32> {
+        Ok(self.take(1)?[0] != 0)
+    }
+
+    fn buffer(&mut self) -> Result<Vec<u8>, i32> {
+        let len = self.i32()?;
+        if len < 0 {
+            return Ok(Vec::new());
+        }
+        Ok(self.take(len as usize)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String, i32> {
+        String::from_utf8(self.buffer()?).map_err(|_| ERR_MARSHALLING)
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>, i32> {
+        let len = self.i32()?;
+        (0..len.max(0)).map(|_| self.string()).collect()
+    }
+
+    fn acls(&mut self) -> Result<Vec<Acl>, i32> {
+        let len = self.i32()?;
+        (0..len.max(0))
+            .map(|_| {
+                Ok(Acl {
+                    perms: self.i32()?,
+                    scheme: self.string()?,
+                    id: self.string()?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn i32(&mut self, v: i32) -> &mut Self {
+        self.0.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    fn i64(&mut self, v: i64) -> &mut Self {
+        self.0.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    fn bool(&mut self, v: bool) -> &mut Self {
+        self.0.push(v as u8);
+        self
+    }
+
+    fn buffer(&mut self, v: &[u8]) -> &mut Self {
+        self.i32(v.len() as i32);
+        self.0.extend_from_slice(v);
+        self
+    }
+
+    fn string(&mut self, v: &str) -> &mut Self {
+        self.buffer(v.as_bytes())
+    }
+
+    fn acls(&mut self, acls: &[Acl]) -> &mut Self {
+        self.i32(acls.len() as i32);
+        for acl in acls {
+            self.i32(acl.perms).string(&acl.scheme).string(&acl.id);
+        }
+        self
+    }
+
+    fn stat(&mut self, node: &Node) -> &mut Self {
+        let stat = &node.stat;
+        self.i64(stat.czxid)
+            .i64(stat.mzxid)
+            .i64(stat.ctime)
+            .i64(stat.mtime)
+            .i32(stat.version)
+            .i32(stat.cversion)
+            .i32(stat.aversion)
+            .i64(stat.ephemeral_owner)
+            .i32(node.data.len() as i32)
+            .i32(node.children.len() as i32)
+            .i64(stat.pzxid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        read_frame, Reader, Writer, ZkServer, ERR_NODE_EXISTS, ERR_NO_NODE, EVENT_CHILDREN_CHANGED,
+        EVENT_DELETED, FLAG_EPHEMERAL, FLAG_SEQUENTIAL, NOTIFICATION_XID, OP_CLOSE_SESSION,
+        OP_CREATE, OP_EXISTS, OP_GET_CHILDREN, OP_GET_DATA,
+    };
+    use std::{io::Write, net::TcpStream, thread, time::Duration};
+
+    // a bare bones client, just enough to drive the server.
+    struct Client {
+        stream: TcpStream,
+        xid: i32,
+        notifications: Vec<(i32, String)>,
+    }
+
+    impl Client {
+        fn connect(server: &ZkServer, session_id: i64) -> (Client, i64) {
+            let mut stream = TcpStream::connect(server.connect_string()).unwrap();
+            let mut w = Writer::default();
+            w.i32(0)
+                .i64(0)
+                .i32(100)
+                .i64(session_id)
+                .buffer(&[0; 16])
+                .bool(false);
+            send(&mut stream, &w.0);
+            let frame = read_frame(&mut stream).unwrap();
+            let mut r = Reader(&frame);
+            let (_, _, session_id) = (r.i32().unwrap(), r.i32().unwrap(), r.i64().unwrap());
+            let client = Client {
+                stream,
+                xid: 0,
+                notifications: Vec::new(),
+            };
+            (client, session_id)
+        }
+
+        fn request(&mut self, op: i32, body: &mut Writer) -> (i32, Vec<u8>) {
+            self.xid += 1;
+            let mut w = Writer::default();
+            w.i32(self.xid).i32(op);
+            w.0.append(&mut body.0);
+            send(&mut self.stream, &w.0);
+            loop {
+                let frame = read_frame(&mut self.stream).unwrap();
+                let mut r = Reader(&frame);
+                let (xid, _, err) = (r.i32().unwrap(), r.i64().unwrap(), r.i32().unwrap());
+                if xid == NOTIFICATION_XID {
+                    let (event, _) = (r.i32().unwrap(), r.i32().unwrap());
+                    self.notifications.push((event, r.string().unwrap()));
+                    continue;
+                }
+                assert_eq!(xid, self.xid);
+                return (err, r.0.to_vec());
+            }
+        }
+
+        fn create(&mut self, path: &str, flags: i32) -> Result<String, i32> {
+            let mut w = Writer::default();
+            w.string(path).buffer(b"data").acls(&[]).i32(flags);
+            match self.request(OP_CREATE, &mut w) {
+                (0, body) => Ok(Reader(&body).string().unwrap()),
+                (err, _) => Err(err),
+            }
+        }
+
+        fn exists(&mut self, path: &str) -> bool {
+            let mut w = Writer::default();
+            w.string(path).bool(false);
+            self.request(OP_EXISTS, &mut w).0 == 0
+        }
+    }
+
+    fn send(stream: &mut TcpStream, frame: &[u8]) {
+        stream
+            .write_all(&(frame.len() as i32).to_be_bytes())
+            .unwrap();
+        stream.write_all(frame).unwrap();
+    }
+
+    #[test]
+    fn test_zk_server_nodes_and_watches() {
+        let server = ZkServer::start().unwrap();
+        let (mut watching, _) = Client::connect(&server, 0);
+        let (mut owner, _) = Client::connect(&server, 0);
+
+        assert_eq!(watching.create("/app", 0), Ok("/app".to_owned()));
+        assert_eq!(watching.create("/app", 0), Err(ERR_NODE_EXISTS));
+        assert_eq!(watching.create("/missing/child", 0), Err(ERR_NO_NODE));
+        let mut w = Writer::default();
+        w.string("/app").bool(true);
+        let (err, body) = watching.request(OP_GET_CHILDREN, &mut w);
+        assert_eq!((err, Reader(&body).strings().unwrap()), (0, vec![]));
+
+        assert_eq!(
+            owner.create("/app/ins", FLAG_EPHEMERAL),
+            Ok("/app/ins".to_owned())
+        );
+        assert_eq!(
+            owner.create("/app/seq-", FLAG_SEQUENTIAL),
+            Ok("/app/seq-0000000001".to_owned())
+        );
+        assert_eq!(server.children("/app"), vec!["ins", "seq-0000000001"]);
+        let mut w = Writer::default();
+        w.string("/app/ins").bool(false);
+        let (err, body) = watching.request(OP_GET_DATA, &mut w);
+        assert_eq!(
+            (err, Reader(&body).buffer().unwrap()),
+            (0, b"data".to_vec())
+        );
+        // one-shot: only the first change is reported.
+        assert_eq!(
+            watching.notifications,
+            vec![(EVENT_CHILDREN_CHANGED, "/app".to_owned())]
+        );
+
+        let mut w = Writer::default();
+        w.string("/app/ins").bool(true);
+        watching.request(OP_EXISTS, &mut w);
+        owner.request(OP_CLOSE_SESSION, &mut Writer::default());
+        thread::sleep(Duration::from_millis(50));
+        assert!(!watching.exists("/app/ins"));
+        assert!(watching.exists("/app/seq-0000000001"));
+        assert_eq!(
+            watching.notifications[1],
+            (EVENT_DELETED, "/app/ins".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_zk_server_sessions() {
+        let server = ZkServer::start().unwrap();
+        let (mut client, session_id) = Client::connect(&server, 0);
+        client.create("/ins", FLAG_EPHEMERAL).unwrap();
+
+        // the session survives a lost connection.
+        server.disconnect();
+        let (mut client, resumed) = Client::connect(&server, session_id);
+        assert_eq!(resumed, session_id);
+        assert!(client.exists("/ins"));
+
+        server.expire_sessions();
+        assert!(server.get("/ins").is_none());
+        let (_, resumed) = Client::connect(&server, session_id);
+        assert_eq!(resumed, 0);
+
+        // and expires once away longer than its timeout.
+        let (mut client, _) = Client::connect(&server, 0);
+        client.create("/ins", FLAG_EPHEMERAL).unwrap();
+        drop(client);
+        thread::sleep(Duration::from_millis(300));
+        assert!(server.get("/ins").is_none());
+    }
+}
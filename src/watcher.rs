@@ -1,28 +1,109 @@
-use crate::Instance;
+use crate::{delta::InstanceDelta, DiscoverError, Instance};
 use futures::Stream;
-use std::time::SystemTime;
+use std::{sync::Arc, time::SystemTime};
 
-#[derive(PartialEq, Eq, Debug)]
+/// A change of the instances of an app. Instances are shared, so passing
+/// events along never copies them.
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Event {
-    Create(Instance),
-    Delete(Instance),
+    Create(Arc<Instance>),
+    /// A new version of an instance reported before, e.g. with new
+    /// metadata, replacing it.
+    Update(Arc<Instance>),
+    Delete(Arc<Instance>),
 }
 
 pub trait Watcher: Stream {}
 
 impl<T> Watcher for T where T: Stream<Item = WatchEvent> {}
 
-#[derive(Debug)]
+/// An item of the watchers `AppDiscover` follows: the events of registry
+/// watchers, or the results of watchers that may fail.
+pub trait WatchItem {
+    fn into_result(self) -> Result<WatchEvent, DiscoverError>;
+}
+
+impl WatchItem for WatchEvent {
+    fn into_result(self) -> Result<WatchEvent, DiscoverError> {
+        Ok(self)
+    }
+}
+
+impl<E> WatchItem for Result<WatchEvent, E>
+where
+    E: Into<DiscoverError>,
+{
+    fn into_result(self) -> Result<WatchEvent, DiscoverError> {
+        self.map_err(Into::into)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct WatchEvent {
     pub event: Event,
     pub timestamp: SystemTime,
+    /// Why the instance of a `Delete` went away, when the registry knows.
+    pub reason: Option<DeleteReason>,
+    /// Set on the first event of a watcher after it failed over to another
+    /// registry, see `failover::FailoverRegistry`.
+    pub failed_over: bool,
+    /// What changed in the instance of an `Update`, when the registry knows
+    /// the version it replaces.
+    pub delta: Option<InstanceDelta>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeleteReason {
+    /// The instance deregistered itself.
+    Deregistered,
+    /// Removed by someone else, see `Registry::evict`.
+    Evicted,
+    /// Not renewed within its TTL, see `expiry::Expiring`.
+    Expired,
 }
 
 impl WatchEvent {
     pub fn new(event: Event) -> WatchEvent {
+        Self::with_clock(event, &SystemClock)
+    }
+
+    /// Timestamps the event with `clock` instead of the system time.
+    pub fn with_clock(event: Event, clock: &dyn Clock) -> WatchEvent {
         WatchEvent {
             event,
-            timestamp: SystemTime::now(),
+            timestamp: clock.now(),
+            reason: None,
+            failed_over: false,
+            delta: None,
         }
     }
+
+    pub fn with_reason(mut self, reason: DeleteReason) -> WatchEvent {
+        self.reason = Some(reason);
+        self
+    }
+
+    /// Sets the delta of an `Update` from `old`, the version it replaces.
+    pub fn with_delta(mut self, old: &Instance) -> WatchEvent {
+        if let Event::Update(ins) = &self.event {
+            self.delta = Some(old.diff(ins));
+        }
+        self
+    }
+}
+
+/// Where registries get the timestamp of the events they report, so tests
+/// can control it.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
 }
@@ -0,0 +1,746 @@
+//! Serves the watched instances to Envoy and gRPC xDS clients, as EDS
+//! endpoints of CDS clusters.
+use crate::{
+    identity::{DefaultIdentity, Identity},
+    runtime::{self, AbortOnDrop},
+    watcher::{Event, WatchEvent},
+    Instance,
+};
+use futures::{channel::mpsc, future, stream, Stream, StreamExt};
+use log::warn;
+use prost::Message;
+use std::{
+    collections::{BTreeMap, HashMap},
+    pin::Pin,
+    sync::{Arc, Mutex, RwLock},
+    task::{Context, Poll},
+};
+use tokio::sync::watch;
+use tonic::{
+    body::BoxBody,
+    codec::ProstCodec,
+    codegen::{http, BoxFuture, HttpBody, Never, Service, StdError},
+    server::{Grpc, StreamingService},
+    transport::NamedService,
+    Request, Response, Status, Streaming,
+};
+
+pub const CLUSTER_TYPE: &str = "type.googleapis.com/envoy.config.cluster.v3.Cluster";
+pub const ENDPOINT_TYPE: &str =
+    "type.googleapis.com/envoy.config.endpoint.v3.ClusterLoadAssignment";
+
+type Key = <DefaultIdentity as Identity>::Key;
+
+type ResponseStream =
+    Pin<Box<dyn Stream<Item = Result<DiscoveryResponse, Status>> + Send + Sync + 'static>>;
+
+/// An xDS management server of the clusters added with `add_cluster`.
+///
+/// Every cluster is an EDS cluster whose endpoints are the instances of its
+/// watcher, grouped in localities by zone and weighted by their `weight`
+/// metadata. Resources are served state of the world over ADS and the
+/// separate CDS and EDS services. Needs a tokio runtime.
+///
+/// ```ignore
+/// let xds = XdsServer::new();
+/// xds.add_cluster("provider", zk.watch("/dubbo-rs/provider"));
+/// Server::builder()
+///     .add_service(xds.ads_service())
+///     .serve(addr)
+///     .await?;
+/// ```
+#[derive(Clone)]
+pub struct XdsServer {
+    shared: Arc<Shared>,
+    tasks: Arc<Mutex<Vec<AbortOnDrop>>>,
+}
+
+struct Shared {
+    scheme: String,
+    state: RwLock<State>,
+    version_tx: watch::Sender<u64>,
+    version_rx: watch::Receiver<u64>,
+}
+
+#[derive(Default)]
+struct State {
+    version: u64,
+    clusters: BTreeMap<String, HashMap<Key, Arc<Instance>>>,
+}
+
+impl XdsServer {
+    pub fn new() -> Self {
+        Self::with_scheme("grpc")
+    }
+
+    /// Endpoints are the addresses of `scheme` of instances, the ones
+    /// without such an address are left out.
+    pub fn with_scheme(scheme: impl Into<String>) -> Self {
+        let (version_tx, version_rx) = watch::channel(0);
+        XdsServer {
+            shared: Arc::new(Shared {
+                scheme: scheme.into(),
+                state: RwLock::new(State::default()),
+                version_tx,
+                version_rx,
+            }),
+            tasks: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Serves the instances of `watcher` as the cluster `name`, until the
+    /// server and its services are dropped.
+    pub fn add_cluster<W>(&self, name: impl Into<String>, watcher: W)
+    where
+        W: Stream<Item = WatchEvent> + Send + 'static,
+    {
+        let name = name.into();
+        let shared = self.shared.clone();
+        shared.update(|state| {
+            state.clusters.entry(name.clone()).or_default();
+        });
+        let task = watcher.for_each(move |watch_event| {
+            shared.update(|state| {
+                let instances = state.clusters.entry(name.clone()).or_default();
+                match watch_event.event {
+                    Event::Create(ins) | Event::Update(ins) => {
+                        instances.insert(DefaultIdentity.identify(&ins), ins);
+                    }
+                    Event::Delete(ins) => {
+                        instances.remove(&DefaultIdentity.identify(&ins));
+                    }
+                }
+            });
+            future::ready(())
+        });
+        self.tasks
+            .lock()
+            .unwrap()
+            .push(runtime::spawn_abortable(task));
+    }
+
+    /// `envoy.service.discovery.v3.AggregatedDiscoveryService`, the one
+    /// gRPC xDS clients use.
+    pub fn ads_service(&self) -> AggregatedDiscoveryService {
+        AggregatedDiscoveryService(self.clone())
+    }
+
+    /// `envoy.service.cluster.v3.ClusterDiscoveryService`.
+    pub fn cds_service(&self) -> ClusterDiscoveryService {
+        ClusterDiscoveryService(self.clone())
+    }
+
+    /// `envoy.service.endpoint.v3.EndpointDiscoveryService`.
+    pub fn eds_service(&self) -> EndpointDiscoveryService {
+        EndpointDiscoveryService(self.clone())
+    }
+
+    /// Answers a stream of discovery requests.
+    fn respond<S>(&self, requests: S) -> ResponseStream
+    where
+        S: Stream<Item = Result<DiscoveryRequest, Status>> + Send + Unpin + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded();
+        let shared = self.shared.clone();
+        runtime::spawn(async move {
+            let requests = requests
+                .map(Input::Request)
+                .chain(stream::once(future::ready(Input::Closed)));
+            let versions = shared.version_rx.clone().map(|_| Input::Changed);
+            let mut inputs = stream::select(requests, versions);
+            let mut subscriptions = HashMap::<String, Subscription>::new();
+            let mut nonce = 0u64;
+            while let Some(input) = inputs.next().await {
+                let type_urls = match input {
+                    Input::Request(Ok(req)) => {
+                        let sub = subscriptions.entry(req.type_url.clone()).or_default();
+                        if req.response_nonce != sub.nonce {
+                            // answers a response superseded since.
+                            continue;
+                        }
+                        if let Some(status) = &req.error_detail {
+                            warn!(
+                                "xds client rejected {} version {}: {}",
+                                req.type_url, sub.version, status.message
+                            );
+                        }
+                        let state = shared.state.read().unwrap();
+                        if req.resource_names == sub.names && Some(state.version) == sub.sent {
+                            continue;
+                        }
+                        sub.names = req.resource_names;
+                        vec![req.type_url]
+                    }
+                    Input::Request(Err(_)) | Input::Closed => break,
+                    Input::Changed => {
+                        let version = shared.state.read().unwrap().version;
+                        subscriptions
+                            .iter()
+                            .filter(|(_, sub)| sub.sent != Some(version))
+                            .map(|(type_url, _)| type_url.clone())
+                            .collect()
+                    }
+                };
+                for type_url in type_urls {
+                    let sub = subscriptions.get_mut(&type_url).unwrap();
+                    let state = shared.state.read().unwrap();
+                    let resources = match shared.resources(&state, &type_url, &sub.names) {
+                        Some(resources) => resources,
+                        None => {
+                            warn!("xds client asked for unknown type {}", type_url);
+                            continue;
+                        }
+                    };
+                    nonce += 1;
+                    sub.nonce = nonce.to_string();
+                    sub.version = state.version.to_string();
+                    sub.sent = Some(state.version);
+                    let rsp = DiscoveryResponse {
+                        version_info: sub.version.clone(),
+                        resources,
+                        type_url,
+                        nonce: sub.nonce.clone(),
+                    };
+                    if tx.unbounded_send(Ok(rsp)).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Box::pin(rx)
+    }
+
+    fn serve<B>(
+        &self,
+        path: &'static str,
+        req: http::Request<B>,
+    ) -> BoxFuture<http::Response<BoxBody>, Never>
+    where
+        B: HttpBody + Send + Sync + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        if req.uri().path() != path {
+            return Box::pin(future::ok(
+                http::Response::builder()
+                    .status(200)
+                    .header("grpc-status", "12")
+                    .header("content-type", "application/grpc")
+                    .body(BoxBody::empty())
+                    .unwrap(),
+            ));
+        }
+        let streams = Streams(self.clone());
+        Box::pin(async move {
+            let mut grpc = Grpc::new(ProstCodec::default());
+            Ok(grpc.streaming(streams, req).await)
+        })
+    }
+}
+
+impl Default for XdsServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shared {
+    fn update<F: FnOnce(&mut State)>(&self, f: F) {
+        let version = {
+            let mut state = self.state.write().unwrap();
+            f(&mut state);
+            state.version += 1;
+            state.version
+        };
+        let _ = self.version_tx.broadcast(version);
+    }
+
+    fn resources(&self, state: &State, type_url: &str, names: &[String]) -> Option<Vec<Any>> {
+        let wanted = |name: &String| names.is_empty() || names.contains(name);
+        let clusters = state.clusters.iter().filter(|(name, _)| wanted(name));
+        let resources = match type_url {
+            CLUSTER_TYPE => clusters
+                .map(|(name, _)| to_any(CLUSTER_TYPE, &cluster(name)))
+                .collect(),
+            ENDPOINT_TYPE => clusters
+                .map(|(name, instances)| {
+                    to_any(
+                        ENDPOINT_TYPE,
+                        &self.load_assignment(name, instances.values().map(|ins| &**ins)),
+                    )
+                })
+                .collect(),
+            _ => return None,
+        };
+        Some(resources)
+    }
+
+    fn load_assignment<'a>(
+        &self,
+        name: &str,
+        instances: impl Iterator<Item = &'a Instance>,
+    ) -> ClusterLoadAssignment {
+        let mut zones = BTreeMap::<&str, Vec<(SocketAddress, &Instance)>>::new();
+        for ins in instances {
+            let addr = ins.addr_for_scheme(&self.scheme).and_then(socket_address);
+            if let Some(addr) = addr {
+                zones.entry(&ins.zone).or_default().push((addr, ins));
+            }
+        }
+        let endpoints = zones
+            .into_iter()
+            .map(|(zone, mut endpoints)| {
+                endpoints.sort_by(|(a, _), (b, _)| {
+                    (&a.address, a.port_value).cmp(&(&b.address, b.port_value))
+                });
+                let lb_endpoints = endpoints
+                    .into_iter()
+                    .map(|(addr, ins)| LbEndpoint {
+                        endpoint: Some(Endpoint {
+                            address: Some(Address {
+                                socket_address: Some(addr),
+                            }),
+                            hostname: ins.hostname.clone(),
+                        }),
+                        health_status: HealthStatus::Healthy as i32,
+                        load_balancing_weight: Some(UInt32Value {
+                            value: ins.weight().unwrap_or(1).max(1),
+                        }),
+                    })
+                    .collect::<Vec<_>>();
+                let weight = lb_endpoints
+                    .iter()
+                    .filter_map(|lb_endpoint| lb_endpoint.load_balancing_weight.as_ref())
+                    .map(|weight| weight.value)
+                    .sum();
+                LocalityLbEndpoints {
+                    locality: Some(Locality {
+                        zone: zone.into(),
+                        ..Default::default()
+                    }),
+                    lb_endpoints,
+                    // gRPC xDS clients ignore the localities without weight.
+                    load_balancing_weight: Some(UInt32Value { value: weight }),
+                    priority: 0,
+                }
+            })
+            .collect();
+        ClusterLoadAssignment {
+            cluster_name: name.to_owned(),
+            endpoints,
+        }
+    }
+}
+
+enum Input {
+    Request(Result<DiscoveryRequest, Status>),
+    Closed,
+    Changed,
+}
+
+/// What was last sent to a client for a resource type.
+#[derive(Default)]
+struct Subscription {
+    names: Vec<String>,
+    nonce: String,
+    version: String,
+    sent: Option<u64>,
+}
+
+fn cluster(name: &str) -> Cluster {
+    Cluster {
+        name: name.to_owned(),
+        r#type: DiscoveryType::Eds as i32,
+        eds_cluster_config: Some(EdsClusterConfig {
+            eds_config: Some(ConfigSource {
+                // the server the cluster came from.
+                self_source: Some(SelfConfigSource {
+                    transport_api_version: API_VERSION_V3,
+                }),
+                resource_api_version: API_VERSION_V3,
+            }),
+            service_name: name.to_owned(),
+        }),
+    }
+}
+
+fn socket_address(host_port: &str) -> Option<SocketAddress> {
+    let pos = host_port.rfind(':')?;
+    let port = host_port[pos + 1..].trim_end_matches('/').parse().ok()?;
+    Some(SocketAddress {
+        address: host_port[..pos]
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned(),
+        port_value: port,
+    })
+}
+
+fn to_any<M: Message>(type_url: &str, msg: &M) -> Any {
+    let mut value = Vec::with_capacity(msg.encoded_len());
+    // a Vec grows as needed.
+    msg.encode(&mut value).unwrap();
+    Any {
+        type_url: type_url.to_owned(),
+        value,
+    }
+}
+
+struct Streams(XdsServer);
+
+impl StreamingService<DiscoveryRequest> for Streams {
+    type Response = DiscoveryResponse;
+    type ResponseStream = ResponseStream;
+    type Future = future::Ready<Result<Response<ResponseStream>, Status>>;
+
+    fn call(&mut self, req: Request<Streaming<DiscoveryRequest>>) -> Self::Future {
+        future::ok(Response::new(self.0.respond(req.into_inner())))
+    }
+}
+
+/// The ADS service of an `XdsServer`, for `tonic::transport::Server`.
+#[derive(Clone)]
+pub struct AggregatedDiscoveryService(XdsServer);
+
+/// The CDS service of an `XdsServer`, for `tonic::transport::Server`.
+#[derive(Clone)]
+pub struct ClusterDiscoveryService(XdsServer);
+
+/// The EDS service of an `XdsServer`, for `tonic::transport::Server`.
+#[derive(Clone)]
+pub struct EndpointDiscoveryService(XdsServer);
+
+macro_rules! discovery_service {
+    ($service:ident, $name:literal, $method:literal) => {
+        impl NamedService for $service {
+            const NAME: &'static str = $name;
+        }
+
+        impl<B> Service<http::Request<B>> for $service
+        where
+            B: HttpBody + Send + Sync + 'static,
+            B::Error: Into<StdError> + Send + 'static,
+        {
+            type Response = http::Response<BoxBody>;
+            type Error = Never;
+            type Future = BoxFuture<Self::Response, Never>;
+
+            fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Never>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, req: http::Request<B>) -> Self::Future {
+                self.0.serve(concat!("/", $name, "/", $method), req)
+            }
+        }
+    };
+}
+
+discovery_service!(
+    AggregatedDiscoveryService,
+    "envoy.service.discovery.v3.AggregatedDiscoveryService",
+    "StreamAggregatedResources"
+);
+discovery_service!(
+    ClusterDiscoveryService,
+    "envoy.service.cluster.v3.ClusterDiscoveryService",
+    "StreamClusters"
+);
+discovery_service!(
+    EndpointDiscoveryService,
+    "envoy.service.endpoint.v3.EndpointDiscoveryService",
+    "StreamEndpoints"
+);
+
+// the parts of the envoy v3 api used, fields left out are skipped when
+// decoding and take their defaults in clients.
+const API_VERSION_V3: i32 = 2;
+
+#[derive(Clone, PartialEq, Message)]
+struct DiscoveryRequest {
+    #[prost(string, tag = "1")]
+    version_info: String,
+    #[prost(string, repeated, tag = "3")]
+    resource_names: Vec<String>,
+    #[prost(string, tag = "4")]
+    type_url: String,
+    #[prost(string, tag = "5")]
+    response_nonce: String,
+    #[prost(message, optional, tag = "6")]
+    error_detail: Option<RpcStatus>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct RpcStatus {
+    #[prost(int32, tag = "1")]
+    code: i32,
+    #[prost(string, tag = "2")]
+    message: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct DiscoveryResponse {
+    #[prost(string, tag = "1")]
+    version_info: String,
+    #[prost(message, repeated, tag = "2")]
+    resources: Vec<Any>,
+    #[prost(string, tag = "4")]
+    type_url: String,
+    #[prost(string, tag = "5")]
+    nonce: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Any {
+    #[prost(string, tag = "1")]
+    type_url: String,
+    #[prost(bytes, tag = "2")]
+    value: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct UInt32Value {
+    #[prost(uint32, tag = "1")]
+    value: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Cluster {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(enumeration = "DiscoveryType", tag = "2")]
+    r#type: i32,
+    #[prost(message, optional, tag = "3")]
+    eds_cluster_config: Option<EdsClusterConfig>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+enum DiscoveryType {
+    Static = 0,
+    StrictDns = 1,
+    LogicalDns = 2,
+    Eds = 3,
+    OriginalDst = 4,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct EdsClusterConfig {
+    #[prost(message, optional, tag = "1")]
+    eds_config: Option<ConfigSource>,
+    #[prost(string, tag = "2")]
+    service_name: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ConfigSource {
+    #[prost(message, optional, tag = "5")]
+    self_source: Option<SelfConfigSource>,
+    #[prost(enumeration = "ApiVersion", tag = "6")]
+    resource_api_version: i32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct SelfConfigSource {
+    #[prost(enumeration = "ApiVersion", tag = "1")]
+    transport_api_version: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+enum ApiVersion {
+    Auto = 0,
+    V2 = 1,
+    V3 = 2,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ClusterLoadAssignment {
+    #[prost(string, tag = "1")]
+    cluster_name: String,
+    #[prost(message, repeated, tag = "2")]
+    endpoints: Vec<LocalityLbEndpoints>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct LocalityLbEndpoints {
+    #[prost(message, optional, tag = "1")]
+    locality: Option<Locality>,
+    #[prost(message, repeated, tag = "2")]
+    lb_endpoints: Vec<LbEndpoint>,
+    #[prost(message, optional, tag = "3")]
+    load_balancing_weight: Option<UInt32Value>,
+    #[prost(uint32, tag = "5")]
+    priority: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Locality {
+    #[prost(string, tag = "1")]
+    region: String,
+    #[prost(string, tag = "2")]
+    zone: String,
+    #[prost(string, tag = "3")]
+    sub_zone: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct LbEndpoint {
+    #[prost(message, optional, tag = "1")]
+    endpoint: Option<Endpoint>,
+    #[prost(enumeration = "HealthStatus", tag = "2")]
+    health_status: i32,
+    #[prost(message, optional, tag = "4")]
+    load_balancing_weight: Option<UInt32Value>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+enum HealthStatus {
+    Unknown = 0,
+    Healthy = 1,
+    Unhealthy = 2,
+    Draining = 3,
+    Timeout = 4,
+    Degraded = 5,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Endpoint {
+    #[prost(message, optional, tag = "1")]
+    address: Option<Address>,
+    #[prost(string, tag = "3")]
+    hostname: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Address {
+    #[prost(message, optional, tag = "1")]
+    socket_address: Option<SocketAddress>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct SocketAddress {
+    #[prost(string, tag = "2")]
+    address: String,
+    #[prost(uint32, tag = "3")]
+    port_value: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ClusterLoadAssignment, DiscoveryRequest, DiscoveryResponse, ResponseStream, XdsServer,
+        CLUSTER_TYPE, ENDPOINT_TYPE,
+    };
+    use crate::{testing::MockRegistry, Instance, Registry};
+    use futures::{channel::mpsc, StreamExt};
+    use prost::Message;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    fn instance(zone: &str, addr: &str) -> Instance {
+        Instance {
+            zone: zone.into(),
+            appid: "provider".into(),
+            addrs: vec![addr.to_owned()].into(),
+            ..Default::default()
+        }
+    }
+
+    fn request(type_url: &str, names: &[&str], nonce: &str) -> DiscoveryRequest {
+        DiscoveryRequest {
+            resource_names: names.iter().map(|name| name.to_string()).collect(),
+            type_url: type_url.to_owned(),
+            response_nonce: nonce.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn endpoints(rsp: &DiscoveryResponse) -> Vec<(String, Vec<String>)> {
+        let assignment = ClusterLoadAssignment::decode(&rsp.resources[0].value[..]).unwrap();
+        assignment
+            .endpoints
+            .into_iter()
+            .map(|locality| {
+                let addrs = locality
+                    .lb_endpoints
+                    .into_iter()
+                    .map(|lb_endpoint| {
+                        let addr = lb_endpoint.endpoint.unwrap().address.unwrap();
+                        let addr = addr.socket_address.unwrap();
+                        format!("{}:{}", addr.address, addr.port_value)
+                    })
+                    .collect();
+                (locality.locality.unwrap().zone, addrs)
+            })
+            .collect()
+    }
+
+    async fn next(responses: &mut ResponseStream) -> Option<DiscoveryResponse> {
+        let rsp = timeout(Duration::from_millis(100), responses.next()).await;
+        rsp.ok().flatten().map(Result::unwrap)
+    }
+
+    #[tokio::test]
+    async fn test_xds_server() {
+        tokio::time::pause();
+        let registry = MockRegistry::new();
+        registry.insert(instance("sh1", "grpc://172.1.1.2:9999"));
+        registry.insert(instance("sh1", "grpc://172.1.1.1:9999"));
+        registry.insert(instance("sh2", "grpc://172.1.2.1:9999"));
+        registry.insert(instance("sh2", "http://172.1.2.2:8000"));
+        let xds = XdsServer::new();
+        xds.add_cluster("provider", registry.watch("provider"));
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+
+        let (tx, requests) = mpsc::unbounded();
+        let mut responses = xds.respond(requests.map(Ok));
+
+        tx.unbounded_send(request(CLUSTER_TYPE, &[], "")).unwrap();
+        let rsp = next(&mut responses).await.unwrap();
+        assert_eq!(rsp.type_url, CLUSTER_TYPE);
+        assert_eq!(rsp.resources.len(), 1);
+        tx.unbounded_send(request(CLUSTER_TYPE, &[], &rsp.nonce))
+            .unwrap();
+
+        tx.unbounded_send(request(ENDPOINT_TYPE, &["provider"], ""))
+            .unwrap();
+        let rsp = next(&mut responses).await.unwrap();
+        assert_eq!(rsp.type_url, ENDPOINT_TYPE);
+        assert_eq!(
+            endpoints(&rsp),
+            vec![
+                (
+                    "sh1".to_owned(),
+                    vec!["172.1.1.1:9999".to_owned(), "172.1.1.2:9999".to_owned()]
+                ),
+                ("sh2".to_owned(), vec!["172.1.2.1:9999".to_owned()]),
+            ]
+        );
+        // acked, nothing new to send.
+        tx.unbounded_send(request(ENDPOINT_TYPE, &["provider"], &rsp.nonce))
+            .unwrap();
+        assert_eq!(next(&mut responses).await, None);
+
+        registry.remove(&instance("sh1", "grpc://172.1.1.2:9999"));
+        // both types are sent the new version.
+        let mut rsps = [
+            next(&mut responses).await.unwrap(),
+            next(&mut responses).await.unwrap(),
+        ];
+        rsps.sort_by(|a, b| a.type_url.cmp(&b.type_url));
+        assert_eq!(rsps[0].type_url, CLUSTER_TYPE);
+        let rsp = &rsps[1];
+        assert_eq!(
+            endpoints(rsp),
+            vec![
+                ("sh1".to_owned(), vec!["172.1.1.1:9999".to_owned()]),
+                ("sh2".to_owned(), vec!["172.1.2.1:9999".to_owned()]),
+            ]
+        );
+
+        drop(tx);
+        let end = timeout(Duration::from_millis(100), responses.next());
+        assert!(end.await.unwrap().is_none());
+    }
+}
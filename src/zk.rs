@@ -1,210 +1,746 @@
 use crate::{
-    codec::{Codec, DecodeErorr, Decoder, EncodeError, Encoder},
-    HashSet, Instance, Registry,
+    codec::{Codec, DecodeError, Decoder, DefaultDecoder, DefaultEncoder, EncodeError, Encoder},
+    hooks::ConnectionState,
+    identity::{DefaultIdentity, Identity},
+    watcher::Clock,
+    DiscoverError, Instance, Registry,
 };
-use futures::{ready, Future, FutureExt};
+use client::ZkClient;
+use futures::{channel::oneshot, future::BoxFuture, ready, Future, Stream};
+use log::error;
+use path_cache::PathCache;
 use pin_project::pin_project;
-use std::{pin::Pin, sync::{Arc, RwLock}, task::{Context, Poll}, time::Duration, fmt};
-use tokio::task;
-use tokio::task::JoinError;
-use tokio::task::JoinHandle;
-use zk_watcher::ZkWatcher;
-use zookeeper::{Acl, CreateMode, ZkError, ZooKeeper};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt,
+    pin::Pin,
+    slice,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use worker::Workers;
+use zk_watcher::{decode_instance, fill_from_stat, fill_named};
+use zookeeper::{Acl, CreateMode, ZkError};
+
+pub use config::{RetryPolicy, ZkBuilder, ZkConfig};
+pub use control::ZkControlWatcher;
+pub use election::ZkCampaign;
+pub use kv::ZkKeyWatcher;
+pub use layout::{
+    AppidLayout, Category, DataLayout, DubboLayout, Layout, Payload, SpringCloudLayout,
+};
+#[cfg(feature = "zk-tls")]
+pub use tls::ZkTls;
+pub use zk_watcher::{FallibleZkWatcher, ZkWatcher};
 
+mod client;
+mod config;
+mod control;
+mod election;
+mod kv;
+mod layout;
+mod path_cache;
+#[cfg(feature = "zk-tls")]
+mod tls;
+mod worker;
 mod zk_watcher;
 
-pub struct Zk<EC, DC>
-    where
-        EC: 'static,
-        DC: 'static,
-{
-    client: Arc<ZooKeeper>,
-    codec: &'static Codec<EC, DC>,
-    persistent_exist_node_path: Arc<RwLock<HashSet<String>>>,
+/// A ZooKeeper registry, see `ZkBuilder`.
+///
+/// The `zookeeper` client is blocking, so its calls are made on the worker
+/// threads of the registry, see `ZkConfig::worker_threads`. Its futures are
+/// handles onto them: they never block the runtime, and dropping one before
+/// its call is picked up cancels it. A call in flight still holds a thread
+/// until ZooKeeper answers, there being no async client yet.
+pub struct Zk<EC, DC, I = DefaultIdentity> {
+    client: Arc<ZkClient>,
+    codec: Arc<Codec<EC, DC>>,
+    root_prefix: String,
+    persistent_exist_node_path: Arc<PathCache>,
+    // the nodes of the instances registered by the path ZooKeeper created,
+    // with the counter of sequential nodes, to deregister them without
+    // encoding them again and to create them again in a new session.
+    registered: Registered,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+    layout: Arc<dyn Layout>,
+    workers: Arc<Workers>,
+    resync_interval: Option<Duration>,
+    sequential: bool,
+}
+
+type Registered = Arc<Mutex<HashMap<String, Node>>>;
+
+#[derive(Clone)]
+struct Node {
+    data: Vec<u8>,
+    mode: CreateMode,
+    acl: Vec<Acl>,
+    // as last registered or updated.
+    ins: Arc<Instance>,
+}
+
+// creates the ephemeral nodes registered again in a new session, the ones of
+// the session before being gone, until the `Zk` is dropped.
+fn reregister(
+    registered: &Registered,
+    persistent_exist_node_path: &Arc<PathCache>,
+) -> impl FnMut(&Arc<ZkClient>) -> bool + Send + 'static {
+    let registered = Arc::downgrade(registered);
+    let persistent_exist_node_path = persistent_exist_node_path.clone();
+    move |client| {
+        let registered = match registered.upgrade() {
+            Some(registered) => registered,
+            None => return false,
+        };
+        let nodes = registered
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, node)| node.mode != CreateMode::Persistent)
+            .map(|(path, node)| (path.clone(), node.clone()))
+            .collect::<Vec<_>>();
+        for (old, node) in nodes {
+            // sequential nodes get the next counter.
+            let path = match node.mode {
+                CreateMode::EphemeralSequential => strip_sequence(&old),
+                _ => &old,
+            };
+            let created = create_path_tracked(
+                client.clone(),
+                path,
+                node.data.clone(),
+                node.mode,
+                node.acl.clone(),
+                &persistent_exist_node_path,
+                &mut Vec::new(),
+            );
+            match created {
+                // unless deregistered meanwhile.
+                Ok(path) => {
+                    let mut registered = registered.lock().unwrap();
+                    if let Some(node) = registered.remove(&old) {
+                        registered.insert(path, node);
+                    }
+                }
+                Err(e) => error!("failed to register {} again. {}", old, e),
+            }
+        }
+        true
+    }
+}
+
+impl Zk<DefaultEncoder, DefaultDecoder> {
+    /// Connects to `connect_string`, see `ZkBuilder` for the options.
+    pub fn builder(connect_string: &str) -> ZkBuilder<DefaultEncoder, DefaultDecoder> {
+        ZkBuilder::new(connect_string)
+    }
 }
 
-impl<EC, DC> Zk<EC, DC>
+impl<EC, DC, I> Zk<EC, DC, I> {
+    /// Sets how watchers decide that a changed node is a re-registration of an
+    /// instance they already reported rather than a new one.
+    pub fn with_identity<NI>(self, identity: NI) -> Zk<EC, DC, NI> {
+        Zk {
+            client: self.client,
+            codec: self.codec,
+            root_prefix: self.root_prefix,
+            persistent_exist_node_path: self.persistent_exist_node_path,
+            registered: self.registered,
+            identity: Arc::new(identity),
+            clock: self.clock,
+            layout: self.layout,
+            workers: self.workers,
+            resync_interval: self.resync_interval,
+            sequential: self.sequential,
+        }
+    }
+
+    /// Sets the clock timestamping the events of watchers.
+    pub fn with_clock<C>(mut self, clock: C) -> Self
     where
-        EC: Sync,
-        DC: Sync,
-{
-    pub fn new(
-        zk_urls: &str,
-        timeout: Duration,
-        codec: &'static Codec<EC, DC>,
-    ) -> impl Future<Output=Zk<EC, DC>> {
-        let zk_urls = zk_urls.to_string();
-
-        task::spawn_blocking(move || Zk {
-            client: Arc::new(ZooKeeper::connect(zk_urls.as_str(), timeout, |_| {}).unwrap()),
-            codec,
-            persistent_exist_node_path: Arc::new(RwLock::new(HashSet::default())),
-        })
-            .map(|zk| zk.unwrap())
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Sets where instances are registered, `AppidLayout` by default.
+    pub fn with_layout<L>(mut self, layout: L) -> Self
+    where
+        L: Layout + 'static,
+    {
+        self.layout = Arc::new(layout);
+        self
+    }
+
+    /// The path everything is registered and watched under, see
+    /// `ZkConfig::root_prefix`.
+    pub fn root_prefix(&self) -> &str {
+        &self.root_prefix
+    }
+
+    /// Registers `ins` with `acl` rather than the ACL of the config, e.g. so
+    /// that only its owner may deregister it.
+    pub fn register_with_acl(&self, ins: Arc<Instance>, acl: Vec<Acl>) -> RegFut
+    where
+        EC: Encoder + Send + Sync + 'static,
+        DC: Decoder + Send + Sync + 'static,
+    {
+        RegFut::with_acl(self, vec![ins], acl)
+    }
+
+    /// The connection states of the session from now on, e.g. to alert while
+    /// the registry is unreachable. An expired session is followed by a new
+    /// one, `Connected` once established.
+    pub fn connection_state(&self) -> impl Stream<Item = ConnectionState> {
+        self.client.connection_states()
     }
 }
 
 #[pin_project]
 pub struct RegFut {
     #[pin]
-    join_handle: JoinHandle<Result<(), ZkRegError>>,
+    rx: oneshot::Receiver<Result<(), ZkRegError>>,
 }
 
 impl RegFut {
-    pub fn new<EC>(
-        client: Arc<ZooKeeper>,
-        ins: Instance,
-        encoder: &'static EC,
-        dynamic: bool,
-        persistent_exist_node_path: Arc<RwLock<HashSet<String>>>,
+    pub(crate) fn new<EC, DC, I>(zk: &Zk<EC, DC, I>, ins: Arc<Instance>) -> Self
+    where
+        EC: Encoder + Send + Sync + 'static,
+        DC: Decoder + Send + Sync + 'static,
+    {
+        Self::with_acl(zk, vec![ins], zk.client.acl.clone())
+    }
+
+    // creates the nodes of `instances` in one go, see `with_acl`.
+    pub(crate) fn all<EC, DC, I>(zk: &Zk<EC, DC, I>, instances: Vec<Arc<Instance>>) -> Self
+    where
+        EC: Encoder + Send + Sync + 'static,
+        DC: Decoder + Send + Sync + 'static,
+    {
+        Self::with_acl(zk, instances, zk.client.acl.clone())
+    }
+
+    // creates the nodes of `instances` with `acl` on a single worker, their
+    // parents with the ACL of the config. The `zookeeper` client has no
+    // multi, so this isn't atomic: watchers may see the first nodes before
+    // one fails, and the nodes created are then deleted again, parents
+    // included, as are the ones a lost connection leaves unknown.
+    pub(crate) fn with_acl<EC, DC, I>(
+        zk: &Zk<EC, DC, I>,
+        instances: Vec<Arc<Instance>>,
+        acl: Vec<Acl>,
+    ) -> Self
+    where
+        EC: Encoder + Send + Sync + 'static,
+        DC: Decoder + Send + Sync + 'static,
+    {
+        let client = zk.client.clone();
+        let nodes = instances
+            .into_iter()
+            .map(|ins| {
+                let dynamic = ins
+                    .metadata
+                    .get("dynamic")
+                    .map(|v| v == "true")
+                    .unwrap_or(true);
+                let mode = match (dynamic, zk.sequential) {
+                    (false, _) => CreateMode::Persistent,
+                    (true, false) => CreateMode::Ephemeral,
+                    (true, true) => CreateMode::EphemeralSequential,
+                };
+                (zk.dir(&ins), zk.node_name(&ins), mode, ins)
+            })
+            .collect::<Vec<_>>();
+        let codec = zk.codec.clone();
+        let persistent_exist_node_path = zk.persistent_exist_node_path.clone();
+        let registered = zk.registered.clone();
+        RegFut {
+            rx: zk.workers.run(move || {
+                let encoder = codec.get_encoder_ref();
+                let (mut created, mut batch) = (Vec::new(), Vec::new());
+                let create_all = || -> Result<(), ZkRegError> {
+                    for (dir, name, mode, ins) in nodes {
+                        // the instance is either the node name or its data.
+                        let (path, data) = match name {
+                            Some(name) => (
+                                dir + "/" + name.as_str(),
+                                encoder
+                                    .encode(&ins)
+                                    .map_err(|e| -> EncodeError { e.into() })?,
+                            ),
+                            None => (name_path(encoder, &ins, dir)?, Vec::new()),
+                        };
+                        let path = create_path_tracked(
+                            client.clone(),
+                            &path,
+                            data.clone(),
+                            mode,
+                            acl.clone(),
+                            &persistent_exist_node_path,
+                            &mut created,
+                        )?;
+                        let node = Node {
+                            data,
+                            mode,
+                            acl: acl.clone(),
+                            ins,
+                        };
+                        batch.push((path, node));
+                    }
+                    Ok(())
+                };
+                if let Err(e) = create_all() {
+                    roll_back(&client, &created, &persistent_exist_node_path);
+                    return Err(e);
+                }
+                registered.lock().unwrap().extend(batch);
+                Ok(())
+            }),
+        }
+    }
+}
+
+impl RegFut {
+    // sets the data of the node of `ins`, at `expected_version`. The node is
+    // looked up by identity when named after what changed, e.g. with
+    // `SpringCloudLayout`.
+    pub(crate) fn update_if<EC, DC, I>(
+        zk: &Zk<EC, DC, I>,
+        ins: Arc<Instance>,
+        expected_version: u64,
     ) -> Self
-        where
-            EC: Encoder + Sync + 'static,
+    where
+        EC: Encoder + Send + Sync + 'static,
+        DC: Decoder + Send + Sync + 'static,
+        I: Identity + Send + Sync + 'static,
+        I::Key: Send,
     {
+        let client = zk.client.clone();
+        let dir = zk.dir(&ins);
+        let name = zk.node_name(&ins);
+        let codec = zk.codec.clone();
+        let identity = zk.identity.clone();
+        let registered = zk.registered.clone();
         RegFut {
-            join_handle: task::spawn_blocking(move || {
-                let last_path = String::from_utf8(
-                    encoder
-                        .encode(&ins)
-                        .map_err(|e| -> EncodeError { e.into() })?,
-                )
-                    .map_err(|e| EncodeError {})?;
-                create_path(
-                    client,
-                    &(ins.appid + "/" + last_path.as_str()),
-                    dynamic,
-                    persistent_exist_node_path,
-                )
+            rx: zk.workers.run(move || {
+                let encoder = codec.get_encoder_ref();
+                let decoder = codec.get_decoder_ref();
+                let name = name.ok_or(ZkRegError::Unsupported)?;
+                let data = encoder
+                    .encode(&ins)
+                    .map_err(|e| -> EncodeError { e.into() })?;
+                // zk versions are i32, no node is at a later one.
+                let version =
+                    Some(i32::try_from(expected_version).map_err(|_| ZkRegError::Conflict)?);
+                let set_data = |path: &str| {
+                    client
+                        .retry
+                        .run(|| client.set_data(path, data.clone(), version))
+                };
+                let mut path = dir.clone() + "/" + name.as_str();
+                let mut set = set_data(&path);
+                if let Err(ZkError::NoNode) = set {
+                    if let Some(found) = find_node(&client, &dir, decoder, |exist| {
+                        identity.identify(exist) == identity.identify(&ins)
+                    }) {
+                        set = set_data(&found);
+                        path = found;
+                    }
+                }
+                match set {
+                    Ok(_) => {
+                        // so that a new session registers the update again.
+                        let mut registered = registered.lock().unwrap();
+                        if let Some(node) = registered.get_mut(&path) {
+                            node.data = data;
+                            node.ins = ins;
+                        }
+                        Ok(())
+                    }
+                    // updated or deregistered since.
+                    Err(ZkError::BadVersion) | Err(ZkError::NoNode) => Err(ZkRegError::Conflict),
+                    Err(e) => Err(ZkRegError::SetData(e)),
+                }
             }),
         }
     }
 }
 
+// the path of the first child of `dir` holding an instance `matches`.
+fn find_node<DC, F>(client: &ZkClient, dir: &str, decoder: &DC, matches: F) -> Option<String>
+where
+    DC: Decoder,
+    F: Fn(&Instance) -> bool,
+{
+    let children = client.retry.run(|| client.get_children(dir, false)).ok()?;
+    children
+        .into_iter()
+        .map(|child| format!("{}/{}", dir, child))
+        .find(|path| match client.get_data(path, false) {
+            Ok((data, _)) => decoder.decode(&data).is_ok_and(|ins| matches(&ins)),
+            Err(_) => false,
+        })
+}
+
+// `dir` joined with `ins` encoded as a node name, encoded right into the path.
+fn name_path<EC>(encoder: &EC, ins: &Instance, dir: String) -> Result<String, ZkRegError>
+where
+    EC: Encoder,
+{
+    let mut path = dir.into_bytes();
+    path.push(b'/');
+    encoder
+        .encode_to(ins, &mut path)
+        .map_err(|e| -> EncodeError { e.into() })?;
+    Ok(String::from_utf8(path).map_err(EncodeError::new)?)
+}
+
 fn create_path(
-    client: Arc<ZooKeeper>,
+    client: Arc<ZkClient>,
     path: &str,
+    data: Vec<u8>,
     dynamic: bool,
-    persistent_exist_node_path: Arc<RwLock<HashSet<String>>>,
+    persistent_exist_node_path: &PathCache,
 ) -> Result<(), ZkRegError> {
+    let acl = client.acl.clone();
+    create_path_with_acl(client, path, data, dynamic, acl, persistent_exist_node_path)
+}
+
+fn create_path_with_acl(
+    client: Arc<ZkClient>,
+    path: &str,
+    data: Vec<u8>,
+    dynamic: bool,
+    acl: Vec<Acl>,
+    persistent_exist_node_path: &PathCache,
+) -> Result<(), ZkRegError> {
+    let mode = if dynamic {
+        CreateMode::Ephemeral
+    } else {
+        CreateMode::Persistent
+    };
+    let mut created = Vec::new();
+    create_path_tracked(
+        client,
+        path,
+        data,
+        mode,
+        acl,
+        persistent_exist_node_path,
+        &mut created,
+    )?;
+    Ok(())
+}
+
+// like `create_path_with_acl` with `mode`, pushing the nodes it creates,
+// parents first, to `created`. The path of the node is returned, with the
+// counter of sequential ones.
+fn create_path_tracked(
+    client: Arc<ZkClient>,
+    path: &str,
+    data: Vec<u8>,
+    mode: CreateMode,
+    acl: Vec<Acl>,
+    persistent_exist_node_path: &PathCache,
+    created: &mut Vec<String>,
+) -> Result<String, ZkRegError> {
+    let dynamic = mode != CreateMode::Persistent;
     if !dynamic {
-        if persistent_exist_node_path.read().unwrap().contains(path) {
-            return Ok(());
+        if persistent_exist_node_path.contains(path) {
+            return Ok(path.to_owned());
         }
         if client
-            .exists(path, false)
-            .map_err(|e| ZkRegError::CreatePath(e))?
+            .retry
+            .run(|| client.exists(path, false))
+            .map_err(ZkRegError::CreatePath)?
             .is_some()
         {
-            persistent_exist_node_path
-                .write()
-                .unwrap()
-                .insert(path.to_owned());
+            persistent_exist_node_path.insert(path);
+            return Ok(path.to_owned());
         }
     }
 
     if let Some(pos) = path.rfind('/') {
         if pos > 0 {
-            create_path(
+            create_path_tracked(
                 client.clone(),
                 &path[..pos],
-                false,
-                persistent_exist_node_path.clone(),
+                Vec::new(),
+                CreateMode::Persistent,
+                client.acl.clone(),
+                persistent_exist_node_path,
+                created,
             )?;
         }
     }
 
-    client
-        .create(
-            path,
-            Vec::new(),
-            Acl::open_unsafe().clone(),
-            if dynamic {
-                CreateMode::Ephemeral
-            } else {
-                CreateMode::Persistent
-            },
-        )
-        .map_err(|e| ZkRegError::CreatePath(e))?;
-    persistent_exist_node_path
-        .write()
-        .unwrap()
-        .insert(path.to_owned());
-    Ok(())
+    // whether a create lost its connection, the node maybe created anyway.
+    let mut lost = false;
+    let created_path = client.retry.run(|| {
+        let create = client.create(path, data.clone(), acl.clone(), mode);
+        lost |= matches!(
+            create,
+            Err(ZkError::ConnectionLoss) | Err(ZkError::OperationTimeout)
+        );
+        create
+    });
+    let path = match created_path {
+        Ok(path) => {
+            created.push(path.clone());
+            path
+        }
+        // created meanwhile, e.g. by another process registering in the same app.
+        Err(ZkError::NodeExists) if !dynamic => path.to_owned(),
+        Err(e) => {
+            // rolled back too, the counter of a sequential one is unknown.
+            if lost && mode != CreateMode::EphemeralSequential {
+                created.push(path.to_owned());
+            } else if lost {
+                error!(
+                    "{} may be left created, with a counter, until the session ends",
+                    path
+                );
+            }
+            return Err(ZkRegError::CreatePath(e));
+        }
+    };
+    // ephemeral nodes go away with the session, only persistent ones are kept.
+    if !dynamic {
+        persistent_exist_node_path.insert(&path);
+    }
+    Ok(path)
+}
+
+// the digits ZooKeeper appends to the name of sequential nodes.
+const SEQUENCE_DIGITS: usize = 10;
+
+// `name` without the counter of a sequential node, see `ZkConfig::sequential`.
+fn strip_sequence(name: &str) -> &str {
+    let split = name.len().saturating_sub(SEQUENCE_DIGITS);
+    match name.get(split..) {
+        Some(counter) if split > 0 && counter.bytes().all(|b| b.is_ascii_digit()) => &name[..split],
+        _ => name,
+    }
+}
+
+// deletes the nodes of a failed batch, `created` in the order they were,
+// leaving the parents others created nodes under meanwhile. Ephemeral nodes
+// failing to be deleted go away with the session.
+fn roll_back(client: &ZkClient, created: &[String], persistent_exist_node_path: &PathCache) {
+    for path in created.iter().rev() {
+        match client.retry.run(|| client.delete(path, None)) {
+            Ok(()) | Err(ZkError::NoNode) => persistent_exist_node_path.remove(path),
+            Err(ZkError::NotEmpty) => {}
+            Err(e) => error!("failed to delete {} rolling back. {}", path, e),
+        }
+    }
+}
+
+impl RegFut {
+    // resolves right away.
+    fn ok() -> Self {
+        let (tx, rx) = oneshot::channel();
+        let _ = tx.send(Ok(()));
+        RegFut { rx }
+    }
 }
 
 impl Future for RegFut {
     type Output = Result<(), ZkRegError>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        Poll::Ready(match ready!(self.project().join_handle.poll(cx)) {
+        Poll::Ready(match ready!(self.project().rx.poll(cx)) {
             Ok(out) => out,
-            Err(e) => Err(ZkRegError::Join(e)),
+            Err(_) => Err(ZkRegError::Canceled),
         })
     }
 }
 
 #[derive(Debug)]
 pub enum ZkRegError {
-    Encode,
-    Decode,
+    Encode(EncodeError),
+    Decode(DecodeError),
     CreatePath(ZkError),
     DeletePath(ZkError),
-    Join(JoinError),
+    SetData(ZkError),
+    GetChildren(ZkError),
+    /// The node was updated or deleted since the expected version, see
+    /// `Registry::update_if`.
+    Conflict,
+    /// The layout names nodes after the whole instance, they can't be
+    /// updated in place. So does `AppidLayout`, the default.
+    Unsupported,
+    /// The worker thread making the call panicked.
+    Canceled,
 }
 
-impl std::error::Error for ZkRegError {}
+impl std::error::Error for ZkRegError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ZkRegError::Encode(e) => Some(e),
+            ZkRegError::Decode(e) => Some(e),
+            ZkRegError::CreatePath(e)
+            | ZkRegError::DeletePath(e)
+            | ZkRegError::SetData(e)
+            | ZkRegError::GetChildren(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for ZkRegError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            ZkRegError::Encode(e) => e.fmt(f),
+            ZkRegError::Decode(e) => e.fmt(f),
+            ZkRegError::CreatePath(e) => write!(f, "failed to create the zk node: {}", e),
+            ZkRegError::DeletePath(e) => write!(f, "failed to delete the zk node: {}", e),
+            ZkRegError::SetData(e) => write!(f, "failed to set the zk node data: {}", e),
+            ZkRegError::GetChildren(e) => write!(f, "failed to list the zk nodes: {}", e),
+            ZkRegError::Conflict => write!(f, "the instance was updated since"),
+            ZkRegError::Unsupported => write!(f, "unsupported by the zk layout"),
+            ZkRegError::Canceled => write!(f, "the zk worker panicked"),
+        }
     }
 }
 
 impl From<EncodeError> for ZkRegError {
     fn from(e: EncodeError) -> Self {
-        todo!()
+        ZkRegError::Encode(e)
     }
 }
 
-impl From<DecodeErorr> for ZkRegError {
-    fn from(e: DecodeErorr) -> Self {
-        todo!()
+impl From<DecodeError> for ZkRegError {
+    fn from(e: DecodeError) -> Self {
+        ZkRegError::Decode(e)
+    }
+}
+
+impl From<ZkRegError> for DiscoverError {
+    fn from(e: ZkRegError) -> Self {
+        match e {
+            ZkRegError::Decode(e) => DiscoverError::Decode(e),
+            e => DiscoverError::registry(e),
+        }
     }
 }
 
 #[pin_project]
 pub struct DeRegFut {
     #[pin]
-    join_handle: JoinHandle<Result<(), ZkRegError>>,
+    rx: oneshot::Receiver<Result<(), ZkRegError>>,
 }
 
 impl DeRegFut {
-    pub fn new<EC>(
-        client: Arc<ZooKeeper>,
-        ins: &Instance,
-        encoder: &'static EC,
-        persistent_exist_node_path: Arc<RwLock<HashSet<String>>>,
-    ) -> Self
-        where
-            EC: Encoder + Sync + 'static,
+    pub(crate) fn new<EC, DC, I>(zk: &Zk<EC, DC, I>, ins: &Arc<Instance>) -> Self
+    where
+        EC: Encoder + Send + Sync + 'static,
+        DC: Decoder + Send + Sync + 'static,
+        I: Identity,
+    {
+        Self::all(zk, slice::from_ref(ins))
+    }
+
+    // deletes the nodes of `instances` on a single worker, all of them even
+    // when some fail, failing with the first failure. A node stays registered
+    // until deleted, so that one failing to be, or whose future is dropped
+    // first, is still registered again in a new session.
+    pub(crate) fn all<EC, DC, I>(zk: &Zk<EC, DC, I>, instances: &[Arc<Instance>]) -> Self
+    where
+        EC: Encoder + Send + Sync + 'static,
+        DC: Decoder + Send + Sync + 'static,
+        I: Identity,
+    {
+        // each instance takes another node when registered more than once.
+        let mut left = zk.registered.lock().unwrap().clone();
+        let nodes = instances
+            .iter()
+            .map(|ins| {
+                let path = zk
+                    .registered_key(&left, ins)
+                    .filter(|path| left.remove(path).is_some());
+                (path, zk.dir(ins), zk.node_name(ins), ins.clone())
+            })
+            .collect::<Vec<_>>();
+        let client = zk.client.clone();
+        let registered = zk.registered.clone();
+        let codec = zk.codec.clone();
+        let persistent_exist_node_path = zk.persistent_exist_node_path.clone();
+        DeRegFut {
+            rx: zk.workers.run(move || {
+                let encoder = codec.get_encoder_ref();
+                let mut result = Ok(());
+                for (known, dir, name, ins) in nodes {
+                    let deleted = match (known.clone(), name) {
+                        (Some(path), _) => Ok(path),
+                        (None, Some(name)) => Ok(dir + "/" + name.as_str()),
+                        (None, None) => name_path(encoder, &ins, dir),
+                    }
+                    .and_then(|path| {
+                        persistent_exist_node_path.remove(&path);
+                        let deleted = client.retry.run(|| client.delete(path.as_str(), None));
+                        // deleted, or gone already with an expired session
+                        // and not to be registered again either.
+                        if let (Some(known), Ok(()) | Err(ZkError::NoNode)) = (&known, &deleted) {
+                            registered.lock().unwrap().remove(known);
+                        }
+                        deleted.map_err(ZkRegError::DeletePath)
+                    });
+                    if result.is_ok() {
+                        result = deleted;
+                    }
+                }
+                result
+            }),
+        }
+    }
+}
+
+impl DeRegFut {
+    // deletes the nodes of `appid` decoding to an instance `instance_id` names.
+    pub(crate) fn evict<EC, DC, I>(zk: &Zk<EC, DC, I>, appid: &str, instance_id: &str) -> Self
+    where
+        EC: Encoder + Send + Sync + 'static,
+        DC: Decoder + Send + Sync + 'static,
     {
-        let ins = ins.clone();
+        let client = zk.client.clone();
+        let dir = zk.root_prefix.clone() + &zk.layout.watch_dir(appid);
+        let codec = zk.codec.clone();
+        let data_payload = zk.layout.payload() == Payload::Data;
+        let instance_id = instance_id.to_owned();
         DeRegFut {
-            join_handle: task::spawn_blocking(move || {
-                let last_path = String::from_utf8(
-                    encoder
-                        .encode(&ins)
-                        .map_err(|e| -> EncodeError { e.into() })?,
-                )
-                    .map_err(|e| EncodeError {})?;
-                let path = ins.appid + "/" + last_path.as_str();
-                persistent_exist_node_path
-                    .write()
-                    .unwrap()
-                    .insert(path.clone());
-                client
-                    .delete(path.as_str(), None)
-                    .map_err(|e| ZkRegError::DeletePath(e))
+            rx: zk.workers.run(move || {
+                let decoder = codec.get_decoder_ref();
+                let children = match client.retry.run(|| client.get_children(&dir, false)) {
+                    Ok(children) => children,
+                    Err(ZkError::NoNode) => return Ok(()),
+                    Err(e) => return Err(ZkRegError::DeletePath(e)),
+                };
+                for child in children {
+                    let path = dir.clone() + "/" + child.as_str();
+                    let encoded = if data_payload {
+                        match client.get_data(&path, false) {
+                            Ok((data, _)) => data,
+                            Err(_) => continue,
+                        }
+                    } else {
+                        child.into_bytes()
+                    };
+                    match decoder.decode(&encoded) {
+                        Ok(ins) if ins.has_id(&instance_id) => {}
+                        _ => continue,
+                    }
+                    match client.retry.run(|| client.delete(&path, None)) {
+                        // deregistered meanwhile.
+                        Ok(()) | Err(ZkError::NoNode) => {}
+                        Err(e) => return Err(ZkRegError::DeletePath(e)),
+                    }
+                }
+                Ok(())
             }),
         }
     }
@@ -214,14 +750,131 @@ impl Future for DeRegFut {
     type Output = Result<(), ZkRegError>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        Poll::Ready(ready!(self.project().join_handle.poll(cx)).unwrap())
+        Poll::Ready(match ready!(self.project().rx.poll(cx)) {
+            Ok(out) => out,
+            Err(_) => Err(ZkRegError::Canceled),
+        })
     }
 }
 
-impl<EC, DC> Registry for Zk<EC, DC>
+#[pin_project]
+pub struct ListFut {
+    #[pin]
+    rx: oneshot::Receiver<Result<Vec<Arc<Instance>>, ZkRegError>>,
+}
+
+impl ListFut {
+    // the instances of the children of the watch directory of `appid`, as
+    // watchers report them.
+    pub(crate) fn new<EC, DC, I>(zk: &Zk<EC, DC, I>, appid: &str) -> Self
     where
-        EC: Encoder + Sync + 'static,
-        DC: Decoder + Sync + 'static,
+        EC: Encoder + Send + Sync + 'static,
+        DC: Decoder + Send + Sync + 'static,
+    {
+        let client = zk.client.clone();
+        let dir = zk.root_prefix.clone() + &zk.layout.watch_dir(appid);
+        let codec = zk.codec.clone();
+        let data_payload = zk.layout.payload() == Payload::Data;
+        let sequential = zk.sequential;
+        ListFut {
+            rx: zk.workers.run(move || {
+                let decoder = codec.get_decoder_ref();
+                let children = match client.retry.run(|| client.get_children(&dir, false)) {
+                    Ok(children) => children,
+                    Err(ZkError::NoNode) => return Ok(Vec::new()),
+                    Err(e) => return Err(ZkRegError::GetChildren(e)),
+                };
+                let mut instances = Vec::new();
+                for child in children {
+                    let path = dir.clone() + "/" + child.as_str();
+                    let (ins, stat) = if data_payload {
+                        match client.get_data(&path, false) {
+                            Ok((data, stat)) => (decode_instance(&data, decoder).ok(), Some(stat)),
+                            // deleted meanwhile.
+                            Err(_) => continue,
+                        }
+                    } else {
+                        let name = if sequential {
+                            strip_sequence(&child)
+                        } else {
+                            &child
+                        };
+                        (decode_instance(name.as_bytes(), decoder).ok(), None)
+                    };
+                    let mut ins = match ins {
+                        Some(ins) => ins,
+                        None => continue,
+                    };
+                    match stat {
+                        Some(stat) => fill_from_stat(&mut ins, &stat),
+                        None => fill_named(&mut ins),
+                    }
+                    instances.push(Arc::new(ins));
+                }
+                Ok(instances)
+            }),
+        }
+    }
+}
+
+impl Future for ListFut {
+    type Output = Result<Vec<Arc<Instance>>, ZkRegError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Poll::Ready(match ready!(self.project().rx.poll(cx)) {
+            Ok(out) => out,
+            Err(_) => Err(ZkRegError::Canceled),
+        })
+    }
+}
+
+impl<EC, DC, I> Zk<EC, DC, I> {
+    // the directory `ins` is registered in, under the root prefix.
+    fn dir(&self, ins: &Instance) -> String {
+        self.root_prefix.clone() + &self.layout.dir(ins)
+    }
+
+    // the node name of `ins` when not its encoding.
+    fn node_name(&self, ins: &Instance) -> Option<String> {
+        match self.layout.payload() {
+            Payload::Name => None,
+            Payload::Data => Some(self.layout.node_name(ins)),
+        }
+    }
+
+    // the path of the node registered for `ins`, or for an instance of the
+    // same directory it identifies as, e.g. one since given a revision or
+    // registered as a sequential node.
+    fn registered_key(&self, registered: &HashMap<String, Node>, ins: &Instance) -> Option<String>
+    where
+        I: Identity,
+    {
+        let dir = self.dir(ins);
+        if let Some(name) = self.node_name(ins) {
+            let key = dir.clone() + "/" + name.as_str();
+            if registered.contains_key(&key) {
+                return Some(key);
+            }
+        }
+        let key = self.identity.identify(ins);
+        registered
+            .iter()
+            .find(|(_, node)| *node.ins == *ins)
+            .or_else(|| {
+                registered.iter().find(|(_, node)| {
+                    self.dir(&node.ins) == dir && self.identity.identify(&node.ins) == key
+                })
+            })
+            .map(|(path, _)| path.clone())
+    }
+}
+
+impl<EC, DC, I> Registry for Zk<EC, DC, I>
+where
+    EC: Encoder + Send + Sync + 'static,
+    DC: Decoder + Send + Sync + 'static,
+    I: Identity + Send + Sync + 'static,
+    I::Key: Send,
 {
     type Error = ZkRegError;
 
@@ -229,33 +882,62 @@ impl<EC, DC> Registry for Zk<EC, DC>
 
     type DeRegFuture = DeRegFut;
 
+    type ListFuture = ListFut;
+
     type Watcher = ZkWatcher;
 
-    fn register(&self, ins: Instance) -> Self::RegFuture {
-        let dynamic = ins
-            .metadata
-            .get("dynamic")
-            .map(|v| v == "true")
-            .unwrap_or(true);
-        RegFut::new(
-            self.client.clone(),
-            ins,
-            self.codec.get_encoder_ref(),
-            dynamic,
-            self.persistent_exist_node_path.clone(),
-        )
-    }
-
-    fn deregister(&self, ins: &Instance) -> Self::DeRegFuture {
-        DeRegFut::new(
-            self.client.clone(),
-            ins,
-            self.codec.get_encoder_ref(),
-            self.persistent_exist_node_path.clone(),
-        )
-    }
-
-    fn watch(&self, appid: &'static str) -> Self::Watcher {
-        ZkWatcher::new(self.client.clone(), appid, self.codec.get_decoder_ref())
+    fn register(&self, ins: Arc<Instance>) -> Self::RegFuture {
+        RegFut::new(self, ins)
+    }
+
+    fn deregister(&self, ins: &Arc<Instance>) -> Self::DeRegFuture {
+        DeRegFut::new(self, ins)
+    }
+
+    // on a single worker rather than one job per instance, rolled back when
+    // one fails but not atomically, see `RegFut::with_acl`.
+    fn register_all(
+        &self,
+        instances: Vec<Arc<Instance>>,
+    ) -> BoxFuture<'_, Result<(), Self::Error>> {
+        Box::pin(RegFut::all(self, instances))
+    }
+
+    fn deregister_all(
+        &self,
+        instances: &[Arc<Instance>],
+    ) -> BoxFuture<'_, Result<(), Self::Error>> {
+        Box::pin(DeRegFut::all(self, instances))
+    }
+
+    // ZooKeeper doesn't tell why a node went away, so watchers report
+    // evictions as deletes without a reason.
+    fn evict(&self, appid: &str, instance_id: &str) -> Self::DeRegFuture {
+        DeRegFut::evict(self, appid, instance_id)
+    }
+
+    // fails with `Unsupported` on layouts putting instances in node names,
+    // `AppidLayout` by default.
+    fn update_if(&self, ins: Arc<Instance>, expected_version: u64) -> Self::RegFuture {
+        RegFut::update_if(self, ins, expected_version)
+    }
+
+    // ephemeral nodes last as long as the session, which the client keeps
+    // alive and whose expiry registers them again.
+    fn renew(&self, _ins: &Arc<Instance>) -> Self::RegFuture {
+        RegFut::ok()
+    }
+
+    fn list(&self, appid: &str) -> Self::ListFuture {
+        ListFut::new(self, appid)
+    }
+
+    fn watch(&self, appid: &str) -> Self::Watcher {
+        self.watch_from(appid, &[])
+    }
+
+    fn watch_from(&self, appid: &str, instances: &[Arc<Instance>]) -> Self::Watcher {
+        let path = self.root_prefix.clone() + &self.layout.watch_dir(appid);
+        ZkWatcher::new(self, path, instances)
     }
 }
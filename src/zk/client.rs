@@ -0,0 +1,228 @@
+use super::{config::ZkConfig, RetryPolicy};
+use crate::hooks::{ConnectionState, Hooks};
+use futures::channel::mpsc::{self as channel, UnboundedReceiver, UnboundedSender};
+use log::{error, warn};
+use std::{
+    sync::{mpsc, Arc, Mutex, RwLock, Weak},
+    thread,
+};
+use zookeeper::{
+    Acl, CreateMode, KeeperState, Stat, WatchedEvent, Watcher, ZkError, ZkResult, ZooKeeper,
+};
+
+/// A session, with how nodes are created and calls retried in it. Once the
+/// session expires, a new one is established and the hooks added with
+/// `on_session` run in it.
+pub(crate) struct ZkClient {
+    // replaced once expired.
+    zk: RwLock<Arc<ZooKeeper>>,
+    pub(crate) acl: Vec<Acl>,
+    pub(crate) retry: RetryPolicy,
+    listeners: Listeners,
+    sessions: Mutex<Vec<SessionHook>>,
+    // what the session connects through, closed along with the client.
+    #[cfg(feature = "zk-tls")]
+    tunnel: Mutex<Option<super::tls::Tunnel>>,
+}
+
+// what follows the connection state of the session inside the crate.
+type Listeners = Arc<Mutex<Vec<UnboundedSender<ConnectionState>>>>;
+
+// run in every new session, kept while it returns true.
+type SessionHook = Box<dyn FnMut(&Arc<ZkClient>) -> bool + Send>;
+
+impl ZkClient {
+    pub(crate) fn connect(config: &ZkConfig, hooks: &Hooks) -> ZkResult<Arc<Self>> {
+        let listeners = Listeners::default();
+        let (zk, expired) = session(config, hooks, &listeners)?;
+        let client = Arc::new(ZkClient {
+            zk: RwLock::new(Arc::new(zk)),
+            acl: config.acl.clone(),
+            retry: config.retry,
+            listeners: listeners.clone(),
+            sessions: Mutex::new(Vec::new()),
+            #[cfg(feature = "zk-tls")]
+            tunnel: Mutex::new(None),
+        });
+        let (renewing, config, hooks) = (Arc::downgrade(&client), config.clone(), hooks.clone());
+        thread::Builder::new()
+            .name("zk-session".to_owned())
+            .spawn(move || renew(renewing, expired, &config, &hooks, &listeners))
+            .expect("failed to spawn the zookeeper session thread");
+        Ok(client)
+    }
+
+    /// The connection states of the session from now on.
+    pub(crate) fn connection_states(&self) -> UnboundedReceiver<ConnectionState> {
+        let (tx, rx) = channel::unbounded();
+        self.listeners.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Runs `hook` in every session established from now on, as long as it
+    /// returns true, e.g. to create ephemeral nodes again.
+    pub(crate) fn on_session<F>(&self, hook: F)
+    where
+        F: FnMut(&Arc<ZkClient>) -> bool + Send + 'static,
+    {
+        self.sessions.lock().unwrap().push(Box::new(hook));
+    }
+
+    /// Keeps `tunnel` open as long as the client.
+    #[cfg(feature = "zk-tls")]
+    pub(crate) fn keep(&self, tunnel: super::tls::Tunnel) {
+        *self.tunnel.lock().unwrap() = Some(tunnel);
+    }
+
+    fn zk(&self) -> Arc<ZooKeeper> {
+        self.zk.read().unwrap().clone()
+    }
+
+    pub(crate) fn create(
+        &self,
+        path: &str,
+        data: Vec<u8>,
+        acl: Vec<Acl>,
+        mode: CreateMode,
+    ) -> ZkResult<String> {
+        self.zk().create(path, data, acl, mode)
+    }
+
+    pub(crate) fn delete(&self, path: &str, version: Option<i32>) -> ZkResult<()> {
+        self.zk().delete(path, version)
+    }
+
+    pub(crate) fn exists(&self, path: &str, watch: bool) -> ZkResult<Option<Stat>> {
+        self.zk().exists(path, watch)
+    }
+
+    pub(crate) fn exists_w<W>(&self, path: &str, watcher: W) -> ZkResult<Option<Stat>>
+    where
+        W: Watcher + 'static,
+    {
+        self.zk().exists_w(path, watcher)
+    }
+
+    pub(crate) fn get_children(&self, path: &str, watch: bool) -> ZkResult<Vec<String>> {
+        self.zk().get_children(path, watch)
+    }
+
+    pub(crate) fn get_children_w<W>(&self, path: &str, watcher: W) -> ZkResult<Vec<String>>
+    where
+        W: Watcher + 'static,
+    {
+        self.zk().get_children_w(path, watcher)
+    }
+
+    pub(crate) fn get_data(&self, path: &str, watch: bool) -> ZkResult<(Vec<u8>, Stat)> {
+        self.zk().get_data(path, watch)
+    }
+
+    pub(crate) fn get_data_w<W>(&self, path: &str, watcher: W) -> ZkResult<(Vec<u8>, Stat)>
+    where
+        W: Watcher + 'static,
+    {
+        self.zk().get_data_w(path, watcher)
+    }
+
+    pub(crate) fn set_data(
+        &self,
+        path: &str,
+        data: Vec<u8>,
+        version: Option<i32>,
+    ) -> ZkResult<Stat> {
+        self.zk().set_data(path, data, version)
+    }
+}
+
+// establishes sessions in place of the expired ones, until the client is
+// dropped.
+fn renew(
+    client: Weak<ZkClient>,
+    mut expired: mpsc::Receiver<()>,
+    config: &ZkConfig,
+    hooks: &Hooks,
+    listeners: &Listeners,
+) {
+    // fails once the session, and its sender, are dropped with the client.
+    while expired.recv().is_ok() {
+        warn!("the zookeeper session expired, establishing a new one");
+        let (zk, next) = loop {
+            if client.strong_count() == 0 {
+                return;
+            }
+            match session(config, hooks, listeners) {
+                Ok(session) => break session,
+                Err(e) => {
+                    error!("failed to establish a zookeeper session. {}", e);
+                    thread::sleep(config.connect_timeout);
+                }
+            }
+        };
+        let client = match client.upgrade() {
+            Some(client) => client,
+            None => return,
+        };
+        let old = std::mem::replace(&mut *client.zk.write().unwrap(), Arc::new(zk));
+        let _ = old.close();
+        expired = next;
+        // hooks added meanwhile run from the next session on.
+        let mut sessions = std::mem::take(&mut *client.sessions.lock().unwrap());
+        sessions.retain_mut(|hook| hook(&client));
+        client.sessions.lock().unwrap().append(&mut sessions);
+    }
+}
+
+// a session with the credentials added, and what tells it expired.
+fn session(
+    config: &ZkConfig,
+    hooks: &Hooks,
+    listeners: &Listeners,
+) -> ZkResult<(ZooKeeper, mpsc::Receiver<()>)> {
+    let (expired_tx, expired) = mpsc::channel();
+    let zk = config
+        .retry
+        .run(|| connect(config, hooks, listeners, expired_tx.clone()))?;
+    for (scheme, auth) in &config.auth {
+        zk.add_auth(scheme, auth.clone())?;
+    }
+    Ok((zk, expired))
+}
+
+// waits for the session to be established.
+fn connect(
+    config: &ZkConfig,
+    hooks: &Hooks,
+    listeners: &Listeners,
+    expired: mpsc::Sender<()>,
+) -> ZkResult<ZooKeeper> {
+    let (tx, rx) = mpsc::channel();
+    let hooks = hooks.clone();
+    let listeners = listeners.clone();
+    let zk = ZooKeeper::connect(
+        &config.connect_string,
+        config.session_timeout,
+        move |event: WatchedEvent| {
+            let state = match event.keeper_state {
+                KeeperState::SyncConnected => {
+                    let _ = tx.send(());
+                    ConnectionState::Connected
+                }
+                KeeperState::Disconnected => ConnectionState::Disconnected,
+                KeeperState::Expired => {
+                    let _ = expired.send(());
+                    ConnectionState::Expired
+                }
+                _ => return,
+            };
+            hooks.connection_changed(state);
+            let mut listeners = listeners.lock().unwrap();
+            listeners.retain(|listener| listener.unbounded_send(state).is_ok());
+        },
+    )?;
+    if rx.recv_timeout(config.connect_timeout).is_err() {
+        let _ = zk.close();
+        return Err(ZkError::OperationTimeout);
+    }
+    Ok(zk)
+}
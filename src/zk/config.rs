@@ -0,0 +1,364 @@
+#[cfg(feature = "zk-tls")]
+use super::ZkTls;
+use super::{
+    client::ZkClient, path_cache::PathCache, reregister, worker::Workers, AppidLayout, Zk,
+};
+use crate::{
+    codec::{new_default_codec, Codec, DefaultDecoder, DefaultEncoder},
+    hooks::Hooks,
+    identity::DefaultIdentity,
+    watcher::SystemClock,
+};
+use futures::Future;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use zookeeper::{Acl, Permission, ZkError, ZkResult};
+
+/// How to connect to ZooKeeper and create nodes, see `ZkBuilder`.
+#[derive(Debug, Clone)]
+pub struct ZkConfig {
+    /// The servers, e.g. `10.0.0.1:2181,10.0.0.2:2181`.
+    pub connect_string: String,
+    /// Once the session expires, a new one is established where the
+    /// instances registered are registered again and watches are set again.
+    pub session_timeout: Duration,
+    /// How long to wait for the session to be established.
+    pub connect_timeout: Duration,
+    /// The credentials added to the session, as `(scheme, auth)` pairs such
+    /// as `("digest", b"user:password")`.
+    pub auth: Vec<(String, Vec<u8>)>,
+    /// The ACL of the nodes created, open to everyone by default.
+    pub acl: Vec<Acl>,
+    /// Prepended to every path registered or watched, e.g. `/discovery/prod`,
+    /// so that environments share an ensemble without the appids telling
+    /// them apart.
+    pub root_prefix: String,
+    pub retry: RetryPolicy,
+    /// How many threads make the blocking ZooKeeper calls. Calls beyond that
+    /// wait for a thread to be free.
+    pub worker_threads: usize,
+    /// How often watchers list the children again, reporting the changes
+    /// missed between a notification and the next watch. Never when none.
+    pub resync_interval: Option<Duration>,
+    /// Registers ephemeral nodes as sequential ones, ZooKeeper appending a
+    /// counter to their name, so that the same instance may be registered
+    /// more than once, e.g. by processes sharing an address, without
+    /// failing with `NodeExists`. Watchers strip the counter off.
+    pub sequential: bool,
+    /// Connects to the servers over TLS with these certificates, see
+    /// `ZkTls`.
+    #[cfg(feature = "zk-tls")]
+    pub tls: Option<ZkTls>,
+}
+
+impl Default for ZkConfig {
+    fn default() -> Self {
+        ZkConfig {
+            connect_string: "127.0.0.1:2181".to_owned(),
+            session_timeout: Duration::from_secs(3),
+            connect_timeout: Duration::from_secs(3),
+            auth: Vec::new(),
+            // what `Acl::open_unsafe` returns.
+            acl: vec![Acl::new(Permission::ALL, "world", "anyone")],
+            root_prefix: String::new(),
+            retry: RetryPolicy::default(),
+            worker_threads: 2,
+            resync_interval: None,
+            sequential: false,
+            #[cfg(feature = "zk-tls")]
+            tls: None,
+        }
+    }
+}
+
+/// How calls failing with a lost connection or a timeout are retried, the
+/// connection included. The backoff doubles after every retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Fails on the first error.
+    pub fn never() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            backoff: Duration::default(),
+        }
+    }
+
+    // blocks the calling thread while backing off, only call it on workers.
+    pub(crate) fn run<T, F>(&self, mut call: F) -> ZkResult<T>
+    where
+        F: FnMut() -> ZkResult<T>,
+    {
+        let mut backoff = self.backoff;
+        for _ in 0..self.max_retries {
+            match call() {
+                Err(ZkError::ConnectionLoss) | Err(ZkError::OperationTimeout) => {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                done => return done,
+            }
+        }
+        call()
+    }
+}
+
+/// Builds a `Zk`.
+///
+/// ```ignore
+/// let zk = Zk::builder("10.0.0.1:2181,10.0.0.2:2181")
+///     .session_timeout(Duration::from_secs(10))
+///     .auth("digest", "user:password")
+///     .root_prefix("/discovery/prod")
+///     .codec(new_dubbo_codec())
+///     .build()
+///     .await?
+///     .with_layout(DubboLayout::new());
+/// ```
+pub struct ZkBuilder<EC, DC> {
+    config: ZkConfig,
+    codec: Arc<Codec<EC, DC>>,
+    hooks: Hooks,
+}
+
+impl ZkBuilder<DefaultEncoder, DefaultDecoder> {
+    /// Connects to `connect_string`, with the default codec.
+    pub fn new(connect_string: &str) -> Self {
+        ZkBuilder::from_config(ZkConfig {
+            connect_string: connect_string.to_owned(),
+            ..Default::default()
+        })
+    }
+
+    pub fn from_config(config: ZkConfig) -> Self {
+        ZkBuilder {
+            config,
+            codec: Arc::new(new_default_codec()),
+            hooks: Hooks::new(),
+        }
+    }
+}
+
+impl<EC, DC> ZkBuilder<EC, DC> {
+    pub fn session_timeout(mut self, timeout: Duration) -> Self {
+        self.config.session_timeout = timeout;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = timeout;
+        self
+    }
+
+    /// Adds credentials to the session, e.g. `auth("digest", "user:password")`.
+    pub fn auth(mut self, scheme: &str, auth: impl Into<Vec<u8>>) -> Self {
+        self.config.auth.push((scheme.to_owned(), auth.into()));
+        self
+    }
+
+    /// Sets the ACL of the nodes created.
+    pub fn acl(mut self, acl: Vec<Acl>) -> Self {
+        self.config.acl = acl;
+        self
+    }
+
+    pub fn root_prefix(mut self, prefix: &str) -> Self {
+        self.config.root_prefix = prefix.to_owned();
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.config.retry = retry;
+        self
+    }
+
+    pub fn worker_threads(mut self, threads: usize) -> Self {
+        self.config.worker_threads = threads;
+        self
+    }
+
+    pub fn resync_interval(mut self, interval: Duration) -> Self {
+        self.config.resync_interval = Some(interval);
+        self
+    }
+
+    /// Registers instances as sequential nodes, see `ZkConfig::sequential`.
+    pub fn sequential(mut self, sequential: bool) -> Self {
+        self.config.sequential = sequential;
+        self
+    }
+
+    /// Connects to the servers over TLS, e.g. to ensembles only listening on
+    /// their `secureClientPort`.
+    #[cfg(feature = "zk-tls")]
+    pub fn tls(mut self, tls: ZkTls) -> Self {
+        self.config.tls = Some(tls);
+        self
+    }
+
+    /// Sets how instances are encoded, `new_default_codec()` by default, e.g.
+    /// a codec built from the config at runtime.
+    pub fn codec<NEC, NDC>(self, codec: impl Into<Arc<Codec<NEC, NDC>>>) -> ZkBuilder<NEC, NDC> {
+        ZkBuilder {
+            config: self.config,
+            codec: codec.into(),
+            hooks: self.hooks,
+        }
+    }
+
+    /// Runs the connection changes of `hooks`, see `hooks::Hooked` for the
+    /// others.
+    pub fn hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Connects, failing with `ZkError::OperationTimeout` when no session is
+    /// established within the connect timeout, retries included.
+    pub fn build(self) -> impl Future<Output = ZkResult<Zk<EC, DC>>>
+    where
+        EC: Send + Sync,
+        DC: Send + Sync,
+    {
+        let ZkBuilder {
+            config,
+            codec,
+            hooks,
+        } = self;
+        async move {
+            #[cfg(feature = "zk-tls")]
+            let mut config = config;
+            #[cfg(feature = "zk-tls")]
+            let tunnel = match config.tls.take() {
+                Some(tls) => {
+                    let tunnel = super::tls::tunnel(&config.connect_string, &tls)?;
+                    config.connect_string = tunnel.connect_string().to_owned();
+                    Some(tunnel)
+                }
+                None => None,
+            };
+            let workers = Arc::new(Workers::new(config.worker_threads));
+            let root_prefix = root_prefix(&config.root_prefix);
+            let (resync_interval, sequential) = (config.resync_interval, config.sequential);
+            let client = workers
+                .run(move || ZkClient::connect(&config, &hooks))
+                .await
+                // the connecting worker panicked.
+                .unwrap_or(Err(ZkError::SystemError))?;
+            #[cfg(feature = "zk-tls")]
+            {
+                if let Some(tunnel) = tunnel {
+                    client.keep(tunnel);
+                }
+            }
+            let registered = Arc::new(Mutex::new(HashMap::new()));
+            let persistent_exist_node_path = Arc::new(PathCache::new());
+            client.on_session(reregister(&registered, &persistent_exist_node_path));
+            Ok(Zk {
+                client,
+                codec,
+                root_prefix,
+                persistent_exist_node_path,
+                registered,
+                identity: Arc::new(DefaultIdentity),
+                clock: Arc::new(SystemClock),
+                layout: Arc::new(AppidLayout),
+                workers,
+                resync_interval,
+                sequential,
+            })
+        }
+    }
+}
+
+// `prefix` as an absolute path without a trailing slash, empty for the root.
+fn root_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    if prefix.is_empty() || prefix.starts_with('/') {
+        prefix.to_owned()
+    } else {
+        format!("/{}", prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{root_prefix, RetryPolicy, ZkBuilder};
+    use std::{cell::Cell, time::Duration};
+    use zookeeper::ZkError;
+
+    #[test]
+    fn test_retry_policy() {
+        let retry = RetryPolicy {
+            max_retries: 2,
+            backoff: Duration::from_millis(1),
+        };
+        let calls = Cell::new(0);
+        let lost = retry.run(|| -> Result<(), _> {
+            calls.set(calls.get() + 1);
+            Err(ZkError::ConnectionLoss)
+        });
+        assert_eq!(lost, Err(ZkError::ConnectionLoss));
+        assert_eq!(calls.get(), 3);
+
+        calls.set(0);
+        let created = retry.run(|| {
+            calls.set(calls.get() + 1);
+            match calls.get() {
+                1 => Err(ZkError::OperationTimeout),
+                _ => Ok("/provider"),
+            }
+        });
+        assert_eq!(created, Ok("/provider"));
+        assert_eq!(calls.get(), 2);
+
+        calls.set(0);
+        let exists = RetryPolicy::default().run(|| -> Result<(), _> {
+            calls.set(calls.get() + 1);
+            Err(ZkError::NodeExists)
+        });
+        assert_eq!(exists, Err(ZkError::NodeExists));
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_builder() {
+        let builder = ZkBuilder::new("10.0.0.1:2181")
+            .session_timeout(Duration::from_secs(10))
+            .auth("digest", "user:password")
+            .root_prefix("/discovery/prod")
+            .retry(RetryPolicy::never())
+            .resync_interval(Duration::from_secs(60));
+        let config = &builder.config;
+        assert_eq!(config.connect_string, "10.0.0.1:2181");
+        assert_eq!(config.session_timeout, Duration::from_secs(10));
+        assert_eq!(config.connect_timeout, Duration::from_secs(3));
+        assert_eq!(
+            config.auth,
+            vec![("digest".to_owned(), b"user:password".to_vec())]
+        );
+        assert_eq!(config.acl[0].scheme, "world");
+        assert_eq!(config.retry.max_retries, 0);
+        assert_eq!(config.resync_interval, Some(Duration::from_secs(60)));
+        assert_eq!(root_prefix(&config.root_prefix), "/discovery/prod");
+        assert_eq!(root_prefix("discovery/prod/"), "/discovery/prod");
+        assert_eq!(root_prefix("/"), "");
+    }
+}
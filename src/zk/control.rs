@@ -0,0 +1,68 @@
+use super::{kv::ZkKeyWatcher, RegFut, Zk, ZkRegError};
+use crate::control::{Control, ControlParams};
+use futures::Stream;
+use log::error;
+use pin_project::pin_project;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+impl<EC, DC, I> Control for Zk<EC, DC, I> {
+    type Error = ZkRegError;
+
+    type SetFuture = RegFut;
+
+    type ControlWatcher = ZkControlWatcher;
+
+    // the parameters are the JSON data of a persistent node, see
+    // `Layout::control_path`.
+    fn set_control(&self, appid: &str, params: &ControlParams) -> Self::SetFuture {
+        let path = self.root_prefix.clone() + &self.layout.control_path(appid);
+        self.set_node(path, params.to_json().into_bytes())
+    }
+
+    fn watch_control(&self, appid: &str) -> Self::ControlWatcher {
+        let path = self.root_prefix.clone() + &self.layout.control_path(appid);
+        ZkControlWatcher {
+            inner: self.watch_node(path.clone()),
+            path,
+        }
+    }
+}
+
+/// The control parameters of an app, see `Control::watch_control`.
+#[pin_project]
+pub struct ZkControlWatcher {
+    path: String,
+    #[pin]
+    inner: ZkKeyWatcher,
+}
+
+impl Stream for ZkControlWatcher {
+    type Item = ControlParams;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            let data = match this.inner.as_mut().poll_next(cx) {
+                Poll::Ready(Some(data)) => data.unwrap_or_default(),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            // empty while the node doesn't exist.
+            match parse(&data) {
+                Ok(params) => return Poll::Ready(Some(params)),
+                Err(e) => error!("bad control parameters in {}. {}", this.path, e),
+            }
+        }
+    }
+}
+
+fn parse(data: &[u8]) -> Result<ControlParams, String> {
+    if data.is_empty() {
+        return Ok(ControlParams::default());
+    }
+    let json = std::str::from_utf8(data).map_err(|e| e.to_string())?;
+    ControlParams::from_json(json).map_err(|e| e.to_string())
+}
@@ -0,0 +1,244 @@
+use super::{client::ZkClient, create_path, worker::Workers, PathCache, Zk, ZkRegError};
+use crate::{
+    election::{Election, Leadership},
+    hooks::ConnectionState,
+};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    Stream,
+};
+use log::error;
+use pin_project::{pin_project, pinned_drop};
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+use zookeeper::{CreateMode, WatchedEvent, WatchedEventType, Watcher};
+
+impl<EC, DC, I> Election for Zk<EC, DC, I> {
+    type Error = ZkRegError;
+
+    type Campaign = ZkCampaign;
+
+    // the candidates are the ephemeral sequential children of
+    // `/elections/<name>` under the root prefix, the lowest one leads and its
+    // sequence number is the token.
+    fn campaign(&self, name: &str, candidate: &str) -> Self::Campaign {
+        let (tx, updates) = mpsc::unbounded();
+        let joining = Arc::new(Candidate {
+            client: self.client.clone(),
+            dir: format!("{}/elections/{}", self.root_prefix, name),
+            node: Mutex::new(None),
+            resigned: AtomicBool::new(false),
+            tx,
+        });
+        let states = self.client.connection_states();
+        let data = candidate.as_bytes().to_vec();
+        let persistent_exist_node_path = self.persistent_exist_node_path.clone();
+        self.workers.spawn({
+            let joining = joining.clone();
+            move || joining.join(data, &persistent_exist_node_path)
+        });
+        ZkCampaign {
+            candidate: joining,
+            workers: self.workers.clone(),
+            updates,
+            states,
+            leader: false,
+            done: false,
+        }
+    }
+}
+
+enum Update {
+    Leader(u64),
+    // the node of the candidate is gone, e.g. with the session.
+    Gone,
+    Failed(ZkRegError),
+}
+
+struct Candidate {
+    client: Arc<ZkClient>,
+    dir: String,
+    // the node created, once it is.
+    node: Mutex<Option<String>>,
+    resigned: AtomicBool,
+    tx: UnboundedSender<Update>,
+}
+
+impl Candidate {
+    fn join(self: &Arc<Self>, data: Vec<u8>, persistent_exist_node_path: &PathCache) {
+        let client = &self.client;
+        if let Err(e) = create_path(
+            client.clone(),
+            &self.dir,
+            Vec::new(),
+            false,
+            persistent_exist_node_path,
+        ) {
+            let _ = self.tx.unbounded_send(Update::Failed(e));
+            return;
+        }
+        let prefix = format!("{}/n_", self.dir);
+        let created = client.retry.run(|| {
+            client.create(
+                &prefix,
+                data.clone(),
+                client.acl.clone(),
+                CreateMode::EphemeralSequential,
+            )
+        });
+        match created {
+            Ok(path) => {
+                let mut node = self.node.lock().unwrap();
+                if self.resigned.load(Ordering::SeqCst) {
+                    let _ = client.delete(&path, None);
+                    return;
+                }
+                *node = Some(path);
+            }
+            Err(e) => {
+                let _ = self
+                    .tx
+                    .unbounded_send(Update::Failed(ZkRegError::CreatePath(e)));
+                return;
+            }
+        }
+        self.check();
+    }
+
+    // leads when its node is the lowest, otherwise watches the one right
+    // before it.
+    fn check(self: &Arc<Self>) {
+        let node = match self.node.lock().unwrap().clone() {
+            Some(node) => node,
+            None => return,
+        };
+        let name = &node[self.dir.len() + 1..];
+        loop {
+            // on a lost connection, checked again once connected.
+            let mut children = match self.client.get_children(&self.dir, false) {
+                Ok(children) => children,
+                Err(e) => return error!("failed to list {}. {}", self.dir, e),
+            };
+            children.sort_by_key(|child| sequence(child));
+            let pos = match children.iter().position(|child| child == name) {
+                Some(pos) => pos,
+                None => {
+                    let _ = self.tx.unbounded_send(Update::Gone);
+                    return;
+                }
+            };
+            if pos == 0 {
+                let _ = self.tx.unbounded_send(Update::Leader(sequence(name)));
+                return;
+            }
+            let before = format!("{}/{}", self.dir, children[pos - 1]);
+            match self.client.exists_w(&before, Check(self.clone())) {
+                Ok(Some(_)) => return,
+                // gone meanwhile.
+                Ok(None) => continue,
+                Err(e) => return error!("failed to watch {}. {}", before, e),
+            }
+        }
+    }
+
+    fn resign(&self) {
+        // under the lock, a node created meanwhile is deleted right away.
+        let mut node = self.node.lock().unwrap();
+        self.resigned.store(true, Ordering::SeqCst);
+        if let Some(node) = node.take() {
+            let _ = self.client.delete(&node, None);
+        }
+    }
+}
+
+// the counter ZooKeeper appends to sequential nodes.
+fn sequence(child: &str) -> u64 {
+    child[child.len().saturating_sub(10)..]
+        .parse()
+        .unwrap_or(u64::MAX)
+}
+
+struct Check(Arc<Candidate>);
+
+impl Watcher for Check {
+    fn handle(&self, event: WatchedEvent) {
+        if event.event_type == WatchedEventType::NodeDeleted {
+            self.0.check();
+        }
+    }
+}
+
+/// A campaign for leadership in ZooKeeper, resigning when dropped.
+#[pin_project(PinnedDrop)]
+pub struct ZkCampaign {
+    candidate: Arc<Candidate>,
+    workers: Arc<Workers>,
+    #[pin]
+    updates: UnboundedReceiver<Update>,
+    #[pin]
+    states: UnboundedReceiver<ConnectionState>,
+    leader: bool,
+    done: bool,
+}
+
+impl Stream for ZkCampaign {
+    type Item = Result<Leadership, ZkRegError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        while !*this.done {
+            match this.states.as_mut().poll_next(cx) {
+                Poll::Ready(Some(ConnectionState::Disconnected)) => {
+                    if *this.leader {
+                        *this.leader = false;
+                        return Poll::Ready(Some(Ok(Leadership::Lost)));
+                    }
+                    continue;
+                }
+                Poll::Ready(Some(ConnectionState::Connected)) => {
+                    let candidate = this.candidate.clone();
+                    this.workers.spawn(move || candidate.check());
+                    continue;
+                }
+                Poll::Ready(Some(ConnectionState::Expired)) | Poll::Ready(None) => {
+                    *this.done = true;
+                    break;
+                }
+                Poll::Pending => {}
+            }
+            match this.updates.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Update::Leader(token))) => {
+                    if !*this.leader {
+                        *this.leader = true;
+                        return Poll::Ready(Some(Ok(Leadership::Acquired { token })));
+                    }
+                }
+                Poll::Ready(Some(Update::Gone)) | Poll::Ready(None) => *this.done = true,
+                Poll::Ready(Some(Update::Failed(e))) => {
+                    *this.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        if *this.leader {
+            *this.leader = false;
+            return Poll::Ready(Some(Ok(Leadership::Lost)));
+        }
+        Poll::Ready(None)
+    }
+}
+
+#[pinned_drop]
+impl PinnedDrop for ZkCampaign {
+    fn drop(self: Pin<&mut Self>) {
+        let candidate = self.candidate.clone();
+        self.workers.spawn(move || candidate.resign());
+    }
+}
@@ -0,0 +1,177 @@
+use super::{client::ZkClient, create_path, PathCache, RegFut, Zk, ZkRegError};
+use crate::kv::KvStore;
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    Stream,
+};
+use log::error;
+use pin_project::pin_project;
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use zookeeper::{CreateMode, WatchedEvent, WatchedEventType, Watcher, ZkError};
+
+// keys are the paths of persistent nodes under the root prefix.
+impl<EC, DC, I> KvStore for Zk<EC, DC, I> {
+    type Error = ZkRegError;
+
+    type SetFuture = RegFut;
+
+    type KeyWatcher = ZkKeyWatcher;
+
+    fn set_key(&self, key: &str, value: Vec<u8>) -> Self::SetFuture {
+        self.set_node(self.root_prefix.clone() + key, value)
+    }
+
+    fn delete_key(&self, key: &str) -> Self::SetFuture {
+        let client = self.client.clone();
+        let path = self.root_prefix.clone() + key;
+        let persistent_exist_node_path = self.persistent_exist_node_path.clone();
+        RegFut {
+            rx: self.workers.run(move || {
+                persistent_exist_node_path.remove(&path);
+                match client.retry.run(|| client.delete(&path, None)) {
+                    Ok(()) | Err(ZkError::NoNode) => Ok(()),
+                    Err(e) => Err(ZkRegError::DeletePath(e)),
+                }
+            }),
+        }
+    }
+
+    fn watch_key(&self, key: &str) -> Self::KeyWatcher {
+        self.watch_node(self.root_prefix.clone() + key)
+    }
+}
+
+impl<EC, DC, I> Zk<EC, DC, I> {
+    // sets the data of the persistent node `path`, creating it as needed.
+    pub(crate) fn set_node(&self, path: String, data: Vec<u8>) -> RegFut {
+        let client = self.client.clone();
+        let persistent_exist_node_path = self.persistent_exist_node_path.clone();
+        RegFut {
+            rx: self
+                .workers
+                .run(move || set_node(&client, &path, data, &persistent_exist_node_path)),
+        }
+    }
+
+    // the data of the node `path`, `None` while it doesn't exist.
+    pub(crate) fn watch_node(&self, path: String) -> ZkKeyWatcher {
+        let (tx, rx) = mpsc::unbounded();
+        let watch = KeyWatch {
+            client: self.client.clone(),
+            path: Arc::new(path),
+            tx,
+        };
+        // the watch is gone with an expired session.
+        self.client.on_session({
+            let watch = watch.clone();
+            move |_| {
+                if watch.tx.is_closed() {
+                    return false;
+                }
+                watch.read();
+                true
+            }
+        });
+        self.workers.spawn(move || watch.read());
+        ZkKeyWatcher {
+            zk_client: self.client.clone(),
+            rx,
+        }
+    }
+}
+
+fn set_node(
+    client: &Arc<ZkClient>,
+    path: &str,
+    data: Vec<u8>,
+    persistent_exist_node_path: &PathCache,
+) -> Result<(), ZkRegError> {
+    loop {
+        match client
+            .retry
+            .run(|| client.set_data(path, data.clone(), None))
+        {
+            Ok(_) => return Ok(()),
+            Err(ZkError::NoNode) => {}
+            Err(e) => return Err(ZkRegError::SetData(e)),
+        }
+        if let Some(pos) = path.rfind('/').filter(|&pos| pos > 0) {
+            create_path(
+                client.clone(),
+                &path[..pos],
+                Vec::new(),
+                false,
+                persistent_exist_node_path,
+            )?;
+        }
+        let created = client.retry.run(|| {
+            client.create(
+                path,
+                data.clone(),
+                client.acl.clone(),
+                CreateMode::Persistent,
+            )
+        });
+        match created {
+            Ok(_) => return Ok(()),
+            // created meanwhile, set it.
+            Err(ZkError::NodeExists) => {}
+            Err(e) => return Err(ZkRegError::CreatePath(e)),
+        }
+    }
+}
+
+/// The data of a node, see `KvStore::watch_key`.
+#[pin_project]
+pub struct ZkKeyWatcher {
+    zk_client: Arc<ZkClient>,
+    #[pin]
+    rx: UnboundedReceiver<Option<Vec<u8>>>,
+}
+
+impl Stream for ZkKeyWatcher {
+    type Item = Option<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().rx.poll_next(cx)
+    }
+}
+
+#[derive(Clone)]
+struct KeyWatch {
+    client: Arc<ZkClient>,
+    path: Arc<String>,
+    tx: UnboundedSender<Option<Vec<u8>>>,
+}
+
+impl KeyWatch {
+    // sends the data and watches it again.
+    fn read(&self) {
+        let data = match self.client.exists_w(&self.path, self.clone()) {
+            Ok(Some(_)) => match self.client.get_data(&self.path, false) {
+                Ok((data, _)) => Some(data),
+                // deleted since, the watch tells.
+                Err(ZkError::NoNode) => return,
+                Err(e) => return error!("failed to read {}. {}", self.path, e),
+            },
+            Ok(None) => None,
+            Err(e) => return error!("failed to watch {}. {}", self.path, e),
+        };
+        let _ = self.tx.unbounded_send(data);
+    }
+}
+
+impl Watcher for KeyWatch {
+    fn handle(&self, event: WatchedEvent) {
+        match event.event_type {
+            WatchedEventType::NodeCreated
+            | WatchedEventType::NodeDataChanged
+            | WatchedEventType::NodeDeleted => self.read(),
+            _ => {}
+        }
+    }
+}
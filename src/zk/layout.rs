@@ -0,0 +1,295 @@
+use crate::{codec::service_instance_id, Instance};
+
+/// How an instance is stored in its node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Payload {
+    /// The node is named after the encoded instance.
+    Name,
+    /// The encoded instance is the data of the node, named by
+    /// `Layout::node_name`.
+    Data,
+}
+
+/// Where instances are registered in the ZooKeeper tree.
+///
+/// Instances are the children of a directory node, named after their
+/// encoding by the codec of the registry or holding it as data.
+pub trait Layout: Send + Sync {
+    /// The directory `ins` is registered in.
+    fn dir(&self, ins: &Instance) -> String;
+
+    /// The directory watched for the instances of `appid`.
+    fn watch_dir(&self, appid: &str) -> String;
+
+    /// Whether to create the watched directory when missing, so instances
+    /// registered later are reported.
+    fn create_watch_dir(&self) -> bool {
+        false
+    }
+
+    fn payload(&self) -> Payload {
+        Payload::Name
+    }
+
+    /// The name of the node of `ins` with `Payload::Data`.
+    fn node_name(&self, ins: &Instance) -> String {
+        ins.hostname.clone()
+    }
+
+    /// The node holding the `control::ControlParams` of `appid`.
+    fn control_path(&self, appid: &str) -> String {
+        format!("{}.control", self.watch_dir(appid))
+    }
+}
+
+/// The appid is the directory, e.g. `/dubbo-rs/provider/zone=sh1&env=...`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AppidLayout;
+
+impl Layout for AppidLayout {
+    fn dir(&self, ins: &Instance) -> String {
+        ins.appid.to_string()
+    }
+
+    fn watch_dir(&self, appid: &str) -> String {
+        appid.to_owned()
+    }
+}
+
+/// The appid is the directory, like `AppidLayout`, and instances are the data
+/// of nodes named `hostname:port`, e.g. `/dubbo-rs/provider/myhostname:9999`.
+///
+/// Paths stay short whatever the metadata, which can be updated in place, see
+/// `Registry::update_if`. The port is the one of the first address, its host
+/// naming the node when the hostname is empty.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DataLayout;
+
+impl Layout for DataLayout {
+    fn dir(&self, ins: &Instance) -> String {
+        ins.appid.to_string()
+    }
+
+    fn watch_dir(&self, appid: &str) -> String {
+        appid.to_owned()
+    }
+
+    fn payload(&self) -> Payload {
+        Payload::Data
+    }
+
+    fn node_name(&self, ins: &Instance) -> String {
+        let addr = ins.addrs.first().map(String::as_str).unwrap_or_default();
+        let addr = addr.splitn(2, "://").last().unwrap_or_default();
+        let host_port = addr.split('/').next().unwrap_or_default();
+        match host_port.rsplit_once(':') {
+            Some((_, port)) if !ins.hostname.is_empty() => format!("{}:{}", ins.hostname, port),
+            _ => host_port.to_owned(),
+        }
+    }
+}
+
+/// A Dubbo registry category, the directories under a service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Providers,
+    Consumers,
+    Configurators,
+    Routers,
+}
+
+impl Category {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Providers => "providers",
+            Category::Consumers => "consumers",
+            Category::Configurators => "configurators",
+            Category::Routers => "routers",
+        }
+    }
+}
+
+/// The layout of Java Dubbo's ZooKeeper registry,
+/// `/dubbo/{interface}/{category}/{url}`, to use with `codec::new_dubbo_codec`.
+///
+/// The appid is the service interface. Instances are registered in the
+/// category of their `category` metadata, providers when missing, and
+/// watchers watch the providers unless told otherwise.
+///
+/// ```ignore
+/// let zk = Zk::builder(urls).codec(new_dubbo_codec()).build().await?.with_layout(DubboLayout::new());
+/// let providers = zk.watch("org.apache.dubbo.demo.DemoService");
+/// ```
+#[derive(Debug, Clone)]
+pub struct DubboLayout {
+    root: String,
+    watch: Category,
+}
+
+impl DubboLayout {
+    pub fn new() -> Self {
+        DubboLayout {
+            root: "/dubbo".to_owned(),
+            watch: Category::Providers,
+        }
+    }
+
+    /// The root node, `/dubbo` unless Dubbo is configured with another group.
+    pub fn root(mut self, root: impl Into<String>) -> Self {
+        self.root = root.into();
+        self
+    }
+
+    /// The category watched.
+    pub fn watch(mut self, category: Category) -> Self {
+        self.watch = category;
+        self
+    }
+}
+
+impl Default for DubboLayout {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Layout for DubboLayout {
+    fn dir(&self, ins: &Instance) -> String {
+        let category = ins
+            .metadata
+            .get("category")
+            .map(String::as_str)
+            .unwrap_or_else(|| Category::Providers.as_str());
+        format!("{}/{}/{}", self.root, ins.appid, category)
+    }
+
+    fn watch_dir(&self, appid: &str) -> String {
+        format!("{}/{}/{}", self.root, appid, self.watch.as_str())
+    }
+
+    // like Dubbo consumers do when subscribing.
+    fn create_watch_dir(&self) -> bool {
+        true
+    }
+
+    // next to the categories, whichever is watched.
+    fn control_path(&self, appid: &str) -> String {
+        format!("{}/{}/control", self.root, appid)
+    }
+}
+
+/// The layout of Curator service discovery used by Spring Cloud Zookeeper,
+/// `/services/{name}/{id}` with the service instance as data, to use with
+/// `codec::new_spring_cloud_codec`.
+///
+/// The appid is the service name and the id derived from the instance, see
+/// `codec::service_instance_id`.
+///
+/// ```ignore
+/// let zk = Zk::builder(urls)
+///     .codec(new_spring_cloud_codec())
+///     .build()
+///     .await?
+///     .with_layout(SpringCloudLayout::new());
+/// let instances = zk.watch("provider");
+/// ```
+#[derive(Debug, Clone)]
+pub struct SpringCloudLayout {
+    root: String,
+}
+
+impl SpringCloudLayout {
+    pub fn new() -> Self {
+        SpringCloudLayout {
+            root: "/services".to_owned(),
+        }
+    }
+
+    /// The root node, `/services` unless Spring Cloud Zookeeper is
+    /// configured with another root.
+    pub fn root(mut self, root: impl Into<String>) -> Self {
+        self.root = root.into();
+        self
+    }
+}
+
+impl Default for SpringCloudLayout {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Layout for SpringCloudLayout {
+    fn dir(&self, ins: &Instance) -> String {
+        self.watch_dir(&ins.appid)
+    }
+
+    fn watch_dir(&self, appid: &str) -> String {
+        format!("{}/{}", self.root, appid)
+    }
+
+    fn create_watch_dir(&self) -> bool {
+        true
+    }
+
+    fn payload(&self) -> Payload {
+        Payload::Data
+    }
+
+    fn node_name(&self, ins: &Instance) -> String {
+        service_instance_id(ins)
+    }
+
+    // out of the root, where it would look like a service.
+    fn control_path(&self, appid: &str) -> String {
+        format!("{}.control/{}", self.root, appid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Category, DataLayout, DubboLayout, Layout};
+    use crate::Instance;
+
+    #[test]
+    fn test_dubbo_layout() {
+        let layout = DubboLayout::new();
+        let mut ins = Instance {
+            appid: "org.apache.dubbo.demo.DemoService".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            layout.dir(&ins),
+            "/dubbo/org.apache.dubbo.demo.DemoService/providers"
+        );
+        ins.metadata
+            .insert("category".to_owned(), "consumers".to_owned());
+        assert_eq!(
+            layout.dir(&ins),
+            "/dubbo/org.apache.dubbo.demo.DemoService/consumers"
+        );
+        assert_eq!(
+            layout
+                .root("/dubbo-test")
+                .watch(Category::Configurators)
+                .watch_dir("org.apache.dubbo.demo.DemoService"),
+            "/dubbo-test/org.apache.dubbo.demo.DemoService/configurators"
+        );
+    }
+    #[test]
+    fn test_data_layout() {
+        let mut ins = Instance {
+            appid: "/dubbo-rs/provider".into(),
+            addrs: vec![
+                "grpc://172.1.1.1:9999/provider".to_owned(),
+                "http://172.1.1.1:8000".to_owned(),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        assert_eq!(DataLayout.node_name(&ins), "172.1.1.1:9999");
+        ins.hostname = "myhostname".to_owned();
+        assert_eq!(DataLayout.dir(&ins), "/dubbo-rs/provider");
+        assert_eq!(DataLayout.node_name(&ins), "myhostname:9999");
+    }
+}
@@ -0,0 +1,60 @@
+use crate::HashSet;
+use std::sync::RwLock;
+
+const SHARDS: usize = 16;
+
+/// The persistent znodes known to exist, so registering doesn't check them
+/// every time.
+///
+/// Split in shards by the hash of the path, so registrations under different
+/// paths don't wait on each other.
+pub(crate) struct PathCache {
+    shards: Vec<RwLock<HashSet<String>>>,
+}
+
+impl PathCache {
+    pub(crate) fn new() -> Self {
+        PathCache {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+        }
+    }
+
+    pub(crate) fn contains(&self, path: &str) -> bool {
+        self.shard(path).read().unwrap().contains(path)
+    }
+
+    pub(crate) fn insert(&self, path: &str) {
+        let mut shard = self.shard(path).write().unwrap();
+        if !shard.contains(path) {
+            shard.insert(path.to_owned());
+        }
+    }
+
+    pub(crate) fn remove(&self, path: &str) {
+        self.shard(path).write().unwrap().remove(path);
+    }
+
+    fn shard(&self, path: &str) -> &RwLock<HashSet<String>> {
+        &self.shards[fxhash::hash64(path) as usize % SHARDS]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PathCache;
+
+    #[test]
+    fn test_path_cache() {
+        let cache = PathCache::new();
+        cache.insert("/dubbo-rs");
+        cache.insert("/dubbo-rs/provider");
+        cache.insert("/dubbo-rs/provider");
+        assert!(cache.contains("/dubbo-rs"));
+        assert!(cache.contains("/dubbo-rs/provider"));
+        assert!(!cache.contains("/dubbo-rs/consumer"));
+
+        cache.remove("/dubbo-rs/provider");
+        assert!(!cache.contains("/dubbo-rs/provider"));
+        assert!(cache.contains("/dubbo-rs"));
+    }
+}
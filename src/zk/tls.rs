@@ -0,0 +1,267 @@
+//! ZooKeeper over TLS.
+//!
+//! The `zookeeper` client owns its sockets and only speaks plain TCP, so
+//! every server of the connect string gets a listener on the loopback whose
+//! connections are forwarded to it over TLS, the session connecting to those
+//! listeners instead.
+use crate::runtime::{self, AbortOnDrop};
+use futures::{future, pin_mut};
+use log::{debug, error, warn};
+use std::{
+    fmt,
+    io::{self, BufReader},
+    net::{Ipv4Addr, TcpListener as StdTcpListener},
+    sync::Arc,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{
+    rustls::{internal::pemfile, ClientConfig},
+    webpki::{DNSName, DNSNameRef},
+    TlsConnector,
+};
+use zookeeper::{ZkError, ZkResult};
+
+/// The certificates to connect to ZooKeeper with, see `ZkBuilder::tls`.
+///
+/// ```ignore
+/// let tls = ZkTls::new(&fs::read("ca.pem")?)?
+///     .identity(&fs::read("client.pem")?, &fs::read("client.key")?)?;
+/// let zk = Zk::builder("zk1.example.com:2281,zk2.example.com:2281")
+///     .tls(tls)
+///     .build()
+///     .await?;
+/// ```
+#[derive(Clone)]
+pub struct ZkTls {
+    config: ClientConfig,
+    server_name: Option<String>,
+}
+
+impl ZkTls {
+    /// Trusts the servers whose certificate is signed by one in `ca_pem`.
+    pub fn new(ca_pem: &[u8]) -> io::Result<Self> {
+        let mut config = ClientConfig::new();
+        match config.root_store.add_pem_file(&mut BufReader::new(ca_pem)) {
+            Ok((added, _)) if added > 0 => Ok(ZkTls {
+                config,
+                server_name: None,
+            }),
+            _ => Err(invalid_data("no ca certificate in the pem")),
+        }
+    }
+
+    /// Presents the certificate chain in `cert_pem`, whose private key is in
+    /// `key_pem` as PKCS#8 or RSA, to servers authenticating clients.
+    pub fn identity(mut self, cert_pem: &[u8], key_pem: &[u8]) -> io::Result<Self> {
+        let certs = pemfile::certs(&mut BufReader::new(cert_pem))
+            .ok()
+            .filter(|certs| !certs.is_empty())
+            .ok_or_else(|| invalid_data("no certificate in the pem"))?;
+        let key = pemfile::pkcs8_private_keys(&mut BufReader::new(key_pem))
+            .ok()
+            .filter(|keys| !keys.is_empty())
+            .or_else(|| pemfile::rsa_private_keys(&mut BufReader::new(key_pem)).ok())
+            .and_then(|keys| keys.into_iter().next())
+            .ok_or_else(|| invalid_data("no private key in the pem"))?;
+        self.config
+            .set_single_client_cert(certs, key)
+            .map_err(|err| invalid_data(&err.to_string()))?;
+        Ok(self)
+    }
+
+    /// Checks the certificates of every server against `name` rather than
+    /// the host connected to, e.g. when connecting to addresses.
+    pub fn server_name(mut self, name: &str) -> Self {
+        self.server_name = Some(name.to_owned());
+        self
+    }
+}
+
+impl fmt::Debug for ZkTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZkTls")
+            .field("server_name", &self.server_name)
+            .finish()
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// The listeners forwarding to the servers over TLS, closed once dropped.
+pub(crate) struct Tunnel {
+    connect_string: String,
+    _accepting: Vec<AbortOnDrop>,
+}
+
+impl Tunnel {
+    /// The connect string of the servers, through the tunnel.
+    pub(crate) fn connect_string(&self) -> &str {
+        &self.connect_string
+    }
+}
+
+/// Listens on the loopback for every server of `connect_string`, failing
+/// with `ZkError::BadArguments` when a server can't be named.
+pub(crate) fn tunnel(connect_string: &str, tls: &ZkTls) -> ZkResult<Tunnel> {
+    let (servers, chroot) = match connect_string.find('/') {
+        Some(i) => connect_string.split_at(i),
+        None => (connect_string, ""),
+    };
+    let connector = TlsConnector::from(Arc::new(tls.config.clone()));
+    let mut local = Vec::new();
+    let mut accepting = Vec::new();
+    for server in servers.split(',').map(str::trim) {
+        let host = match server.rfind(':') {
+            Some(i) => &server[..i],
+            None => {
+                error!("zookeeper server {} has no port", server);
+                return Err(ZkError::BadArguments);
+            }
+        };
+        let name = tls.server_name.as_deref().unwrap_or(host);
+        let name = match DNSNameRef::try_from_ascii_str(name) {
+            Ok(name) => name.to_owned(),
+            Err(_) => {
+                error!(
+                    "{} isn't a dns name to check the certificate of zookeeper server {} against",
+                    name, server
+                );
+                return Err(ZkError::BadArguments);
+            }
+        };
+        let listener = StdTcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .and_then(|listener| Ok((listener.local_addr()?, listener)));
+        let (addr, listener) = match listener {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("failed to listen for the zookeeper tls tunnel: {}", err);
+                return Err(ZkError::SystemError);
+            }
+        };
+        local.push(addr.to_string());
+        let forwarding = forward_all(listener, connector.clone(), server.to_owned(), name);
+        accepting.push(runtime::spawn_abortable(forwarding));
+    }
+    Ok(Tunnel {
+        connect_string: format!("{}{}", local.join(","), chroot),
+        _accepting: accepting,
+    })
+}
+
+async fn forward_all(
+    listener: StdTcpListener,
+    connector: TlsConnector,
+    server: String,
+    name: DNSName,
+) {
+    let mut listener = match TcpListener::from_std(listener) {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("failed to listen for the zookeeper tls tunnel: {}", err);
+            return;
+        }
+    };
+    loop {
+        let local = match listener.accept().await {
+            Ok((local, _)) => local,
+            Err(err) => {
+                warn!("failed to accept for the zookeeper tls tunnel: {}", err);
+                continue;
+            }
+        };
+        let (connector, server, name) = (connector.clone(), server.clone(), name.clone());
+        runtime::spawn(async move {
+            if let Err(err) = forward(local, &connector, &server, &name).await {
+                debug!("zookeeper tls tunnel to {} closed: {}", server, err);
+            }
+        });
+    }
+}
+
+// until either side closes.
+async fn forward(
+    local: TcpStream,
+    connector: &TlsConnector,
+    server: &str,
+    name: &DNSName,
+) -> io::Result<()> {
+    let remote = TcpStream::connect(server).await?;
+    let remote = connector.connect(name.as_ref(), remote).await?;
+    let (mut local_read, mut local_write) = tokio::io::split(local);
+    let (mut remote_read, mut remote_write) = tokio::io::split(remote);
+    let sending = tokio::io::copy(&mut local_read, &mut remote_write);
+    let receiving = tokio::io::copy(&mut remote_read, &mut local_write);
+    pin_mut!(sending, receiving);
+    match future::select(sending, receiving).await {
+        future::Either::Left((copied, _)) | future::Either::Right((copied, _)) => copied.map(drop),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tunnel, ZkTls};
+    use std::{io::BufReader, sync::Arc};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::{
+        rustls::{internal::pemfile, NoClientAuth, ServerConfig},
+        TlsAcceptor,
+    };
+
+    const CA: &[u8] = include_bytes!("testdata/ca.pem");
+    const CERT: &[u8] = include_bytes!("testdata/server.pem");
+    const KEY: &[u8] = include_bytes!("testdata/server.key");
+
+    // echoes what it reads over tls as localhost.
+    async fn echo_server() -> u16 {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        let certs = pemfile::certs(&mut BufReader::new(CERT)).unwrap();
+        let key = pemfile::pkcs8_private_keys(&mut BufReader::new(KEY)).unwrap();
+        config.set_single_cert(certs, key[0].clone()).unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let stream = acceptor.accept(stream).await.unwrap();
+                    let (mut read, mut write) = tokio::io::split(stream);
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                });
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn test_tunnel() {
+        let port = echo_server().await;
+        let tls = ZkTls::new(CA).unwrap();
+        let tunneled = tunnel(&format!("localhost:{}/discovery", port), &tls).unwrap();
+        let local = tunneled.connect_string().trim_end_matches("/discovery");
+        assert!(local.starts_with("127.0.0.1:"));
+
+        let mut stream = TcpStream::connect(local).await.unwrap();
+        stream.write_all(b"ruok").await.unwrap();
+        let mut echoed = [0; 4];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ruok");
+
+        // the name the certificate was issued to, not the one connected to.
+        let named = ZkTls::new(CA).unwrap().server_name("localhost");
+        let tunneled = tunnel(&format!("127.0.0.1:{}", port), &named).unwrap();
+        let mut stream = TcpStream::connect(tunneled.connect_string()).await.unwrap();
+        stream.write_all(b"ruok").await.unwrap();
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ruok");
+
+        // webpki doesn't check certificates against addresses.
+        assert!(tunnel(&format!("127.0.0.1:{}", port), &tls).is_err());
+        assert!(ZkTls::new(b"").is_err());
+    }
+}
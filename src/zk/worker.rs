@@ -0,0 +1,118 @@
+use futures::channel::oneshot;
+use log::error;
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads making the blocking ZooKeeper calls, so bursts of them queue up
+/// here instead of filling the blocking pool of the runtime.
+///
+/// The threads stop once the pool is dropped and the queued calls are done.
+pub(crate) struct Workers {
+    jobs: Mutex<mpsc::Sender<Job>>,
+}
+
+impl Workers {
+    pub(crate) fn new(threads: usize) -> Self {
+        let (jobs, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..threads.max(1) {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("zk-worker-{}", i))
+                .spawn(move || loop {
+                    let job = match rx.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    // a panicking call fails alone, its caller sees it canceled.
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        error!("zookeeper call panicked");
+                    }
+                })
+                .expect("failed to spawn a zookeeper worker thread");
+        }
+        Workers {
+            jobs: Mutex::new(jobs),
+        }
+    }
+
+    /// Queues `f`, the receiver gets its result. Dropping the receiver
+    /// before a thread picks `f` up cancels it, e.g. a registration future
+    /// dropped under a timeout.
+    pub(crate) fn run<F, T>(&self, f: F) -> oneshot::Receiver<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.spawn(move || {
+            if !tx.is_canceled() {
+                let _ = tx.send(f());
+            }
+        });
+        rx
+    }
+
+    /// Queues `f`, without waiting for it.
+    pub(crate) fn spawn<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // the threads only stop once the sender is dropped.
+        let _ = self.jobs.lock().unwrap().send(Box::new(f));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Workers;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Barrier,
+        },
+        thread,
+    };
+
+    #[tokio::test]
+    async fn test_workers() {
+        let workers = Workers::new(2);
+        // both threads run at once.
+        let barrier = Arc::new(Barrier::new(2));
+        let calls = (0..2).map(|_| {
+            let barrier = barrier.clone();
+            workers.run(move || {
+                barrier.wait();
+                thread::current().name().unwrap().to_owned()
+            })
+        });
+        let mut names = futures::future::try_join_all(calls).await.unwrap();
+        names.sort();
+        assert_eq!(names, vec!["zk-worker-0", "zk-worker-1"]);
+
+        let panicked = workers.run::<_, ()>(|| panic!("boom"));
+        assert!(panicked.await.is_err());
+        assert_eq!(workers.run(|| 1).await, Ok(1));
+
+        // queued behind both threads, then dropped.
+        let (busy, ran) = (Arc::new(Barrier::new(3)), Arc::new(AtomicBool::new(false)));
+        for _ in 0..2 {
+            let busy = busy.clone();
+            workers.spawn(move || {
+                busy.wait();
+            });
+        }
+        drop(workers.run({
+            let ran = ran.clone();
+            move || ran.store(true, Ordering::SeqCst)
+        }));
+        busy.wait();
+        assert_eq!(workers.run(|| 1).await, Ok(1));
+        assert!(!ran.load(Ordering::SeqCst));
+    }
+}
@@ -1,55 +1,165 @@
-use crate::codec::Decoder;
-use crate::watcher::{Event, WatchEvent};
-use crate::{HashSet, Instance};
+use super::{client::ZkClient, create_path, strip_sequence, Payload, Zk, ZkRegError};
+use crate::codec::{decode_with_payload, from_unix_millis, Codec, DecodeError, Decoder, Encoder};
+use crate::identity::Identity;
+use crate::runtime::{self, AbortOnDrop};
+use crate::watcher::{Clock, Event, WatchEvent};
+use crate::{DiscoverError, Instance};
 use futures::channel::mpsc;
-use futures::Stream;
+use futures::{ready, Stream};
 use log::error;
 use pin_project::pin_project;
-use std::iter::FromIterator;
 use std::{
+    collections::{hash_map::Entry, HashMap},
     sync::{Arc, Mutex},
     task::Poll,
 };
-use tokio::task;
-use zookeeper::{WatchedEvent, WatchedEventType, Watcher, ZooKeeper};
+use zookeeper::{Stat, WatchedEvent, WatchedEventType, Watcher, ZkError};
 
+/// The watcher of `Zk`. The failures to list the children or to decode one
+/// are logged and skipped, as the instances stay as they were; `fallible`
+/// reports them.
 #[pin_project]
 pub struct ZkWatcher {
-    zk_client: Arc<ZooKeeper>,
+    zk_client: Arc<ZkClient>,
     #[pin]
-    watch_event_rx: mpsc::UnboundedReceiver<WatchEvent>,
+    watch_event_rx: mpsc::UnboundedReceiver<WatchItem>,
+    // stops resyncing once the watcher is dropped.
+    _resync: Option<AbortOnDrop>,
 }
 
+/// A `ZkWatcher` reporting its failures, see `ZkWatcher::fallible`.
+#[pin_project]
+pub struct FallibleZkWatcher {
+    #[pin]
+    inner: ZkWatcher,
+}
+
+type WatchItem = Result<WatchEvent, DiscoverError>;
+
 impl ZkWatcher {
-    pub fn new<D>(zk_client: Arc<ZooKeeper>, appid: &'static str, decoder: &'static D) -> Self
+    /// Watches the children of `path`, known to be `resume` already: they are
+    /// reported right away and the first listing only reports the difference.
+    pub(crate) fn new<EC, DC, I>(zk: &Zk<EC, DC, I>, path: String, resume: &[Arc<Instance>]) -> Self
     where
-        D: Decoder + Sync + 'static,
+        EC: Encoder + Send + Sync + 'static,
+        DC: Decoder + Send + Sync + 'static,
+        I: Identity + Send + Sync + 'static,
+        I::Key: Send,
     {
         let (watch_event_tx, watch_event_rx) = mpsc::unbounded();
+        let zk_client = zk.client.clone();
         let client = zk_client.clone();
+        let (identity, clock, payload) =
+            (zk.identity.clone(), zk.clock.clone(), zk.layout.payload());
+        let mut children = Children::default();
+        for ins in resume {
+            // the node names `ins` may have been reported from, as registering
+            // it names it.
+            let names = match payload {
+                Payload::Data => vec![zk.layout.node_name(ins)],
+                // its timestamps are either encoded or filled in from the node.
+                Payload::Name => {
+                    let unstamped = Instance {
+                        registered_at: None,
+                        last_renewed_at: None,
+                        ..Instance::clone(ins)
+                    };
+                    let encoder = zk.codec.get_encoder_ref();
+                    let mut names = [&**ins, &unstamped]
+                        .iter()
+                        .filter_map(|ins| encoder.encode(ins).ok())
+                        .filter_map(|raw| String::from_utf8(raw).ok())
+                        .collect::<Vec<_>>();
+                    names.dedup();
+                    names
+                }
+            };
+            if names.is_empty() {
+                continue;
+            }
+            // the names not listed are dropped as replaced, the instance is
+            // deleted once none is.
+            for raw in names {
+                *children.keys.entry(identity.identify(ins)).or_insert(0) += 1;
+                children.known.insert(raw, (Some(ins.clone()), 0));
+            }
+            let event = WatchEvent::with_clock(Event::Create(ins.clone()), &*clock);
+            let _ = watch_event_tx.unbounded_send(Ok(event));
+        }
+        let create_dir = if zk.layout.create_watch_dir() {
+            Some(zk.persistent_exist_node_path.clone())
+        } else {
+            None
+        };
 
-        task::spawn_blocking(move || {
-            let raw_instances = Arc::new(Mutex::new(HashSet::default()));
-            *raw_instances.lock().unwrap() = client
-                .get_children_w(
-                    appid,
-                    ZkAppWatchHandler {
-                        zk_client: client.clone(),
-                        raw_instances: raw_instances.clone(),
-                        watch_event_tx: watch_event_tx.clone(),
-                        decoder,
-                    },
-                )
-                .map(|children| HashSet::from_iter(children.into_iter()))
-                .unwrap_or(HashSet::default()); // todo error;
+        let handler = ZkAppWatchHandler {
+            zk_client: client.clone(),
+            children: Arc::new(Mutex::new(children)),
+            watch_event_tx,
+            codec: zk.codec.clone(),
+            identity,
+            clock,
+            data_payload: payload == Payload::Data,
+            sequential: zk.sequential,
+        };
+        let resync = zk.resync_interval.map(|interval| {
+            let (handler, path, workers) = (handler.clone(), path.clone(), zk.workers.clone());
+            let resync = async move {
+                loop {
+                    runtime::delay_for(interval).await;
+                    let (handler, path) = (handler.clone(), path.clone());
+                    let _ = workers.run(move || handler.resync(&path)).await;
+                }
+            };
+            runtime::spawn_abortable(resync)
+        });
+
+        // the watches are gone with an expired session.
+        client.on_session({
+            let (handler, path) = (handler.clone(), path.clone());
+            move |_| {
+                if handler.watch_event_tx.is_closed() {
+                    return false;
+                }
+                handler.rearm(&path);
+                true
+            }
+        });
+
+        zk.workers.spawn(move || {
+            if let Some(persistent_exist_node_path) = create_dir {
+                if let Err(e) = create_path(
+                    client.clone(),
+                    &path,
+                    Vec::new(),
+                    false,
+                    &persistent_exist_node_path,
+                ) {
+                    error!("failed to create {}. {}", path, e);
+                }
+            }
+            // Hold the lock until the initial children are reported, so a change
+            // notification racing with it is diffed against them.
+            let mut children = handler.children.lock().unwrap();
+            handler.watch_children(&mut children, &path);
         });
         Self {
             zk_client,
             watch_event_rx,
+            _resync: resync,
         }
     }
 }
 
+impl ZkWatcher {
+    /// Reports the failures to list the children or to decode one as errors,
+    /// e.g. for `AppDiscover::with_watcher` to return them. The watcher goes
+    /// on after them.
+    pub fn fallible(self) -> FallibleZkWatcher {
+        FallibleZkWatcher { inner: self }
+    }
+}
+
 impl Stream for ZkWatcher {
     type Item = WatchEvent;
 
@@ -57,84 +167,339 @@ impl Stream for ZkWatcher {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        self.as_mut().project().watch_event_rx.poll_next(cx)
+        let mut this = self.as_mut().project();
+        loop {
+            // logged when they happened.
+            match ready!(this.watch_event_rx.as_mut().poll_next(cx)) {
+                Some(Ok(event)) => return Poll::Ready(Some(event)),
+                Some(Err(_)) => {}
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl Stream for FallibleZkWatcher {
+    type Item = Result<WatchEvent, DiscoverError>;
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.project().inner.project().watch_event_rx.poll_next(cx)
     }
 }
 
-struct ZkAppWatchHandler<D>
+struct ZkAppWatchHandler<E, D, I>
 where
-    D: 'static,
+    I: Identity,
 {
-    zk_client: Arc<ZooKeeper>,
-    raw_instances: Arc<Mutex<HashSet<String>>>,
-    watch_event_tx: mpsc::UnboundedSender<WatchEvent>,
-    decoder: &'static D,
+    zk_client: Arc<ZkClient>,
+    children: Arc<Mutex<Children<I::Key>>>,
+    watch_event_tx: mpsc::UnboundedSender<WatchItem>,
+    codec: Arc<Codec<E, D>>,
+    identity: Arc<I>,
+    clock: Arc<dyn Clock>,
+    // whether the data of the children holds the instances, not their names.
+    data_payload: bool,
+    // whether the names end with the counter of sequential nodes.
+    sequential: bool,
+}
+
+// The children of the watched znode as last reported, so a notification only
+// decodes the new ones.
+struct Children<K> {
+    // the instance of each child, none when it doesn't decode, and the
+    // notification it was last seen in.
+    known: HashMap<String, (Option<Arc<Instance>>, u64)>,
+    // how many known instances have each identity.
+    keys: HashMap<K, usize>,
+    notification: u64,
+    // reused from one notification to the next.
+    created: Vec<Arc<Instance>>,
+    deleted: Vec<Arc<Instance>>,
 }
 
-impl<D> ZkAppWatchHandler<D>
+impl<K> Default for Children<K> {
+    fn default() -> Self {
+        Children {
+            known: HashMap::new(),
+            keys: HashMap::new(),
+            notification: 0,
+            created: Vec::new(),
+            deleted: Vec::new(),
+        }
+    }
+}
+
+impl<E, D, I> Clone for ZkAppWatchHandler<E, D, I>
 where
-    D: Decoder,
+    I: Identity,
 {
-    fn diff_and_send_watch_event(&self, new_instances: HashSet<String>) {
-        let (created_diff, deleted_diff) = {
-            let mut old_instance = self.raw_instances.lock().unwrap();
-            let diff = (
-                new_instances
-                    .difference(&old_instance)
-                    .cloned()
-                    .collect::<Vec<String>>(),
-                old_instance
-                    .difference(&new_instances)
-                    .cloned()
-                    .collect::<Vec<String>>(),
-            );
-            *old_instance = new_instances;
-            diff
-        };
-        let created_instances_iter = created_diff.iter().filter_map(|ins| {
-            decode_instance(ins, self.decoder).map(|ins| WatchEvent::new(Event::Create(ins)))
+    fn clone(&self) -> Self {
+        ZkAppWatchHandler {
+            zk_client: self.zk_client.clone(),
+            children: self.children.clone(),
+            watch_event_tx: self.watch_event_tx.clone(),
+            codec: self.codec.clone(),
+            identity: self.identity.clone(),
+            clock: self.clock.clone(),
+            data_payload: self.data_payload,
+            sequential: self.sequential,
+        }
+    }
+}
+
+impl<E, D, I> ZkAppWatchHandler<E, D, I>
+where
+    E: Encoder + Send + Sync + 'static,
+    D: Decoder + Send + Sync + 'static,
+    I: Identity + Send + Sync + 'static,
+    I::Key: Send,
+{
+    // watches the children of `path` again, reporting what changed.
+    fn watch_children(&self, children: &mut Children<I::Key>, path: &str) {
+        match self.zk_client.get_children_w(path, self.clone()) {
+            Ok(new_children) => self.send_diff(children, path, new_children),
+            Err(ZkError::NoNode) => self.send_diff(children, path, Vec::new()),
+            // the instances stay as they were until the next resync.
+            Err(e) => {
+                error!("failed to watch {}. {}", path, e);
+                self.fail(ZkRegError::GetChildren(e));
+            }
+        }
+    }
+
+    fn send(&self, event: WatchEvent) {
+        let _ = self.watch_event_tx.unbounded_send(Ok(event));
+    }
+
+    fn fail(&self, e: impl Into<DiscoverError>) {
+        let _ = self.watch_event_tx.unbounded_send(Err(e.into()));
+    }
+
+    fn send_diff(&self, children: &mut Children<I::Key>, path: &str, new_children: Vec<String>) {
+        children.notification += 1;
+        let notification = children.notification;
+        for raw in new_children {
+            match children.known.get_mut(&raw) {
+                Some((_, seen)) => *seen = notification,
+                None => {
+                    let ins = self.decode_child(path, &raw);
+                    if let Some(ins) = &ins {
+                        *children
+                            .keys
+                            .entry(self.identity.identify(ins))
+                            .or_insert(0) += 1;
+                        children.created.push(ins.clone());
+                    }
+                    children.known.insert(raw, (ins, notification));
+                }
+            }
+        }
+        let Children {
+            known,
+            keys,
+            created,
+            deleted,
+            ..
+        } = children;
+        known.retain(|_, (ins, seen)| {
+            if *seen == notification {
+                return true;
+            }
+            deleted.extend(ins.take());
+            false
         });
-        let deleted_instances_iter = deleted_diff.iter().filter_map(|ins| {
-            decode_instance(ins, self.decoder).map(|ins| WatchEvent::new(Event::Delete(ins)))
+        // A node replaced by one with the same identity (e.g. re-registered with new
+        // metadata) is only reported as a Create, so consumers keyed by identity
+        // don't drop the instance right after inserting its new version. The new
+        // node may have shown up in an earlier notification.
+        deleted.retain(|ins| match keys.entry(self.identity.identify(ins)) {
+            Entry::Occupied(mut count) if *count.get() > 1 => {
+                *count.get_mut() -= 1;
+                false
+            }
+            Entry::Occupied(count) => {
+                count.remove();
+                true
+            }
+            Entry::Vacant(_) => true,
         });
+        let created_instances_iter = created
+            .drain(..)
+            .map(|ins| WatchEvent::with_clock(Event::Create(ins), &*self.clock));
+        let deleted_instances_iter = deleted
+            .drain(..)
+            .map(|ins| WatchEvent::with_clock(Event::Delete(ins), &*self.clock));
         for event in created_instances_iter.chain(deleted_instances_iter) {
-            self.watch_event_tx.unbounded_send(event);
+            self.send(event);
+        }
+    }
+
+    // watches the children again in a new session, reporting what changed
+    // while they weren't.
+    fn rearm(&self, path: &str) {
+        let mut children = self.children.lock().unwrap();
+        let before = children.known.keys().cloned().collect::<Vec<_>>();
+        match self.zk_client.get_children_w(path, self.clone()) {
+            Ok(new_children) => self.send_diff(&mut children, path, new_children),
+            Err(e) => {
+                error!("failed to watch {} again. {}", path, e);
+                return self.fail(ZkRegError::GetChildren(e));
+            }
+        }
+        drop(children);
+        // the data of the children known before, the new ones are watched.
+        if self.data_payload {
+            for raw in before {
+                self.update_child(&format!("{}/{}", path, raw));
+            }
+        }
+    }
+
+    // lists the children again, reporting what the notifications missed.
+    fn resync(&self, path: &str) {
+        let mut children = self.children.lock().unwrap();
+        match self.zk_client.get_children(path, false) {
+            Ok(new_children) => self.send_diff(&mut children, path, new_children),
+            Err(ZkError::NoNode) => self.send_diff(&mut children, path, Vec::new()),
+            // the instances stay as they were until the next resync.
+            Err(e) => {
+                error!("failed to resync {}. {}", path, e);
+                self.fail(ZkRegError::GetChildren(e));
+            }
+        }
+    }
+
+    // the instance of a new child, with the data fetched when it holds it.
+    fn decode_child(&self, path: &str, raw: &str) -> Option<Arc<Instance>> {
+        let child = format!("{}/{}", path, raw);
+        let decoder = self.codec.get_decoder_ref();
+        let (mut ins, stat) = if self.data_payload {
+            // watched to report updates in place, see `Registry::update_if`.
+            match self.zk_client.get_data_w(&child, self.clone()) {
+                Ok((data, stat)) => (self.decode(&data, decoder)?, Some(stat)),
+                // deleted meanwhile, reported with the next listing.
+                Err(ZkError::NoNode) => return None,
+                Err(e) => {
+                    error!("failed to get the data of {}. {}", child, e);
+                    self.fail(DiscoverError::registry(e));
+                    return None;
+                }
+            }
+        } else {
+            let name = if self.sequential {
+                strip_sequence(raw)
+            } else {
+                raw
+            };
+            (self.decode(name.as_bytes(), decoder)?, None)
+        };
+        match stat {
+            Some(stat) => fill_from_stat(&mut ins, &stat),
+            None => fill_named(&mut ins),
         }
+        Some(Arc::new(ins))
+    }
+
+    fn decode(&self, data: &[u8], decoder: &D) -> Option<Instance> {
+        decode_instance(data, decoder)
+            .map_err(|e| self.fail(e))
+            .ok()
+    }
+
+    // reports the new data of a child as an Update, or as a Create of another
+    // instance when it changed identity.
+    fn update_child(&self, child: &str) {
+        let (path, raw) = match child.rfind('/') {
+            Some(pos) => (&child[..pos], &child[pos + 1..]),
+            None => return,
+        };
+        let mut children = self.children.lock().unwrap();
+        let Children { known, keys, .. } = &mut *children;
+        let known = match known.get_mut(raw) {
+            Some((known, _)) => known,
+            // deleted meanwhile.
+            None => return,
+        };
+        let ins = self.decode_child(path, raw);
+        if ins == *known {
+            return;
+        }
+        let old = std::mem::replace(known, ins.clone());
+        if let (Some(ins), Some(old)) = (&ins, &old) {
+            if self.identity.identify(ins) == self.identity.identify(old) {
+                let event = WatchEvent::with_clock(Event::Update(ins.clone()), &*self.clock);
+                self.send(event.with_delta(old));
+                return;
+            }
+        }
+        if let Some(ins) = &ins {
+            *keys.entry(self.identity.identify(ins)).or_insert(0) += 1;
+            let event = WatchEvent::with_clock(Event::Create(ins.clone()), &*self.clock);
+            self.send(event);
+        }
+        let old = match old {
+            Some(old) => old,
+            None => return,
+        };
+        if let Entry::Occupied(mut count) = keys.entry(self.identity.identify(&old)) {
+            *count.get_mut() -= 1;
+            if *count.get() > 0 {
+                return;
+            }
+            count.remove();
+        }
+        let event = WatchEvent::with_clock(Event::Delete(old), &*self.clock);
+        self.send(event);
     }
 }
 
-impl<D> Watcher for ZkAppWatchHandler<D>
+// znode stats carry the creation and last modification time of the
+// registration, and its version.
+pub(super) fn fill_from_stat(ins: &mut Instance, stat: &Stat) {
+    ins.registered_at
+        .get_or_insert_with(|| from_unix_millis(stat.ctime as u64));
+    ins.last_renewed_at
+        .get_or_insert_with(|| from_unix_millis(stat.mtime as u64));
+    ins.revision = Some(stat.version as u64);
+}
+
+// nodes named after their instance are never set, so at their first version.
+// Their stats would cost a call per child, they only carry the timestamps
+// their names do.
+pub(super) fn fill_named(ins: &mut Instance) {
+    ins.revision = Some(0);
+}
+
+impl<E, D, I> Watcher for ZkAppWatchHandler<E, D, I>
 where
-    D: Decoder + Sync,
+    E: Encoder + Send + Sync + 'static,
+    D: Decoder + Send + Sync + 'static,
+    I: Identity + Send + Sync + 'static,
+    I::Key: Send,
 {
     fn handle(&self, we: WatchedEvent) {
-        if let (WatchedEventType::NodeChildrenChanged, Some(path)) = (we.event_type, we.path) {
-            // the children of a watched znode are created or deleted.
-            let new_instances = self
-                .zk_client
-                .get_children_w(
-                    path.as_str(),
-                    ZkAppWatchHandler {
-                        zk_client: self.zk_client.clone(),
-                        raw_instances: self.raw_instances.clone(),
-                        watch_event_tx: self.watch_event_tx.clone(),
-                        decoder: self.decoder,
-                    },
-                )
-                .map(|children| HashSet::from_iter(children.into_iter()))
-                .unwrap_or(HashSet::default()); // todo error
-            self.diff_and_send_watch_event(new_instances);
+        match (we.event_type, we.path) {
+            (WatchedEventType::NodeChildrenChanged, Some(path)) => {
+                // the children of a watched znode are created or deleted.
+                let mut children = self.children.lock().unwrap();
+                self.watch_children(&mut children, &path);
+            }
+            (WatchedEventType::NodeDataChanged, Some(path)) => self.update_child(&path),
+            _ => {}
         }
     }
 }
 
 #[inline]
-fn decode_instance<D: Decoder>(ins: &str, decoder: &D) -> Option<Instance> {
-    match decoder.decode(ins.as_bytes()) {
-        Ok(ins) => Some(ins),
-        Err(e) => {
-            error!("instance decode error. {}", e.to_string());
-            None
-        }
-    }
+pub(super) fn decode_instance<D: Decoder>(
+    ins: &[u8],
+    decoder: &D,
+) -> Result<Instance, DecodeError> {
+    decode_with_payload(decoder, ins).map_err(|e| {
+        error!("instance decode error. {}", e);
+        e
+    })
 }
@@ -1,86 +1,35 @@
-use discover::codec::DEFAULT_CODEC;
-use discover::zk::Zk;
-use discover::{watcher::Event, Instance, Registry};
-use futures::stream::{self, StreamExt};
-use std::io::{BufRead, BufReader, Write};
-use std::pin::Pin;
-use std::process::{Child, Command, Stdio};
-use std::time::Duration;
-use zookeeper::ZooKeeper;
-
-pub struct ZkCluster {
-    process: Child,
-    connect_string: String,
-    closed: bool,
-}
-
-impl ZkCluster {
-    fn start(instances: usize) -> ZkCluster {
-        let mut process = match Command::new("java")
-            .arg("-jar")
-            .arg("zk-test-cluster/target/main.jar")
-            .arg(instances.to_string())
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-        {
-            Ok(p) => p,
-            Err(e) => panic!("failed to start ZkCluster: {}", e),
-        };
-        let connect_string = Self::read_connect_string(&mut process);
-        ZkCluster {
-            process,
-            connect_string,
-            closed: false,
-        }
-    }
-
-    fn read_connect_string(process: &mut Child) -> String {
-        let mut reader = BufReader::new(process.stdout.as_mut().unwrap());
-        let mut connect_string = String::new();
-        if reader.read_line(&mut connect_string).is_err() {
-            panic!("Couldn't read ZK connect_string")
-        }
-        connect_string.pop(); // remove '\n'
-        connect_string
-    }
-
-    fn kill_an_instance(&mut self) {
-        self.process.stdin.as_mut().unwrap().write(b"k").unwrap();
-    }
-
-    fn shutdown(&mut self) {
-        if !self.closed {
-            self.process.stdin.as_mut().unwrap().write(b"q").unwrap();
-            assert!(self.process.wait().unwrap().success());
-            self.closed = true
-        }
-    }
-}
-
-impl Drop for ZkCluster {
-    fn drop(&mut self) {
-        self.shutdown()
-    }
-}
+use discover::codec::{
+    new_dubbo_codec, new_spring_cloud_codec, service_instance_id, Codec, DefaultDecoder,
+    DefaultEncoder, MultiDecoder, MultiEncoder,
+};
+use discover::control::{watch_controlled, Control, ControlParams};
+use discover::election::{Election, Leadership};
+use discover::identity::DefaultIdentity;
+use discover::kv::{watch_value, KeyEvent, KvStore, Utf8};
+use discover::testing::{expect_create, expect_delete, expect_quiescent, expect_update, ZkServer};
+use discover::zk::{DubboLayout, SpringCloudLayout, Zk, ZkRegError};
+use discover::{AppDiscover, DiscoverError, Instance, Registry};
+use futures::{future::poll_fn, StreamExt};
+use smallvec::smallvec;
+use std::{pin::Pin, sync::Arc, time::Duration};
+use tower::discover::{Change, Discover};
+use zookeeper::{Acl, CreateMode, ZooKeeper};
 
 #[cfg(test)]
 #[tokio::test(threaded_scheduler)]
 async fn test_register_deregister() {
-    let cluster = ZkCluster::start(3);
-    let zk = Zk::new(
-        &cluster.connect_string,
-        Duration::from_millis(3000),
-        &DEFAULT_CODEC,
-    )
-    .await;
-
-    let ins = Instance {
-        zone: "sh1".to_owned(),
-        env: "test".to_owned(),
-        appid: "/dubbo-rs/provider".to_owned(),
+    let server = ZkServer::start().unwrap();
+    let zk = Zk::builder(&server.connect_string())
+        .build()
+        .await
+        .unwrap();
+
+    let ins = Arc::new(Instance {
+        zone: "sh1".into(),
+        env: "test".into(),
+        appid: "/dubbo-rs/provider".into(),
         hostname: "myhostname".to_owned(),
-        addrs: vec![
+        addrs: smallvec![
             "http://172.1.1.1:8000".to_owned(),
             "grpc://172.1.1.1:9999".to_owned(),
         ],
@@ -89,12 +38,13 @@ async fn test_register_deregister() {
             .iter()
             .cloned()
             .collect(),
-    };
+        ..Default::default()
+    });
 
-    let _ = zk.register(ins.clone()).await.unwrap();
+    zk.register(ins.clone()).await.unwrap();
 
     let zk_client =
-        ZooKeeper::connect(&cluster.connect_string, Duration::from_millis(3000), |_| {}).unwrap();
+        ZooKeeper::connect(&server.connect_string(), Duration::from_millis(3000), |_| {}).unwrap();
     let path = "/dubbo-rs/provider/zone=sh1&env=test&appid=%2Fdubbo-rs%2Fprovider&hostname=myhostname&addrs=http%3A%2F%2F172.1.1.1%3A8000&addrs=grpc%3A%2F%2F172.1.1.1%3A9999&version=111&metadata=%7B%22weight%22%3A%2210%22%7D";
     assert!(zk_client.exists(path, false).unwrap().is_some());
 
@@ -102,24 +52,122 @@ async fn test_register_deregister() {
     assert!(zk_client.exists(path, false).unwrap().is_none());
 }
 
+#[tokio::test(threaded_scheduler)]
+async fn test_register_all_rolls_back() {
+    let server = ZkServer::start().unwrap();
+    let zk = Zk::builder(&server.connect_string())
+        .build()
+        .await
+        .unwrap();
+    let instance = |appid: &str| {
+        Arc::new(Instance {
+            appid: appid.into(),
+            addrs: smallvec!["grpc://172.1.1.1:9999".to_owned()],
+            ..Default::default()
+        })
+    };
+    let (a, b) = (
+        instance("/rollback/provider"),
+        instance("/dubbo-rs/provider"),
+    );
+    zk.register(b.clone()).await.unwrap();
+
+    // b exists already, a and the parents created for it are deleted again.
+    assert!(zk.register_all(vec![a, b.clone()]).await.is_err());
+    let zk_client =
+        ZooKeeper::connect(&server.connect_string(), Duration::from_millis(3000), |_| {}).unwrap();
+    assert!(zk_client.exists("/rollback", false).unwrap().is_none());
+    assert_eq!(zk.list("/dubbo-rs/provider").await.unwrap().len(), 1);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_sequential() {
+    let server = ZkServer::start().unwrap();
+    let connect = || {
+        Zk::builder(&server.connect_string())
+            .sequential(true)
+            .build()
+    };
+    let (zk, other) = (connect().await.unwrap(), connect().await.unwrap());
+    let ins = Arc::new(Instance {
+        appid: "/dubbo-rs/provider".into(),
+        addrs: smallvec!["grpc://172.1.1.1:9999".to_owned()],
+        ..Default::default()
+    });
+
+    // registered twice without conflicting, decoded without the counter.
+    zk.register(ins.clone()).await.unwrap();
+    other.register(ins.clone()).await.unwrap();
+    let listed = zk.list("/dubbo-rs/provider").await.unwrap();
+    assert_eq!(listed.len(), 2);
+    assert!(listed.iter().all(|listed| listed.addrs == ins.addrs));
+
+    other.deregister(&ins).await.unwrap();
+    assert_eq!(zk.list("/dubbo-rs/provider").await.unwrap().len(), 1);
+
+    // registered twice by the same registry, both again in a new session,
+    // each deregistration deleting one of them.
+    zk.register(ins.clone()).await.unwrap();
+    server.expire_sessions();
+    let mut listed = Vec::new();
+    for _ in 0..50 {
+        listed = zk.list("/dubbo-rs/provider").await.unwrap_or_default();
+        if listed.len() == 2 {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+    }
+    assert_eq!(listed.len(), 2);
+    zk.deregister(&ins).await.unwrap();
+    assert_eq!(zk.list("/dubbo-rs/provider").await.unwrap().len(), 1);
+    zk.deregister(&ins).await.unwrap();
+    assert!(zk.list("/dubbo-rs/provider").await.unwrap().is_empty());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_root_prefix() {
+    let server = ZkServer::start().unwrap();
+    let zk = Zk::builder(&server.connect_string())
+        .root_prefix("/discovery/prod/")
+        .build()
+        .await
+        .unwrap();
+    let mut watcher = zk.watch("/dubbo-rs/provider");
+
+    let ins = Arc::new(Instance {
+        appid: "/dubbo-rs/provider".into(),
+        addrs: smallvec!["grpc://172.1.1.1:9999".to_owned()],
+        ..Default::default()
+    });
+    zk.register(ins.clone()).await.unwrap();
+    let created = expect_create(&mut watcher, |_| true, Duration::from_secs(5)).await;
+    assert_eq!(created, ins);
+
+    let zk_client =
+        ZooKeeper::connect(&server.connect_string(), Duration::from_millis(3000), |_| {}).unwrap();
+    let children = zk_client
+        .get_children("/discovery/prod/dubbo-rs/provider", false)
+        .unwrap();
+    assert_eq!(children.len(), 1);
+    assert!(zk_client.exists("/dubbo-rs", false).unwrap().is_none());
+}
+
 #[tokio::test(threaded_scheduler)]
 async fn test_watch() {
-    let cluster = ZkCluster::start(3);
+    let server = ZkServer::start().unwrap();
 
-    let zk = Zk::new(
-        &cluster.connect_string,
-        Duration::from_millis(3000),
-        &DEFAULT_CODEC,
-    )
-    .await;
+    let zk = Zk::builder(&server.connect_string())
+        .build()
+        .await
+        .unwrap();
 
     let app_id = "/dubbo-rs/provider";
-    let ins1 = Instance {
-        zone: "sh1".to_owned(),
-        env: "test".to_owned(),
-        appid: app_id.to_owned(),
+    let ins1 = Arc::new(Instance {
+        zone: "sh1".into(),
+        env: "test".into(),
+        appid: app_id.into(),
         hostname: "myhostname".to_owned(),
-        addrs: vec![
+        addrs: smallvec![
             "http://172.1.1.1:8000".to_owned(),
             "grpc://172.1.1.1:9999".to_owned(),
         ],
@@ -128,14 +176,15 @@ async fn test_watch() {
             .iter()
             .cloned()
             .collect(),
-    };
+        ..Default::default()
+    });
 
-    let ins2 = Instance {
-        zone: "sh1".to_owned(),
-        env: "test".to_owned(),
-        appid: app_id.to_owned(),
+    let ins2 = Arc::new(Instance {
+        zone: "sh1".into(),
+        env: "test".into(),
+        appid: app_id.into(),
         hostname: "myhostname".to_owned(),
-        addrs: vec![
+        addrs: smallvec![
             "http://172.1.1.2:8000".to_owned(),
             "grpc://172.1.1.2:9999".to_owned(),
         ],
@@ -144,39 +193,386 @@ async fn test_watch() {
             .iter()
             .cloned()
             .collect(),
-    };
+        ..Default::default()
+    });
 
     let _ = zk.register(ins1.clone()).await;
 
     let mut watcher = zk.watch(app_id);
+    let within = Duration::from_secs(5);
 
     let _ = zk.register(ins2.clone()).await;
 
+    let created2 = expect_create(&mut watcher, |ins| ins.addrs == ins2.addrs, within).await;
+    // named after the instance, the node has no other timestamps to report.
+    assert_eq!(created2.registered_at, None);
+    assert_eq!(created2.revision, Some(0));
+    assert_eq!(
+        Instance {
+            revision: None,
+            ..Instance::clone(&created2)
+        },
+        *ins2
+    );
+
     let _ = zk.deregister(&ins1).await;
+    let ins = expect_delete(&mut watcher, |ins| ins.addrs == ins1.addrs, within).await;
+    assert_eq!(
+        Instance {
+            revision: None,
+            ..Instance::clone(&ins)
+        },
+        *ins1
+    );
 
+    // a Delete reports the instance its Create did.
     let _ = zk.deregister(&ins2).await;
+    let ins = expect_delete(&mut watcher, |ins| ins.addrs == ins2.addrs, within).await;
+    assert!(Arc::ptr_eq(&ins, &created2));
+    expect_quiescent(&mut watcher, Duration::from_millis(100)).await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_watch_registered_before() {
+    let server = ZkServer::start().unwrap();
+    let zk = Zk::builder(&server.connect_string())
+        .build()
+        .await
+        .unwrap();
+    let ins = Arc::new(Instance {
+        appid: "/dubbo-rs/provider".into(),
+        addrs: smallvec!["grpc://172.1.1.1:9999".to_owned()],
+        ..Default::default()
+    });
+    zk.register(ins.clone()).await.unwrap();
+
+    // reported when the watch is set, like by the other registries.
+    let mut watcher = zk.watch("/dubbo-rs/provider");
+    let within = Duration::from_secs(5);
+    let created = expect_create(&mut watcher, |_| true, within).await;
+    assert_eq!(created.addrs, ins.addrs);
+
+    zk.deregister(&ins).await.unwrap();
+    let deleted = expect_delete(&mut watcher, |_| true, within).await;
+    assert!(Arc::ptr_eq(&deleted, &created));
+    expect_quiescent(&mut watcher, Duration::from_millis(100)).await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_multi_codec() {
+    let server = ZkServer::start().unwrap();
+    let codec = Codec::new(
+        MultiEncoder::new(1, DefaultEncoder),
+        MultiDecoder::new(DefaultDecoder).version(1, DefaultDecoder),
+    );
+    let zk = Zk::builder(&server.connect_string())
+        .codec(codec)
+        .build()
+        .await
+        .unwrap();
+    let mut watcher = zk.watch("/dubbo-rs/provider");
+
+    // enveloped in the node name of the default layout.
+    let ins = Arc::new(Instance {
+        appid: "/dubbo-rs/provider".into(),
+        addrs: smallvec!["grpc://172.1.1.1:9999".to_owned()],
+        ..Default::default()
+    });
+    zk.register(ins.clone()).await.unwrap();
+    let children = server.children("/dubbo-rs/provider");
+    assert_eq!(children.len(), 1);
+    assert!(children[0].starts_with("v1;"));
+
+    let created = expect_create(&mut watcher, |_| true, Duration::from_secs(5)).await;
+    assert_eq!(created.addrs, ins.addrs);
+    zk.deregister(&ins).await.unwrap();
+    let deleted = expect_delete(&mut watcher, |_| true, Duration::from_secs(5)).await;
+    assert_eq!(deleted.addrs, ins.addrs);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_watch_from() {
+    let server = ZkServer::start().unwrap();
+    let zk = Zk::builder(&server.connect_string())
+        .build()
+        .await
+        .unwrap();
+    let instance = |addr: &str| {
+        Arc::new(Instance {
+            appid: "/dubbo-rs/provider".into(),
+            addrs: smallvec![addr.to_owned()],
+            ..Default::default()
+        })
+    };
+    let (kept, gone, new) = (
+        instance("grpc://172.1.1.1:9999"),
+        instance("grpc://172.1.1.2:9999"),
+        instance("grpc://172.1.1.3:9999"),
+    );
+    zk.register(kept.clone()).await.unwrap();
+    zk.register(new.clone()).await.unwrap();
+
+    let mut watcher = zk.watch_from("/dubbo-rs/provider", &[kept.clone(), gone.clone()]);
+    let within = Duration::from_secs(5);
+    let resumed = expect_create(&mut watcher, |ins| ins.addrs == kept.addrs, within).await;
+    assert!(Arc::ptr_eq(&resumed, &kept));
+    expect_create(&mut watcher, |ins| ins.addrs == gone.addrs, within).await;
+    // then only the difference with what is registered.
+    expect_create(&mut watcher, |ins| ins.addrs == new.addrs, within).await;
+    let deleted = expect_delete(&mut watcher, |_| true, within).await;
+    assert_eq!(deleted, gone);
+    expect_quiescent(&mut watcher, Duration::from_millis(100)).await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_dubbo_layout() {
+    let server = ZkServer::start().unwrap();
+    let zk = Zk::builder(&server.connect_string())
+        .codec(new_dubbo_codec())
+        .build()
+        .await
+        .unwrap()
+    .with_layout(DubboLayout::new());
+
+    let service = "org.apache.dubbo.demo.DemoService";
+    // a consumer subscribing before any provider is registered.
+    let mut watcher = zk.watch(service);
+    expect_quiescent(&mut watcher, Duration::from_millis(100)).await;
+    assert!(server.get("/dubbo/org.apache.dubbo.demo.DemoService/providers").is_some());
+
+    let ins = Arc::new(Instance {
+        appid: service.into(),
+        addrs: vec!["dubbo://172.1.1.1:20880".to_owned()].into(),
+        version: "1.0.0".to_owned(),
+        metadata: [("side".to_owned(), "provider".to_owned())]
+            .iter()
+            .cloned()
+            .collect(),
+        ..Default::default()
+    });
+    zk.register(ins.clone()).await.unwrap();
+    assert_eq!(
+        server.children("/dubbo/org.apache.dubbo.demo.DemoService/providers"),
+        vec!["dubbo%3A%2F%2F172.1.1.1%3A20880%2Forg.apache.dubbo.demo.DemoService%3Finterface%3Dorg.apache.dubbo.demo.DemoService%26side%3Dprovider%26version%3D1.0.0"]
+    );
+    let created = expect_create(&mut watcher, |_| true, Duration::from_secs(5)).await;
+    assert_eq!(created.addrs, ins.addrs);
+    assert_eq!(created.metadata, ins.metadata);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_spring_cloud_layout() {
+    let server = ZkServer::start().unwrap();
+    let zk = Zk::builder(&server.connect_string())
+        .codec(new_spring_cloud_codec())
+        .build()
+        .await
+        .unwrap()
+    .with_layout(SpringCloudLayout::new());
+
+    let mut watcher = zk.watch("provider");
+    let ins = Arc::new(Instance {
+        appid: "provider".into(),
+        addrs: vec!["http://172.1.1.1:8080".to_owned()].into(),
+        ..Default::default()
+    });
+    zk.register(ins.clone()).await.unwrap();
+    let path = format!("/services/provider/{}", service_instance_id(&ins));
+    assert!(server.get(&path).unwrap().starts_with(b"{"));
+
+    let created = expect_create(&mut watcher, |_| true, Duration::from_secs(5)).await;
+    assert_eq!(created.addrs, ins.addrs);
+
+    zk.deregister(&ins).await.unwrap();
+    assert!(server.get(&path).is_none());
+    let deleted = expect_delete(&mut watcher, |_| true, Duration::from_secs(5)).await;
+    assert_eq!(deleted.addrs, ins.addrs);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_update_if() {
+    let server = ZkServer::start().unwrap();
+    let zk = Zk::builder(&server.connect_string())
+        .codec(new_spring_cloud_codec())
+        .build()
+        .await
+        .unwrap()
+    .with_layout(SpringCloudLayout::new());
+
+    let mut watcher = zk.watch("provider");
+    let ins = Arc::new(Instance {
+        appid: "provider".into(),
+        addrs: vec!["http://172.1.1.1:8080".to_owned()].into(),
+        ..Default::default()
+    });
+    zk.register(ins.clone()).await.unwrap();
+    let created = expect_create(&mut watcher, |_| true, Duration::from_secs(5)).await;
+    assert_eq!(created.revision, Some(0));
+
+    let mut weighted = Instance::clone(&ins);
+    weighted.metadata.insert("weight".to_owned(), "10".to_owned());
+    zk.update_if(Arc::new(weighted.clone()), 0).await.unwrap();
+    let updated = expect_update(&mut watcher, |_| true, Duration::from_secs(5)).await;
+    assert_eq!(updated.weight(), Some(10));
+    assert_eq!(updated.revision, Some(1));
+
+    match zk.update_if(Arc::new(weighted), 0).await {
+        Err(ZkRegError::Conflict) => {}
+        updated => panic!("stale update not rejected: {:?}", updated),
+    }
+    expect_quiescent(&mut watcher, Duration::from_millis(100)).await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_update_if_registered_again() {
+    let server = ZkServer::start().unwrap();
+    let zk = Zk::builder(&server.connect_string())
+        .codec(new_spring_cloud_codec())
+        .build()
+        .await
+        .unwrap()
+    .with_layout(SpringCloudLayout::new());
 
-    let first_event = watcher.next().await;
-    assert!(first_event.is_some());
-    let first_event = first_event.unwrap();
-    assert!(matches!(first_event.event, Event::Create(..)));
-    if let Event::Create(ins) = first_event.event {
-        assert_eq!(ins, ins2);
+    let ins = Arc::new(Instance {
+        appid: "provider".into(),
+        addrs: vec!["http://172.1.1.1:8080".to_owned()].into(),
+        ..Default::default()
+    });
+    zk.register(ins.clone()).await.unwrap();
+    let mut weighted = Instance::clone(&ins);
+    weighted.metadata.insert("weight".to_owned(), "10".to_owned());
+    zk.update_if(Arc::new(weighted), 0).await.unwrap();
+
+    // a new session registers the update, not the instance first registered.
+    server.expire_sessions();
+    let path = format!("/services/provider/{}", service_instance_id(&ins));
+    let mut registered = None;
+    for _ in 0..50 {
+        registered = server.get(&path);
+        if registered.is_some() {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(100)).await;
     }
+    let listed = zk.list("provider").await.unwrap();
+    assert!(registered.is_some());
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].weight(), Some(10));
+}
 
-    let second_event = watcher.next().await;
-    assert!(second_event.is_some());
-    let second_event = second_event.unwrap();
-    assert!(matches!(second_event.event, Event::Delete(..)));
-    if let Event::Delete(ins) = second_event.event {
-        assert_eq!(ins, ins1);
+#[tokio::test(threaded_scheduler)]
+async fn test_deregister_identified() {
+    let server = ZkServer::start().unwrap();
+    let zk = Zk::builder(&server.connect_string())
+        .build()
+        .await
+        .unwrap();
+    let ins = Arc::new(Instance {
+        appid: "/dubbo-rs/provider".into(),
+        addrs: smallvec!["grpc://172.1.1.1:9999".to_owned()],
+        ..Default::default()
+    });
+    zk.register(ins.clone()).await.unwrap();
+
+    // the same instance by identity, though since given a revision.
+    let mut revised = Instance::clone(&ins);
+    revised.revision = Some(3);
+    zk.deregister(&Arc::new(revised)).await.unwrap();
+    assert!(zk.list("/dubbo-rs/provider").await.unwrap().is_empty());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_discover_errors() {
+    let server = ZkServer::start().unwrap();
+    let zk = Zk::builder(&server.connect_string())
+        .build()
+        .await
+        .unwrap();
+    let ins = Arc::new(Instance {
+        appid: "/dubbo-rs/provider".into(),
+        addrs: smallvec!["grpc://172.1.1.1:9999".to_owned()],
+        ..Default::default()
+    });
+    zk.register(ins.clone()).await.unwrap();
+    let mut discover = AppDiscover::<_, Zk<DefaultEncoder, DefaultDecoder>, _, _>::with_watcher(
+        zk.watch("/dubbo-rs/provider").fallible(),
+        |ins: &Instance| ins.addrs[0].clone(),
+        DefaultIdentity,
+    );
+    match poll_fn(|cx| Pin::new(&mut discover).poll_discover(cx)).await {
+        Ok(Change::Insert(_, service)) => assert_eq!(service, "grpc://172.1.1.1:9999"),
+        _ => panic!("expected an insert"),
     }
 
-    let third_event = watcher.next().await;
-    assert!(third_event.is_some());
-    let third_event = third_event.unwrap();
-    assert!(matches!(third_event.event, Event::Delete(..)));
-    if let Event::Delete(ins) = third_event.event {
-        assert_eq!(ins, ins2);
+    // registered by something else, in a format it doesn't decode.
+    let zk_client =
+        ZooKeeper::connect(&server.connect_string(), Duration::from_millis(3000), |_| {}).unwrap();
+    zk_client
+        .create(
+            "/dubbo-rs/provider/appid=provider&registered_at=yesterday",
+            Vec::new(),
+            Acl::open_unsafe().clone(),
+            CreateMode::Ephemeral,
+        )
+        .unwrap();
+    assert!(matches!(
+        poll_fn(|cx| Pin::new(&mut discover).poll_discover(cx)).await,
+        Err(DiscoverError::Decode(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_control() {
+    let server = ZkServer::start().unwrap();
+    let zk = Zk::builder(&server.connect_string()).build().await.unwrap();
+
+    let mut control = zk.watch_control("provider");
+    assert_eq!(control.next().await.unwrap(), ControlParams::default());
+    let params = ControlParams::from_json(r#"{"metadata": {"weight": "5"}}"#).unwrap();
+    zk.set_control("provider", &params).await.unwrap();
+    assert_eq!(control.next().await.unwrap(), params);
+
+    zk.register(Arc::new(Instance {
+        appid: "provider".into(),
+        addrs: vec!["grpc://172.1.1.1:9999".to_owned()].into(),
+        ..Default::default()
+    }))
+    .await
+    .unwrap();
+    let mut watcher = watch_controlled(&zk, "provider");
+    let created = expect_create(&mut watcher, |_| true, Duration::from_secs(5)).await;
+    assert_eq!(created.weight(), Some(5));
+}
+
+#[tokio::test]
+async fn test_election() {
+    let server = ZkServer::start().unwrap();
+    let zk = Zk::builder(&server.connect_string()).build().await.unwrap();
+
+    let mut first = zk.campaign("reconciler", "host-1");
+    let token = match first.next().await.unwrap().unwrap() {
+        Leadership::Acquired { token } => token,
+        lost => panic!("unexpected {:?}", lost),
+    };
+    let mut second = zk.campaign("reconciler", "host-2");
+    let waiting = tokio::time::timeout(Duration::from_millis(100), second.next());
+    assert!(waiting.await.is_err());
+
+    drop(first);
+    match second.next().await.unwrap().unwrap() {
+        Leadership::Acquired { token: next } => assert!(next > token),
+        lost => panic!("unexpected {:?}", lost),
     }
 }
+
+#[tokio::test]
+async fn test_kv() {
+    let server = ZkServer::start().unwrap();
+    let zk = Zk::builder(&server.connect_string()).build().await.unwrap();
+
+    let mut limits = watch_value(&zk, "/config/billing/limits", Utf8);
+    zk.set_key("/config/billing/limits", b"100".to_vec()).await.unwrap();
+    assert_eq!(limits.next().await.unwrap().unwrap(), KeyEvent::Set("100".to_owned()));
+    zk.delete_key("/config/billing/limits").await.unwrap();
+    assert_eq!(limits.next().await.unwrap().unwrap(), KeyEvent::Deleted);
+}